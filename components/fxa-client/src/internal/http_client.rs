@@ -466,7 +466,11 @@ impl Client {
 
     fn handle_too_many_requests(&self, resp: Response) -> Result<Response> {
         let path = resp.url.path().to_string();
-        if let Some(retry_after) = resp.headers.get_as::<u64, _>(header_names::RETRY_AFTER) {
+        if let Some(retry_after) = resp
+            .headers
+            .get_as::<u64, _>(header_names::RETRY_AFTER)
+            .transpose()
+        {
             let retry_after = retry_after.unwrap_or(RETRY_AFTER_DEFAULT_SECONDS);
             let time_out_state = HttpClientState::Backoff {
                 backoff_end_duration: Duration::from_secs(retry_after),
@@ -221,6 +221,20 @@ class DatabaseLoginsStorage(private val dbPath: String) : AutoCloseable, LoginsS
         }
     }
 
+    @Throws(LoginsStorageException::class)
+    override fun getForSite(origin: String, formActionOrigin: String?): List<ServerPassword> {
+        return readQueryCounters.measure {
+            val rustBuf = rustCallWithLock { raw, error ->
+                PasswordSyncAdapter.INSTANCE.sync15_passwords_get_for_site(raw, origin, formActionOrigin, error)
+            }
+            try {
+                ServerPassword.fromCollectionMessage(MsgTypes.PasswordInfos.parseFrom(rustBuf.asCodedInputStream()!!))
+            } finally {
+                PasswordSyncAdapter.INSTANCE.sync15_passwords_destroy_buffer(rustBuf)
+            }
+        }
+    }
+
     @Throws(LoginsStorageException::class)
     override fun add(login: ServerPassword): String {
         return writeQueryCounters.measure {
@@ -446,6 +460,9 @@ class LoginsStoreCounterMetrics(
                 is InvalidRecordException -> {
                     errCount["invalid_record"].add()
                 }
+                is TooManyRecordsForHostException -> {
+                    errCount["too_many_records_for_host"].add()
+                }
                 is LoginsStorageException -> {
                     errCount["storage_error"].add()
                 }
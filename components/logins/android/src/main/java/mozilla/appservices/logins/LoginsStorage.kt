@@ -155,6 +155,19 @@ interface LoginsStorage : AutoCloseable {
     @Throws(LoginsStorageException::class)
     fun getByBaseDomain(baseDomain: String): List<ServerPassword>
 
+    /**
+     * Fetch the passwords which can be filled in on a page at [origin], as desktop's
+     * `getLoginsForSite` does. Passwords saved for the `http` version of an `https` origin
+     * are included, but not the other way around.
+     *
+     * If [formActionOrigin] is given, only form logins which submit there are included,
+     * otherwise only HTTP auth logins. Exact matches come first, then the most recently used.
+     *
+     * @throws [LoginsStorageException] On unexpected errors (IO failure, rust panics, etc)
+     */
+    @Throws(LoginsStorageException::class)
+    fun getForSite(origin: String, formActionOrigin: String?): List<ServerPassword>
+
     /**
      * Inserts the provided login into the database, returning its id.
      *
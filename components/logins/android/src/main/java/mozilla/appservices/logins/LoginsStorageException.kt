@@ -54,6 +54,36 @@ class RequestFailedException(msg: String) : LoginsStorageException(msg)
  */
 class InterruptedException(msg: String) : LoginsStorageException(msg)
 
+/**
+ * This error is emitted if the database is busy or locked by another
+ * connection. The operation may succeed if retried later.
+ */
+class DatabaseBusyException(msg: String) : LoginsStorageException(msg)
+
+/**
+ * This error is emitted if a write was refused because the database is over
+ * its maximum size. Deleting records frees up space.
+ */
+class QuotaExceededException(msg: String) : LoginsStorageException(msg)
+
+/**
+ * This error is emitted if the disk (or the database) is full.
+ */
+class DatabaseFullException(msg: String) : LoginsStorageException(msg)
+
+/**
+ * This error is emitted if the database file is corrupt. Unlike
+ * [InvalidKeyException], this is only emitted for unencrypted databases,
+ * or when the file could be decrypted but not read.
+ */
+class DatabaseCorruptException(msg: String) : LoginsStorageException(msg)
+
+/**
+ * This error is emitted if a login wasn't added because its host already
+ * has as many records as it's allowed.
+ */
+class TooManyRecordsForHostException(msg: String) : LoginsStorageException(msg)
+
 /**
  * A reason a login may be invalid
  */
@@ -41,12 +41,21 @@ internal interface PasswordSyncAdapter : Library {
     // Returns null if the id does not exist, otherwise protocol buffer
     fun sync15_passwords_get_by_id(handle: LoginsDbHandle, id: String, error: RustError.ByReference): RustBuffer.ByValue
 
+    // Returns a JSON `RecordDebugInfo`, with the passwords redacted, or `null`.
+    fun sync15_passwords_get_record_debug_info(handle: LoginsDbHandle, id: String, error: RustError.ByReference): Pointer?
+
     // return protocol buffer
     fun sync15_passwords_get_all(handle: LoginsDbHandle, error: RustError.ByReference): RustBuffer.ByValue
 
     // return protocol buffer
     fun sync15_passwords_get_by_base_domain(handle: LoginsDbHandle, basedomain: String, error: RustError.ByReference): RustBuffer.ByValue
 
+    // return protocol buffer
+    fun sync15_passwords_get_for_site(handle: LoginsDbHandle, origin: String, formActionOrigin: String?, error: RustError.ByReference): RustBuffer.ByValue
+
+    // Takes a JSON array of origins, returns a protocol buffer.
+    fun sync15_passwords_get_by_hostnames(handle: LoginsDbHandle, hostnamesJson: String, error: RustError.ByReference): RustBuffer.ByValue
+
     // Returns a JSON string containing a sync ping.
     fun sync15_passwords_sync(
         handle: LoginsDbHandle,
@@ -65,12 +74,21 @@ internal interface PasswordSyncAdapter : Library {
 
     fun sync15_passwords_check_valid(handle: LoginsDbHandle, data: Pointer, len: Int, error: RustError.ByReference)
 
+    // Returns a JSON `ValidationResult`.
+    fun sync15_passwords_validate(recordJson: String, error: RustError.ByReference): Pointer?
+
+    // Returns a JSON `KeyVerification`, such as `"WrongKey"` or
+    // `{"IoError":"PermissionDenied"}`. This doesn't change the file.
+    fun sync15_passwords_verify_key(dbPath: String, encryptionKey: String, error: RustError.ByReference): Pointer?
+
     // This is 1 for true and 0 for false, it would be a boolean but we need to return a value with
     // a known size.
     fun sync15_passwords_delete(handle: LoginsDbHandle, id: String, error: RustError.ByReference): Byte
     // Note: returns guid of new login entry (unless one was specifically requested)
     fun sync15_passwords_add(handle: LoginsDbHandle, data: Pointer, len: Int, error: RustError.ByReference): Pointer?
     fun sync15_passwords_update(handle: LoginsDbHandle, data: Pointer, len: Int, error: RustError.ByReference)
+    // Takes a JSON `LoginChanges`, returns the updated record as a protocol buffer.
+    fun sync15_passwords_update_fields(handle: LoginsDbHandle, id: String, changesJson: String, error: RustError.ByReference): RustBuffer.ByValue
 
     // Returns a JSON string containing import metrics
     fun sync15_passwords_import(handle: LoginsDbHandle, data: Pointer, len: Int, error: RustError.ByReference): Pointer?
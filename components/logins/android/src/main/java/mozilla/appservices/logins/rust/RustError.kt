@@ -6,12 +6,17 @@ package mozilla.appservices.logins.rust
 
 import com.sun.jna.Pointer
 import com.sun.jna.Structure
+import mozilla.appservices.logins.DatabaseBusyException
+import mozilla.appservices.logins.DatabaseCorruptException
+import mozilla.appservices.logins.DatabaseFullException
 import mozilla.appservices.logins.IdCollisionException
 import mozilla.appservices.logins.InvalidKeyException
 import mozilla.appservices.logins.InvalidRecordException
 import mozilla.appservices.logins.InvalidLoginReason
 import mozilla.appservices.logins.LoginsStorageException
 import mozilla.appservices.logins.NoSuchRecordException
+import mozilla.appservices.logins.QuotaExceededException
+import mozilla.appservices.logins.TooManyRecordsForHostException
 import mozilla.appservices.logins.RequestFailedException
 import mozilla.appservices.logins.InterruptedException
 import mozilla.appservices.logins.SyncAuthInvalidException
@@ -51,6 +56,11 @@ open class RustError : Structure() {
             4 -> return InvalidKeyException(message)
             5 -> return RequestFailedException(message)
             6 -> return InterruptedException(message)
+            8 -> return DatabaseBusyException(message)
+            9 -> return QuotaExceededException(message)
+            10 -> return DatabaseFullException(message)
+            11 -> return DatabaseCorruptException(message)
+            12 -> return TooManyRecordsForHostException(message)
 
             64 -> return InvalidRecordException(message, InvalidLoginReason.EMPTY_ORIGIN)
             65 -> return InvalidRecordException(message, InvalidLoginReason.EMPTY_PASSWORD)
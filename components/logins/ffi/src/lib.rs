@@ -13,8 +13,10 @@ use ffi_support::{
     define_box_destructor, define_bytebuffer_destructor, define_handle_map_deleter,
     define_string_destructor, ByteBuffer, ExternError, FfiStr,
 };
-use logins::msg_types::{PasswordInfo, PasswordInfos};
-use logins::{Login, LoginDb, PasswordStore, Result};
+use logins::msg_types::{
+    HostnamePasswordInfos, PasswordInfo, PasswordInfos, PasswordInfosByHostname,
+};
+use logins::{Login, LoginChanges, LoginDb, PasswordStore, Result};
 use std::os::raw::c_char;
 use std::sync::{Arc, Mutex};
 
@@ -254,6 +256,36 @@ pub unsafe extern "C" fn sync15_passwords_check_valid(
     })
 }
 
+/// Validates a login given as JSON, without a store, and returns a
+/// `ValidationResult` as JSON. See `logins::validate`.
+#[no_mangle]
+pub extern "C" fn sync15_passwords_validate(
+    record_json: FfiStr<'_>,
+    error: &mut ExternError,
+) -> *mut c_char {
+    log::debug!("sync15_passwords_validate");
+    ffi_support::call_with_result(error, || -> Result<String> {
+        let result = logins::validate(record_json.as_str())?;
+        Ok(serde_json::to_string(&result)?)
+    })
+}
+
+/// Checks whether `encryption_key` opens the database at `db_path`, without
+/// changing the file, and returns a `KeyVerification` as JSON. See
+/// `LoginDb::verify_key`.
+#[no_mangle]
+pub extern "C" fn sync15_passwords_verify_key(
+    db_path: FfiStr<'_>,
+    encryption_key: FfiStr<'_>,
+    error: &mut ExternError,
+) -> *mut c_char {
+    log::debug!("sync15_passwords_verify_key");
+    ffi_support::call_with_result(error, || -> Result<String> {
+        let result = LoginDb::verify_key(db_path.as_str(), encryption_key.as_str())?;
+        Ok(serde_json::to_string(&result)?)
+    })
+}
+
 #[no_mangle]
 pub extern "C" fn sync15_passwords_delete(
     handle: u64,
@@ -338,6 +370,52 @@ pub extern "C" fn sync15_passwords_get_by_base_domain(
     })
 }
 
+#[no_mangle]
+pub extern "C" fn sync15_passwords_get_for_site(
+    handle: u64,
+    origin: FfiStr<'_>,
+    form_action_origin: FfiStr<'_>,
+    error: &mut ExternError,
+) -> ByteBuffer {
+    log::debug!("sync15_passwords_get_for_site");
+    STORES.call_with_result(error, handle, |state| -> Result<_> {
+        let infos = state
+            .lock()
+            .unwrap()
+            .get_for_site(origin.as_str(), form_action_origin.as_opt_str())?
+            .into_iter()
+            .map(Login::into)
+            .collect();
+        Ok(PasswordInfos { infos })
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn sync15_passwords_get_by_hostnames(
+    handle: u64,
+    hostnames_json: FfiStr<'_>,
+    error: &mut ExternError,
+) -> ByteBuffer {
+    log::debug!("sync15_passwords_get_by_hostnames");
+    STORES.call_with_result(error, handle, |state| -> Result<_> {
+        let hostnames: Vec<String> = serde_json::from_str(hostnames_json.as_str())?;
+        let hostnames = hostnames.iter().map(String::as_str).collect::<Vec<_>>();
+        let mut found = state.lock().unwrap().get_by_hostnames(&hostnames)?;
+        // In the order they were asked for, once each.
+        let hostnames = hostnames
+            .into_iter()
+            .filter_map(|hostname| {
+                let logins = found.remove(hostname)?;
+                Some(HostnamePasswordInfos {
+                    hostname: hostname.to_owned(),
+                    infos: logins.into_iter().map(Login::into).collect(),
+                })
+            })
+            .collect();
+        Ok(PasswordInfosByHostname { hostnames })
+    })
+}
+
 /// # Safety
 /// Deref pointer, thus unsafe
 #[no_mangle]
@@ -374,6 +452,24 @@ pub extern "C" fn sync15_passwords_get_by_id(
     })
 }
 
+/// Returns `get_record_debug_info` for `id` as JSON, with the passwords
+/// redacted, or `null` if there's no such record.
+#[no_mangle]
+pub extern "C" fn sync15_passwords_get_record_debug_info(
+    handle: u64,
+    id: FfiStr<'_>,
+    error: &mut ExternError,
+) -> *mut c_char {
+    log::debug!("sync15_passwords_get_record_debug_info");
+    STORES.call_with_result(error, handle, |state| -> Result<String> {
+        let info = state
+            .lock()
+            .unwrap()
+            .get_record_debug_info(id.as_str(), false)?;
+        Ok(serde_json::to_string(&info)?)
+    })
+}
+
 /// # Safety
 /// Deref pointer, thus unsafe
 #[no_mangle]
@@ -428,6 +524,25 @@ pub unsafe extern "C" fn sync15_passwords_update(
     });
 }
 
+/// Changes the fields of the record with `id` which are given in
+/// `changes_json`, a JSON `LoginChanges`, and returns the record as it is
+/// afterwards. Missing fields are left as they are, while `null` clears
+/// `httpRealm` or `formSubmitURL`.
+#[no_mangle]
+pub extern "C" fn sync15_passwords_update_fields(
+    handle: u64,
+    id: FfiStr<'_>,
+    changes_json: FfiStr<'_>,
+    error: &mut ExternError,
+) -> ByteBuffer {
+    log::debug!("sync15_passwords_update_fields");
+    STORES.call_with_result(error, handle, |state| -> Result<PasswordInfo> {
+        let changes: LoginChanges = serde_json::from_str(changes_json.as_str())?;
+        let login = state.lock().unwrap().update_fields(id.as_str(), changes)?;
+        Ok(login.into())
+    })
+}
+
 define_string_destructor!(sync15_passwords_destroy_string);
 define_bytebuffer_destructor!(sync15_passwords_destroy_buffer);
 define_handle_map_deleter!(STORES, sync15_passwords_state_destroy);
@@ -0,0 +1,60 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use logins::{Login, LoginDb, LoginStore};
+use sync15::{telemetry, IncomingChangeset, Payload, ServerTimestamp, SyncEngine};
+use sync_guid::Guid;
+
+const GUIDS: &[&str] = &["seededsynced", "seedednewrec", "seededdeletd"];
+
+fn apply(db: &LoginDb, payload: Option<Payload>, server_now: i64) {
+    let mut changeset = IncomingChangeset::new("passwords", ServerTimestamp(server_now));
+    if let Some(payload) = payload {
+        changeset.changes.push((payload, ServerTimestamp(1000)));
+    }
+    let mut telem = telemetry::Engine::new("passwords");
+    // Errors are fine, panics aren't.
+    let _ = LoginStore::new(db).apply_incoming(vec![changeset], &mut telem);
+}
+
+fn seeded_db() -> LoginDb {
+    let db = LoginDb::open_in_memory(Some("fuzzing")).unwrap();
+    for guid in GUIDS {
+        db.add(Login {
+            guid: Guid::new(guid),
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("https://www.example.com".into()),
+            username: (*guid).into(),
+            password: "password".into(),
+            ..Login::default()
+        })
+        .unwrap();
+    }
+    apply(&db, None, 1000);
+    LoginStore::new(&db)
+        .sync_finished(
+            ServerTimestamp(1000),
+            vec![Guid::new(GUIDS[0]), Guid::new(GUIDS[2])],
+        )
+        .unwrap();
+    db.delete(GUIDS[2]).unwrap();
+    db
+}
+
+fuzz_target!(|data: &[u8]| {
+    let json = match serde_json::from_slice::<serde_json::Value>(data) {
+        Ok(json) => json,
+        Err(_) => return,
+    };
+    let payload = match Payload::from_json(json) {
+        Ok(payload) => payload,
+        Err(_) => return,
+    };
+    let db = seeded_db();
+    apply(&db, Some(payload), 1_600_000_000_000);
+    assert_eq!(db.check_integrity().unwrap(), Vec::<String>::new());
+});
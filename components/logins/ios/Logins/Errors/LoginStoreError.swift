@@ -45,6 +45,26 @@ public enum LoginsStoreError: LocalizedError {
     /// database was invalid.
     case invalidSalt(message: String)
 
+    /// This error is emitted if the database is busy or locked by another
+    /// connection. The operation may succeed if retried later.
+    case databaseBusy(message: String)
+
+    /// This error is emitted if a write was refused because the database is
+    /// over its maximum size. Deleting records frees up space.
+    case quotaExceeded(message: String)
+
+    /// This error is emitted if the disk (or the database) is full.
+    case databaseFull(message: String)
+
+    /// This error is emitted if the database file is corrupt. Unlike
+    /// `invalidKey`, this is only emitted for unencrypted databases, or when
+    /// the file could be decrypted but not read.
+    case databaseCorrupt(message: String)
+
+    /// This error is emitted if a login wasn't added because its host
+    /// already has as many records as it's allowed.
+    case tooManyRecordsForHost(message: String)
+
     /// Our implementation of the localizedError protocol -- (This shows up in Sentry)
     public var errorDescription: String? {
         switch self {
@@ -68,6 +88,16 @@ public enum LoginsStoreError: LocalizedError {
             return "LoginsStoreError.interrupted: \(message)"
         case let .invalidSalt(message):
             return "LoginsStoreError.invalidSalt: \(message)"
+        case let .databaseBusy(message):
+            return "LoginsStoreError.databaseBusy: \(message)"
+        case let .quotaExceeded(message):
+            return "LoginsStoreError.quotaExceeded: \(message)"
+        case let .databaseFull(message):
+            return "LoginsStoreError.databaseFull: \(message)"
+        case let .databaseCorrupt(message):
+            return "LoginsStoreError.databaseCorrupt: \(message)"
+        case let .tooManyRecordsForHost(message):
+            return "LoginsStoreError.tooManyRecordsForHost: \(message)"
         }
     }
 
@@ -126,6 +156,21 @@ public enum LoginsStoreError: LocalizedError {
         case Sync15Passwords_InvalidSaltError:
             return .invalidSalt(message: String(freeingRustString: message!))
 
+        case Sync15Passwords_DatabaseBusyError:
+            return .databaseBusy(message: String(freeingRustString: message!))
+
+        case Sync15Passwords_QuotaExceededError:
+            return .quotaExceeded(message: String(freeingRustString: message!))
+
+        case Sync15Passwords_DatabaseFullError:
+            return .databaseFull(message: String(freeingRustString: message!))
+
+        case Sync15Passwords_DatabaseCorruptError:
+            return .databaseCorrupt(message: String(freeingRustString: message!))
+
+        case Sync15Passwords_TooManyRecordsForHostError:
+            return .tooManyRecordsForHost(message: String(freeingRustString: message!))
+
         default:
             return .unspecified(message: String(freeingRustString: message!))
         }
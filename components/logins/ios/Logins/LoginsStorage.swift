@@ -405,6 +405,25 @@ open class LoginsStorage {
         }
     }
 
+    /// Get the records which can be filled in on a page at `origin`, as desktop's
+    /// `getLoginsForSite` does. Records saved for the `http` version of an `https`
+    /// origin are included, but not the other way around.
+    ///
+    /// If `formActionOrigin` is given, only form logins which submit there are
+    /// included, otherwise only HTTP auth logins. Exact matches come first, then
+    /// the most recently used.
+    open func getForSite(origin: String, formActionOrigin: String?) throws -> [LoginRecord] {
+        return try queue.sync {
+            let engine = try self.getUnlocked()
+            let buffer = try LoginsStoreError.unwrap { err in
+                sync15_passwords_get_for_site(engine, origin, formActionOrigin, err)
+            }
+            defer { sync15_passwords_destroy_buffer(buffer) }
+            let msgList = try MsgTypes_PasswordInfos(serializedData: Data(loginsRustBuffer: buffer))
+            return unpackProtobufInfoList(msgList: msgList)
+        }
+    }
+
     /// Interrupt a pending operation on another thread, causing it to fail with
     /// `LoginsStoreError.interrupted`.
     ///
@@ -16,6 +16,11 @@ typedef enum Sync15PasswordsErrorCode {
     Sync15Passwords_NetworkError     = 5,
     Sync15Passwords_InterruptedError = 6,
     Sync15Passwords_InvalidSaltError = 7,
+    Sync15Passwords_DatabaseBusyError = 8,
+    Sync15Passwords_QuotaExceededError = 9,
+    Sync15Passwords_DatabaseFullError = 10,
+    Sync15Passwords_DatabaseCorruptError = 11,
+    Sync15Passwords_TooManyRecordsForHostError = 12,
 
     Sync15Passwords_InvalidLogin_EmptyOrigin = 64 + 0,
     Sync15Passwords_InvalidLogin_EmptyPassword = 64 + 1,
@@ -72,10 +77,23 @@ Sync15PasswordsRustBuffer sync15_passwords_get_by_id(Sync15PasswordEngineHandle
                                           char const *_Nonnull id,
                                           Sync15PasswordsError *_Nonnull error_out);
 
+char *_Nullable sync15_passwords_get_record_debug_info(Sync15PasswordEngineHandle handle,
+                                                       char const *_Nonnull id,
+                                                       Sync15PasswordsError *_Nonnull error_out);
+
 Sync15PasswordsRustBuffer sync15_passwords_get_by_base_domain(Sync15PasswordEngineHandle handle,
                                           char const *_Nonnull baseDomain,
                                           Sync15PasswordsError *_Nonnull error_out);
 
+Sync15PasswordsRustBuffer sync15_passwords_get_for_site(Sync15PasswordEngineHandle handle,
+                                          char const *_Nonnull origin,
+                                          char const *_Nullable formActionOrigin,
+                                          Sync15PasswordsError *_Nonnull error_out);
+
+Sync15PasswordsRustBuffer sync15_passwords_get_by_hostnames(Sync15PasswordEngineHandle handle,
+                                          char const *_Nonnull hostnamesJson,
+                                          Sync15PasswordsError *_Nonnull error_out);
+
 Sync15PasswordsRustBuffer sync15_passwords_get_all(Sync15PasswordEngineHandle handle,
                                                    Sync15PasswordsError *_Nonnull error_out);
 
@@ -125,6 +143,13 @@ void sync15_passwords_check_valid(Sync15PasswordEngineHandle handle,
                                   int32_t len,
                                   Sync15PasswordsError *_Nonnull error);
 
+char *_Nullable sync15_passwords_validate(char const *_Nonnull record_json,
+                                          Sync15PasswordsError *_Nonnull error);
+
+char *_Nullable sync15_passwords_verify_key(char const *_Nonnull db_path,
+                                            char const *_Nonnull encryption_key,
+                                            Sync15PasswordsError *_Nonnull error);
+
 char *_Nullable sync15_passwords_add(Sync15PasswordEngineHandle handle,
                                      uint8_t const *_Nonnull data,
                                      int32_t len,
@@ -135,6 +160,11 @@ void sync15_passwords_update(Sync15PasswordEngineHandle handle,
                              int32_t len,
                              Sync15PasswordsError *_Nonnull error);
 
+Sync15PasswordsRustBuffer sync15_passwords_update_fields(Sync15PasswordEngineHandle handle,
+                                                         char const *_Nonnull id,
+                                                         char const *_Nonnull changes_json,
+                                                         Sync15PasswordsError *_Nonnull error);
+
 void sync15_passwords_destroy_buffer(Sync15PasswordsRustBuffer bb);
 
 void sync15_passwords_destroy_string(char const *_Nonnull str);
@@ -0,0 +1,279 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Local-only annotations on login records.
+//!
+//! Annotations are arbitrary string key-value pairs attached to a record, and
+//! stored in the `loginsLocalMeta` table (see the [schema](crate::schema) docs
+//! for their lifetime). They're never included in outgoing sync payloads.
+//!
+//! The "breached" annotation, used to flag records reported by a
+//! breach-monitoring service, is built on top of these.
+
+use crate::db::LoginDb;
+use crate::error::*;
+use crate::login::Login;
+use crate::schema::{self, TableNames};
+use rusqlite::{named_params, Connection};
+use sql_support::ConnExt;
+use std::collections::HashMap;
+
+/// The annotation key used by `mark_breached`. The value is the time of the
+/// breach, in milliseconds since the unix epoch.
+pub const BREACHED_ANNOTATION_KEY: &str = "breached";
+
+impl LoginDb {
+    /// Set the annotation `key` on the record with the given guid to `value`,
+    /// replacing any existing value. Fails with `NoSuchRecord` if there's no
+    /// such (non-deleted) record.
+    pub fn set_local_annotation(&self, guid: &str, key: &str, value: &str) -> Result<()> {
+        let tx = self.unchecked_transaction()?;
+        if !self.exists(guid)? {
+            throw!(ErrorKind::NoSuchRecord(guid.to_owned()));
+        }
+        self.execute_named_cached(
+            &self.sql(
+                "REPLACE INTO loginsLocalMeta (guid, key, value) VALUES (:guid, :key, :value)",
+            ),
+            named_params! { ":guid": guid, ":key": key, ":value": value },
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Get all the annotations on the record with the given guid.
+    pub fn get_local_annotations(&self, guid: &str) -> Result<HashMap<String, String>> {
+        let mut stmt = self.db.prepare_cached(
+            &self.sql("SELECT key, value FROM loginsLocalMeta WHERE guid = :guid"),
+        )?;
+        let rows = stmt.query_and_then_named(named_params! { ":guid": guid }, |row| {
+            Ok::<_, Error>((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+        rows.collect()
+    }
+
+    /// Get the guids of all records where the annotation `key` is set to
+    /// `value`.
+    pub fn get_guids_with_annotation(&self, key: &str, value: &str) -> Result<Vec<String>> {
+        let mut stmt = self.db.prepare_cached(
+            &self.sql("SELECT guid FROM loginsLocalMeta WHERE key = :key AND value = :value"),
+        )?;
+        let rows = stmt
+            .query_and_then_named(named_params! { ":key": key, ":value": value }, |row| {
+                Ok::<_, Error>(row.get::<_, String>(0)?)
+            })?;
+        rows.collect()
+    }
+
+    /// Flag the record with the given guid as having been found in a breach
+    /// at `breach_time` (milliseconds since the unix epoch).
+    pub fn mark_breached(&self, guid: &str, breach_time: i64) -> Result<()> {
+        self.set_local_annotation(guid, BREACHED_ANNOTATION_KEY, &breach_time.to_string())
+    }
+
+    /// Get all the records which have been flagged with `mark_breached`.
+    pub fn get_breached(&self) -> Result<Vec<Login>> {
+        lazy_static::lazy_static! {
+            static ref GET_BREACHED_SQL: String = format!(
+                "SELECT {common_cols} FROM loginsL
+                 WHERE is_deleted = 0
+                   AND guid IN (SELECT guid FROM loginsLocalMeta WHERE key = :key)
+                 UNION ALL
+                 SELECT {common_cols} FROM loginsM
+                 WHERE is_overridden = 0
+                   AND guid IN (SELECT guid FROM loginsLocalMeta WHERE key = :key)",
+                common_cols = schema::COMMON_COLS
+            );
+        }
+        let mut stmt = self.db.prepare_cached(&self.sql(&GET_BREACHED_SQL))?;
+        let rows = stmt
+            .query_and_then_named(named_params! { ":key": BREACHED_ANNOTATION_KEY }, |row| {
+                Login::from_row(row, self.encdec())
+            })?;
+        rows.collect()
+    }
+}
+
+/// Remove the annotations for the given guids.
+pub(crate) fn delete_annotations(
+    conn: &Connection,
+    tables: &TableNames,
+    guids: &[&str],
+) -> Result<()> {
+    sql_support::each_chunk(guids, |chunk, _| -> Result<()> {
+        conn.execute(
+            &tables.resolve(&format!(
+                "DELETE FROM loginsLocalMeta WHERE guid IN ({vars})",
+                vars = sql_support::repeat_sql_vars(chunk.len())
+            )),
+            chunk,
+        )?;
+        Ok(())
+    })
+}
+
+/// Remove annotations whose record no longer exists in either table.
+pub(crate) fn delete_orphaned_annotations(conn: &Connection, tables: &TableNames) -> Result<()> {
+    conn.execute_batch(&tables.resolve(
+        "DELETE FROM loginsLocalMeta
+         WHERE guid NOT IN (SELECT guid FROM loginsL WHERE is_deleted = 0)
+           AND guid NOT IN (SELECT guid FROM loginsM WHERE is_overridden = 0)",
+    ))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{sync_db, LoginFixture};
+    use crate::LoginStore;
+    use sync15::{IncomingChangeset, Payload, ServerTimestamp, SyncEngine};
+
+    fn incoming(payload: serde_json::Value, ts: i64) -> Vec<IncomingChangeset> {
+        let mut changeset = IncomingChangeset::new("passwords", ServerTimestamp(ts));
+        changeset
+            .changes
+            .push((Payload::from_json(payload).unwrap(), ServerTimestamp(ts)));
+        vec![changeset]
+    }
+
+    // Sync `login` to the server, then apply an incoming change to it, which
+    // forces a three-way merge against a local change.
+    fn sync_round_trip(db: &LoginDb, login: &Login) {
+        let (outgoing, mut telem) = sync_db(db, vec![], ServerTimestamp(1000));
+        for payload in &outgoing.changes {
+            assert!(!payload.data.contains_key(BREACHED_ANNOTATION_KEY));
+        }
+
+        db.touch(login.guid_str()).unwrap();
+        let engine = LoginStore::new(db);
+        engine
+            .apply_incoming(
+                incoming(
+                    serde_json::json!({
+                        "id": login.guid_str(),
+                        "hostname": login.hostname,
+                        "formSubmitURL": login.form_submit_url,
+                        "username": login.username,
+                        "password": "new-password",
+                        "timePasswordChanged": 2000,
+                    }),
+                    2000,
+                ),
+                &mut telem,
+            )
+            .unwrap();
+        engine
+            .sync_finished(ServerTimestamp(2000), vec![login.guid.clone()])
+            .unwrap();
+    }
+
+    #[test]
+    fn test_annotations() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let login = db.add(LoginFixture::numbered(0).build()).unwrap();
+        let other = db.add(LoginFixture::numbered(1).build()).unwrap();
+
+        db.set_local_annotation(login.guid_str(), "color", "red")
+            .unwrap();
+        db.set_local_annotation(login.guid_str(), "shape", "round")
+            .unwrap();
+        db.set_local_annotation(other.guid_str(), "color", "blue")
+            .unwrap();
+        db.set_local_annotation(login.guid_str(), "color", "blue")
+            .unwrap();
+
+        let annotations = db.get_local_annotations(login.guid_str()).unwrap();
+        assert_eq!(annotations.len(), 2);
+        assert_eq!(annotations["color"], "blue");
+        assert_eq!(annotations["shape"], "round");
+
+        let mut guids = db.get_guids_with_annotation("color", "blue").unwrap();
+        guids.sort();
+        let mut expected = vec![login.guid.to_string(), other.guid.to_string()];
+        expected.sort();
+        assert_eq!(guids, expected);
+
+        assert!(db
+            .set_local_annotation("dummy_000001", "color", "red")
+            .is_err());
+    }
+
+    #[test]
+    fn test_breached_survives_sync_and_reset() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let login = db.add(LoginFixture::numbered(0).build()).unwrap();
+        db.add(LoginFixture::numbered(1).build()).unwrap();
+        db.mark_breached(login.guid_str(), 1234).unwrap();
+
+        sync_round_trip(&db, &login);
+        let breached = db.get_breached().unwrap();
+        assert_eq!(breached.len(), 1);
+        assert_eq!(breached[0].guid, login.guid);
+        assert_eq!(breached[0].password, "new-password");
+        assert_eq!(
+            db.get_local_annotations(login.guid_str()).unwrap()[BREACHED_ANNOTATION_KEY],
+            "1234"
+        );
+
+        db.reset(&sync15::EngineSyncAssociation::Disconnected)
+            .unwrap();
+        let breached = db.get_breached().unwrap();
+        assert_eq!(breached.len(), 1);
+        assert_eq!(breached[0].guid, login.guid);
+    }
+
+    #[test]
+    fn test_deleted_with_record() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let login = db.add(LoginFixture::numbered(0).build()).unwrap();
+        db.mark_breached(login.guid_str(), 1234).unwrap();
+        db.delete(login.guid_str()).unwrap();
+        assert!(db
+            .get_local_annotations(login.guid_str())
+            .unwrap()
+            .is_empty());
+        assert!(db.get_breached().unwrap().is_empty());
+
+        let login = db.add(LoginFixture::numbered(2).build()).unwrap();
+        db.mark_breached(login.guid_str(), 1234).unwrap();
+        db.wipe(&db.begin_interrupt_scope()).unwrap();
+        assert!(db
+            .get_local_annotations(login.guid_str())
+            .unwrap()
+            .is_empty());
+
+        let login = db.add(LoginFixture::numbered(1).build()).unwrap();
+        db.mark_breached(login.guid_str(), 1234).unwrap();
+        db.wipe_local().unwrap();
+        assert!(db
+            .get_local_annotations(login.guid_str())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_deleted_by_incoming_tombstone() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let login = db.add(LoginFixture::numbered(0).build()).unwrap();
+        db.mark_breached(login.guid_str(), 1234).unwrap();
+        sync_round_trip(&db, &login);
+
+        let engine = LoginStore::new(&db);
+        let mut telem = sync15::telemetry::Engine::new("passwords");
+        engine
+            .apply_incoming(
+                incoming(
+                    serde_json::json!({ "id": login.guid_str(), "deleted": true }),
+                    3000,
+                ),
+                &mut telem,
+            )
+            .unwrap();
+        assert!(db
+            .get_local_annotations(login.guid_str())
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,498 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Encrypted, passphrase-protected backups of the logins store.
+//!
+//! A backup is a small JSON envelope:
+//!
+//! ```json
+//! {
+//!     "magic": "mozilla-appservices-logins-backup",
+//!     "version": 1,
+//!     "kdf": { "algorithm": "pbkdf2-sha256", "iterations": 100000, "salt": "<base64>" },
+//!     "nonce": "<base64>",
+//!     "ciphertext": "<base64>"
+//! }
+//! ```
+//!
+//! The ciphertext is the JSON-serialized list of records (in the same format
+//! we use for sync payloads), encrypted with AES-256-GCM using a key derived
+//! from the passphrase with PBKDF2. The magic and version are bound to the
+//! ciphertext as additional authenticated data, so tampering with them makes
+//! decryption fail.
+
+use crate::db::LoginDb;
+use crate::error::*;
+use crate::login::Login;
+use crate::{timestamps, util};
+use rc_crypto::{aead, pbkdf2, rand};
+use rusqlite::named_params;
+use serde_derive::*;
+use sql_support::ConnExt;
+use std::io::{Read, Write};
+use std::time::SystemTime;
+use sync_guid::Guid;
+
+const BACKUP_MAGIC: &str = "mozilla-appservices-logins-backup";
+const BACKUP_VERSION: u32 = 1;
+const KDF_ALGORITHM: &str = "pbkdf2-sha256";
+const KDF_ITERATIONS: u32 = 100_000;
+// The most iterations we'll run for a backup which asks for them, so that a
+// bad one can't keep us busy for hours.
+const MAX_KDF_ITERATIONS: u32 = 10 * KDF_ITERATIONS;
+const KDF_SALT_LEN: usize = 16;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BackupEnvelope {
+    magic: String,
+    version: u32,
+    kdf: KdfParams,
+    nonce: String,
+    ciphertext: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct KdfParams {
+    algorithm: String,
+    iterations: u32,
+    salt: String,
+}
+
+/// How `import_from_backup` should treat a store that already contains data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportMode {
+    /// Fail with `NonEmptyTable` unless the store is empty, exactly like
+    /// `import_multiple`.
+    RequireEmpty,
+    /// Skip records which are duplicates of an existing record, and add the
+    /// others as new records.
+    Merge,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+pub struct ExportSummary {
+    pub num_exported: u64,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+pub struct ImportMetrics {
+    pub num_processed: u64,
+    pub num_imported: u64,
+    pub num_skipped: u64,
+    pub num_failed: u64,
+    pub errors: Vec<String>,
+    /// How many timestamps had to be repaired. See the `timestamps` module.
+    #[serde(default)]
+    pub num_fixed_timestamps: u64,
+}
+
+fn aad() -> Vec<u8> {
+    format!("{}:{}", BACKUP_MAGIC, BACKUP_VERSION).into_bytes()
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], iterations: u32) -> Result<Vec<u8>> {
+    let mut key = vec![0u8; aead::AES_256_GCM.key_len()];
+    pbkdf2::derive(
+        passphrase.as_bytes(),
+        salt,
+        iterations,
+        pbkdf2::HashAlgorithm::SHA256,
+        &mut key,
+    )?;
+    Ok(key)
+}
+
+fn decode_field(name: &str, value: &str) -> Result<Vec<u8>> {
+    base64::decode(value).map_err(|e| {
+        ErrorKind::InvalidBackup(format!("`{}` is not valid base64: {}", name, e)).into()
+    })
+}
+
+fn seal_records(logins: &[Login], passphrase: &str) -> Result<BackupEnvelope> {
+    rc_crypto::ensure_initialized();
+    let mut salt = vec![0u8; KDF_SALT_LEN];
+    rand::fill(&mut salt)?;
+    let mut nonce = vec![0u8; aead::AES_256_GCM.nonce_len()];
+    rand::fill(&mut nonce)?;
+
+    let key = derive_key(passphrase, &salt, KDF_ITERATIONS)?;
+    let sealing_key = aead::SealingKey::new(&aead::AES_256_GCM, &key)?;
+    let plaintext = serde_json::to_vec(logins)?;
+    let aad = aad();
+    let ciphertext = aead::seal(
+        &sealing_key,
+        aead::Nonce::try_assume_unique_for_key(&aead::AES_256_GCM, &nonce)?,
+        aead::Aad::from(&aad),
+        &plaintext,
+    )?;
+
+    Ok(BackupEnvelope {
+        magic: BACKUP_MAGIC.into(),
+        version: BACKUP_VERSION,
+        kdf: KdfParams {
+            algorithm: KDF_ALGORITHM.into(),
+            iterations: KDF_ITERATIONS,
+            salt: base64::encode(&salt),
+        },
+        nonce: base64::encode(&nonce),
+        ciphertext: base64::encode(&ciphertext),
+    })
+}
+
+fn open_records(envelope: &BackupEnvelope, passphrase: &str) -> Result<Vec<Login>> {
+    if envelope.magic != BACKUP_MAGIC {
+        throw!(ErrorKind::InvalidBackup("not a logins backup".into()));
+    }
+    if envelope.version != BACKUP_VERSION {
+        throw!(ErrorKind::InvalidBackup(format!(
+            "unsupported version {}",
+            envelope.version
+        )));
+    }
+    if envelope.kdf.algorithm != KDF_ALGORITHM {
+        throw!(ErrorKind::InvalidBackup(format!(
+            "unsupported kdf {:?}",
+            envelope.kdf.algorithm
+        )));
+    }
+    if !(1..=MAX_KDF_ITERATIONS).contains(&envelope.kdf.iterations) {
+        throw!(ErrorKind::InvalidBackup(format!(
+            "unsupported kdf iterations {}",
+            envelope.kdf.iterations
+        )));
+    }
+    rc_crypto::ensure_initialized();
+    let salt = decode_field("kdf.salt", &envelope.kdf.salt)?;
+    let nonce = decode_field("nonce", &envelope.nonce)?;
+    let ciphertext = decode_field("ciphertext", &envelope.ciphertext)?;
+
+    let key = derive_key(passphrase, &salt, envelope.kdf.iterations)?;
+    let opening_key = aead::OpeningKey::new(&aead::AES_256_GCM, &key)?;
+    let nonce = aead::Nonce::try_assume_unique_for_key(&aead::AES_256_GCM, &nonce)
+        .map_err(|_| ErrorKind::InvalidBackup("bad nonce length".into()))?;
+    let aad = aad();
+    // The only way this fails with well-formed inputs is an authentication
+    // failure, which is what a wrong passphrase looks like.
+    let plaintext = aead::open(&opening_key, nonce, aead::Aad::from(&aad), &ciphertext)
+        .map_err(|_| ErrorKind::BackupDecryptionFailed)?;
+    Ok(serde_json::from_slice(&plaintext)?)
+}
+
+impl LoginDb {
+    /// Write all (non-deleted) records to `writer` as an encrypted backup
+    /// protected by `passphrase`.
+    pub fn export_to_backup(&self, writer: impl Write, passphrase: &str) -> Result<ExportSummary> {
+        let logins = self.get_all()?;
+        let envelope = seal_records(&logins, passphrase)?;
+        serde_json::to_writer(writer, &envelope)?;
+        Ok(ExportSummary {
+            num_exported: logins.len() as u64,
+        })
+    }
+
+    /// Read an encrypted backup written by `export_to_backup` and add the
+    /// records it contains to this store. A wrong passphrase results in
+    /// `ErrorKind::BackupDecryptionFailed`.
+    pub fn import_from_backup(
+        &self,
+        reader: impl Read,
+        passphrase: &str,
+        mode: ImportMode,
+    ) -> Result<ImportMetrics> {
+        let envelope: BackupEnvelope =
+            serde_json::from_reader(reader).map_err(|e| ErrorKind::InvalidBackup(e.to_string()))?;
+        let logins = open_records(&envelope, passphrase)?;
+        match mode {
+            ImportMode::RequireEmpty => {
+                let metrics = self.import_multiple(&logins)?;
+                Ok(ImportMetrics {
+                    num_processed: metrics.num_processed(),
+                    num_imported: metrics.num_succeeded(),
+                    num_skipped: 0,
+                    num_failed: metrics.num_failed(),
+                    errors: metrics.errors().to_vec(),
+                    num_fixed_timestamps: metrics.num_fixed_timestamps(),
+                })
+            }
+            ImportMode::Merge => self.merge_backup_records(logins),
+        }
+    }
+
+    // Adds the records in one transaction. Records which are invalid, or
+    // which we fail to insert, are skipped and counted in the metrics, like
+    // `import_multiple` does; any other error rolls the transaction back, so
+    // that none of the records are added.
+    fn merge_backup_records(&self, logins: Vec<Login>) -> Result<ImportMetrics> {
+        self.check_quota()?;
+        let mut metrics = ImportMetrics {
+            num_processed: logins.len() as u64,
+            ..ImportMetrics::default()
+        };
+        let tx = self.unchecked_transaction()?;
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        let mut imported_guids = Vec::new();
+        for login in logins {
+            let mut login = match login.fixup() {
+                Ok(l) => l,
+                Err(e) => {
+                    log::warn!("Skipping invalid record from backup ({}).", e);
+                    metrics.errors.push(e.label().into());
+                    metrics.num_failed += 1;
+                    continue;
+                }
+            };
+            // Keep the GUID from the backup where we can, but never reuse one
+            // that we already know about (including as a tombstone). Note that
+            // this must happen before the dupe check, which ignores records
+            // sharing the GUID of the record being checked.
+            if !login.guid.is_valid_for_sync_server() || self.guid_in_use(login.guid_str())? {
+                login.guid = Guid::random();
+            }
+            if self.dupe_exists(&login)? {
+                metrics.num_skipped += 1;
+                continue;
+            }
+            metrics.num_fixed_timestamps += timestamps::sanitize(&mut login, now_ms) as u64;
+            let inserted = self
+                .check_host_record_count(&login.hostname)
+                .and_then(|_| self.insert_new_login(login, now_ms));
+            match inserted {
+                Ok(login) => {
+                    imported_guids.push(login.guid);
+                    metrics.num_imported += 1;
+                }
+                Err(e) => {
+                    log::warn!("Could not import record from backup ({}).", e);
+                    metrics.errors.push(e.label().into());
+                    metrics.num_failed += 1;
+                }
+            }
+        }
+        self.note_changed(&imported_guids)?;
+        tx.commit()?;
+        Ok(metrics)
+    }
+
+    pub(crate) fn guid_in_use(&self, guid: &str) -> Result<bool> {
+        Ok(self.db.query_row_named(
+            &self.sql(
+                "SELECT EXISTS(
+                     SELECT 1 FROM loginsL WHERE guid = :guid
+                     UNION ALL
+                     SELECT 1 FROM loginsM WHERE guid = :guid
+                 )",
+            ),
+            named_params! { ":guid": guid },
+            |row| row.get(0),
+        )?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::LoginFixture;
+    use rusqlite::NO_PARAMS;
+
+    fn login(hostname: &str, username: &str, password: &str) -> Login {
+        LoginFixture::builder()
+            .hostname(hostname)
+            .form_submit_url(hostname)
+            .username(username)
+            .password(password)
+            .build()
+    }
+
+    fn export(db: &LoginDb, passphrase: &str) -> Vec<u8> {
+        let mut buf = Vec::new();
+        db.export_to_backup(&mut buf, passphrase).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let src = LoginDb::open_in_memory(Some("testing")).unwrap();
+        src.add(login("https://www.example.com", "a", "pw-a"))
+            .unwrap();
+        src.add(login("https://www.example2.com", "b", "pw-b"))
+            .unwrap();
+        let mut buf = Vec::new();
+        let summary = src.export_to_backup(&mut buf, "hunter2").unwrap();
+        assert_eq!(summary.num_exported, 2);
+
+        let dest = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let metrics = dest
+            .import_from_backup(buf.as_slice(), "hunter2", ImportMode::RequireEmpty)
+            .unwrap();
+        assert_eq!(metrics.num_imported, 2);
+
+        let mut expected = src.get_all().unwrap();
+        let mut actual = dest.get_all().unwrap();
+        expected.sort_by(|a, b| a.guid.cmp(&b.guid));
+        actual.sort_by(|a, b| a.guid.cmp(&b.guid));
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_wrong_passphrase() {
+        let src = LoginDb::open_in_memory(Some("testing")).unwrap();
+        src.add(login("https://www.example.com", "a", "pw-a"))
+            .unwrap();
+        let buf = export(&src, "hunter2");
+        let dest = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let err = dest
+            .import_from_backup(buf.as_slice(), "hunter3", ImportMode::Merge)
+            .unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::BackupDecryptionFailed));
+        let num_rows: i64 = dest
+            .query_row("SELECT COUNT(*) FROM loginsL", NO_PARAMS, |r| r.get(0))
+            .unwrap();
+        assert_eq!(num_rows, 0);
+    }
+
+    #[test]
+    fn test_not_a_backup() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let err = db
+            .import_from_backup(&b"{\"foo\": 1}"[..], "x", ImportMode::Merge)
+            .unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::InvalidBackup(_)));
+    }
+
+    #[test]
+    fn test_kdf_iterations_bounded() {
+        let src = LoginDb::open_in_memory(Some("testing")).unwrap();
+        src.add(login("https://www.example.com", "a", "pw-a"))
+            .unwrap();
+        let buf = export(&src, "hunter2");
+        let with_iterations = |iterations: u32| {
+            let mut envelope: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+            envelope["kdf"]["iterations"] = iterations.into();
+            serde_json::to_vec(&envelope).unwrap()
+        };
+        let dest = LoginDb::open_in_memory(Some("testing")).unwrap();
+        for iterations in &[0, MAX_KDF_ITERATIONS + 1, u32::MAX] {
+            let err = dest
+                .import_from_backup(
+                    with_iterations(*iterations).as_slice(),
+                    "hunter2",
+                    ImportMode::Merge,
+                )
+                .unwrap_err();
+            assert!(
+                matches!(err.kind(), ErrorKind::InvalidBackup(_)),
+                "{}: {:?}",
+                iterations,
+                err
+            );
+        }
+        // Anything else is fine, although the key won't be right unless it's
+        // the count the backup was made with.
+        let err = dest
+            .import_from_backup(with_iterations(1).as_slice(), "hunter2", ImportMode::Merge)
+            .unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::BackupDecryptionFailed));
+        assert!(dest.get_all().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_require_empty() {
+        let src = LoginDb::open_in_memory(Some("testing")).unwrap();
+        src.add(login("https://www.example.com", "a", "pw-a"))
+            .unwrap();
+        let buf = export(&src, "hunter2");
+        let err = src
+            .import_from_backup(buf.as_slice(), "hunter2", ImportMode::RequireEmpty)
+            .unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::NonEmptyTable));
+    }
+
+    #[test]
+    fn test_merge() {
+        let src = LoginDb::open_in_memory(Some("testing")).unwrap();
+        src.add(login("https://www.example.com", "a", "pw-a"))
+            .unwrap();
+        src.add(login("https://www.example2.com", "b", "pw-b"))
+            .unwrap();
+        let buf = export(&src, "hunter2");
+
+        let dest = LoginDb::open_in_memory(Some("testing")).unwrap();
+        // Same dedupe key as the first record, different password.
+        dest.add(login("https://www.example.com", "a", "other"))
+            .unwrap();
+        let metrics = dest
+            .import_from_backup(buf.as_slice(), "hunter2", ImportMode::Merge)
+            .unwrap();
+        assert_eq!(metrics.num_processed, 2);
+        assert_eq!(metrics.num_imported, 1);
+        assert_eq!(metrics.num_skipped, 1);
+        assert_eq!(dest.get_all().unwrap().len(), 2);
+
+        // Importing the same backup again into the same store is a no-op.
+        let metrics = dest
+            .import_from_backup(buf.as_slice(), "hunter2", ImportMode::Merge)
+            .unwrap();
+        assert_eq!(metrics.num_imported, 0);
+        assert_eq!(metrics.num_skipped, 2);
+    }
+
+    #[test]
+    fn test_failed_import_rolls_back() {
+        let src = LoginDb::open_in_memory(Some("testing")).unwrap();
+        src.add(login("https://www.example.com", "a", "pw-a"))
+            .unwrap();
+        src.add(login("https://www.example2.com", "b", "pw-b"))
+            .unwrap();
+        let buf = export(&src, "hunter2");
+        // Fails once the records have been inserted, but before the
+        // transaction is committed.
+        let break_change_log = |db: &LoginDb| {
+            db.execute_batch(
+                "CREATE TEMP TRIGGER broken_change_log BEFORE INSERT ON loginsChangeLog
+                 BEGIN SELECT RAISE(ABORT, 'broken'); END;",
+            )
+            .unwrap();
+        };
+
+        let dest = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let existing = dest
+            .add(login("https://www.example3.com", "c", "pw-c"))
+            .unwrap();
+        break_change_log(&dest);
+        dest.import_from_backup(buf.as_slice(), "hunter2", ImportMode::Merge)
+            .unwrap_err();
+        let guids = dest
+            .get_all()
+            .unwrap()
+            .into_iter()
+            .map(|login| login.guid)
+            .collect::<Vec<_>>();
+        assert_eq!(guids, vec![existing.guid]);
+
+        let dest = LoginDb::open_in_memory(Some("testing")).unwrap();
+        break_change_log(&dest);
+        dest.import_from_backup(buf.as_slice(), "hunter2", ImportMode::RequireEmpty)
+            .unwrap_err();
+        assert!(dest.get_all().unwrap().is_empty());
+    }
+
+    // Generated by version 1 of this format; checks we can still read it.
+    #[test]
+    fn test_v1_fixture() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let fixture = include_str!("../fixtures/backup_v1.json");
+        db.import_from_backup(
+            fixture.as_bytes(),
+            "correct horse",
+            ImportMode::RequireEmpty,
+        )
+        .unwrap();
+        let logins = db.get_all().unwrap();
+        assert_eq!(logins.len(), 1);
+        assert_eq!(logins[0].guid, "dummy_000001");
+        assert_eq!(logins[0].hostname, "https://www.example.com");
+        assert_eq!(logins[0].username, "fixture-user");
+        assert_eq!(logins[0].password, "fixture-password");
+    }
+}
@@ -0,0 +1,265 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Change tracking for embedders which mirror the logins into their own cache.
+//!
+//! Every mutating transaction bumps a global change counter and records the
+//! guids it touched in `loginsChangeLog` (see the [schema](crate::schema)
+//! docs). An embedder remembers the counter from its last call to
+//! `get_modified_since`, and passes it back to find out what changed.
+
+use crate::db::LoginDb;
+use crate::error::*;
+use crate::login::Login;
+use crate::schema;
+use rusqlite::named_params;
+use sql_support::ConnExt;
+use sync_guid::Guid;
+
+/// The records which changed since a given value of the change counter.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChangesSince {
+    /// The current state of records which were added or changed.
+    pub records: Vec<Login>,
+    /// The guids of records which were deleted.
+    pub deleted_guids: Vec<Guid>,
+    /// The counter to pass to the next call to `get_modified_since`.
+    pub new_counter: i64,
+}
+
+impl LoginDb {
+    /// Get the current value of the change counter. Embedders populating their
+    /// cache with `get_all` should fetch this in the same transaction.
+    pub fn get_change_counter(&self) -> Result<i64> {
+        Ok(self
+            .get_meta::<i64>(schema::CHANGE_COUNTER_META_KEY)?
+            .unwrap_or_default())
+    }
+
+    /// Get the records which were added, changed or deleted since the change
+    /// counter had the value `counter`.
+    pub fn get_modified_since(&self, counter: i64) -> Result<ChangesSince> {
+        lazy_static::lazy_static! {
+            static ref GET_MODIFIED_SQL: String = format!(
+                "SELECT {common_cols} FROM loginsL
+                 WHERE is_deleted = 0
+                   AND guid IN (SELECT guid FROM loginsChangeLog WHERE change_counter > :counter)
+                 UNION ALL
+                 SELECT {common_cols} FROM loginsM
+                 WHERE is_overridden = 0
+                   AND guid IN (SELECT guid FROM loginsChangeLog WHERE change_counter > :counter)",
+                common_cols = schema::COMMON_COLS
+            );
+        }
+        let tx = self.unchecked_transaction()?;
+        let new_counter = self.get_change_counter()?;
+        let records = {
+            let mut stmt = self.db.prepare_cached(&self.sql(&GET_MODIFIED_SQL))?;
+            let rows = stmt.query_and_then_named(named_params! { ":counter": counter }, |row| {
+                Login::from_row(row, self.encdec())
+            })?;
+            rows.collect::<Result<Vec<_>>>()?
+        };
+        let deleted_guids = {
+            let mut stmt = self.db.prepare_cached(&self.sql(
+                "SELECT guid FROM loginsChangeLog
+                 WHERE change_counter > :counter
+                   AND guid NOT IN (SELECT guid FROM loginsL WHERE is_deleted = 0)
+                   AND guid NOT IN (SELECT guid FROM loginsM WHERE is_overridden = 0)",
+            ))?;
+            let rows = stmt.query_and_then_named(named_params! { ":counter": counter }, |row| {
+                Ok::<_, Error>(row.get::<_, Guid>(0)?)
+            })?;
+            rows.collect::<Result<Vec<_>>>()?
+        };
+        tx.commit()?;
+        Ok(ChangesSince {
+            records,
+            deleted_guids,
+            new_counter,
+        })
+    }
+
+    /// Bump the change counter, and record that the given guids changed.
+    /// Must be called inside the transaction making the change.
+    pub(crate) fn note_changed<S: AsRef<str>>(&self, guids: &[S]) -> Result<()> {
+        if guids.is_empty() {
+            return Ok(());
+        }
+        let counter = self.get_change_counter()? + 1;
+        self.put_meta(schema::CHANGE_COUNTER_META_KEY, &counter)?;
+        for guid in guids {
+            self.execute_named_cached(
+                &self.sql(
+                    "REPLACE INTO loginsChangeLog (guid, change_counter) VALUES (:guid, :counter)",
+                ),
+                named_params! { ":guid": guid.as_ref(), ":counter": counter },
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The guids of all the records which currently exist.
+    pub(crate) fn get_all_guids(&self) -> Result<Vec<String>> {
+        let mut stmt = self.db.prepare_cached(&self.sql(
+            "SELECT guid FROM loginsL WHERE is_deleted = 0
+             UNION
+             SELECT guid FROM loginsM WHERE is_overridden = 0",
+        ))?;
+        let rows = stmt.query_and_then(rusqlite::NO_PARAMS, |row| {
+            Ok::<_, Error>(row.get::<_, String>(0)?)
+        })?;
+        rows.collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::LoginFixture;
+    use crate::LoginStore;
+    use std::collections::HashMap;
+    use sync15::{IncomingChangeset, Payload, ServerTimestamp, SyncEngine};
+
+    fn login(hostname: &str, password: &str) -> Login {
+        LoginFixture::builder()
+            .hostname(hostname)
+            .form_submit_url(hostname)
+            .username("user")
+            .password(password)
+            .build()
+    }
+
+    // A stand-in for an embedder's cache.
+    #[derive(Default)]
+    struct Mirror {
+        records: HashMap<Guid, Login>,
+        counter: i64,
+    }
+
+    impl Mirror {
+        fn catch_up(&mut self, db: &LoginDb) {
+            let changes = db.get_modified_since(self.counter).unwrap();
+            for guid in changes.deleted_guids {
+                self.records.remove(&guid);
+            }
+            for record in changes.records {
+                self.records.insert(record.guid.clone(), record);
+            }
+            self.counter = changes.new_counter;
+        }
+
+        fn assert_matches(&self, db: &LoginDb) {
+            let all = db.get_all().unwrap();
+            assert_eq!(self.records.len(), all.len());
+            for record in all {
+                assert_eq!(self.records[&record.guid], record);
+            }
+        }
+    }
+
+    #[test]
+    fn test_counter_bumps() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        assert_eq!(db.get_change_counter().unwrap(), 0);
+        let added = db
+            .add(login("https://www.example.com", "password"))
+            .unwrap();
+        assert_eq!(db.get_change_counter().unwrap(), 1);
+        db.touch(added.guid_str()).unwrap();
+        assert_eq!(db.get_change_counter().unwrap(), 2);
+
+        let changes = db.get_modified_since(1).unwrap();
+        assert_eq!(changes.new_counter, 2);
+        assert_eq!(changes.records.len(), 1);
+        assert!(changes.deleted_guids.is_empty());
+
+        let changes = db.get_modified_since(2).unwrap();
+        assert!(changes.records.is_empty());
+        assert!(changes.deleted_guids.is_empty());
+
+        db.wipe_local().unwrap();
+        assert_eq!(db.get_change_counter().unwrap(), 3);
+        let changes = db.get_modified_since(2).unwrap();
+        assert!(changes.records.is_empty());
+        assert_eq!(changes.deleted_guids, vec![added.guid]);
+    }
+
+    #[test]
+    fn test_deltas_reconstruct_state() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let mut mirror = Mirror::default();
+
+        let a = db.add(login("https://a.example.com", "a")).unwrap();
+        let b = db.add(login("https://b.example.com", "b")).unwrap();
+        let c = db.add(login("https://c.example.com", "c")).unwrap();
+        mirror.catch_up(&db);
+        mirror.assert_matches(&db);
+
+        db.update(Login {
+            password: "a2".into(),
+            ..a.clone()
+        })
+        .unwrap();
+        db.delete(b.guid_str()).unwrap();
+        mirror.catch_up(&db);
+        mirror.assert_matches(&db);
+
+        // Upload everything, then take a remote edit of `c`, a remote
+        // deletion of `a`, and a new remote record.
+        let engine = LoginStore::new(&db);
+        let mut telem = sync15::telemetry::Engine::new("passwords");
+        let outgoing = engine
+            .apply_incoming(
+                vec![IncomingChangeset::new("passwords", ServerTimestamp(1000))],
+                &mut telem,
+            )
+            .unwrap();
+        let guids = outgoing.changes.iter().map(|p| p.id.clone()).collect();
+        engine.sync_finished(ServerTimestamp(1000), guids).unwrap();
+        mirror.catch_up(&db);
+        mirror.assert_matches(&db);
+
+        let mut incoming = IncomingChangeset::new("passwords", ServerTimestamp(2000));
+        for payload in vec![
+            serde_json::json!({
+                "id": c.guid_str(),
+                "hostname": c.hostname,
+                "formSubmitURL": c.form_submit_url,
+                "username": c.username,
+                "password": "c2",
+                "timePasswordChanged": 2000,
+            }),
+            serde_json::json!({ "id": a.guid_str(), "deleted": true }),
+            serde_json::json!({
+                "id": "dummy_000001",
+                "hostname": "https://d.example.com",
+                "formSubmitURL": "https://d.example.com",
+                "username": "user",
+                "password": "d",
+            }),
+        ] {
+            incoming
+                .changes
+                .push((Payload::from_json(payload).unwrap(), ServerTimestamp(2000)));
+        }
+        let counter_before = db.get_change_counter().unwrap();
+        engine.apply_incoming(vec![incoming], &mut telem).unwrap();
+        assert!(db.get_change_counter().unwrap() > counter_before);
+        engine.sync_finished(ServerTimestamp(2000), vec![]).unwrap();
+
+        let changes = db.get_modified_since(mirror.counter).unwrap();
+        assert_eq!(changes.deleted_guids, vec![a.guid.clone()]);
+        assert_eq!(changes.records.len(), 2);
+        mirror.catch_up(&db);
+        mirror.assert_matches(&db);
+        assert_eq!(mirror.records[&c.guid].password, "c2");
+
+        db.add(login("https://e.example.com", "e")).unwrap();
+        db.wipe(&db.begin_interrupt_scope()).unwrap();
+        mirror.catch_up(&db);
+        mirror.assert_matches(&db);
+        assert!(mirror.records.is_empty());
+    }
+}
@@ -0,0 +1,278 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Repairing records whose local and mirror rows disagree.
+//!
+//! A record's row in `loginsM` (our copy of the server's) must be flagged
+//! `is_overridden` exactly when the record also has a row in `loginsL`,
+//! which is then the version the rest of the API sees. `update`, `delete`
+//! and sync keep the two in step inside a transaction, but if they ever
+//! don't (through a bug, or a future change which splits one of them up), a
+//! record disappears from `get_all`, or shows up twice. `repair_consistency`
+//! puts them back in step. It runs from `run_maintenance`, and when the
+//! database is opened, if `needs_consistency_repair` finds anything to
+//! repair.
+//!
+//! It also removes local tombstones marked `SyncStatus::New`. Deleting a
+//! record always marks its tombstone as changed, and so do `reset` and
+//! sync, so a new one is left over from a write which went wrong, and is
+//! for a record the server never had.
+
+use crate::db::LoginDb;
+use crate::error::*;
+use crate::login::SyncStatus;
+use rusqlite::NO_PARAMS;
+use serde_derive::*;
+use sql_support::ConnExt;
+
+/// What `repair_consistency` repaired.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RepairReport {
+    /// Mirror rows flagged as overridden with no local row, which hid the
+    /// record. The flag is cleared, so that the mirror's version shows.
+    pub overridden_without_local: usize,
+    /// Mirror rows not flagged as overridden despite a local row (or local
+    /// tombstone), which showed the record twice, or showed a deleted one.
+    /// The flag is set, so that only the local version shows.
+    pub local_without_override: usize,
+    /// Local tombstones marked as new, which should have been removed
+    /// altogether. They're removed.
+    pub new_tombstones_removed: usize,
+}
+
+impl RepairReport {
+    pub fn total(&self) -> usize {
+        self.overridden_without_local + self.local_without_override + self.new_tombstones_removed
+    }
+}
+
+// Mirror rows (in `loginsM`) which are hidden for no reason.
+const OVERRIDDEN_WITHOUT_LOCAL: &str =
+    "is_overridden = 1 AND guid NOT IN (SELECT guid FROM loginsL)";
+
+// Mirror rows (in `loginsM`) which should be hidden, but aren't.
+const LOCAL_WITHOUT_OVERRIDE: &str = "is_overridden = 0 AND guid IN (SELECT guid FROM loginsL)";
+
+lazy_static::lazy_static! {
+    // Local rows (in `loginsL`) which shouldn't be there. Only those without
+    // a mirror row, since removing one with a mirror row would bring back
+    // the mirror's version of a record which was deleted.
+    static ref NEW_TOMBSTONES: String = format!(
+        "is_deleted = 1 AND sync_status = {new} AND guid NOT IN (SELECT guid FROM loginsM)",
+        new = SyncStatus::New as u8
+    );
+}
+
+impl LoginDb {
+    /// Whether `repair_consistency` has anything to repair. This is a single
+    /// query, cheap enough to run whenever the database is opened.
+    pub fn needs_consistency_repair(&self) -> Result<bool> {
+        Ok(self.query_row(
+            &self.sql(&format!(
+                "SELECT EXISTS(SELECT 1 FROM loginsM WHERE {})
+                     OR EXISTS(SELECT 1 FROM loginsM WHERE {})
+                     OR EXISTS(SELECT 1 FROM loginsL WHERE {})",
+                OVERRIDDEN_WITHOUT_LOCAL, LOCAL_WITHOUT_OVERRIDE, &*NEW_TOMBSTONES
+            )),
+            NO_PARAMS,
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Put local and mirror rows which disagree back in step. See the
+    /// `consistency` module.
+    pub fn repair_consistency(&self) -> Result<RepairReport> {
+        let tx = self.unchecked_transaction()?;
+        let new_tombstones_removed = self.execute(
+            &self.sql(&format!("DELETE FROM loginsL WHERE {}", &*NEW_TOMBSTONES)),
+            NO_PARAMS,
+        )?;
+        let hidden = self.mirror_guids_where(OVERRIDDEN_WITHOUT_LOCAL)?;
+        self.execute(
+            &self.sql(&format!(
+                "UPDATE loginsM SET is_overridden = 0 WHERE {}",
+                OVERRIDDEN_WITHOUT_LOCAL
+            )),
+            NO_PARAMS,
+        )?;
+        let doubled = self.mirror_guids_where(LOCAL_WITHOUT_OVERRIDE)?;
+        self.execute(
+            &self.sql(&format!(
+                "UPDATE loginsM SET is_overridden = 1 WHERE {}",
+                LOCAL_WITHOUT_OVERRIDE
+            )),
+            NO_PARAMS,
+        )?;
+        // Both change which version of the record is visible.
+        self.note_changed(&[&hidden[..], &doubled[..]].concat())?;
+        tx.commit()?;
+
+        let report = RepairReport {
+            overridden_without_local: hidden.len(),
+            local_without_override: doubled.len(),
+            new_tombstones_removed,
+        };
+        if report.total() > 0 {
+            log::warn!("Repaired inconsistent records: {:?}", report);
+        }
+        Ok(report)
+    }
+
+    fn mirror_guids_where(&self, condition: &str) -> Result<Vec<String>> {
+        Ok(self.query_rows_and_then_named(
+            &self.sql(&format!("SELECT guid FROM loginsM WHERE {}", condition)),
+            &[],
+            |row| row.get(0),
+        )?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{sync_db, LoginFixture};
+    use rusqlite::named_params;
+    use sync15::{EngineSyncAssociation, ServerTimestamp};
+    use sync_guid::Guid;
+
+    fn set_overridden(db: &LoginDb, guid: &str, overridden: bool) {
+        db.execute_named(
+            "UPDATE loginsM SET is_overridden = :overridden WHERE guid = :guid",
+            named_params! { ":overridden": overridden, ":guid": guid },
+        )
+        .unwrap();
+    }
+
+    fn visible_guids(db: &LoginDb) -> Vec<String> {
+        let mut guids: Vec<String> = db
+            .get_all()
+            .unwrap()
+            .into_iter()
+            .map(|login| login.guid.into_string())
+            .collect();
+        guids.sort();
+        guids
+    }
+
+    // Sets up one record in each broken state, along with one which is fine.
+    fn broken_db(db: &LoginDb) {
+        for guid in &["hidden_0001", "doubled_001", "deleted_001", "fine_000001"] {
+            db.add(LoginFixture::builder().guid(*guid).username(*guid).build())
+                .unwrap();
+        }
+        sync_db(db, vec![], ServerTimestamp(1000));
+        db.add(
+            LoginFixture::builder()
+                .guid("new_0000001")
+                .username("new_0000001")
+                .build(),
+        )
+        .unwrap();
+
+        // A mirror row which is overridden by nothing.
+        set_overridden(db, "hidden_0001", true);
+        // A local change whose mirror row still shows.
+        db.touch("doubled_001").unwrap();
+        set_overridden(db, "doubled_001", false);
+        // A local deletion whose mirror row still shows.
+        db.delete("deleted_001").unwrap();
+        set_overridden(db, "deleted_001", false);
+        // A tombstone for a record which was never uploaded.
+        db.execute(
+            "UPDATE loginsL SET is_deleted = 1 WHERE guid = 'new_0000001'",
+            NO_PARAMS,
+        )
+        .unwrap();
+    }
+
+    fn has_local_row(db: &LoginDb, guid: &str) -> bool {
+        db.query_row_named(
+            "SELECT EXISTS(SELECT 1 FROM loginsL WHERE guid = :guid)",
+            named_params! { ":guid": guid },
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_repair_consistency() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        assert!(!db.needs_consistency_repair().unwrap());
+        broken_db(&db);
+        assert!(db.needs_consistency_repair().unwrap());
+        assert_eq!(
+            visible_guids(&db),
+            vec!["deleted_001", "doubled_001", "doubled_001", "fine_000001"]
+        );
+
+        let counter = db.get_change_counter().unwrap();
+        assert_eq!(
+            db.repair_consistency().unwrap(),
+            RepairReport {
+                overridden_without_local: 1,
+                local_without_override: 2,
+                new_tombstones_removed: 1,
+            }
+        );
+        assert!(!has_local_row(&db, "new_0000001"));
+        assert!(!db.needs_consistency_repair().unwrap());
+        assert_eq!(
+            visible_guids(&db),
+            vec!["doubled_001", "fine_000001", "hidden_0001"]
+        );
+        let changes = db.get_modified_since(counter).unwrap();
+        let mut changed: Vec<_> = changes
+            .records
+            .into_iter()
+            .map(|login| login.guid.into_string())
+            .collect();
+        changed.sort();
+        assert_eq!(changed, vec!["doubled_001", "hidden_0001"]);
+        assert_eq!(changes.deleted_guids, vec![Guid::from("deleted_001")]);
+
+        // There's nothing left to do.
+        assert_eq!(db.repair_consistency().unwrap(), RepairReport::default());
+    }
+
+    #[test]
+    fn test_reset_tombstones_kept() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let login = db.add(LoginFixture::builder().build()).unwrap();
+        sync_db(&db, vec![], ServerTimestamp(1000));
+        db.delete(login.guid_str()).unwrap();
+
+        // A reset leaves the deletion to be uploaded again, and it isn't
+        // mistaken for a new tombstone.
+        db.reset(&EngineSyncAssociation::Disconnected).unwrap();
+        assert!(!db.needs_consistency_repair().unwrap());
+        assert_eq!(db.repair_consistency().unwrap(), RepairReport::default());
+        let (outgoing, _) = sync_db(&db, vec![], ServerTimestamp(2000));
+        assert!(outgoing
+            .changes
+            .iter()
+            .any(|payload| payload.id == login.guid && payload.deleted));
+    }
+
+    #[test]
+    fn test_repair_on_open() {
+        let dir = tempdir::TempDir::new("repair_on_open").unwrap();
+        let path = dir.path().join("logins.sqlite");
+        broken_db(&LoginDb::open(&path, Some("secret")).unwrap());
+
+        let db = LoginDb::open(&path, Some("secret")).unwrap();
+        assert!(!db.needs_consistency_repair().unwrap());
+        assert_eq!(
+            visible_guids(&db),
+            vec!["doubled_001", "fine_000001", "hidden_0001"]
+        );
+    }
+
+    #[test]
+    fn test_repair_in_maintenance() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        broken_db(&db);
+        db.run_maintenance().unwrap();
+        assert!(!db.needs_consistency_repair().unwrap());
+    }
+}
@@ -2,28 +2,38 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use crate::annotations;
+use crate::encryption::{self, EncryptorDecryptor, NoopEncryptor};
 use crate::error::*;
-use crate::login::{LocalLogin, Login, MirrorLogin, SyncLoginData, SyncStatus};
-use crate::schema;
-use crate::update_plan::UpdatePlan;
+use crate::host_cap::DEFAULT_MAX_RECORDS_PER_HOST;
+use crate::login::{change_flags, LocalLogin, Login, MirrorLogin, SyncLoginData, SyncStatus};
+use crate::op_stats::{DebugOptions, OpStats};
+use crate::schema::{self, LoginParams, TableNames, Write};
+use crate::timestamps;
+use crate::tombstone_retention::{AGED_OUT_TOMBSTONES_SQL, DEFAULT_TOMBSTONE_RETENTION};
+use crate::unknown_fields;
+use crate::update_plan::{TombstonePolicy, UpdatePlan};
 use crate::util;
 use lazy_static::lazy_static;
 use rusqlite::{
     named_params,
     types::{FromSql, ToSql},
-    Connection, OpenFlags, NO_PARAMS,
+    Connection, OpenFlags, Row, NO_PARAMS,
 };
 use serde_derive::*;
 use sql_support::{self, ConnExt};
 use sql_support::{SqlInterruptHandle, SqlInterruptScope};
-use std::collections::HashSet;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
 use std::ops::Deref;
 use std::path::Path;
 use std::sync::{atomic::AtomicUsize, Arc};
 use std::time::{Duration, Instant, SystemTime};
 use sync15::{
-    extract_v1_state, telemetry, CollSyncIds, CollectionRequest, EngineSyncAssociation,
-    IncomingChangeset, OutgoingChangeset, Payload, ServerTimestamp, SyncEngine,
+    telemetry, CollSyncIds, CollectionRequest, EngineSyncAssociation, IncomingChangeset,
+    OutgoingChangeset, Payload, ServerTimestamp, SyncEngine,
 };
 use sync_guid::Guid;
 use url::{Host, Url};
@@ -37,6 +47,13 @@ pub struct MigrationPhaseMetrics {
     errors: Vec<String>,
 }
 
+impl MigrationPhaseMetrics {
+    #[cfg(test)]
+    pub(crate) fn errors(&self) -> &[String] {
+        &self.errors
+    }
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
 pub struct MigrationMetrics {
     fixup_phase: MigrationPhaseMetrics,
@@ -46,11 +63,104 @@ pub struct MigrationMetrics {
     num_failed: u64,
     total_duration: u128,
     errors: Vec<String>,
+    /// How many timestamps had to be repaired. See the `timestamps` module.
+    #[serde(default)]
+    num_fixed_timestamps: u64,
+}
+
+impl MigrationMetrics {
+    #[cfg(test)]
+    pub(crate) fn insert_phase(&self) -> &MigrationPhaseMetrics {
+        &self.insert_phase
+    }
+
+    pub(crate) fn num_processed(&self) -> u64 {
+        self.num_processed
+    }
+
+    pub(crate) fn num_succeeded(&self) -> u64 {
+        self.num_succeeded
+    }
+
+    pub(crate) fn num_failed(&self) -> u64 {
+        self.num_failed
+    }
+
+    pub(crate) fn errors(&self) -> &[String] {
+        &self.errors
+    }
+
+    pub(crate) fn num_fixed_timestamps(&self) -> u64 {
+        self.num_fixed_timestamps
+    }
+}
+
+// Recorded when we apply incoming records, and cleared once the sync finishes.
+// If it's still present at the start of the next sync, the last one was
+// interrupted.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct SyncInProgress {
+    // The timestamp of the incoming changeset, in milliseconds.
+    timestamp: i64,
+    // The guids of the records we changed or were uploading.
+    guids: Vec<String>,
+    // While incoming records are being applied, how many have been, in
+    // order of their guids. `None` once they all have.
+    #[serde(default)]
+    applied: Option<usize>,
+}
+
+/// How many incoming records are applied in each transaction. Each batch is
+/// committed as it's finished, so that a sync which is interrupted can carry
+/// on from where it got to.
+pub(crate) const APPLY_BATCH_SIZE: usize = 500;
+
+impl SyncInProgress {
+    fn new(timestamp: ServerTimestamp) -> Self {
+        SyncInProgress {
+            timestamp: timestamp.as_millis(),
+            guids: vec![],
+            applied: None,
+        }
+    }
+}
+
+// What `mark_as_synchronized` found which it didn't expect.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct SyncedReport {
+    // Guids the upload reported which had no local record.
+    pub not_found: Vec<Guid>,
+    // Guids whose local record changed while it was being uploaded. These are
+    // left to be uploaded again.
+    pub changed_during_upload: Vec<Guid>,
 }
 
 pub struct LoginDb {
     pub db: Connection,
     interrupt_counter: Arc<AtomicUsize>,
+    // Ids passed to `queue_touch` which haven't been flushed yet.
+    queued_touches: RefCell<Vec<String>>,
+    // See `set_max_db_size_bytes`.
+    pub(crate) max_db_size: Cell<Option<u64>>,
+    // See `set_max_records_per_host`.
+    pub(crate) max_records_per_host: Cell<Option<usize>>,
+    // See `set_scrub_mirror_on_delete`.
+    scrub_mirror_on_delete: Cell<bool>,
+    // See `set_tombstone_policy`.
+    tombstone_policy: Cell<TombstonePolicy>,
+    // See `set_tombstone_retention`.
+    pub(crate) tombstone_retention: Cell<Duration>,
+    // `APPLY_BATCH_SIZE`, except in tests.
+    pub(crate) apply_batch_size: Cell<usize>,
+    // See `open_with_encryptor`.
+    encdec: Arc<dyn EncryptorDecryptor>,
+    // See `attach_to_connection`.
+    tables: TableNames,
+    // Whether the connection is the embedder's, from `attach_to_connection`.
+    pub(crate) attached: bool,
+    // See `set_debug_options`.
+    pub(crate) debug_options: Cell<DebugOptions>,
+    pub(crate) recent_op_stats: RefCell<VecDeque<OpStats>>,
 }
 
 impl LoginDb {
@@ -59,11 +169,34 @@ impl LoginDb {
         encryption_key: Option<&str>,
         salt: Option<&str>,
     ) -> Result<Self> {
-        #[cfg(test)]
-        {
-            util::init_test_logging();
-        }
+        Self::with_connection_and_encryptor(db, encryption_key, salt, None)
+    }
+
+    /// Use our tables in a database which the embedder manages, and may
+    /// share with other components. Unlike `with_connection`, this doesn't
+    /// set the key or any pragmas, which are up to the embedder.
+    ///
+    /// If `table_prefix` is given, it's added to the name of every table and
+    /// index we create, so that they can't collide with anyone else's, and
+    /// the schema version is kept in our own tables instead of
+    /// `PRAGMA user_version`. It must be letters, digits and underscores, and
+    /// the same every time the database is opened.
+    pub fn attach_to_connection(db: Connection, table_prefix: Option<&str>) -> Result<Self> {
+        let tables = match table_prefix {
+            Some(prefix) => TableNames::with_prefix(prefix)?,
+            None => TableNames::default(),
+        };
+        let mut logins = Self::init(db, tables, None)?;
+        logins.attached = true;
+        Ok(logins)
+    }
 
+    fn with_connection_and_encryptor(
+        db: Connection,
+        encryption_key: Option<&str>,
+        salt: Option<&str>,
+        encdec: Option<Arc<dyn EncryptorDecryptor>>,
+    ) -> Result<Self> {
         if let Some(key) = encryption_key {
             db.set_pragma("key", key)?
                 .set_pragma("secure_delete", true)?;
@@ -84,13 +217,59 @@ impl LoginDb {
         // do this on Android, or allow caller to configure it.
         db.set_pragma("temp_store", 2)?;
 
+        // Allow `run_maintenance` to reclaim free space with
+        // `incremental_vacuum`. This only takes effect for new databases.
+        db.set_pragma("auto_vacuum", 2)?;
+
+        Self::init(db, TableNames::default(), encdec)
+    }
+
+    // Creates or upgrades the schema of a connection which is ready to use.
+    fn init(
+        db: Connection,
+        tables: TableNames,
+        encdec: Option<Arc<dyn EncryptorDecryptor>>,
+    ) -> Result<Self> {
+        #[cfg(test)]
+        {
+            util::init_test_logging();
+        }
+
         let mut logins = Self {
             db,
             interrupt_counter: Arc::new(AtomicUsize::new(0)),
+            queued_touches: RefCell::default(),
+            max_db_size: Cell::default(),
+            max_records_per_host: Cell::new(Some(DEFAULT_MAX_RECORDS_PER_HOST)),
+            scrub_mirror_on_delete: Cell::new(true),
+            tombstone_policy: Cell::default(),
+            tombstone_retention: Cell::new(DEFAULT_TOMBSTONE_RETENTION),
+            apply_batch_size: Cell::new(APPLY_BATCH_SIZE),
+            encdec: Arc::new(NoopEncryptor),
+            tables,
+            attached: false,
+            debug_options: Cell::default(),
+            recent_op_stats: RefCell::default(),
         };
         let tx = logins.db.transaction()?;
-        schema::init(&tx)?;
+        schema::init(&tx, &logins.tables)?;
         tx.commit()?;
+        match encdec {
+            Some(encdec) => {
+                logins.encdec = encdec;
+                logins.encrypt_plaintext_passwords()?;
+            }
+            None => {
+                if logins.passwords_are_encrypted()? {
+                    throw!(ErrorKind::DecryptionFailed(
+                        "The passwords are encrypted, but no encryptor was given".into()
+                    ));
+                }
+            }
+        }
+        if logins.needs_consistency_repair()? {
+            logins.repair_consistency()?;
+        }
         Ok(logins)
     }
 
@@ -111,6 +290,93 @@ impl LoginDb {
         Self::with_connection(Connection::open_in_memory()?, encryption_key, None)
     }
 
+    /// Like `open`, but with the passwords in the database encrypted by
+    /// `encdec` as well as by SQLCipher. See the `encryption` module.
+    ///
+    /// Passwords already in the database are encrypted in place the first
+    /// time it's opened like this. After that, it must always be opened
+    /// with the same encryptor: `open` fails with `DecryptionFailed`, and
+    /// so do reads with a different encryptor.
+    pub fn open_with_encryptor(
+        path: impl AsRef<Path>,
+        encryption_key: Option<&str>,
+        encdec: Arc<dyn EncryptorDecryptor>,
+    ) -> Result<Self> {
+        Self::with_connection_and_encryptor(
+            Connection::open(path)?,
+            encryption_key,
+            None,
+            Some(encdec),
+        )
+    }
+
+    #[cfg(test)]
+    pub(crate) fn open_in_memory_with_encryptor(
+        encdec: Arc<dyn EncryptorDecryptor>,
+    ) -> Result<Self> {
+        Self::with_connection_and_encryptor(
+            Connection::open_in_memory()?,
+            Some("testing"),
+            None,
+            Some(encdec),
+        )
+    }
+
+    pub(crate) fn encdec(&self) -> &dyn EncryptorDecryptor {
+        &*self.encdec
+    }
+
+    /// `sql`, with our table names resolved. Every statement which uses our
+    /// tables must go through this. See `TableNames`.
+    pub(crate) fn sql<'a>(&self, sql: &'a str) -> Cow<'a, str> {
+        self.tables.resolve(sql)
+    }
+
+    fn passwords_are_encrypted(&self) -> Result<bool> {
+        Ok(self
+            .get_meta::<bool>(schema::PASSWORDS_ENCRYPTED_META_KEY)?
+            .unwrap_or(false))
+    }
+
+    // Encrypts the passwords in the database with its encryptor, unless
+    // that's already been done. There's no way back.
+    fn encrypt_plaintext_passwords(&self) -> Result<()> {
+        if self.passwords_are_encrypted()? {
+            return Ok(());
+        }
+        let tx = self.unchecked_transaction()?;
+        for table in &["loginsL", "loginsM"] {
+            // Unreadable passwords are left for `quarantine_invalid_local_rows`.
+            let passwords: Vec<(i64, Option<String>)> = self.query_rows_and_then_named(
+                &self.sql(&format!(
+                    "SELECT id, password FROM {} WHERE password <> ''",
+                    table
+                )),
+                &[],
+                |row| Ok::<_, Error>((row.get(0)?, row.get(1).ok())),
+            )?;
+            for (id, password) in passwords {
+                let password = match password {
+                    Some(password) => password,
+                    None => continue,
+                };
+                self.execute_named_cached(
+                    &self.sql(&format!(
+                        "UPDATE {} SET password = :password WHERE id = :id",
+                        table
+                    )),
+                    named_params! {
+                        ":password": self.encdec.encrypt(&password),
+                        ":id": id,
+                    },
+                )?;
+            }
+        }
+        self.put_meta(schema::PASSWORDS_ENCRYPTED_META_KEY, &true)?;
+        tx.commit()?;
+        Ok(())
+    }
+
     /// Opens an existing database and fetches the salt.
     /// This method is used by iOS consumers as part as the migration plan to store
     /// the salt outside of the sqlite db headers.
@@ -174,6 +440,52 @@ impl LoginDb {
     }
 }
 
+// The parts of an origin `get_for_site` compares. The port is only set if it
+// isn't the scheme's default, so that `http://example.com` matches
+// `https://example.com`.
+#[derive(PartialEq)]
+struct SiteOrigin {
+    scheme: String,
+    host: String,
+    port: Option<u16>,
+}
+
+impl SiteOrigin {
+    fn parse(origin: &str) -> Option<Self> {
+        let url = Url::parse(origin).ok()?;
+        Some(Self {
+            scheme: url.scheme().to_owned(),
+            host: url.host_str()?.to_owned(),
+            port: url.port(),
+        })
+    }
+
+    // Whether a login saved for `self` may be offered on `other`, because
+    // `other` is the `https` version of `self`.
+    fn upgrades_to(&self, other: &SiteOrigin) -> bool {
+        self.scheme == "http"
+            && other.scheme == "https"
+            && self.host == other.host
+            && self.port == other.port
+    }
+}
+
+// Clears the same fields in the mirror as `delete` does in the local table.
+// See `LoginDb::set_scrub_mirror_on_delete`.
+// The unknown fields go too, since we can't tell what's in them.
+const SCRUB_MIRROR_SQL: &str =
+    "UPDATE loginsM SET password = '', hostname = '', username = '', unknown_fields = NULL";
+
+// The mirror's copy of a deleted record, without the sensitive fields.
+fn scrubbed(login: Login) -> Login {
+    Login {
+        password: String::new(),
+        hostname: String::new(),
+        username: String::new(),
+        ..login
+    }
+}
+
 // Checks if the provided string is a 32 len hex string.
 fn ensure_valid_salt(salt: &str) -> Result<()> {
     let is_valid_hex_character = |c: &u8| {
@@ -188,7 +500,7 @@ fn ensure_valid_salt(salt: &str) -> Result<()> {
     Err(ErrorKind::InvalidSalt.into())
 }
 
-fn sqlcipher_3_compat(conn: &Connection) -> Result<()> {
+pub(crate) fn sqlcipher_3_compat(conn: &Connection) -> Result<()> {
     // SQLcipher pre-4.0.0 compatibility. Using SHA1 still
     // is less than ideal, but should be fine. Real uses of
     // this (lockwise, etc) use a real random string for the
@@ -201,6 +513,65 @@ fn sqlcipher_3_compat(conn: &Connection) -> Result<()> {
     Ok(())
 }
 
+// Whether a row has the same target as the login in `:http_realm` and
+// `:form_submit`. As on Desktop, an empty formSubmitURL matches any form
+// target, and a scheme-relative one matches the same origin over either
+// scheme.
+const TARGET_MATCHES_SQL: &str = "(
+    httpRealm = :http_realm
+    OR formSubmitURL = :form_submit
+    OR (formSubmitURL = '' AND :form_submit IS NOT NULL)
+    OR (:form_submit = '' AND formSubmitURL IS NOT NULL)
+    OR formSubmitURL IN ('http:' || :form_submit, 'https:' || :form_submit)
+    OR :form_submit IN ('http:' || formSubmitURL, 'https:' || formSubmitURL)
+)";
+
+// The payload to upload for a row of `OUTGOING_ROWS_SQL`. This fails for rows which
+// break the schema's rules in ways SQLite doesn't enforce, such as text
+// which isn't valid UTF-8.
+pub(crate) fn outgoing_payload(row: &Row<'_>, encdec: &dyn EncryptorDecryptor) -> Result<Payload> {
+    // Taken from iOS. Arbitrarily large, so that clients that want to
+    // process deletions first can; for us it doesn't matter.
+    const TOMBSTONE_SORTINDEX: i32 = 5_000_000;
+    const DEFAULT_SORTINDEX: i32 = 1;
+    let payload = record_payload(row, encdec)?;
+    Ok(if payload.deleted {
+        payload.with_sortindex(TOMBSTONE_SORTINDEX)
+    } else {
+        payload.with_sortindex(DEFAULT_SORTINDEX)
+    })
+}
+
+// Like `outgoing_payload`, but without the sortindex, which only means
+// something to the server. Reads the `guid`, `is_deleted` and
+// `unknown_fields` columns, as well as the `COMMON_COLS`.
+pub(crate) fn record_payload(row: &Row<'_>, encdec: &dyn EncryptorDecryptor) -> Result<Payload> {
+    Ok(if row.get::<_, bool>("is_deleted")? {
+        Payload::new_tombstone(row.get::<_, String>("guid")?)
+    } else {
+        let login = Login::from_row(row, encdec)?;
+        let unknown_fields: Option<String> = row.get("unknown_fields")?;
+        unknown_fields::reattach(Payload::from_record(login)?, unknown_fields.as_deref())
+    })
+}
+
+// The rows of `loginsL` which `outgoing_payload` reads, along with the unknown
+// fields the server's copy had.
+pub(crate) const OUTGOING_ROWS_SQL: &str = "
+    SELECT l.*, CAST(l.guid AS BLOB) AS guid_bytes, m.unknown_fields
+    FROM loginsL l
+    LEFT JOIN loginsM m ON m.guid = l.guid";
+
+// The guid of a row selected with `CAST(guid AS BLOB) AS guid_bytes`, which
+// works even when `outgoing_payload` can't read the row. Invalid UTF-8 is
+// replaced, so this is only good for reporting the row.
+pub(crate) fn raw_guid(row: &Row<'_>) -> Result<Guid> {
+    let bytes: Vec<u8> = row.get("guid_bytes")?;
+    Ok(Guid::from_string(
+        String::from_utf8_lossy(&bytes).into_owned(),
+    ))
+}
+
 impl ConnExt for LoginDb {
     #[inline]
     fn conn(&self) -> &Connection {
@@ -219,52 +590,207 @@ impl Deref for LoginDb {
 // login specific stuff.
 
 impl LoginDb {
-    fn mark_as_synchronized(
+    pub(crate) fn mark_as_synchronized(
         &self,
         guids: &[&str],
         ts: ServerTimestamp,
         scope: &SqlInterruptScope,
-    ) -> Result<()> {
+    ) -> Result<SyncedReport> {
+        let tx = self.unchecked_transaction()?;
+        let mut report = SyncedReport::default();
+        sql_support::each_chunk(guids, |chunk, _| -> Result<()> {
+            let local =
+                self.query_chunk_guids("SELECT guid FROM loginsL WHERE guid IN ({vars})", chunk)?;
+            let changed = self.query_chunk_guids(
+                "SELECT p.guid FROM loginsPendingUpload p
+                 JOIN loginsL l ON l.guid = p.guid
+                 WHERE p.guid IN ({vars})
+                   AND p.change_counter <> COALESCE(
+                       (SELECT change_counter FROM loginsChangeLog c WHERE c.guid = p.guid),
+                       0
+                   )",
+                chunk,
+            )?;
+            let unchanged = chunk
+                .iter()
+                .filter(|guid| !changed.contains(**guid))
+                .copied()
+                .collect::<Vec<_>>();
+            self.move_local_to_mirror(&unchanged, ts, scope)?;
+            let changed = changed.iter().map(String::as_str).collect::<Vec<_>>();
+            self.move_uploaded_to_mirror(&changed, ts, scope)?;
+
+            report.not_found.extend(
+                chunk
+                    .iter()
+                    .filter(|guid| !local.contains(**guid))
+                    .map(|guid| Guid::from(*guid)),
+            );
+            report
+                .changed_during_upload
+                .extend(changed.into_iter().map(Guid::from));
+            Ok(())
+        })?;
+        self.execute(&self.sql("DELETE FROM loginsPendingUpload"), NO_PARAMS)?;
+        self.set_last_sync(ts)?;
+        self.delete_meta(schema::SYNC_IN_PROGRESS_META_KEY)?;
+        tx.commit()?;
+        Ok(report)
+    }
+
+    // Runs `sql`, with `{vars}` replaced by a placeholder for each guid in
+    // `chunk`, and returns the guids in its first column.
+    fn query_chunk_guids(&self, sql: &str, chunk: &[&str]) -> Result<HashSet<String>> {
+        let sql = self
+            .sql(sql)
+            .replace("{vars}", &sql_support::repeat_sql_vars(chunk.len()));
+        let mut stmt = self.db.prepare(&sql)?;
+        let guids = stmt.query_map(chunk, |row| row.get::<_, String>(0))?;
+        Ok(guids.collect::<rusqlite::Result<_>>()?)
+    }
+
+    // Remember what we're about to upload for each of `guids`, so that
+    // `mark_as_synchronized` can tell if it changes before the upload is
+    // done. See the `loginsPendingUpload` docs in the schema.
+    fn record_pending_upload(&self, guids: &[&str]) -> Result<()> {
         let tx = self.unchecked_transaction()?;
+        self.execute(&self.sql("DELETE FROM loginsPendingUpload"), NO_PARAMS)?;
         sql_support::each_chunk(guids, |chunk, _| -> Result<()> {
             self.db.execute(
-                &format!(
-                    "DELETE FROM loginsM WHERE guid IN ({vars})",
+                &self.sql(&format!(
+                    "INSERT INTO loginsPendingUpload ({common_cols}, is_deleted, change_counter)
+                     SELECT {common_cols}, is_deleted,
+                            COALESCE(
+                                (SELECT change_counter FROM loginsChangeLog c
+                                 WHERE c.guid = loginsL.guid),
+                                0
+                            )
+                     FROM loginsL
+                     WHERE guid IN ({vars})",
+                    common_cols = schema::COMMON_COLS,
                     vars = sql_support::repeat_sql_vars(chunk.len())
-                ),
+                )),
+                chunk,
+            )?;
+            Ok(())
+        })?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    // Like `move_local_to_mirror`, but for records which changed locally while
+    // we were uploading them: the mirror gets the version we uploaded, from
+    // `loginsPendingUpload`, and the local record stays, to be uploaded next
+    // time.
+    fn move_uploaded_to_mirror(
+        &self,
+        guids: &[&str],
+        ts: ServerTimestamp,
+        scope: &SqlInterruptScope,
+    ) -> Result<()> {
+        sql_support::each_chunk(guids, |chunk, _| -> Result<()> {
+            self.db.execute(
+                &self.sql(&format!(
+                    "INSERT OR REPLACE INTO loginsM (
+                         {common_cols}, is_overridden, server_modified, unknown_fields
+                     )
+                     SELECT {common_cols}, 1, {modified_ms_i64},
+                            (SELECT unknown_fields FROM loginsM
+                             WHERE loginsM.guid = loginsPendingUpload.guid)
+                     FROM loginsPendingUpload
+                     WHERE is_deleted = 0 AND guid IN ({vars})",
+                    common_cols = schema::COMMON_COLS,
+                    modified_ms_i64 = ts.as_millis() as i64,
+                    vars = sql_support::repeat_sql_vars(chunk.len())
+                )),
                 chunk,
             )?;
             scope.err_if_interrupted()?;
 
             self.db.execute(
-                &format!(
-                    "INSERT OR IGNORE INTO loginsM (
-                         {common_cols}, is_overridden, server_modified
+                &self.sql(&format!(
+                    "DELETE FROM loginsM
+                     WHERE guid IN ({vars})
+                       AND guid IN (SELECT guid FROM loginsPendingUpload WHERE is_deleted = 1)",
+                    vars = sql_support::repeat_sql_vars(chunk.len())
+                )),
+                chunk,
+            )?;
+            scope.err_if_interrupted()?;
+
+            // The local record is now a change to what the server has, or, if
+            // we uploaded a tombstone, new to it. Local tombstones are always
+            // changes, though, so that they aren't taken for ones which should
+            // have been removed (see the `consistency` module).
+            self.db.execute(
+                &self.sql(&format!(
+                    "UPDATE loginsL
+                     SET sync_status = CASE WHEN is_deleted = 1
+                                              OR guid IN (SELECT guid FROM loginsM)
+                                            THEN {changed}
+                                            ELSE {new}
+                                       END
+                     WHERE guid IN ({vars})",
+                    changed = SyncStatus::Changed as u8,
+                    new = SyncStatus::New as u8,
+                    vars = sql_support::repeat_sql_vars(chunk.len())
+                )),
+                chunk,
+            )?;
+            scope.err_if_interrupted()?;
+            Ok(())
+        })
+    }
+
+    // Replace the mirror with the local record for each guid, and drop the
+    // local record. Guids which only have a mirror record (say, because
+    // they've already been moved, before we crashed) are left alone. The
+    // mirror's unknown fields were uploaded with the record, so they're kept.
+    fn move_local_to_mirror(
+        &self,
+        guids: &[&str],
+        ts: ServerTimestamp,
+        scope: &SqlInterruptScope,
+    ) -> Result<()> {
+        sql_support::each_chunk(guids, |chunk, _| -> Result<()> {
+            self.db.execute(
+                &self.sql(&format!(
+                    "INSERT OR REPLACE INTO loginsM (
+                         {common_cols}, is_overridden, server_modified, unknown_fields
                      )
-                     SELECT {common_cols}, 0, {modified_ms_i64}
+                     SELECT {common_cols}, 0, {modified_ms_i64},
+                            (SELECT unknown_fields FROM loginsM WHERE loginsM.guid = loginsL.guid)
                      FROM loginsL
                      WHERE is_deleted = 0 AND guid IN ({vars})",
                     common_cols = schema::COMMON_COLS,
                     modified_ms_i64 = ts.as_millis() as i64,
                     vars = sql_support::repeat_sql_vars(chunk.len())
-                ),
+                )),
                 chunk,
             )?;
             scope.err_if_interrupted()?;
 
             self.db.execute(
-                &format!(
+                &self.sql(&format!(
+                    "DELETE FROM loginsM
+                     WHERE guid IN ({vars})
+                       AND guid IN (SELECT guid FROM loginsL WHERE is_deleted = 1)",
+                    vars = sql_support::repeat_sql_vars(chunk.len())
+                )),
+                chunk,
+            )?;
+            scope.err_if_interrupted()?;
+
+            self.db.execute(
+                &self.sql(&format!(
                     "DELETE FROM loginsL WHERE guid IN ({vars})",
                     vars = sql_support::repeat_sql_vars(chunk.len())
-                ),
+                )),
                 chunk,
             )?;
             scope.err_if_interrupted()?;
             Ok(())
-        })?;
-        self.set_last_sync(ts)?;
-        tx.commit()?;
-        Ok(())
+        })
     }
 
     // Fetch all the data for the provided IDs.
@@ -276,30 +802,63 @@ impl LoginDb {
         telem: &mut telemetry::EngineIncoming,
         scope: &SqlInterruptScope,
     ) -> Result<Vec<SyncLoginData>> {
-        let mut sync_data = Vec::with_capacity(records.len());
-        {
-            let mut seen_ids: HashSet<Guid> = HashSet::with_capacity(records.len());
-            for incoming in records.iter() {
-                if seen_ids.contains(&incoming.0.id) {
-                    throw!(ErrorKind::DuplicateGuid(incoming.0.id.to_string()))
-                }
-                seen_ids.insert(incoming.0.id.clone());
-                match SyncLoginData::from_payload(incoming.0.clone(), incoming.1) {
-                    Ok(v) => sync_data.push(v),
-                    Err(e) => {
-                        log::error!("Failed to deserialize record {:?}: {}", incoming.0.id, e);
-                        // Ideally we'd track new_failed, but it's unclear how
-                        // much value it has.
-                        telem.failed(1);
+        // The server can send the same record more than once, say, across
+        // batches. Only the newest copy is kept, in the place of the first.
+        let mut deduped: Vec<&(sync15::Payload, ServerTimestamp)> =
+            Vec::with_capacity(records.len());
+        let mut seen_ids: HashMap<&Guid, usize> = HashMap::with_capacity(records.len());
+        for incoming in records.iter() {
+            match seen_ids.get(&incoming.0.id) {
+                Some(&i) => {
+                    log::warn!("Incoming record {:?} is duplicated", incoming.0.id);
+                    telem.deduplicated(1);
+                    if incoming.1 >= deduped[i].1 {
+                        deduped[i] = incoming;
                     }
                 }
+                None => {
+                    seen_ids.insert(&incoming.0.id, deduped.len());
+                    deduped.push(incoming);
+                }
+            }
+        }
+        let mut sync_data = Vec::with_capacity(deduped.len());
+        for incoming in deduped {
+            match SyncLoginData::from_payload(incoming.0.clone(), incoming.1) {
+                Ok(v) => sync_data.push(v),
+                Err(e) => {
+                    log::error!("Failed to deserialize record {:?}: {}", incoming.0.id, e);
+                    // Ideally we'd track new_failed, but it's unclear how
+                    // much value it has.
+                    telem.failed(1);
+                }
             }
         }
         scope.err_if_interrupted()?;
 
-        sql_support::each_chunk_mapped(
-            &records,
-            |r| r.0.id.as_str(),
+        self.fetch_local_and_mirror(
+            &mut sync_data,
+            sql_support::default_max_variable_number(),
+            scope,
+        )?;
+        Ok(sync_data)
+    }
+
+    // Fills in the local and mirror records for each item in `sync_data`,
+    // `chunk_size` items per query. Note that this has to chunk `sync_data`
+    // itself, rather than the incoming records, since the records which
+    // failed to deserialize aren't in it, and the indexes must line up.
+    fn fetch_local_and_mirror(
+        &self,
+        sync_data: &mut [SyncLoginData],
+        chunk_size: usize,
+        scope: &SqlInterruptScope,
+    ) -> Result<()> {
+        let guids: Vec<Guid> = sync_data.iter().map(|d| d.guid().clone()).collect();
+        sql_support::each_sized_chunk_mapped(
+            &guids,
+            chunk_size,
+            |guid| guid.as_str(),
             |chunk, offset| -> Result<()> {
                 // pairs the bound parameter for the guid with an integer index.
                 let values_with_idx = sql_support::repeat_display(chunk.len(), ",", |i, f| {
@@ -314,6 +873,7 @@ impl LoginDb {
                          NULL as local_modified,
                          NULL as is_deleted,
                          NULL as sync_status,
+                         NULL as change_flags,
                          1 as is_mirror,
                          to_fetch.guid_idx as guid_idx
                      FROM loginsM
@@ -329,6 +889,7 @@ impl LoginDb {
                          local_modified,
                          is_deleted,
                          sync_status,
+                         change_flags,
                          0 as is_mirror,
                          to_fetch.guid_idx as guid_idx
                      FROM loginsL
@@ -339,19 +900,27 @@ impl LoginDb {
                     common_cols = schema::COMMON_COLS,
                 );
 
-                let mut stmt = self.db.prepare(&query)?;
+                let mut stmt = self.db.prepare(&self.sql(&query))?;
 
                 let rows = stmt.query_and_then(chunk, |row| {
                     let guid_idx_i = row.get::<_, i64>("guid_idx")?;
-                    // Hitting this means our math is wrong...
-                    assert!(guid_idx_i >= 0);
-
-                    let guid_idx = guid_idx_i as usize;
+                    let count = sync_data.len();
+                    let data = match usize::try_from(guid_idx_i)
+                        .ok()
+                        .and_then(|idx| sync_data.get_mut(idx))
+                    {
+                        Some(data) => data,
+                        // Hitting this means our math is wrong...
+                        None => throw!(ErrorKind::IncomingCorruption(format!(
+                            "guid_idx {} is out of range for {} records",
+                            guid_idx_i, count
+                        ))),
+                    };
                     let is_mirror: bool = row.get("is_mirror")?;
                     if is_mirror {
-                        sync_data[guid_idx].set_mirror(MirrorLogin::from_row(row)?)?;
+                        data.set_mirror(MirrorLogin::from_row(row, self.encdec())?)?;
                     } else {
-                        sync_data[guid_idx].set_local(LocalLogin::from_row(row)?)?;
+                        data.set_local(LocalLogin::from_row(row, self.encdec())?)?;
                     }
                     scope.err_if_interrupted()?;
                     Ok(())
@@ -360,8 +929,7 @@ impl LoginDb {
                 rows.collect::<Result<_>>()?;
                 Ok(())
             },
-        )?;
-        Ok(sync_data)
+        )
     }
 
     // It would be nice if this were a batch-ish api (e.g. takes a slice of records and finds dupes
@@ -391,13 +959,21 @@ impl LoginDb {
         } else {
             query += " AND formSubmitURL IS :form_submit"
         }
-        self.try_query_row(&query, args, |row| Login::from_row(row), false)
+        self.try_query_row(
+            &self.sql(&query),
+            args,
+            |row| Login::from_row(row, self.encdec()),
+            false,
+        )
     }
 
     pub fn get_all(&self) -> Result<Vec<Login>> {
-        let mut stmt = self.db.prepare_cached(&GET_ALL_SQL)?;
-        let rows = stmt.query_and_then(NO_PARAMS, Login::from_row)?;
-        rows.collect::<Result<_>>()
+        let mut op = self.begin_op("get_all");
+        let mut stmt = self.db.prepare_cached(&self.sql(&GET_ALL_SQL))?;
+        let rows = stmt.query_and_then(NO_PARAMS, |row| Login::from_row(row, self.encdec()))?;
+        let logins = rows.collect::<Result<Vec<_>>>()?;
+        op.set_rows(logins.len());
+        Ok(logins)
     }
 
     pub fn get_by_base_domain(&self, base_domain: &str) -> Result<Vec<Login>> {
@@ -416,14 +992,16 @@ impl LoginDb {
         // counts are expected to be so low.
         // A regex would probably make this simpler, but we don't want to drag
         // in a regex lib just for this.
-        let mut stmt = self.db.prepare_cached(&GET_ALL_SQL)?;
+        let mut stmt = self.db.prepare_cached(&self.sql(&GET_ALL_SQL))?;
         let rows = stmt
-            .query_and_then(NO_PARAMS, Login::from_row)?
+            .query_and_then(NO_PARAMS, |row| Login::from_row(row, self.encdec()))?
             .filter(|r| {
-                let login = r
-                    .as_ref()
-                    .ok()
-                    .and_then(|login| Url::parse(&login.hostname).ok());
+                // Keep errors (like a password we can't decrypt), so that
+                // they're returned rather than hiding the login.
+                let login = match r {
+                    Ok(login) => Url::parse(&login.hostname).ok(),
+                    Err(_) => return true,
+                };
                 let this_host = login.as_ref().and_then(|url| url.host());
                 match (&base_host, this_host) {
                     (Host::Domain(base), Some(Host::Domain(look))) => {
@@ -451,16 +1029,73 @@ impl LoginDb {
         rows.collect::<Result<_>>()
     }
 
+    /// The logins which may be filled in on a page at `origin`, matching
+    /// Desktop's `getLoginsForSite`. Logins saved for `origin`, or for its
+    /// `http` equivalent if it's `https`, are included (but never the other
+    /// way around). If `form_action_origin` is given, only form logins which
+    /// submit there (or anywhere) are included, otherwise only HTTP auth
+    /// logins.
+    ///
+    /// Logins for exactly `origin` come first, then the most recently used.
+    pub fn get_for_site(
+        &self,
+        origin: &str,
+        form_action_origin: Option<&str>,
+    ) -> Result<Vec<Login>> {
+        let site = match SiteOrigin::parse(origin) {
+            Some(site) => site,
+            None => {
+                // don't log the input string as it's PII.
+                log::warn!("get_for_site was passed an invalid origin");
+                return Ok(vec![]);
+            }
+        };
+        let form_action_host_port = form_action_origin.and_then(util::url_host_port);
+        // A linear scan, for the same reasons as `get_by_base_domain`.
+        let mut stmt = self.db.prepare_cached(&self.sql(&GET_ALL_SQL))?;
+        let mut matches = Vec::new();
+        for login in stmt.query_and_then(NO_PARAMS, |row| Login::from_row(row, self.encdec()))? {
+            let login = login?;
+            let is_exact = match SiteOrigin::parse(&login.hostname) {
+                Some(saved) if saved == site => true,
+                Some(saved) if saved.upgrades_to(&site) => false,
+                _ => continue,
+            };
+            let target_matches = match (form_action_origin, &login.form_submit_url) {
+                // An empty formSubmitURL matches any form, and others (like
+                // "javascript:") which aren't URLs must match exactly.
+                (Some(form_action_origin), Some(url)) => {
+                    url.is_empty()
+                        || url == form_action_origin
+                        || (form_action_host_port.is_some()
+                            && util::url_host_port(url) == form_action_host_port)
+                }
+                (Some(_), None) => false,
+                (None, _) => login.http_realm.is_some(),
+            };
+            if target_matches {
+                matches.push((is_exact, login));
+            }
+        }
+        matches.sort_by(|(a_is_exact, a), (b_is_exact, b)| {
+            b_is_exact
+                .cmp(a_is_exact)
+                .then_with(|| b.time_last_used.cmp(&a.time_last_used))
+        });
+        Ok(matches.into_iter().map(|(_, login)| login).collect())
+    }
+
     pub fn get_by_id(&self, id: &str) -> Result<Option<Login>> {
         self.try_query_row(
-            &GET_BY_GUID_SQL,
+            &self.sql(&GET_BY_GUID_SQL),
             &[(":guid", &id as &dyn ToSql)],
-            Login::from_row,
+            |row| Login::from_row(row, self.encdec()),
             true,
         )
     }
 
     pub fn touch(&self, id: &str) -> Result<()> {
+        let mut op = self.begin_op("touch");
         let tx = self.unchecked_transaction()?;
         self.ensure_local_overlay_exists(id)?;
         self.mark_mirror_overridden(id)?;
@@ -468,27 +1103,133 @@ impl LoginDb {
         // As on iOS, just using a record doesn't flip it's status to changed.
         // TODO: this might be wrong for lockbox!
         self.execute_named_cached(
-            "UPDATE loginsL
-             SET timeLastUsed = :now_millis,
-                 timesUsed = timesUsed + 1,
-                 local_modified = :now_millis
-             WHERE guid = :guid
-                 AND is_deleted = 0",
+            &self.sql(&format!(
+                "UPDATE loginsL
+                 SET timeLastUsed = :now_millis,
+                     timesUsed = timesUsed + 1,
+                     local_modified = :now_millis,
+                     change_flags = change_flags | {usage}
+                 WHERE guid = :guid
+                     AND is_deleted = 0",
+                usage = change_flags::USAGE
+            )),
             named_params! {
                 ":now_millis": now_ms,
                 ":guid": id,
             },
         )?;
+        self.note_changed(&[id])?;
+        tx.commit()?;
+        op.set_rows(1);
+        Ok(())
+    }
+
+    /// Like `touch`, but for many records in a single transaction. Ids may be
+    /// repeated, in which case the record is touched once per occurrence.
+    pub fn touch_multiple(&self, ids: &[&str]) -> Result<()> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let mut counts: HashMap<&str, i64> = HashMap::new();
+        for id in ids {
+            *counts.entry(*id).or_default() += 1;
+        }
+        // Group the ids by how many times they were touched, so each group
+        // can be updated with a single statement per chunk.
+        let mut by_count: BTreeMap<i64, Vec<&str>> = BTreeMap::new();
+        for (id, count) in &counts {
+            by_count.entry(*count).or_default().push(*id);
+        }
+
+        let tx = self.unchecked_transaction()?;
+        for id in counts.keys() {
+            self.ensure_local_overlay_exists(id)?;
+        }
+        let guids = counts.keys().copied().collect::<Vec<_>>();
+        sql_support::each_chunk(&guids, |chunk, _| -> Result<()> {
+            self.execute(
+                &self.sql(&format!(
+                    "UPDATE loginsM SET is_overridden = 1 WHERE guid IN ({vars})",
+                    vars = sql_support::repeat_sql_vars(chunk.len())
+                )),
+                chunk,
+            )?;
+            Ok(())
+        })?;
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        for (count, ids) in &by_count {
+            sql_support::each_chunk(ids, |chunk, _| -> Result<()> {
+                self.execute(
+                    &self.sql(&format!(
+                        "UPDATE loginsL
+                         SET timeLastUsed = {now_millis},
+                             timesUsed = timesUsed + {count},
+                             local_modified = {now_millis},
+                             change_flags = change_flags | {usage}
+                         WHERE guid IN ({vars})
+                             AND is_deleted = 0",
+                        now_millis = now_ms,
+                        count = count,
+                        usage = change_flags::USAGE,
+                        vars = sql_support::repeat_sql_vars(chunk.len())
+                    )),
+                    chunk,
+                )?;
+                Ok(())
+            })?;
+        }
+        self.note_changed(&guids)?;
         tx.commit()?;
         Ok(())
     }
 
+    /// Remember that the record with the given id was used, without writing
+    /// anything until `flush_touches` is called. Intended for callers which
+    /// touch records in quick succession, such as autofill.
+    pub fn queue_touch(&self, id: &str) {
+        self.queued_touches.borrow_mut().push(id.to_owned());
+    }
+
+    /// Apply all the touches queued by `queue_touch` in a single transaction.
+    /// Records which were deleted since being queued are skipped.
+    pub fn flush_touches(&self) -> Result<()> {
+        let queued = self.queued_touches.replace(Vec::new());
+        let mut ids = Vec::with_capacity(queued.len());
+        for id in &queued {
+            if self.exists(id)? {
+                ids.push(id.as_str());
+            }
+        }
+        if let Err(e) = self.touch_multiple(&ids) {
+            // Put them back so a later flush can try again.
+            let mut pending = self.queued_touches.borrow_mut();
+            let newer = std::mem::replace(&mut *pending, queued);
+            pending.extend(newer);
+            return Err(e);
+        }
+        Ok(())
+    }
+
     pub fn add(&self, login: Login) -> Result<Login> {
+        let mut op = self.begin_op("add");
+        self.check_quota()?;
         let mut login = self.fixup_and_check_for_dupes(login)?;
+        self.check_host_record_count(&login.hostname)?;
 
         let tx = self.unchecked_transaction()?;
         let now_ms = util::system_time_ms_i64(SystemTime::now());
+        timestamps::sanitize(&mut login, now_ms);
+        let login = self.insert_new_login(login, now_ms)?;
+        self.note_changed(&[login.guid_str()])?;
+        tx.commit()?;
+        op.set_rows(1);
+        Ok(login)
+    }
 
+    // Inserts a new local record for `login`, which has already been fixed
+    // up and checked, filling in its guid and metadata if they're missing.
+    // The caller must be in a transaction, and note the change.
+    pub(crate) fn insert_new_login(&self, mut login: Login, now_ms: i64) -> Result<Login> {
         // Allow an empty GUID to be passed to indicate that we should generate
         // one. (Note that the FFI, does not require that the `id` field be
         // present in the JSON, and replaces it with an empty string if missing).
@@ -510,60 +1251,10 @@ impl LoginDb {
             login.times_used = 1;
         }
 
-        let sql = format!(
-            "INSERT OR IGNORE INTO loginsL (
-                hostname,
-                httpRealm,
-                formSubmitURL,
-                usernameField,
-                passwordField,
-                timesUsed,
-                username,
-                password,
-                guid,
-                timeCreated,
-                timeLastUsed,
-                timePasswordChanged,
-                local_modified,
-                is_deleted,
-                sync_status
-            ) VALUES (
-                :hostname,
-                :http_realm,
-                :form_submit_url,
-                :username_field,
-                :password_field,
-                :times_used,
-                :username,
-                :password,
-                :guid,
-                :time_created,
-                :time_last_used,
-                :time_password_changed,
-                :local_modified,
-                0, -- is_deleted
-                {new} -- sync_status
-            )",
-            new = SyncStatus::New as u8
-        );
-
         let rows_changed = self.execute_named(
-            &sql,
-            named_params! {
-                ":hostname": login.hostname,
-                ":http_realm": login.http_realm,
-                ":form_submit_url": login.form_submit_url,
-                ":username_field": login.username_field,
-                ":password_field": login.password_field,
-                ":username": login.username,
-                ":password": login.password,
-                ":guid": login.guid,
-                ":time_created": login.time_created,
-                ":times_used": login.times_used,
-                ":time_last_used": login.time_last_used,
-                ":time_password_changed": login.time_password_changed,
-                ":local_modified": now_ms,
-            },
+            &self.sql(&INSERT_LOCAL_SQL),
+            &LoginParams::new(&login, self.encdec())
+                .bind(Write::Insert, named_params! { ":local_modified": now_ms }),
         )?;
         if rows_changed == 0 {
             log::error!(
@@ -572,64 +1263,36 @@ impl LoginDb {
             );
             throw!(ErrorKind::DuplicateGuid(login.guid.into_string()));
         }
-        tx.commit()?;
         Ok(login)
     }
 
     pub fn import_multiple(&self, logins: &[Login]) -> Result<MigrationMetrics> {
+        self.check_quota()?;
         // Check if the logins table is empty first.
-        let mut num_existing_logins =
-            self.query_row::<i64, _, _>("SELECT COUNT(*) FROM loginsL", NO_PARAMS, |r| r.get(0))?;
-        num_existing_logins +=
-            self.query_row::<i64, _, _>("SELECT COUNT(*) FROM loginsM", NO_PARAMS, |r| r.get(0))?;
+        let mut num_existing_logins = self.query_row::<i64, _, _>(
+            &self.sql("SELECT COUNT(*) FROM loginsL"),
+            NO_PARAMS,
+            |r| r.get(0),
+        )?;
+        num_existing_logins += self.query_row::<i64, _, _>(
+            &self.sql("SELECT COUNT(*) FROM loginsM"),
+            NO_PARAMS,
+            |r| r.get(0),
+        )?;
         if num_existing_logins > 0 {
             return Err(ErrorKind::NonEmptyTable.into());
         }
         let tx = self.unchecked_transaction()?;
         let now_ms = util::system_time_ms_i64(SystemTime::now());
         let import_start = Instant::now();
-        let sql = format!(
-            "INSERT OR IGNORE INTO loginsL (
-                hostname,
-                httpRealm,
-                formSubmitURL,
-                usernameField,
-                passwordField,
-                timesUsed,
-                username,
-                password,
-                guid,
-                timeCreated,
-                timeLastUsed,
-                timePasswordChanged,
-                local_modified,
-                is_deleted,
-                sync_status
-            ) VALUES (
-                :hostname,
-                :http_realm,
-                :form_submit_url,
-                :username_field,
-                :password_field,
-                :times_used,
-                :username,
-                :password,
-                :guid,
-                :time_created,
-                :time_last_used,
-                :time_password_changed,
-                :local_modified,
-                0, -- is_deleted
-                {new} -- sync_status
-            )",
-            new = SyncStatus::New as u8
-        );
         let import_start_total_logins: u64 = logins.len() as u64;
         let mut num_failed_fixup: u64 = 0;
         let mut num_failed_insert: u64 = 0;
         let mut fixup_phase_duration = Duration::new(0, 0);
         let mut fixup_errors: Vec<String> = Vec::new();
         let mut insert_errors: Vec<String> = Vec::new();
+        let mut imported_guids = Vec::new();
+        let mut num_fixed_timestamps: u64 = 0;
 
         for login in logins {
             // This is a little bit of hoop-jumping to avoid cloning each borrowed item
@@ -657,31 +1320,38 @@ impl LoginDb {
             };
             // Now we can safely insert it, knowing that it's valid data.
             let old_guid = &login.guid; // Keep the old GUID around so we can debug errors easily.
-            let guid = if old_guid.is_valid_for_sync_server() {
-                old_guid.clone()
-            } else {
-                Guid::random()
-            };
+            let with_new_guid;
+            if !old_guid.is_valid_for_sync_server() {
+                with_new_guid = Login {
+                    guid: Guid::random(),
+                    ..login.clone()
+                };
+                login = &with_new_guid;
+            }
+            let with_sane_timestamps;
+            if timestamps::need_sanitizing(login, now_ms) {
+                let mut sane = login.clone();
+                num_fixed_timestamps += timestamps::sanitize(&mut sane, now_ms) as u64;
+                with_sane_timestamps = sane;
+                login = &with_sane_timestamps;
+            }
+            let guid = &login.guid;
             fixup_phase_duration = import_start.elapsed();
+            if let Err(e) = self.check_host_record_count(&login.hostname) {
+                log::warn!("Could not import {} ({}).", old_guid, e.label());
+                insert_errors.push(e.label().into());
+                num_failed_insert += 1;
+                continue;
+            }
             match self.execute_named_cached(
-                &sql,
-                named_params! {
-                    ":hostname": login.hostname,
-                    ":http_realm": login.http_realm,
-                    ":form_submit_url": login.form_submit_url,
-                    ":username_field": login.username_field,
-                    ":password_field": login.password_field,
-                    ":username": login.username,
-                    ":password": login.password,
-                    ":guid": guid,
-                    ":time_created": login.time_created,
-                    ":times_used": login.times_used,
-                    ":time_last_used": login.time_last_used,
-                    ":time_password_changed": login.time_password_changed,
-                    ":local_modified": now_ms,
-                },
+                &self.sql(&INSERT_LOCAL_SQL),
+                &LoginParams::new(login, self.encdec())
+                    .bind(Write::Insert, named_params! { ":local_modified": now_ms }),
             ) {
-                Ok(_) => log::info!("Imported {} (new GUID {}) successfully.", old_guid, guid),
+                Ok(_) => {
+                    log::info!("Imported {} (new GUID {}) successfully.", old_guid, guid);
+                    imported_guids.push(guid.clone());
+                }
                 Err(e) => {
                     log::warn!("Could not import {} ({}).", old_guid, e);
                     insert_errors.push(Error::from(e).label().into());
@@ -689,6 +1359,7 @@ impl LoginDb {
                 }
             };
         }
+        self.note_changed(&imported_guids)?;
         tx.commit()?;
 
         let num_post_fixup = import_start_total_logins - num_failed_fixup;
@@ -724,6 +1395,7 @@ impl LoginDb {
                 .unwrap_or_else(|| Duration::new(0, 0))
                 .as_millis(),
             errors: all_errors,
+            num_fixed_timestamps,
         };
         log::info!(
             "Finished importing logins with the following metrics: {:#?}",
@@ -733,14 +1405,30 @@ impl LoginDb {
     }
 
     pub fn update(&self, login: Login) -> Result<()> {
-        let login = self.fixup_and_check_for_dupes(login)?;
+        let mut op = self.begin_op("update");
+        self.check_quota()?;
+        let original_form_submit_url = login.form_submit_url.clone();
+        let is_scheme_relative = login.has_scheme_relative_form_submit_url();
+        let mut login = self.fixup_and_check_for_dupes(login)?;
+        // Like records from sync, keep a scheme-relative formSubmitURL as it
+        // is, unless the caller changed it.
+        if is_scheme_relative
+            && login.form_submit_url != original_form_submit_url
+            && self.has_form_submit_url(login.guid_str(), &original_form_submit_url)?
+        {
+            login.form_submit_url = original_form_submit_url;
+        }
 
         let tx = self.unchecked_transaction()?;
-        // Note: These fail with DuplicateGuid if the record doesn't exist.
+        // Note: These fail with NoSuchRecord if the record doesn't exist, and
+        // RecordDeleted if it's been deleted.
         self.ensure_local_overlay_exists(login.guid_str())?;
         self.mark_mirror_overridden(login.guid_str())?;
 
         let now_ms = util::system_time_ms_i64(SystemTime::now());
+        // Encrypting the same password twice needn't give the same result,
+        // so this has to be compared decrypted.
+        let password_changed = self.local_password(login.guid_str())? != login.password;
 
         let sql = format!(
             "UPDATE loginsL
@@ -748,9 +1436,9 @@ impl LoginDb {
                  timeLastUsed        = :now_millis,
                  -- Only update timePasswordChanged if, well, the password changed.
                  timePasswordChanged = (CASE
-                     WHEN password = :password
-                     THEN timePasswordChanged
-                     ELSE :now_millis
+                     WHEN :password_changed
+                     THEN :now_millis
+                     ELSE timePasswordChanged
                  END),
                  httpRealm           = :http_realm,
                  formSubmitURL       = :form_submit_url,
@@ -761,17 +1449,33 @@ impl LoginDb {
                  password            = :password,
                  hostname            = :hostname,
                  -- leave New records as they are, otherwise update them to `changed`
-                 sync_status         = max(sync_status, {changed})
+                 sync_status         = max(sync_status, {changed}),
+                 -- Updating a record counts as using it, but only counts as
+                 -- changing its other fields if they actually changed.
+                 change_flags        = change_flags | {usage} | (CASE
+                     WHEN httpRealm IS NOT :http_realm
+                       OR formSubmitURL IS NOT :form_submit_url
+                       OR usernameField IS NOT :username_field
+                       OR passwordField IS NOT :password_field
+                       OR username IS NOT :username
+                       OR :password_changed
+                       OR hostname IS NOT :hostname
+                     THEN {fields}
+                     ELSE 0
+                 END)
              WHERE guid = :guid",
-            changed = SyncStatus::Changed as u8
+            changed = SyncStatus::Changed as u8,
+            usage = change_flags::USAGE,
+            fields = change_flags::FIELDS,
         );
 
         self.db.execute_named(
-            &sql,
+            &self.sql(&sql),
             named_params! {
                 ":hostname": login.hostname,
                 ":username": login.username,
-                ":password": login.password,
+                ":password": encryption::encrypt_password(self.encdec(), &login.password),
+                ":password_changed": password_changed,
                 ":http_realm": login.http_realm,
                 ":form_submit_url": login.form_submit_url,
                 ":username_field": login.username_field,
@@ -780,10 +1484,35 @@ impl LoginDb {
                 ":now_millis": now_ms,
             },
         )?;
+        self.note_changed(&[login.guid_str()])?;
         tx.commit()?;
+        op.set_rows(1);
         Ok(())
     }
 
+    fn local_password(&self, guid: &str) -> Result<String> {
+        let password: String = self.db.query_row_named(
+            &self.sql("SELECT password FROM loginsL WHERE guid = :guid"),
+            named_params! { ":guid": guid },
+            |row| row.get(0),
+        )?;
+        encryption::decrypt_password(self.encdec(), &password)
+    }
+
+    fn has_form_submit_url(&self, guid: &str, url: &Option<String>) -> Result<bool> {
+        Ok(self.db.query_row_named(
+            &self.sql(
+                "SELECT EXISTS(
+                    SELECT 1 FROM loginsL WHERE guid = :guid AND formSubmitURL = :url
+                    UNION ALL
+                    SELECT 1 FROM loginsM WHERE guid = :guid AND formSubmitURL = :url
+                 )",
+            ),
+            named_params! { ":guid": guid, ":url": url },
+            |row| row.get(0),
+        )?)
+    }
+
     pub fn check_valid_with_no_dupes(&self, login: &Login) -> Result<()> {
         login.check_valid()?;
         self.check_for_dupes(login)
@@ -803,34 +1532,32 @@ impl LoginDb {
     }
 
     pub fn dupe_exists(&self, login: &Login) -> Result<bool> {
-        // Note: the query below compares the guids of the given login with existing logins
-        //  to prevent a login from being considered a duplicate of itself (e.g. during updates).
+        lazy_static::lazy_static! {
+            // Note: the query below compares the guids of the given login with existing logins
+            //  to prevent a login from being considered a duplicate of itself (e.g. during updates).
+            static ref DUPE_EXISTS_SQL: String = format!(
+                "SELECT EXISTS(
+                    SELECT 1 FROM loginsL
+                    WHERE is_deleted = 0
+                        AND guid <> :guid
+                        AND hostname = :hostname
+                        AND NULLIF(username, '') = :username
+                        AND {target_matches}
+
+                    UNION ALL
+
+                    SELECT 1 FROM loginsM
+                    WHERE is_overridden = 0
+                        AND guid <> :guid
+                        AND hostname = :hostname
+                        AND NULLIF(username, '') = :username
+                        AND {target_matches}
+                 )",
+                target_matches = TARGET_MATCHES_SQL,
+            );
+        }
         Ok(self.db.query_row_named(
-            "SELECT EXISTS(
-                SELECT 1 FROM loginsL
-                WHERE is_deleted = 0
-                    AND guid <> :guid
-                    AND hostname = :hostname
-                    AND NULLIF(username, '') = :username
-                    AND (
-                        formSubmitURL = :form_submit
-                        OR
-                        httpRealm = :http_realm
-                    )
-
-                UNION ALL
-
-                SELECT 1 FROM loginsM
-                WHERE is_overridden = 0
-                    AND guid <> :guid
-                    AND hostname = :hostname
-                    AND NULLIF(username, '') = :username
-                    AND (
-                        formSubmitURL = :form_submit
-                        OR
-                        httpRealm = :http_realm
-                    )
-             )",
+            &self.sql(&DUPE_EXISTS_SQL),
             named_params! {
                 ":guid": &login.guid,
                 ":hostname": &login.hostname,
@@ -849,46 +1576,43 @@ impl LoginDb {
                 "SELECT {common_cols} FROM loginsL
                 WHERE is_deleted = 0
                     AND hostname = :hostname
-                    AND (
-                        formSubmitURL = :form_submit
-                        OR
-                        httpRealm = :http_realm
-                    )
+                    AND {target_matches}
 
                 UNION ALL
 
                 SELECT {common_cols} FROM loginsM
                 WHERE is_overridden = 0
                     AND hostname = :hostname
-                    AND (
-                        formSubmitURL = :form_submit
-                        OR
-                        httpRealm = :http_realm
-                    )
+                    AND {target_matches}
                 ",
-                common_cols = schema::COMMON_COLS
+                common_cols = schema::COMMON_COLS,
+                target_matches = TARGET_MATCHES_SQL,
             );
         }
-        let mut stmt = self.db.prepare_cached(&DUPES_IGNORING_USERNAME_SQL)?;
+        let mut stmt = self
+            .db
+            .prepare_cached(&self.sql(&DUPES_IGNORING_USERNAME_SQL))?;
         let params = named_params! {
             ":hostname": &login.hostname,
             ":http_realm": login.http_realm.as_ref(),
             ":form_submit": login.form_submit_url.as_ref(),
         };
         // Needs to be two lines for borrow checker
-        let rows = stmt.query_and_then_named(params, Login::from_row)?;
+        let rows = stmt.query_and_then_named(params, |row| Login::from_row(row, self.encdec()))?;
         rows.collect()
     }
 
     pub fn exists(&self, id: &str) -> Result<bool> {
         Ok(self.db.query_row_named(
-            "SELECT EXISTS(
-                 SELECT 1 FROM loginsL
-                 WHERE guid = :guid AND is_deleted = 0
-                 UNION ALL
-                 SELECT 1 FROM loginsM
-                 WHERE guid = :guid AND is_overridden IS NOT 1
-             )",
+            &self.sql(
+                "SELECT EXISTS(
+                     SELECT 1 FROM loginsL
+                     WHERE guid = :guid AND is_deleted = 0
+                     UNION ALL
+                     SELECT 1 FROM loginsM
+                     WHERE guid = :guid AND is_overridden IS NOT 1
+                 )",
+            ),
             named_params! { ":guid": id },
             |row| row.get(0),
         )?)
@@ -897,13 +1621,14 @@ impl LoginDb {
     /// Delete the record with the provided id. Returns true if the record
     /// existed already.
     pub fn delete(&self, id: &str) -> Result<bool> {
+        let mut op = self.begin_op("delete");
         let tx = self.unchecked_transaction_imm()?;
         let exists = self.exists(id)?;
         let now_ms = util::system_time_ms_i64(SystemTime::now());
 
         // For IDs that have, mark is_deleted and clear sensitive fields
         self.execute_named(
-            &format!(
+            &self.sql(&format!(
                 "UPDATE loginsL
                  SET local_modified = :now_ms,
                      sync_status = {status_changed},
@@ -913,47 +1638,77 @@ impl LoginDb {
                      username = ''
                  WHERE guid = :guid",
                 status_changed = SyncStatus::Changed as u8
-            ),
+            )),
             named_params! { ":now_ms": now_ms, ":guid": id },
         )?;
 
         // Mark the mirror as overridden
         self.execute_named(
-            "UPDATE loginsM SET is_overridden = 1 WHERE guid = :guid",
+            &self.sql("UPDATE loginsM SET is_overridden = 1 WHERE guid = :guid"),
             named_params! { ":guid": id },
         )?;
+        if self.scrub_mirror_on_delete.get() {
+            self.execute_named(
+                &self.sql(&format!("{} WHERE guid = :guid", SCRUB_MIRROR_SQL)),
+                named_params! { ":guid": id },
+            )?;
+        }
 
         // If we don't have a local record for this ID, but do have it in the mirror
         // insert a tombstone.
-        self.execute_named(&format!("
+        self.execute_named(&self.sql(&format!("
             INSERT OR IGNORE INTO loginsL
                     (guid, local_modified, is_deleted, sync_status, hostname, timeCreated, timePasswordChanged, password, username)
             SELECT   guid, :now_ms,        1,          {changed},   '',       timeCreated, :now_ms,                   '',       ''
             FROM loginsM
             WHERE guid = :guid",
-            changed = SyncStatus::Changed as u8),
+            changed = SyncStatus::Changed as u8)),
             named_params! { ":now_ms": now_ms, ":guid": id })?;
+        annotations::delete_annotations(&self.db, &self.tables, &[id])?;
+        self.note_changed(&[id])?;
         tx.commit()?;
+        op.set_rows(exists as usize);
         Ok(exists)
     }
 
-    fn mark_mirror_overridden(&self, guid: &str) -> Result<()> {
+    /// Whether `delete` and `wipe` should also remove the password, hostname
+    /// and username from the mirror (our copy of what's on the server), as
+    /// they do for the local record. On by default; otherwise they stay in
+    /// the mirror until the deletion is synced, which may be never.
+    ///
+    /// The rest of the mirror record is kept, since sync still needs it.
+    pub fn set_scrub_mirror_on_delete(&self, scrub: bool) {
+        self.scrub_mirror_on_delete.set(scrub);
+    }
+
+    /// What syncing does with local changes to a record which was deleted on
+    /// another device. `TombstonePolicy::Resurrect` by default.
+    pub fn set_tombstone_policy(&self, policy: TombstonePolicy) {
+        self.tombstone_policy.set(policy);
+    }
+
+    pub(crate) fn mark_mirror_overridden(&self, guid: &str) -> Result<()> {
         self.execute_named_cached(
-            "UPDATE loginsM SET is_overridden = 1 WHERE guid = :guid",
+            &self.sql("UPDATE loginsM SET is_overridden = 1 WHERE guid = :guid"),
             named_params! { ":guid": guid },
         )?;
         Ok(())
     }
 
-    fn ensure_local_overlay_exists(&self, guid: &str) -> Result<()> {
-        let already_have_local: bool = self.db.query_row_named(
-            "SELECT EXISTS(SELECT 1 FROM loginsL WHERE guid = :guid)",
+    // Fails with `RecordDeleted` if the local record is a tombstone, whether
+    // or not the mirror still has the record, so that changing it can't bring
+    // it back.
+    pub(crate) fn ensure_local_overlay_exists(&self, guid: &str) -> Result<()> {
+        let local_is_deleted: Option<bool> = self.try_query_row(
+            &self.sql("SELECT is_deleted FROM loginsL WHERE guid = :guid"),
             named_params! { ":guid": guid },
-            |row| row.get(0),
+            |row| Ok::<_, Error>(row.get(0)?),
+            true,
         )?;
-
-        if already_have_local {
-            return Ok(());
+        match local_is_deleted {
+            Some(true) => throw!(ErrorKind::RecordDeleted(guid.to_owned())),
+            Some(false) => return Ok(()),
+            None => {}
         }
 
         log::debug!("No overlay; cloning one for {:?}.", guid);
@@ -966,18 +1721,31 @@ impl LoginDb {
     }
 
     fn clone_mirror_to_overlay(&self, guid: &str) -> Result<usize> {
-        Ok(self
-            .execute_named_cached(&*CLONE_SINGLE_MIRROR_SQL, &[(":guid", &guid as &dyn ToSql)])?)
+        Ok(self.execute_named_cached(
+            &self.sql(&CLONE_SINGLE_MIRROR_SQL),
+            &[(":guid", &guid as &dyn ToSql)],
+        )?)
     }
 
     pub fn reset(&self, assoc: &EngineSyncAssociation) -> Result<()> {
         log::info!("Executing reset on password engine!");
         let tx = self.db.unchecked_transaction()?;
-        self.execute_all(&[
-            &*CLONE_ENTIRE_MIRROR_SQL,
-            "DELETE FROM loginsM",
-            &format!("UPDATE loginsL SET sync_status = {}", SyncStatus::New as u8),
-        ])?;
+        self.tables.execute_all(
+            &self.db,
+            &[
+                &*CLONE_ENTIRE_MIRROR_SQL,
+                "DELETE FROM loginsM",
+                "DELETE FROM loginsPendingUpload",
+                // Everything local is new to the server now, except tombstones,
+                // which are still changes to upload.
+                &format!(
+                    "UPDATE loginsL
+                     SET sync_status = CASE WHEN is_deleted = 1 THEN {changed} ELSE {new} END",
+                    changed = SyncStatus::Changed as u8,
+                    new = SyncStatus::New as u8
+                ),
+            ],
+        )?;
         self.set_last_sync(ServerTimestamp(0))?;
         match assoc {
             EngineSyncAssociation::Disconnected => {
@@ -990,6 +1758,7 @@ impl LoginDb {
             }
         };
         self.delete_meta(schema::GLOBAL_STATE_META_KEY)?;
+        self.delete_meta(schema::SYNC_IN_PROGRESS_META_KEY)?;
         tx.commit()?;
         Ok(())
     }
@@ -999,8 +1768,9 @@ impl LoginDb {
         log::info!("Executing wipe on password engine!");
         let now_ms = util::system_time_ms_i64(SystemTime::now());
         scope.err_if_interrupted()?;
+        let wiped_guids = self.get_all_guids()?;
         self.execute_named(
-            &format!(
+            &self.sql(&format!(
                 "
                 UPDATE loginsL
                 SET local_modified = :now_ms,
@@ -1011,23 +1781,30 @@ impl LoginDb {
                     username = ''
                 WHERE is_deleted = 0",
                 changed = SyncStatus::Changed as u8
-            ),
+            )),
             named_params! { ":now_ms": now_ms },
         )?;
         scope.err_if_interrupted()?;
 
-        self.execute("UPDATE loginsM SET is_overridden = 1", NO_PARAMS)?;
+        self.execute(&self.sql("UPDATE loginsM SET is_overridden = 1"), NO_PARAMS)?;
         scope.err_if_interrupted()?;
+        if self.scrub_mirror_on_delete.get() {
+            self.execute(&self.sql(SCRUB_MIRROR_SQL), NO_PARAMS)?;
+            scope.err_if_interrupted()?;
+        }
 
         self.execute_named(
-            &format!("
+            &self.sql(&format!("
                 INSERT OR IGNORE INTO loginsL
                       (guid, local_modified, is_deleted, sync_status, hostname, timeCreated, timePasswordChanged, password, username)
                 SELECT guid, :now_ms,        1,          {changed},   '',       timeCreated, :now_ms,             '',       ''
                 FROM loginsM",
-                changed = SyncStatus::Changed as u8),
+                changed = SyncStatus::Changed as u8)),
             named_params! { ":now_ms": now_ms })?;
         scope.err_if_interrupted()?;
+
+        self.execute(&self.sql("DELETE FROM loginsLocalMeta"), NO_PARAMS)?;
+        self.note_changed(&wiped_guids)?;
         tx.commit()?;
         Ok(())
     }
@@ -1035,11 +1812,36 @@ impl LoginDb {
     pub fn wipe_local(&self) -> Result<()> {
         log::info!("Executing wipe_local on password engine!");
         let tx = self.unchecked_transaction()?;
-        self.execute_all(&[
-            "DELETE FROM loginsL",
-            "DELETE FROM loginsM",
-            "DELETE FROM loginsSyncMeta",
-        ])?;
+        let wiped_guids = self.get_all_guids()?;
+        self.tables.execute_all(
+            &self.db,
+            &[
+                "DELETE FROM loginsL",
+                "DELETE FROM loginsM",
+                "DELETE FROM loginsLocalMeta",
+                "DELETE FROM loginsDisabledHosts",
+                "DELETE FROM loginsQuarantine",
+                "DELETE FROM loginsRecentTombstones",
+                "DELETE FROM loginsPendingUpload",
+            ],
+        )?;
+        // The change counter must never go backwards, so it survives, and
+        // so does the record of whether passwords are encrypted, and whether
+        // we're disconnected.
+        self.execute_named(
+            &self.sql(
+                "DELETE FROM loginsSyncMeta
+                 WHERE key NOT IN (
+                     :change_counter_key, :passwords_encrypted_key, :lifecycle_state_key
+                 )",
+            ),
+            named_params! {
+                ":change_counter_key": schema::CHANGE_COUNTER_META_KEY,
+                ":passwords_encrypted_key": schema::PASSWORDS_ENCRYPTED_META_KEY,
+                ":lifecycle_state_key": schema::LIFECYCLE_STATE_META_KEY,
+            },
+        )?;
+        self.note_changed(&wiped_guids)?;
         tx.commit()?;
         Ok(())
     }
@@ -1050,21 +1852,45 @@ impl LoginDb {
         server_now: ServerTimestamp,
         telem: &mut telemetry::EngineIncoming,
         scope: &SqlInterruptScope,
+        tombstone_policy: TombstonePolicy,
     ) -> Result<UpdatePlan> {
         let mut plan = UpdatePlan::default();
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
 
         for mut record in records {
             scope.err_if_interrupted()?;
             log::debug!("Processing remote change {}", record.guid());
-            let upstream = if let Some(inbound) = record.inbound.0.take() {
+            let upstream_time = record.inbound.1;
+            let mut upstream = if let Some(inbound) = record.inbound.0.take() {
                 inbound
             } else {
-                log::debug!("Processing inbound deletion (always prefer)");
-                plan.plan_delete(record.guid.clone());
+                if tombstone_policy.keeps_local(record.local.as_ref(), upstream_time) {
+                    log::debug!("Processing inbound deletion (keeping newer local record)");
+                    plan.plan_resurrection(record.guid.clone());
+                    telem.reconciled(1);
+                } else {
+                    log::debug!("Processing inbound deletion");
+                    plan.plan_delete(record.guid.clone());
+                }
                 continue;
             };
-            let upstream_time = record.inbound.1;
+            plan.sanitize_timestamps(&mut upstream, now_ms, server_now);
+            let mut unknown_fields = record.inbound_unknown_fields.take();
             match (record.mirror.take(), record.local.take()) {
+                (Some(_mirror), Some(local)) if local.is_deleted => {
+                    // Our deletion wins, and will be uploaded. There's no
+                    // need to merge, and doing so would copy the remote
+                    // record into our tombstone.
+                    log::debug!("  Remote change to a locally deleted record, keeping deletion");
+                    let upstream = if self.scrub_mirror_on_delete.get() {
+                        unknown_fields = None;
+                        scrubbed(upstream)
+                    } else {
+                        upstream
+                    };
+                    plan.plan_mirror_update(upstream, upstream_time);
+                    telem.reconciled(1);
+                }
                 (Some(mirror), Some(local)) => {
                     log::debug!("  Conflict between remote and local, Resolving with 3WM");
                     plan.plan_three_way_merge(local, mirror, upstream, upstream_time, server_now);
@@ -1075,6 +1901,13 @@ impl LoginDb {
                     plan.plan_mirror_update(upstream, upstream_time);
                     telem.applied(1);
                 }
+                (None, Some(local)) if !local.is_deleted && local.login == upstream => {
+                    // Usually because we adopted its guid, or uploaded it
+                    // before a reset.
+                    log::debug!("  Local record is identical to remote, moving to mirror");
+                    plan.plan_move_to_mirror(upstream, upstream_time);
+                    telem.applied(1);
+                }
                 (None, Some(local)) => {
                     log::debug!("  Conflicting record without shared parent, using newer");
                     plan.plan_two_way_merge(&local.login, (upstream, upstream_time));
@@ -1095,100 +1928,328 @@ impl LoginDb {
                     telem.applied(1);
                 }
             }
+            plan.plan_unknown_fields(record.guid.clone(), unknown_fields);
         }
         Ok(plan)
     }
 
-    fn execute_plan(&self, plan: UpdatePlan, scope: &SqlInterruptScope) -> Result<()> {
+    // Applies `plan`, adding the records it changes to `marker`, which is
+    // saved in the same transaction.
+    fn execute_plan(
+        &self,
+        plan: UpdatePlan,
+        marker: &mut SyncInProgress,
+        scope: &SqlInterruptScope,
+    ) -> Result<()> {
         // Because rusqlite want a mutable reference to create a transaction
         // (as a way to save us from ourselves), we side-step that by creating
         // it manually.
         let tx = self.db.unchecked_transaction()?;
-        plan.execute(&tx, scope)?;
+        let changed_guids = plan.changed_guids();
+        marker
+            .guids
+            .extend(changed_guids.iter().map(|g| (*g).to_owned()));
+        self.put_sync_in_progress(marker)?;
+        plan.execute(&tx, &self.tables, self.encdec(), scope)?;
+        self.note_changed(&changed_guids)?;
         tx.commit()?;
         Ok(())
     }
 
+    // Reconciles and applies `data`, which must be sorted by guid, a batch at
+    // a time, skipping the first `marker.applied` records, which an earlier
+    // attempt already applied. Returns the number of realm repairs, merge
+    // conflicts and timestamp repairs.
+    fn apply_in_batches(
+        &self,
+        data: Vec<SyncLoginData>,
+        server_now: ServerTimestamp,
+        marker: &mut SyncInProgress,
+        telem: &mut telemetry::EngineIncoming,
+        scope: &SqlInterruptScope,
+    ) -> Result<(usize, usize, usize)> {
+        let total = data.len();
+        let mut applied = marker.applied.unwrap_or_default().min(total);
+        if applied > 0 {
+            log::info!(
+                "Resuming an interrupted sync after {} of {} records",
+                applied,
+                total
+            );
+        }
+        let mut remaining = data.into_iter().skip(applied).peekable();
+        let (mut realm_repairs, mut merge_conflicts, mut timestamp_repairs) = (0, 0, 0);
+        // Even if there's nothing to apply, we still record that a sync is
+        // in progress.
+        loop {
+            let batch = remaining
+                .by_ref()
+                .take(self.apply_batch_size.get().max(1))
+                .collect::<Vec<_>>();
+            applied += batch.len();
+            let plan =
+                self.reconcile(batch, server_now, telem, scope, self.tombstone_policy.get())?;
+            realm_repairs += plan.realm_repairs;
+            merge_conflicts += plan.merge_conflicts;
+            timestamp_repairs += plan.timestamp_repairs;
+            let done = remaining.peek().is_none();
+            marker.applied = if done { None } else { Some(applied) };
+            self.execute_plan(plan, marker, scope)?;
+            if done {
+                return Ok((realm_repairs, merge_conflicts, timestamp_repairs));
+            }
+        }
+    }
+
+    fn get_sync_in_progress(&self) -> Result<Option<SyncInProgress>> {
+        match self.get_meta::<String>(schema::SYNC_IN_PROGRESS_META_KEY)? {
+            Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn put_sync_in_progress(&self, marker: &SyncInProgress) -> Result<()> {
+        self.put_meta(
+            schema::SYNC_IN_PROGRESS_META_KEY,
+            &serde_json::to_string(marker)?,
+        )
+    }
+
+    // If a previous sync applied its incoming records but never got as far as
+    // `sync_finished`, we may well have uploaded records without recording
+    // that we did. Those uploads come back to us in this sync, and without
+    // this they'd look like conflicting remote changes, and be uploaded again.
+    //
+    // So, for each record that sync touched, if what the server has now is
+    // exactly what we have locally, we treat it as synchronized before
+    // reconciling. Returns true if anything changed, in which case `data` is
+    // stale.
+    fn recover_interrupted_sync(
+        &self,
+        marker: &SyncInProgress,
+        data: &[SyncLoginData],
+        scope: &SqlInterruptScope,
+    ) -> Result<bool> {
+        log::info!(
+            "Recovering from an interrupted sync at {} ({} records)",
+            marker.timestamp,
+            marker.guids.len()
+        );
+        let touched = marker
+            .guids
+            .iter()
+            .map(String::as_str)
+            .collect::<HashSet<_>>();
+        let tx = self.unchecked_transaction()?;
+        let mut num_recovered = 0;
+        for record in data {
+            let local = match &record.local {
+                Some(local) if touched.contains(record.guid_str()) => local,
+                _ => continue,
+            };
+            if local.sync_status == SyncStatus::Synced {
+                continue;
+            }
+            let already_uploaded = match &record.inbound.0 {
+                Some(upstream) => !local.is_deleted && local.login == *upstream,
+                None => local.is_deleted,
+            };
+            if already_uploaded {
+                self.move_local_to_mirror(&[record.guid_str()], record.inbound.1, scope)?;
+                num_recovered += 1;
+            }
+        }
+        self.delete_meta(schema::SYNC_IN_PROGRESS_META_KEY)?;
+        tx.commit()?;
+        log::info!("Recovered {} already-uploaded records", num_recovered);
+        Ok(num_recovered > 0)
+    }
+
+    /// The records we need to upload. Local records which can't be read (say,
+    /// because they aren't valid UTF-8) are left out, rather than failing the
+    /// sync; see `quarantine_invalid_local_rows`. So are aged-out tombstones;
+    /// see `set_tombstone_retention`.
     pub fn fetch_outgoing(
         &self,
         st: ServerTimestamp,
         scope: &SqlInterruptScope,
     ) -> Result<OutgoingChangeset> {
-        // Taken from iOS. Arbitrarily large, so that clients that want to
-        // process deletions first can; for us it doesn't matter.
-        const TOMBSTONE_SORTINDEX: i32 = 5_000_000;
-        const DEFAULT_SORTINDEX: i32 = 1;
-        let mut outgoing = OutgoingChangeset::new("passwords", st);
-        let mut stmt = self.db.prepare_cached(&format!(
-            "SELECT * FROM loginsL WHERE sync_status IS NOT {synced}",
-            synced = SyncStatus::Synced as u8
-        ))?;
-        let rows = stmt.query_and_then(NO_PARAMS, |row| {
-            scope.err_if_interrupted()?;
-            Ok(if row.get::<_, bool>("is_deleted")? {
-                Payload::new_tombstone(row.get::<_, String>("guid")?)
-                    .with_sortindex(TOMBSTONE_SORTINDEX)
-            } else {
-                let login = Login::from_row(row)?;
-                Payload::from_record(login)?.with_sortindex(DEFAULT_SORTINDEX)
-            })
-        })?;
-        outgoing.changes = rows.collect::<Result<_>>()?;
-
+        let mut op = self.begin_op("fetch_outgoing");
+        let outgoing = self.fetch_outgoing_and_skipped(st, scope)?.0;
+        op.set_rows(outgoing.changes.len());
         Ok(outgoing)
     }
 
+    // Like `fetch_outgoing`, but also returns the guids of the records which
+    // were left out.
+    fn fetch_outgoing_and_skipped(
+        &self,
+        st: ServerTimestamp,
+        scope: &SqlInterruptScope,
+    ) -> Result<(OutgoingChangeset, Vec<Guid>)> {
+        let mut outgoing = OutgoingChangeset::new(COLLECTION_NAME, st);
+        let mut skipped = vec![];
+        let mut stmt = self.db.prepare_cached(&self.sql(&format!(
+            "{rows}
+             WHERE l.sync_status IS NOT {synced}
+               AND l.guid NOT IN (SELECT guid FROM loginsL WHERE {aged_out})",
+            rows = OUTGOING_ROWS_SQL,
+            synced = SyncStatus::Synced as u8,
+            aged_out = AGED_OUT_TOMBSTONES_SQL,
+        )))?;
+        let mut rows = stmt
+            .query_named(named_params! { ":cutoff": self.tombstone_cutoff(SystemTime::now()) })?;
+        while let Some(row) = rows.next()? {
+            scope.err_if_interrupted()?;
+            match outgoing_payload(row, self.encdec()) {
+                Ok(payload) => outgoing.changes.push(payload),
+                // Unlike a broken row, this means we can't read any of them.
+                Err(e) if matches!(e.kind(), ErrorKind::DecryptionFailed(_)) => return Err(e),
+                Err(e) => {
+                    let guid = raw_guid(row)?;
+                    log::warn!("Not uploading unreadable record {:?}: {}", guid, e.label());
+                    skipped.push(guid);
+                }
+            }
+        }
+        Ok((outgoing, skipped))
+    }
+
     fn do_apply_incoming(
         &self,
         inbound: IncomingChangeset,
         telem: &mut telemetry::Engine,
         scope: &SqlInterruptScope,
     ) -> Result<OutgoingChangeset> {
+        let mut op = self.begin_op("apply_incoming");
+        op.set_rows(inbound.changes.len());
+        // We've never synced, or we've been reset, so the server may well
+        // have been wiped.
+        let fresh_start = self
+            .get_last_sync()?
+            .map_or(true, |last_sync| last_sync == ServerTimestamp(0));
+        if fresh_start {
+            // Records imported before signing in are often already on the
+            // server, under other guids.
+            let adoptions = self.propose_guid_adoptions(&inbound.changes)?;
+            if !adoptions.is_empty() {
+                let report = self.adopt_remote_guids(&adoptions)?;
+                log::info!("Adopted {} remote guids", report.num_adopted);
+            }
+        }
         let mut incoming_telemetry = telemetry::EngineIncoming::new();
-        let data = self.fetch_login_data(&inbound.changes, &mut incoming_telemetry, scope)?;
-        let plan = {
-            let result = self.reconcile(data, inbound.timestamp, &mut incoming_telemetry, scope);
+        let mut data = self.fetch_login_data(&inbound.changes, &mut incoming_telemetry, scope)?;
+        let mut marker = match self.get_sync_in_progress()? {
+            // We were interrupted while applying these same records, so we
+            // can pick up where we left off.
+            Some(marker)
+                if marker.applied.is_some()
+                    && marker.timestamp == inbound.timestamp.as_millis() =>
+            {
+                marker
+            }
+            Some(marker) => {
+                if self.recover_interrupted_sync(&marker, &data, scope)? {
+                    incoming_telemetry = telemetry::EngineIncoming::new();
+                    data =
+                        self.fetch_login_data(&inbound.changes, &mut incoming_telemetry, scope)?;
+                }
+                SyncInProgress::new(inbound.timestamp)
+            }
+            None => SyncInProgress::new(inbound.timestamp),
+        };
+        // The batches must be the same if we have to resume.
+        data.sort_by(|a, b| a.guid().cmp(b.guid()));
+        let (realm_repairs, merge_conflicts, timestamp_repairs) = {
+            let result = self.apply_in_batches(
+                data,
+                inbound.timestamp,
+                &mut marker,
+                &mut incoming_telemetry,
+                scope,
+            );
             telem.incoming(incoming_telemetry);
             result
         }?;
-        self.execute_plan(plan, scope)?;
-        self.fetch_outgoing(inbound.timestamp, scope)
+        // We have to take whatever the server has, but we want to know when
+        // that's too much.
+        let hosts_over_record_cap = self.note_hosts_over_record_cap()?;
+        // Aged-out tombstones wouldn't be uploaded anyway, but they'd stay
+        // until the next `run_maintenance`.
+        let aged_out_tombstones = if fresh_start {
+            self.prune_aged_out_tombstones(SystemTime::now())?
+        } else {
+            0
+        };
+        let (outgoing, skipped) = self.fetch_outgoing_and_skipped(inbound.timestamp, scope)?;
+        self.record_pending_upload(
+            &outgoing
+                .changes
+                .iter()
+                .map(|p| p.id.as_str())
+                .collect::<Vec<_>>(),
+        )?;
+        if realm_repairs > 0
+            || merge_conflicts > 0
+            || timestamp_repairs > 0
+            || !skipped.is_empty()
+            || aged_out_tombstones > 0
+            || hosts_over_record_cap > 0
+        {
+            let mut validation = telemetry::Validation::with_version(1);
+            validation
+                .problem("repairedRealmOrFormSubmitURL", realm_repairs)
+                .problem("mergeConflicts", merge_conflicts)
+                .problem("repairedTimestamps", timestamp_repairs)
+                .problem("unreadableOutgoing", skipped.len())
+                .problem("agedOutTombstones", aged_out_tombstones)
+                .problem("hostsOverRecordCap", hosts_over_record_cap);
+            telem.validation(validation);
+        }
+        // Remember what we're about to upload, too, in case we don't make it
+        // to `sync_finished`.
+        marker
+            .guids
+            .extend(outgoing.changes.iter().map(|p| p.id.to_string()));
+        self.put_sync_in_progress(&marker)?;
+        Ok(outgoing)
     }
 
-    fn put_meta(&self, key: &str, value: &dyn ToSql) -> Result<()> {
+    pub(crate) fn put_meta(&self, key: &str, value: &dyn ToSql) -> Result<()> {
         self.execute_named_cached(
-            "REPLACE INTO loginsSyncMeta (key, value) VALUES (:key, :value)",
+            &self.sql("REPLACE INTO loginsSyncMeta (key, value) VALUES (:key, :value)"),
             named_params! { ":key": key, ":value": value },
         )?;
         Ok(())
     }
 
-    fn get_meta<T: FromSql>(&self, key: &str) -> Result<Option<T>> {
+    pub(crate) fn get_meta<T: FromSql>(&self, key: &str) -> Result<Option<T>> {
         self.try_query_row(
-            "SELECT value FROM loginsSyncMeta WHERE key = :key",
+            &self.sql("SELECT value FROM loginsSyncMeta WHERE key = :key"),
             named_params! { ":key": key },
             |row| Ok::<_, Error>(row.get(0)?),
             true,
         )
     }
 
-    fn delete_meta(&self, key: &str) -> Result<()> {
+    pub(crate) fn delete_meta(&self, key: &str) -> Result<()> {
         self.execute_named_cached(
-            "DELETE FROM loginsSyncMeta WHERE key = :key",
+            &self.sql("DELETE FROM loginsSyncMeta WHERE key = :key"),
             named_params! { ":key": key },
         )?;
         Ok(())
     }
 
-    fn set_last_sync(&self, last_sync: ServerTimestamp) -> Result<()> {
+    pub(crate) fn set_last_sync(&self, last_sync: ServerTimestamp) -> Result<()> {
         log::debug!("Updating last sync to {}", last_sync);
         let last_sync_millis = last_sync.as_millis() as i64;
         self.put_meta(schema::LAST_SYNC_META_KEY, &last_sync_millis)
     }
 
-    fn get_last_sync(&self) -> Result<Option<ServerTimestamp>> {
-        let millis = self.get_meta::<i64>(schema::LAST_SYNC_META_KEY)?.unwrap();
-        Ok(Some(ServerTimestamp(millis)))
+    pub(crate) fn get_last_sync(&self) -> Result<Option<ServerTimestamp>> {
+        Ok(self
+            .get_meta::<i64>(schema::LAST_SYNC_META_KEY)?
+            .map(ServerTimestamp))
     }
 
     pub fn set_global_state(&self, state: &Option<String>) -> Result<()> {
@@ -1203,31 +2264,61 @@ impl LoginDb {
         self.get_meta::<String>(schema::GLOBAL_STATE_META_KEY)
     }
 
-    /// A utility we can kill by the end of 2019 ;)
-    pub fn migrate_global_state(&self) -> Result<()> {
-        let tx = self.unchecked_transaction_imm()?;
-        if let Some(old_state) = self.get_meta("global_state")? {
-            log::info!("there's old global state - migrating");
-            let (new_sync_ids, new_global_state) = extract_v1_state(old_state, "passwords");
-            if let Some(sync_ids) = new_sync_ids {
-                self.put_meta(schema::GLOBAL_SYNCID_META_KEY, &sync_ids.global)?;
-                self.put_meta(schema::COLLECTION_SYNCID_META_KEY, &sync_ids.coll)?;
-                log::info!("migrated the sync IDs");
-            }
-            if let Some(new_global_state) = new_global_state {
-                self.set_global_state(&Some(new_global_state))?;
-                log::info!("migrated the global state");
+    /// Check the invariants between the local and mirror tables which
+    /// syncing relies on, returning a description of each violation. An
+    /// empty result means everything is fine. This reads every row, so it's
+    /// meant for tests and diagnostics rather than routine use.
+    pub fn check_integrity(&self) -> Result<Vec<String>> {
+        let mut problems = vec![];
+        let integrity = self.query_one::<String>("PRAGMA integrity_check")?;
+        if integrity != "ok" {
+            problems.push(format!("PRAGMA integrity_check: {}", integrity));
+        }
+        let checks: &[(&str, &str)] = &[
+            (
+                "overridden mirror record without a local record",
+                "SELECT guid FROM loginsM
+                 WHERE is_overridden = 1
+                   AND guid NOT IN (SELECT guid FROM loginsL)",
+            ),
+            (
+                "local record without exactly one of httpRealm and formSubmitURL",
+                "SELECT guid FROM loginsL
+                 WHERE is_deleted = 0
+                   AND (httpRealm IS NULL) = (formSubmitURL IS NULL)",
+            ),
+            (
+                "mirror record without exactly one of httpRealm and formSubmitURL",
+                "SELECT guid FROM loginsM
+                 WHERE (httpRealm IS NULL) = (formSubmitURL IS NULL)",
+            ),
+            (
+                "local tombstone with sensitive fields",
+                "SELECT guid FROM loginsL
+                 WHERE is_deleted = 1
+                   AND (password != '' OR hostname != '' OR username != '')",
+            ),
+        ];
+        for (description, sql) in checks {
+            let mut stmt = self.prepare(&self.sql(sql))?;
+            let guids = stmt.query_map(NO_PARAMS, |row| row.get::<_, String>(0))?;
+            for guid in guids {
+                problems.push(format!("{}: {:?}", description, guid?));
             }
-            self.delete_meta("global_state")?;
         }
-        tx.commit()?;
-        Ok(())
+        Ok(problems)
     }
 }
 
+/// The name of the sync collection (and engine) for logins.
+pub(crate) const COLLECTION_NAME: &str = "passwords";
+
 pub struct LoginStore<'a> {
     pub db: &'a LoginDb,
     pub scope: sql_support::SqlInterruptScope,
+    // Set once this sync has found the engine disabled. It stays a no-op
+    // from then on, even if the engine is enabled again part way through.
+    skipping: Cell<bool>,
 }
 
 impl<'a> LoginStore<'a> {
@@ -1235,13 +2326,24 @@ impl<'a> LoginStore<'a> {
         Self {
             db,
             scope: db.begin_interrupt_scope(),
+            skipping: Cell::new(false),
+        }
+    }
+
+    // Whether to skip the rest of this sync, because the engine is (or was)
+    // disabled.
+    fn should_skip(&self) -> Result<bool> {
+        if !self.skipping.get() && !self.db.get_local_engine_enabled()? {
+            log::info!("The password engine is disabled locally. Skipping");
+            self.skipping.set(true);
         }
+        Ok(self.skipping.get())
     }
 }
 
 impl<'a> SyncEngine for LoginStore<'a> {
     fn collection_name(&self) -> std::borrow::Cow<'static, str> {
-        "passwords".into()
+        COLLECTION_NAME.into()
     }
 
     fn apply_incoming(
@@ -1251,6 +2353,10 @@ impl<'a> SyncEngine for LoginStore<'a> {
     ) -> anyhow::Result<OutgoingChangeset> {
         assert_eq!(inbound.len(), 1, "logins only requests one item");
         let inbound = inbound.into_iter().next().unwrap();
+        self.db.check_can_sync()?;
+        if self.should_skip()? {
+            return Ok(OutgoingChangeset::new(COLLECTION_NAME, inbound.timestamp));
+        }
         Ok(self.db.do_apply_incoming(inbound, telem, &self.scope)?)
     }
 
@@ -1259,11 +2365,26 @@ impl<'a> SyncEngine for LoginStore<'a> {
         new_timestamp: ServerTimestamp,
         records_synced: Vec<Guid>,
     ) -> anyhow::Result<()> {
-        self.db.mark_as_synchronized(
+        if self.should_skip()? {
+            return Ok(());
+        }
+        let report = self.db.mark_as_synchronized(
             &records_synced.iter().map(Guid::as_str).collect::<Vec<_>>(),
             new_timestamp,
             &self.scope,
         )?;
+        if !report.not_found.is_empty() {
+            log::warn!(
+                "{} uploaded records had no local record",
+                report.not_found.len()
+            );
+        }
+        if !report.changed_during_upload.is_empty() {
+            log::info!(
+                "{} records changed while being uploaded; they'll be uploaded again",
+                report.changed_during_upload.len()
+            );
+        }
         Ok(())
     }
 
@@ -1272,10 +2393,12 @@ impl<'a> SyncEngine for LoginStore<'a> {
         server_timestamp: ServerTimestamp,
     ) -> anyhow::Result<Vec<CollectionRequest>> {
         let since = self.db.get_last_sync()?.unwrap_or_default();
-        Ok(if since == server_timestamp {
+        Ok(if self.should_skip()? || since == server_timestamp {
             vec![]
         } else {
-            vec![CollectionRequest::new("passwords").full().newer_than(since)]
+            vec![CollectionRequest::new(COLLECTION_NAME)
+                .full()
+                .newer_than(since)]
         })
     }
 
@@ -1330,6 +2453,17 @@ lazy_static! {
          FROM loginsM",
         common_cols = schema::COMMON_COLS,
     );
+    static ref INSERT_LOCAL_SQL: String = format!(
+        "INSERT OR IGNORE INTO loginsL (
+            {common_cols}, local_modified, is_deleted, sync_status, change_flags
+         ) VALUES (
+            {common_params}, :local_modified, 0, {new}, {all}
+         )",
+        common_cols = schema::COMMON_COLS,
+        common_params = schema::COMMON_PARAMS,
+        new = SyncStatus::New as u8,
+        all = change_flags::FIELDS | change_flags::USAGE,
+    );
     static ref CLONE_SINGLE_MIRROR_SQL: String =
         format!("{} WHERE guid = :guid", &*CLONE_ENTIRE_MIRROR_SQL,);
 }
@@ -1337,50 +2471,170 @@ lazy_static! {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing::{incoming_changes, incoming_changeset, sync_db, LoginFixture};
+    use crate::LoginStore;
     #[test]
     fn test_bad_record() {
         let db = LoginDb::open_in_memory(Some("testing")).unwrap();
         let scope = db.begin_interrupt_scope();
         let mut telem = sync15::telemetry::EngineIncoming::new();
-        let res = db
-            .fetch_login_data(
-                &[
-                    // tombstone
-                    (
-                        sync15::Payload::new_tombstone("dummy_000001"),
-                        sync15::ServerTimestamp(10000),
-                    ),
-                    // invalid
-                    (
-                        sync15::Payload::from_json(serde_json::json!({
-                            "id": "dummy_000002",
-                            "garbage": "data",
-                            "etc": "not a login"
-                        }))
-                        .unwrap(),
-                        sync15::ServerTimestamp(10000),
-                    ),
-                    // valid
-                    (
-                        sync15::Payload::from_json(serde_json::json!({
-                            "id": "dummy_000003",
-                            "formSubmitURL": "https://www.example.com/submit",
-                            "hostname": "https://www.example.com",
-                            "username": "test",
-                            "password": "test",
-                        }))
-                        .unwrap(),
-                        sync15::ServerTimestamp(10000),
-                    ),
-                ],
-                &mut telem,
-                &scope,
-            )
-            .unwrap();
+        let valid = || LoginFixture::builder().guid("dummy_000003");
+        let mut incoming = incoming_changes(
+            vec![
+                // tombstone
+                Payload::new_tombstone("dummy_000001"),
+                // invalid
+                Payload::from_json(serde_json::json!({
+                    "id": "dummy_000002",
+                    "garbage": "data",
+                    "etc": "not a login"
+                }))
+                .unwrap(),
+                // valid
+                valid().password("test").payload(),
+            ],
+            ServerTimestamp(10000),
+        );
+        // an older copy of the valid one, which is dropped
+        incoming.push((valid().password("older").payload(), ServerTimestamp(9000)));
+        let res = db.fetch_login_data(&incoming, &mut telem, &scope).unwrap();
         assert_eq!(telem.get_failed(), 1);
+        assert_eq!(telem.get_deduplicated(), 1);
         assert_eq!(res.len(), 2);
         assert_eq!(res[0].guid, "dummy_000001");
         assert_eq!(res[1].guid, "dummy_000003");
+        assert_eq!(res[1].inbound.0.as_ref().unwrap().password, "test");
+    }
+
+    #[test]
+    fn test_duplicate_incoming_records() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let engine = LoginStore::new(&db);
+        let mut incoming = incoming_changeset(vec![], ServerTimestamp(3000));
+        for (password, ts) in &[("newer", 2000), ("older", 1000)] {
+            let payload = LoginFixture::builder()
+                .guid("dummy_000001")
+                .password(*password)
+                .payload();
+            incoming.changes.push((payload, ServerTimestamp(*ts)));
+        }
+        let mut telem = sync15::telemetry::Engine::new("passwords");
+        let outgoing = engine.apply_incoming(vec![incoming], &mut telem).unwrap();
+        assert!(outgoing.changes.is_empty());
+        engine.sync_finished(ServerTimestamp(3000), vec![]).unwrap();
+
+        let logins = db.get_all().unwrap();
+        assert_eq!(logins.len(), 1);
+        assert_eq!(logins[0].password, "newer");
+        assert_eq!(db.get_last_sync().unwrap(), Some(ServerTimestamp(3000)));
+
+        assert_eq!(telem.get_incoming().unwrap().get_deduplicated(), 1);
+    }
+
+    #[test]
+    fn test_bad_record_before_existing() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        db.add(LoginFixture::builder().guid("dummy_000002").build())
+            .unwrap();
+        let scope = db.begin_interrupt_scope();
+        let mut telem = sync15::telemetry::EngineIncoming::new();
+        // The local data for the second record must not end up on the first
+        // one just because a record before it was skipped.
+        let incoming = incoming_changes(
+            vec![
+                Payload::from_json(serde_json::json!({
+                    "id": "dummy_000001",
+                    "garbage": "data",
+                }))
+                .unwrap(),
+                Payload::new_tombstone("dummy_000003"),
+                Payload::new_tombstone("dummy_000002"),
+            ],
+            ServerTimestamp(10000),
+        );
+        let res = db.fetch_login_data(&incoming, &mut telem, &scope).unwrap();
+        assert_eq!(telem.get_failed(), 1);
+        assert_eq!(res.len(), 2);
+        assert!(res[0].local.is_none());
+        assert_eq!(res[1].local.as_ref().unwrap().guid_str(), "dummy_000002");
+    }
+
+    // Fetches `count` records, `chunk_size` at a time, where only the first
+    // and last records of each chunk exist locally, and checks that every
+    // local record is paired with the right incoming one.
+    fn check_chunk_pairing(count: usize, chunk_size: usize) {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let guid = |i: usize| Guid::from(format!("chunk_{:06}", i));
+        let existing: HashSet<usize> = (0..count)
+            .filter(|i| i % chunk_size == 0 || i % chunk_size == chunk_size - 1 || i + 1 == count)
+            .collect();
+        for &i in &existing {
+            db.add(Login {
+                guid: guid(i),
+                form_submit_url: Some("https://www.example.com/submit".into()),
+                hostname: "https://www.example.com".into(),
+                username: format!("user{}", i),
+                password: "test".into(),
+                ..Login::default()
+            })
+            .unwrap();
+        }
+        let mut sync_data: Vec<SyncLoginData> = (0..count)
+            .map(|i| {
+                SyncLoginData::from_payload(Payload::new_tombstone(guid(i)), ServerTimestamp(10000))
+                    .unwrap()
+            })
+            .collect();
+        let scope = db.begin_interrupt_scope();
+        db.fetch_local_and_mirror(&mut sync_data, chunk_size, &scope)
+            .unwrap();
+        for (i, data) in sync_data.iter().enumerate() {
+            match &data.local {
+                Some(local) => {
+                    assert!(existing.contains(&i), "Unexpected local data for {}", i);
+                    assert_eq!(local.guid_str(), data.guid_str());
+                }
+                None => assert!(!existing.contains(&i), "Missing local data for {}", i),
+            }
+        }
+    }
+
+    #[test]
+    fn test_fetch_chunk_pairing() {
+        check_chunk_pairing(1, 1);
+        check_chunk_pairing(2, 1);
+        check_chunk_pairing(5, 5);
+        check_chunk_pairing(6, 5);
+        check_chunk_pairing(11, 5);
+        let limit = sql_support::default_max_variable_number();
+        check_chunk_pairing(limit, limit);
+        check_chunk_pairing(limit + 1, limit);
+    }
+
+    #[test]
+    fn test_set_local_twice() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let login = db
+            .add(Login {
+                form_submit_url: Some("https://www.example.com/submit".into()),
+                hostname: "https://www.example.com".into(),
+                username: "test".into(),
+                password: "test".into(),
+                ..Login::default()
+            })
+            .unwrap();
+        let mut sync_data = vec![SyncLoginData::from_payload(
+            Payload::new_tombstone(login.guid.clone()),
+            ServerTimestamp(10000),
+        )
+        .unwrap()];
+        let scope = db.begin_interrupt_scope();
+        db.fetch_local_and_mirror(&mut sync_data, 1, &scope)
+            .unwrap();
+        let err = db
+            .fetch_local_and_mirror(&mut sync_data, 1, &scope)
+            .unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::IncomingCorruption(_)));
     }
 
     #[test]
@@ -1620,25 +2874,779 @@ mod tests {
         );
     }
 
+    fn site_hostnames(db: &LoginDb, origin: &str, form_action_origin: Option<&str>) -> Vec<String> {
+        db.get_for_site(origin, form_action_origin)
+            .unwrap()
+            .into_iter()
+            .map(|login| format!("{} {}", login.hostname, login.username))
+            .collect()
+    }
+
     #[test]
-    fn test_delete() {
+    fn test_get_for_site_scheme_upgrade() {
         let db = LoginDb::open_in_memory(Some("testing")).unwrap();
-        let _login = db
-            .add(Login {
-                hostname: "https://www.example.com".into(),
-                http_realm: Some("https://www.example.com".into()),
-                username: "test_user".into(),
-                password: "test_password".into(),
-                ..Login::default()
+        for hostname in &["http://example.com", "https://example.com"] {
+            db.add(Login {
+                username: "form".into(),
+                ..sync_login(hostname)
             })
             .unwrap();
-
-        assert!(db.delete(_login.guid_str()).unwrap());
-
-        let tombstone_exists: bool = db
-            .query_row_named(
-                "SELECT EXISTS(
-                    SELECT 1 FROM loginsL
+        }
+        let form = Some("https://example.com");
+        // http logins can be used on https, and exact matches come first...
+        assert_eq!(
+            site_hostnames(&db, "https://example.com", form),
+            vec!["https://example.com form", "http://example.com form"]
+        );
+        // ...but https logins are never offered on http.
+        assert_eq!(
+            site_hostnames(&db, "http://example.com", form),
+            vec!["http://example.com form"]
+        );
+        // The origin is normalized.
+        assert_eq!(
+            site_hostnames(&db, "HTTPS://EXAMPLE.COM:443/some/path", form).len(),
+            2
+        );
+        assert!(site_hostnames(&db, "https://www.example.com", form).is_empty());
+        assert!(site_hostnames(&db, "not a url", form).is_empty());
+    }
+
+    #[test]
+    fn test_get_for_site_ports() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        db.add(sync_login("http://example.com:8080")).unwrap();
+        let form = Some("https://example.com:8080");
+        assert_eq!(
+            site_hostnames(&db, "https://example.com:8080", form),
+            vec!["http://example.com:8080 user"]
+        );
+        assert!(site_hostnames(&db, "https://example.com", form).is_empty());
+        assert!(site_hostnames(&db, "http://example.com", form).is_empty());
+        assert!(site_hostnames(&db, "https://example.com:8443", form).is_empty());
+    }
+
+    #[test]
+    fn test_get_for_site_realm_and_form() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let realm = db
+            .add(Login {
+                http_realm: Some("Example".into()),
+                form_submit_url: None,
+                username: "realm".into(),
+                ..sync_login("https://example.com")
+            })
+            .unwrap();
+        db.add(Login {
+            form_submit_url: Some("https://login.example.com".into()),
+            username: "form".into(),
+            ..sync_login("https://example.com")
+        })
+        .unwrap();
+        let anywhere = db
+            .add(Login {
+                form_submit_url: Some("".into()),
+                username: "anywhere".into(),
+                ..sync_login("https://example.com")
+            })
+            .unwrap();
+
+        assert_eq!(
+            site_hostnames(&db, "https://example.com", None),
+            vec!["https://example.com realm"]
+        );
+        let mut form_logins = site_hostnames(
+            &db,
+            "https://example.com",
+            Some("https://login.example.com"),
+        );
+        form_logins.sort();
+        assert_eq!(
+            form_logins,
+            vec!["https://example.com anywhere", "https://example.com form"]
+        );
+        assert_eq!(
+            site_hostnames(&db, "https://example.com", Some("https://example.org")),
+            vec!["https://example.com anywhere"]
+        );
+
+        // Deleted logins are never included.
+        db.delete(realm.guid_str()).unwrap();
+        db.delete(anywhere.guid_str()).unwrap();
+        assert!(site_hostnames(&db, "https://example.com", None).is_empty());
+        assert_eq!(
+            site_hostnames(
+                &db,
+                "https://example.com",
+                Some("https://login.example.com")
+            ),
+            vec!["https://example.com form"]
+        );
+    }
+
+    // Records with the legacy form targets some Desktop records have, as
+    // they'd arrive from the server.
+    fn legacy_form_target_changeset(ts: i64) -> Vec<IncomingChangeset> {
+        let mut incoming = IncomingChangeset::new("passwords", ServerTimestamp(ts));
+        for (id, username, form_submit_url) in &[
+            ("legacy_00001", "anywhere", ""),
+            ("legacy_00002", "relative", "//www.example.com/login"),
+        ] {
+            let payload = sync15::Payload::from_json(serde_json::json!({
+                "id": id,
+                "hostname": "https://www.example.com",
+                "formSubmitURL": form_submit_url,
+                "username": username,
+                "password": "password",
+                "timeCreated": 1000,
+                "timePasswordChanged": 1000,
+            }))
+            .unwrap();
+            incoming.changes.push((payload, ServerTimestamp(ts)));
+        }
+        vec![incoming]
+    }
+
+    #[test]
+    fn test_legacy_form_targets_round_trip() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let engine = LoginStore::new(&db);
+        let mut telem = sync15::telemetry::Engine::new("passwords");
+        let outgoing = engine
+            .apply_incoming(legacy_form_target_changeset(1000), &mut telem)
+            .unwrap();
+        assert!(outgoing.changes.is_empty());
+        engine.sync_finished(ServerTimestamp(1000), vec![]).unwrap();
+
+        let relative = db.get_by_id("legacy_00002").unwrap().unwrap();
+        assert_eq!(
+            relative.form_submit_url.as_deref(),
+            Some("//www.example.com/login")
+        );
+        assert_eq!(
+            db.get_by_id("legacy_00001")
+                .unwrap()
+                .unwrap()
+                .form_submit_url
+                .as_deref(),
+            Some("")
+        );
+
+        // Changing the password uploads the form target as we got it.
+        db.update(Login {
+            password: "new-password".into(),
+            ..relative.clone()
+        })
+        .unwrap();
+        let outgoing = engine
+            .apply_incoming(
+                vec![IncomingChangeset::new("passwords", ServerTimestamp(1000))],
+                &mut telem,
+            )
+            .unwrap();
+        assert_eq!(outgoing.changes.len(), 1);
+        let uploaded = &outgoing.changes[0].data;
+        assert_eq!(uploaded["formSubmitURL"], "//www.example.com/login");
+        assert_eq!(uploaded["password"], "new-password");
+
+        // But changing the form target normalizes it.
+        db.update(Login {
+            form_submit_url: Some("//www.example.org/login".into()),
+            ..relative
+        })
+        .unwrap();
+        assert_eq!(
+            db.get_by_id("legacy_00002")
+                .unwrap()
+                .unwrap()
+                .form_submit_url
+                .as_deref(),
+            Some("//www.example.org")
+        );
+    }
+
+    #[test]
+    fn test_legacy_form_targets_match() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let engine = LoginStore::new(&db);
+        let mut telem = sync15::telemetry::Engine::new("passwords");
+        engine
+            .apply_incoming(legacy_form_target_changeset(1000), &mut telem)
+            .unwrap();
+        engine.sync_finished(ServerTimestamp(1000), vec![]).unwrap();
+
+        let mut matches = site_hostnames(
+            &db,
+            "https://www.example.com",
+            Some("https://www.example.com"),
+        );
+        matches.sort();
+        assert_eq!(
+            matches,
+            vec![
+                "https://www.example.com anywhere",
+                "https://www.example.com relative"
+            ]
+        );
+        assert_eq!(
+            site_hostnames(
+                &db,
+                "https://www.example.com",
+                Some("http://www.example.com")
+            )
+            .len(),
+            2
+        );
+        assert_eq!(
+            site_hostnames(
+                &db,
+                "https://www.example.com",
+                Some("https://login.example.com")
+            ),
+            vec!["https://www.example.com anywhere"]
+        );
+        assert!(site_hostnames(&db, "https://www.example.com", None).is_empty());
+    }
+
+    #[test]
+    fn test_legacy_form_target_dupes() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        db.add(Login {
+            form_submit_url: Some("".into()),
+            username: "anywhere".into(),
+            ..sync_login("https://www.example.com")
+        })
+        .unwrap();
+        let relative = db
+            .add(Login {
+                form_submit_url: Some("//www.example.com/login".into()),
+                username: "relative".into(),
+                ..sync_login("https://www.example.com")
+            })
+            .unwrap();
+        assert_eq!(
+            relative.form_submit_url.as_deref(),
+            Some("//www.example.com")
+        );
+
+        let is_dupe = |username: &str, form_submit_url: Option<&str>| {
+            let login = Login {
+                form_submit_url: form_submit_url.map(Into::into),
+                http_realm: if form_submit_url.is_none() {
+                    Some("realm".into())
+                } else {
+                    None
+                },
+                username: username.into(),
+                ..sync_login("https://www.example.com")
+            };
+            match db.add(login) {
+                Ok(_) => false,
+                Err(e) => match e.kind() {
+                    ErrorKind::InvalidLogin(InvalidLogin::DuplicateLogin) => true,
+                    _ => panic!("Unexpected error {}", e),
+                },
+            }
+        };
+        // The wildcard matches any form target, but not HTTP auth.
+        assert!(is_dupe("anywhere", Some("https://login.example.com")));
+        assert!(is_dupe("anywhere", Some("//www.example.com")));
+        assert!(!is_dupe("anywhere", None));
+        // A scheme-relative target matches either scheme, and the wildcard.
+        assert!(is_dupe("relative", Some("https://www.example.com")));
+        assert!(is_dupe("relative", Some("http://www.example.com")));
+        assert!(is_dupe("relative", Some("")));
+        assert!(!is_dupe("relative", Some("https://login.example.com")));
+
+        let dupes = db
+            .potential_dupes_ignoring_username(&Login {
+                username: "someone".into(),
+                ..sync_login("https://www.example.com")
+            })
+            .unwrap();
+        let mut usernames: Vec<_> = dupes.into_iter().map(|l| l.username).collect();
+        usernames.sort();
+        assert_eq!(usernames, vec!["anywhere", "relative"]);
+    }
+
+    #[test]
+    fn test_get_for_site_most_recently_used_first() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let logins = (0..3)
+            .map(|i| {
+                db.add(Login {
+                    username: format!("user{}", i),
+                    ..sync_login("https://example.com")
+                })
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+        db.execute_named(
+            "UPDATE loginsL SET timeLastUsed = :time WHERE guid = :guid",
+            named_params! { ":time": 5000, ":guid": logins[1].guid },
+        )
+        .unwrap();
+        db.execute_named(
+            "UPDATE loginsL SET timeLastUsed = :time WHERE guid = :guid",
+            named_params! { ":time": 1000, ":guid": logins[2].guid },
+        )
+        .unwrap();
+        db.execute_named(
+            "UPDATE loginsL SET timeLastUsed = :time WHERE guid = :guid",
+            named_params! { ":time": 3000, ":guid": logins[0].guid },
+        )
+        .unwrap();
+        let usernames = db
+            .get_for_site("https://example.com", Some("https://example.com"))
+            .unwrap()
+            .into_iter()
+            .map(|login| login.username)
+            .collect::<Vec<_>>();
+        assert_eq!(usernames, vec!["user1", "user0", "user2"]);
+    }
+
+    fn sync_login(hostname: &str) -> Login {
+        Login {
+            hostname: hostname.into(),
+            form_submit_url: Some(hostname.into()),
+            username: "user".into(),
+            password: "password".into(),
+            ..Login::default()
+        }
+    }
+
+    fn echo(outgoing: &OutgoingChangeset, ts: i64) -> Vec<IncomingChangeset> {
+        let mut incoming = IncomingChangeset::new("passwords", ServerTimestamp(ts));
+        for payload in &outgoing.changes {
+            incoming
+                .changes
+                .push((payload.clone(), ServerTimestamp(ts)));
+        }
+        vec![incoming]
+    }
+
+    #[test]
+    fn test_interrupted_sync_recovers() {
+        let dir = tempdir::TempDir::new("interrupted_sync").unwrap();
+        let dbpath = dir.path().join("logins.sqlite");
+        let mut telem = sync15::telemetry::Engine::new("passwords");
+        let (deleted, outgoing) = {
+            let db = LoginDb::open(&dbpath, Some("testing")).unwrap();
+            let engine = LoginStore::new(&db);
+            let deleted = db.add(sync_login("https://www.example.org")).unwrap();
+            let outgoing = engine
+                .apply_incoming(
+                    vec![IncomingChangeset::new("passwords", ServerTimestamp(1000))],
+                    &mut telem,
+                )
+                .unwrap();
+            let guids = outgoing.changes.iter().map(|p| p.id.clone()).collect();
+            engine.sync_finished(ServerTimestamp(1000), guids).unwrap();
+
+            db.add(sync_login("https://www.example.com")).unwrap();
+            db.delete(deleted.guid_str()).unwrap();
+            let outgoing = engine
+                .apply_incoming(
+                    vec![IncomingChangeset::new("passwords", ServerTimestamp(1000))],
+                    &mut telem,
+                )
+                .unwrap();
+            assert_eq!(outgoing.changes.len(), 2);
+            // We crash after uploading, but before `sync_finished`.
+            (deleted, outgoing)
+        };
+
+        let db = LoginDb::open(&dbpath, Some("testing")).unwrap();
+        let marker = db.get_sync_in_progress().unwrap().unwrap();
+        assert_eq!(marker.timestamp, 1000);
+        assert_eq!(marker.guids.len(), 2);
+
+        // The next sync sees our own uploads come back.
+        let engine = LoginStore::new(&db);
+        let reupload = engine
+            .apply_incoming(echo(&outgoing, 2000), &mut telem)
+            .unwrap();
+        assert!(reupload.changes.is_empty());
+        engine.sync_finished(ServerTimestamp(2000), vec![]).unwrap();
+
+        assert_eq!(db.get_sync_in_progress().unwrap(), None);
+        assert_eq!(
+            db.query_one::<i64>("SELECT COUNT(*) FROM loginsL").unwrap(),
+            0
+        );
+        let all = db.get_all().unwrap();
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].hostname, "https://www.example.com");
+        assert!(!db.exists(deleted.guid_str()).unwrap());
+    }
+
+    #[test]
+    fn test_interrupted_sync_keeps_new_changes() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let engine = LoginStore::new(&db);
+        let mut telem = sync15::telemetry::Engine::new("passwords");
+        let login = db
+            .add(Login {
+                time_password_changed: 1000,
+                ..sync_login("https://www.example.com")
+            })
+            .unwrap();
+        let outgoing = engine
+            .apply_incoming(
+                vec![IncomingChangeset::new("passwords", ServerTimestamp(1000))],
+                &mut telem,
+            )
+            .unwrap();
+        // No `sync_finished`, and the record changes again before the next
+        // sync, so it still needs to be uploaded.
+        db.update(Login {
+            password: "new-password".into(),
+            ..login
+        })
+        .unwrap();
+        let reupload = engine
+            .apply_incoming(echo(&outgoing, 2000), &mut telem)
+            .unwrap();
+        assert_eq!(reupload.changes.len(), 1);
+        assert_eq!(reupload.changes[0].data["password"], "new-password");
+    }
+
+    // Sets up the local records for `test_resume_interrupted_apply`: one of
+    // the incoming records, changed, and one which isn't on the server.
+    fn first_sync_setup(db: &LoginDb) {
+        db.add(Login {
+            guid: "remote000003".into(),
+            password: "local-password".into(),
+            ..sync_login("https://www3.example.com")
+        })
+        .unwrap();
+        db.add(Login {
+            guid: "localonly000".into(),
+            ..sync_login("https://local.example.com")
+        })
+        .unwrap();
+        // So that the records are the same whenever they were added, and the
+        // local change is newer.
+        db.execute_batch(
+            "UPDATE loginsL SET timeCreated = 500, timeLastUsed = 500, timePasswordChanged = 2000",
+        )
+        .unwrap();
+    }
+
+    fn first_sync_incoming() -> Vec<IncomingChangeset> {
+        let mut incoming = IncomingChangeset::new("passwords", ServerTimestamp(5000));
+        // Backwards, since they're applied in order of their guids.
+        for n in (0..10).rev() {
+            let login = Login {
+                guid: format!("remote{:06}", n).into(),
+                time_created: 500,
+                time_password_changed: 1000,
+                ..sync_login(&format!("https://www{}.example.com", n))
+            };
+            incoming
+                .changes
+                .push((Payload::from_record(login).unwrap(), ServerTimestamp(1000)));
+        }
+        vec![incoming]
+    }
+
+    // The records, what we'd upload, and how many mirror records there are.
+    fn first_sync_state(
+        db: &LoginDb,
+        outgoing: &OutgoingChangeset,
+    ) -> (Vec<Login>, Vec<Login>, i64) {
+        let by_guid = |a: &Login, b: &Login| a.guid.cmp(&b.guid);
+        let mut all = db.get_all().unwrap();
+        all.sort_by(by_guid);
+        let mut uploads = outgoing
+            .changes
+            .iter()
+            .map(|p| p.clone().into_record::<Login>().unwrap())
+            .collect::<Vec<_>>();
+        uploads.sort_by(by_guid);
+        let mirror = db.query_one::<i64>("SELECT COUNT(*) FROM loginsM").unwrap();
+        (all, uploads, mirror)
+    }
+
+    #[test]
+    fn test_resume_interrupted_apply() {
+        let expected = {
+            let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+            first_sync_setup(&db);
+            let engine = LoginStore::new(&db);
+            let mut telem = sync15::telemetry::Engine::new("passwords");
+            let outgoing = engine
+                .apply_incoming(first_sync_incoming(), &mut telem)
+                .unwrap();
+            first_sync_state(&db, &outgoing)
+        };
+        assert_eq!(expected.0.len(), 11);
+        assert_eq!(expected.1.len(), 2);
+
+        let dir = tempdir::TempDir::new("resume_interrupted_apply").unwrap();
+        let dbpath = dir.path().join("logins.sqlite");
+        {
+            let db = LoginDb::open(&dbpath, Some("testing")).unwrap();
+            first_sync_setup(&db);
+            db.apply_batch_size.set(3);
+            // Stands in for the app being killed during the third batch.
+            db.execute_batch(
+                "CREATE TEMP TRIGGER interrupt BEFORE INSERT ON loginsM
+                 WHEN NEW.guid = 'remote000007'
+                 BEGIN SELECT RAISE(ABORT, 'interrupted'); END",
+            )
+            .unwrap();
+            let engine = LoginStore::new(&db);
+            let mut telem = sync15::telemetry::Engine::new("passwords");
+            engine
+                .apply_incoming(first_sync_incoming(), &mut telem)
+                .unwrap_err();
+            let marker = db.get_sync_in_progress().unwrap().unwrap();
+            assert_eq!(marker.timestamp, 5000);
+            assert_eq!(marker.applied, Some(6));
+            assert_eq!(
+                db.query_one::<i64>("SELECT COUNT(*) FROM loginsM").unwrap(),
+                6
+            );
+        }
+
+        let db = LoginDb::open(&dbpath, Some("testing")).unwrap();
+        db.apply_batch_size.set(3);
+        let engine = LoginStore::new(&db);
+        let mut telem = sync15::telemetry::Engine::new("passwords");
+        let outgoing = engine
+            .apply_incoming(first_sync_incoming(), &mut telem)
+            .unwrap();
+        // Only the records which hadn't been applied yet were this time.
+        let telem = serde_json::to_value(&telem).unwrap();
+        assert_eq!(telem["incoming"]["applied"], 4);
+        assert_eq!(telem["incoming"].get("reconciled"), None);
+        assert_eq!(db.get_sync_in_progress().unwrap().unwrap().applied, None);
+        assert_eq!(first_sync_state(&db, &outgoing), expected);
+
+        // And only now does the last sync time move on.
+        assert_eq!(db.get_last_sync().unwrap(), None);
+        let guids = outgoing.changes.iter().map(|p| p.id.clone()).collect();
+        engine.sync_finished(ServerTimestamp(5000), guids).unwrap();
+        assert_eq!(db.get_last_sync().unwrap(), Some(ServerTimestamp(5000)));
+        assert_eq!(db.get_sync_in_progress().unwrap(), None);
+    }
+
+    #[test]
+    fn test_sync_finished_is_idempotent() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let engine = LoginStore::new(&db);
+        let mut telem = sync15::telemetry::Engine::new("passwords");
+        db.add(sync_login("https://www.example.com")).unwrap();
+        let outgoing = engine
+            .apply_incoming(
+                vec![IncomingChangeset::new("passwords", ServerTimestamp(1000))],
+                &mut telem,
+            )
+            .unwrap();
+        let guids: Vec<Guid> = outgoing.changes.iter().map(|p| p.id.clone()).collect();
+        engine
+            .sync_finished(ServerTimestamp(1000), guids.clone())
+            .unwrap();
+        engine.sync_finished(ServerTimestamp(1000), guids).unwrap();
+        assert_eq!(db.get_all().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_changed_during_upload() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let engine = LoginStore::new(&db);
+        let mut telem = sync15::telemetry::Engine::new("passwords");
+        let edited = db.add(sync_login("https://www.example.com")).unwrap();
+        let deleted = db.add(sync_login("https://www.example.org")).unwrap();
+        let unchanged = db.add(sync_login("https://www.example.net")).unwrap();
+        let outgoing = engine
+            .apply_incoming(
+                vec![IncomingChangeset::new("passwords", ServerTimestamp(1000))],
+                &mut telem,
+            )
+            .unwrap();
+        assert_eq!(outgoing.changes.len(), 3);
+
+        // The user edits one record and deletes another while we're uploading.
+        db.update(Login {
+            username: "new-username".into(),
+            ..edited.clone()
+        })
+        .unwrap();
+        db.delete(deleted.guid_str()).unwrap();
+
+        let mut guids: Vec<&str> = outgoing.changes.iter().map(|p| p.id.as_str()).collect();
+        guids.push("dummy_000099");
+        let report = db
+            .mark_as_synchronized(&guids, ServerTimestamp(1000), &engine.scope)
+            .unwrap();
+        assert_eq!(report.not_found, vec![Guid::from("dummy_000099")]);
+        let mut changed = report.changed_during_upload;
+        changed.sort();
+        let mut expected = vec![edited.guid.clone(), deleted.guid.clone()];
+        expected.sort();
+        assert_eq!(changed, expected);
+
+        // The mirror has what we uploaded, overridden by the local changes.
+        let mirror = |guid: &Guid| {
+            db.try_query_row(
+                "SELECT username, is_overridden FROM loginsM WHERE guid = :guid",
+                named_params! { ":guid": guid },
+                |row| Ok::<_, Error>((row.get::<_, String>(0)?, row.get::<_, bool>(1)?)),
+                true,
+            )
+            .unwrap()
+        };
+        assert_eq!(mirror(&edited.guid), Some((edited.username.clone(), true)));
+        assert_eq!(
+            mirror(&deleted.guid),
+            Some((deleted.username.clone(), true))
+        );
+        assert_eq!(
+            mirror(&unchanged.guid),
+            Some((unchanged.username.clone(), false))
+        );
+        assert_eq!(
+            db.query_one::<i64>("SELECT COUNT(*) FROM loginsPendingUpload")
+                .unwrap(),
+            0
+        );
+
+        // The changes are uploaded by the next sync.
+        let reupload = engine
+            .apply_incoming(
+                vec![IncomingChangeset::new("passwords", ServerTimestamp(2000))],
+                &mut telem,
+            )
+            .unwrap();
+        let mut reupload = reupload.changes;
+        reupload.sort_by(|a, b| a.id.cmp(&b.id));
+        let mut expected = vec![(edited.guid.clone(), false), (deleted.guid.clone(), true)];
+        expected.sort();
+        assert_eq!(
+            reupload
+                .iter()
+                .map(|p| (p.id.clone(), p.deleted))
+                .collect::<Vec<_>>(),
+            expected
+        );
+        let edited_payload = reupload.iter().find(|p| p.id == edited.guid).unwrap();
+        assert_eq!(edited_payload.data["username"], "new-username");
+        assert!(db.check_integrity().unwrap().is_empty());
+    }
+
+    fn add_touch_test_logins(db: &LoginDb) -> Vec<String> {
+        (1..=3)
+            .map(|i| {
+                db.add(Login {
+                    guid: format!("dummy_00000{}", i).into(),
+                    hostname: format!("https://www.example{}.com", i),
+                    http_realm: Some("https://www.example.com".into()),
+                    username: "test_user".into(),
+                    password: "test_password".into(),
+                    ..Login::default()
+                })
+                .unwrap()
+                .guid
+                .into_string()
+            })
+            .collect()
+    }
+
+    fn times_used(db: &LoginDb) -> Vec<(Guid, i64)> {
+        let mut result = db
+            .get_all()
+            .unwrap()
+            .into_iter()
+            .map(|l| (l.guid, l.times_used))
+            .collect::<Vec<_>>();
+        result.sort();
+        result
+    }
+
+    #[test]
+    fn test_touch_multiple() {
+        let touches = ["dummy_000001", "dummy_000002", "dummy_000001"];
+
+        let individually = LoginDb::open_in_memory(Some("testing")).unwrap();
+        add_touch_test_logins(&individually);
+        for id in &touches {
+            individually.touch(id).unwrap();
+        }
+
+        let batched = LoginDb::open_in_memory(Some("testing")).unwrap();
+        add_touch_test_logins(&batched);
+        let counter = batched.get_change_counter().unwrap();
+        batched.touch_multiple(&touches).unwrap();
+        assert_eq!(batched.get_change_counter().unwrap(), counter + 1);
+
+        assert_eq!(times_used(&batched), times_used(&individually));
+        assert_eq!(
+            times_used(&batched),
+            vec![
+                ("dummy_000001".into(), 3),
+                ("dummy_000002".into(), 2),
+                ("dummy_000003".into(), 1),
+            ]
+        );
+
+        assert!(batched.touch_multiple(&["dummy_000004"]).is_err());
+        assert_eq!(times_used(&batched), times_used(&individually));
+    }
+
+    #[test]
+    fn test_queued_touches() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let guids = add_touch_test_logins(&db);
+        db.queue_touch(&guids[0]);
+        db.queue_touch(&guids[1]);
+        db.queue_touch(&guids[0]);
+        db.queue_touch(&guids[2]);
+
+        // Nothing's written until we flush, and direct changes in the
+        // meantime don't lose the queued touches.
+        db.touch(&guids[0]).unwrap();
+        let login = db.get_by_id(&guids[1]).unwrap().unwrap();
+        db.update(Login {
+            password: "new_password".into(),
+            ..login
+        })
+        .unwrap();
+        db.delete(&guids[2]).unwrap();
+        let counter = db.get_change_counter().unwrap();
+
+        db.flush_touches().unwrap();
+        assert_eq!(db.get_change_counter().unwrap(), counter + 1);
+        assert_eq!(
+            times_used(&db),
+            vec![("dummy_000001".into(), 4), ("dummy_000002".into(), 3)]
+        );
+
+        // The queue is empty after a flush.
+        db.flush_touches().unwrap();
+        assert_eq!(db.get_change_counter().unwrap(), counter + 1);
+    }
+
+    #[test]
+    fn test_delete() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let _login = db
+            .add(Login {
+                hostname: "https://www.example.com".into(),
+                http_realm: Some("https://www.example.com".into()),
+                username: "test_user".into(),
+                password: "test_password".into(),
+                ..Login::default()
+            })
+            .unwrap();
+
+        assert!(db.delete(_login.guid_str()).unwrap());
+
+        let tombstone_exists: bool = db
+            .query_row_named(
+                "SELECT EXISTS(
+                    SELECT 1 FROM loginsL
                     WHERE guid = :guid AND is_deleted = 1
                 )",
                 named_params! { ":guid": _login.guid_str() },
@@ -1646,8 +3654,44 @@ mod tests {
             )
             .unwrap();
 
-        assert!(tombstone_exists);
-        assert!(!db.exists(_login.guid_str()).unwrap());
+        assert!(tombstone_exists);
+        assert!(!db.exists(_login.guid_str()).unwrap());
+    }
+
+    #[test]
+    fn test_change_deleted_record() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let login = db.add(sync_login("https://www.example.com")).unwrap();
+        sync_db(&db, vec![], ServerTimestamp(1000));
+        assert!(db.delete(login.guid_str()).unwrap());
+
+        let is_record_deleted = |e: Error| matches!(e.kind(), ErrorKind::RecordDeleted(_));
+        assert!(is_record_deleted(db.touch(login.guid_str()).unwrap_err()));
+        assert!(is_record_deleted(
+            db.touch_multiple(&[login.guid_str()]).unwrap_err()
+        ));
+        assert!(is_record_deleted(
+            db.update(Login {
+                password: "new-password".into(),
+                ..login.clone()
+            })
+            .unwrap_err()
+        ));
+        assert!(!db.exists(login.guid_str()).unwrap());
+
+        // Only the tombstone is uploaded.
+        let scope = db.begin_interrupt_scope();
+        let outgoing = db.fetch_outgoing(ServerTimestamp(2000), &scope).unwrap();
+        assert_eq!(outgoing.changes.len(), 1);
+        assert!(outgoing.changes[0].is_tombstone());
+        assert_eq!(db.check_integrity().unwrap(), Vec::<String>::new());
+
+        // Records which were never there at all are still missing, rather
+        // than deleted.
+        assert!(matches!(
+            db.touch("dummy_000001").unwrap_err().kind(),
+            ErrorKind::NoSuchRecord(_)
+        ));
     }
 
     #[test]
@@ -1982,4 +4026,845 @@ mod tests {
         assert!(ensure_valid_salt("deadbeef").is_err());
         assert!(ensure_valid_salt("deadbeefdeadbeefdeadbeefdeadbeef").is_ok());
     }
+
+    fn realm_columns(db: &LoginDb, table: &str, guid: &str) -> (Option<String>, Option<String>) {
+        db.query_row_named(
+            &format!(
+                "SELECT httpRealm, formSubmitURL FROM {} WHERE guid = :guid",
+                table
+            ),
+            named_params! { ":guid": guid },
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .unwrap()
+    }
+
+    // Apply an incoming tombstone for `guid`, deleted at `deleted_at`.
+    fn apply_tombstone(db: &LoginDb, guid: &str, deleted_at: ServerTimestamp) -> UpdatePlan {
+        let scope = db.begin_interrupt_scope();
+        let mut telem = sync15::telemetry::EngineIncoming::new();
+        let data = db
+            .fetch_login_data(
+                &[(Payload::new_tombstone(guid), deleted_at)],
+                &mut telem,
+                &scope,
+            )
+            .unwrap();
+        let plan = db
+            .reconcile(
+                data,
+                deleted_at,
+                &mut telem,
+                &scope,
+                db.tombstone_policy.get(),
+            )
+            .unwrap();
+        db.execute_plan(plan.clone(), deleted_at, &scope).unwrap();
+        plan
+    }
+
+    fn outgoing_guids(db: &LoginDb) -> Vec<Guid> {
+        let scope = db.begin_interrupt_scope();
+        let outgoing = db.fetch_outgoing(ServerTimestamp(0), &scope).unwrap();
+        outgoing.changes.into_iter().map(|p| p.id).collect()
+    }
+
+    // Adds a login, and syncs it, then changes it locally.
+    fn add_synced_then_edit(db: &LoginDb) -> Login {
+        let engine = LoginStore::new(db);
+        let mut telem = sync15::telemetry::Engine::new("passwords");
+        let login = db.add(sync_login("https://www.example.com")).unwrap();
+        let outgoing = engine
+            .apply_incoming(
+                vec![IncomingChangeset::new("passwords", ServerTimestamp(1000))],
+                &mut telem,
+            )
+            .unwrap();
+        let guids = outgoing.changes.iter().map(|p| p.id.clone()).collect();
+        engine.sync_finished(ServerTimestamp(1000), guids).unwrap();
+        db.update(Login {
+            password: "new-password".into(),
+            ..login.clone()
+        })
+        .unwrap();
+        login
+    }
+
+    fn ms_from_now(delta: i64) -> ServerTimestamp {
+        ServerTimestamp(util::system_time_ms_i64(SystemTime::now()) + delta)
+    }
+
+    #[test]
+    fn test_tombstone_vs_newer_edit() {
+        let five_days_ago = ms_from_now(-5 * 24 * 60 * 60 * 1000);
+
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let login = add_synced_then_edit(&db);
+        apply_tombstone(&db, login.guid_str(), five_days_ago);
+        let kept = db.get_by_id(login.guid_str()).unwrap().unwrap();
+        assert_eq!(kept.password, "new-password");
+        assert_eq!(
+            db.query_one::<i64>("SELECT COUNT(*) FROM loginsM").unwrap(),
+            0
+        );
+        assert_eq!(outgoing_guids(&db), vec![login.guid.clone()]);
+
+        // Without resurrection, the edit is lost.
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let login = add_synced_then_edit(&db);
+        db.set_tombstone_policy(TombstonePolicy::Delete);
+        apply_tombstone(&db, login.guid_str(), five_days_ago);
+        assert!(!db.exists(login.guid_str()).unwrap());
+        assert!(outgoing_guids(&db).is_empty());
+    }
+
+    #[test]
+    fn test_tombstone_vs_older_edit() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let login = add_synced_then_edit(&db);
+        apply_tombstone(&db, login.guid_str(), ms_from_now(60_000));
+        assert!(!db.exists(login.guid_str()).unwrap());
+        assert!(outgoing_guids(&db).is_empty());
+    }
+
+    #[test]
+    fn test_tombstone_vs_unsynced_new_record() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let login = db.add(sync_login("https://www.example.com")).unwrap();
+        // Even if the deletion looks newer, the record it deleted wasn't
+        // this one.
+        apply_tombstone(&db, login.guid_str(), ms_from_now(60_000));
+        let kept = db.get_by_id(login.guid_str()).unwrap().unwrap();
+        assert_eq!(kept.guid, login.guid);
+        assert_eq!(outgoing_guids(&db), vec![login.guid.clone()]);
+
+        db.set_tombstone_policy(TombstonePolicy::Delete);
+        apply_tombstone(&db, login.guid_str(), ms_from_now(60_000));
+        assert!(!db.exists(login.guid_str()).unwrap());
+    }
+
+    #[test]
+    fn test_attach_to_connection() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE embedder_data (id INTEGER PRIMARY KEY);
+             PRAGMA user_version = 7;",
+        )
+        .unwrap();
+        let db = LoginDb::attach_to_connection(conn, Some("ext_")).unwrap();
+
+        let login = db.add(sync_login("https://www.example.com")).unwrap();
+        let guid = login.guid_str().to_owned();
+        db.update(Login {
+            password: "new-password".into(),
+            ..login
+        })
+        .unwrap();
+        db.touch(&guid).unwrap();
+        sync_db(&db, vec![], ServerTimestamp(1000));
+        let synced = db.get_by_id(&guid).unwrap().unwrap();
+        assert_eq!(synced.password, "new-password");
+        assert_eq!(synced.times_used, 3);
+        assert_eq!(
+            db.query_one::<i64>("SELECT COUNT(*) FROM ext_loginsM")
+                .unwrap(),
+            1
+        );
+
+        assert!(db.delete(&guid).unwrap());
+        sync_db(&db, vec![], ServerTimestamp(2000));
+        assert!(db.get_all().unwrap().is_empty());
+        assert_eq!(
+            db.query_one::<i64>("SELECT COUNT(*) FROM ext_loginsL")
+                .unwrap(),
+            0
+        );
+
+        // Everything we created has the prefix, and the embedder's version
+        // is left alone.
+        let names = db
+            .query_rows_and_then_named(
+                "SELECT name FROM sqlite_master WHERE name NOT LIKE 'sqlite_%'",
+                &[],
+                |row| row.get::<_, String>(0),
+            )
+            .unwrap();
+        assert!(names.len() > 1);
+        for name in names {
+            assert!(
+                name == "embedder_data" || name.starts_with("ext_"),
+                "unprefixed {:?}",
+                name
+            );
+        }
+        assert_eq!(db.query_one::<i64>("PRAGMA user_version").unwrap(), 7);
+        assert_eq!(
+            db.get_meta::<i64>(schema::SCHEMA_VERSION_META_KEY).unwrap(),
+            Some(schema::VERSION)
+        );
+
+        // Attaching again finds the existing tables.
+        let db = LoginDb::attach_to_connection(db.db, Some("ext_")).unwrap();
+        assert_eq!(db.get_last_sync().unwrap(), Some(ServerTimestamp(2000)));
+    }
+
+    fn mirror_columns(db: &LoginDb, guid: &str) -> (String, String, String, bool, i64) {
+        db.query_row_named(
+            "SELECT hostname, username, password, is_overridden, server_modified
+             FROM loginsM WHERE guid = :guid",
+            named_params! { ":guid": guid },
+            |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_delete_scrubs_mirror() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        // The overwritten values are zeroed on disk, too.
+        assert_eq!(db.query_one::<i64>("PRAGMA secure_delete").unwrap(), 1);
+
+        let login = db.add(sync_login("https://www.example.com")).unwrap();
+        sync_db(&db, vec![], ServerTimestamp(1000));
+        assert!(db.delete(login.guid_str()).unwrap());
+        assert_eq!(
+            mirror_columns(&db, login.guid_str()),
+            ("".into(), "".into(), "".into(), true, 1000)
+        );
+
+        // A remote change to the record doesn't bring it back, or put the
+        // remote record's secrets into the mirror.
+        let mut incoming = IncomingChangeset::new("passwords", ServerTimestamp(2000));
+        incoming.changes.push((
+            Payload::from_record(Login {
+                password: "remote-password".into(),
+                ..login.clone()
+            })
+            .unwrap(),
+            ServerTimestamp(2000),
+        ));
+        let engine = LoginStore::new(&db);
+        let mut telem = sync15::telemetry::Engine::new("passwords");
+        let outgoing = engine.apply_incoming(vec![incoming], &mut telem).unwrap();
+        assert_eq!(outgoing.changes.len(), 1);
+        assert!(outgoing.changes[0].is_tombstone());
+        assert!(!db.exists(login.guid_str()).unwrap());
+        assert_eq!(
+            mirror_columns(&db, login.guid_str()),
+            ("".into(), "".into(), "".into(), true, 2000)
+        );
+        let guids = outgoing.changes.iter().map(|p| p.id.clone()).collect();
+        engine.sync_finished(ServerTimestamp(2000), guids).unwrap();
+        assert_eq!(
+            db.query_one::<i64>("SELECT COUNT(*) FROM loginsM").unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_wipe_scrubs_mirror() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        db.add(sync_login("https://www.example.com")).unwrap();
+        db.add(sync_login("https://www.example.org")).unwrap();
+        sync_db(&db, vec![], ServerTimestamp(1000));
+        db.wipe(&db.begin_interrupt_scope()).unwrap();
+        assert_eq!(
+            db.query_one::<i64>("SELECT COUNT(*) FROM loginsM").unwrap(),
+            2
+        );
+        assert_eq!(
+            db.query_one::<i64>(
+                "SELECT COUNT(*) FROM loginsM
+                 WHERE hostname <> '' OR username <> '' OR password <> ''"
+            )
+            .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_delete_without_scrubbing_mirror() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        db.set_scrub_mirror_on_delete(false);
+        let login = db.add(sync_login("https://www.example.com")).unwrap();
+        sync_db(&db, vec![], ServerTimestamp(1000));
+        db.delete(login.guid_str()).unwrap();
+        assert_eq!(
+            mirror_columns(&db, login.guid_str()),
+            (
+                "https://www.example.com".into(),
+                "user".into(),
+                "password".into(),
+                true,
+                1000
+            )
+        );
+    }
+
+    fn mirror_unknown_fields(db: &LoginDb, guid: &str) -> Option<String> {
+        db.query_row_named(
+            "SELECT unknown_fields FROM loginsM WHERE guid = :guid",
+            named_params! { ":guid": guid },
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_unknown_fields_round_trip() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let engine = LoginStore::new(&db);
+        let mut telem = sync15::telemetry::Engine::new("passwords");
+        let login = Login {
+            guid: "dummy_000001".into(),
+            ..sync_login("https://www.example.com")
+        };
+        let mut payload = Payload::from_record(login.clone()).unwrap();
+        payload
+            .data
+            .insert("newField".into(), serde_json::json!({ "a": [1, "two"] }));
+        payload
+            .data
+            .insert("futureFlag".into(), serde_json::json!(true));
+        let mut incoming = IncomingChangeset::new("passwords", ServerTimestamp(1000));
+        incoming.changes.push((payload, ServerTimestamp(1000)));
+        let outgoing = engine.apply_incoming(vec![incoming], &mut telem).unwrap();
+        assert!(outgoing.changes.is_empty());
+        engine.sync_finished(ServerTimestamp(1000), vec![]).unwrap();
+
+        // Changing the password locally uploads the fields unchanged.
+        db.update(Login {
+            password: "new-password".into(),
+            ..login.clone()
+        })
+        .unwrap();
+        let outgoing = engine
+            .apply_incoming(
+                vec![IncomingChangeset::new("passwords", ServerTimestamp(2000))],
+                &mut telem,
+            )
+            .unwrap();
+        assert_eq!(outgoing.changes.len(), 1);
+        let uploaded = &outgoing.changes[0].data;
+        assert_eq!(uploaded["password"], "new-password");
+        assert_eq!(uploaded["newField"], serde_json::json!({ "a": [1, "two"] }));
+        assert_eq!(uploaded["futureFlag"], serde_json::json!(true));
+
+        // And they stay in the mirror once the upload is done.
+        let guids = outgoing.changes.iter().map(|p| p.id.clone()).collect();
+        engine.sync_finished(ServerTimestamp(2000), guids).unwrap();
+        let fields = mirror_unknown_fields(&db, "dummy_000001").unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&fields).unwrap(),
+            serde_json::json!({ "newField": { "a": [1, "two"] }, "futureFlag": true })
+        );
+
+        // A newer version of the record without them replaces them.
+        let mut incoming = IncomingChangeset::new("passwords", ServerTimestamp(3000));
+        incoming.changes.push((
+            Payload::from_record(login.clone()).unwrap(),
+            ServerTimestamp(3000),
+        ));
+        engine.apply_incoming(vec![incoming], &mut telem).unwrap();
+        assert_eq!(mirror_unknown_fields(&db, "dummy_000001"), None);
+    }
+
+    #[test]
+    fn test_delete_scrubs_unknown_fields() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let engine = LoginStore::new(&db);
+        let mut telem = sync15::telemetry::Engine::new("passwords");
+        let login = Login {
+            guid: "dummy_000001".into(),
+            ..sync_login("https://www.example.com")
+        };
+        let mut payload = Payload::from_record(login).unwrap();
+        payload
+            .data
+            .insert("newField".into(), serde_json::json!("secret"));
+        let mut incoming = IncomingChangeset::new("passwords", ServerTimestamp(1000));
+        incoming.changes.push((payload, ServerTimestamp(1000)));
+        engine.apply_incoming(vec![incoming], &mut telem).unwrap();
+        assert!(mirror_unknown_fields(&db, "dummy_000001").is_some());
+
+        db.delete("dummy_000001").unwrap();
+        assert_eq!(mirror_unknown_fields(&db, "dummy_000001"), None);
+    }
+
+    fn local_change_flags(db: &LoginDb, guid: &str) -> u8 {
+        db.query_row_named(
+            "SELECT change_flags FROM loginsL WHERE guid = :guid",
+            named_params! { ":guid": guid },
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_change_flags() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let login = db.add(sync_login("https://www.example.com")).unwrap();
+        // Everything about a new record is a local change.
+        assert_eq!(
+            local_change_flags(&db, login.guid_str()),
+            change_flags::USAGE | change_flags::FIELDS
+        );
+        sync_db(&db, vec![], ServerTimestamp(1000));
+
+        db.touch(login.guid_str()).unwrap();
+        assert_eq!(
+            local_change_flags(&db, login.guid_str()),
+            change_flags::USAGE
+        );
+        // Updating a record without changing anything only counts as a use.
+        db.update(login.clone()).unwrap();
+        assert_eq!(
+            local_change_flags(&db, login.guid_str()),
+            change_flags::USAGE
+        );
+        db.update(Login {
+            password: "new-password".into(),
+            ..login.clone()
+        })
+        .unwrap();
+        assert_eq!(
+            local_change_flags(&db, login.guid_str()),
+            change_flags::USAGE | change_flags::FIELDS
+        );
+
+        sync_db(&db, vec![], ServerTimestamp(2000));
+        assert_eq!(
+            db.query_one::<i64>("SELECT COUNT(*) FROM loginsL").unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_local_use_vs_remote_password_change() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let login = db.add(sync_login("https://www.example.com")).unwrap();
+        sync_db(&db, vec![], ServerTimestamp(1000));
+        let synced = db.get_by_id(login.guid_str()).unwrap().unwrap();
+
+        db.touch(login.guid_str()).unwrap();
+        db.touch(login.guid_str()).unwrap();
+        let used = db.get_by_id(login.guid_str()).unwrap().unwrap();
+
+        // Another device changed the password, and used the login too, but
+        // longer ago.
+        let mut incoming = IncomingChangeset::new("passwords", ms_from_now(0));
+        incoming.changes.push((
+            Payload::from_record(Login {
+                password: "remote-password".into(),
+                times_used: synced.times_used + 3,
+                time_last_used: used.time_last_used - 1000,
+                time_password_changed: used.time_last_used - 1000,
+                ..synced.clone()
+            })
+            .unwrap(),
+            ms_from_now(0),
+        ));
+        let engine = LoginStore::new(&db);
+        let mut telem = sync15::telemetry::Engine::new("passwords");
+        engine.apply_incoming(vec![incoming], &mut telem).unwrap();
+
+        let merged = db.get_by_id(login.guid_str()).unwrap().unwrap();
+        assert_eq!(merged.password, "remote-password");
+        assert_eq!(merged.times_used, synced.times_used + 5);
+        assert_eq!(merged.time_last_used, used.time_last_used);
+    }
+
+    #[test]
+    fn test_usage_only_changes_dont_conflict() {
+        let now = ServerTimestamp(1000);
+        let shared = sync_login("https://www.example.com");
+        let three_way_merge = |flags: u8| {
+            let mut plan = UpdatePlan::default();
+            // Locally, the password looks different (say, from an earlier
+            // merge), and the local record is newer.
+            plan.plan_three_way_merge(
+                LocalLogin {
+                    login: Login {
+                        password: "local-password".into(),
+                        times_used: 2,
+                        ..shared.clone()
+                    },
+                    local_modified: SystemTime::now(),
+                    is_deleted: false,
+                    sync_status: SyncStatus::Changed,
+                    change_flags: flags,
+                },
+                MirrorLogin {
+                    login: shared.clone(),
+                    is_overridden: false,
+                    server_modified: now,
+                },
+                Login {
+                    password: "remote-password".into(),
+                    times_used: 1,
+                    ..shared.clone()
+                },
+                now,
+                ServerTimestamp(now.as_millis() + 60_000),
+            );
+            plan.local_updates[0].login.clone()
+        };
+
+        let merged = three_way_merge(change_flags::USAGE);
+        assert_eq!(merged.password, "remote-password");
+        assert_eq!(merged.times_used, 3);
+
+        let merged = three_way_merge(change_flags::USAGE | change_flags::FIELDS);
+        assert_eq!(merged.password, "local-password");
+        assert_eq!(merged.times_used, 3);
+    }
+
+    // Syncs a login, changes it here with `change_local` and on the server
+    // with `change_remote`, and syncs again. Returns the merged record, and
+    // what we uploaded.
+    fn merge_with_remote(
+        change_local: impl FnOnce(&mut Login),
+        change_remote: impl FnOnce(&mut Login),
+    ) -> (Login, Login) {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let engine = LoginStore::new(&db);
+        let mut telem = sync15::telemetry::Engine::new("passwords");
+        let login = db
+            .add(Login {
+                username_field: "username-field".into(),
+                password_field: "password-field".into(),
+                ..sync_login("https://www.example.com")
+            })
+            .unwrap();
+        let outgoing = engine
+            .apply_incoming(
+                vec![IncomingChangeset::new("passwords", ServerTimestamp(1000))],
+                &mut telem,
+            )
+            .unwrap();
+        let guids = outgoing.changes.iter().map(|p| p.id.clone()).collect();
+        engine.sync_finished(ServerTimestamp(1000), guids).unwrap();
+
+        let mut local = login.clone();
+        change_local(&mut local);
+        db.update(local).unwrap();
+        let mut remote = login.clone();
+        change_remote(&mut remote);
+        let now = util::system_time_ms_i64(SystemTime::now());
+        let mut incoming = IncomingChangeset::new("passwords", ServerTimestamp(now));
+        incoming
+            .changes
+            .push((Payload::from_record(remote).unwrap(), ServerTimestamp(1000)));
+        let outgoing = engine.apply_incoming(vec![incoming], &mut telem).unwrap();
+
+        let merged = db.get_by_id(login.guid_str()).unwrap().unwrap();
+        assert_eq!(outgoing.changes.len(), 1);
+        let uploaded = outgoing.changes[0].clone().into_record::<Login>().unwrap();
+        (merged, uploaded)
+    }
+
+    #[test]
+    fn test_merge_cleared_vs_unchanged() {
+        // Cleared here, while the server changed something else.
+        let (merged, uploaded) = merge_with_remote(
+            |login| {
+                login.username.clear();
+                login.username_field.clear();
+            },
+            |login| login.password = "remote-password".into(),
+        );
+        for login in &[merged, uploaded] {
+            assert_eq!(login.username, "");
+            assert_eq!(login.username_field, "");
+            assert_eq!(login.password, "remote-password");
+            assert_eq!(login.password_field, "password-field");
+        }
+
+        // And the other way around.
+        let (merged, uploaded) = merge_with_remote(
+            |login| login.password = "local-password".into(),
+            |login| login.password_field.clear(),
+        );
+        for login in &[merged, uploaded] {
+            assert_eq!(login.password, "local-password");
+            assert_eq!(login.password_field, "");
+            assert_eq!(login.username_field, "username-field");
+        }
+    }
+
+    #[test]
+    fn test_merge_both_cleared() {
+        let (merged, uploaded) = merge_with_remote(
+            |login| {
+                login.password_field.clear();
+                login.password = "local-password".into();
+            },
+            |login| {
+                login.password_field.clear();
+                login.username.clear();
+            },
+        );
+        for login in &[merged, uploaded] {
+            assert_eq!(login.password_field, "");
+            assert_eq!(login.username, "");
+            assert_eq!(login.password, "local-password");
+            assert_eq!(login.username_field, "username-field");
+        }
+    }
+
+    #[test]
+    fn test_merge_cleared_vs_changed() {
+        let now = ServerTimestamp(1000);
+        let shared = Login {
+            username_field: "username-field".into(),
+            ..sync_login("https://www.example.com")
+        };
+        // Here, the field is cleared, and on the server, it's changed.
+        let three_way_merge = |local_age: Duration, remote_age: Duration| {
+            let mut plan = UpdatePlan::default();
+            plan.plan_three_way_merge(
+                LocalLogin {
+                    login: Login {
+                        username_field: "".into(),
+                        ..shared.clone()
+                    },
+                    local_modified: SystemTime::now() - local_age,
+                    is_deleted: false,
+                    sync_status: SyncStatus::Changed,
+                    change_flags: change_flags::FIELDS,
+                },
+                MirrorLogin {
+                    login: shared.clone(),
+                    is_overridden: false,
+                    server_modified: now,
+                },
+                Login {
+                    username_field: "remote-field".into(),
+                    ..shared.clone()
+                },
+                now,
+                ServerTimestamp(now.as_millis() + remote_age.as_millis() as i64),
+            );
+            assert_eq!(plan.merge_conflicts, 1);
+            plan.local_updates[0].login.clone()
+        };
+
+        let merged = three_way_merge(Duration::default(), Duration::from_secs(60));
+        assert_eq!(merged.username_field, "");
+        let merged = three_way_merge(Duration::from_secs(60), Duration::default());
+        assert_eq!(merged.username_field, "remote-field");
+    }
+
+    #[test]
+    fn test_merge_realm_and_form_submit_url_together() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let engine = LoginStore::new(&db);
+        let mut telem = sync15::telemetry::Engine::new("passwords");
+        let login = db.add(sync_login("https://www.example.com")).unwrap();
+        let outgoing = engine
+            .apply_incoming(
+                vec![IncomingChangeset::new("passwords", ServerTimestamp(1000))],
+                &mut telem,
+            )
+            .unwrap();
+        let guids = outgoing.changes.iter().map(|p| p.id.clone()).collect();
+        engine.sync_finished(ServerTimestamp(1000), guids).unwrap();
+
+        // Locally, the form moves. Remotely, the record becomes an HTTP auth
+        // login instead. The local change is newer, so it should win, and
+        // we must not take the realm from the remote change.
+        db.update(Login {
+            form_submit_url: Some("https://accounts.example.com".into()),
+            ..login.clone()
+        })
+        .unwrap();
+        let now = util::system_time_ms_i64(SystemTime::now());
+        let mut incoming = incoming_changeset(vec![], ServerTimestamp(now));
+        incoming.changes.push((
+            LoginFixture::builder()
+                .guid(login.guid.clone())
+                .http_realm("Example")
+                .username("user")
+                .payload(),
+            ServerTimestamp(1000),
+        ));
+        let mut telem = sync15::telemetry::Engine::new("passwords");
+        engine.apply_incoming(vec![incoming], &mut telem).unwrap();
+
+        assert_eq!(
+            realm_columns(&db, "loginsL", login.guid_str()),
+            (None, Some("https://accounts.example.com".into()))
+        );
+        assert_eq!(
+            realm_columns(&db, "loginsM", login.guid_str()),
+            (Some("Example".into()), None)
+        );
+        let merged = db.get_by_id(login.guid_str()).unwrap().unwrap();
+        merged.check_valid().unwrap();
+    }
+
+    #[test]
+    fn test_repair_invalid_targets() {
+        let now = ServerTimestamp(1000);
+        let shared = sync_login("https://www.example.com");
+        let invalid = Login {
+            http_realm: None,
+            form_submit_url: None,
+            ..shared.clone()
+        };
+
+        let mut plan = UpdatePlan::default();
+        plan.plan_mirror_insert(invalid.clone(), now, false);
+        plan.plan_mirror_update(
+            Login {
+                http_realm: Some("Example".into()),
+                ..shared.clone()
+            },
+            now,
+        );
+        assert_eq!(plan.realm_repairs, 2);
+        assert_eq!(plan.mirror_inserts[0].0.form_submit_url, Some("".into()));
+        assert_eq!(plan.mirror_updates[0].0.http_realm, None);
+
+        // In a three-way merge, we prefer the newer side's values.
+        let three_way_merge = |local_age: Duration, remote_age: Duration| {
+            let mut plan = UpdatePlan::default();
+            plan.plan_three_way_merge(
+                LocalLogin {
+                    login: Login {
+                        form_submit_url: Some("https://accounts.example.com".into()),
+                        ..shared.clone()
+                    },
+                    local_modified: SystemTime::now() - local_age,
+                    is_deleted: false,
+                    sync_status: SyncStatus::Changed,
+                    change_flags: change_flags::FIELDS,
+                },
+                MirrorLogin {
+                    login: shared.clone(),
+                    is_overridden: false,
+                    server_modified: now,
+                },
+                invalid.clone(),
+                now,
+                ServerTimestamp(now.as_millis() + remote_age.as_millis() as i64),
+            );
+            assert!(plan.mirror_updates[0].0.has_valid_target());
+            plan
+        };
+
+        // The local change wins, so only the mirror needs repairing.
+        let plan = three_way_merge(Duration::default(), Duration::from_secs(60));
+        assert_eq!(plan.realm_repairs, 1);
+        let merged = &plan.local_updates[0].login;
+        assert_eq!(merged.http_realm, None);
+        assert_eq!(
+            merged.form_submit_url,
+            Some("https://accounts.example.com".into())
+        );
+
+        // The invalid remote change wins, so the merged record needs
+        // repairing too, from the (repaired) remote record.
+        let plan = three_way_merge(Duration::from_secs(60), Duration::default());
+        assert_eq!(plan.realm_repairs, 2);
+        let merged = &plan.local_updates[0].login;
+        assert_eq!(merged.http_realm, None);
+        assert_eq!(merged.form_submit_url, Some("".into()));
+    }
+
+    #[test]
+    fn test_add_sanitizes_timestamps() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let before = util::system_time_ms_i64(SystemTime::now());
+        let added = db
+            .add(Login {
+                time_created: -1,
+                time_password_changed: 1_500_000_000,
+                time_last_used: before * 1000,
+                ..sync_login("https://www.example.com")
+            })
+            .unwrap();
+        let after = util::system_time_ms_i64(SystemTime::now());
+        let stored = db.get_by_id(added.guid_str()).unwrap().unwrap();
+        assert_eq!(stored, added);
+        assert!(stored.time_created >= before && stored.time_created <= after);
+        assert_eq!(stored.time_password_changed, 1_500_000_000_000);
+        assert_eq!(stored.time_last_used, before);
+    }
+
+    #[test]
+    fn test_import_sanitizes_timestamps() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let now = util::system_time_ms_i64(SystemTime::now());
+        let plausible = now - 60_000;
+        let metrics = db
+            .import_multiple(&[
+                Login {
+                    guid: "garbage00000".into(),
+                    time_created: -5,
+                    time_password_changed: plausible * 1000,
+                    time_last_used: now + timestamps::FUTURE_SLACK_MS * 24,
+                    ..sync_login("https://www.example.com")
+                },
+                Login {
+                    guid: "plausible000".into(),
+                    time_created: plausible,
+                    time_password_changed: plausible,
+                    time_last_used: plausible,
+                    ..sync_login("https://www.example.org")
+                },
+            ])
+            .unwrap();
+        assert_eq!(metrics.num_succeeded, 2);
+        assert_eq!(metrics.num_fixed_timestamps, 3);
+
+        let garbage = db.get_by_id("garbage00000").unwrap().unwrap();
+        // Imports keep missing timestamps missing.
+        assert_eq!(garbage.time_created, 0);
+        assert_eq!(garbage.time_password_changed, plausible);
+        assert!(garbage.time_last_used >= now);
+        assert!(garbage.time_last_used < now + timestamps::FUTURE_SLACK_MS);
+
+        let plausible_login = db.get_by_id("plausible000").unwrap().unwrap();
+        assert_eq!(plausible_login.time_created, plausible);
+        assert_eq!(plausible_login.time_password_changed, plausible);
+        assert_eq!(plausible_login.time_last_used, plausible);
+    }
+
+    #[test]
+    fn test_incoming_sanitizes_timestamps() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let now = util::system_time_ms_i64(SystemTime::now());
+        let server_now = ServerTimestamp(now - 10_000);
+        let mut incoming = IncomingChangeset::new("passwords", server_now);
+        let login = Login {
+            guid: "remote000000".into(),
+            time_created: now / 1000,
+            time_password_changed: now,
+            time_last_used: now + timestamps::FUTURE_SLACK_MS * 24,
+            ..sync_login("https://www.example.com")
+        };
+        incoming
+            .changes
+            .push((Payload::from_record(login).unwrap(), server_now));
+
+        let engine = LoginStore::new(&db);
+        let mut telem = sync15::telemetry::Engine::new("passwords");
+        engine.apply_incoming(vec![incoming], &mut telem).unwrap();
+        assert!(format!("{:?}", telem).contains("repairedTimestamps"));
+
+        let stored = db.get_by_id("remote000000").unwrap().unwrap();
+        assert_eq!(stored.time_created, now / 1000 * 1000);
+        assert_eq!(stored.time_password_changed, now);
+        // The server's time, rather than ours.
+        assert_eq!(stored.time_last_used, server_now.as_millis());
+    }
 }
@@ -0,0 +1,247 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Everything we know about one record, for tracking down sync problems
+//! like "my password went back to an old one".
+//!
+//! That means both the local and mirror copies (see the [schema](crate::schema)
+//! docs) with their bookkeeping columns, which otherwise can only be seen by
+//! querying the database by hand. Passwords are replaced with their length
+//! and the start of their SHA-256 hash, which is enough to tell whether two
+//! copies match, unless `include_secrets` is passed.
+
+use crate::db::LoginDb;
+use crate::error::*;
+use crate::login::{Login, SyncStatus};
+use rc_crypto::digest;
+use rusqlite::{named_params, Row};
+use serde_derive::*;
+use sql_support::ConnExt;
+use std::time::SystemTime;
+use sync_guid::Guid;
+
+// How many bytes of the hash to show. Short enough that it doesn't help
+// guess the password.
+const HASH_PREFIX_LEN: usize = 4;
+
+/// The `loginsL` row for a record.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LocalRowDebugInfo {
+    pub login: Login,
+    /// Milliseconds, or None if it's never been changed locally.
+    pub local_modified: Option<i64>,
+    /// "Synced", "Changed" or "New".
+    pub sync_status: String,
+    pub is_deleted: bool,
+    pub change_flags: u8,
+}
+
+/// The `loginsM` row for a record.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MirrorRowDebugInfo {
+    pub login: Login,
+    /// Milliseconds.
+    pub server_modified: i64,
+    pub is_overridden: bool,
+    /// The size of the fields we kept because we don't know them, in bytes
+    /// of JSON.
+    pub unknown_fields_len: usize,
+}
+
+/// Returned by `get_record_debug_info`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordDebugInfo {
+    pub guid: Guid,
+    pub local: Option<LocalRowDebugInfo>,
+    pub mirror: Option<MirrorRowDebugInfo>,
+    /// When the last sync finished, in milliseconds of server time.
+    pub last_sync: Option<i64>,
+    /// Whether the next sync will upload the local copy.
+    pub would_upload: bool,
+    /// Whether the server's copy changed after the local one, so the local
+    /// copy is (or will be) a merge of the two rather than just what was
+    /// entered on this device.
+    pub has_conflict: bool,
+}
+
+impl LoginDb {
+    /// Both copies of the record `guid`, and how the next sync will treat
+    /// them, or None if there's neither. Passwords are redacted unless
+    /// `include_secrets` is true, which should only be used by local
+    /// debugging tools.
+    pub fn get_record_debug_info(
+        &self,
+        guid: &str,
+        include_secrets: bool,
+    ) -> Result<Option<RecordDebugInfo>> {
+        let local = self.try_query_row(
+            &self.sql("SELECT * FROM loginsL WHERE guid = :guid"),
+            named_params! { ":guid": guid },
+            |row| local_row(row, self),
+            false,
+        )?;
+        let mirror = self.try_query_row(
+            &self.sql("SELECT * FROM loginsM WHERE guid = :guid"),
+            named_params! { ":guid": guid },
+            |row| mirror_row(row, self),
+            false,
+        )?;
+        if local.is_none() && mirror.is_none() {
+            return Ok(None);
+        }
+        // Aged-out tombstones aren't uploaded; see `set_tombstone_retention`.
+        let cutoff = self.tombstone_cutoff(SystemTime::now());
+        let would_upload = local.as_ref().map_or(false, |l| {
+            let aged_out = l.is_deleted
+                && mirror.is_none()
+                && l.local_modified.map_or(false, |modified| modified < cutoff);
+            l.sync_status != format!("{:?}", SyncStatus::Synced) && !aged_out
+        });
+        let has_conflict = match (&local, &mirror) {
+            (Some(l), Some(m)) => would_upload && m.server_modified > l.local_modified.unwrap_or(0),
+            _ => false,
+        };
+        let mut info = RecordDebugInfo {
+            guid: guid.into(),
+            local,
+            mirror,
+            last_sync: self.get_last_sync()?.map(|ts| ts.as_millis() as i64),
+            would_upload,
+            has_conflict,
+        };
+        if !include_secrets {
+            if let Some(local) = &mut info.local {
+                local.login.password = redact(&local.login.password)?;
+            }
+            if let Some(mirror) = &mut info.mirror {
+                mirror.login.password = redact(&mirror.login.password)?;
+            }
+        }
+        Ok(Some(info))
+    }
+}
+
+fn local_row(row: &Row<'_>, db: &LoginDb) -> Result<LocalRowDebugInfo> {
+    Ok(LocalRowDebugInfo {
+        login: Login::from_row(row, db.encdec())?,
+        local_modified: row.get("local_modified")?,
+        sync_status: format!("{:?}", SyncStatus::from_u8(row.get("sync_status")?)?),
+        is_deleted: row.get("is_deleted")?,
+        change_flags: row.get("change_flags")?,
+    })
+}
+
+fn mirror_row(row: &Row<'_>, db: &LoginDb) -> Result<MirrorRowDebugInfo> {
+    Ok(MirrorRowDebugInfo {
+        login: Login::from_row(row, db.encdec())?,
+        server_modified: row.get("server_modified")?,
+        is_overridden: row.get("is_overridden")?,
+        unknown_fields_len: row
+            .get::<_, Option<String>>("unknown_fields")?
+            .map_or(0, |f| f.len()),
+    })
+}
+
+// Replaces a password with something like `<8 chars, sha256:1a2b3c4d>`.
+fn redact(password: &str) -> Result<String> {
+    rc_crypto::ensure_initialized();
+    let hash = digest::digest(&digest::SHA256, password.as_bytes())?;
+    let prefix = hash.as_ref()[..HASH_PREFIX_LEN]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    Ok(format!(
+        "<{} chars, sha256:{}>",
+        password.chars().count(),
+        prefix
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{sync_db, LoginFixture};
+    use sync15::ServerTimestamp;
+
+    fn login(guid: &str, password: &str) -> Login {
+        LoginFixture::builder()
+            .guid(guid)
+            .username("user")
+            .password(password)
+            .build()
+    }
+
+    #[test]
+    fn test_redact() {
+        // The SHA-256 of "password" starts with 5e884898.
+        assert_eq!(redact("password").unwrap(), "<8 chars, sha256:5e884898>");
+        assert_eq!(redact("pässword").unwrap().find("8 chars"), Some(1));
+    }
+
+    #[test]
+    fn test_local_and_mirror() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        assert_eq!(
+            db.get_record_debug_info("dummy_000001", false).unwrap(),
+            None
+        );
+        db.add(login("dummy_000001", "old-password")).unwrap();
+        sync_db(&db, vec![], ServerTimestamp(1000));
+        let synced = db.get_by_id("dummy_000001").unwrap().unwrap();
+        db.update(Login {
+            password: "new-password".into(),
+            ..synced
+        })
+        .unwrap();
+
+        let info = db
+            .get_record_debug_info("dummy_000001", false)
+            .unwrap()
+            .unwrap();
+        let local = info.local.as_ref().unwrap();
+        let mirror = info.mirror.as_ref().unwrap();
+        assert_eq!(local.sync_status, "Changed");
+        assert!(!local.is_deleted);
+        assert!(local.local_modified.is_some());
+        assert_eq!(mirror.server_modified, 1000);
+        assert!(mirror.is_overridden);
+        assert_eq!(info.last_sync, Some(1000));
+        assert!(info.would_upload);
+        assert!(!info.has_conflict);
+
+        // The passwords are different, and redacted.
+        assert!(local.login.password.starts_with("<12 chars, sha256:"));
+        assert!(mirror.login.password.starts_with("<12 chars, sha256:"));
+        assert_ne!(local.login.password, mirror.login.password);
+        let json = serde_json::to_string(&info).unwrap();
+        assert!(!json.contains("old-password"));
+        assert!(!json.contains("new-password"));
+
+        let info = db
+            .get_record_debug_info("dummy_000001", true)
+            .unwrap()
+            .unwrap();
+        assert_eq!(info.local.unwrap().login.password, "new-password");
+        assert_eq!(info.mirror.unwrap().login.password, "old-password");
+    }
+
+    #[test]
+    fn test_mirror_only() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        db.add(login("dummy_000001", "password")).unwrap();
+        sync_db(&db, vec![], ServerTimestamp(1000));
+
+        let info = db
+            .get_record_debug_info("dummy_000001", false)
+            .unwrap()
+            .unwrap();
+        assert_eq!(info.local, None);
+        let mirror = info.mirror.unwrap();
+        assert!(!mirror.is_overridden);
+        assert_eq!(mirror.login.password, "<8 chars, sha256:5e884898>");
+        assert_eq!(mirror.unknown_fields_len, 0);
+        assert!(!info.would_upload);
+        assert!(!info.has_conflict);
+    }
+}
@@ -0,0 +1,150 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The "never save passwords for this site" list.
+//!
+//! Hosts on this list are stored in the `loginsDisabledHosts` table (see the
+//! [schema](crate::schema) docs for its lifetime). The list is never synced,
+//! since desktop keeps it in a separate store.
+//!
+//! Hostnames are normalized the same way as the query APIs do it, so
+//! `https://www.example.com`, `http://www.example.com:8080/path` and
+//! `www.example.com` all refer to the same entry.
+
+use crate::db::LoginDb;
+use crate::error::*;
+use rusqlite::{named_params, NO_PARAMS};
+use sql_support::ConnExt;
+use url::Url;
+
+// Reduce a hostname, which may be an origin or a bare host, to just the
+// (normalized) host.
+pub(crate) fn normalize_hostname(hostname: &str) -> Result<String> {
+    // Bare hosts, possibly with a port, either fail to parse as a URL or
+    // parse without a host (`example.com:8080` has the scheme `example.com`),
+    // so we parse them as the host of an https URL instead.
+    let url = match Url::parse(hostname) {
+        Ok(url) if url.host().is_some() => url,
+        _ => Url::parse(&format!("https://{}", hostname))?,
+    };
+    Ok(url.host_str().unwrap_or_default().to_owned())
+}
+
+impl LoginDb {
+    /// Add `hostname` to, or remove it from, the list of sites we should never
+    /// offer to save logins for.
+    pub fn set_save_disabled(&self, hostname: &str, disabled: bool) -> Result<()> {
+        let host = normalize_hostname(hostname)?;
+        let sql = if disabled {
+            "INSERT OR IGNORE INTO loginsDisabledHosts (hostname) VALUES (:hostname)"
+        } else {
+            "DELETE FROM loginsDisabledHosts WHERE hostname = :hostname"
+        };
+        self.execute_named_cached(&self.sql(sql), named_params! { ":hostname": host })?;
+        Ok(())
+    }
+
+    /// Whether saving logins has been disabled for `hostname`.
+    pub fn is_save_disabled(&self, hostname: &str) -> Result<bool> {
+        let host = match normalize_hostname(hostname) {
+            Ok(host) => host,
+            Err(e) => {
+                // don't log the input string as it's PII.
+                log::warn!("is_save_disabled was passed an invalid hostname: {}", e);
+                return Ok(false);
+            }
+        };
+        Ok(self
+            .try_query_row(
+                &self.sql("SELECT 1 FROM loginsDisabledHosts WHERE hostname = :hostname"),
+                named_params! { ":hostname": host },
+                |_| Ok::<_, Error>(()),
+                true,
+            )?
+            .is_some())
+    }
+
+    /// Get every host saving logins has been disabled for, in their normalized
+    /// form.
+    pub fn get_disabled_hostnames(&self) -> Result<Vec<String>> {
+        let mut stmt = self.db.prepare_cached(
+            &self.sql("SELECT hostname FROM loginsDisabledHosts ORDER BY hostname"),
+        )?;
+        let rows =
+            stmt.query_and_then(NO_PARAMS, |row| Ok::<_, Error>(row.get::<_, String>(0)?))?;
+        rows.collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::login::Login;
+
+    #[test]
+    fn test_normalization() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        db.set_save_disabled("https://www.example.com", true)
+            .unwrap();
+        for variant in &[
+            "https://www.example.com",
+            "http://www.example.com",
+            "https://WWW.Example.com:8443/login",
+            "www.example.com",
+            "www.example.com:8080",
+        ] {
+            assert!(db.is_save_disabled(variant).unwrap(), "{}", variant);
+        }
+        assert!(!db.is_save_disabled("https://example.com").unwrap());
+        assert!(!db.is_save_disabled("https://mail.example.com").unwrap());
+        assert!(!db.is_save_disabled("").unwrap());
+
+        // Adding a variant doesn't add a second entry.
+        db.set_save_disabled("http://www.example.com:8080", true)
+            .unwrap();
+        db.set_save_disabled("http://[::1]:8080", true).unwrap();
+        assert_eq!(
+            db.get_disabled_hostnames().unwrap(),
+            vec!["[::1]".to_string(), "www.example.com".to_string()]
+        );
+
+        assert!(db.set_save_disabled("", true).is_err());
+    }
+
+    #[test]
+    fn test_toggle() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        assert!(!db.is_save_disabled("https://example.com").unwrap());
+        db.set_save_disabled("https://example.com", true).unwrap();
+        assert!(db.is_save_disabled("https://example.com").unwrap());
+        db.set_save_disabled("example.com", false).unwrap();
+        assert!(!db.is_save_disabled("https://example.com").unwrap());
+        assert!(db.get_disabled_hostnames().unwrap().is_empty());
+        // Enabling an enabled host is a no-op.
+        db.set_save_disabled("https://example.com", false).unwrap();
+    }
+
+    #[test]
+    fn test_wipes() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        db.add(Login {
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("https://www.example.com".into()),
+            username: "user".into(),
+            password: "password".into(),
+            ..Login::default()
+        })
+        .unwrap();
+        db.set_save_disabled("https://example.com", true).unwrap();
+
+        // The list isn't synced, so a sync-driven wipe leaves it alone...
+        db.wipe(&db.begin_interrupt_scope()).unwrap();
+        assert!(db.get_all().unwrap().is_empty());
+        assert!(db.is_save_disabled("https://example.com").unwrap());
+
+        // ...but it's local data, so a local wipe clears it.
+        db.wipe_local().unwrap();
+        assert!(db.get_disabled_hostnames().unwrap().is_empty());
+    }
+}
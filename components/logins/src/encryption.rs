@@ -0,0 +1,343 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Encrypting passwords inside the database.
+//!
+//! SQLCipher encrypts the whole database file, but once it's open, every
+//! password can be read with a single query. Embedders which can keep a key
+//! somewhere safer (say, the platform keystore) can supply an
+//! [EncryptorDecryptor] when opening the database, and the `password` column
+//! of `loginsL` and `loginsM` will then hold what it returns instead.
+//!
+//! This is transparent to the rest of the API: logins are decrypted as
+//! they're read, encrypted as they're written, and uploaded in cleartext
+//! (they're encrypted for sync separately, by the sync keys).
+//!
+//! Only passwords are encrypted. Usernames are compared in SQL to find
+//! duplicates, which couldn't be done with encryption that doesn't always
+//! produce the same output. Empty passwords, left behind by deletions, are
+//! stored as they are.
+
+use crate::error::*;
+
+/// Encrypts and decrypts the `password` column. Implementations should use
+/// authenticated encryption, so that `decrypt` fails when given something
+/// it didn't encrypt (or the wrong key) rather than returning garbage.
+pub trait EncryptorDecryptor: Send + Sync {
+    fn encrypt(&self, cleartext: &str) -> String;
+
+    /// Fails with `ErrorKind::DecryptionFailed` if `ciphertext` wasn't
+    /// produced by `encrypt`.
+    fn decrypt(&self, ciphertext: &str) -> Result<String>;
+}
+
+/// Stores passwords as they are, relying on SQLCipher alone. This is what
+/// the database uses when it's opened without an encryptor.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopEncryptor;
+
+impl EncryptorDecryptor for NoopEncryptor {
+    fn encrypt(&self, cleartext: &str) -> String {
+        cleartext.to_owned()
+    }
+
+    fn decrypt(&self, ciphertext: &str) -> Result<String> {
+        Ok(ciphertext.to_owned())
+    }
+}
+
+pub(crate) fn encrypt_password(encdec: &dyn EncryptorDecryptor, password: &str) -> String {
+    if password.is_empty() {
+        String::new()
+    } else {
+        encdec.encrypt(password)
+    }
+}
+
+pub(crate) fn decrypt_password(encdec: &dyn EncryptorDecryptor, password: &str) -> Result<String> {
+    if password.is_empty() {
+        Ok(String::new())
+    } else {
+        encdec.decrypt(password)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_utils {
+    use super::*;
+
+    /// XORs passwords with a key, for tests only. The prefix makes
+    /// decrypting something it didn't encrypt fail, like real authenticated
+    /// encryption would.
+    pub struct TestEncryptor {
+        pub key: Vec<u8>,
+    }
+
+    const PREFIX: &str = "test-enc:";
+
+    impl TestEncryptor {
+        pub fn new(key: &str) -> Self {
+            Self {
+                key: key.as_bytes().to_vec(),
+            }
+        }
+
+        fn xor(&self, data: &[u8]) -> Vec<u8> {
+            data.iter()
+                .zip(self.key.iter().cycle())
+                .map(|(b, k)| b ^ k)
+                .collect()
+        }
+    }
+
+    impl EncryptorDecryptor for TestEncryptor {
+        fn encrypt(&self, cleartext: &str) -> String {
+            let mut keyed = self.key.clone();
+            keyed.extend(self.xor(cleartext.as_bytes()));
+            format!("{}{}", PREFIX, base64::encode(&keyed))
+        }
+
+        fn decrypt(&self, ciphertext: &str) -> Result<String> {
+            let fail = |reason: &str| ErrorKind::DecryptionFailed(reason.to_owned());
+            if !ciphertext.starts_with(PREFIX) {
+                throw!(fail("not encrypted"));
+            }
+            let bytes =
+                base64::decode(&ciphertext[PREFIX.len()..]).map_err(|_| fail("not base64"))?;
+            if !bytes.starts_with(&self.key) {
+                throw!(fail("wrong key"));
+            }
+            String::from_utf8(self.xor(&bytes[self.key.len()..]))
+                .map_err(|_| fail("not UTF-8").into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_utils::TestEncryptor;
+    use super::*;
+    use crate::db::LoginDb;
+    use crate::login::Login;
+    use crate::testing::{sync_db, LoginFixture};
+    use rusqlite::{named_params, NO_PARAMS};
+    use sql_support::ConnExt;
+    use std::sync::Arc;
+    use sync15::{Payload, ServerTimestamp};
+
+    #[test]
+    fn test_round_trip() {
+        let encdec = TestEncryptor::new("key");
+        let encrypted = encrypt_password(&encdec, "hunter2");
+        assert_ne!(encrypted, "hunter2");
+        assert_eq!(decrypt_password(&encdec, &encrypted).unwrap(), "hunter2");
+
+        assert_eq!(encrypt_password(&encdec, ""), "");
+        assert_eq!(decrypt_password(&encdec, "").unwrap(), "");
+
+        assert_eq!(encrypt_password(&NoopEncryptor, "hunter2"), "hunter2");
+        assert_eq!(
+            decrypt_password(&NoopEncryptor, "hunter2").unwrap(),
+            "hunter2"
+        );
+    }
+
+    #[test]
+    fn test_decrypt_failure() {
+        let encdec = TestEncryptor::new("key");
+        let encrypted = encdec.encrypt("hunter2");
+        let failures = vec![
+            encdec.decrypt("hunter2"),
+            encdec.decrypt("test-enc:!!!"),
+            TestEncryptor::new("other").decrypt(&encrypted),
+        ];
+        for result in failures {
+            assert!(matches!(
+                result.unwrap_err().kind(),
+                ErrorKind::DecryptionFailed(_)
+            ));
+        }
+    }
+
+    fn login() -> Login {
+        LoginFixture::builder().username("user").build()
+    }
+
+    fn login_with_guid(guid: &str) -> Login {
+        LoginFixture::builder().guid(guid).username("user").build()
+    }
+
+    fn raw_passwords(db: &LoginDb, table: &str) -> Vec<String> {
+        db.query_rows_and_then_named(
+            &format!("SELECT password FROM {} ORDER BY guid", table),
+            &[],
+            |row| row.get::<_, String>(0),
+        )
+        .unwrap()
+    }
+
+    fn time_password_changed(db: &LoginDb, guid: &str) -> i64 {
+        db.query_row_named(
+            "SELECT timePasswordChanged FROM loginsL WHERE guid = :guid",
+            named_params! { ":guid": guid },
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_crud_and_sync() {
+        let encdec = TestEncryptor::new("key");
+        let db =
+            LoginDb::open_in_memory_with_encryptor(Arc::new(TestEncryptor::new("key"))).unwrap();
+
+        let added = db.add(login()).unwrap();
+        let raw = raw_passwords(&db, "loginsL");
+        assert_ne!(raw, vec!["password".to_owned()]);
+        assert_eq!(encdec.decrypt(&raw[0]).unwrap(), "password");
+        assert_eq!(
+            db.get_by_id(&added.guid).unwrap().unwrap().password,
+            "password"
+        );
+        assert_eq!(db.get_all().unwrap()[0].password, "password");
+
+        // Uploaded in cleartext, and kept encrypted in the mirror.
+        let (outgoing, _) = sync_db(&db, vec![], ServerTimestamp(1000));
+        let uploaded: Login = outgoing.changes[0].clone().into_record().unwrap();
+        assert_eq!(uploaded.password, "password");
+        let raw = raw_passwords(&db, "loginsM");
+        assert_eq!(encdec.decrypt(&raw[0]).unwrap(), "password");
+
+        // Updating without changing the password doesn't count as changing it.
+        let before = db.get_by_id(&added.guid).unwrap().unwrap();
+        db.update(before.clone()).unwrap();
+        assert_eq!(
+            time_password_changed(&db, &added.guid),
+            before.time_password_changed
+        );
+        db.update(Login {
+            password: "new-password".into(),
+            ..before
+        })
+        .unwrap();
+        assert_eq!(
+            db.get_by_id(&added.guid).unwrap().unwrap().password,
+            "new-password"
+        );
+        let (outgoing, _) = sync_db(&db, vec![], ServerTimestamp(2000));
+        let uploaded: Login = outgoing.changes[0].clone().into_record().unwrap();
+        assert_eq!(uploaded.password, "new-password");
+
+        // Incoming records are encrypted as they're stored.
+        let incoming = Login {
+            guid: "incoming_guid".into(),
+            hostname: "https://www.example.org".into(),
+            password: "incoming-password".into(),
+            ..login()
+        };
+        sync_db(
+            &db,
+            vec![Payload::from_record(incoming).unwrap()],
+            ServerTimestamp(3000),
+        );
+        assert!(!raw_passwords(&db, "loginsM").contains(&"incoming-password".to_owned()));
+        assert_eq!(
+            db.get_by_id("incoming_guid").unwrap().unwrap().password,
+            "incoming-password"
+        );
+
+        db.delete(&added.guid).unwrap();
+        assert!(db.get_by_id(&added.guid).unwrap().is_none());
+        assert_eq!(db.get_all().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_import() {
+        let db =
+            LoginDb::open_in_memory_with_encryptor(Arc::new(TestEncryptor::new("key"))).unwrap();
+        db.import_multiple(&[login()]).unwrap();
+        assert!(raw_passwords(&db, "loginsL")[0].starts_with("test-enc:"));
+        assert_eq!(db.get_all().unwrap()[0].password, "password");
+    }
+
+    #[test]
+    fn test_decrypt_failure_is_reported() {
+        let db =
+            LoginDb::open_in_memory_with_encryptor(Arc::new(TestEncryptor::new("key"))).unwrap();
+        let added = db.add(login()).unwrap();
+        db.execute("UPDATE loginsL SET password = 'garbage'", NO_PARAMS)
+            .unwrap();
+
+        let is_decryption_failure = |e: Error| matches!(e.kind(), ErrorKind::DecryptionFailed(_));
+        assert!(is_decryption_failure(
+            db.get_by_id(&added.guid).unwrap_err()
+        ));
+        assert!(is_decryption_failure(db.get_all().unwrap_err()));
+        assert!(is_decryption_failure(
+            db.get_by_base_domain("example.com").unwrap_err()
+        ));
+        assert!(is_decryption_failure(
+            db.update(login_with_guid(&added.guid)).unwrap_err()
+        ));
+
+        // Syncing fails, rather than skipping (or quarantining) every record.
+        let scope = db.begin_interrupt_scope();
+        assert!(is_decryption_failure(
+            db.fetch_outgoing(ServerTimestamp(0), &scope).unwrap_err()
+        ));
+        assert!(is_decryption_failure(
+            db.quarantine_invalid_local_rows().unwrap_err()
+        ));
+        assert_eq!(
+            db.query_one::<i64>("SELECT COUNT(*) FROM loginsQuarantine")
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_encrypt_existing_passwords() {
+        let dir = tempdir::TempDir::new("encrypt_existing_passwords").unwrap();
+        let path = dir.path().join("logins.sqlite");
+        let encdec = || Arc::new(TestEncryptor::new("key"));
+
+        let guid = {
+            let db = LoginDb::open(&path, Some("testing")).unwrap();
+            let synced = db.add(login()).unwrap();
+            sync_db(&db, vec![], ServerTimestamp(1000));
+            db.update(Login {
+                password: "new-password".into(),
+                ..synced.clone()
+            })
+            .unwrap();
+            assert_eq!(raw_passwords(&db, "loginsM"), vec!["password".to_owned()]);
+            synced.guid
+        };
+
+        {
+            let db = LoginDb::open_with_encryptor(&path, Some("testing"), encdec()).unwrap();
+            for table in &["loginsL", "loginsM"] {
+                assert!(raw_passwords(&db, table)[0].starts_with("test-enc:"));
+            }
+            assert_eq!(
+                db.get_by_id(&guid).unwrap().unwrap().password,
+                "new-password"
+            );
+            db.wipe_local().unwrap();
+            db.add(login()).unwrap();
+        }
+
+        // Opening again doesn't encrypt them twice.
+        {
+            let db = LoginDb::open_with_encryptor(&path, Some("testing"), encdec()).unwrap();
+            assert_eq!(db.get_all().unwrap()[0].password, "password");
+        }
+
+        // And they can't be read without the encryptor, even after a wipe.
+        assert!(matches!(
+            LoginDb::open(&path, Some("testing")).unwrap_err().kind(),
+            ErrorKind::DecryptionFailed(_)
+        ));
+    }
+}
@@ -0,0 +1,203 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Whether passwords are synced at all.
+//!
+//! There are two switches. The local one is this device's "sync passwords"
+//! setting, which the app reads and writes with
+//! `set_local_engine_enabled`/`get_local_engine_enabled`. While it's off,
+//! syncs neither download nor upload anything for the engine, and don't
+//! advance its last sync time. Turning it back on makes the next sync fetch
+//! every record and reconcile it with what we have, rather than trusting the
+//! mirror to be current.
+//!
+//! The other is the declined engines list in meta/global, which any device
+//! can change. `sync_multiple` already skips declined engines; we just keep
+//! whether we were declined as of the last sync, for the settings UI.
+//!
+//! Both are stored in `loginsSyncMeta` (see the [schema](crate::schema)
+//! docs), so they go along with the data rather than the app's preferences.
+
+use crate::db::LoginDb;
+use crate::error::*;
+use crate::schema;
+use sync15::ServerTimestamp;
+
+impl LoginDb {
+    /// Turn syncing passwords on this device on or off. While it's off,
+    /// syncs skip the engine without downloading or uploading anything.
+    /// Turning it back on makes the next sync fetch every record.
+    pub fn set_local_engine_enabled(&self, enabled: bool) -> Result<()> {
+        let tx = self.unchecked_transaction()?;
+        let was_enabled = self.get_local_engine_enabled()?;
+        self.put_meta(schema::LOCAL_ENGINE_ENABLED_META_KEY, &enabled)?;
+        if enabled && !was_enabled {
+            log::info!("Password engine enabled; the next sync will fetch everything");
+            self.set_last_sync(ServerTimestamp(0))?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Whether syncing passwords is turned on for this device. It is unless
+    /// `set_local_engine_enabled` turned it off.
+    pub fn get_local_engine_enabled(&self) -> Result<bool> {
+        Ok(self
+            .get_meta::<bool>(schema::LOCAL_ENGINE_ENABLED_META_KEY)?
+            .unwrap_or(true))
+    }
+
+    pub(crate) fn set_declined_remotely(&self, declined: bool) -> Result<()> {
+        self.put_meta(schema::DECLINED_REMOTELY_META_KEY, &declined)
+    }
+
+    /// Whether, as of the last sync which got that far, the passwords engine
+    /// was declined in meta/global, probably by another device. False if we
+    /// don't know.
+    pub fn get_declined_remotely(&self) -> Result<bool> {
+        Ok(self
+            .get_meta::<bool>(schema::DECLINED_REMOTELY_META_KEY)?
+            .unwrap_or(false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::LoginStore;
+    use crate::login::Login;
+    use crate::testing::LoginFixture;
+    use sync15::{telemetry, IncomingChangeset, Payload, SyncEngine};
+
+    fn login(guid: &str, password: &str) -> Login {
+        LoginFixture::builder()
+            .guid(guid)
+            .username(guid)
+            .password(password)
+            .build()
+    }
+
+    // Runs a sync the way `sync15::synchronize` would, with `server` as the
+    // records we download if we ask for any, and returns what we'd have
+    // uploaded, and whether we asked to download anything.
+    fn sync(db: &LoginDb, server: &[Login], ts: i64) -> (Vec<Payload>, bool) {
+        let engine = LoginStore::new(db);
+        let mut telem = telemetry::Engine::new("passwords");
+        let requests = engine.get_collection_requests(ServerTimestamp(ts)).unwrap();
+        let mut incoming = IncomingChangeset::new("passwords", ServerTimestamp(ts));
+        if !requests.is_empty() {
+            for login in server {
+                incoming.changes.push((
+                    Payload::from_record(login.clone()).unwrap(),
+                    ServerTimestamp(ts),
+                ));
+            }
+        }
+        let outgoing = engine.apply_incoming(vec![incoming], &mut telem).unwrap();
+        let guids = outgoing.changes.iter().map(|p| p.id.clone()).collect();
+        engine.sync_finished(ServerTimestamp(ts), guids).unwrap();
+        (outgoing.changes, !requests.is_empty())
+    }
+
+    #[test]
+    fn test_disabled_engine_doesnt_sync() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        assert!(db.get_local_engine_enabled().unwrap());
+        let local = db.add(login("dummy_000001", "local")).unwrap();
+        let (uploaded, _) = sync(&db, &[], 1000);
+        assert_eq!(uploaded.len(), 1);
+
+        db.set_local_engine_enabled(false).unwrap();
+        assert!(!db.get_local_engine_enabled().unwrap());
+        db.update(Login {
+            password: "changed".into(),
+            ..local.clone()
+        })
+        .unwrap();
+        let remote = login("dummy_000002", "remote");
+        let (uploaded, downloaded) = sync(&db, &[remote.clone()], 2000);
+        assert!(uploaded.is_empty());
+        assert!(!downloaded);
+        assert!(db.get_by_id("dummy_000002").unwrap().is_none());
+        assert_eq!(db.get_last_sync().unwrap(), Some(ServerTimestamp(1000)));
+
+        // Turning it back on fetches everything, including records older
+        // than the last sync, and uploads what changed in the meantime.
+        db.set_local_engine_enabled(true).unwrap();
+        assert_eq!(db.get_last_sync().unwrap(), Some(ServerTimestamp(0)));
+        let (uploaded, downloaded) = sync(&db, &[remote], 3000);
+        assert!(downloaded);
+        assert_eq!(uploaded.len(), 1);
+        assert_eq!(uploaded[0].id, "dummy_000001");
+        assert_eq!(uploaded[0].data["password"], "changed");
+        assert!(db.get_by_id("dummy_000002").unwrap().is_some());
+        assert_eq!(db.get_last_sync().unwrap(), Some(ServerTimestamp(3000)));
+
+        // Turning it on when it's already on doesn't.
+        db.set_local_engine_enabled(true).unwrap();
+        assert_eq!(db.get_last_sync().unwrap(), Some(ServerTimestamp(3000)));
+    }
+
+    #[test]
+    fn test_disabled_mid_sync() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        db.add(login("dummy_000001", "local")).unwrap();
+        let engine = LoginStore::new(&db);
+        let mut telem = telemetry::Engine::new("passwords");
+        assert!(!engine
+            .get_collection_requests(ServerTimestamp(1000))
+            .unwrap()
+            .is_empty());
+
+        // The app turns sync off after we've asked for records, but before
+        // they're applied.
+        db.set_local_engine_enabled(false).unwrap();
+        let mut incoming = IncomingChangeset::new("passwords", ServerTimestamp(1000));
+        incoming.changes.push((
+            Payload::from_record(login("dummy_000002", "remote")).unwrap(),
+            ServerTimestamp(1000),
+        ));
+        let outgoing = engine.apply_incoming(vec![incoming], &mut telem).unwrap();
+        assert!(outgoing.changes.is_empty());
+        engine.sync_finished(ServerTimestamp(1000), vec![]).unwrap();
+        assert!(db.get_by_id("dummy_000002").unwrap().is_none());
+        assert_eq!(db.get_last_sync().unwrap(), None);
+    }
+
+    #[test]
+    fn test_enabled_mid_sync() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        db.set_local_engine_enabled(false).unwrap();
+        db.add(login("dummy_000001", "local")).unwrap();
+        let engine = LoginStore::new(&db);
+        let mut telem = telemetry::Engine::new("passwords");
+        assert!(engine
+            .get_collection_requests(ServerTimestamp(1000))
+            .unwrap()
+            .is_empty());
+
+        // Turned back on after we skipped downloading. We mustn't carry on
+        // as if there was nothing to download.
+        db.set_local_engine_enabled(true).unwrap();
+        let incoming = IncomingChangeset::new("passwords", ServerTimestamp(1000));
+        let outgoing = engine.apply_incoming(vec![incoming], &mut telem).unwrap();
+        assert!(outgoing.changes.is_empty());
+        engine.sync_finished(ServerTimestamp(1000), vec![]).unwrap();
+        assert_eq!(db.get_last_sync().unwrap(), Some(ServerTimestamp(0)));
+
+        let (uploaded, downloaded) = sync(&db, &[], 2000);
+        assert!(downloaded);
+        assert_eq!(uploaded.len(), 1);
+    }
+
+    #[test]
+    fn test_declined_remotely() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        assert!(!db.get_declined_remotely().unwrap());
+        db.set_declined_remotely(true).unwrap();
+        assert!(db.get_declined_remotely().unwrap());
+        db.wipe_local().unwrap();
+        assert!(!db.get_declined_remotely().unwrap());
+    }
+}
@@ -10,6 +10,8 @@ macro_rules! throw {
     };
 }
 
+use crate::lifecycle::LifecycleState;
+
 #[derive(Debug, thiserror::Error)]
 pub enum ErrorKind {
     #[error("Invalid login: {0}")]
@@ -24,6 +26,11 @@ pub enum ErrorKind {
     #[error("No record with guid exists (when one was required): {0:?}")]
     NoSuchRecord(String),
 
+    // The record exists, but only as a local tombstone, so there's nothing
+    // left to change.
+    #[error("The record has been deleted: {0:?}")]
+    RecordDeleted(String),
+
     // Fennec import only works on empty logins tables.
     #[error("The logins tables are not empty")]
     NonEmptyTable,
@@ -31,6 +38,23 @@ pub enum ErrorKind {
     #[error("The provided salt is invalid")]
     InvalidSalt,
 
+    // The prefix passed to `attach_to_connection` isn't a valid part of a
+    // table name.
+    #[error("Invalid table name prefix: {0:?}")]
+    InvalidTablePrefix(String),
+
+    // See the `lifecycle` module.
+    #[error("Can't {action} while {state:?}")]
+    InvalidLifecycleTransition {
+        state: LifecycleState,
+        action: &'static str,
+    },
+
+    // `UploadDeletionsThenDisconnect` was passed to `on_disconnect` without
+    // a callback to upload with.
+    #[error("No callback to upload changes with before disconnecting")]
+    MissingFinalUpload,
+
     #[error("Error synchronizing: {0}")]
     SyncAdapterError(#[from] sync15::Error),
 
@@ -40,6 +64,33 @@ pub enum ErrorKind {
     #[error("Error executing SQL: {0}")]
     SqlError(#[from] rusqlite::Error),
 
+    // SQLite's SQLITE_FULL: the disk is full, or the database hit its
+    // `max_page_count`.
+    #[error("The database or disk is full: {0}")]
+    DatabaseFull(rusqlite::Error),
+
+    #[error("The database is {current} bytes, which is over its {max} byte quota")]
+    QuotaExceeded { current: u64, max: u64 },
+
+    // See the `host_cap` module.
+    #[error("The host already has {count} records, which is its limit of {max}")]
+    TooManyRecordsForHost {
+        hostname: String,
+        count: usize,
+        max: usize,
+    },
+
+    // The database was still locked by another connection after
+    // `open_with_retry` gave up.
+    #[error("The database is locked: {0}")]
+    DatabaseLocked(String),
+
+    #[error("The database is corrupt: {0}")]
+    DatabaseCorrupt(String),
+
+    #[error("The encryption key is wrong")]
+    WrongEncryptionKey,
+
     #[error("Error parsing URL: {0}")]
     UrlParseError(#[from] url::ParseError),
 
@@ -48,6 +99,29 @@ pub enum ErrorKind {
 
     #[error("Protobuf decode error: {0}")]
     ProtobufDecodeError(#[from] prost::DecodeError),
+
+    #[error("Invalid backup: {0}")]
+    InvalidBackup(String),
+
+    // Deliberately vague, this is what a wrong passphrase looks like.
+    #[error("Failed to decrypt backup (wrong passphrase or corrupt file)")]
+    BackupDecryptionFailed,
+
+    #[error("Exporting sync payloads includes passwords, so needs `include_secrets: true`")]
+    SecretsNotIncluded,
+
+    #[error("Crypto error: {0}")]
+    CryptoError(#[from] rc_crypto::Error),
+
+    // A password couldn't be decrypted by the `EncryptorDecryptor` the
+    // database was opened with.
+    #[error("Failed to decrypt a password: {0}")]
+    DecryptionFailed(String),
+
+    // The incoming records and what we have stored for them didn't line up
+    // the way `fetch_login_data` expects. This is a bug, not bad server data.
+    #[error("Incoming records don't match the local data: {0}")]
+    IncomingCorruption(String),
 }
 
 error_support::define_error! {
@@ -55,14 +129,29 @@ error_support::define_error! {
         (SyncAdapterError, sync15::Error),
         (JsonError, serde_json::Error),
         (UrlParseError, url::ParseError),
-        (SqlError, rusqlite::Error),
         (InvalidLogin, InvalidLogin),
         (Interrupted, interrupt_support::Interrupted),
         (ProtobufDecodeError, prost::DecodeError),
+        (CryptoError, rc_crypto::Error),
     }
 }
 
-#[derive(Debug, thiserror::Error)]
+// Not generated by `define_error!`, so that SQLITE_FULL gets its own kind.
+impl From<rusqlite::Error> for Error {
+    #[cold]
+    fn from(e: rusqlite::Error) -> Self {
+        match &e {
+            rusqlite::Error::SqliteFailure(err, _) if err.code == rusqlite::ErrorCode::DiskFull => {
+                ErrorKind::DatabaseFull(e).into()
+            }
+            _ => ErrorKind::SqlError(e).into(),
+        }
+    }
+}
+
+// Serialized for `validate`, as `{"reason": "IllegalFieldValue", "field_info": ...}`.
+#[derive(Debug, Clone, PartialEq, thiserror::Error, serde_derive::Serialize)]
+#[serde(tag = "reason")]
 pub enum InvalidLogin {
     // EmptyOrigin error occurs when the login's hostname field is empty.
     #[error("Origin is empty")]
@@ -87,12 +176,22 @@ impl Error {
             ErrorKind::BadSyncStatus(_) => "BadSyncStatus",
             ErrorKind::DuplicateGuid(_) => "DuplicateGuid",
             ErrorKind::NoSuchRecord(_) => "NoSuchRecord",
+            ErrorKind::RecordDeleted(_) => "RecordDeleted",
             ErrorKind::NonEmptyTable => "NonEmptyTable",
             ErrorKind::InvalidSalt => "InvalidSalt",
+            ErrorKind::InvalidTablePrefix(_) => "InvalidTablePrefix",
+            ErrorKind::InvalidLifecycleTransition { .. } => "InvalidLifecycleTransition",
+            ErrorKind::MissingFinalUpload => "MissingFinalUpload",
             ErrorKind::SyncAdapterError(_) => "SyncAdapterError",
             ErrorKind::JsonError(_) => "JsonError",
             ErrorKind::UrlParseError(_) => "UrlParseError",
             ErrorKind::SqlError(_) => "SqlError",
+            ErrorKind::DatabaseFull(_) => "DatabaseFull",
+            ErrorKind::QuotaExceeded { .. } => "QuotaExceeded",
+            ErrorKind::TooManyRecordsForHost { .. } => "TooManyRecordsForHost",
+            ErrorKind::DatabaseLocked(_) => "DatabaseLocked",
+            ErrorKind::DatabaseCorrupt(_) => "DatabaseCorrupt",
+            ErrorKind::WrongEncryptionKey => "WrongEncryptionKey",
             ErrorKind::Interrupted(_) => "Interrupted",
             ErrorKind::InvalidLogin(desc) => match desc {
                 InvalidLogin::EmptyOrigin => "InvalidLogin::EmptyOrigin",
@@ -103,6 +202,12 @@ impl Error {
                 InvalidLogin::IllegalFieldValue { .. } => "InvalidLogin::IllegalFieldValue",
             },
             ErrorKind::ProtobufDecodeError(_) => "BufDecodeError",
+            ErrorKind::InvalidBackup(_) => "InvalidBackup",
+            ErrorKind::BackupDecryptionFailed => "BackupDecryptionFailed",
+            ErrorKind::SecretsNotIncluded => "SecretsNotIncluded",
+            ErrorKind::CryptoError(_) => "CryptoError",
+            ErrorKind::DecryptionFailed(_) => "DecryptionFailed",
+            ErrorKind::IncomingCorruption(_) => "IncomingCorruption",
         }
     }
 }
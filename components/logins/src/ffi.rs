@@ -9,6 +9,17 @@ use crate::{Error, ErrorKind, InvalidLogin};
 use ffi_support::{implement_into_ffi_by_protobuf, ErrorCode, ExternError};
 use sync15::ErrorKind as Sync15ErrorKind;
 
+/// The error codes we hand to the Kotlin and Swift wrappers, which switch on
+/// them to decide how to recover.
+///
+/// These values are part of our public API: once shipped, a code is never
+/// renumbered or reused for a different error, even if the error it refers
+/// to goes away. General errors count up from 1, and the reasons a login may
+/// be invalid count up from 64.
+///
+/// For invalid logins, the `ExternError` message is prefixed with
+/// `InvalidLogin:<Reason>` (for example, `InvalidLogin:EmptyOrigin`), so
+/// that the reason survives consumers which only look at the message.
 pub mod error_codes {
     /// An unexpected error occurred which likely cannot be meaningfully handled
     /// by the application.
@@ -39,6 +50,24 @@ pub mod error_codes {
     /// An invalid salt was provided.
     pub const INVALID_SALT: i32 = 7;
 
+    /// The database is busy or locked by another connection. The operation
+    /// may succeed if retried later.
+    pub const DATABASE_BUSY: i32 = 8;
+
+    /// A write was refused because the database is over the maximum size set
+    /// with `set_max_db_size_bytes`. Deleting records frees up space.
+    pub const QUOTA_EXCEEDED: i32 = 9;
+
+    /// The disk (or the database) is full.
+    pub const DATABASE_FULL: i32 = 10;
+
+    /// The database file is corrupt.
+    pub const DATABASE_CORRUPT: i32 = 11;
+
+    /// An `add` was refused because the login's host already has as many
+    /// records as it's allowed. See `set_max_records_per_host`.
+    pub const TOO_MANY_RECORDS_FOR_HOST: i32 = 12;
+
     // Skip a bunch of spaces to make it clear these are part of a group,
     // even as more and more errors get added. We're only exposing the
     // InvalidLogin items that can actually be triggered, the others
@@ -72,6 +101,12 @@ fn get_code(err: &Error) -> ErrorCode {
             log::error!("No record exists with id {}", id);
             ErrorCode::new(error_codes::NO_SUCH_RECORD)
         }
+        // As far as the bindings are concerned, a deleted record doesn't
+        // exist, which is what they already handle.
+        ErrorKind::RecordDeleted(id) => {
+            log::error!("Record with id {} has been deleted", id);
+            ErrorCode::new(error_codes::NO_SUCH_RECORD)
+        }
         ErrorKind::InvalidLogin(desc) => {
             log::error!("Invalid login: {}", desc);
             ErrorCode::new(match desc {
@@ -94,6 +129,14 @@ fn get_code(err: &Error) -> ErrorCode {
             ErrorCode::new(error_codes::INVALID_KEY)
         }
 
+        ErrorKind::SqlError(rusqlite::Error::SqliteFailure(err, _))
+            if err.code == rusqlite::ErrorCode::DatabaseBusy
+                || err.code == rusqlite::ErrorCode::DatabaseLocked =>
+        {
+            log::warn!("Database busy or locked");
+            ErrorCode::new(error_codes::DATABASE_BUSY)
+        }
+
         ErrorKind::SqlError(rusqlite::Error::SqliteFailure(err, _))
             if err.code == rusqlite::ErrorCode::OperationInterrupted =>
         {
@@ -101,6 +144,37 @@ fn get_code(err: &Error) -> ErrorCode {
             ErrorCode::new(error_codes::INTERRUPTED)
         }
 
+        ErrorKind::WrongEncryptionKey => {
+            log::error!("Invalid key error");
+            ErrorCode::new(error_codes::INVALID_KEY)
+        }
+
+        ErrorKind::DatabaseLocked(_) => {
+            log::warn!("Database locked");
+            ErrorCode::new(error_codes::DATABASE_BUSY)
+        }
+
+        ErrorKind::DatabaseCorrupt(_) => {
+            log::error!("Database corrupt");
+            ErrorCode::new(error_codes::DATABASE_CORRUPT)
+        }
+
+        ErrorKind::QuotaExceeded { .. } => {
+            log::warn!("Database quota exceeded");
+            ErrorCode::new(error_codes::QUOTA_EXCEEDED)
+        }
+
+        ErrorKind::TooManyRecordsForHost { count, max, .. } => {
+            // don't log the hostname as it's PII.
+            log::warn!("Too many records for one host ({} of {})", count, max);
+            ErrorCode::new(error_codes::TOO_MANY_RECORDS_FOR_HOST)
+        }
+
+        ErrorKind::DatabaseFull(_) => {
+            log::error!("Database or disk full");
+            ErrorCode::new(error_codes::DATABASE_FULL)
+        }
+
         ErrorKind::Interrupted(_) => {
             log::warn!("Operation interrupted (Outside SQL)");
             ErrorCode::new(error_codes::INTERRUPTED)
@@ -118,11 +192,123 @@ fn get_code(err: &Error) -> ErrorCode {
     }
 }
 
+fn get_message(err: &Error) -> String {
+    match err.kind() {
+        ErrorKind::InvalidLogin(desc) => {
+            let reason = match desc {
+                InvalidLogin::EmptyOrigin => "EmptyOrigin",
+                InvalidLogin::EmptyPassword => "EmptyPassword",
+                InvalidLogin::DuplicateLogin => "DuplicateLogin",
+                InvalidLogin::BothTargets => "BothTargets",
+                InvalidLogin::NoTarget => "NoTarget",
+                InvalidLogin::IllegalFieldValue { .. } => "IllegalFieldValue",
+            };
+            format!("InvalidLogin:{}: {}", reason, desc)
+        }
+        _ => err.to_string(),
+    }
+}
+
 impl From<Error> for ExternError {
     fn from(e: Error) -> ExternError {
-        ExternError::new_error(get_code(&e), e.to_string())
+        ExternError::new_error(get_code(&e), get_message(&e))
     }
 }
 
 implement_into_ffi_by_protobuf!(msg_types::PasswordInfo);
 implement_into_ffi_by_protobuf!(msg_types::PasswordInfos);
+implement_into_ffi_by_protobuf!(msg_types::PasswordInfosByHostname);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::LoginFixture;
+    use crate::{Login, LoginDb, PasswordStore};
+    use std::time::Duration;
+
+    fn code_and_message<T>(res: crate::Result<T>) -> (i32, String) {
+        let err: ExternError = match res {
+            Ok(_) => panic!("expected an error"),
+            Err(e) => e.into(),
+        };
+        let result = (err.get_code().code(), err.get_message().as_str().to_owned());
+        unsafe { err.manually_release() };
+        result
+    }
+
+    fn login(hostname: &str) -> Login {
+        LoginFixture::builder()
+            .hostname(hostname)
+            .form_submit_url(hostname)
+            .username("user")
+            .build()
+    }
+
+    #[test]
+    fn test_record_errors() {
+        let store = PasswordStore::new_in_memory(Some("secret")).unwrap();
+        let added = store.add(login("https://www.example.com")).unwrap();
+
+        let mut missing = login("https://www.example.org");
+        missing.guid = "dummy_000001".into();
+        let (code, _) = code_and_message(store.update(missing));
+        assert_eq!(code, error_codes::NO_SUCH_RECORD);
+
+        let mut dupe_guid = login("https://www.example.net");
+        dupe_guid.guid = added.into();
+        let (code, _) = code_and_message(store.add(dupe_guid));
+        assert_eq!(code, error_codes::DUPLICATE_GUID);
+
+        let (code, message) = code_and_message(store.add(login("")));
+        assert_eq!(code, error_codes::INVALID_LOGIN_EMPTY_ORIGIN);
+        assert_eq!(message, "InvalidLogin:EmptyOrigin: Origin is empty");
+
+        let (code, message) = code_and_message(store.add(login("https://www.example.com")));
+        assert_eq!(code, error_codes::INVALID_LOGIN_DUPLICATE_LOGIN);
+        assert!(message.starts_with("InvalidLogin:DuplicateLogin"));
+    }
+
+    #[test]
+    fn test_interrupted() {
+        let db = LoginDb::open_in_memory(Some("secret")).unwrap();
+        let scope = db.begin_interrupt_scope();
+        db.new_interrupt_handle().interrupt();
+        let (code, _) = code_and_message(db.wipe(&scope));
+        assert_eq!(code, error_codes::INTERRUPTED);
+    }
+
+    #[test]
+    fn test_invalid_key() {
+        let dir = tempdir::TempDir::new("ffi_invalid_key").unwrap();
+        let path = dir.path().join("logins.sqlite");
+        PasswordStore::new(&path, Some("secret"))
+            .unwrap()
+            .add(login("https://www.example.com"))
+            .unwrap();
+        let (code, _) = code_and_message(PasswordStore::new(&path, Some("wrong")));
+        assert_eq!(code, error_codes::INVALID_KEY);
+    }
+
+    #[test]
+    fn test_database_corrupt() {
+        let dir = tempdir::TempDir::new("ffi_database_corrupt").unwrap();
+        let path = dir.path().join("logins.sqlite");
+        std::fs::write(&path, vec![0xffu8; 4096]).unwrap();
+        let (code, _) = code_and_message(PasswordStore::new(&path, None));
+        assert_eq!(code, error_codes::DATABASE_CORRUPT);
+    }
+
+    #[test]
+    fn test_database_busy() {
+        let dir = tempdir::TempDir::new("ffi_database_busy").unwrap();
+        let path = dir.path().join("logins.sqlite");
+        let holder = PasswordStore::new(&path, Some("secret")).unwrap();
+        let waiter = PasswordStore::new(&path, Some("secret")).unwrap();
+        waiter.db.busy_timeout(Duration::from_millis(0)).unwrap();
+
+        holder.db.execute_batch("BEGIN EXCLUSIVE").unwrap();
+        let (code, _) = code_and_message(waiter.add(login("https://www.example.com")));
+        assert_eq!(code, error_codes::DATABASE_BUSY);
+        holder.db.execute_batch("ROLLBACK").unwrap();
+    }
+}
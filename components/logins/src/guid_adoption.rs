@@ -0,0 +1,344 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Giving local records the guids of the server's copies of them.
+//!
+//! When a profile is imported (say, from Fennec or Desktop) before signing in
+//! to sync, the imported records are usually already on the server, under
+//! different guids. Left alone, the first sync looks for a dupe of each
+//! incoming record, one query at a time, and merges them one by one.
+//!
+//! Instead, `propose_guid_adoptions` matches the incoming records up with
+//! local ones in one go, with the same rules as the dupe check, and
+//! `adopt_remote_guids` renames the local records to match. Records which
+//! are then identical to the server's are moved straight to the mirror
+//! without merging. The first sync after a reset does both.
+
+use crate::db::LoginDb;
+use crate::error::*;
+use crate::login::{Login, SyncLoginData, SyncStatus};
+use crate::schema;
+use crate::util;
+use rusqlite::{named_params, NO_PARAMS};
+use serde_derive::*;
+use std::collections::{HashMap, HashSet};
+use sync15::{Payload, ServerTimestamp};
+use sync_guid::Guid;
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+pub struct AdoptReport {
+    pub num_adopted: u64,
+    /// The local guids which kept their guid, because the record wasn't a
+    /// new, undeleted local record, or the remote guid was invalid or
+    /// already in use.
+    pub refused: Vec<Guid>,
+}
+
+// What the dupe check (`LoginDb::find_dupe`) compares exactly. The form
+// target is compared with `form_submit_matches`.
+type DupeKey = (String, Option<String>, String);
+
+fn dupe_key(login: &Login) -> DupeKey {
+    (
+        login.hostname.clone(),
+        login.http_realm.clone(),
+        login.username.clone(),
+    )
+}
+
+// Whether a local record with `form_submit_url` could be a dupe of one whose
+// form target has the host and port `upstream_host_port`, as in `find_dupe`.
+fn form_submit_matches(form_submit_url: Option<&str>, upstream_host_port: Option<&str>) -> bool {
+    match (form_submit_url, upstream_host_port) {
+        (Some(url), Some(host_port)) => url.is_empty() || url.contains(host_port),
+        (url, None) => url.is_none(),
+        (None, Some(_)) => false,
+    }
+}
+
+impl LoginDb {
+    /// Find the new local records which are dupes of `incoming` records we
+    /// don't have yet, returning pairs of the local and remote guids. Each
+    /// local record is paired with at most one remote one. Nothing is
+    /// changed; pass the result to `adopt_remote_guids` for that.
+    pub fn propose_guid_adoptions(
+        &self,
+        incoming: &[(Payload, ServerTimestamp)],
+    ) -> Result<Vec<(Guid, Guid)>> {
+        let mut op = self.begin_op("propose_guid_adoptions");
+        let mut stmt = self.db.prepare(&self.sql(&format!(
+            "SELECT {common_cols} FROM loginsL
+             WHERE is_deleted = 0
+               AND sync_status = {new}",
+            common_cols = schema::COMMON_COLS,
+            new = SyncStatus::New as u8,
+        )))?;
+        // A local record which the server has under its own guid isn't a
+        // dupe of anything.
+        let incoming_guids = incoming
+            .iter()
+            .map(|(payload, _)| &payload.id)
+            .collect::<HashSet<_>>();
+        let mut candidates: HashMap<DupeKey, Vec<Login>> = HashMap::new();
+        for login in stmt.query_and_then(NO_PARAMS, |row| Login::from_row(row, self.encdec()))? {
+            let login = login?;
+            if incoming_guids.contains(&login.guid) {
+                continue;
+            }
+            candidates.entry(dupe_key(&login)).or_default().push(login);
+        }
+        if candidates.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut stmt = self
+            .db
+            .prepare(&self.sql("SELECT guid FROM loginsL UNION SELECT guid FROM loginsM"))?;
+        let known_guids = stmt
+            .query_map(NO_PARAMS, |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<HashSet<_>>>()?;
+
+        let mut proposed = HashSet::new();
+        let mut adoptions = vec![];
+        for (payload, timestamp) in incoming {
+            if known_guids.contains(payload.id.as_str()) || proposed.contains(&payload.id) {
+                continue;
+            }
+            // Tombstones, and records we can't read, are left to reconcile.
+            let upstream = match SyncLoginData::from_payload(payload.clone(), *timestamp) {
+                Ok(SyncLoginData {
+                    inbound: (Some(upstream), _),
+                    ..
+                }) => upstream,
+                _ => continue,
+            };
+            let locals = match candidates.get_mut(&dupe_key(&upstream)) {
+                Some(locals) => locals,
+                None => continue,
+            };
+            let host_port = upstream
+                .form_submit_url
+                .as_ref()
+                .and_then(|url| util::url_host_port(url));
+            let found = locals.iter().position(|local| {
+                form_submit_matches(local.form_submit_url.as_deref(), host_port.as_deref())
+            });
+            if let Some(i) = found {
+                let local = locals.remove(i);
+                proposed.insert(upstream.guid.clone());
+                adoptions.push((local.guid, upstream.guid));
+            }
+        }
+        op.set_rows(adoptions.len());
+        Ok(adoptions)
+    }
+
+    /// Rename each local record in `mappings` (pairs of the local and
+    /// remote guids, like `propose_guid_adoptions` returns) to its remote
+    /// guid, in one transaction. Only new, undeleted local records are
+    /// renamed, and only to valid guids we don't know about yet; the others
+    /// are left alone, and listed in the report.
+    pub fn adopt_remote_guids(&self, mappings: &[(Guid, Guid)]) -> Result<AdoptReport> {
+        let mut op = self.begin_op("adopt_remote_guids");
+        self.check_quota()?;
+        let tx = self.unchecked_transaction()?;
+        let mut report = AdoptReport::default();
+        let mut changed = vec![];
+        for (local_guid, remote_guid) in mappings {
+            if !self.can_adopt(local_guid, remote_guid)? {
+                log::warn!("Not adopting guid {} for {}", remote_guid, local_guid);
+                report.refused.push(local_guid.clone());
+                continue;
+            }
+            let args = named_params! {
+                ":local_guid": local_guid.as_str(),
+                ":remote_guid": remote_guid.as_str(),
+            };
+            self.execute_named_cached(
+                &self.sql("UPDATE loginsL SET guid = :remote_guid WHERE guid = :local_guid"),
+                args,
+            )?;
+            // Annotations are keyed by guid too.
+            self.execute_named_cached(
+                &self
+                    .sql("UPDATE loginsLocalMeta SET guid = :remote_guid WHERE guid = :local_guid"),
+                args,
+            )?;
+            changed.push(local_guid.as_str());
+            changed.push(remote_guid.as_str());
+            report.num_adopted += 1;
+        }
+        self.note_changed(&changed)?;
+        tx.commit()?;
+        op.set_rows(report.num_adopted as usize);
+        Ok(report)
+    }
+
+    fn can_adopt(&self, local_guid: &Guid, remote_guid: &Guid) -> Result<bool> {
+        if !remote_guid.is_valid_for_sync_server() || self.guid_in_use(remote_guid.as_str())? {
+            return Ok(false);
+        }
+        Ok(self.db.query_row_named(
+            &self.sql(
+                "SELECT EXISTS(
+                     SELECT 1 FROM loginsL
+                     WHERE guid = :guid
+                       AND is_deleted = 0
+                       AND sync_status = :new
+                 )",
+            ),
+            named_params! { ":guid": local_guid.as_str(), ":new": SyncStatus::New as u8 },
+            |row| row.get(0),
+        )?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::LoginStore;
+    use crate::testing::LoginFixture;
+    use sync15::{telemetry, IncomingChangeset, SyncEngine};
+
+    // What the server has for `login`, under a different guid.
+    fn remote(login: &Login) -> (Payload, ServerTimestamp) {
+        let payload = Payload::from_record(Login {
+            guid: Guid::random(),
+            ..login.clone()
+        })
+        .unwrap();
+        (payload, ServerTimestamp(1000))
+    }
+
+    fn sync(
+        db: &LoginDb,
+        records: Vec<(Payload, ServerTimestamp)>,
+    ) -> (Vec<Guid>, serde_json::Value) {
+        let engine = LoginStore::new(db);
+        let mut telem = telemetry::Engine::new("passwords");
+        let mut inbound = IncomingChangeset::new("passwords", ServerTimestamp(2000));
+        inbound.changes = records;
+        let outgoing = engine.apply_incoming(vec![inbound], &mut telem).unwrap();
+        let guids = outgoing
+            .changes
+            .into_iter()
+            .map(|p| p.id)
+            .collect::<Vec<_>>();
+        engine
+            .sync_finished(ServerTimestamp(2000), guids.clone())
+            .unwrap();
+        (guids, serde_json::to_value(&telem).unwrap())
+    }
+
+    #[test]
+    fn test_import_then_first_sync() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        // Older than the server's copies, so that the changed one wins.
+        let imported = (0..500)
+            .map(|n| {
+                LoginFixture::numbered(n)
+                    .time_created(1000)
+                    .time_password_changed(1000)
+                    .build()
+            })
+            .collect::<Vec<_>>();
+        db.import_multiple(&imported).unwrap();
+        let mut local = db.get_all().unwrap();
+        local.sort_by_key(|login| login.hostname.clone());
+        // A record the server doesn't have, and one which has changed since.
+        db.add(LoginFixture::numbered(500).build()).unwrap();
+        let mut records = local.iter().map(remote).collect::<Vec<_>>();
+        records[0] = remote(&Login {
+            password: "changed".into(),
+            time_password_changed: 2000,
+            ..local[0].clone()
+        });
+        let remote_guids = records
+            .iter()
+            .map(|(payload, _)| payload.id.clone())
+            .collect::<HashSet<_>>();
+
+        let proposed = db.propose_guid_adoptions(&records).unwrap();
+        assert_eq!(proposed.len(), 500);
+        // Proposing doesn't change anything.
+        assert_eq!(db.get_all().unwrap().len(), 501);
+        assert!(db.get_by_id(&local[0].guid).unwrap().is_some());
+
+        let (uploaded, telem) = sync(&db, records);
+        // Only the record the server didn't have goes up.
+        assert_eq!(uploaded.len(), 1);
+        assert!(!remote_guids.contains(&uploaded[0]));
+        // The identical records were applied, and only the changed one had
+        // to be merged.
+        assert_eq!(telem["incoming"]["applied"], 499);
+        assert_eq!(telem["incoming"]["reconciled"], 1);
+
+        let all = db.get_all().unwrap();
+        assert_eq!(all.len(), 501);
+        let all_guids = all.iter().map(|l| l.guid.clone()).collect::<HashSet<_>>();
+        assert!(remote_guids.is_subset(&all_guids));
+        let changed = all.iter().find(|l| l.hostname == local[0].hostname);
+        assert_eq!(changed.unwrap().password, "changed");
+    }
+
+    #[test]
+    fn test_adopt_remote_guids() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let synced = db.add(LoginFixture::numbered(0).build()).unwrap();
+        sync(&db, vec![]);
+        let new = db.add(LoginFixture::numbered(1).build()).unwrap();
+        let other = db.add(LoginFixture::numbered(2).build()).unwrap();
+        let deleted = db.add(LoginFixture::numbered(3).build()).unwrap();
+        db.delete(&deleted.guid).unwrap();
+        db.set_local_annotation(&new.guid, "key", "value").unwrap();
+
+        let remote_guid = Guid::random();
+        let report = db
+            .adopt_remote_guids(&[
+                (new.guid.clone(), remote_guid.clone()),
+                // Records which are already synced, or deleted, can't be
+                // renamed.
+                (synced.guid.clone(), Guid::random()),
+                (deleted.guid.clone(), Guid::random()),
+                // The guid's taken now.
+                (other.guid.clone(), remote_guid.clone()),
+                (other.guid.clone(), Guid::new("not a valid guid")),
+            ])
+            .unwrap();
+        assert_eq!(report.num_adopted, 1);
+        assert_eq!(
+            report.refused,
+            vec![
+                synced.guid.clone(),
+                deleted.guid.clone(),
+                other.guid.clone(),
+                other.guid.clone()
+            ]
+        );
+        assert!(db.get_by_id(&new.guid).unwrap().is_none());
+        let adopted = db.get_by_id(&remote_guid).unwrap().unwrap();
+        assert_eq!(adopted.username, new.username);
+        assert_eq!(
+            db.get_local_annotations(&remote_guid).unwrap()["key"],
+            "value"
+        );
+        assert!(db.get_by_id(&other.guid).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_form_submit_matches() {
+        let host_port = Some("www.example.com");
+        assert!(form_submit_matches(Some(""), host_port));
+        assert!(form_submit_matches(
+            Some("https://www.example.com"),
+            host_port
+        ));
+        assert!(!form_submit_matches(
+            Some("https://www.example.org"),
+            host_port
+        ));
+        assert!(!form_submit_matches(None, host_port));
+        assert!(form_submit_matches(None, None));
+        assert!(!form_submit_matches(Some(""), None));
+    }
+}
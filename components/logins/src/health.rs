@@ -0,0 +1,291 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Metadata for a "password health" score: how old each password is, and
+//! which records share a password with records for other sites.
+//!
+//! Finding reused passwords means comparing them, which we do here, so that
+//! the app never has to fetch them all. Nothing returned from this module
+//! contains a password. Sites are compared by host, normalized the same way
+//! as the "never save" list, so the same password on `https://example.com`
+//! and `http://example.com:8080` doesn't count as reuse.
+
+use crate::db::LoginDb;
+use crate::disabled_hosts::normalize_hostname;
+use crate::error::*;
+use crate::login::Login;
+use crate::schema;
+use crate::util;
+use lazy_static::lazy_static;
+use rusqlite::NO_PARAMS;
+use serde_derive::*;
+use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
+use sync_guid::Guid;
+
+/// Passwords which haven't been changed for at least this many days are
+/// counted in `HealthSummary::old_password_count`.
+pub const OLD_PASSWORD_DAYS: i64 = 365;
+
+const MS_PER_DAY: i64 = 24 * 60 * 60 * 1000;
+
+/// The health metadata for one record.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PasswordHealth {
+    pub guid: Guid,
+    pub hostname: String,
+    /// Whole days since `timePasswordChanged`. Never negative, even if the
+    /// record claims to have been changed in the future.
+    pub password_age_days: i64,
+    pub times_used: i64,
+    /// The guids of the records for other sites which have the same
+    /// password, sorted.
+    pub reused_on: Vec<Guid>,
+}
+
+/// Totals over the records returned by `get_password_health`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HealthSummary {
+    /// How many distinct passwords are used for more than one site.
+    pub reused_groups: u64,
+    /// How many records have a password at least `OLD_PASSWORD_DAYS` old.
+    pub old_password_count: u64,
+}
+
+lazy_static! {
+    // Every live record once: the local version if there is one, otherwise
+    // the mirror's.
+    static ref LIVE_RECORDS_SQL: String = format!(
+        "SELECT {common_cols} FROM loginsL WHERE is_deleted = 0
+         UNION ALL
+         SELECT {common_cols} FROM loginsM
+         WHERE is_overridden = 0
+           AND guid NOT IN (SELECT guid FROM loginsL)",
+        common_cols = schema::COMMON_COLS,
+    );
+}
+
+impl LoginDb {
+    /// The health metadata for every record, sorted by hostname and then
+    /// guid.
+    pub fn get_password_health(&self) -> Result<Vec<PasswordHealth>> {
+        Ok(self
+            .password_health_at(util::system_time_ms_i64(SystemTime::now()))?
+            .0)
+    }
+
+    /// Totals over the records `get_password_health` returns.
+    pub fn get_password_health_summary(&self) -> Result<HealthSummary> {
+        Ok(self
+            .password_health_at(util::system_time_ms_i64(SystemTime::now()))?
+            .1)
+    }
+
+    pub(crate) fn password_health_at(
+        &self,
+        now_ms: i64,
+    ) -> Result<(Vec<PasswordHealth>, HealthSummary)> {
+        let mut stmt = self.db.prepare_cached(&self.sql(&LIVE_RECORDS_SQL))?;
+        let logins = stmt
+            .query_and_then(NO_PARAMS, |row| Login::from_row(row, self.encdec()))?
+            .collect::<Result<Vec<_>>>()?;
+
+        let sites = logins
+            .iter()
+            .map(|l| normalize_hostname(&l.hostname).unwrap_or_else(|_| l.hostname.clone()))
+            .collect::<Vec<_>>();
+        let mut by_password: HashMap<&str, Vec<usize>> = HashMap::new();
+        for (i, login) in logins.iter().enumerate() {
+            by_password.entry(&login.password).or_default().push(i);
+        }
+
+        let mut health = logins
+            .iter()
+            .enumerate()
+            .map(|(i, login)| {
+                let mut reused_on = by_password[login.password.as_str()]
+                    .iter()
+                    .filter(|&&j| sites[j] != sites[i])
+                    .map(|&j| logins[j].guid.clone())
+                    .collect::<Vec<_>>();
+                reused_on.sort();
+                PasswordHealth {
+                    guid: login.guid.clone(),
+                    hostname: login.hostname.clone(),
+                    password_age_days: (now_ms - login.time_password_changed).max(0) / MS_PER_DAY,
+                    times_used: login.times_used,
+                    reused_on,
+                }
+            })
+            .collect::<Vec<_>>();
+        health.sort_by(|a, b| (&a.hostname, &a.guid).cmp(&(&b.hostname, &b.guid)));
+
+        let summary = HealthSummary {
+            reused_groups: by_password
+                .values()
+                .filter(|group| {
+                    group
+                        .iter()
+                        .map(|&i| &sites[i])
+                        .collect::<HashSet<_>>()
+                        .len()
+                        > 1
+                })
+                .count() as u64,
+            old_password_count: health
+                .iter()
+                .filter(|h| h.password_age_days >= OLD_PASSWORD_DAYS)
+                .count() as u64,
+        };
+        Ok((health, summary))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{sync_db, LoginFixture};
+    use sync15::ServerTimestamp;
+
+    const NOW: i64 = 1_600_000_000_000;
+
+    fn login(guid: &str, hostname: &str, password: &str, age_days: i64) -> Login {
+        LoginFixture::builder()
+            .guid(guid)
+            .hostname(hostname)
+            .form_submit_url(hostname)
+            .username(guid)
+            .password(password)
+            .time_password_changed(NOW - age_days * MS_PER_DAY - 1000)
+            .build()
+    }
+
+    fn reuse(health: &[PasswordHealth]) -> Vec<(&str, Vec<&str>)> {
+        health
+            .iter()
+            .map(|h| {
+                (
+                    h.guid.as_str(),
+                    h.reused_on.iter().map(Guid::as_str).collect(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_reuse() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        db.add(login("dummy_000001", "https://a.example.com", "shared", 0))
+            .unwrap();
+        db.add(login("dummy_000002", "https://b.example.com", "shared", 0))
+            .unwrap();
+        // The same site, with a different scheme and port.
+        db.add(login(
+            "dummy_000003",
+            "http://a.example.com:8080",
+            "shared",
+            0,
+        ))
+        .unwrap();
+        db.add(login("dummy_000004", "https://c.example.com", "unique", 0))
+            .unwrap();
+        db.add(login("dummy_000005", "https://c.example.com", "other", 0))
+            .unwrap();
+        db.add(login("dummy_000006", "https://d.example.com", "other", 0))
+            .unwrap();
+        // Mirror-only records count too.
+        sync_db(&db, vec![], ServerTimestamp(1000));
+        // Deleted ones don't.
+        db.add(login("dummy_000007", "https://e.example.com", "shared", 0))
+            .unwrap();
+        db.delete("dummy_000007").unwrap();
+
+        let (health, summary) = db.password_health_at(NOW).unwrap();
+        assert_eq!(
+            reuse(&health),
+            vec![
+                ("dummy_000003", vec!["dummy_000002"]),
+                ("dummy_000001", vec!["dummy_000002"]),
+                ("dummy_000002", vec!["dummy_000001", "dummy_000003"]),
+                ("dummy_000004", vec![]),
+                ("dummy_000005", vec!["dummy_000006"]),
+                ("dummy_000006", vec!["dummy_000005"]),
+            ]
+        );
+        assert_eq!(
+            summary,
+            HealthSummary {
+                reused_groups: 2,
+                old_password_count: 0,
+            }
+        );
+
+        // A local change to a synced record is what counts.
+        db.update(Login {
+            password: "changed".into(),
+            ..login("dummy_000006", "https://d.example.com", "other", 0)
+        })
+        .unwrap();
+        let (health, summary) = db.password_health_at(NOW).unwrap();
+        assert_eq!(health.len(), 6);
+        assert_eq!(summary.reused_groups, 1);
+    }
+
+    #[test]
+    fn test_age() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        db.add(login("dummy_000001", "https://a.example.com", "a", 0))
+            .unwrap();
+        db.add(login(
+            "dummy_000002",
+            "https://b.example.com",
+            "b",
+            OLD_PASSWORD_DAYS - 1,
+        ))
+        .unwrap();
+        db.add(login(
+            "dummy_000003",
+            "https://c.example.com",
+            "c",
+            OLD_PASSWORD_DAYS,
+        ))
+        .unwrap();
+        // Changed in the future, according to a device with a broken clock.
+        db.add(Login {
+            time_password_changed: NOW + MS_PER_DAY,
+            ..login("dummy_000004", "https://d.example.com", "d", 0)
+        })
+        .unwrap();
+
+        let (health, summary) = db.password_health_at(NOW).unwrap();
+        assert_eq!(
+            health
+                .iter()
+                .map(|h| h.password_age_days)
+                .collect::<Vec<_>>(),
+            vec![0, OLD_PASSWORD_DAYS - 1, OLD_PASSWORD_DAYS, 0]
+        );
+        assert_eq!(
+            summary,
+            HealthSummary {
+                reused_groups: 0,
+                old_password_count: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_no_secrets() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        db.add(login("dummy_000001", "https://a.example.com", "hunter2", 0))
+            .unwrap();
+        db.add(login("dummy_000002", "https://b.example.com", "hunter2", 0))
+            .unwrap();
+        let (health, summary) = db.password_health_at(NOW).unwrap();
+        assert_eq!(summary.reused_groups, 1);
+        let json = serde_json::to_string(&health).unwrap();
+        assert!(json.contains("dummy_000002"));
+        assert!(!json.contains("hunter2"));
+    }
+}
@@ -0,0 +1,250 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A limit on how many records one site can have.
+//!
+//! A page script which keeps changing a form, with an app which keeps
+//! offering to save it, can leave thousands of near-identical records for one
+//! host, which makes every lookup and sync slow for that user. So `add` and
+//! `import_multiple` refuse to save a record for a hostname which already has
+//! `set_max_records_per_host` live records, failing with (or, for imports,
+//! counting as) `ErrorKind::TooManyRecordsForHost`.
+//!
+//! Syncing doesn't enforce the limit, since we have to take whatever the
+//! server has, but if it leaves a host over the limit, it logs a warning,
+//! counts the hosts in its telemetry, and sets a flag which stays set until
+//! `wipe_local`, so that diagnostics can tell us about it. See
+//! `DbSizeInfo::hosts_over_record_cap`.
+
+use crate::db::LoginDb;
+use crate::error::*;
+use crate::schema;
+use rusqlite::named_params;
+use sql_support::ConnExt;
+
+/// How many records each hostname may have by default. This is far more
+/// than anyone should need for one site.
+pub const DEFAULT_MAX_RECORDS_PER_HOST: usize = 1000;
+
+impl LoginDb {
+    /// Set (or lift) the maximum number of live records a hostname may have
+    /// before `add` fails with `ErrorKind::TooManyRecordsForHost`.
+    /// `DEFAULT_MAX_RECORDS_PER_HOST` by default.
+    pub fn set_max_records_per_host(&self, max: Option<usize>) {
+        self.max_records_per_host.set(max);
+    }
+
+    // How many live records there are for `hostname`, which must be
+    // normalized like the records' hostnames.
+    fn count_records_for_host(&self, hostname: &str) -> Result<usize> {
+        let count = self.query_row_named(
+            &self.sql(
+                "SELECT (SELECT COUNT(*) FROM loginsL
+                         WHERE is_deleted = 0 AND hostname = :hostname)
+                      + (SELECT COUNT(*) FROM loginsM
+                         WHERE is_overridden = 0 AND hostname = :hostname
+                           AND guid NOT IN (SELECT guid FROM loginsL))",
+            ),
+            named_params! { ":hostname": hostname },
+            |row| row.get::<_, i64>(0),
+        )?;
+        Ok(count as usize)
+    }
+
+    /// Fail with `ErrorKind::TooManyRecordsForHost` if `hostname` already has
+    /// as many records as it's allowed. Called before adding a record.
+    pub(crate) fn check_host_record_count(&self, hostname: &str) -> Result<()> {
+        let max = match self.max_records_per_host.get() {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+        let count = self.count_records_for_host(hostname)?;
+        if count >= max {
+            // don't log the hostname as it's PII.
+            log::warn!(
+                "Refusing to add a record, its host has {} of {}",
+                count,
+                max
+            );
+            throw!(ErrorKind::TooManyRecordsForHost {
+                hostname: hostname.to_owned(),
+                count,
+                max,
+            });
+        }
+        Ok(())
+    }
+
+    /// Count the hosts with more records than they're allowed, after a sync
+    /// brought them in, and remember that it happened. Returns the count.
+    pub(crate) fn note_hosts_over_record_cap(&self) -> Result<usize> {
+        let max = match self.max_records_per_host.get() {
+            Some(max) => max,
+            None => return Ok(0),
+        };
+        let over = self.query_row_named(
+            &self.sql(
+                "SELECT COUNT(*) FROM (
+                     SELECT hostname FROM (
+                         SELECT hostname FROM loginsL WHERE is_deleted = 0
+                         UNION ALL
+                         SELECT hostname FROM loginsM
+                         WHERE is_overridden = 0
+                           AND guid NOT IN (SELECT guid FROM loginsL)
+                     )
+                     GROUP BY hostname
+                     HAVING COUNT(*) > :max
+                 )",
+            ),
+            named_params! { ":max": max as i64 },
+            |row| row.get::<_, i64>(0),
+        )? as usize;
+        if over > 0 {
+            log::warn!(
+                "Sync left {} hosts with more than {} records each",
+                over,
+                max
+            );
+            self.put_meta(schema::HOSTS_OVER_RECORD_CAP_META_KEY, &true)?;
+        }
+        Ok(over)
+    }
+
+    /// Whether a sync has ever left a host with more records than
+    /// `set_max_records_per_host` allows, since the last `wipe_local`.
+    pub fn hosts_over_record_cap(&self) -> Result<bool> {
+        Ok(self
+            .get_meta::<bool>(schema::HOSTS_OVER_RECORD_CAP_META_KEY)?
+            .unwrap_or(false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::LoginStore;
+    use crate::testing::{incoming_changeset, LoginFixture};
+    use sync15::{telemetry, ServerTimestamp, SyncEngine};
+
+    // The site all the logins in these tests are for, unless they say otherwise.
+    const HOST: &str = "https://www.example.com";
+
+    fn assert_too_many(err: Error, expected_count: usize, expected_max: usize) {
+        match err.kind() {
+            ErrorKind::TooManyRecordsForHost {
+                hostname,
+                count,
+                max,
+            } => {
+                assert_eq!(hostname, HOST);
+                assert_eq!(*count, expected_count);
+                assert_eq!(*max, expected_max);
+            }
+            e => panic!("Expected TooManyRecordsForHost, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_default_cap() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        assert_eq!(
+            db.max_records_per_host.get(),
+            Some(DEFAULT_MAX_RECORDS_PER_HOST)
+        );
+    }
+
+    #[test]
+    fn test_add_up_to_cap() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        db.set_max_records_per_host(Some(3));
+        let added = (0..3)
+            .map(|n| {
+                db.add(LoginFixture::numbered(n).hostname(HOST).build())
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_too_many(
+            db.add(LoginFixture::numbered(3).hostname(HOST).build())
+                .unwrap_err(),
+            3,
+            3,
+        );
+        assert_eq!(db.get_all().unwrap().len(), 3);
+
+        // Other hosts aren't affected...
+        db.add(
+            LoginFixture::builder()
+                .hostname("https://www.example2.com")
+                .form_submit_url("https://www.example2.com")
+                .build(),
+        )
+        .unwrap();
+        // ...deleted records don't count...
+        db.delete(&added[0].guid).unwrap();
+        db.add(LoginFixture::numbered(3).hostname(HOST).build())
+            .unwrap();
+        assert_too_many(
+            db.add(LoginFixture::numbered(4).hostname(HOST).build())
+                .unwrap_err(),
+            3,
+            3,
+        );
+        // ...and the limit can be lifted.
+        db.set_max_records_per_host(None);
+        db.add(LoginFixture::numbered(4).hostname(HOST).build())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_import_over_cap() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        db.set_max_records_per_host(Some(3));
+        let logins = (0..5)
+            .map(|n| LoginFixture::numbered(n).hostname(HOST).build())
+            .collect::<Vec<_>>();
+        let metrics = db.import_multiple(&logins).unwrap();
+        assert_eq!(metrics.num_succeeded(), 3);
+        assert_eq!(metrics.num_failed(), 2);
+        assert_eq!(
+            metrics.insert_phase().errors(),
+            vec!["TooManyRecordsForHost", "TooManyRecordsForHost"]
+        );
+        assert_eq!(db.get_all().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_sync_over_cap() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        db.set_max_records_per_host(Some(3));
+        for n in 0..3 {
+            db.add(LoginFixture::numbered(n).hostname(HOST).build())
+                .unwrap();
+        }
+        assert!(!db.hosts_over_record_cap().unwrap());
+
+        // The server's record is taken anyway, but we remember that it was
+        // one too many.
+        let incoming = LoginFixture::numbered(3).hostname(HOST).payload();
+        let engine = LoginStore::new(&db);
+        let mut telem = telemetry::Engine::new("passwords");
+        engine
+            .apply_incoming(
+                vec![incoming_changeset(vec![incoming], ServerTimestamp(1000))],
+                &mut telem,
+            )
+            .unwrap();
+        assert_eq!(db.get_all().unwrap().len(), 4);
+        assert!(db.hosts_over_record_cap().unwrap());
+        assert!(db.get_db_size_info().unwrap().hosts_over_record_cap);
+        assert!(format!("{:?}", telem).contains("hostsOverRecordCap"));
+
+        // Adding another is still refused.
+        assert_too_many(
+            db.add(LoginFixture::numbered(4).hostname(HOST).build())
+                .unwrap_err(),
+            4,
+            3,
+        );
+    }
+}
@@ -0,0 +1,204 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Looking up the records for several origins at once.
+//!
+//! Pages with a login form in an iframe, or "sign in with" buttons, need
+//! records for more than one origin. Fetching them in one call is quicker
+//! than a call per origin over the FFI, and means they all come from the
+//! same snapshot of the database.
+
+use crate::db::LoginDb;
+use crate::error::*;
+use crate::login::Login;
+use crate::schema;
+use std::collections::HashMap;
+
+impl LoginDb {
+    /// The records saved for each of `hostnames`, which are origins like
+    /// `https://www.example.com`, most recently used first. Results are
+    /// keyed by the hostnames as they were given, even if they needed
+    /// normalizing (say, they had a trailing `/`), and every one of them has
+    /// an entry, which is empty if there are no records for it or it isn't
+    /// a valid origin.
+    pub fn get_by_hostnames(&self, hostnames: &[&str]) -> Result<HashMap<String, Vec<Login>>> {
+        self.get_by_hostnames_chunked(hostnames, sql_support::default_max_variable_number())
+    }
+
+    fn get_by_hostnames_chunked(
+        &self,
+        hostnames: &[&str],
+        chunk_size: usize,
+    ) -> Result<HashMap<String, Vec<Login>>> {
+        let mut op = self.begin_op("get_by_hostnames");
+        let mut results = HashMap::with_capacity(hostnames.len());
+        // The hostnames we were given for each normalized one.
+        let mut requested: HashMap<String, Vec<&str>> = HashMap::new();
+        for &hostname in hostnames {
+            if results.insert(hostname.to_owned(), vec![]).is_some() {
+                continue;
+            }
+            match normalize_origin(hostname) {
+                Some(normalized) => requested.entry(normalized).or_default().push(hostname),
+                // don't log the input string as it's PII.
+                None => log::warn!("get_by_hostnames was passed an invalid origin"),
+            }
+        }
+
+        let normalized = requested.keys().map(String::as_str).collect::<Vec<_>>();
+        let mut found: HashMap<String, Vec<Login>> = HashMap::new();
+        sql_support::each_sized_chunk(&normalized, chunk_size, |chunk, _| -> Result<()> {
+            let sql = self.sql(&format!(
+                "WITH wanted(hostname) AS (VALUES {vals})
+                 SELECT {common_cols} FROM loginsL
+                 WHERE is_deleted = 0
+                   AND hostname IN (SELECT hostname FROM wanted)
+                 UNION ALL
+                 SELECT {common_cols} FROM loginsM
+                 WHERE is_overridden = 0
+                   AND hostname IN (SELECT hostname FROM wanted)",
+                vals = sql_support::repeat_sql_values(chunk.len()),
+                common_cols = schema::COMMON_COLS,
+            ));
+            let mut stmt = self.db.prepare(&sql)?;
+            for login in stmt.query_and_then(chunk, |row| Login::from_row(row, self.encdec()))? {
+                let login = login?;
+                found.entry(login.hostname.clone()).or_default().push(login);
+            }
+            Ok(())
+        })?;
+
+        let mut num_found = 0;
+        for (normalized, mut logins) in found {
+            logins.sort_by(|a, b| {
+                b.time_last_used
+                    .cmp(&a.time_last_used)
+                    .then_with(|| a.guid.cmp(&b.guid))
+            });
+            num_found += logins.len();
+            for &hostname in &requested[&normalized] {
+                results.insert(hostname.to_owned(), logins.clone());
+            }
+        }
+        op.set_rows(num_found);
+        Ok(results)
+    }
+}
+
+// `origin`, normalized the way the hostnames of records are when they're
+// saved, or `None` if it isn't a valid origin.
+fn normalize_origin(origin: &str) -> Option<String> {
+    match Login::validate_and_fixup_origin(origin) {
+        Ok(Some(fixed)) => Some(fixed),
+        Ok(None) => Some(origin.to_owned()),
+        Err(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{sync_db, LoginFixture};
+    use sync15::ServerTimestamp;
+
+    fn login(hostname: &str, username: &str) -> Login {
+        LoginFixture::builder()
+            .hostname(hostname)
+            .form_submit_url(hostname)
+            .username(username)
+            .build()
+    }
+
+    fn usernames(results: &HashMap<String, Vec<Login>>, hostname: &str) -> Vec<String> {
+        results[hostname]
+            .iter()
+            .map(|login| login.username.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_get_by_hostnames() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        db.add(Login {
+            time_last_used: 1000,
+            ..login("https://www.example.com", "synced")
+        })
+        .unwrap();
+        sync_db(&db, vec![], ServerTimestamp(1000));
+        db.add(login("https://www.example.com", "local")).unwrap();
+        db.add(login("https://accounts.example.org", "other"))
+            .unwrap();
+        db.add(login("https://www.example.net", "unrequested"))
+            .unwrap();
+
+        let results = db
+            .get_by_hostnames(&[
+                "https://www.example.com",
+                "https://ACCOUNTS.example.org/",
+                "https://www.example.com",
+                "https://nothing.example.com",
+                "not an origin",
+            ])
+            .unwrap();
+        assert_eq!(results.len(), 4);
+        // The one in the mirror is included, and the one used most recently
+        // comes first. Asking twice doesn't give us two of each.
+        assert_eq!(
+            usernames(&results, "https://www.example.com"),
+            vec!["local", "synced"]
+        );
+        assert_eq!(
+            usernames(&results, "https://ACCOUNTS.example.org/"),
+            vec!["other"]
+        );
+        assert!(results["https://nothing.example.com"].is_empty());
+        assert!(results["not an origin"].is_empty());
+
+        // Two spellings of the same origin both get its records.
+        let results = db
+            .get_by_hostnames(&[
+                "https://accounts.example.org",
+                "HTTPS://accounts.example.org",
+            ])
+            .unwrap();
+        assert_eq!(
+            usernames(&results, "https://accounts.example.org"),
+            vec!["other"]
+        );
+        assert_eq!(
+            usernames(&results, "HTTPS://accounts.example.org"),
+            vec!["other"]
+        );
+    }
+
+    #[test]
+    fn test_get_by_hostnames_chunked() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let hostnames = (0..160)
+            .map(|n| format!("https://www{}.example.com", n))
+            .collect::<Vec<_>>();
+        for hostname in hostnames.iter().step_by(2) {
+            db.add(login(hostname, "user")).unwrap();
+        }
+        // These are only in the mirror once they're synced.
+        sync_db(&db, vec![], ServerTimestamp(1000));
+        for hostname in hostnames.iter().skip(100).step_by(2) {
+            db.add(login(hostname, "local")).unwrap();
+        }
+
+        let requested = hostnames.iter().map(String::as_str).collect::<Vec<_>>();
+        for &chunk_size in &[7, 50, 1000] {
+            let results = db.get_by_hostnames_chunked(&requested, chunk_size).unwrap();
+            assert_eq!(results.len(), hostnames.len());
+            for (n, hostname) in hostnames.iter().enumerate() {
+                let expected = match n {
+                    n if n % 2 == 1 => 0,
+                    n if n >= 100 => 2,
+                    _ => 1,
+                };
+                assert_eq!(results[hostname].len(), expected, "{}", hostname);
+            }
+        }
+    }
+}
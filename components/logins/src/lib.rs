@@ -9,20 +9,71 @@
 mod error;
 mod login;
 
+mod annotations;
+mod backup;
+mod changes;
+mod consistency;
 mod db;
+mod debug_info;
+mod disabled_hosts;
+mod encryption;
+mod engine_state;
+mod guid_adoption;
+mod health;
+mod host_cap;
+mod hostname_lookup;
+mod lifecycle;
+mod migrate;
+mod op_stats;
+mod open;
+mod payload_export;
+mod quarantine;
+mod quota;
+mod recent_deletions;
 pub mod schema;
 mod store;
+mod summaries;
+#[cfg(test)]
+mod sync_proptests;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod testing;
+mod timestamps;
+mod tombstone_retention;
+mod unknown_fields;
+mod update_fields;
 mod update_plan;
 mod util;
+mod validation;
 
 mod ffi;
 
+pub use crate::annotations::BREACHED_ANNOTATION_KEY;
+pub use crate::backup::{ExportSummary, ImportMetrics, ImportMode};
+pub use crate::changes::ChangesSince;
+pub use crate::consistency::RepairReport;
 // Mostly exposed for the sync manager.
 pub use crate::db::LoginDb;
 pub use crate::db::LoginStore;
+pub use crate::debug_info::{LocalRowDebugInfo, MirrorRowDebugInfo, RecordDebugInfo};
+pub use crate::encryption::{EncryptorDecryptor, NoopEncryptor};
 pub use crate::error::*;
+pub use crate::guid_adoption::AdoptReport;
+pub use crate::health::{HealthSummary, PasswordHealth, OLD_PASSWORD_DAYS};
+pub use crate::host_cap::DEFAULT_MAX_RECORDS_PER_HOST;
+pub use crate::lifecycle::{DisconnectPolicy, FinalUpload, LifecycleState, UploadedChanges};
 pub use crate::login::*;
+pub use crate::migrate::{LegacyImportReport, LegacySyncMetadataFormat};
+pub use crate::op_stats::{DebugOptions, OpStats, StatementStats, RECENT_OP_STATS_CAPACITY};
+pub use crate::open::{HealthStatus, KeyVerification, RetryConfig};
+pub use crate::payload_export::{PayloadExportOptions, PayloadExportSummary};
+pub use crate::quota::DbSizeInfo;
+pub use crate::recent_deletions::RemoteDeletion;
 pub use crate::store::*;
+pub use crate::summaries::{HostnameSummary, HostnameSummaryOrder};
+pub use crate::tombstone_retention::DEFAULT_TOMBSTONE_RETENTION;
+pub use crate::update_fields::LoginChanges;
+pub use crate::update_plan::TombstonePolicy;
+pub use crate::validation::{validate, ValidationResult};
 
 pub mod msg_types {
     include!("mozilla.appservices.logins.protobuf.rs");
@@ -0,0 +1,394 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Connecting to and disconnecting from a sync account.
+//!
+//! Signing out used to mean calling some combination of `reset`, `wipe` and
+//! `wipe_local`, and the order mattered: wiping after resetting leaves
+//! tombstones which are never uploaded, and resetting before a final sync
+//! drops whatever hadn't been uploaded yet. `on_disconnect` does the whole
+//! thing in one call, with a `DisconnectPolicy` saying what happens to the
+//! local data, and `on_connect` undoes it when the user signs in again.
+//!
+//! In between, we're `Disconnected`, and syncing fails with
+//! `InvalidLifecycleTransition`. The state is stored in `loginsSyncMeta`
+//! (see the [schema](crate::schema) docs), so it survives restarts, and
+//! `wipe_local`.
+
+use crate::db::LoginDb;
+use crate::error::*;
+use crate::schema;
+use sync15::{EngineSyncAssociation, OutgoingChangeset, ServerTimestamp};
+use sync_guid::Guid;
+
+/// Whether we're associated with a sync account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LifecycleState {
+    /// Syncing is allowed. This is where every database starts.
+    Connected,
+    /// `on_disconnect` has run, and syncing fails until `on_connect` does.
+    Disconnected,
+}
+
+impl LifecycleState {
+    fn as_str(self) -> &'static str {
+        match self {
+            LifecycleState::Connected => "connected",
+            LifecycleState::Disconnected => "disconnected",
+        }
+    }
+}
+
+/// What `on_disconnect` does with the local data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectPolicy {
+    /// Keep the records, and forget everything we know about the sync
+    /// account, as `reset` does. Changes which haven't been uploaded are
+    /// kept, but won't be uploaded to this account.
+    KeepLocalData,
+    /// Delete the records, and everything we know about the sync account, as
+    /// `wipe_local` does.
+    DeleteLocalData,
+    /// Upload the changes (including deletions) which haven't been yet, with
+    /// the callback passed to `on_disconnect`, then `KeepLocalData`.
+    UploadDeletionsThenDisconnect,
+}
+
+/// What the upload callback passed to `on_disconnect` managed to upload.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UploadedChanges {
+    /// The server's timestamp for the upload.
+    pub timestamp: ServerTimestamp,
+    /// The ids of the records the server accepted. Records which aren't
+    /// listed stay changed locally.
+    pub guids: Vec<Guid>,
+}
+
+/// Uploads the outgoing changes for `UploadDeletionsThenDisconnect`.
+pub type FinalUpload<'a> = dyn FnMut(OutgoingChangeset) -> Result<UploadedChanges> + 'a;
+
+impl LoginDb {
+    /// Whether we're connected to a sync account.
+    pub fn get_lifecycle_state(&self) -> Result<LifecycleState> {
+        let state = self.get_meta::<String>(schema::LIFECYCLE_STATE_META_KEY)?;
+        Ok(match state.as_deref() {
+            None | Some("connected") => LifecycleState::Connected,
+            Some("disconnected") => LifecycleState::Disconnected,
+            Some(other) => {
+                log::warn!("Unknown lifecycle state {:?}; assuming connected", other);
+                LifecycleState::Connected
+            }
+        })
+    }
+
+    fn set_lifecycle_state(&self, state: LifecycleState) -> Result<()> {
+        self.put_meta(schema::LIFECYCLE_STATE_META_KEY, &state.as_str())
+    }
+
+    /// Fails with `InvalidLifecycleTransition` unless we're connected, and
+    /// so allowed to sync.
+    pub(crate) fn check_can_sync(&self) -> Result<()> {
+        let state = self.get_lifecycle_state()?;
+        if state != LifecycleState::Connected {
+            throw!(ErrorKind::InvalidLifecycleTransition {
+                state,
+                action: "sync",
+            });
+        }
+        Ok(())
+    }
+
+    /// Disconnect from the sync account, doing what `policy` says with the
+    /// local data. `final_upload` is required for
+    /// `UploadDeletionsThenDisconnect`, and ignored otherwise; if it fails,
+    /// we stay connected and nothing is reset, so it's safe to try again.
+    ///
+    /// Once we're disconnected, only `DeleteLocalData` is allowed again;
+    /// anything else fails with `InvalidLifecycleTransition`.
+    pub fn on_disconnect(
+        &self,
+        policy: DisconnectPolicy,
+        final_upload: Option<&mut FinalUpload<'_>>,
+    ) -> Result<()> {
+        let state = self.get_lifecycle_state()?;
+        if state == LifecycleState::Disconnected && policy != DisconnectPolicy::DeleteLocalData {
+            throw!(ErrorKind::InvalidLifecycleTransition {
+                state,
+                action: "disconnect",
+            });
+        }
+        log::info!("Disconnecting with {:?}", policy);
+        match policy {
+            DisconnectPolicy::KeepLocalData => {}
+            DisconnectPolicy::DeleteLocalData => {
+                self.wipe_local()?;
+            }
+            DisconnectPolicy::UploadDeletionsThenDisconnect => {
+                let final_upload = match final_upload {
+                    Some(final_upload) => final_upload,
+                    None => throw!(ErrorKind::MissingFinalUpload),
+                };
+                self.upload_before_disconnect(final_upload)?;
+            }
+        }
+        // Resetting and recording the new state can't share a transaction,
+        // since `reset` has its own. Doing them in this order means that if
+        // we crash in between, we've reset, as the old API would have.
+        self.reset(&EngineSyncAssociation::Disconnected)?;
+        self.set_lifecycle_state(LifecycleState::Disconnected)
+    }
+
+    fn upload_before_disconnect(&self, final_upload: &mut FinalUpload<'_>) -> Result<()> {
+        // If syncing passwords is off, the user didn't want them uploaded.
+        if !self.get_local_engine_enabled()? {
+            return Ok(());
+        }
+        let scope = self.begin_interrupt_scope();
+        let last_sync = self.get_last_sync()?.unwrap_or_default();
+        let outgoing = self.fetch_outgoing(last_sync, &scope)?;
+        if outgoing.changes.is_empty() {
+            return Ok(());
+        }
+        log::debug!(
+            "Uploading {} records before disconnecting",
+            outgoing.changes.len()
+        );
+        let uploaded = final_upload(outgoing)?;
+        self.mark_as_synchronized(
+            &uploaded.guids.iter().map(Guid::as_str).collect::<Vec<_>>(),
+            uploaded.timestamp,
+            &scope,
+        )?;
+        Ok(())
+    }
+
+    /// Connect to a sync account again, after `on_disconnect`. Does nothing
+    /// if we're already connected.
+    pub fn on_connect(&self) -> Result<()> {
+        if self.get_lifecycle_state()? == LifecycleState::Connected {
+            return Ok(());
+        }
+        log::info!("Reconnecting");
+        self.set_lifecycle_state(LifecycleState::Connected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::LoginStore;
+    use crate::login::Login;
+    use crate::store::PasswordStore;
+    use crate::testing::LoginFixture;
+    use sql_support::ConnExt;
+    use sync15::{telemetry, IncomingChangeset, SyncEngine};
+
+    fn login(guid: &str) -> Login {
+        LoginFixture::builder().guid(guid).username(guid).build()
+    }
+
+    // Runs a sync with nothing incoming, returning the guids we uploaded.
+    fn sync(db: &LoginDb, ts: i64) -> anyhow::Result<Vec<Guid>> {
+        let engine = LoginStore::new(db);
+        let mut telem = telemetry::Engine::new("passwords");
+        let incoming = IncomingChangeset::new("passwords", ServerTimestamp(ts));
+        let outgoing = engine.apply_incoming(vec![incoming], &mut telem)?;
+        let guids: Vec<Guid> = outgoing.changes.iter().map(|p| p.id.clone()).collect();
+        engine.sync_finished(ServerTimestamp(ts), guids.clone())?;
+        Ok(guids)
+    }
+
+    fn assert_lifecycle_error(result: Result<()>, action: &str) {
+        match result.unwrap_err().kind() {
+            ErrorKind::InvalidLifecycleTransition {
+                state: LifecycleState::Disconnected,
+                action: a,
+            } => assert_eq!(*a, action),
+            kind => panic!("unexpected error {:?}", kind),
+        }
+    }
+
+    fn count(db: &LoginDb, table: &str) -> i64 {
+        db.query_one::<i64>(&format!("SELECT COUNT(*) FROM {}", table))
+            .unwrap()
+    }
+
+    // A database with two synced records, one of which has since been
+    // deleted, and a new one which hasn't been uploaded yet.
+    fn db_with_pending_changes() -> LoginDb {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        db.add(login("dummy_000001")).unwrap();
+        db.add(login("dummy_000002")).unwrap();
+        sync(&db, 1000).unwrap();
+        db.put_meta(schema::GLOBAL_SYNCID_META_KEY, &"global")
+            .unwrap();
+        db.put_meta(schema::COLLECTION_SYNCID_META_KEY, &"coll")
+            .unwrap();
+        db.delete("dummy_000001").unwrap();
+        db.add(login("dummy_000003")).unwrap();
+        db
+    }
+
+    fn assert_reset(db: &LoginDb) {
+        assert_eq!(db.get_last_sync().unwrap(), Some(ServerTimestamp(0)));
+        assert_eq!(
+            db.get_meta::<String>(schema::GLOBAL_SYNCID_META_KEY)
+                .unwrap(),
+            None
+        );
+        assert_eq!(count(db, "loginsM"), 0);
+    }
+
+    #[test]
+    fn test_keep_local_data() {
+        let db = db_with_pending_changes();
+        assert_eq!(db.get_lifecycle_state().unwrap(), LifecycleState::Connected);
+        db.on_disconnect(DisconnectPolicy::KeepLocalData, None)
+            .unwrap();
+        assert_eq!(
+            db.get_lifecycle_state().unwrap(),
+            LifecycleState::Disconnected
+        );
+        assert_reset(&db);
+        let mut guids: Vec<_> = db.get_all().unwrap().into_iter().map(|l| l.guid).collect();
+        guids.sort();
+        assert_eq!(guids, vec!["dummy_000002", "dummy_000003"]);
+    }
+
+    #[test]
+    fn test_delete_local_data() {
+        let db = db_with_pending_changes();
+        let counter = db.get_change_counter().unwrap();
+        db.on_disconnect(DisconnectPolicy::DeleteLocalData, None)
+            .unwrap();
+        assert_eq!(
+            db.get_lifecycle_state().unwrap(),
+            LifecycleState::Disconnected
+        );
+        assert_reset(&db);
+        assert_eq!(count(&db, "loginsL"), 0);
+        assert!(db.get_change_counter().unwrap() > counter);
+    }
+
+    #[test]
+    fn test_upload_then_disconnect() {
+        let db = db_with_pending_changes();
+        // Without a way to upload, nothing happens.
+        let err = db
+            .on_disconnect(DisconnectPolicy::UploadDeletionsThenDisconnect, None)
+            .unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::MissingFinalUpload));
+        assert_eq!(db.get_lifecycle_state().unwrap(), LifecycleState::Connected);
+
+        // Nor if the upload fails.
+        let err = db
+            .on_disconnect(
+                DisconnectPolicy::UploadDeletionsThenDisconnect,
+                Some(&mut |_| Err(ErrorKind::NoSuchRecord("server".into()).into())),
+            )
+            .unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::NoSuchRecord(_)));
+        assert_eq!(db.get_lifecycle_state().unwrap(), LifecycleState::Connected);
+        assert_eq!(count(&db, "loginsM"), 2);
+
+        let mut uploaded = vec![];
+        db.on_disconnect(
+            DisconnectPolicy::UploadDeletionsThenDisconnect,
+            Some(&mut |outgoing: OutgoingChangeset| {
+                uploaded = outgoing.changes.iter().map(|p| p.id.clone()).collect();
+                Ok(UploadedChanges {
+                    timestamp: ServerTimestamp(2000),
+                    guids: uploaded.clone(),
+                })
+            }),
+        )
+        .unwrap();
+        uploaded.sort();
+        assert_eq!(uploaded, vec!["dummy_000001", "dummy_000003"]);
+        assert_eq!(
+            db.get_lifecycle_state().unwrap(),
+            LifecycleState::Disconnected
+        );
+        assert_reset(&db);
+        // The uploaded tombstone is gone, and nothing is left to upload.
+        assert_eq!(count(&db, "loginsL WHERE is_deleted = 1"), 0);
+        assert_eq!(db.get_all().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_out_of_order() {
+        let db = db_with_pending_changes();
+        db.on_disconnect(DisconnectPolicy::KeepLocalData, None)
+            .unwrap();
+
+        // No syncing, or disconnecting again, until we reconnect...
+        let err = sync(&db, 2000).unwrap_err();
+        assert_lifecycle_error(Err(err.downcast::<Error>().unwrap()), "sync");
+        assert_lifecycle_error(
+            db.on_disconnect(DisconnectPolicy::KeepLocalData, None),
+            "disconnect",
+        );
+        assert_lifecycle_error(
+            db.on_disconnect(
+                DisconnectPolicy::UploadDeletionsThenDisconnect,
+                Some(&mut |_| unreachable!()),
+            ),
+            "disconnect",
+        );
+        assert_eq!(db.get_all().unwrap().len(), 2);
+
+        // ...except to delete what's left.
+        db.on_disconnect(DisconnectPolicy::DeleteLocalData, None)
+            .unwrap();
+        assert_eq!(
+            db.get_lifecycle_state().unwrap(),
+            LifecycleState::Disconnected
+        );
+        assert!(db.get_all().unwrap().is_empty());
+
+        db.on_connect().unwrap();
+        assert_eq!(db.get_lifecycle_state().unwrap(), LifecycleState::Connected);
+        db.add(login("dummy_000004")).unwrap();
+        assert_eq!(sync(&db, 3000).unwrap(), vec!["dummy_000004"]);
+        // Connecting when we're connected is fine.
+        db.on_connect().unwrap();
+    }
+
+    #[test]
+    fn test_legacy_entry_points() {
+        let store = PasswordStore::new_in_memory(Some("testing")).unwrap();
+        store.add(login("dummy_000001")).unwrap();
+        sync(&store.db, 1000).unwrap();
+
+        // Wiping while connected leaves tombstones to upload.
+        store.wipe().unwrap();
+        assert_eq!(count(&store.db, "loginsL WHERE is_deleted = 1"), 1);
+        sync(&store.db, 2000).unwrap();
+
+        // But they'd never be uploaded after disconnecting, so they aren't
+        // left.
+        store.add(login("dummy_000002")).unwrap();
+        sync(&store.db, 3000).unwrap();
+        store
+            .on_disconnect(DisconnectPolicy::KeepLocalData, None)
+            .unwrap();
+        store.wipe().unwrap();
+        assert_eq!(count(&store.db, "loginsL"), 0);
+        assert!(store.list().unwrap().is_empty());
+
+        // Neither `reset` nor `wipe_local` change the state.
+        store.reset().unwrap();
+        store.wipe_local().unwrap();
+        assert_eq!(
+            store.get_lifecycle_state().unwrap(),
+            LifecycleState::Disconnected
+        );
+        store.on_connect().unwrap();
+        store.reset().unwrap();
+        assert_eq!(
+            store.get_lifecycle_state().unwrap(),
+            LifecycleState::Connected
+        );
+    }
+}
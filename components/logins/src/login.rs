@@ -82,6 +82,8 @@
 //!   - An empty string, which is a wildcard match for any origin.
 //!   - The single character ".", which is equivalent to the empty string
 //!   - The string "javascript:", which matches any form with javascript target URL.
+//!   - A scheme-relative origin like "//example.com", which some legacy Desktop records have,
+//!     and which matches a form target with that host and port over either scheme.
 //!
 //!   **YES, THIS FIELD IS CONFUSINGLY NAMED. IT SHOULD BE AN ORIGIN, NOT A FULL URL. WE INTEND TO
 //!   RENAME THIS TO `formActionOrigin` IN A FUTURE RELEASE.**
@@ -93,8 +95,12 @@
 //!   logins store will attempt to coerce it into valid data by:
 //!   - truncating full URLs to just their origin component
 //!   - converting origins with non-ascii characters into punycode
+//!   - truncating scheme-relative URLs to just their host and port, as in "//example.com:8080"
 //!   - replacing invalid values with null if a valid 'httpRealm' field is present
 //!
+//!   Scheme-relative values received via sync are kept as they are, even if they could be
+//!   truncated, so that we don't rewrite other clients' records when we next upload them.
+//!
 //!   **XXX TODO**:
 //!   - return a "display" field (exact name TBD) in the serialized
 //!     version, which will be the unicode version of punycode urls.
@@ -226,8 +232,11 @@
 //! - `Login::fixup()`:   Returns either the existing login if it is valid, a clone with invalid fields
 //!                       fixed up if it was safe to do so, or an error if the login is irreparably invalid.
 
+use crate::encryption::EncryptorDecryptor;
 use crate::error::*;
 use crate::msg_types::PasswordInfo;
+use crate::schema;
+use crate::unknown_fields;
 use crate::util;
 use rusqlite::Row;
 use serde_derive::*;
@@ -277,6 +286,7 @@ pub struct Login {
     pub time_last_used: i64,
 
     #[serde(default)]
+    #[serde(deserialize_with = "deserialize_times_used")]
     pub times_used: i64,
 }
 
@@ -287,15 +297,22 @@ where
     D: serde::de::Deserializer<'de>,
 {
     use serde::de::Deserialize;
-    // Invalid and negative timestamps are all replaced with 0. Eventually we
-    // should investigate replacing values that are unreasonable but still fit
-    // in an i64 (a date 1000 years in the future, for example), but
-    // appropriately handling that is complex.
+    // Invalid and negative timestamps are all replaced with 0. Values that
+    // are unreasonable but still fit in an i64 (a date 1000 years in the
+    // future, for example) are dealt with by the `timestamps` module, before
+    // the record is stored.
     Ok(i64::deserialize(deserializer).unwrap_or_default().max(0))
 }
 
-fn string_or_default(row: &Row<'_>, col: &str) -> Result<String> {
-    Ok(row.get::<_, Option<String>>(col)?.unwrap_or_default())
+// Like timestamps, invalid and negative counts are replaced with 0, so a bad
+// value from another client can't throw off our arithmetic.
+#[allow(clippy::unnecessary_wraps)]
+fn deserialize_times_used<'de, D>(deserializer: D) -> std::result::Result<i64, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    use serde::de::Deserialize;
+    Ok(i64::deserialize(deserializer).unwrap_or_default().max(0))
 }
 
 impl Login {
@@ -312,7 +329,7 @@ impl Login {
     /// Checks whether the Login is valid, without attempting to fix any fields.
     /// Returns an error if invalid data is found, even if it could have been fixed.
     pub fn check_valid(&self) -> Result<()> {
-        self.validate_and_fixup(false)?;
+        self.validate_and_fixup(false, None)?;
         Ok(())
     }
 
@@ -331,12 +348,29 @@ impl Login {
     /// an Option for the fixed-up version, allowing the caller to make
     /// more choices about what to do next.
     pub fn maybe_fixup(&self) -> Result<Option<Self>> {
-        self.validate_and_fixup(true)
+        self.validate_and_fixup(true, None)
+    }
+
+    /// Like `maybe_fixup()`, but rather than failing on the first problem
+    /// which can't be fixed up, returns all of them.
+    pub fn maybe_fixup_all(&self) -> std::result::Result<Option<Self>, Vec<InvalidLogin>> {
+        let mut problems = vec![];
+        // This never fails when it's given `problems`.
+        let fixed = self
+            .validate_and_fixup(true, Some(&mut problems))
+            .unwrap_or(None);
+        if problems.is_empty() {
+            Ok(fixed)
+        } else {
+            Err(problems)
+        }
     }
 
     /// Internal helper for validation and fixups of an "origin" stored as
     /// a string.
-    fn validate_and_fixup_origin(origin: &str) -> Result<Option<String>> {
+    pub(crate) fn validate_and_fixup_origin(
+        origin: &str,
+    ) -> std::result::Result<Option<String>, InvalidLogin> {
         // Check we can parse the origin, then use the normalized version of it.
         match Url::parse(&origin) {
             Ok(mut u) => {
@@ -377,19 +411,84 @@ impl Login {
             }
             Err(_) => {
                 // We can't fixup completely invalid records, so always throw.
-                throw!(InvalidLogin::IllegalFieldValue {
-                    field_info: "Origin is Malformed".into()
-                });
+                Err(InvalidLogin::IllegalFieldValue {
+                    field_info: "Origin is Malformed".into(),
+                })
+            }
+        }
+    }
+
+    /// Internal helper for validation and fixups of a scheme-relative
+    /// `formSubmitURL`, which we normalize to `//host[:port]`. A port is only
+    /// dropped if it's the default for the scheme of `hostname`.
+    fn validate_and_fixup_scheme_relative(
+        href: &str,
+        hostname: &str,
+    ) -> std::result::Result<Option<String>, InvalidLogin> {
+        let scheme = Url::parse(hostname)
+            .map(|u| u.scheme().to_owned())
+            .unwrap_or_else(|_| "https".into());
+        let malformed = || InvalidLogin::IllegalFieldValue {
+            field_info: "Origin is Malformed".into(),
+        };
+        let url = Url::parse(&format!("{}:{}", scheme, href)).map_err(|_| malformed())?;
+        let host = url.host_str().ok_or_else(malformed)?;
+        let normalized = match url.port() {
+            Some(port) => format!("//{}:{}", host, port),
+            None => format!("//{}", host),
+        };
+        Ok(if normalized == href {
+            None
+        } else {
+            Some(normalized)
+        })
+    }
+
+    /// Whether `form_submit_url` is one of the legacy scheme-relative forms
+    /// described at the top of this file.
+    pub(crate) fn has_scheme_relative_form_submit_url(&self) -> bool {
+        self.form_submit_url
+            .as_ref()
+            .map_or(false, |url| url.starts_with("//"))
+    }
+
+    /// Fixes up a record we've read from the database or received via sync,
+    /// keeping a scheme-relative `formSubmitURL` as it was, so that we don't
+    /// rewrite it when we upload the record. If the record can't be fixed up,
+    /// it's returned as it is.
+    pub(crate) fn fixup_synced(self) -> Login {
+        match self.maybe_fixup() {
+            Ok(Some(mut fixed)) => {
+                if self.has_scheme_relative_form_submit_url() && fixed.form_submit_url.is_some() {
+                    fixed.form_submit_url = self.form_submit_url;
+                }
+                fixed
             }
+            _ => self,
         }
     }
 
-    /// Internal helper for doing validation and fixups.
-    fn validate_and_fixup(&self, fixup: bool) -> Result<Option<Self>> {
+    /// Internal helper for doing validation and fixups. If `problems` is
+    /// given, problems are added to it and checking carries on, rather than
+    /// returning an error for the first.
+    fn validate_and_fixup(
+        &self,
+        fixup: bool,
+        mut problems: Option<&mut Vec<InvalidLogin>>,
+    ) -> Result<Option<Self>> {
         // XXX TODO: we've definitely got more validation and fixups to add here!
 
         let mut maybe_fixed = None;
 
+        macro_rules! invalid {
+            ($err:expr) => {
+                match problems.as_mut() {
+                    Some(problems) => problems.push($err),
+                    None => throw!($err),
+                }
+            };
+        };
+
         /// A little helper to magic a Some(self.clone()) into existence when needed.
         macro_rules! get_fixed_or_throw {
             ($err:expr) => {
@@ -397,9 +496,10 @@ impl Login {
                 // entirely so we can give it an explicit type declaration.
                 {
                     if !fixup {
-                        throw!($err)
+                        invalid!($err)
+                    } else {
+                        log::warn!("Fixing login record {}: {:?}", self.guid, $err);
                     }
-                    log::warn!("Fixing login record {}: {:?}", self.guid, $err);
                     let fixed: Result<&mut Login> =
                         Ok(maybe_fixed.get_or_insert_with(|| self.clone()));
                     fixed
@@ -408,11 +508,11 @@ impl Login {
         };
 
         if self.hostname.is_empty() {
-            throw!(InvalidLogin::EmptyOrigin);
+            invalid!(InvalidLogin::EmptyOrigin);
         }
 
         if self.password.is_empty() {
-            throw!(InvalidLogin::EmptyPassword);
+            invalid!(InvalidLogin::EmptyPassword);
         }
 
         if self.form_submit_url.is_some() && self.http_realm.is_some() {
@@ -420,7 +520,7 @@ impl Login {
         }
 
         if self.form_submit_url.is_none() && self.http_realm.is_none() {
-            throw!(InvalidLogin::NoTarget);
+            invalid!(InvalidLogin::NoTarget);
         }
 
         let form_submit_url = self.form_submit_url.clone().unwrap_or_default();
@@ -444,7 +544,7 @@ impl Login {
         for (field_name, field_value) in &field_data {
             // Nuls are invalid.
             if field_value.contains('\0') {
-                throw!(InvalidLogin::IllegalFieldValue {
+                invalid!(InvalidLogin::IllegalFieldValue {
                     field_info: format!("`{}` contains Nul", field_name)
                 });
             }
@@ -455,7 +555,7 @@ impl Login {
                 && field_name != &"password"
                 && (field_value.contains('\n') || field_value.contains('\r'))
             {
-                throw!(InvalidLogin::IllegalFieldValue {
+                invalid!(InvalidLogin::IllegalFieldValue {
                     field_info: format!("`{}` contains newline", field_name)
                 });
             }
@@ -463,17 +563,24 @@ impl Login {
 
         // Desktop doesn't like fields with the below patterns
         if self.username_field == "." {
-            throw!(InvalidLogin::IllegalFieldValue {
+            invalid!(InvalidLogin::IllegalFieldValue {
                 field_info: "`usernameField` is a period".into()
             });
         }
 
         // Check we can parse the origin, then use the normalized version of it.
-        if let Some(fixed) = Login::validate_and_fixup_origin(&self.hostname)? {
-            get_fixed_or_throw!(InvalidLogin::IllegalFieldValue {
-                field_info: "Origin is not normalized".into()
-            })?
-            .hostname = fixed;
+        // (An empty one has already been reported.)
+        if !self.hostname.is_empty() {
+            match Login::validate_and_fixup_origin(&self.hostname) {
+                Ok(Some(fixed)) => {
+                    get_fixed_or_throw!(InvalidLogin::IllegalFieldValue {
+                        field_info: "Origin is not normalized".into()
+                    })?
+                    .hostname = fixed;
+                }
+                Ok(None) => {}
+                Err(e) => invalid!(e),
+            }
         }
 
         match &maybe_fixed.as_ref().unwrap_or(self).form_submit_url {
@@ -503,12 +610,27 @@ impl Login {
                             .get_or_insert_with(|| self.clone())
                             .form_submit_url = Some("".into());
                     }
+                } else if href.starts_with("//") {
+                    match Login::validate_and_fixup_scheme_relative(&href, &self.hostname) {
+                        Ok(Some(fixed)) => {
+                            get_fixed_or_throw!(InvalidLogin::IllegalFieldValue {
+                                field_info: "formActionOrigin is not normalized".into()
+                            })?
+                            .form_submit_url = Some(fixed);
+                        }
+                        Ok(None) => {}
+                        Err(e) => invalid!(e),
+                    }
                 } else if !href.is_empty() && href != "javascript:" {
-                    if let Some(fixed) = Login::validate_and_fixup_origin(&href)? {
-                        get_fixed_or_throw!(InvalidLogin::IllegalFieldValue {
-                            field_info: "formActionOrigin is not normalized".into()
-                        })?
-                        .form_submit_url = Some(fixed);
+                    match Login::validate_and_fixup_origin(&href) {
+                        Ok(Some(fixed)) => {
+                            get_fixed_or_throw!(InvalidLogin::IllegalFieldValue {
+                                field_info: "formActionOrigin is not normalized".into()
+                            })?
+                            .form_submit_url = Some(fixed);
+                        }
+                        Ok(None) => {}
+                        Err(e) => invalid!(e),
                     }
                 }
             }
@@ -517,32 +639,11 @@ impl Login {
         Ok(maybe_fixed)
     }
 
-    pub(crate) fn from_row(row: &Row<'_>) -> Result<Login> {
-        let login = Login {
-            guid: row.get("guid")?,
-            password: row.get("password")?,
-            username: string_or_default(row, "username")?,
-
-            hostname: row.get("hostname")?,
-            http_realm: row.get("httpRealm")?,
-
-            form_submit_url: row.get("formSubmitURL")?,
-
-            username_field: string_or_default(row, "usernameField")?,
-            password_field: string_or_default(row, "passwordField")?,
-
-            time_created: row.get("timeCreated")?,
-            // Might be null
-            time_last_used: row
-                .get::<_, Option<i64>>("timeLastUsed")?
-                .unwrap_or_default(),
-
-            time_password_changed: row.get("timePasswordChanged")?,
-            times_used: row.get("timesUsed")?,
-        };
+    pub(crate) fn from_row(row: &Row<'_>, encdec: &dyn EncryptorDecryptor) -> Result<Login> {
+        let login = schema::login_from_row(row, encdec)?;
         // For now, we want to apply fixups but still return the record if
         // there is unfixably invalid data in the db.
-        Ok(login.maybe_fixup().unwrap_or(None).unwrap_or(login))
+        Ok(login.fixup_synced())
     }
 }
 
@@ -597,9 +698,9 @@ impl MirrorLogin {
         self.login.guid_str()
     }
 
-    pub(crate) fn from_row(row: &Row<'_>) -> Result<MirrorLogin> {
+    pub(crate) fn from_row(row: &Row<'_>, encdec: &dyn EncryptorDecryptor) -> Result<MirrorLogin> {
         Ok(MirrorLogin {
-            login: Login::from_row(row)?,
+            login: Login::from_row(row, encdec)?,
             is_overridden: row.get("is_overridden")?,
             server_modified: ServerTimestamp(row.get::<_, i64>("server_modified")?),
         })
@@ -627,12 +728,22 @@ impl SyncStatus {
     }
 }
 
+/// Bits of `loginsL.change_flags`, recording which groups of fields have
+/// changed locally since the last sync.
+pub(crate) mod change_flags {
+    /// Anything other than usage metadata.
+    pub const FIELDS: u8 = 1;
+    /// `timesUsed` and `timeLastUsed`.
+    pub const USAGE: u8 = 2;
+}
+
 #[derive(Clone, Debug)]
 pub(crate) struct LocalLogin {
     pub login: Login,
     pub sync_status: SyncStatus,
     pub is_deleted: bool,
     pub local_modified: SystemTime,
+    pub change_flags: u8,
 }
 
 impl LocalLogin {
@@ -641,12 +752,13 @@ impl LocalLogin {
         self.login.guid_str()
     }
 
-    pub(crate) fn from_row(row: &Row<'_>) -> Result<LocalLogin> {
+    pub(crate) fn from_row(row: &Row<'_>, encdec: &dyn EncryptorDecryptor) -> Result<LocalLogin> {
         Ok(LocalLogin {
-            login: Login::from_row(row)?,
+            login: Login::from_row(row, encdec)?,
             sync_status: SyncStatus::from_u8(row.get("sync_status")?)?,
             is_deleted: row.get("is_deleted")?,
             local_modified: util::system_time_millis_from_row(row, "local_modified")?,
+            change_flags: row.get("change_flags")?,
         })
     }
 }
@@ -701,6 +813,9 @@ pub(crate) struct SyncLoginData {
     pub mirror: Option<MirrorLogin>,
     // None means it's a deletion
     pub inbound: (Option<Login>, ServerTimestamp),
+    // The fields of the inbound record which `Login` doesn't have, as a JSON
+    // object. See `unknown_fields`.
+    pub inbound_unknown_fields: Option<String>,
 }
 
 impl SyncLoginData {
@@ -721,19 +836,21 @@ impl SyncLoginData {
         ts: ServerTimestamp,
     ) -> std::result::Result<Self, serde_json::Error> {
         let guid = payload.id.clone();
+        let unknown_fields = unknown_fields::extract(&payload);
         let login: Option<Login> = if payload.is_tombstone() {
             None
         } else {
             let record: Login = payload.into_record()?;
             // If we can fixup incoming records from sync, do so.
             // But if we can't then keep the invalid data.
-            record.maybe_fixup().unwrap_or(None).or(Some(record))
+            Some(record.fixup_synced())
         };
         Ok(Self {
             guid,
             local: None,
             mirror: None,
             inbound: (login, ts),
+            inbound_unknown_fields: unknown_fields,
         })
     }
 }
@@ -742,25 +859,24 @@ macro_rules! impl_login_setter {
     ($setter_name:ident, $field:ident, $Login:ty) => {
         impl SyncLoginData {
             pub(crate) fn $setter_name(&mut self, record: $Login) -> Result<()> {
-                // TODO: We probably shouldn't panic in this function!
                 if self.$field.is_some() {
                     // Shouldn't be possible (only could happen if UNIQUE fails in sqlite, or if we
                     // get duplicate guids somewhere,but we check).
-                    panic!(
+                    throw!(ErrorKind::IncomingCorruption(format!(
                         "SyncLoginData::{} called on object that already has {} data",
                         stringify!($setter_name),
                         stringify!($field)
-                    );
+                    )));
                 }
 
                 if self.guid_str() != record.guid_str() {
                     // This is almost certainly a bug in our code.
-                    panic!(
+                    throw!(ErrorKind::IncomingCorruption(format!(
                         "Wrong guid on login in {}: {:?} != {:?}",
                         stringify!($setter_name),
                         self.guid_str(),
                         record.guid_str()
-                    );
+                    )));
                 }
 
                 self.$field = Some(record);
@@ -773,22 +889,82 @@ macro_rules! impl_login_setter {
 impl_login_setter!(set_local, local, LocalLogin);
 impl_login_setter!(set_mirror, mirror, MirrorLogin);
 
+/// How one side of a three-way merge changed an optional text field, relative
+/// to the shared parent. Clearing a field is a change like any other, and
+/// must win over the other side leaving it alone.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum FieldChange {
+    Unchanged,
+    ChangedTo(String),
+    Cleared,
+}
+
+impl Default for FieldChange {
+    fn default() -> Self {
+        FieldChange::Unchanged
+    }
+}
+
+impl FieldChange {
+    /// The change from `older` to `newer`, for fields where the empty string
+    /// means there's no value.
+    fn between(newer: &str, older: &str) -> Self {
+        if newer == older {
+            FieldChange::Unchanged
+        } else if newer.is_empty() {
+            FieldChange::Cleared
+        } else {
+            FieldChange::ChangedTo(newer.to_owned())
+        }
+    }
+
+    /// A change to `value`, for fields where `None` means there's no value.
+    /// Note that `Some("")` is a value, which matches any form for
+    /// `form_submit_url`.
+    fn to_option(value: &Option<String>) -> Self {
+        match value {
+            Some(value) => FieldChange::ChangedTo(value.clone()),
+            None => FieldChange::Cleared,
+        }
+    }
+
+    fn is_changed(&self) -> bool {
+        *self != FieldChange::Unchanged
+    }
+
+    fn apply(self, field: &mut String) {
+        match self {
+            FieldChange::Unchanged => {}
+            FieldChange::ChangedTo(value) => *field = value,
+            FieldChange::Cleared => field.clear(),
+        }
+    }
+
+    fn apply_option(self, field: &mut Option<String>) {
+        match self {
+            FieldChange::Unchanged => {}
+            FieldChange::ChangedTo(value) => *field = Some(value),
+            FieldChange::Cleared => *field = None,
+        }
+    }
+}
+
 #[derive(Debug, Default, Clone)]
 pub(crate) struct LoginDelta {
     // "non-commutative" fields
     pub hostname: Option<String>,
     pub password: Option<String>,
-    pub username: Option<String>,
-    pub http_realm: Option<String>,
-    pub form_submit_url: Option<String>,
+    pub username: FieldChange,
+    pub http_realm: FieldChange,
+    pub form_submit_url: FieldChange,
 
     pub time_created: Option<i64>,
     pub time_last_used: Option<i64>,
     pub time_password_changed: Option<i64>,
 
     // "non-conflicting" fields (which are the same)
-    pub password_field: Option<String>,
-    pub username_field: Option<String>,
+    pub password_field: FieldChange,
+    pub username_field: FieldChange,
 
     // Commutative field
     pub times_used: i64,
@@ -807,29 +983,89 @@ macro_rules! merge_field {
             }
         }
     };
+    // Also counts the conflict, if the two sides changed it differently.
+    ($merged:ident, $b:ident, $prefer_b:expr, $conflicts:ident, $field:ident) => {
+        if $b.$field.is_some() && $merged.$field.is_some() && $b.$field != $merged.$field {
+            $conflicts += 1;
+        }
+        merge_field!($merged, $b, $prefer_b, $field);
+    };
+}
+
+macro_rules! merge_text_field {
+    ($merged:ident, $b:ident, $prefer_b:expr, $conflicts:ident, $field:ident) => {
+        let $field = std::mem::take(&mut $b.$field);
+        if $field.is_changed() {
+            if !$merged.$field.is_changed() {
+                $merged.$field = $field;
+            } else if $merged.$field != $field {
+                log::warn!("Collision merging login field {}", stringify!($field));
+                $conflicts += 1;
+                if $prefer_b {
+                    $merged.$field = $field;
+                }
+            }
+        }
+    };
 }
 
 impl LoginDelta {
+    /// Merge `self` with `b`, taking `b`'s side of any conflicts if it's
+    /// newer. Also returns how many fields (other than timestamps) the two
+    /// sides changed to different values, including where one cleared a
+    /// field the other changed.
     #[allow(clippy::cognitive_complexity)] // Looks like clippy considers this after macro-expansion...
-    pub fn merge(self, mut b: LoginDelta, b_is_newer: bool) -> LoginDelta {
+    pub fn merge(self, mut b: LoginDelta, b_is_newer: bool) -> (LoginDelta, usize) {
         let mut merged = self;
-        merge_field!(merged, b, b_is_newer, hostname);
-        merge_field!(merged, b, b_is_newer, password);
-        merge_field!(merged, b, b_is_newer, username);
-        merge_field!(merged, b, b_is_newer, http_realm);
-        merge_field!(merged, b, b_is_newer, form_submit_url);
+        let mut conflicts = 0;
+        merge_field!(merged, b, b_is_newer, conflicts, hostname);
+        merge_field!(merged, b, b_is_newer, conflicts, password);
+        merge_text_field!(merged, b, b_is_newer, conflicts, username);
+        // httpRealm and formSubmitURL are resolved together, since taking one
+        // from each side could leave us with both or neither set.
+        if b.http_realm.is_changed() || b.form_submit_url.is_changed() {
+            let merged_has_realm =
+                merged.http_realm.is_changed() || merged.form_submit_url.is_changed();
+            if merged_has_realm
+                && (merged.http_realm != b.http_realm
+                    || merged.form_submit_url != b.form_submit_url)
+            {
+                log::warn!("Collision merging login fields http_realm and form_submit_url");
+                conflicts += 1;
+            }
+            if !merged_has_realm || b_is_newer {
+                merged.http_realm = std::mem::take(&mut b.http_realm);
+                merged.form_submit_url = std::mem::take(&mut b.form_submit_url);
+            }
+        }
 
         merge_field!(merged, b, b_is_newer, time_created);
-        merge_field!(merged, b, b_is_newer, time_last_used);
+        // The most recent use wins, whichever side it was on.
+        if let Some(b_last_used) = b.time_last_used.take() {
+            merged.time_last_used = Some(
+                merged
+                    .time_last_used
+                    .map_or(b_last_used, |last_used| last_used.max(b_last_used)),
+            );
+        }
         merge_field!(merged, b, b_is_newer, time_password_changed);
 
-        merge_field!(merged, b, b_is_newer, password_field);
-        merge_field!(merged, b, b_is_newer, username_field);
+        merge_text_field!(merged, b, b_is_newer, conflicts, password_field);
+        merge_text_field!(merged, b, b_is_newer, conflicts, username_field);
 
         // commutative fields
-        merged.times_used += b.times_used;
+        merged.times_used = merged.times_used.saturating_add(b.times_used);
 
-        merged
+        (merged, conflicts)
+    }
+
+    /// Just the changes to usage metadata, for records which were only used.
+    pub fn usage_only(self) -> LoginDelta {
+        LoginDelta {
+            time_last_used: self.time_last_used,
+            times_used: self.times_used,
+            ..LoginDelta::default()
+        }
     }
 }
 
@@ -846,53 +1082,48 @@ impl Login {
         apply_field!(self, delta, hostname);
 
         apply_field!(self, delta, password);
-        apply_field!(self, delta, username);
+        delta.username.apply(&mut self.username);
 
         apply_field!(self, delta, time_created);
         apply_field!(self, delta, time_last_used);
         apply_field!(self, delta, time_password_changed);
 
-        apply_field!(self, delta, password_field);
-        apply_field!(self, delta, username_field);
+        delta.password_field.apply(&mut self.password_field);
+        delta.username_field.apply(&mut self.username_field);
 
-        // Use Some("") to indicate that it should be changed to be None (hacky...)
-        if let Some(realm) = delta.http_realm.take() {
-            self.http_realm = if realm.is_empty() { None } else { Some(realm) };
-        }
+        delta.http_realm.apply_option(&mut self.http_realm);
+        delta
+            .form_submit_url
+            .apply_option(&mut self.form_submit_url);
 
-        if let Some(url) = delta.form_submit_url.take() {
-            self.form_submit_url = if url.is_empty() { None } else { Some(url) };
-        }
+        self.times_used = self.times_used.saturating_add(delta.times_used);
+    }
 
-        self.times_used += delta.times_used;
+    /// Whether exactly one of `http_realm` and `form_submit_url` is set, as
+    /// `check_valid` requires.
+    pub(crate) fn has_valid_target(&self) -> bool {
+        self.http_realm.is_some() != self.form_submit_url.is_some()
     }
 
     pub(crate) fn delta(&self, older: &Login) -> LoginDelta {
         let mut delta = LoginDelta::default();
 
-        if self.form_submit_url != older.form_submit_url {
-            delta.form_submit_url = Some(self.form_submit_url.clone().unwrap_or_default());
-        }
-
-        if self.http_realm != older.http_realm {
-            delta.http_realm = Some(self.http_realm.clone().unwrap_or_default());
+        // Exactly one of these may be set, so if either changed we include
+        // both, allowing `merge` to take them from the same side.
+        if self.form_submit_url != older.form_submit_url || self.http_realm != older.http_realm {
+            delta.form_submit_url = FieldChange::to_option(&self.form_submit_url);
+            delta.http_realm = FieldChange::to_option(&self.http_realm);
         }
 
         if self.hostname != older.hostname {
             delta.hostname = Some(self.hostname.clone());
         }
-        if self.username != older.username {
-            delta.username = Some(self.username.clone());
-        }
         if self.password != older.password {
             delta.password = Some(self.password.clone());
         }
-        if self.password_field != older.password_field {
-            delta.password_field = Some(self.password_field.clone());
-        }
-        if self.username_field != older.username_field {
-            delta.username_field = Some(self.username_field.clone());
-        }
+        delta.username = FieldChange::between(&self.username, &older.username);
+        delta.password_field = FieldChange::between(&self.password_field, &older.password_field);
+        delta.username_field = FieldChange::between(&self.username_field, &older.username_field);
 
         // We discard zero (and negative numbers) for timestamps so that a
         // record that doesn't contain this information (these are
@@ -916,7 +1147,7 @@ impl Login {
         }
 
         if self.times_used > 0 && self.times_used != older.times_used {
-            delta.times_used = self.times_used - older.times_used;
+            delta.times_used = self.times_used.saturating_sub(older.times_used);
         }
 
         delta
@@ -1154,6 +1385,24 @@ mod tests {
             ..Login::default()
         };
 
+        let login_with_scheme_relative_form_submit_url = Login {
+            form_submit_url: Some("//www.example.com".into()),
+            hostname: "https://www.example.com".into(),
+            username: "test".into(),
+            password: "test".into(),
+            ..Login::default()
+        };
+
+        let login_with_scheme_relative_form_submit_path = Login {
+            form_submit_url: Some("//www.example.com/login".into()),
+            ..login_with_scheme_relative_form_submit_url.clone()
+        };
+
+        let login_with_scheme_relative_form_submit_no_host = Login {
+            form_submit_url: Some("//".into()),
+            ..login_with_scheme_relative_form_submit_url.clone()
+        };
+
         let login_with_malformed_origin_parens = Login {
             hostname: " (".into(),
             http_realm: Some("https://www.example.com".into()),
@@ -1272,6 +1521,22 @@ mod tests {
                 should_err: false,
                 expected_err: "",
             },
+            TestCase {
+                login: login_with_scheme_relative_form_submit_url,
+                should_err: false,
+                expected_err: "",
+            },
+            TestCase {
+                login: login_with_scheme_relative_form_submit_path,
+                should_err: true,
+                expected_err:
+                    "Invalid login: Login has illegal field: formActionOrigin is not normalized",
+            },
+            TestCase {
+                login: login_with_scheme_relative_form_submit_no_host,
+                should_err: true,
+                expected_err: "Invalid login: Login has illegal field: Origin is Malformed",
+            },
             TestCase {
                 login: login_with_malformed_origin_parens,
                 should_err: true,
@@ -1362,6 +1627,21 @@ mod tests {
             ..Login::default()
         };
 
+        let login_with_scheme_relative_fsu = Login {
+            hostname: "https://example.com".into(),
+            form_submit_url: Some("//example.com:443/login?next=/".into()),
+            username: "test".into(),
+            password: "test".into(),
+            ..Login::default()
+        };
+        let login_with_scheme_relative_fsu_port = Login {
+            hostname: "http://example.com".into(),
+            form_submit_url: Some("//😍.com:8080".into()),
+            username: "test".into(),
+            password: "test".into(),
+            ..Login::default()
+        };
+
         let login_with_form_submit_and_http_realm = Login {
             hostname: "https://www.example.com".into(),
             form_submit_url: Some("https://www.example.com".into()),
@@ -1401,6 +1681,17 @@ mod tests {
                 fixedup_form_submit_url: Some("".into()),
                 ..TestCase::default()
             },
+            TestCase {
+                login: login_with_scheme_relative_fsu,
+                // The default port for the hostname's scheme is dropped.
+                fixedup_form_submit_url: Some("//example.com".into()),
+                ..TestCase::default()
+            },
+            TestCase {
+                login: login_with_scheme_relative_fsu_port,
+                fixedup_form_submit_url: Some("//xn--r28h.com:8080".into()),
+                ..TestCase::default()
+            },
         ];
 
         for tc in &test_cases {
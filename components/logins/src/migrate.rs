@@ -0,0 +1,346 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Helpers for carrying sync metadata over from older versions of this
+//! component, and from Fennec.
+//!
+//! `import_legacy_sync_metadata` accepts two shapes of JSON:
+//!
+//! 1. The V1 persisted `GlobalState` which `migrate_global_state` migrates
+//!    from our own meta table, identified by `"schema_version": "V1"`. This
+//!    holds the sync IDs and the declined engines, but no last-sync time.
+//!
+//! 2. The blob written by Fennec's sync code, which looks like:
+//!
+//!    ```json
+//!    {
+//!        "syncID": "<global sync ID>",
+//!        "declined": ["bookmarks"],
+//!        "engines": {
+//!            "passwords": {
+//!                "syncID": "<collection sync ID>",
+//!                "lastSync": 1548214240340
+//!            }
+//!        }
+//!    }
+//!    ```
+//!
+//!    where `lastSync` is in milliseconds. Every field is optional.
+//!
+//! Only the sync IDs, the last-sync time and the declined list are stored,
+//! under the same meta keys a sync would use. Anything else in the blob is
+//! listed in the report and otherwise ignored.
+
+use crate::db::{LoginDb, COLLECTION_NAME};
+use crate::error::*;
+use crate::schema;
+use serde_derive::*;
+use serde_json::Value;
+use sql_support::ConnExt;
+use sync15::extract_v1_state;
+
+/// The shape of a legacy sync metadata blob.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LegacySyncMetadataFormat {
+    /// Our own V1 persisted global state.
+    GlobalStateV1,
+    /// Fennec's sync metadata.
+    Fennec,
+}
+
+/// What `import_legacy_sync_metadata` found and stored.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LegacyImportReport {
+    /// None if the blob was valid JSON, but in neither shape we know about,
+    /// in which case nothing was migrated.
+    pub format: Option<LegacySyncMetadataFormat>,
+    pub migrated_sync_ids: bool,
+    pub migrated_last_sync: bool,
+    pub migrated_declined: bool,
+    /// Fields we found but didn't migrate, such as other engines' state, as
+    /// dotted paths (eg, `engines.bookmarks`).
+    pub ignored: Vec<String>,
+}
+
+impl LoginDb {
+    /// A utility we can kill by the end of 2019 ;)
+    ///
+    /// This is idempotent - the old key is deleted once migrated, so running
+    /// it again does nothing.
+    pub fn migrate_global_state(&self) -> Result<()> {
+        let tx = self.unchecked_transaction_imm()?;
+        if let Some(old_state) = self.get_meta::<String>("global_state")? {
+            log::info!("there's old global state - migrating");
+            self.import_v1_state(old_state)?;
+            self.delete_meta("global_state")?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Import the sync IDs, last-sync time and declined engines from a
+    /// legacy JSON blob - see the module docs for the shapes we accept.
+    /// Fails if `json` isn't valid JSON.
+    pub fn import_legacy_sync_metadata(&self, json: &str) -> Result<LegacyImportReport> {
+        let value: Value = serde_json::from_str(json)?;
+        let tx = self.unchecked_transaction_imm()?;
+        let report = match value.as_object() {
+            Some(ob) if ob.get("schema_version").and_then(Value::as_str) == Some("V1") => {
+                let mut report = self.import_v1_state(json.to_string())?;
+                report.ignored = ob
+                    .keys()
+                    .filter(|k| {
+                        !["schema_version", "global", "engine_state_changes"].contains(&k.as_str())
+                    })
+                    .cloned()
+                    .collect();
+                report
+            }
+            Some(ob) if ob.contains_key("engines") || ob.contains_key("syncID") => {
+                self.import_fennec_state(ob)?
+            }
+            _ => {
+                log::warn!("legacy sync metadata is in an unknown format - ignoring it");
+                LegacyImportReport::default()
+            }
+        };
+        tx.commit()?;
+        log::info!("imported legacy sync metadata: {:?}", report);
+        Ok(report)
+    }
+
+    fn import_v1_state(&self, state: String) -> Result<LegacyImportReport> {
+        let mut report = LegacyImportReport {
+            format: Some(LegacySyncMetadataFormat::GlobalStateV1),
+            ..LegacyImportReport::default()
+        };
+        let (new_sync_ids, new_global_state) = extract_v1_state(Some(state), COLLECTION_NAME);
+        if let Some(sync_ids) = new_sync_ids {
+            self.put_meta(schema::GLOBAL_SYNCID_META_KEY, &sync_ids.global)?;
+            self.put_meta(schema::COLLECTION_SYNCID_META_KEY, &sync_ids.coll)?;
+            report.migrated_sync_ids = true;
+            log::info!("migrated the sync IDs");
+        }
+        if let Some(new_global_state) = new_global_state {
+            self.set_global_state(&Some(new_global_state))?;
+            report.migrated_declined = true;
+            log::info!("migrated the global state");
+        }
+        Ok(report)
+    }
+
+    fn import_fennec_state(
+        &self,
+        ob: &serde_json::Map<String, Value>,
+    ) -> Result<LegacyImportReport> {
+        let mut report = LegacyImportReport {
+            format: Some(LegacySyncMetadataFormat::Fennec),
+            ..LegacyImportReport::default()
+        };
+        for key in ob.keys() {
+            if !["syncID", "declined", "engines"].contains(&key.as_str()) {
+                report.ignored.push(key.clone());
+            }
+        }
+
+        let mut engine = None;
+        if let Some(engines) = ob.get("engines").and_then(Value::as_object) {
+            for (name, state) in engines {
+                if name == COLLECTION_NAME {
+                    engine = state.as_object();
+                } else {
+                    report.ignored.push(format!("engines.{}", name));
+                }
+            }
+        }
+        if let Some(engine) = engine {
+            for key in engine.keys() {
+                if !["syncID", "lastSync"].contains(&key.as_str()) {
+                    report
+                        .ignored
+                        .push(format!("engines.{}.{}", COLLECTION_NAME, key));
+                }
+            }
+            // We need both IDs, otherwise the next sync would be confused.
+            let global = ob.get("syncID").and_then(Value::as_str);
+            let coll = engine.get("syncID").and_then(Value::as_str);
+            if let (Some(global), Some(coll)) = (global, coll) {
+                self.put_meta(schema::GLOBAL_SYNCID_META_KEY, &global)?;
+                self.put_meta(schema::COLLECTION_SYNCID_META_KEY, &coll)?;
+                report.migrated_sync_ids = true;
+            }
+            if let Some(last_sync) = engine.get("lastSync").and_then(Value::as_i64) {
+                self.put_meta(schema::LAST_SYNC_META_KEY, &last_sync)?;
+                report.migrated_last_sync = true;
+            }
+        }
+
+        if let Some(declined) = ob.get("declined").and_then(Value::as_array) {
+            // This is how `sync15::PersistedGlobalState::V2` is serialized.
+            let declined: Vec<&str> = declined.iter().filter_map(Value::as_str).collect();
+            let state = serde_json::json!({ "schema_version": "V2", "declined": declined });
+            self.set_global_state(&Some(state.to_string()))?;
+            report.migrated_declined = true;
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A trimmed copy of a V1 persisted global state - see
+    // sync15's migrate_state.rs.
+    const V1_STATE: &str = r#"{
+        "schema_version":"V1",
+        "global":{
+            "id":"global",
+            "collection":"",
+            "payload":"{\"syncID\":\"qZKAMjhyV6Ti\",\"storageVersion\":5,\"engines\":{\"passwords\":{\"version\":1,\"syncID\":\"8M-HfX6dm-pD\"},\"bookmarks\":{\"version\":2,\"syncID\":\"AVXtnKkH5OTi\"}},\"declined\":[\"history\"]}"
+        },
+        "keys":{"timestamp":1548214240.34,"default":{"enc_key":[36,76],"mac_key":[222,241]},"collections":{}},
+        "engine_state_changes":[]
+    }"#;
+
+    const FENNEC_STATE: &str = r#"{
+        "syncID": "qZKAMjhyV6Ti",
+        "declined": ["history", "tabs"],
+        "clusterURL": "https://sync-1.example.com/",
+        "engines": {
+            "passwords": {
+                "syncID": "8M-HfX6dm-pD",
+                "lastSync": 1548214240340,
+                "enabled": true
+            },
+            "bookmarks": {
+                "syncID": "AVXtnKkH5OTi",
+                "lastSync": 1548214240000
+            }
+        }
+    }"#;
+
+    fn meta(db: &LoginDb) -> (Option<String>, Option<String>, Option<i64>, Option<Value>) {
+        (
+            db.get_meta(schema::GLOBAL_SYNCID_META_KEY).unwrap(),
+            db.get_meta(schema::COLLECTION_SYNCID_META_KEY).unwrap(),
+            db.get_meta(schema::LAST_SYNC_META_KEY).unwrap(),
+            db.get_global_state()
+                .unwrap()
+                .map(|s| serde_json::from_str(&s).unwrap()),
+        )
+    }
+
+    #[test]
+    fn test_migrate_global_state_twice() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        db.put_meta("global_state", &V1_STATE).unwrap();
+
+        db.migrate_global_state().unwrap();
+        let migrated = meta(&db);
+        assert_eq!(
+            migrated,
+            (
+                Some("qZKAMjhyV6Ti".to_string()),
+                Some("8M-HfX6dm-pD".to_string()),
+                None,
+                Some(serde_json::json!({"schema_version": "V2", "declined": ["history"]})),
+            )
+        );
+        assert_eq!(db.get_meta::<String>("global_state").unwrap(), None);
+
+        db.migrate_global_state().unwrap();
+        assert_eq!(meta(&db), migrated);
+    }
+
+    #[test]
+    fn test_import_v1() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let report = db.import_legacy_sync_metadata(V1_STATE).unwrap();
+        assert_eq!(
+            report,
+            LegacyImportReport {
+                format: Some(LegacySyncMetadataFormat::GlobalStateV1),
+                migrated_sync_ids: true,
+                migrated_last_sync: false,
+                migrated_declined: true,
+                ignored: vec!["keys".to_string()],
+            }
+        );
+        assert_eq!(
+            meta(&db),
+            (
+                Some("qZKAMjhyV6Ti".to_string()),
+                Some("8M-HfX6dm-pD".to_string()),
+                None,
+                Some(serde_json::json!({"schema_version": "V2", "declined": ["history"]})),
+            )
+        );
+        // Importing again changes nothing.
+        assert_eq!(db.import_legacy_sync_metadata(V1_STATE).unwrap(), report);
+    }
+
+    #[test]
+    fn test_import_fennec() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let mut report = db.import_legacy_sync_metadata(FENNEC_STATE).unwrap();
+        report.ignored.sort();
+        assert_eq!(
+            report,
+            LegacyImportReport {
+                format: Some(LegacySyncMetadataFormat::Fennec),
+                migrated_sync_ids: true,
+                migrated_last_sync: true,
+                migrated_declined: true,
+                ignored: vec![
+                    "clusterURL".to_string(),
+                    "engines.bookmarks".to_string(),
+                    "engines.passwords.enabled".to_string(),
+                ],
+            }
+        );
+        assert_eq!(
+            meta(&db),
+            (
+                Some("qZKAMjhyV6Ti".to_string()),
+                Some("8M-HfX6dm-pD".to_string()),
+                Some(1_548_214_240_340),
+                Some(serde_json::json!({"schema_version": "V2", "declined": ["history", "tabs"]})),
+            )
+        );
+    }
+
+    #[test]
+    fn test_import_fennec_partial() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        db.put_meta(schema::LAST_SYNC_META_KEY, &1234i64).unwrap();
+        // No global sync ID, so the collection's can't be used, and
+        // everything else we already had is left alone.
+        let report = db
+            .import_legacy_sync_metadata(
+                r#"{"engines": {"passwords": {"syncID": "8M-HfX6dm-pD"}}}"#,
+            )
+            .unwrap();
+        assert_eq!(
+            report,
+            LegacyImportReport {
+                format: Some(LegacySyncMetadataFormat::Fennec),
+                ..LegacyImportReport::default()
+            }
+        );
+        assert_eq!(meta(&db), (None, None, Some(1234), None));
+    }
+
+    #[test]
+    fn test_import_unknown_and_invalid() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        assert_eq!(
+            db.import_legacy_sync_metadata(r#"{"schema_version": "V7"}"#)
+                .unwrap(),
+            LegacyImportReport::default()
+        );
+        assert!(db.import_legacy_sync_metadata("not json").is_err());
+        assert_eq!(meta(&db), (None, None, None, None));
+    }
+}
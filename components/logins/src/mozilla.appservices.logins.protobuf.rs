@@ -30,3 +30,15 @@ pub struct PasswordInfos {
     #[prost(message, repeated, tag="1")]
     pub infos: ::std::vec::Vec<PasswordInfo>,
 }
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct HostnamePasswordInfos {
+    #[prost(string, required, tag="1")]
+    pub hostname: std::string::String,
+    #[prost(message, repeated, tag="2")]
+    pub infos: ::std::vec::Vec<PasswordInfo>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct PasswordInfosByHostname {
+    #[prost(message, repeated, tag="1")]
+    pub hostnames: ::std::vec::Vec<HostnamePasswordInfos>,
+}
@@ -0,0 +1,377 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Timing for the main operations, for tracking down slow ones.
+//!
+//! This is off until it's turned on with `set_debug_options`. After that,
+//! `add`, `update`, `delete`, `touch`, `get_all` and applying and fetching
+//! records for sync each log how long they took and how many rows they
+//! returned or changed, along with how long each statement took if they were
+//! slower than `DebugOptions::log_slow_ops_over`. The most recent ones are
+//! also kept for `get_recent_op_stats`.
+//!
+//! Statements are timed with a SQLite profile hook, which is only installed
+//! while debug options are set, so that there's no cost otherwise. It's never
+//! installed on connections from `attach_to_connection`, which might have
+//! the embedder's own hook, so their operations are timed without the
+//! statements. The text of each statement is logged with any
+//! string literals replaced by `?`, although values should be bound as
+//! parameters, which never appear in it anyway. Operations which run other
+//! operations (applying incoming records fetches the outgoing ones, for
+//! example) are timed as one.
+
+use crate::db::LoginDb;
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+
+/// How many operations `get_recent_op_stats` remembers.
+pub const RECENT_OP_STATS_CAPACITY: usize = 50;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DebugOptions {
+    /// Log operations which take longer than this at `warn` level, with the
+    /// time spent in each statement. Faster ones are logged at `debug`.
+    pub log_slow_ops_over: Option<Duration>,
+    /// Log every statement an operation runs, at `debug` level, as it
+    /// finishes.
+    pub log_all_sql: bool,
+}
+
+impl DebugOptions {
+    fn is_enabled(&self) -> bool {
+        self.log_slow_ops_over.is_some() || self.log_all_sql
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpStats {
+    /// The name of the `LoginDb` method, like `"add"`.
+    pub operation: String,
+    pub duration: Duration,
+    /// The number of logins returned, or changed.
+    pub rows: usize,
+    /// The statements run, in the order they were first run.
+    pub statements: Vec<StatementStats>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatementStats {
+    /// The text of the statement, with string literals replaced by `?`.
+    pub sql: String,
+    /// How many times it ran.
+    pub count: usize,
+    /// The time it took, in total.
+    pub duration: Duration,
+}
+
+// The operation being timed on this thread, if there is one.
+struct CurrentOp {
+    log_all_sql: bool,
+    statements: Vec<StatementStats>,
+}
+
+thread_local! {
+    static CURRENT_OP: RefCell<Option<CurrentOp>> = RefCell::new(None);
+}
+
+// Installed as the profile hook of our connection while debug options are
+// set. SQLite calls this when each statement finishes, with its text before
+// any parameters were bound.
+fn profile_statement(sql: &str, duration: Duration) {
+    CURRENT_OP.with(|op| {
+        let mut op = op.borrow_mut();
+        let op = match op.as_mut() {
+            Some(op) => op,
+            None => return,
+        };
+        let sql = elide_literals(sql);
+        if op.log_all_sql {
+            log::debug!("Statement took {:?}: {}", duration, sql);
+        }
+        match op.statements.iter_mut().find(|stats| stats.sql == sql) {
+            Some(stats) => {
+                stats.count += 1;
+                stats.duration += duration;
+            }
+            None => op.statements.push(StatementStats {
+                sql,
+                count: 1,
+                duration,
+            }),
+        }
+    })
+}
+
+// Replaces the contents of any (non-empty) string literals in `sql` with `?`.
+fn elide_literals(sql: &str) -> String {
+    let mut result = String::with_capacity(sql.len());
+    let mut in_literal = false;
+    let mut chars = sql.chars().peekable();
+    while let Some(c) = chars.next() {
+        if !in_literal {
+            result.push(c);
+            if c == '-' && chars.peek() == Some(&'-') {
+                // A comment, which may have apostrophes in it.
+                while let Some(&c) = chars.peek() {
+                    if c == '\n' {
+                        break;
+                    }
+                    result.push(c);
+                    chars.next();
+                }
+            } else if c == '\'' {
+                if chars.peek() == Some(&'\'') {
+                    // An empty string.
+                    result.push(chars.next().unwrap());
+                } else {
+                    in_literal = true;
+                    result.push('?');
+                }
+            }
+        } else if c == '\'' {
+            if chars.peek() == Some(&'\'') {
+                // An escaped quote, which doesn't end the literal.
+                chars.next();
+            } else {
+                in_literal = false;
+                result.push(c);
+            }
+        }
+    }
+    result
+}
+
+/// Times an operation, from `LoginDb::begin_op` until it's dropped.
+pub(crate) struct OpTimer<'a> {
+    db: &'a LoginDb,
+    // `None` if there are no debug options, or another operation is already
+    // being timed.
+    started: Option<(&'static str, Instant)>,
+    rows: usize,
+}
+
+impl<'a> OpTimer<'a> {
+    pub(crate) fn set_rows(&mut self, rows: usize) {
+        self.rows = rows;
+    }
+}
+
+impl<'a> Drop for OpTimer<'a> {
+    fn drop(&mut self) {
+        let (operation, start) = match self.started.take() {
+            Some(started) => started,
+            None => return,
+        };
+        let duration = start.elapsed();
+        let statements = CURRENT_OP
+            .with(|op| op.borrow_mut().take())
+            .map_or_else(Vec::new, |op| op.statements);
+        self.db.finish_op(OpStats {
+            operation: operation.into(),
+            duration,
+            rows: self.rows,
+            statements,
+        });
+    }
+}
+
+impl LoginDb {
+    /// Set (or, with `DebugOptions::default()`, clear) the options for
+    /// logging slow operations. See the `op_stats` module.
+    pub fn set_debug_options(&mut self, options: DebugOptions) {
+        self.debug_options.set(options);
+        if !self.attached {
+            self.db.profile(if options.is_enabled() {
+                Some(profile_statement)
+            } else {
+                None
+            });
+        }
+    }
+
+    /// The most recent operations timed since debug options were set, oldest
+    /// first. At most `RECENT_OP_STATS_CAPACITY` are kept.
+    pub fn get_recent_op_stats(&self) -> Vec<OpStats> {
+        self.recent_op_stats.borrow().iter().cloned().collect()
+    }
+
+    pub(crate) fn begin_op(&self, operation: &'static str) -> OpTimer<'_> {
+        let options = self.debug_options.get();
+        let started = options.is_enabled()
+            && CURRENT_OP.with(|op| {
+                let mut op = op.borrow_mut();
+                if op.is_some() {
+                    return false;
+                }
+                *op = Some(CurrentOp {
+                    log_all_sql: options.log_all_sql,
+                    statements: Vec::new(),
+                });
+                true
+            });
+        OpTimer {
+            db: self,
+            started: if started {
+                Some((operation, Instant::now()))
+            } else {
+                None
+            },
+            rows: 0,
+        }
+    }
+
+    fn finish_op(&self, stats: OpStats) {
+        match self.debug_options.get().log_slow_ops_over {
+            Some(threshold) if stats.duration > threshold => {
+                log::warn!(
+                    "Slow {}: took {:?}, {} rows",
+                    stats.operation,
+                    stats.duration,
+                    stats.rows
+                );
+                let mut statements = stats.statements.iter().collect::<Vec<_>>();
+                statements.sort_by(|a, b| b.duration.cmp(&a.duration));
+                for statement in statements {
+                    log::warn!(
+                        "  {:?} ({} times): {}",
+                        statement.duration,
+                        statement.count,
+                        statement.sql
+                    );
+                }
+            }
+            _ => log::debug!(
+                "{} took {:?}, {} rows",
+                stats.operation,
+                stats.duration,
+                stats.rows
+            ),
+        }
+        let mut recent = self.recent_op_stats.borrow_mut();
+        if recent.len() == RECENT_OP_STATS_CAPACITY {
+            recent.pop_front();
+        }
+        recent.push_back(stats);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::login::Login;
+    use crate::testing::LoginFixture;
+
+    fn login(id: &str, password: &str) -> Login {
+        LoginFixture::builder()
+            .guid(id)
+            .http_realm("https://www.example.com")
+            .username("user")
+            .password(password)
+            .build()
+    }
+
+    #[test]
+    fn test_elide_literals() {
+        assert_eq!(
+            elide_literals("SELECT 1 FROM t WHERE a = 'secret' AND b = '' AND c = 'it''s'"),
+            "SELECT 1 FROM t WHERE a = '?' AND b = '' AND c = '?'"
+        );
+        assert_eq!(
+            elide_literals("SELECT 'a' -- isn't 'b'\n FROM t"),
+            "SELECT '?' -- isn't 'b'\n FROM t"
+        );
+        assert_eq!(elide_literals("SELECT :guid"), "SELECT :guid");
+    }
+
+    #[test]
+    fn test_disabled() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        db.add(login("dummy_000001", "hunter2")).unwrap();
+        db.get_all().unwrap();
+        assert!(db.get_recent_op_stats().is_empty());
+    }
+
+    #[test]
+    fn test_op_stats() {
+        let mut db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        db.set_debug_options(DebugOptions {
+            log_slow_ops_over: Some(Duration::from_secs(0)),
+            log_all_sql: true,
+        });
+        db.add(login("dummy_000001", "hunter2")).unwrap();
+        db.add(login("dummy_000002", "hunter3")).unwrap();
+        db.touch("dummy_000001").unwrap();
+        assert_eq!(db.get_all().unwrap().len(), 2);
+        assert!(db.delete("dummy_000002").unwrap());
+
+        let stats = db.get_recent_op_stats();
+        let ops = stats
+            .iter()
+            .map(|stats| (stats.operation.as_str(), stats.rows))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ops,
+            vec![
+                ("add", 1),
+                ("add", 1),
+                ("touch", 1),
+                ("get_all", 2),
+                ("delete", 1)
+            ]
+        );
+        for stats in &stats {
+            assert!(!stats.statements.is_empty(), "{:?}", stats);
+            for statement in &stats.statements {
+                // What's logged is what's kept here.
+                assert!(!statement.sql.contains("hunter"), "{}", statement.sql);
+                assert!(!statement.sql.contains("dummy_"), "{}", statement.sql);
+                assert!(statement.count > 0);
+            }
+        }
+        assert!(stats[3]
+            .statements
+            .iter()
+            .any(|statement| statement.sql.contains("FROM loginsL")));
+
+        // Only the most recent are kept.
+        for _ in 0..RECENT_OP_STATS_CAPACITY {
+            db.get_all().unwrap();
+        }
+        let stats = db.get_recent_op_stats();
+        assert_eq!(stats.len(), RECENT_OP_STATS_CAPACITY);
+        assert!(stats.iter().all(|stats| stats.operation == "get_all"));
+
+        // Turning them off stops collecting, but keeps what was collected.
+        db.set_debug_options(DebugOptions::default());
+        db.touch("dummy_000001").unwrap();
+        let stats = db.get_recent_op_stats();
+        assert_eq!(stats.len(), RECENT_OP_STATS_CAPACITY);
+        assert_eq!(stats.last().unwrap().operation, "get_all");
+    }
+
+    #[test]
+    fn test_attached_connection_keeps_its_hook() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static EMBEDDER_STATEMENTS: AtomicUsize = AtomicUsize::new(0);
+        fn embedder_profile(_: &str, _: Duration) {
+            EMBEDDER_STATEMENTS.fetch_add(1, Ordering::SeqCst);
+        }
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.profile(Some(embedder_profile));
+        let mut db = LoginDb::attach_to_connection(conn, None).unwrap();
+        db.set_debug_options(DebugOptions {
+            log_slow_ops_over: Some(Duration::from_secs(0)),
+            log_all_sql: false,
+        });
+        let before = EMBEDDER_STATEMENTS.load(Ordering::SeqCst);
+        db.add(login("dummy_000001", "hunter2")).unwrap();
+        assert!(EMBEDDER_STATEMENTS.load(Ordering::SeqCst) > before);
+
+        // The operation is still timed, just without its statements.
+        let stats = db.get_recent_op_stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].operation, "add");
+        assert!(stats[0].statements.is_empty());
+    }
+}
@@ -0,0 +1,535 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Opening the database when something else might briefly have it locked.
+//!
+//! On Android, the database is opened during startup, when a backup agent or
+//! the previous instance of the app may still be holding a lock on the file.
+//! `LoginDb::open` fails straight away with `SQLITE_BUSY` in that case, which
+//! is easy to mistake for a broken database. `open_with_retry` retries those
+//! failures, and tells them apart from a corrupt file or a wrong key, so that
+//! callers only reset the database when there's really no other choice.
+//!
+//! When opening fails anyway, `verify_key` takes a closer look at the file,
+//! without changing it, to tell a wrong key from a corrupt or unreadable
+//! file. Only a wrong key or a corrupt file are reasons to start over.
+
+use crate::db::{sqlcipher_3_compat, LoginDb};
+use crate::error::*;
+use rusqlite::{Connection, ErrorCode, OpenFlags, NO_PARAMS};
+use serde_derive::*;
+use sql_support::ConnExt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::Duration;
+
+// Every plaintext SQLite database starts with this.
+const SQLITE_HEADER_MAGIC: &[u8] = b"SQLite format 3\0";
+const SQLITE_HEADER_SIZE: usize = 100;
+// Database files are a whole number of pages, which are at least this big.
+const MIN_PAGE_SIZE: u64 = 512;
+
+/// How hard `open_with_retry` tries when the database is locked.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryConfig {
+    /// How many times to try opening the database, including the first.
+    pub max_attempts: u32,
+    /// How long to wait before the first retry. This doubles after each
+    /// attempt, up to `max_delay`.
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// The `busy_timeout` set on the connection once it's open.
+    pub busy_timeout: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        // About 2 seconds in all, which is longer than a backup agent
+        // usually holds on to the file.
+        Self {
+            max_attempts: 6,
+            initial_delay: Duration::from_millis(50),
+            max_delay: Duration::from_millis(800),
+            busy_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// The result of `LoginDb::health_check`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HealthStatus {
+    Healthy,
+    /// Another connection has the database locked. This is probably
+    /// temporary.
+    Locked,
+    /// The database couldn't be read.
+    Corrupt,
+}
+
+/// The result of `LoginDb::verify_key`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum KeyVerification {
+    /// The database is encrypted with the key.
+    ValidKey,
+    /// The database is encrypted, with its salt in the file, but not with
+    /// this key. A file whose first page is garbage looks just the same.
+    WrongKey,
+    /// The database is readable without a key. An empty file counts.
+    NotEncrypted,
+    /// The file is truncated, or its header or schema is damaged.
+    CorruptFile,
+    /// The database is encrypted, but keeps its salt outside the file (see
+    /// `open_with_salt`), so the key can't be checked without it.
+    SaltNotInFile,
+    /// The file couldn't be read. This might be temporary, or mean that it
+    /// doesn't exist.
+    IoError(#[serde(serialize_with = "serialize_io_error_kind")] io::ErrorKind),
+}
+
+fn serialize_io_error_kind<S>(
+    kind: &io::ErrorKind,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.serialize_str(&format!("{:?}", kind))
+}
+
+// What the first bytes of a database file say about it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Header {
+    Plaintext,
+    // SQLCipher starts the file with the salt, instead of the magic.
+    SqlCipher,
+    // SQLCipher leaves the first 32 bytes in plaintext, but still reserves
+    // space at the end of each page for its IV and HMAC, which plain SQLite
+    // doesn't.
+    SqlCipherPlaintextHeader,
+}
+
+// The file's length, and its header, if it's long enough to have one.
+fn read_header(path: &Path) -> io::Result<(u64, Option<Header>)> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    if len < SQLITE_HEADER_SIZE as u64 {
+        return Ok((len, None));
+    }
+    let mut header = [0u8; SQLITE_HEADER_SIZE];
+    file.read_exact(&mut header)?;
+    let header = if &header[..SQLITE_HEADER_MAGIC.len()] != SQLITE_HEADER_MAGIC {
+        Header::SqlCipher
+    } else if header[20] != 0 {
+        Header::SqlCipherPlaintextHeader
+    } else {
+        Header::Plaintext
+    };
+    Ok((len, Some(header)))
+}
+
+// Reads the schema, and nothing else, over a read-only connection.
+fn read_schema(path: &Path, encryption_key: Option<&str>) -> Result<()> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    if let Some(key) = encryption_key {
+        conn.set_pragma("key", key)?;
+        sqlcipher_3_compat(&conn)?;
+    }
+    conn.query_one::<i64>("SELECT count(*) FROM sqlite_master")?;
+    Ok(())
+}
+
+fn sqlite_code(e: &Error) -> Option<ErrorCode> {
+    match e.kind() {
+        ErrorKind::SqlError(rusqlite::Error::SqliteFailure(err, _)) => Some(err.code),
+        _ => None,
+    }
+}
+
+fn is_locked(code: Option<ErrorCode>) -> bool {
+    code == Some(ErrorCode::DatabaseBusy) || code == Some(ErrorCode::DatabaseLocked)
+}
+
+// Turn the errors we know how to recover from into their own kinds. SQLCipher
+// reports a wrong key and a corrupt file the same way, so `verify_key` has
+// a closer look at the file when opening an encrypted database fails.
+fn classify_open_error(e: Error, path: &Path, encryption_key: Option<&str>) -> Error {
+    match (sqlite_code(&e), encryption_key) {
+        (code, _) if is_locked(code) => ErrorKind::DatabaseLocked(e.to_string()).into(),
+        (Some(ErrorCode::NotADatabase), Some(key)) => match LoginDb::verify_key(path, key) {
+            Ok(KeyVerification::WrongKey) | Ok(KeyVerification::NotEncrypted) => {
+                ErrorKind::WrongEncryptionKey.into()
+            }
+            Ok(KeyVerification::CorruptFile) => ErrorKind::DatabaseCorrupt(e.to_string()).into(),
+            // Don't guess.
+            _ => e,
+        },
+        (Some(ErrorCode::NotADatabase), None) | (Some(ErrorCode::DatabaseCorrupt), _) => {
+            ErrorKind::DatabaseCorrupt(e.to_string()).into()
+        }
+        _ => e,
+    }
+}
+
+// `KeyVerification` for a failure to read the schema.
+fn classify_read_error(e: Error, keyed: bool) -> Result<KeyVerification> {
+    Ok(match sqlite_code(&e) {
+        Some(ErrorCode::NotADatabase) if keyed => KeyVerification::WrongKey,
+        Some(ErrorCode::NotADatabase) | Some(ErrorCode::DatabaseCorrupt) => {
+            KeyVerification::CorruptFile
+        }
+        Some(ErrorCode::PermissionDenied) => {
+            KeyVerification::IoError(io::ErrorKind::PermissionDenied)
+        }
+        Some(ErrorCode::SystemIOFailure) | Some(ErrorCode::CannotOpen) => {
+            KeyVerification::IoError(io::ErrorKind::Other)
+        }
+        code if is_locked(code) => throw!(ErrorKind::DatabaseLocked(e.to_string())),
+        _ => return Err(e),
+    })
+}
+
+impl LoginDb {
+    /// Like `open`, but retries (with backoff) if the database is locked, and
+    /// fails with `ErrorKind::DatabaseLocked`, `ErrorKind::DatabaseCorrupt`
+    /// or `ErrorKind::WrongEncryptionKey` when it can tell what went wrong.
+    pub fn open_with_retry(
+        path: impl AsRef<Path>,
+        encryption_key: Option<&str>,
+        retry: RetryConfig,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let mut delay = retry.initial_delay;
+        let mut attempt = 1;
+        loop {
+            match Self::try_open(path, encryption_key, retry.busy_timeout) {
+                Ok(db) => return Ok(db),
+                Err(e) => {
+                    let e = classify_open_error(e, path, encryption_key);
+                    let locked = matches!(e.kind(), ErrorKind::DatabaseLocked(_));
+                    if !locked || attempt >= retry.max_attempts {
+                        return Err(e);
+                    }
+                    log::warn!(
+                        "Database locked on attempt {} to open it, retrying in {:?}",
+                        attempt,
+                        delay
+                    );
+                }
+            }
+            std::thread::sleep(delay);
+            delay = std::cmp::min(delay * 2, retry.max_delay);
+            attempt += 1;
+        }
+    }
+
+    /// Checks whether `encryption_key` opens the database at `path`, without
+    /// changing the file, or setting up the schema. Fails with
+    /// `ErrorKind::DatabaseLocked` if another connection has it locked.
+    pub fn verify_key(path: impl AsRef<Path>, encryption_key: &str) -> Result<KeyVerification> {
+        let path = path.as_ref();
+        let (len, header) = match read_header(path) {
+            Ok(header) => header,
+            Err(e) => return Ok(KeyVerification::IoError(e.kind())),
+        };
+        if len == 0 {
+            // SQLite treats this as a new database.
+            return Ok(KeyVerification::NotEncrypted);
+        }
+        if len % MIN_PAGE_SIZE != 0 {
+            return Ok(KeyVerification::CorruptFile);
+        }
+        match header {
+            Some(Header::SqlCipher) => match read_schema(path, Some(encryption_key)) {
+                Ok(()) => Ok(KeyVerification::ValidKey),
+                Err(e) => classify_read_error(e, true),
+            },
+            Some(Header::Plaintext) => match read_schema(path, None) {
+                Ok(()) => Ok(KeyVerification::NotEncrypted),
+                Err(e) => classify_read_error(e, false),
+            },
+            Some(Header::SqlCipherPlaintextHeader) => Ok(KeyVerification::SaltNotInFile),
+            // A whole page is longer than a header.
+            None => Ok(KeyVerification::CorruptFile),
+        }
+    }
+
+    /// Whether the file at `path` looks like a SQLCipher database, going by
+    /// its header. Files too short to have one aren't.
+    pub fn is_sqlcipher_database(path: impl AsRef<Path>) -> io::Result<bool> {
+        let (_, header) = read_header(path.as_ref())?;
+        Ok(matches!(
+            header,
+            Some(Header::SqlCipher) | Some(Header::SqlCipherPlaintextHeader)
+        ))
+    }
+
+    fn try_open(path: &Path, encryption_key: Option<&str>, busy_timeout: Duration) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        // Fail fast while opening, so that the backoff in `open_with_retry`
+        // decides how long we wait.
+        conn.busy_timeout(Duration::from_millis(0))?;
+        let db = Self::with_connection(conn, encryption_key, None)?;
+        db.busy_timeout(busy_timeout)?;
+        Ok(db)
+    }
+
+    /// A cheap read of each table, for startup diagnostics. Unlike
+    /// `check_integrity`, this doesn't look at every row.
+    pub fn health_check(&self) -> Result<HealthStatus> {
+        let read = || -> Result<()> {
+            self.query_one::<i64>("SELECT COUNT(*) FROM sqlite_master")?;
+            for table in &["loginsL", "loginsM", "loginsSyncMeta"] {
+                self.query_row(
+                    &self.sql(&format!("SELECT EXISTS(SELECT 1 FROM {})", table)),
+                    NO_PARAMS,
+                    |row| row.get::<_, bool>(0),
+                )?;
+            }
+            Ok(())
+        };
+        match read() {
+            Ok(()) => Ok(HealthStatus::Healthy),
+            Err(e) => {
+                let code = sqlite_code(&e);
+                if is_locked(code) {
+                    Ok(HealthStatus::Locked)
+                } else if code == Some(ErrorCode::DatabaseCorrupt)
+                    || code == Some(ErrorCode::NotADatabase)
+                {
+                    Ok(HealthStatus::Corrupt)
+                } else {
+                    Err(e)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::LoginFixture;
+    use crate::Login;
+    use std::io::{Seek, SeekFrom, Write};
+
+    fn login() -> Login {
+        LoginFixture::builder().username("user").build()
+    }
+
+    fn quick_retry(max_attempts: u32) -> RetryConfig {
+        RetryConfig {
+            max_attempts,
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(50),
+            busy_timeout: Duration::from_millis(100),
+        }
+    }
+
+    #[test]
+    fn test_retry_while_locked() {
+        let dir = tempdir::TempDir::new("open_retry").unwrap();
+        let path = dir.path().join("logins.sqlite");
+        let holder = LoginDb::open(&path, Some("secret")).unwrap();
+        holder.add(login()).unwrap();
+        holder.execute_batch("BEGIN EXCLUSIVE").unwrap();
+
+        // Gives up if the lock isn't released in time...
+        let err = LoginDb::open_with_retry(&path, Some("secret"), quick_retry(2))
+            .err()
+            .expect("should fail while locked");
+        match err.kind() {
+            ErrorKind::DatabaseLocked(_) => {}
+            e => panic!("Expected DatabaseLocked, got {:?}", e),
+        }
+
+        // ...but succeeds if it's released while we're retrying.
+        let releaser = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            holder.execute_batch("ROLLBACK").unwrap();
+        });
+        let db = LoginDb::open_with_retry(&path, Some("secret"), quick_retry(50)).unwrap();
+        releaser.join().unwrap();
+        assert_eq!(db.get_all().unwrap().len(), 1);
+        assert_eq!(db.query_one::<i64>("PRAGMA busy_timeout").unwrap(), 100);
+        assert_eq!(db.health_check().unwrap(), HealthStatus::Healthy);
+    }
+
+    #[test]
+    fn test_health_check_locked() {
+        let dir = tempdir::TempDir::new("health_locked").unwrap();
+        let path = dir.path().join("logins.sqlite");
+        let holder = LoginDb::open(&path, Some("secret")).unwrap();
+        let db = LoginDb::open_with_retry(&path, Some("secret"), quick_retry(1)).unwrap();
+        db.busy_timeout(Duration::from_millis(0)).unwrap();
+        holder.execute_batch("BEGIN EXCLUSIVE").unwrap();
+        assert_eq!(db.health_check().unwrap(), HealthStatus::Locked);
+        holder.execute_batch("ROLLBACK").unwrap();
+        assert_eq!(db.health_check().unwrap(), HealthStatus::Healthy);
+    }
+
+    #[test]
+    fn test_corrupt_header() {
+        let dir = tempdir::TempDir::new("open_corrupt").unwrap();
+        let path = dir.path().join("logins.sqlite");
+        LoginDb::open(&path, None).unwrap().add(login()).unwrap();
+
+        let mut file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(b"definitely not sqlite").unwrap();
+        drop(file);
+
+        let err = LoginDb::open_with_retry(&path, None, quick_retry(3))
+            .err()
+            .expect("should fail to open");
+        match err.kind() {
+            ErrorKind::DatabaseCorrupt(_) => {}
+            e => panic!("Expected DatabaseCorrupt, got {:?}", e),
+        }
+    }
+
+    fn encrypted_db(dir: &tempdir::TempDir) -> std::path::PathBuf {
+        let path = dir.path().join("logins.sqlite");
+        LoginDb::open(&path, Some("secret"))
+            .unwrap()
+            .add(login())
+            .unwrap();
+        path
+    }
+
+    #[test]
+    fn test_verify_key() {
+        let dir = tempdir::TempDir::new("verify_key").unwrap();
+        let path = encrypted_db(&dir);
+        let before = std::fs::read(&path).unwrap();
+        assert!(LoginDb::is_sqlcipher_database(&path).unwrap());
+        assert_eq!(
+            LoginDb::verify_key(&path, "secret").unwrap(),
+            KeyVerification::ValidKey
+        );
+        assert_eq!(
+            LoginDb::verify_key(&path, "wrong").unwrap(),
+            KeyVerification::WrongKey
+        );
+        // Neither changed anything.
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+    }
+
+    #[test]
+    fn test_verify_key_not_encrypted() {
+        let dir = tempdir::TempDir::new("verify_key_plaintext").unwrap();
+        let path = dir.path().join("logins.sqlite");
+        LoginDb::open(&path, None).unwrap().add(login()).unwrap();
+        assert!(!LoginDb::is_sqlcipher_database(&path).unwrap());
+        assert_eq!(
+            LoginDb::verify_key(&path, "secret").unwrap(),
+            KeyVerification::NotEncrypted
+        );
+
+        let empty = dir.path().join("empty.sqlite");
+        File::create(&empty).unwrap();
+        assert!(!LoginDb::is_sqlcipher_database(&empty).unwrap());
+        assert_eq!(
+            LoginDb::verify_key(&empty, "secret").unwrap(),
+            KeyVerification::NotEncrypted
+        );
+    }
+
+    #[test]
+    fn test_verify_key_salt_not_in_file() {
+        let dir = tempdir::TempDir::new("verify_key_salt").unwrap();
+        let path = dir.path().join("logins.sqlite");
+        LoginDb::open_with_salt(&path, "secret", "952b9e3d53b39a8eba70b398acefa0a0").unwrap();
+        assert!(LoginDb::is_sqlcipher_database(&path).unwrap());
+        assert_eq!(
+            LoginDb::verify_key(&path, "secret").unwrap(),
+            KeyVerification::SaltNotInFile
+        );
+    }
+
+    #[test]
+    fn test_verify_key_corrupt() {
+        let dir = tempdir::TempDir::new("verify_key_corrupt").unwrap();
+        let path = encrypted_db(&dir);
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(700).unwrap();
+        drop(file);
+        assert_eq!(
+            LoginDb::verify_key(&path, "secret").unwrap(),
+            KeyVerification::CorruptFile
+        );
+        // Opening the database tells it apart from a wrong key, too.
+        let err = LoginDb::open_with_retry(&path, Some("secret"), quick_retry(1))
+            .err()
+            .expect("should fail to open");
+        match err.kind() {
+            ErrorKind::DatabaseCorrupt(_) => {}
+            e => panic!("Expected DatabaseCorrupt, got {:?}", e),
+        }
+
+        // A plaintext database with a damaged schema page.
+        let path = dir.path().join("plaintext.sqlite");
+        LoginDb::open(&path, None).unwrap().add(login()).unwrap();
+        let mut file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(SQLITE_HEADER_SIZE as u64))
+            .unwrap();
+        file.write_all(&[0xff; 400]).unwrap();
+        drop(file);
+        assert_eq!(
+            LoginDb::verify_key(&path, "secret").unwrap(),
+            KeyVerification::CorruptFile
+        );
+    }
+
+    #[test]
+    fn test_verify_key_io_error() {
+        let dir = tempdir::TempDir::new("verify_key_io").unwrap();
+        let missing = dir.path().join("missing.sqlite");
+        let result = LoginDb::verify_key(&missing, "secret").unwrap();
+        assert_eq!(result, KeyVerification::IoError(io::ErrorKind::NotFound));
+        assert_eq!(
+            serde_json::to_string(&result).unwrap(),
+            r#"{"IoError":"NotFound"}"#
+        );
+        assert!(!missing.exists());
+        assert_eq!(
+            LoginDb::is_sqlcipher_database(&missing).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_verify_key_permission_denied() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempdir::TempDir::new("verify_key_permissions").unwrap();
+        let path = encrypted_db(&dir);
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o000)).unwrap();
+        if File::open(&path).is_ok() {
+            // Running as root, so permissions don't apply.
+            return;
+        }
+        assert_eq!(
+            LoginDb::verify_key(&path, "secret").unwrap(),
+            KeyVerification::IoError(io::ErrorKind::PermissionDenied)
+        );
+    }
+
+    #[test]
+    fn test_wrong_key() {
+        let dir = tempdir::TempDir::new("open_wrong_key").unwrap();
+        let path = dir.path().join("logins.sqlite");
+        LoginDb::open(&path, Some("secret"))
+            .unwrap()
+            .add(login())
+            .unwrap();
+        let err = LoginDb::open_with_retry(&path, Some("wrong"), quick_retry(3))
+            .err()
+            .expect("should fail to open");
+        match err.kind() {
+            ErrorKind::WrongEncryptionKey => {}
+            e => panic!("Expected WrongEncryptionKey, got {:?}", e),
+        }
+    }
+}
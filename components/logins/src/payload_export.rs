@@ -0,0 +1,230 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Exporting every record as the JSON we'd upload to the server, for "download
+//! your data" requests. Unlike `export_to_backup`, the output isn't
+//! encrypted, so it's only for handing straight to the user.
+//!
+//! The output is a JSON array of sync payloads (the cleartext of the BSOs,
+//! with any fields we got from the server but don't know about), written one
+//! record at a time so that we never have all of them in memory.
+
+use crate::db::{raw_guid, record_payload, LoginDb};
+use crate::error::*;
+use crate::schema;
+use lazy_static::lazy_static;
+use rusqlite::named_params;
+use serde_derive::*;
+use std::io::{BufWriter, Write};
+
+/// Options for `export_sync_payloads`. There's deliberately no `Default`, so
+/// that callers have to spell out `include_secrets: true`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PayloadExportOptions {
+    /// Must be `true`, as the payloads include passwords in plain text.
+    pub include_secrets: bool,
+    /// Whether to include local deletions, as `{"id": ..., "deleted": true}`.
+    pub include_tombstones: bool,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+pub struct PayloadExportSummary {
+    /// How many payloads were written, including tombstones.
+    pub num_exported: u64,
+    pub num_tombstones: u64,
+    /// How many records were left out because we couldn't read them.
+    pub num_skipped: u64,
+    pub errors: Vec<String>,
+}
+
+lazy_static! {
+    // The local record where there is one, and the mirror's otherwise, as in
+    // `get_all`, with the columns `record_payload` needs.
+    static ref EXPORT_ROWS_SQL: String = format!(
+        "SELECT {common_cols}, is_deleted, unknown_fields, guid_bytes FROM (
+             SELECT l.*, CAST(l.guid AS BLOB) AS guid_bytes, m.unknown_fields
+             FROM loginsL l
+             LEFT JOIN loginsM m ON m.guid = l.guid
+             WHERE l.is_deleted = 0 OR :include_tombstones
+         )
+         UNION ALL
+         SELECT {common_cols}, 0, unknown_fields, CAST(guid AS BLOB)
+         FROM loginsM
+         WHERE is_overridden = 0",
+        common_cols = schema::COMMON_COLS,
+    );
+}
+
+impl LoginDb {
+    /// Write every record to `writer` as a JSON array of sync payloads.
+    /// Records we can't read are left out and reported in the summary,
+    /// rather than failing the export, unless it's because the encryption
+    /// key is wrong.
+    pub fn export_sync_payloads(
+        &self,
+        writer: impl Write,
+        options: PayloadExportOptions,
+    ) -> Result<PayloadExportSummary> {
+        if !options.include_secrets {
+            throw!(ErrorKind::SecretsNotIncluded);
+        }
+        let mut op = self.begin_op("export_sync_payloads");
+        let mut writer = BufWriter::new(writer);
+        let mut summary = PayloadExportSummary::default();
+        let mut stmt = self.db.prepare_cached(&self.sql(&EXPORT_ROWS_SQL))?;
+        let mut rows = stmt.query_named(named_params! {
+            ":include_tombstones": options.include_tombstones,
+        })?;
+        write_all(&mut writer, b"[")?;
+        while let Some(row) = rows.next()? {
+            let serialized = record_payload(row, self.encdec())
+                .and_then(|payload| Ok((payload.deleted, serde_json::to_vec(&payload)?)));
+            let (deleted, json) = match serialized {
+                Ok(serialized) => serialized,
+                Err(e) if matches!(e.kind(), ErrorKind::DecryptionFailed(_)) => return Err(e),
+                Err(e) => {
+                    let guid = raw_guid(row)?;
+                    log::warn!("Not exporting unreadable record {:?}: {}", guid, e.label());
+                    summary.num_skipped += 1;
+                    summary.errors.push(format!("{}: {}", guid, e.label()));
+                    continue;
+                }
+            };
+            if summary.num_exported > 0 {
+                write_all(&mut writer, b",")?;
+            }
+            write_all(&mut writer, &json)?;
+            summary.num_exported += 1;
+            if deleted {
+                summary.num_tombstones += 1;
+            }
+        }
+        write_all(&mut writer, b"]")?;
+        writer.flush().map_err(serde_json::Error::io)?;
+        op.set_rows(summary.num_exported as usize);
+        Ok(summary)
+    }
+}
+
+// Failing to write is reported the same way as when `serde_json::to_writer`
+// fails to, as with `export_to_backup`.
+fn write_all(writer: &mut impl Write, bytes: &[u8]) -> Result<()> {
+    writer.write_all(bytes).map_err(serde_json::Error::io)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::login::Login;
+    use crate::testing::{sync_db, LoginFixture};
+    use sync15::{Payload, ServerTimestamp};
+
+    const EVERYTHING: PayloadExportOptions = PayloadExportOptions {
+        include_secrets: true,
+        include_tombstones: true,
+    };
+
+    fn export(db: &LoginDb, options: PayloadExportOptions) -> (Vec<Payload>, PayloadExportSummary) {
+        let mut out = vec![];
+        let summary = db.export_sync_payloads(&mut out, options).unwrap();
+        (serde_json::from_slice(&out).unwrap(), summary)
+    }
+
+    fn sorted(mut logins: Vec<Login>) -> Vec<Login> {
+        logins.sort_by(|a, b| a.guid.cmp(&b.guid));
+        logins
+    }
+
+    #[test]
+    fn test_export_matches_get_all() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        assert_eq!(export(&db, EVERYTHING).0, vec![]);
+
+        db.import_multiple(
+            &(0..2000)
+                .map(|n| LoginFixture::numbered(n).build())
+                .collect::<Vec<_>>(),
+        )
+        .unwrap();
+        sync_db(&db, vec![], ServerTimestamp(1000));
+        // Some local changes on top of the synced records, which should win.
+        let all = db.get_all().unwrap();
+        for login in all.iter().take(300) {
+            db.update(Login {
+                password: "changed".into(),
+                ..login.clone()
+            })
+            .unwrap();
+        }
+        for login in all.iter().skip(300).take(100) {
+            db.delete(&login.guid).unwrap();
+        }
+        db.add(LoginFixture::numbered(2000).build()).unwrap();
+
+        let (payloads, summary) = export(
+            &db,
+            PayloadExportOptions {
+                include_secrets: true,
+                include_tombstones: false,
+            },
+        );
+        assert_eq!(summary.num_exported, 1901);
+        assert_eq!(summary.num_tombstones, 0);
+        assert_eq!(summary.num_skipped, 0);
+        assert!(payloads.iter().all(|p| !p.deleted));
+        let exported = payloads
+            .into_iter()
+            .map(|p| p.into_record::<Login>().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(sorted(exported), sorted(db.get_all().unwrap()));
+
+        let (payloads, summary) = export(&db, EVERYTHING);
+        assert_eq!(summary.num_exported, 2001);
+        assert_eq!(summary.num_tombstones, 100);
+        assert_eq!(payloads.iter().filter(|p| p.deleted).count(), 100);
+        // Only the server cares about the sortindex, so it's left out.
+        assert!(payloads.iter().all(|p| !p.data.contains_key("sortindex")));
+    }
+
+    #[test]
+    fn test_export_skips_unreadable() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let good = db.add(LoginFixture::numbered(0).build()).unwrap();
+        // A password which isn't valid UTF-8.
+        db.execute_batch(
+            "INSERT INTO loginsL (guid, hostname, httpRealm, password, timeCreated,
+                                  timePasswordChanged, local_modified, sync_status)
+             VALUES ('broken', 'https://www.example.org', 'realm', CAST(X'C328FF' AS TEXT),
+                     1000, 1000, 1000, 2)",
+        )
+        .unwrap();
+
+        let (payloads, summary) = export(&db, EVERYTHING);
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(payloads[0].id, good.guid);
+        assert_eq!(summary.num_exported, 1);
+        assert_eq!(summary.num_skipped, 1);
+        assert_eq!(summary.errors.len(), 1);
+        assert!(summary.errors[0].starts_with("broken: "));
+    }
+
+    #[test]
+    fn test_export_requires_secrets() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        db.add(LoginFixture::numbered(0).build()).unwrap();
+        let mut out = vec![];
+        let err = db
+            .export_sync_payloads(
+                &mut out,
+                PayloadExportOptions {
+                    include_secrets: false,
+                    include_tombstones: false,
+                },
+            )
+            .unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::SecretsNotIncluded));
+        assert!(out.is_empty());
+    }
+}
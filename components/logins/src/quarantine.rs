@@ -0,0 +1,219 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Moving local records which can't be synced out of the way.
+//!
+//! A row in `loginsL` which breaks the schema's rules in ways SQLite doesn't
+//! enforce (say, a password which isn't valid UTF-8, written by a buggy
+//! migration) can't be uploaded. `fetch_outgoing` skips these, so that they
+//! don't fail every sync, and reports them in the sync's telemetry. But they
+//! stay where they are until `quarantine_invalid_local_rows` moves them to
+//! the `loginsQuarantine` table, where they're kept rather than destroyed.
+
+use crate::db::{outgoing_payload, raw_guid, LoginDb, OUTGOING_ROWS_SQL};
+use crate::error::*;
+use crate::util;
+use rusqlite::{named_params, NO_PARAMS};
+use sql_support::ConnExt;
+use std::time::SystemTime;
+use sync_guid::Guid;
+
+const QUARANTINE_ROW_SQL: &str = "
+    INSERT INTO loginsQuarantine (
+        guid, username, password, hostname, httpRealm, formSubmitURL,
+        usernameField, passwordField, timeCreated, timeLastUsed,
+        timePasswordChanged, timesUsed, local_modified, is_deleted,
+        sync_status, change_flags, quarantined_at
+    )
+    SELECT guid, username, password, hostname, httpRealm, formSubmitURL,
+           usernameField, passwordField, timeCreated, timeLastUsed,
+           timePasswordChanged, timesUsed, local_modified, is_deleted,
+           sync_status, change_flags, :now_ms
+    FROM loginsL
+    WHERE id = :id";
+
+impl LoginDb {
+    /// Move every local record which can't be uploaded into the quarantine
+    /// table, returning their guids. Records which were synced before go back
+    /// to the version on the server; others are gone as far as the rest of
+    /// the API is concerned.
+    pub fn quarantine_invalid_local_rows(&self) -> Result<Vec<Guid>> {
+        let tx = self.unchecked_transaction()?;
+        let mut invalid = vec![];
+        {
+            let mut stmt = self.prepare(&self.sql(OUTGOING_ROWS_SQL))?;
+            let mut rows = stmt.query(NO_PARAMS)?;
+            while let Some(row) = rows.next()? {
+                if let Err(e) = outgoing_payload(row, self.encdec()) {
+                    // With the wrong encryptor, every row would fail.
+                    if let ErrorKind::DecryptionFailed(_) = e.kind() {
+                        return Err(e);
+                    }
+                    let guid = raw_guid(row)?;
+                    log::warn!("Quarantining unreadable record {:?}: {}", guid, e.label());
+                    invalid.push((row.get::<_, i64>("id")?, guid));
+                }
+            }
+        }
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        for (id, _) in &invalid {
+            self.execute_named_cached(
+                &self.sql(QUARANTINE_ROW_SQL),
+                named_params! { ":id": id, ":now_ms": now_ms },
+            )?;
+            // The mirror's copy, if any, is all we have left.
+            self.execute_named_cached(
+                &self.sql(
+                    "UPDATE loginsM SET is_overridden = 0
+                     WHERE guid = (SELECT guid FROM loginsL WHERE id = :id)",
+                ),
+                named_params! { ":id": id },
+            )?;
+            self.execute_named_cached(
+                &self.sql("DELETE FROM loginsL WHERE id = :id"),
+                named_params! { ":id": id },
+            )?;
+        }
+        let guids: Vec<Guid> = invalid.into_iter().map(|(_, guid)| guid).collect();
+        self.note_changed(&guids)?;
+        tx.commit()?;
+        Ok(guids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::LoginStore;
+    use crate::testing::LoginFixture;
+    use crate::Login;
+    use sync15::{telemetry, IncomingChangeset, ServerTimestamp, SyncEngine};
+
+    fn login() -> Login {
+        LoginFixture::builder().username("user").build()
+    }
+
+    // Adds a new local record whose password isn't valid UTF-8.
+    fn insert_broken_row(db: &LoginDb, guid: &str) {
+        db.execute_named(
+            "INSERT INTO loginsL (guid, hostname, httpRealm, password, timeCreated,
+                                  timePasswordChanged, local_modified, sync_status)
+             VALUES (:guid, 'https://www.example.org', 'realm', CAST(X'C328FF' AS TEXT),
+                     1000, 1000, 1000, 2)",
+            named_params! { ":guid": guid },
+        )
+        .unwrap();
+    }
+
+    fn outgoing_guids(db: &LoginDb) -> Vec<Guid> {
+        let scope = db.begin_interrupt_scope();
+        let outgoing = db.fetch_outgoing(ServerTimestamp(0), &scope).unwrap();
+        outgoing.changes.into_iter().map(|p| p.id).collect()
+    }
+
+    #[test]
+    fn test_sync_skips_broken_rows() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let good = db.add(login()).unwrap();
+        insert_broken_row(&db, "broken");
+
+        let engine = LoginStore::new(&db);
+        let mut telem = telemetry::Engine::new("passwords");
+        let outgoing = engine
+            .apply_incoming(
+                vec![IncomingChangeset::new("passwords", ServerTimestamp(1000))],
+                &mut telem,
+            )
+            .unwrap();
+        let guids: Vec<Guid> = outgoing.changes.iter().map(|p| p.id.clone()).collect();
+        assert_eq!(guids, vec![Guid::new(&good.guid)]);
+        assert!(format!("{:?}", telem).contains("unreadableOutgoing"));
+        engine.sync_finished(ServerTimestamp(1000), guids).unwrap();
+
+        // It's still there, and still skipped, until it's quarantined.
+        assert!(outgoing_guids(&db).is_empty());
+        assert_eq!(
+            db.query_one::<i64>("SELECT COUNT(*) FROM loginsL WHERE guid = 'broken'")
+                .unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_quarantine() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let good = db.add(login()).unwrap();
+        insert_broken_row(&db, "broken");
+        let counter = db.get_change_counter().unwrap();
+
+        assert_eq!(
+            db.quarantine_invalid_local_rows().unwrap(),
+            vec![Guid::new("broken")]
+        );
+        assert_eq!(outgoing_guids(&db), vec![Guid::new(&good.guid)]);
+        assert_eq!(db.get_all().unwrap().len(), 1);
+        assert_eq!(db.get_change_counter().unwrap(), counter + 1);
+
+        // The row was kept as it was.
+        let (guid, password_bytes) = db
+            .query_row(
+                "SELECT guid, CAST(password AS BLOB) FROM loginsQuarantine",
+                NO_PARAMS,
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?)),
+            )
+            .unwrap();
+        assert_eq!(guid, "broken");
+        assert_eq!(password_bytes, vec![0xC3, 0x28, 0xFF]);
+
+        // Nothing left to do the second time.
+        assert!(db.quarantine_invalid_local_rows().unwrap().is_empty());
+        assert!(db.check_integrity().unwrap().is_empty());
+
+        db.wipe_local().unwrap();
+        assert_eq!(
+            db.query_one::<i64>("SELECT COUNT(*) FROM loginsQuarantine")
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_quarantine_restores_mirror() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let synced = db.add(login()).unwrap();
+        let engine = LoginStore::new(&db);
+        let mut telem = telemetry::Engine::new("passwords");
+        let outgoing = engine
+            .apply_incoming(
+                vec![IncomingChangeset::new("passwords", ServerTimestamp(1000))],
+                &mut telem,
+            )
+            .unwrap();
+        let guids = outgoing.changes.iter().map(|p| p.id.clone()).collect();
+        engine.sync_finished(ServerTimestamp(1000), guids).unwrap();
+
+        // Break a local change to the synced record.
+        db.update(Login {
+            password: "new-password".into(),
+            ..synced.clone()
+        })
+        .unwrap();
+        db.execute_named(
+            "UPDATE loginsL SET password = CAST(X'FF' AS TEXT) WHERE guid = :guid",
+            named_params! { ":guid": synced.guid },
+        )
+        .unwrap();
+
+        assert_eq!(
+            db.quarantine_invalid_local_rows().unwrap(),
+            vec![Guid::new(&synced.guid)]
+        );
+        assert_eq!(
+            db.get_by_id(&synced.guid).unwrap().unwrap().password,
+            "password"
+        );
+        assert!(outgoing_guids(&db).is_empty());
+        assert!(db.check_integrity().unwrap().is_empty());
+    }
+}
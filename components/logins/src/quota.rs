@@ -0,0 +1,229 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! An optional soft limit on the size of the database.
+//!
+//! On devices which are short on space, SQLite starts failing writes with
+//! `SQLITE_FULL` part way through a transaction, which consumers can't do
+//! much with. If a maximum size is set with `set_max_db_size_bytes`, writes
+//! which would likely take us over it fail up front with
+//! `ErrorKind::QuotaExceeded` instead, while reads (and deletes, which free
+//! space) keep working.
+//!
+//! Syncing isn't limited, since refusing incoming records would leave us
+//! stuck, unable even to apply the deletions which would free up space.
+
+use crate::db::LoginDb;
+use crate::error::*;
+use rusqlite::NO_PARAMS;
+use serde_derive::*;
+use sql_support::ConnExt;
+use std::time::SystemTime;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DbSizeInfo {
+    /// The size of the main database file.
+    pub main_bytes: u64,
+    /// The size of the write-ahead log, if any.
+    pub wal_bytes: u64,
+    /// Space in the main file which is unused, and which will be reused
+    /// before the file grows.
+    pub freelist_bytes: u64,
+    pub page_size: u64,
+    /// The limit set with `set_max_db_size_bytes`, if any.
+    pub max_bytes: Option<u64>,
+    /// Whether a sync has left a host with more records than
+    /// `set_max_records_per_host` allows. See the `host_cap` module.
+    pub hosts_over_record_cap: bool,
+}
+
+impl DbSizeInfo {
+    /// The space taken up on disk.
+    pub fn total_bytes(&self) -> u64 {
+        self.main_bytes + self.wal_bytes
+    }
+
+    /// The space actually holding data, which is what the quota limits.
+    pub fn used_bytes(&self) -> u64 {
+        self.total_bytes().saturating_sub(self.freelist_bytes)
+    }
+}
+
+impl LoginDb {
+    /// Set (or clear) the maximum size the database may grow to before writes
+    /// fail with `ErrorKind::QuotaExceeded`.
+    pub fn set_max_db_size_bytes(&self, max: Option<u64>) {
+        self.max_db_size.set(max);
+    }
+
+    pub fn get_db_size_info(&self) -> Result<DbSizeInfo> {
+        let page_size = self.query_one::<i64>("PRAGMA page_size")? as u64;
+        let page_count = self.query_one::<i64>("PRAGMA page_count")? as u64;
+        let freelist_count = self.query_one::<i64>("PRAGMA freelist_count")? as u64;
+        // This is empty for in-memory databases.
+        let path = self.query_row(
+            "SELECT file FROM pragma_database_list WHERE name = 'main'",
+            NO_PARAMS,
+            |row| row.get::<_, String>(0),
+        )?;
+        let wal_bytes = if path.is_empty() {
+            0
+        } else {
+            std::fs::metadata(format!("{}-wal", path)).map_or(0, |meta| meta.len())
+        };
+        Ok(DbSizeInfo {
+            main_bytes: page_count * page_size,
+            wal_bytes,
+            freelist_bytes: freelist_count * page_size,
+            page_size,
+            max_bytes: self.max_db_size.get(),
+            hosts_over_record_cap: self.hosts_over_record_cap()?,
+        })
+    }
+
+    /// Fail with `ErrorKind::QuotaExceeded` if a write would likely take the
+    /// database over its maximum size. Called before writes which may grow
+    /// the database.
+    pub(crate) fn check_quota(&self) -> Result<()> {
+        let max = match self.max_db_size.get() {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+        let info = self.get_db_size_info()?;
+        // Even a small write which can't reuse free space grows the file by
+        // at least a page.
+        let current = info.used_bytes();
+        if current + info.page_size > max {
+            log::warn!(
+                "Refusing to write, database is {} bytes of {}",
+                current,
+                max
+            );
+            throw!(ErrorKind::QuotaExceeded { current, max });
+        }
+        Ok(())
+    }
+
+    /// Housekeeping which is worth doing occasionally, such as when the app
+    /// is idle. Currently, this forgets remote deletions which are too old to
+    /// offer to restore, removes local deletions which are too old to upload
+    /// (see `set_tombstone_retention`), repairs any records whose local and
+    /// mirror rows disagree, and tries to reclaim space if the database is
+    /// over 80% of its maximum size.
+    pub fn run_maintenance(&self) -> Result<()> {
+        self.expire_recent_deletions(SystemTime::now())?;
+        self.prune_aged_out_tombstones(SystemTime::now())?;
+        self.repair_consistency()?;
+        let info = self.get_db_size_info()?;
+        if let Some(max) = info.max_bytes {
+            if info.total_bytes() > max / 5 * 4 {
+                log::info!("Database is over 80% of its maximum size, reclaiming space");
+                // `incremental_vacuum` only does anything for databases
+                // created with `auto_vacuum = INCREMENTAL`, which older
+                // databases weren't.
+                self.execute_batch("PRAGMA wal_checkpoint(TRUNCATE); PRAGMA incremental_vacuum;")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::LoginStore;
+    use crate::testing::LoginFixture;
+    use sync15::{telemetry, IncomingChangeset, Payload, ServerTimestamp, SyncEngine};
+
+    #[test]
+    fn test_size_info() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let info = db.get_db_size_info().unwrap();
+        assert!(info.main_bytes > 0);
+        assert_eq!(info.main_bytes % info.page_size, 0);
+        assert_eq!(info.wal_bytes, 0);
+        assert_eq!(info.max_bytes, None);
+
+        db.set_max_db_size_bytes(Some(1 << 20));
+        assert_eq!(db.get_db_size_info().unwrap().max_bytes, Some(1 << 20));
+        db.run_maintenance().unwrap();
+    }
+
+    #[test]
+    fn test_quota() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let info = db.get_db_size_info().unwrap();
+        let quota = info.total_bytes() + 20 * info.page_size;
+        db.set_max_db_size_bytes(Some(quota));
+
+        // Big passwords, so that each record needs a page or two.
+        let mut added = vec![];
+        let err = loop {
+            assert!(added.len() < 100, "Quota was never enforced");
+            match db.add(
+                LoginFixture::numbered(added.len())
+                    .password("x".repeat(2000))
+                    .build(),
+            ) {
+                Ok(login) => added.push(login),
+                Err(e) => break e,
+            }
+        };
+        match err.kind() {
+            ErrorKind::QuotaExceeded { max, .. } => assert_eq!(*max, quota),
+            e => panic!("Expected QuotaExceeded, got {:?}", e),
+        }
+        assert!(added.len() > 1);
+
+        // Reads and deletes still work.
+        assert_eq!(db.get_all().unwrap().len(), added.len());
+        assert!(db.delete(added[0].guid_str()).unwrap());
+        db.run_maintenance().unwrap();
+
+        // Syncing isn't limited.
+        let engine = LoginStore::new(&db);
+        let mut telem = telemetry::Engine::new("passwords");
+        let mut incoming = IncomingChangeset::new("passwords", ServerTimestamp(1000));
+        incoming.changes = vec![
+            Payload::new_tombstone(added[1].guid.clone()),
+            LoginFixture::numbered(added.len())
+                .password("x".repeat(2000))
+                .payload(),
+        ]
+        .into_iter()
+        .map(|payload| (payload, ServerTimestamp(1000)))
+        .collect();
+        engine.apply_incoming(vec![incoming], &mut telem).unwrap();
+        assert!(!db.exists(added[1].guid_str()).unwrap());
+        assert_eq!(db.get_all().unwrap().len(), added.len());
+
+        // Without a quota, we can keep going.
+        db.set_max_db_size_bytes(None);
+        db.add(
+            LoginFixture::numbered(added.len() + 1)
+                .password("x".repeat(2000))
+                .build(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_database_full() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        // SQLite reports SQLITE_FULL when it hits `max_page_count`.
+        let page_count = db.query_one::<i64>("PRAGMA page_count").unwrap();
+        db.execute_batch(&format!("PRAGMA max_page_count = {}", page_count))
+            .unwrap();
+        let err = (0..10)
+            .find_map(|n| {
+                db.add(LoginFixture::numbered(n).password("x".repeat(2000)).build())
+                    .err()
+            })
+            .unwrap();
+        match err.kind() {
+            ErrorKind::DatabaseFull(_) => {}
+            e => panic!("Expected DatabaseFull, got {:?}", e),
+        }
+    }
+}
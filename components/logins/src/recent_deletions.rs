@@ -0,0 +1,254 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Records deleted on other devices, so that the UI can say so and offer to
+//! restore them.
+//!
+//! When an incoming tombstone deletes a record the user could see, we keep
+//! everything but its password in the `loginsRecentTombstones` table (see
+//! the [schema](crate::schema) docs for its lifetime). We can't keep the
+//! password, since the whole point of deleting a login is that it's gone, so
+//! `restore_remote_deletion` needs the caller to supply one.
+
+use crate::db::LoginDb;
+use crate::error::*;
+use crate::login::Login;
+use crate::schema::TableNames;
+use crate::util;
+use rusqlite::{named_params, Connection};
+use serde_derive::*;
+use sql_support::ConnExt;
+use std::time::{Duration, SystemTime};
+use sync_guid::Guid;
+
+/// How long we remember remote deletions for.
+const MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// A record which was deleted on another device.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RemoteDeletion {
+    pub guid: String,
+    pub hostname: String,
+    pub username: String,
+    /// When the deletion was applied on this device, in milliseconds since
+    /// the epoch.
+    pub deleted_at: i64,
+}
+
+// Remember the records in `guids` which exist locally, before they're
+// deleted.
+pub(crate) fn record_remote_deletions(
+    conn: &Connection,
+    tables: &TableNames,
+    guids: &[Guid],
+) -> Result<()> {
+    let now_ms = util::system_time_ms_i64(SystemTime::now());
+    let sql = tables.resolve(
+        "
+        INSERT OR REPLACE INTO loginsRecentTombstones (
+            guid, hostname, httpRealm, formSubmitURL, username, usernameField,
+            passwordField, deleted_at, source
+        )
+        SELECT guid, hostname, httpRealm, formSubmitURL, username, usernameField,
+               passwordField, :now_ms, 'remote'
+        FROM loginsL
+        WHERE guid = :guid AND is_deleted = 0
+        UNION ALL
+        SELECT guid, hostname, httpRealm, formSubmitURL, username, usernameField,
+               passwordField, :now_ms, 'remote'
+        FROM loginsM
+        WHERE guid = :guid AND is_overridden = 0",
+    );
+    for guid in guids {
+        conn.execute_named_cached(&sql, named_params! { ":guid": guid, ":now_ms": now_ms })?;
+    }
+    Ok(())
+}
+
+impl LoginDb {
+    /// The records deleted on other devices since `since_ms` (in milliseconds
+    /// since the epoch), most recent first. Records which have since been
+    /// restored, or have come back some other way, aren't included.
+    pub fn get_recent_remote_deletions(&self, since_ms: i64) -> Result<Vec<RemoteDeletion>> {
+        let mut stmt = self.db.prepare_cached(&self.sql(
+            "SELECT guid, hostname, username, deleted_at FROM loginsRecentTombstones
+             WHERE deleted_at >= :since
+               AND guid NOT IN (SELECT guid FROM loginsL WHERE is_deleted = 0
+                                UNION
+                                SELECT guid FROM loginsM WHERE is_overridden = 0)
+             ORDER BY deleted_at DESC, guid",
+        ))?;
+        let rows = stmt.query_and_then_named(named_params! { ":since": since_ms }, |row| {
+            Ok::<_, Error>(RemoteDeletion {
+                guid: row.get("guid")?,
+                hostname: row.get("hostname")?,
+                username: row
+                    .get::<_, Option<String>>("username")?
+                    .unwrap_or_default(),
+                deleted_at: row.get("deleted_at")?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Add a record deleted on another device back, with a new guid and the
+    /// given `password`. The restored record will be uploaded on the next
+    /// sync, like any other new record. Fails with `NoSuchRecord` if there's
+    /// no remembered deletion for `guid`.
+    pub fn restore_remote_deletion(&self, guid: &str, password: &str) -> Result<Login> {
+        let deleted = self
+            .try_query_row(
+                &self.sql("SELECT * FROM loginsRecentTombstones WHERE guid = :guid"),
+                named_params! { ":guid": guid },
+                |row| {
+                    Ok::<_, Error>(Login {
+                        hostname: row.get("hostname")?,
+                        http_realm: row.get("httpRealm")?,
+                        form_submit_url: row.get("formSubmitURL")?,
+                        username: row
+                            .get::<_, Option<String>>("username")?
+                            .unwrap_or_default(),
+                        username_field: row
+                            .get::<_, Option<String>>("usernameField")?
+                            .unwrap_or_default(),
+                        password_field: row
+                            .get::<_, Option<String>>("passwordField")?
+                            .unwrap_or_default(),
+                        ..Login::default()
+                    })
+                },
+                true,
+            )?
+            .ok_or_else(|| ErrorKind::NoSuchRecord(guid.to_owned()))?;
+        let restored = self.add(Login {
+            password: password.to_owned(),
+            ..deleted
+        })?;
+        self.execute_named_cached(
+            &self.sql("DELETE FROM loginsRecentTombstones WHERE guid = :guid"),
+            named_params! { ":guid": guid },
+        )?;
+        Ok(restored)
+    }
+
+    /// Forget remote deletions which are too old to offer to restore.
+    pub(crate) fn expire_recent_deletions(&self, now: SystemTime) -> Result<()> {
+        let cutoff = util::system_time_ms_i64(now) - MAX_AGE.as_millis() as i64;
+        self.execute_named_cached(
+            &self.sql("DELETE FROM loginsRecentTombstones WHERE deleted_at < :cutoff"),
+            named_params! { ":cutoff": cutoff },
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{sync_db, LoginFixture};
+    use sync15::{Payload, ServerTimestamp};
+
+    fn login() -> Login {
+        LoginFixture::builder()
+            .username("user")
+            .username_field("user_field")
+            .build()
+    }
+
+    #[test]
+    fn test_restore_remote_deletion() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let before = util::system_time_ms_i64(SystemTime::now());
+        let synced = db.add(login()).unwrap();
+        sync_db(&db, vec![], ServerTimestamp(1000));
+
+        sync_db(
+            &db,
+            vec![Payload::new_tombstone(synced.guid.clone())],
+            ServerTimestamp(2000),
+        );
+        assert!(db.get_by_id(&synced.guid).unwrap().is_none());
+
+        let deletions = db.get_recent_remote_deletions(before).unwrap();
+        assert_eq!(deletions.len(), 1);
+        assert_eq!(deletions[0].guid, synced.guid.as_str());
+        assert_eq!(deletions[0].hostname, "https://www.example.com");
+        assert_eq!(deletions[0].username, "user");
+        assert!(deletions[0].deleted_at >= before);
+        assert!(db
+            .get_recent_remote_deletions(deletions[0].deleted_at + 1)
+            .unwrap()
+            .is_empty());
+
+        let restored = db
+            .restore_remote_deletion(&synced.guid, "new-password")
+            .unwrap();
+        assert_ne!(restored.guid, synced.guid);
+        assert_eq!(restored.password, "new-password");
+        assert_eq!(restored.username_field, "user_field");
+        assert!(db.get_recent_remote_deletions(before).unwrap().is_empty());
+        assert!(matches!(
+            db.restore_remote_deletion(&synced.guid, "new-password")
+                .unwrap_err()
+                .kind(),
+            ErrorKind::NoSuchRecord(_)
+        ));
+
+        let (outgoing, _) = sync_db(&db, vec![], ServerTimestamp(3000));
+        assert_eq!(outgoing.changes.len(), 1);
+        assert_eq!(outgoing.changes[0].id, restored.guid);
+        let uploaded: Login = outgoing.changes[0].clone().into_record().unwrap();
+        assert_eq!(uploaded.password, "new-password");
+        assert_eq!(uploaded.hostname, "https://www.example.com");
+    }
+
+    #[test]
+    fn test_only_visible_records_are_recorded() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let deleted_here = db.add(login()).unwrap();
+        sync_db(&db, vec![], ServerTimestamp(1000));
+        db.delete(&deleted_here.guid).unwrap();
+
+        // Tombstones for records we never had, and for one we'd already
+        // deleted ourselves, aren't interesting.
+        sync_db(
+            &db,
+            vec![
+                Payload::new_tombstone("never-seen"),
+                Payload::new_tombstone(deleted_here.guid.clone()),
+            ],
+            ServerTimestamp(2000),
+        );
+        assert!(db.get_recent_remote_deletions(0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_expiry() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let synced = db.add(login()).unwrap();
+        sync_db(&db, vec![], ServerTimestamp(1000));
+        sync_db(
+            &db,
+            vec![Payload::new_tombstone(synced.guid.clone())],
+            ServerTimestamp(2000),
+        );
+        assert_eq!(db.get_recent_remote_deletions(0).unwrap().len(), 1);
+
+        db.run_maintenance().unwrap();
+        assert_eq!(db.get_recent_remote_deletions(0).unwrap().len(), 1);
+
+        db.execute(
+            "UPDATE loginsRecentTombstones SET deleted_at = deleted_at - ?",
+            &[MAX_AGE.as_millis() as i64 + 1],
+        )
+        .unwrap();
+        db.run_maintenance().unwrap();
+        assert!(db.get_recent_remote_deletions(0).unwrap().is_empty());
+        assert_eq!(
+            db.query_one::<i64>("SELECT COUNT(*) FROM loginsRecentTombstones")
+                .unwrap(),
+            0
+        );
+    }
+}
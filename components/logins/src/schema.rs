@@ -2,15 +2,22 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-//! Logins Schema v4
-//! ================
+//! Logins Schema v12
+//! =================
 //!
 //! The schema we use is a evolution of the firefox-ios logins database format.
-//! There are three tables:
+//! There are nine tables:
 //!
 //! - `loginsL`: The local table.
 //! - `loginsM`: The mirror table.
 //! - `loginsSyncMeta`: The table used to to store various sync metadata.
+//! - `loginsLocalMeta`: Per-record annotations which are never synced.
+//! - `loginsChangeLog`: Tracks which records changed when, for embedders
+//!   which keep their own copy of the data.
+//! - `loginsDisabledHosts`: Sites we should never offer to save logins for.
+//! - `loginsQuarantine`: Local records which were too broken to sync.
+//! - `loginsRecentTombstones`: Records recently deleted on other devices.
+//! - `loginsPendingUpload`: The records being uploaded by the current sync.
 //!
 //! ## `loginsL`
 //!
@@ -34,7 +41,9 @@
 //!   was changed locally, or NULL if the record has never been changed locally.
 //!
 //! - `is_deleted`: A boolean indicating whether or not this record is a
-//!   tombstone.
+//!   tombstone. Tombstones without a mirror row are removed once their
+//!   `local_modified` is older than the retention window set with
+//!   `LoginDb::set_tombstone_retention`.
 //!
 //! - `sync_status`: A `SyncStatus` enum value, one of
 //!
@@ -45,6 +54,15 @@
 //!
 //!     - `2` (`SyncStatus::New`): Indicating that the record has never been
 //!       synced, or we have been reset since the last time it synced.
+//!       Tombstones are never new, since they're always a change to upload;
+//!       `repair_consistency` removes any which are.
+//!
+//! - `change_flags`: Which groups of fields have changed locally since the
+//!   record was last synced, as a combination of the `change_flags` constants
+//!   (`FIELDS` for anything but usage metadata, and `USAGE` for `timesUsed`
+//!   and `timeLastUsed`). This lets merging tell a record which was only
+//!   used apart from one which was edited. Added in version 8; records which
+//!   had already changed were marked as having changed both.
 //!
 //! ## `loginsM`
 //!
@@ -72,13 +90,18 @@
 //! - `is_overridden`: A boolean indicating whether or not the mirror contents
 //!   are invalid, and that we should defer to the data stored in `loginsL`.
 //!
+//! - `unknown_fields`: The fields of the server's record which we don't
+//!   know about, as a JSON object, or NULL if there weren't any. They're
+//!   added back to the record when we upload it, so that we don't drop the
+//!   fields newer clients add. Added in version 11.
+//!
 //! ## `loginsSyncMeta`
 //!
 //! This is a simple key-value table based on the `moz_meta` table in places.
 //! This table was added (by this rust crate) in version 4, and so is not
 //! present in firefox-ios.
 //!
-//! Currently it is used to store two items:
+//! Currently it is used to store these items:
 //!
 //! 1. The last sync timestamp is stored under [LAST_SYNC_META_KEY], a
 //!    `sync15::ServerTimestamp` stored in integer milliseconds.
@@ -87,47 +110,427 @@
 //!    [GLOBAL_STATE_META_KEY]. This is a `sync15::GlobalState` stored as
 //!    JSON.
 //!
+//! 3. While a sync is in progress, the records it touched, and how many of
+//!    the incoming records it's applied so far, are stored under
+//!    [SYNC_IN_PROGRESS_META_KEY], so that the next sync can recover (or
+//!    carry on) if this one is interrupted.
+//!
+//! 4. Once the passwords in `loginsL` and `loginsM` have been encrypted by
+//!    an [EncryptorDecryptor](crate::EncryptorDecryptor), so that the
+//!    database can only be opened with one, [PASSWORDS_ENCRYPTED_META_KEY]
+//!    is set to 1. Like the change counter, this survives `wipe_local`.
+//!
+//! 5. Whether syncing passwords has been turned off on this device is stored
+//!    under [LOCAL_ENGINE_ENABLED_META_KEY], and whether meta/global
+//!    declined the engine as of the last sync under
+//!    [DECLINED_REMOTELY_META_KEY], both as booleans. Neither is set until
+//!    it's first known. See `LoginDb::set_local_engine_enabled`.
+//!
+//! 6. For databases opened with `LoginDb::attach_to_connection` and a table
+//!    prefix, the schema version is stored under [SCHEMA_VERSION_META_KEY],
+//!    since `PRAGMA user_version` belongs to the embedder.
+//!
+//! 7. Whether we've been disconnected from the sync account with
+//!    `on_disconnect` is stored under [LIFECYCLE_STATE_META_KEY], as
+//!    "connected" or "disconnected". It isn't set until we first disconnect,
+//!    and survives `wipe_local`. See the `lifecycle` module.
+//!
+//! 8. Once a sync has left a host with more records than it's allowed,
+//!    [HOSTS_OVER_RECORD_CAP_META_KEY] is set to 1. See the `host_cap`
+//!    module.
+//!
+//! ## `loginsLocalMeta`
+//!
+//! A key-value table of annotations attached to individual records, keyed by
+//! `(guid, key)`. This was added in version 5.
+//!
+//! Annotations are local-only state: they are never uploaded, and survive
+//! merges and resets since neither changes a record's guid. They're removed
+//! along with their record when it's deleted, whether locally or by an
+//! incoming tombstone, and by wipes.
+//!
+//! ## `loginsChangeLog`
+//!
+//! Maps the guid of every record that's ever been changed (by the local
+//! APIs or by sync) to the value of the change counter when it was last
+//! changed. The counter itself is stored in `loginsSyncMeta` under
+//! [CHANGE_COUNTER_META_KEY], and is bumped once per mutating transaction.
+//! It never goes backwards, even over `wipe_local`.
+//!
+//! This is a separate table rather than a column on `loginsL` and `loginsM`
+//! since records move between those tables, and are removed from both when
+//! deleted, but embedders still need to hear about the deletion. This was
+//! added in version 6.
+//!
+//! ## `loginsDisabledHosts`
+//!
+//! The hosts the user has asked us to never save logins for, stored as
+//! normalized hosts (without scheme or port). This was added in version 7.
+//!
+//! Like `loginsLocalMeta`, this is local-only: it's never synced, since
+//! desktop keeps this list in a separate store. For the same reason it
+//! survives `wipe` (which is driven by sync), but is cleared by `wipe_local`.
+//!
+//! ## `loginsQuarantine`
+//!
+//! Rows moved out of `loginsL` by `quarantine_invalid_local_rows`, because
+//! they couldn't be read (for example, text which isn't valid UTF-8). This
+//! was added in version 9.
+//!
+//! It has the same columns as `loginsL` (except `id`), plus the time the row
+//! was moved, in milliseconds. The columns have no types or constraints, so
+//! that any row can be moved here as it was. Nothing reads this table; it's
+//! kept so that the records aren't lost outright, and is cleared by
+//! `wipe_local`.
+//!
+//! ## `loginsRecentTombstones`
+//!
+//! The records which incoming tombstones deleted, minus their passwords, so
+//! that the UI can offer to restore them with `restore_remote_deletion`.
+//! This was added in version 10.
+//!
+//! Only records which the user could see when the tombstone arrived are
+//! recorded. Entries are removed when they're restored, by
+//! `run_maintenance` once they're a week old, and by `wipe_local`.
+//!
+//! ## `loginsPendingUpload`
+//!
+//! A copy of each `loginsL` row a sync is uploading, as it was when we read
+//! it, along with its `loginsChangeLog` counter at the time (0 if it had
+//! none). This was added in version 12.
+//!
+//! When the upload succeeds, a row whose counter has moved on was changed
+//! while we were uploading it, so rather than moving the local row to the
+//! mirror, we put this copy there, and leave the local row to be uploaded
+//! next time. The table is emptied when the sync finishes, and by `reset`
+//! and `wipe_local`.
+//!
+//! ## Table name prefixes
+//!
+//! `LoginDb::attach_to_connection` lets embedders keep these tables in a
+//! database they share with other components, with a prefix on each table
+//! (and index) name so that they can't collide. So that every statement
+//! uses the same names, all our SQL is written with the names above and
+//! passed through [TableNames::resolve] before it's run.
+//!
 
+use crate::encryption::{self, EncryptorDecryptor};
 use crate::error::*;
+use crate::login::{change_flags, Login, SyncStatus};
 use lazy_static::lazy_static;
-use rusqlite::Connection;
+use rusqlite::{named_params, types::ToSql, Connection, Row};
 use sql_support::ConnExt;
+use std::borrow::Cow;
 
-/// Note that firefox-ios is currently on version 3. Version 4 is this version,
-/// which adds a metadata table and changes timestamps to be in milliseconds
-pub const VERSION: i64 = 4;
-
-/// Every column shared by both tables except for `id`
-///
-/// Note: `timeCreated`, `timeLastUsed`, and `timePasswordChanged` are in
-/// milliseconds. This is in line with how the server and Desktop handle it, but
-/// counter to how firefox-ios handles it (hence needing to fix them up
-/// firefox-ios on schema upgrade from 3, the last firefox-ios password schema
-/// version).
-///
-/// The reason for breaking from how firefox-ios does things is just because it
-/// complicates the code to have multiple kinds of timestamps, for very little
-/// benefit. It also makes it unclear what's stored on the server, leading to
-/// further confusion.
-///
-/// However, note that the `local_modified` (of `loginsL`) and `server_modified`
-/// (of `loginsM`) are stored as milliseconds as well both on firefox-ios and
-/// here (and so they do not need to be updated with the `timeLastUsed`/
-/// `timePasswordChanged`/`timeCreated` timestamps.
-pub const COMMON_COLS: &str = "
-    guid,
-    username,
-    password,
-    hostname,
-    httpRealm,
-    formSubmitURL,
-    usernameField,
-    passwordField,
-    timeCreated,
-    timeLastUsed,
-    timePasswordChanged,
-    timesUsed
-";
+/// Note that firefox-ios is currently on version 3. Version 4 adds a metadata
+/// table and changes timestamps to be in milliseconds. Version 5 adds the
+/// local annotations table, version 6 the change log, version 7 the
+/// disabled hosts table, version 8 `loginsL.change_flags`, version 9 the
+/// quarantine table, version 10 the recent tombstones table, version 11
+/// `loginsM.unknown_fields`, version 12 the pending upload table, and
+/// version 13 stops marking tombstones as new.
+pub const VERSION: i64 = 13;
+
+/// The names of every table and index we create, which `TableNames`
+/// prefixes.
+const OBJECT_NAMES: &[&str] = &[
+    "loginsL",
+    "loginsM",
+    "loginsSyncMeta",
+    "loginsLocalMeta",
+    "loginsChangeLog",
+    "loginsDisabledHosts",
+    "loginsQuarantine",
+    "loginsRecentTombstones",
+    "loginsPendingUpload",
+    "idx_loginsChangeLog_change_counter",
+    "idx_loginsM_is_overridden_hostname",
+    "idx_loginsL_is_deleted_hostname",
+];
+
+/// Resolves the table and index names in our SQL to the ones in the
+/// database. These are the names in `OBJECT_NAMES`, with the prefix given to
+/// `LoginDb::attach_to_connection`, if any.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct TableNames {
+    prefix: String,
+}
+
+impl TableNames {
+    /// Fails with `InvalidTablePrefix` unless `prefix` is letters, digits and
+    /// underscores, since it ends up in SQL.
+    pub fn with_prefix(prefix: &str) -> Result<Self> {
+        if prefix.is_empty() || !prefix.chars().all(is_identifier_char) {
+            throw!(ErrorKind::InvalidTablePrefix(prefix.to_owned()));
+        }
+        Ok(Self {
+            prefix: prefix.to_owned(),
+        })
+    }
+
+    pub fn is_prefixed(&self) -> bool {
+        !self.prefix.is_empty()
+    }
+
+    /// `sql`, with each of our table and index names replaced by the one in
+    /// the database. Only whole identifiers are replaced, so columns and
+    /// other tables which happen to contain one of our names are left alone.
+    pub fn resolve<'a>(&self, sql: &'a str) -> Cow<'a, str> {
+        if !self.is_prefixed() {
+            return Cow::Borrowed(sql);
+        }
+        let mut resolved = String::with_capacity(sql.len() + self.prefix.len() * 4);
+        let mut rest = sql;
+        while !rest.is_empty() {
+            let word_len = rest
+                .find(|c: char| !is_identifier_char(c))
+                .unwrap_or(rest.len());
+            let (word, after) = rest.split_at(word_len);
+            if OBJECT_NAMES.contains(&word) {
+                resolved.push_str(&self.prefix);
+            }
+            resolved.push_str(word);
+            let gap_len = after.find(is_identifier_char).unwrap_or(after.len());
+            resolved.push_str(&after[..gap_len]);
+            rest = &after[gap_len..];
+        }
+        Cow::Owned(resolved)
+    }
+
+    /// Like `ConnExt::execute_all`, but resolving each statement first.
+    pub fn execute_all(&self, conn: &Connection, stmts: &[&str]) -> Result<()> {
+        for sql in stmts {
+            conn.execute_all(&[&self.resolve(sql)])?;
+        }
+        Ok(())
+    }
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// How the statements which update records treat a common column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ColumnKind {
+    /// `guid`, which identifies the record, so is never updated.
+    Guid,
+    /// Part of the login itself, always overwritten.
+    Field,
+    /// Usage metadata and timestamps. Updates from the server keep the
+    /// current value if the incoming one is 0, to avoid zeroes if the remote
+    /// has been overwritten by an older client.
+    Usage,
+    /// `timeCreated`, which is like `Usage`, except that reconciled local
+    /// records keep their own.
+    Created,
+}
+
+/// A column shared by both tables, other than `id`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Column {
+    pub name: &'static str,
+    /// The named parameter it's bound to: `:` and the name of its `Login`
+    /// field.
+    pub param: &'static str,
+    pub kind: ColumnKind,
+}
+
+/// The statements which write every common column of a record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Write {
+    /// Inserting a new local or mirror record.
+    Insert,
+    /// Updating a local record with the result of a merge.
+    LocalUpdate,
+    /// Updating a mirror record with an incoming one.
+    MirrorUpdate,
+}
+
+impl Column {
+    /// Whether `write` binds this column's parameter.
+    pub fn bound_by(&self, write: Write) -> bool {
+        !(write == Write::LocalUpdate && self.kind == ColumnKind::Created)
+    }
+
+    // This column's entry in the `SET` clause of `write`, if it has one.
+    fn assignment(&self, write: Write) -> Option<String> {
+        match (write, self.kind) {
+            (Write::Insert, _) | (_, ColumnKind::Guid) => None,
+            (Write::LocalUpdate, ColumnKind::Created) => None,
+            (Write::MirrorUpdate, ColumnKind::Usage)
+            | (Write::MirrorUpdate, ColumnKind::Created) => Some(format!(
+                "{name} = coalesce(nullif({param}, 0), {name})",
+                name = self.name,
+                param = self.param
+            )),
+            _ => Some(format!("{} = {}", self.name, self.param)),
+        }
+    }
+}
+
+/// The assignments for the `SET` clause of `write`, which must be an update.
+/// Records are updated by guid, so `:guid` is bound but not assigned.
+pub(crate) fn update_assignments(write: Write) -> String {
+    assert_ne!(write, Write::Insert, "Inserts don't have a SET clause");
+    COLUMNS
+        .iter()
+        .filter_map(|column| column.assignment(write))
+        .collect::<Vec<_>>()
+        .join(",\n    ")
+}
+
+/// A login's values for the common columns, with its password encrypted the
+/// way it's stored.
+pub(crate) struct LoginParams<'a> {
+    login: &'a Login,
+    password: String,
+}
+
+impl<'a> LoginParams<'a> {
+    pub fn new(login: &'a Login, encdec: &dyn EncryptorDecryptor) -> Self {
+        Self {
+            login,
+            password: encryption::encrypt_password(encdec, &login.password),
+        }
+    }
+
+    /// The named parameters `write` binds, followed by `extra`.
+    pub fn bind<'p>(
+        &'p self,
+        write: Write,
+        extra: &[(&'p str, &'p dyn ToSql)],
+    ) -> Vec<(&'p str, &'p dyn ToSql)> {
+        COLUMNS
+            .iter()
+            .zip(self.values())
+            .filter(|(column, _)| column.bound_by(write))
+            .map(|(column, value)| (column.param, value))
+            .chain(extra.iter().copied())
+            .collect()
+    }
+}
+
+// How `login_from_row` reads each column.
+mod read {
+    use crate::encryption::{self, EncryptorDecryptor};
+    use crate::error::*;
+    use rusqlite::{types::FromSql, Row};
+
+    pub fn get<T: FromSql>(row: &Row<'_>, _: &dyn EncryptorDecryptor, name: &str) -> Result<T> {
+        Ok(row.get(name)?)
+    }
+
+    // For columns which might be NULL, which we treat as empty.
+    pub fn get_or_default<T: FromSql + Default>(
+        row: &Row<'_>,
+        _: &dyn EncryptorDecryptor,
+        name: &str,
+    ) -> Result<T> {
+        Ok(row.get::<_, Option<T>>(name)?.unwrap_or_default())
+    }
+
+    pub fn decrypt(row: &Row<'_>, encdec: &dyn EncryptorDecryptor, name: &str) -> Result<String> {
+        encryption::decrypt_password(encdec, &row.get::<_, String>(name)?)
+    }
+}
+
+// `"\n    a,\n    b\n"`, with each item after `prefix`.
+macro_rules! sql_list {
+    ($prefix:literal; $first:expr $(, $rest:expr)*) => {
+        concat!("\n    ", $prefix, $first, $(",\n    ", $prefix, $rest,)* "\n")
+    };
+}
+
+// A `LoginParams`' value for a column. Passwords are bound encrypted.
+macro_rules! column_value {
+    (decrypt, $params:expr, $field:ident) => {
+        &$params.password as &dyn ToSql
+    };
+    ($read:ident, $params:expr, $field:ident) => {
+        &$params.login.$field as &dyn ToSql
+    };
+}
+
+// Generates everything which lists the common columns, from a list of
+// `field => "column" (kind, read)`, where `field` is the `Login` field it's
+// stored from, `kind` is its `ColumnKind`, and `read` is the function in
+// `read` which reads it. The doc comments are for `COMMON_COLS`.
+macro_rules! common_columns {
+    (
+        $(#[$attr:meta])*
+        $($field:ident => $name:literal ($kind:ident, $read:ident),)+
+    ) => {
+        $(#[$attr])*
+        pub const COMMON_COLS: &str = sql_list!(""; $($name),+);
+
+        /// The named parameters for `COMMON_COLS`, in the same order.
+        pub(crate) const COMMON_PARAMS: &str = sql_list!(":"; $(stringify!($field)),+);
+
+        /// The common columns, in the same order as `COMMON_COLS`.
+        pub(crate) const COLUMNS: &[Column] = &[$(
+            Column {
+                name: $name,
+                param: concat!(":", stringify!($field)),
+                kind: ColumnKind::$kind,
+            },
+        )+];
+
+        /// Reads the common columns of a row into a `Login`. Rows which came
+        /// from our tables should use `Login::from_row`, which also fixes
+        /// them up.
+        pub(crate) fn login_from_row(
+            row: &Row<'_>,
+            encdec: &dyn EncryptorDecryptor,
+        ) -> Result<Login> {
+            Ok(Login {
+                $($field: read::$read(row, encdec, $name)?,)+
+            })
+        }
+
+        impl<'a> LoginParams<'a> {
+            // The value for each column in `COLUMNS`.
+            fn values(&self) -> Vec<&dyn ToSql> {
+                vec![$(column_value!($read, self, $field)),+]
+            }
+        }
+    };
+}
+
+// The columns shared by `loginsL` and `loginsM`. Adding one here adds it to
+// every statement which reads or writes records, but the tables themselves
+// also need it in `COMMON_SQL`, and a migration.
+common_columns! {
+    /// Every column shared by both tables except for `id`
+    ///
+    /// Note: `timeCreated`, `timeLastUsed`, and `timePasswordChanged` are in
+    /// milliseconds. This is in line with how the server and Desktop handle it, but
+    /// counter to how firefox-ios handles it (hence needing to fix them up
+    /// firefox-ios on schema upgrade from 3, the last firefox-ios password schema
+    /// version).
+    ///
+    /// The reason for breaking from how firefox-ios does things is just because it
+    /// complicates the code to have multiple kinds of timestamps, for very little
+    /// benefit. It also makes it unclear what's stored on the server, leading to
+    /// further confusion.
+    ///
+    /// However, note that the `local_modified` (of `loginsL`) and `server_modified`
+    /// (of `loginsM`) are stored as milliseconds as well both on firefox-ios and
+    /// here (and so they do not need to be updated with the `timeLastUsed`/
+    /// `timePasswordChanged`/`timeCreated` timestamps.
+    guid                  => "guid"                (Guid,    get),
+    username              => "username"            (Field,   get_or_default),
+    password              => "password"            (Field,   decrypt),
+    hostname              => "hostname"            (Field,   get),
+    http_realm            => "httpRealm"           (Field,   get),
+    form_submit_url       => "formSubmitURL"       (Field,   get),
+    username_field        => "usernameField"       (Field,   get_or_default),
+    password_field        => "passwordField"       (Field,   get_or_default),
+    time_created          => "timeCreated"         (Created, get),
+    time_last_used        => "timeLastUsed"        (Usage,   get_or_default),
+    time_password_changed => "timePasswordChanged" (Usage,   get),
+    times_used            => "timesUsed"           (Usage,   get),
+}
 
 const COMMON_SQL: &str = "
     id                  INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -154,7 +557,8 @@ lazy_static! {
             local_modified INTEGER,
 
             is_deleted     TINYINT NOT NULL DEFAULT 0,
-            sync_status    TINYINT NOT NULL DEFAULT 0
+            sync_status    TINYINT NOT NULL DEFAULT 0,
+            change_flags   TINYINT NOT NULL DEFAULT 0
         )",
         common_sql = COMMON_SQL
     );
@@ -164,12 +568,12 @@ lazy_static! {
             -- Milliseconds (a sync15::ServerTimestamp multiplied by
             -- 1000 and truncated)
             server_modified INTEGER NOT NULL,
-            is_overridden   TINYINT NOT NULL DEFAULT 0
+            is_overridden   TINYINT NOT NULL DEFAULT 0,
+            -- JSON, or NULL
+            unknown_fields  TEXT
         )",
         common_sql = COMMON_SQL
     );
-    static ref SET_VERSION_SQL: String =
-        format!("PRAGMA user_version = {version}", version = VERSION);
 }
 
 const CREATE_META_TABLE_SQL: &str = "
@@ -179,6 +583,82 @@ const CREATE_META_TABLE_SQL: &str = "
     )
 ";
 
+const CREATE_LOCAL_META_TABLE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS loginsLocalMeta (
+        guid  TEXT NOT NULL,
+        key   TEXT NOT NULL,
+        value TEXT NOT NULL,
+        PRIMARY KEY (guid, key)
+    )
+";
+
+const CREATE_CHANGE_LOG_TABLE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS loginsChangeLog (
+        guid           TEXT PRIMARY KEY,
+        change_counter INTEGER NOT NULL
+    )
+";
+
+const CREATE_DISABLED_HOSTS_TABLE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS loginsDisabledHosts (
+        hostname TEXT PRIMARY KEY
+    )
+";
+
+const CREATE_QUARANTINE_TABLE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS loginsQuarantine (
+        guid,
+        username,
+        password,
+        hostname,
+        httpRealm,
+        formSubmitURL,
+        usernameField,
+        passwordField,
+        timeCreated,
+        timeLastUsed,
+        timePasswordChanged,
+        timesUsed,
+        local_modified,
+        is_deleted,
+        sync_status,
+        change_flags,
+        quarantined_at INTEGER NOT NULL
+    )
+";
+
+const CREATE_RECENT_TOMBSTONES_TABLE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS loginsRecentTombstones (
+        guid          TEXT PRIMARY KEY,
+        hostname      TEXT NOT NULL,
+        httpRealm     TEXT,
+        formSubmitURL TEXT,
+        username      TEXT,
+        usernameField TEXT,
+        passwordField TEXT,
+        -- Milliseconds, when the deletion was applied here.
+        deleted_at    INTEGER NOT NULL,
+        -- Always 'remote', for now.
+        source        TEXT NOT NULL
+    )
+";
+
+lazy_static! {
+    static ref CREATE_PENDING_UPLOAD_TABLE_SQL: String = format!(
+        "CREATE TABLE IF NOT EXISTS loginsPendingUpload (
+            {common_sql},
+            is_deleted     TINYINT NOT NULL,
+            change_counter INTEGER NOT NULL
+        )",
+        common_sql = COMMON_SQL
+    );
+}
+
+const CREATE_CHANGE_COUNTER_INDEX_SQL: &str = "
+    CREATE INDEX IF NOT EXISTS idx_loginsChangeLog_change_counter
+    ON loginsChangeLog (change_counter)
+";
+
 const CREATE_OVERRIDE_HOSTNAME_INDEX_SQL: &str = "
     CREATE INDEX IF NOT EXISTS idx_loginsM_is_overridden_hostname
     ON loginsM (is_overridden, hostname)
@@ -209,9 +689,59 @@ pub(crate) static LAST_SYNC_META_KEY: &str = "last_sync_time";
 pub(crate) static GLOBAL_STATE_META_KEY: &str = "global_state_v2";
 pub(crate) static GLOBAL_SYNCID_META_KEY: &str = "global_sync_id";
 pub(crate) static COLLECTION_SYNCID_META_KEY: &str = "passwords_sync_id";
+pub(crate) static CHANGE_COUNTER_META_KEY: &str = "change_counter";
+pub(crate) static SYNC_IN_PROGRESS_META_KEY: &str = "sync_in_progress";
+pub(crate) static PASSWORDS_ENCRYPTED_META_KEY: &str = "passwords_encrypted";
+pub(crate) static LOCAL_ENGINE_ENABLED_META_KEY: &str = "local_engine_enabled";
+pub(crate) static DECLINED_REMOTELY_META_KEY: &str = "declined_remotely";
+pub(crate) static SCHEMA_VERSION_META_KEY: &str = "schema_version";
+pub(crate) static LIFECYCLE_STATE_META_KEY: &str = "lifecycle_state";
+pub(crate) static HOSTS_OVER_RECORD_CAP_META_KEY: &str = "hosts_over_record_cap";
+
+// The schema version, which is `PRAGMA user_version` unless our tables are
+// prefixed, in which case it's in `loginsSyncMeta`, or 0 if that doesn't
+// exist yet.
+fn get_version(db: &Connection, tables: &TableNames) -> Result<i64> {
+    if !tables.is_prefixed() {
+        return Ok(db.query_one::<i64>("PRAGMA user_version")?);
+    }
+    let meta_table = tables.resolve("loginsSyncMeta").into_owned();
+    let meta_exists = db.query_row_named(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = :name)",
+        named_params! { ":name": meta_table },
+        |row| row.get::<_, bool>(0),
+    )?;
+    if !meta_exists {
+        return Ok(0);
+    }
+    Ok(db
+        .try_query_row(
+            &tables.resolve("SELECT value FROM loginsSyncMeta WHERE key = :key"),
+            named_params! { ":key": SCHEMA_VERSION_META_KEY },
+            |row| Ok::<_, Error>(row.get::<_, i64>(0)?),
+            false,
+        )?
+        .unwrap_or(0))
+}
+
+fn set_version(db: &Connection, tables: &TableNames, version: i64) -> Result<()> {
+    if tables.is_prefixed() {
+        db.execute_named(
+            &tables.resolve("REPLACE INTO loginsSyncMeta (key, value) VALUES (:key, :value)"),
+            named_params! { ":key": SCHEMA_VERSION_META_KEY, ":value": version },
+        )?;
+    } else {
+        db.execute_batch(&format!("PRAGMA user_version = {}", version))?;
+    }
+    Ok(())
+}
 
-pub(crate) fn init(db: &Connection) -> Result<()> {
-    let user_version = db.query_one::<i64>("PRAGMA user_version")?;
+pub(crate) fn init(db: &Connection, tables: &TableNames) -> Result<()> {
+    let user_version = get_version(db, tables)?;
+    if user_version == 0 && tables.is_prefixed() {
+        // There's no firefox-ios schema to replace, as below.
+        return create(db, tables);
+    }
     if user_version == 0 {
         // This logic is largely taken from firefox-ios. AFAICT at some point
         // they went from having schema versions tracked using a table named
@@ -232,13 +762,13 @@ pub(crate) fn init(db: &Connection) -> Result<()> {
         )? != 0;
 
         if table_list_exists {
-            drop(db)?;
+            drop(db, tables)?;
         }
-        return create(db);
+        return create(db, tables);
     }
     if user_version != VERSION {
         if user_version < VERSION {
-            upgrade(db, user_version)?;
+            upgrade(db, tables, user_version)?;
         } else {
             log::warn!(
                 "Loaded future schema version {} (we only understand version {}). \
@@ -252,7 +782,7 @@ pub(crate) fn init(db: &Connection) -> Result<()> {
 }
 
 // https://github.com/mozilla-mobile/firefox-ios/blob/master/Storage/SQL/LoginsSchema.swift#L100
-fn upgrade(db: &Connection, from: i64) -> Result<()> {
+fn upgrade(db: &Connection, tables: &TableNames, from: i64) -> Result<()> {
     log::debug!("Upgrading schema from {} to {}", from, VERSION);
     if from == VERSION {
         return Ok(());
@@ -263,46 +793,506 @@ fn upgrade(db: &Connection, from: i64) -> Result<()> {
     );
     if from < 3 {
         // These indices were added in v3 (apparently)
-        db.execute_all(&[
-            CREATE_OVERRIDE_HOSTNAME_INDEX_SQL,
-            CREATE_DELETED_HOSTNAME_INDEX_SQL,
-        ])?;
+        tables.execute_all(
+            db,
+            &[
+                CREATE_OVERRIDE_HOSTNAME_INDEX_SQL,
+                CREATE_DELETED_HOSTNAME_INDEX_SQL,
+            ],
+        )?;
     }
     if from < 4 {
         // This is the update from the firefox-ios schema to our schema.
         // The `loginsSyncMeta` table was added in v4, and we moved
         // from using microseconds to milliseconds for `timeCreated`,
         // `timeLastUsed`, and `timePasswordChanged`.
-        db.execute_all(&[
-            CREATE_META_TABLE_SQL,
-            UPDATE_LOCAL_TIMESTAMPS_TO_MILLIS_SQL,
-            UPDATE_MIRROR_TIMESTAMPS_TO_MILLIS_SQL,
-            &*SET_VERSION_SQL,
-        ])?;
+        tables.execute_all(
+            db,
+            &[
+                CREATE_META_TABLE_SQL,
+                UPDATE_LOCAL_TIMESTAMPS_TO_MILLIS_SQL,
+                UPDATE_MIRROR_TIMESTAMPS_TO_MILLIS_SQL,
+            ],
+        )?;
     }
-    Ok(())
+    if from < 5 {
+        tables.execute_all(db, &[CREATE_LOCAL_META_TABLE_SQL])?;
+    }
+    if from < 6 {
+        // Existing records are never reported as changed, since embedders
+        // will need to fetch everything to start with anyway.
+        tables.execute_all(
+            db,
+            &[CREATE_CHANGE_LOG_TABLE_SQL, CREATE_CHANGE_COUNTER_INDEX_SQL],
+        )?;
+    }
+    if from < 7 {
+        tables.execute_all(db, &[CREATE_DISABLED_HOSTS_TABLE_SQL])?;
+    }
+    if from < 8 {
+        // We can't tell what changed in records which already had, so assume
+        // everything did, as merging did before.
+        tables.execute_all(
+            db,
+            &[
+                "ALTER TABLE loginsL ADD COLUMN change_flags TINYINT NOT NULL DEFAULT 0",
+                &format!(
+                    "UPDATE loginsL SET change_flags = {all} WHERE sync_status <> 0",
+                    all = change_flags::FIELDS | change_flags::USAGE
+                ),
+            ],
+        )?;
+    }
+    if from < 9 {
+        tables.execute_all(db, &[CREATE_QUARANTINE_TABLE_SQL])?;
+    }
+    if from < 10 {
+        tables.execute_all(db, &[CREATE_RECENT_TOMBSTONES_TABLE_SQL])?;
+    }
+    if from < 11 {
+        // Records already in the mirror will get theirs the next time they
+        // change on the server.
+        tables.execute_all(db, &["ALTER TABLE loginsM ADD COLUMN unknown_fields TEXT"])?;
+    }
+    if from < 12 {
+        tables.execute_all(db, &[&*CREATE_PENDING_UPLOAD_TABLE_SQL])?;
+    }
+    if from < 13 {
+        // `reset` used to mark tombstones as new, which `repair_consistency`
+        // would now remove before they're uploaded.
+        tables.execute_all(
+            db,
+            &[&format!(
+                "UPDATE loginsL SET sync_status = {changed}
+                 WHERE is_deleted = 1 AND sync_status = {new}",
+                changed = SyncStatus::Changed as u8,
+                new = SyncStatus::New as u8
+            )],
+        )?;
+    }
+    set_version(db, tables, VERSION)
 }
 
-pub(crate) fn create(db: &Connection) -> Result<()> {
+pub(crate) fn create(db: &Connection, tables: &TableNames) -> Result<()> {
     log::debug!("Creating schema");
-    db.execute_all(&[
-        &*CREATE_LOCAL_TABLE_SQL,
-        &*CREATE_MIRROR_TABLE_SQL,
-        CREATE_OVERRIDE_HOSTNAME_INDEX_SQL,
-        CREATE_DELETED_HOSTNAME_INDEX_SQL,
-        CREATE_META_TABLE_SQL,
-        &*SET_VERSION_SQL,
-    ])?;
-    Ok(())
+    tables.execute_all(
+        db,
+        &[
+            &*CREATE_LOCAL_TABLE_SQL,
+            &*CREATE_MIRROR_TABLE_SQL,
+            CREATE_OVERRIDE_HOSTNAME_INDEX_SQL,
+            CREATE_DELETED_HOSTNAME_INDEX_SQL,
+            CREATE_META_TABLE_SQL,
+            CREATE_LOCAL_META_TABLE_SQL,
+            CREATE_CHANGE_LOG_TABLE_SQL,
+            CREATE_CHANGE_COUNTER_INDEX_SQL,
+            CREATE_DISABLED_HOSTS_TABLE_SQL,
+            CREATE_QUARANTINE_TABLE_SQL,
+            CREATE_RECENT_TOMBSTONES_TABLE_SQL,
+            &*CREATE_PENDING_UPLOAD_TABLE_SQL,
+        ],
+    )?;
+    set_version(db, tables, VERSION)
 }
 
-pub(crate) fn drop(db: &Connection) -> Result<()> {
+pub(crate) fn drop(db: &Connection, tables: &TableNames) -> Result<()> {
     log::debug!("Dropping schema");
-    db.execute_all(&[
-        "DROP TABLE IF EXISTS loginsM",
-        "DROP TABLE IF EXISTS loginsL",
-        "DROP TABLE IF EXISTS loginsSyncMeta",
-        "PRAGMA user_version = 0",
-    ])?;
+    tables.execute_all(
+        db,
+        &[
+            "DROP TABLE IF EXISTS loginsM",
+            "DROP TABLE IF EXISTS loginsL",
+            "DROP TABLE IF EXISTS loginsSyncMeta",
+            "DROP TABLE IF EXISTS loginsLocalMeta",
+            "DROP TABLE IF EXISTS loginsChangeLog",
+            "DROP TABLE IF EXISTS loginsDisabledHosts",
+            "DROP TABLE IF EXISTS loginsQuarantine",
+            "DROP TABLE IF EXISTS loginsRecentTombstones",
+            "DROP TABLE IF EXISTS loginsPendingUpload",
+        ],
+    )?;
+    // With a prefix, the version went with `loginsSyncMeta`.
+    if !tables.is_prefixed() {
+        set_version(db, tables, 0)?;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::test_utils::TestEncryptor;
+    use rusqlite::{named_params, NO_PARAMS};
+
+    // These are what the statements which read and write records used when
+    // they listed the columns by hand. Changing them changes those statements.
+    #[test]
+    fn test_generated_sql() {
+        assert_eq!(
+            COMMON_COLS,
+            "
+    guid,
+    username,
+    password,
+    hostname,
+    httpRealm,
+    formSubmitURL,
+    usernameField,
+    passwordField,
+    timeCreated,
+    timeLastUsed,
+    timePasswordChanged,
+    timesUsed
+"
+        );
+        assert_eq!(
+            COMMON_PARAMS,
+            "
+    :guid,
+    :username,
+    :password,
+    :hostname,
+    :http_realm,
+    :form_submit_url,
+    :username_field,
+    :password_field,
+    :time_created,
+    :time_last_used,
+    :time_password_changed,
+    :times_used
+"
+        );
+        assert_eq!(
+            update_assignments(Write::MirrorUpdate),
+            "username = :username,
+    password = :password,
+    hostname = :hostname,
+    httpRealm = :http_realm,
+    formSubmitURL = :form_submit_url,
+    usernameField = :username_field,
+    passwordField = :password_field,
+    timeCreated = coalesce(nullif(:time_created, 0), timeCreated),
+    timeLastUsed = coalesce(nullif(:time_last_used, 0), timeLastUsed),
+    timePasswordChanged = coalesce(nullif(:time_password_changed, 0), timePasswordChanged),
+    timesUsed = coalesce(nullif(:times_used, 0), timesUsed)"
+        );
+        assert_eq!(
+            update_assignments(Write::LocalUpdate),
+            "username = :username,
+    password = :password,
+    hostname = :hostname,
+    httpRealm = :http_realm,
+    formSubmitURL = :form_submit_url,
+    usernameField = :username_field,
+    passwordField = :password_field,
+    timeLastUsed = :time_last_used,
+    timePasswordChanged = :time_password_changed,
+    timesUsed = :times_used"
+        );
+    }
+
+    #[test]
+    fn test_params_round_trip() {
+        let conn = Connection::open_in_memory().unwrap();
+        create(&conn, &TableNames::default()).unwrap();
+        let encdec = TestEncryptor::new("key");
+        let login = Login {
+            guid: "dummy_000001".into(),
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("https://www.example.com".into()),
+            username: "user".into(),
+            password: "password".into(),
+            username_field: "user_input".into(),
+            password_field: "pass_input".into(),
+            time_created: 1000,
+            time_last_used: 2000,
+            time_password_changed: 3000,
+            times_used: 4,
+            ..Login::default()
+        };
+        let read = |table: &str| {
+            conn.query_row(
+                &format!("SELECT {} FROM {}", COMMON_COLS, table),
+                rusqlite::NO_PARAMS,
+                |row| Ok(login_from_row(row, &encdec)),
+            )
+            .unwrap()
+            .unwrap()
+        };
+
+        conn.execute_named(
+            &format!(
+                "INSERT INTO loginsM ({}, server_modified) VALUES ({}, 1000)",
+                COMMON_COLS, COMMON_PARAMS
+            ),
+            &LoginParams::new(&login, &encdec).bind(Write::Insert, &[]),
+        )
+        .unwrap();
+        assert_eq!(read("loginsM"), login);
+        let stored: String = conn
+            .query_row("SELECT password FROM loginsM", rusqlite::NO_PARAMS, |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_ne!(stored, "password");
+
+        // Usage and timestamps which are 0 on the server are ignored.
+        let incoming = Login {
+            password: "new-password".into(),
+            time_created: 0,
+            time_last_used: 0,
+            time_password_changed: 5000,
+            times_used: 0,
+            ..login.clone()
+        };
+        conn.execute_named(
+            &format!(
+                "UPDATE loginsM SET server_modified = :server_modified, {} WHERE guid = :guid",
+                update_assignments(Write::MirrorUpdate)
+            ),
+            &LoginParams::new(&incoming, &encdec).bind(
+                Write::MirrorUpdate,
+                named_params! { ":server_modified": 2000 },
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            read("loginsM"),
+            Login {
+                password: "new-password".into(),
+                time_password_changed: 5000,
+                ..login.clone()
+            }
+        );
+
+        // Local updates keep the record's own `timeCreated`.
+        conn.execute_named(
+            &format!(
+                "INSERT INTO loginsL ({}) VALUES ({})",
+                COMMON_COLS, COMMON_PARAMS
+            ),
+            &LoginParams::new(&login, &encdec).bind(Write::Insert, &[]),
+        )
+        .unwrap();
+        conn.execute_named(
+            &format!(
+                "UPDATE loginsL SET {} WHERE guid = :guid",
+                update_assignments(Write::LocalUpdate)
+            ),
+            &LoginParams::new(&incoming, &encdec).bind(Write::LocalUpdate, &[]),
+        )
+        .unwrap();
+        assert_eq!(
+            read("loginsL"),
+            Login {
+                time_created: 1000,
+                ..incoming
+            }
+        );
+    }
+
+    #[test]
+    fn test_resolve_table_names() {
+        let tables = TableNames::with_prefix("ext_").unwrap();
+        assert_eq!(
+            tables.resolve(
+                "SELECT l.guid, loginsM.hostname FROM loginsL l
+                 JOIN loginsM ON loginsM.guid = l.guid
+                 WHERE l.guid IN (SELECT guid FROM loginsLocalMeta)"
+            ),
+            "SELECT l.guid, ext_loginsM.hostname FROM ext_loginsL l
+                 JOIN ext_loginsM ON ext_loginsM.guid = l.guid
+                 WHERE l.guid IN (SELECT guid FROM ext_loginsLocalMeta)"
+        );
+        // Only whole names are prefixed.
+        assert_eq!(
+            tables.resolve("SELECT loginsLx, xloginsL FROM my_loginsL"),
+            "SELECT loginsLx, xloginsL FROM my_loginsL"
+        );
+        assert_eq!(
+            tables.resolve(CREATE_CHANGE_COUNTER_INDEX_SQL),
+            CREATE_CHANGE_COUNTER_INDEX_SQL
+                .replace("idx_", "ext_idx_")
+                .replace("ON loginsChangeLog", "ON ext_loginsChangeLog")
+        );
+        // Without a prefix, nothing changes.
+        assert!(matches!(
+            TableNames::default().resolve("SELECT * FROM loginsL"),
+            Cow::Borrowed("SELECT * FROM loginsL")
+        ));
+    }
+
+    // The schema as of version 4, which every existing database has, as
+    // `create` made it then.
+    const V4_SCHEMA_SQL: &str = "
+        CREATE TABLE loginsL (
+            id                  INTEGER PRIMARY KEY AUTOINCREMENT,
+            hostname            TEXT NOT NULL,
+            httpRealm           TEXT,
+            formSubmitURL       TEXT,
+            usernameField       TEXT,
+            passwordField       TEXT,
+            timesUsed           INTEGER NOT NULL DEFAULT 0,
+            timeCreated         INTEGER NOT NULL,
+            timeLastUsed        INTEGER,
+            timePasswordChanged INTEGER NOT NULL,
+            username            TEXT,
+            password            TEXT NOT NULL,
+            guid                TEXT NOT NULL UNIQUE,
+            local_modified      INTEGER,
+            is_deleted          TINYINT NOT NULL DEFAULT 0,
+            sync_status         TINYINT NOT NULL DEFAULT 0
+        );
+        CREATE TABLE loginsM (
+            id                  INTEGER PRIMARY KEY AUTOINCREMENT,
+            hostname            TEXT NOT NULL,
+            httpRealm           TEXT,
+            formSubmitURL       TEXT,
+            usernameField       TEXT,
+            passwordField       TEXT,
+            timesUsed           INTEGER NOT NULL DEFAULT 0,
+            timeCreated         INTEGER NOT NULL,
+            timeLastUsed        INTEGER,
+            timePasswordChanged INTEGER NOT NULL,
+            username            TEXT,
+            password            TEXT NOT NULL,
+            guid                TEXT NOT NULL UNIQUE,
+            server_modified     INTEGER NOT NULL,
+            is_overridden       TINYINT NOT NULL DEFAULT 0
+        );
+        CREATE INDEX idx_loginsM_is_overridden_hostname ON loginsM (is_overridden, hostname);
+        CREATE INDEX idx_loginsL_is_deleted_hostname ON loginsL (is_deleted, hostname);
+        CREATE TABLE loginsSyncMeta (
+            key TEXT PRIMARY KEY,
+            value NOT NULL
+        );
+        PRAGMA user_version = 4;
+    ";
+
+    #[test]
+    fn test_upgrade_from_v4() {
+        let dir = tempdir::TempDir::new("upgrade_from_v4").unwrap();
+        let path = dir.path().join("logins.sqlite");
+        {
+            let db = Connection::open(&path).unwrap();
+            db.execute_batch(V4_SCHEMA_SQL).unwrap();
+            // One record only on the server, one changed locally since it
+            // was synced, one which has never been synced, one with a local
+            // row which is the same as the server's, and a deletion which
+            // hasn't been uploaded since a reset.
+            db.execute_batch(
+                "INSERT INTO loginsM (guid, hostname, formSubmitURL, username, password,
+                                      timeCreated, timeLastUsed, timePasswordChanged,
+                                      timesUsed, server_modified, is_overridden)
+                 VALUES ('remote_0001', 'https://remote.example.com', 'https://remote.example.com',
+                         'remote', 'remote-pw', 1000, 2000, 1000, 2, 3000, 0),
+                        ('changed_001', 'https://changed.example.com', 'https://changed.example.com',
+                         'changed', 'old-pw', 1000, 2000, 1000, 1, 3000, 1),
+                        ('synced_0001', 'https://synced.example.com', 'https://synced.example.com',
+                         'synced', 'synced-pw', 1000, 2000, 1000, 1, 3000, 1);
+                 INSERT INTO loginsL (guid, hostname, formSubmitURL, username, password,
+                                      timeCreated, timeLastUsed, timePasswordChanged,
+                                      timesUsed, local_modified, is_deleted, sync_status)
+                 VALUES ('changed_001', 'https://changed.example.com', 'https://changed.example.com',
+                         'changed', 'new-pw', 1000, 4000, 4000, 2, 4000, 0, 1),
+                        ('new_0000001', 'https://new.example.com', 'https://new.example.com',
+                         'new', 'new-record-pw', 5000, 5000, 5000, 1, 5000, 0, 2),
+                        ('synced_0001', 'https://synced.example.com', 'https://synced.example.com',
+                         'synced', 'synced-pw', 1000, 2000, 1000, 1, 3000, 0, 0),
+                        ('reset_00001', '', NULL, '', '', 1000, 2000, 1000, 1, 3000, 1, 2);
+                 INSERT INTO loginsSyncMeta (key, value) VALUES ('last_sync_time', 3000);",
+            )
+            .unwrap();
+        }
+
+        let db = crate::db::LoginDb::open(&path, None).unwrap();
+        assert_eq!(db.query_one::<i64>("PRAGMA user_version").unwrap(), VERSION);
+
+        // Only records with local changes to upload are assumed to have
+        // changed everything.
+        let mut stmt = db
+            .prepare("SELECT guid, change_flags FROM loginsL ORDER BY guid")
+            .unwrap();
+        let flags = stmt
+            .query_map(NO_PARAMS, |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, u8>(1)?))
+            })
+            .unwrap()
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .unwrap();
+        let all = change_flags::FIELDS | change_flags::USAGE;
+        assert_eq!(
+            flags,
+            vec![
+                ("changed_001".to_string(), all),
+                ("new_0000001".to_string(), all),
+                ("reset_00001".to_string(), all),
+                ("synced_0001".to_string(), 0),
+            ]
+        );
+        // The deletion is still there to upload.
+        assert_eq!(
+            db.query_one::<i64>("SELECT sync_status FROM loginsL WHERE guid = 'reset_00001'")
+                .unwrap(),
+            SyncStatus::Changed as i64
+        );
+
+        for table in &[
+            "loginsLocalMeta",
+            "loginsChangeLog",
+            "loginsDisabledHosts",
+            "loginsQuarantine",
+            "loginsRecentTombstones",
+            "loginsPendingUpload",
+        ] {
+            assert_eq!(
+                db.query_one::<i64>(&format!("SELECT COUNT(*) FROM {}", table))
+                    .unwrap(),
+                0,
+                "{}",
+                table
+            );
+        }
+        assert_eq!(
+            db.query_one::<i64>("SELECT COUNT(*) FROM loginsM WHERE unknown_fields IS NULL")
+                .unwrap(),
+            3
+        );
+
+        // The records, and what we knew about syncing them, survived.
+        let mut logins = db.get_all().unwrap();
+        logins.sort_by(|a, b| a.guid.cmp(&b.guid));
+        let summary = logins
+            .iter()
+            .map(|login| (login.guid_str(), login.password.as_str(), login.times_used))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                ("changed_001", "new-pw", 2),
+                ("new_0000001", "new-record-pw", 1),
+                ("remote_0001", "remote-pw", 2),
+                ("synced_0001", "synced-pw", 1),
+            ]
+        );
+        assert_eq!(logins[0].time_password_changed, 4000);
+        assert_eq!(
+            db.query_one::<i64>("SELECT value FROM loginsSyncMeta WHERE key = 'last_sync_time'")
+                .unwrap(),
+            3000
+        );
+
+        // And it can be opened again without upgrading twice.
+        drop(db);
+        crate::db::LoginDb::open(&path, None).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_table_prefix() {
+        for prefix in &["", "ext-", "ext_; DROP TABLE x; --", "ext "] {
+            let err = TableNames::with_prefix(prefix).unwrap_err();
+            assert!(matches!(err.kind(), ErrorKind::InvalidTablePrefix(_)));
+        }
+        assert!(TableNames::with_prefix("Ext_2").is_ok());
+    }
+}
@@ -1,15 +1,38 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
-use crate::db::{LoginDb, LoginStore, MigrationMetrics};
+use crate::backup::{ExportSummary, ImportMetrics, ImportMode};
+use crate::changes::ChangesSince;
+use crate::consistency::RepairReport;
+use crate::db::{LoginDb, LoginStore, MigrationMetrics, COLLECTION_NAME};
+use crate::debug_info::RecordDebugInfo;
+use crate::encryption::EncryptorDecryptor;
 use crate::error::*;
+use crate::guid_adoption::AdoptReport;
+use crate::health::{HealthSummary, PasswordHealth};
+use crate::lifecycle::{DisconnectPolicy, FinalUpload, LifecycleState};
 use crate::login::Login;
+use crate::migrate::LegacyImportReport;
+use crate::op_stats::{DebugOptions, OpStats};
+use crate::open::{HealthStatus, RetryConfig};
+use crate::payload_export::{PayloadExportOptions, PayloadExportSummary};
+use crate::quota::DbSizeInfo;
+use crate::recent_deletions::RemoteDeletion;
+use crate::summaries::{HostnameSummary, HostnameSummaryOrder};
+use crate::update_fields::LoginChanges;
+use crate::update_plan::TombstonePolicy;
+use rusqlite::Connection;
 use std::cell::Cell;
+use std::collections::HashMap;
+use std::io::{Read, Write};
 use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 use sync15::{
-    sync_multiple, telemetry, EngineSyncAssociation, KeyBundle, MemoryCachedState,
-    Sync15StorageClientInit,
+    sync_multiple, telemetry, EngineSyncAssociation, KeyBundle, MemoryCachedState, Payload,
+    ServerTimestamp, Sync15StorageClientInit,
 };
+use sync_guid::Guid;
 
 // This store is a bundle of state to manage the login DB and to help the
 // SyncEngine.
@@ -20,7 +43,7 @@ pub struct PasswordStore {
 
 impl PasswordStore {
     pub fn new(path: impl AsRef<Path>, encryption_key: Option<&str>) -> Result<Self> {
-        let db = LoginDb::open(path, encryption_key)?;
+        let db = LoginDb::open_with_retry(path, encryption_key, RetryConfig::default())?;
         Ok(Self {
             db,
             mem_cached_state: Cell::default(),
@@ -35,6 +58,19 @@ impl PasswordStore {
         })
     }
 
+    /// See `LoginDb::open_with_encryptor`.
+    pub fn new_with_encryptor(
+        path: impl AsRef<Path>,
+        encryption_key: Option<&str>,
+        encdec: Arc<dyn EncryptorDecryptor>,
+    ) -> Result<Self> {
+        let db = LoginDb::open_with_encryptor(path, encryption_key, encdec)?;
+        Ok(Self {
+            db,
+            mem_cached_state: Cell::default(),
+        })
+    }
+
     pub fn new_in_memory(encryption_key: Option<&str>) -> Result<Self> {
         let db = LoginDb::open_in_memory(encryption_key)?;
         Ok(Self {
@@ -43,6 +79,15 @@ impl PasswordStore {
         })
     }
 
+    /// See `LoginDb::attach_to_connection`.
+    pub fn attach_to_connection(db: Connection, table_prefix: Option<&str>) -> Result<Self> {
+        let db = LoginDb::attach_to_connection(db, table_prefix)?;
+        Ok(Self {
+            db,
+            mem_cached_state: Cell::default(),
+        })
+    }
+
     pub fn list(&self) -> Result<Vec<Login>> {
         self.db.get_all()
     }
@@ -55,6 +100,29 @@ impl PasswordStore {
         self.db.get_by_base_domain(base_domain)
     }
 
+    pub fn get_by_hostnames(&self, hostnames: &[&str]) -> Result<HashMap<String, Vec<Login>>> {
+        self.db.get_by_hostnames(hostnames)
+    }
+
+    pub fn get_for_site(
+        &self,
+        origin: &str,
+        form_action_origin: Option<&str>,
+    ) -> Result<Vec<Login>> {
+        self.db.get_for_site(origin, form_action_origin)
+    }
+
+    pub fn propose_guid_adoptions(
+        &self,
+        incoming: &[(Payload, ServerTimestamp)],
+    ) -> Result<Vec<(Guid, Guid)>> {
+        self.db.propose_guid_adoptions(incoming)
+    }
+
+    pub fn adopt_remote_guids(&self, mappings: &[(Guid, Guid)]) -> Result<AdoptReport> {
+        self.db.adopt_remote_guids(mappings)
+    }
+
     pub fn potential_dupes_ignoring_username(&self, login: Login) -> Result<Vec<Login>> {
         self.db.potential_dupes_ignoring_username(&login)
     }
@@ -63,30 +131,77 @@ impl PasswordStore {
         self.db.touch(id)
     }
 
+    pub fn touch_multiple(&self, ids: &[&str]) -> Result<()> {
+        self.db.touch_multiple(ids)
+    }
+
+    pub fn queue_touch(&self, id: &str) {
+        self.db.queue_touch(id)
+    }
+
+    pub fn flush_touches(&self) -> Result<()> {
+        self.db.flush_touches()
+    }
+
     pub fn delete(&self, id: &str) -> Result<bool> {
         self.db.delete(id)
     }
 
+    /// Deprecated: use `on_disconnect` to sign out. After that, this
+    /// behaves like `wipe_local`, since the tombstones it would leave could
+    /// never be uploaded.
     pub fn wipe(&self) -> Result<()> {
+        if self.db.get_lifecycle_state()? == LifecycleState::Disconnected {
+            log::warn!("`wipe` after `on_disconnect` is deprecated; wiping local data only");
+            self.db.wipe_local()?;
+            return Ok(());
+        }
+        log::warn!("`wipe` is deprecated; use `on_disconnect` to sign out");
         let scope = self.db.begin_interrupt_scope();
         self.db.wipe(&scope)?;
         Ok(())
     }
 
+    /// Deprecated: use `on_disconnect` with `DeleteLocalData`. Unlike that,
+    /// this doesn't change the lifecycle state.
     pub fn wipe_local(&self) -> Result<()> {
+        log::warn!("`wipe_local` is deprecated; use `on_disconnect` to sign out");
         self.db.wipe_local()?;
         Ok(())
     }
 
+    /// Deprecated: use `on_disconnect` with `KeepLocalData`. Unlike that,
+    /// this doesn't change the lifecycle state.
     pub fn reset(&self) -> Result<()> {
+        log::warn!("`reset` is deprecated; use `on_disconnect` to sign out");
         self.db.reset(&EngineSyncAssociation::Disconnected)?;
         Ok(())
     }
 
+    pub fn on_disconnect(
+        &self,
+        policy: DisconnectPolicy,
+        final_upload: Option<&mut FinalUpload<'_>>,
+    ) -> Result<()> {
+        self.db.on_disconnect(policy, final_upload)
+    }
+
+    pub fn on_connect(&self) -> Result<()> {
+        self.db.on_connect()
+    }
+
+    pub fn get_lifecycle_state(&self) -> Result<LifecycleState> {
+        self.db.get_lifecycle_state()
+    }
+
     pub fn update(&self, login: Login) -> Result<()> {
         self.db.update(login)
     }
 
+    pub fn update_fields(&self, guid: &str, changes: LoginChanges) -> Result<Login> {
+        self.db.update_fields(guid, changes)
+    }
+
     pub fn add(&self, login: Login) -> Result<String> {
         // Just return the record's ID (which we may have generated).
         self.db.add(login).map(|record| record.guid.into_string())
@@ -96,6 +211,163 @@ impl PasswordStore {
         self.db.import_multiple(logins)
     }
 
+    pub fn export_to_backup(&self, writer: impl Write, passphrase: &str) -> Result<ExportSummary> {
+        self.db.export_to_backup(writer, passphrase)
+    }
+
+    pub fn export_sync_payloads(
+        &self,
+        writer: impl Write,
+        options: PayloadExportOptions,
+    ) -> Result<PayloadExportSummary> {
+        self.db.export_sync_payloads(writer, options)
+    }
+
+    pub fn import_from_backup(
+        &self,
+        reader: impl Read,
+        passphrase: &str,
+        mode: ImportMode,
+    ) -> Result<ImportMetrics> {
+        self.db.import_from_backup(reader, passphrase, mode)
+    }
+
+    pub fn get_change_counter(&self) -> Result<i64> {
+        self.db.get_change_counter()
+    }
+
+    pub fn get_modified_since(&self, counter: i64) -> Result<ChangesSince> {
+        self.db.get_modified_since(counter)
+    }
+
+    pub fn set_local_annotation(&self, guid: &str, key: &str, value: &str) -> Result<()> {
+        self.db.set_local_annotation(guid, key, value)
+    }
+
+    pub fn get_local_annotations(&self, guid: &str) -> Result<HashMap<String, String>> {
+        self.db.get_local_annotations(guid)
+    }
+
+    pub fn get_guids_with_annotation(&self, key: &str, value: &str) -> Result<Vec<String>> {
+        self.db.get_guids_with_annotation(key, value)
+    }
+
+    pub fn mark_breached(&self, guid: &str, breach_time: i64) -> Result<()> {
+        self.db.mark_breached(guid, breach_time)
+    }
+
+    pub fn get_breached(&self) -> Result<Vec<Login>> {
+        self.db.get_breached()
+    }
+
+    pub fn get_hostname_summaries(
+        &self,
+        order: HostnameSummaryOrder,
+        limit: Option<usize>,
+    ) -> Result<Vec<HostnameSummary>> {
+        self.db.get_hostname_summaries(order, limit)
+    }
+
+    pub fn get_record_debug_info(
+        &self,
+        guid: &str,
+        include_secrets: bool,
+    ) -> Result<Option<RecordDebugInfo>> {
+        self.db.get_record_debug_info(guid, include_secrets)
+    }
+
+    pub fn get_password_health(&self) -> Result<Vec<PasswordHealth>> {
+        self.db.get_password_health()
+    }
+
+    pub fn get_password_health_summary(&self) -> Result<HealthSummary> {
+        self.db.get_password_health_summary()
+    }
+
+    pub fn set_save_disabled(&self, hostname: &str, disabled: bool) -> Result<()> {
+        self.db.set_save_disabled(hostname, disabled)
+    }
+
+    pub fn is_save_disabled(&self, hostname: &str) -> Result<bool> {
+        self.db.is_save_disabled(hostname)
+    }
+
+    pub fn get_disabled_hostnames(&self) -> Result<Vec<String>> {
+        self.db.get_disabled_hostnames()
+    }
+
+    pub fn set_local_engine_enabled(&self, enabled: bool) -> Result<()> {
+        self.db.set_local_engine_enabled(enabled)
+    }
+
+    pub fn get_local_engine_enabled(&self) -> Result<bool> {
+        self.db.get_local_engine_enabled()
+    }
+
+    pub fn get_declined_remotely(&self) -> Result<bool> {
+        self.db.get_declined_remotely()
+    }
+
+    pub fn set_scrub_mirror_on_delete(&self, scrub: bool) {
+        self.db.set_scrub_mirror_on_delete(scrub)
+    }
+
+    pub fn set_tombstone_policy(&self, policy: TombstonePolicy) {
+        self.db.set_tombstone_policy(policy)
+    }
+
+    pub fn set_tombstone_retention(&self, retention: Duration) {
+        self.db.set_tombstone_retention(retention)
+    }
+
+    pub fn set_max_db_size_bytes(&self, max: Option<u64>) {
+        self.db.set_max_db_size_bytes(max)
+    }
+
+    pub fn set_max_records_per_host(&self, max: Option<usize>) {
+        self.db.set_max_records_per_host(max)
+    }
+
+    pub fn get_db_size_info(&self) -> Result<DbSizeInfo> {
+        self.db.get_db_size_info()
+    }
+
+    pub fn set_debug_options(&mut self, options: DebugOptions) {
+        self.db.set_debug_options(options)
+    }
+
+    pub fn get_recent_op_stats(&self) -> Vec<OpStats> {
+        self.db.get_recent_op_stats()
+    }
+
+    pub fn import_legacy_sync_metadata(&self, json: &str) -> Result<LegacyImportReport> {
+        self.db.import_legacy_sync_metadata(json)
+    }
+
+    pub fn health_check(&self) -> Result<HealthStatus> {
+        self.db.health_check()
+    }
+
+    pub fn run_maintenance(&self) -> Result<()> {
+        self.db.run_maintenance()
+    }
+
+    pub fn repair_consistency(&self) -> Result<RepairReport> {
+        self.db.repair_consistency()
+    }
+
+    pub fn quarantine_invalid_local_rows(&self) -> Result<Vec<Guid>> {
+        self.db.quarantine_invalid_local_rows()
+    }
+
+    pub fn get_recent_remote_deletions(&self, since_ms: i64) -> Result<Vec<RemoteDeletion>> {
+        self.db.get_recent_remote_deletions(since_ms)
+    }
+
+    pub fn restore_remote_deletion(&self, guid: &str, password: &str) -> Result<Login> {
+        self.db.restore_remote_deletion(guid, password)
+    }
+
     pub fn disable_mem_security(&self) -> Result<()> {
         self.db.disable_mem_security()
     }
@@ -120,6 +392,7 @@ impl PasswordStore {
         storage_init: &Sync15StorageClientInit,
         root_sync_key: &KeyBundle,
     ) -> Result<telemetry::SyncTelemetryPing> {
+        self.db.check_can_sync()?;
         // migrate our V1 state - this needn't live for long.
         self.db.migrate_global_state()?;
 
@@ -139,6 +412,11 @@ impl PasswordStore {
         // We always update the state - sync_multiple does the right thing
         // if it needs to be dropped (ie, they will be None or contain Nones etc)
         self.db.set_global_state(&disk_cached_state)?;
+        // This is None if we didn't get as far as reading meta/global.
+        if let Some(declined) = &result.declined {
+            self.db
+                .set_declined_remotely(declined.iter().any(|name| name == COLLECTION_NAME))?;
+        }
 
         // for b/w compat reasons, we do some dances with the result.
         // XXX - note that this means telemetry isn't going to be reported back
@@ -147,7 +425,7 @@ impl PasswordStore {
         if let Err(e) = result.result {
             return Err(e.into());
         }
-        match result.engine_results.remove("passwords") {
+        match result.engine_results.remove(COLLECTION_NAME) {
             None | Some(Ok(())) => Ok(result.telemetry),
             Some(Err(e)) => Err(e.into()),
         }
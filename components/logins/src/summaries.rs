@@ -0,0 +1,239 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Per-site usage statistics, for a "most used sites" view.
+//!
+//! Records are grouped by host, normalized the same way as the "never save"
+//! list, so `https://www.example.com` and `http://www.example.com:8080`
+//! count towards the same site.
+
+use crate::annotations::BREACHED_ANNOTATION_KEY;
+use crate::db::LoginDb;
+use crate::disabled_hosts::normalize_hostname;
+use crate::error::*;
+use rusqlite::named_params;
+use serde_derive::*;
+use std::collections::BTreeMap;
+
+/// What `get_hostname_summaries` sorts by. Ties are broken by hostname.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HostnameSummaryOrder {
+    /// The most total uses (`timesUsed`) first.
+    MostUsed,
+    /// The most recently used first.
+    MostRecent,
+}
+
+/// The records saved for a host.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HostnameSummary {
+    pub hostname: String,
+    /// How many records there are for the host.
+    pub count: u64,
+    /// The latest `timeLastUsed` of those records.
+    pub time_last_used: i64,
+    /// The sum of their `timesUsed`.
+    pub times_used: i64,
+    /// Whether any of them has been flagged with `mark_breached`.
+    pub has_breached: bool,
+}
+
+// Every live record once: the local version if there is one, otherwise the
+// mirror's, grouped by (unnormalized) hostname.
+const HOSTNAME_SUMMARIES_SQL: &str = "
+    WITH live AS (
+        SELECT guid, hostname, timeLastUsed, timesUsed
+        FROM loginsL
+        WHERE is_deleted = 0
+        UNION ALL
+        SELECT guid, hostname, timeLastUsed, timesUsed
+        FROM loginsM
+        WHERE is_overridden = 0
+          AND guid NOT IN (SELECT guid FROM loginsL)
+    )
+    SELECT hostname,
+           COUNT(*) AS count,
+           MAX(IFNULL(timeLastUsed, 0)) AS time_last_used,
+           SUM(timesUsed) AS times_used,
+           MAX(guid IN (SELECT guid FROM loginsLocalMeta WHERE key = :breached)) AS has_breached
+    FROM live
+    GROUP BY hostname";
+
+impl LoginDb {
+    /// A summary of the records for each host, sorted by `order`, and
+    /// truncated to `limit` hosts if one is given.
+    pub fn get_hostname_summaries(
+        &self,
+        order: HostnameSummaryOrder,
+        limit: Option<usize>,
+    ) -> Result<Vec<HostnameSummary>> {
+        let mut stmt = self.db.prepare_cached(&self.sql(HOSTNAME_SUMMARIES_SQL))?;
+        let rows = stmt.query_and_then_named(
+            named_params! { ":breached": BREACHED_ANNOTATION_KEY },
+            |row| {
+                Ok::<_, Error>(HostnameSummary {
+                    hostname: row.get("hostname")?,
+                    count: row.get::<_, i64>("count")? as u64,
+                    time_last_used: row.get("time_last_used")?,
+                    times_used: row.get("times_used")?,
+                    has_breached: row.get("has_breached")?,
+                })
+            },
+        )?;
+        // Hostnames which normalize to the same host are combined. Ones which
+        // can't be normalized are kept as they are.
+        let mut by_host: BTreeMap<String, HostnameSummary> = BTreeMap::new();
+        for row in rows {
+            let row = row?;
+            let host = normalize_hostname(&row.hostname).unwrap_or_else(|_| row.hostname.clone());
+            match by_host.get_mut(&host) {
+                Some(summary) => {
+                    summary.count += row.count;
+                    summary.time_last_used = summary.time_last_used.max(row.time_last_used);
+                    summary.times_used += row.times_used;
+                    summary.has_breached |= row.has_breached;
+                }
+                None => {
+                    by_host.insert(
+                        host.clone(),
+                        HostnameSummary {
+                            hostname: host,
+                            ..row
+                        },
+                    );
+                }
+            }
+        }
+        // Already sorted by hostname, which breaks ties since the sort is
+        // stable.
+        let mut summaries: Vec<HostnameSummary> = by_host.into_iter().map(|(_, s)| s).collect();
+        match order {
+            HostnameSummaryOrder::MostUsed => {
+                summaries.sort_by(|a, b| b.times_used.cmp(&a.times_used))
+            }
+            HostnameSummaryOrder::MostRecent => {
+                summaries.sort_by(|a, b| b.time_last_used.cmp(&a.time_last_used))
+            }
+        }
+        if let Some(limit) = limit {
+            summaries.truncate(limit);
+        }
+        Ok(summaries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::login::Login;
+    use crate::testing::{sync_db, LoginFixture};
+    use sql_support::ConnExt;
+    use sync15::ServerTimestamp;
+
+    fn login(hostname: &str, username: &str) -> Login {
+        LoginFixture::builder()
+            .hostname(hostname)
+            .form_submit_url(hostname)
+            .username(username)
+            .build()
+    }
+
+    fn set_usage(db: &LoginDb, table: &str, guid: &str, times_used: i64, time_last_used: i64) {
+        db.execute_named(
+            &format!(
+                "UPDATE {} SET timesUsed = :times_used, timeLastUsed = :time_last_used
+                 WHERE guid = :guid",
+                table
+            ),
+            named_params! {
+                ":times_used": times_used,
+                ":time_last_used": time_last_used,
+                ":guid": guid,
+            },
+        )
+        .unwrap();
+    }
+
+    fn summary(
+        hostname: &str,
+        count: u64,
+        time_last_used: i64,
+        times_used: i64,
+        has_breached: bool,
+    ) -> HostnameSummary {
+        HostnameSummary {
+            hostname: hostname.into(),
+            count,
+            time_last_used,
+            times_used,
+            has_breached,
+        }
+    }
+
+    #[test]
+    fn test_summaries() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let a = db.add(login("https://www.example.com", "a")).unwrap();
+        let b = db.add(login("http://www.example.com:8080", "b")).unwrap();
+        let c = db.add(login("https://www.example.org", "c")).unwrap();
+        set_usage(&db, "loginsL", &a.guid, 3, 100);
+        set_usage(&db, "loginsL", &b.guid, 1, 300);
+        set_usage(&db, "loginsL", &c.guid, 5, 200);
+        db.mark_breached(&b.guid, 1000).unwrap();
+
+        assert_eq!(
+            db.get_hostname_summaries(HostnameSummaryOrder::MostUsed, None)
+                .unwrap(),
+            vec![
+                summary("www.example.org", 1, 200, 5, false),
+                summary("www.example.com", 2, 300, 4, true),
+            ]
+        );
+        assert_eq!(
+            db.get_hostname_summaries(HostnameSummaryOrder::MostRecent, Some(1))
+                .unwrap(),
+            vec![summary("www.example.com", 2, 300, 4, true)]
+        );
+
+        db.delete(&c.guid).unwrap();
+        assert_eq!(
+            db.get_hostname_summaries(HostnameSummaryOrder::MostUsed, Some(10))
+                .unwrap(),
+            vec![summary("www.example.com", 2, 300, 4, true)]
+        );
+    }
+
+    #[test]
+    fn test_summaries_prefer_local_rows() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let synced = db.add(login("https://www.example.com", "a")).unwrap();
+        let mirror_only = db.add(login("https://www.example.com", "b")).unwrap();
+        sync_db(&db, vec![], ServerTimestamp(1000));
+        set_usage(&db, "loginsM", &synced.guid, 10, 1000);
+        set_usage(&db, "loginsM", &mirror_only.guid, 1, 100);
+
+        // A local change to one of them. Even if the mirror's copy isn't
+        // marked as overridden, only the local one counts.
+        db.touch(&synced.guid).unwrap();
+        set_usage(&db, "loginsL", &synced.guid, 2, 500);
+        db.execute("UPDATE loginsM SET is_overridden = 0", rusqlite::NO_PARAMS)
+            .unwrap();
+
+        assert_eq!(
+            db.get_hostname_summaries(HostnameSummaryOrder::MostUsed, None)
+                .unwrap(),
+            vec![summary("www.example.com", 2, 500, 3, false)]
+        );
+
+        // A local deletion hides the mirror's copy too.
+        db.delete(&synced.guid).unwrap();
+        db.execute("UPDATE loginsM SET is_overridden = 0", rusqlite::NO_PARAMS)
+            .unwrap();
+        assert_eq!(
+            db.get_hostname_summaries(HostnameSummaryOrder::MostUsed, None)
+                .unwrap(),
+            vec![summary("www.example.com", 1, 100, 1, false)]
+        );
+    }
+}
@@ -0,0 +1,222 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Property tests for applying incoming records. Other clients can upload
+//! anything, so we throw payloads with missing fields, wrong types, huge
+//! strings and odd guids at a database with records in each sync state, and
+//! check that nothing panics, the tables stay consistent, and applying the
+//! same records again changes nothing.
+//!
+//! See also the fuzz target in `components/logins/fuzz`.
+
+use crate::{Login, LoginDb, LoginStore};
+use proptest::prelude::*;
+use serde_json::{json, Map, Value};
+use sync15::{telemetry, IncomingChangeset, Payload, ServerTimestamp, SyncEngine};
+use sync_guid::Guid;
+
+const SYNCED: &str = "seededsynced";
+const CHANGED: &str = "seededchangd";
+const NEW: &str = "seedednewrec";
+const DELETED: &str = "seededdeletd";
+
+const STRING_FIELDS: &[&str] = &[
+    "hostname",
+    "formSubmitURL",
+    "httpRealm",
+    "username",
+    "password",
+    "usernameField",
+    "passwordField",
+];
+
+const NUMBER_FIELDS: &[&str] = &[
+    "timeCreated",
+    "timeLastUsed",
+    "timePasswordChanged",
+    "timesUsed",
+];
+
+// Incoming records are all older than this, and any local change, so
+// merges don't depend on how long the test takes to run.
+const SERVER_NOW: i64 = 1_600_000_000_000;
+
+/// A database with a record in each of the states an incoming record might
+/// meet: synced and unchanged, synced and changed locally, never synced,
+/// and deleted locally.
+fn seeded_db() -> LoginDb {
+    let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+    for guid in &[SYNCED, CHANGED, NEW, DELETED] {
+        db.add(Login {
+            guid: Guid::new(guid),
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("https://www.example.com".into()),
+            username: (*guid).into(),
+            password: "password".into(),
+            ..Login::default()
+        })
+        .unwrap();
+    }
+    let store = LoginStore::new(&db);
+    let mut telem = telemetry::Engine::new("passwords");
+    store
+        .apply_incoming(
+            vec![IncomingChangeset::new("passwords", ServerTimestamp(1000))],
+            &mut telem,
+        )
+        .unwrap();
+    store
+        .sync_finished(
+            ServerTimestamp(1000),
+            vec![Guid::new(SYNCED), Guid::new(CHANGED), Guid::new(DELETED)],
+        )
+        .unwrap();
+
+    let mut changed = db.get_by_id(CHANGED).unwrap().unwrap();
+    changed.password = "changed-password".into();
+    db.update(changed).unwrap();
+    db.delete(DELETED).unwrap();
+    assert_eq!(db.check_integrity().unwrap(), Vec::<String>::new());
+    db
+}
+
+fn guid() -> impl Strategy<Value = String> {
+    prop_oneof![
+        4 => prop::sample::select(vec![SYNCED, CHANGED, NEW, DELETED]).prop_map(String::from),
+        2 => "[a-zA-Z0-9_-]{12}",
+        1 => ".{0,40}",
+        1 => Just(String::new()),
+    ]
+}
+
+fn string() -> impl Strategy<Value = String> {
+    prop_oneof![
+        4 => "https?://[a-z]{1,8}\\.(com|org)(:[0-9]{1,5})?(/[a-z]{0,5})?",
+        4 => "[a-z]{0,12}",
+        1 => ".{0,40}",
+        1 => Just("x".repeat(100_000)),
+    ]
+}
+
+/// Usually a string, but sometimes the wrong type.
+fn string_field() -> impl Strategy<Value = Option<Value>> {
+    prop::option::of(prop_oneof![
+        8 => string().prop_map(Value::from),
+        1 => any::<i64>().prop_map(Value::from),
+        1 => any::<bool>().prop_map(Value::from),
+        1 => Just(Value::Null),
+        1 => Just(json!(["a", 1])),
+    ])
+}
+
+/// Usually a number, but sometimes the wrong type.
+fn number_field() -> impl Strategy<Value = Option<Value>> {
+    prop::option::of(prop_oneof![
+        4 => (0..SERVER_NOW).prop_map(Value::from),
+        2 => any::<i64>().prop_map(Value::from),
+        1 => Just(Value::from(u64::max_value())),
+        1 => any::<f64>().prop_map(Value::from),
+        1 => string().prop_map(Value::from),
+        1 => Just(Value::Null),
+    ])
+}
+
+prop_compose! {
+    fn payload()(
+        id in guid(),
+        deleted in prop::bool::weighted(0.1),
+        strings in prop::collection::vec(string_field(), STRING_FIELDS.len()),
+        numbers in prop::collection::vec(number_field(), NUMBER_FIELDS.len()),
+    ) -> Value {
+        let mut ob = Map::new();
+        ob.insert("id".into(), id.into());
+        if deleted {
+            ob.insert("deleted".into(), true.into());
+        }
+        let fields = STRING_FIELDS.iter().zip(strings).chain(NUMBER_FIELDS.iter().zip(numbers));
+        for (name, value) in fields {
+            if let Some(value) = value {
+                ob.insert((*name).into(), value);
+            }
+        }
+        Value::Object(ob)
+    }
+}
+
+fn changeset() -> impl Strategy<Value = Vec<(Value, i64)>> {
+    prop::collection::vec((payload(), 0..SERVER_NOW), 0..8).prop_map(|mut records| {
+        // A changeset with the same guid twice is rejected outright.
+        let mut seen = std::collections::HashSet::new();
+        records.retain(|(payload, _)| seen.insert(payload["id"].as_str().unwrap().to_owned()));
+        records
+    })
+}
+
+fn apply(db: &LoginDb, records: &[(Value, i64)]) -> Result<(), String> {
+    let mut changeset = IncomingChangeset::new("passwords", ServerTimestamp(SERVER_NOW));
+    for (payload, ts) in records {
+        match Payload::from_json(payload.clone()) {
+            Ok(payload) => changeset.changes.push((payload, ServerTimestamp(*ts))),
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+    let store = LoginStore::new(db);
+    let mut telem = telemetry::Engine::new("passwords");
+    store
+        .apply_incoming(vec![changeset], &mut telem)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+// Everything the user can see. What's in the mirror and local tables can
+// legitimately change when the same records are applied again, since that
+// looks like a retry of an interrupted sync.
+fn snapshot(db: &LoginDb) -> Vec<Login> {
+    let mut logins = db.get_all().unwrap();
+    logins.sort_by(|a, b| a.guid.cmp(&b.guid));
+    logins
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn test_arbitrary_incoming(records in changeset()) {
+        let db = seeded_db();
+        if apply(&db, &records).is_err() {
+            // Errors are fine (although unlikely), as long as they didn't
+            // leave anything half-applied.
+            prop_assert_eq!(db.check_integrity().unwrap(), Vec::<String>::new());
+            return Ok(());
+        }
+        prop_assert_eq!(db.check_integrity().unwrap(), Vec::<String>::new());
+
+        let once = snapshot(&db);
+        apply(&db, &records).unwrap();
+        prop_assert_eq!(db.check_integrity().unwrap(), Vec::<String>::new());
+        prop_assert_eq!(snapshot(&db), once);
+    }
+
+    #[test]
+    fn test_times_used_never_overflows(local in any::<i64>(), remote in any::<i64>()) {
+        let db = seeded_db();
+        db.execute_named(
+            "UPDATE loginsL SET timesUsed = :times_used WHERE guid = :guid",
+            rusqlite::named_params! { ":times_used": local, ":guid": CHANGED },
+        )
+        .unwrap();
+        apply(&db, &[(
+            json!({
+                "id": CHANGED,
+                "hostname": "https://www.example.com",
+                "formSubmitURL": "https://www.example.com",
+                "username": CHANGED,
+                "password": "password",
+                "timesUsed": remote,
+            }),
+            1000,
+        )]).unwrap();
+        prop_assert!(db.get_by_id(CHANGED).unwrap().is_some());
+    }
+}
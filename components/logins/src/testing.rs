@@ -0,0 +1,519 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Helpers for tests which sync logins, built with the `test-utils` feature.
+//!
+//! - [`LoginFixture::builder`] makes valid logins without spelling out every
+//!   field, [`LoginFixture::numbered`] makes sets of them which don't clash,
+//!   and [`incoming_changeset`] wraps their payloads up as they'd arrive from
+//!   the server.
+//! - [`sync_db`] runs one sync of a real [`LoginDb`], for tests which need
+//!   records in the mirror, or want to see what would be uploaded.
+//! - [`FakeLoginStore`] is a [`SyncEngine`] which doesn't need a database or
+//!   a server. It applies incoming records to a map, responds with whatever
+//!   outgoing records (or errors) it's been given, and records each call, so
+//!   tests of the code which schedules and drives syncs can check what it
+//!   did.
+
+use crate::db::{LoginDb, LoginStore, COLLECTION_NAME};
+use crate::login::Login;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use sync15::{
+    telemetry, CollectionRequest, EngineSyncAssociation, IncomingChangeset, OutgoingChangeset,
+    Payload, ServerTimestamp, SyncEngine,
+};
+use sync_guid::Guid;
+
+/// Builds logins for tests. See [`LoginFixture::builder`].
+pub struct LoginFixture;
+
+impl LoginFixture {
+    /// A builder for a valid login, with a random guid, for
+    /// `https://www.example.com`. Override whichever fields the test cares
+    /// about.
+    pub fn builder() -> LoginFixtureBuilder {
+        LoginFixtureBuilder {
+            login: Login {
+                guid: Guid::random(),
+                hostname: "https://www.example.com".into(),
+                form_submit_url: Some("https://www.example.com".into()),
+                username: "username".into(),
+                password: "password".into(),
+                ..Login::default()
+            },
+        }
+    }
+
+    /// A builder for the `n`th of a set of logins which don't clash: each is
+    /// for its own site, `https://www{n}.example.com`, with its own username
+    /// and password.
+    pub fn numbered(n: usize) -> LoginFixtureBuilder {
+        let site = format!("https://www{}.example.com", n);
+        Self::builder()
+            .hostname(site.clone())
+            .form_submit_url(site)
+            .username(format!("user{}", n))
+            .password(format!("password{}", n))
+    }
+}
+
+pub struct LoginFixtureBuilder {
+    login: Login,
+}
+
+impl LoginFixtureBuilder {
+    pub fn guid(mut self, guid: impl Into<Guid>) -> Self {
+        self.login.guid = guid.into();
+        self
+    }
+
+    pub fn hostname(mut self, hostname: impl Into<String>) -> Self {
+        self.login.hostname = hostname.into();
+        self
+    }
+
+    /// Also clears the HTTP realm, since a login can't have both.
+    pub fn form_submit_url(mut self, form_submit_url: impl Into<String>) -> Self {
+        self.login.form_submit_url = Some(form_submit_url.into());
+        self.login.http_realm = None;
+        self
+    }
+
+    /// Also clears the form submit URL, since a login can't have both.
+    pub fn http_realm(mut self, http_realm: impl Into<String>) -> Self {
+        self.login.http_realm = Some(http_realm.into());
+        self.login.form_submit_url = None;
+        self
+    }
+
+    pub fn username(mut self, username: impl Into<String>) -> Self {
+        self.login.username = username.into();
+        self
+    }
+
+    pub fn password(mut self, password: impl Into<String>) -> Self {
+        self.login.password = password.into();
+        self
+    }
+
+    pub fn username_field(mut self, username_field: impl Into<String>) -> Self {
+        self.login.username_field = username_field.into();
+        self
+    }
+
+    pub fn password_field(mut self, password_field: impl Into<String>) -> Self {
+        self.login.password_field = password_field.into();
+        self
+    }
+
+    pub fn time_created(mut self, time_created: i64) -> Self {
+        self.login.time_created = time_created;
+        self
+    }
+
+    pub fn time_password_changed(mut self, time_password_changed: i64) -> Self {
+        self.login.time_password_changed = time_password_changed;
+        self
+    }
+
+    pub fn time_last_used(mut self, time_last_used: i64) -> Self {
+        self.login.time_last_used = time_last_used;
+        self
+    }
+
+    pub fn times_used(mut self, times_used: i64) -> Self {
+        self.login.times_used = times_used;
+        self
+    }
+
+    /// Make any other change, including ones which leave the login invalid.
+    pub fn with(mut self, f: impl FnOnce(&mut Login)) -> Self {
+        f(&mut self.login);
+        self
+    }
+
+    pub fn build(self) -> Login {
+        self.login
+    }
+
+    /// The login as a sync payload.
+    pub fn payload(self) -> Payload {
+        Payload::from_record(self.login).expect("logins always serialize")
+    }
+}
+
+/// `payloads` as they'd arrive from the server, all last modified at
+/// `timestamp`, for `LoginDb::fetch_login_data` and the like.
+pub fn incoming_changes(
+    payloads: impl IntoIterator<Item = Payload>,
+    timestamp: ServerTimestamp,
+) -> Vec<(Payload, ServerTimestamp)> {
+    payloads
+        .into_iter()
+        .map(|payload| (payload, timestamp))
+        .collect()
+}
+
+/// A changeset of `payloads` for the passwords collection, as fetched at
+/// `timestamp`, for [`SyncEngine::apply_incoming`].
+pub fn incoming_changeset(
+    payloads: impl IntoIterator<Item = Payload>,
+    timestamp: ServerTimestamp,
+) -> IncomingChangeset {
+    let mut changeset = IncomingChangeset::new(COLLECTION_NAME, timestamp);
+    changeset.changes = incoming_changes(payloads, timestamp);
+    changeset
+}
+
+/// Sync `db` once, as if `incoming` had been fetched at `timestamp`, and
+/// mark everything it uploads as synced. With nothing incoming, this uploads
+/// every local change, leaving the records in the mirror only. Returns what
+/// was uploaded, and the sync's telemetry.
+pub fn sync_db(
+    db: &LoginDb,
+    incoming: impl IntoIterator<Item = Payload>,
+    timestamp: ServerTimestamp,
+) -> (OutgoingChangeset, telemetry::Engine) {
+    let engine = LoginStore::new(db);
+    let mut telem = telemetry::Engine::new(COLLECTION_NAME);
+    let outgoing = engine
+        .apply_incoming(vec![incoming_changeset(incoming, timestamp)], &mut telem)
+        .unwrap();
+    let guids = outgoing.changes.iter().map(|p| p.id.clone()).collect();
+    engine.sync_finished(timestamp, guids).unwrap();
+    (outgoing, telem)
+}
+
+/// A call made to a [`FakeLoginStore`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum FakeCall {
+    /// The guids of the records in each incoming changeset.
+    ApplyIncoming(Vec<Vec<Guid>>),
+    SyncFinished {
+        new_timestamp: ServerTimestamp,
+        records_synced: Vec<Guid>,
+    },
+    GetCollectionRequests(ServerTimestamp),
+    GetSyncAssoc,
+    Reset(EngineSyncAssociation),
+    Wipe,
+}
+
+/// A [`SyncEngine`] for the passwords collection which keeps its records in
+/// memory, and does what it's told. See the module docs.
+pub struct FakeLoginStore {
+    records: RefCell<HashMap<Guid, Payload>>,
+    // What each `apply_incoming` call returns, in order. Once these run
+    // out, it uploads nothing.
+    script: RefCell<VecDeque<anyhow::Result<Vec<Payload>>>>,
+    last_sync: RefCell<Option<ServerTimestamp>>,
+    assoc: RefCell<EngineSyncAssociation>,
+    calls: RefCell<Vec<FakeCall>>,
+}
+
+impl Default for FakeLoginStore {
+    fn default() -> Self {
+        Self {
+            records: RefCell::default(),
+            script: RefCell::default(),
+            last_sync: RefCell::default(),
+            assoc: RefCell::new(EngineSyncAssociation::Disconnected),
+            calls: RefCell::default(),
+        }
+    }
+}
+
+impl FakeLoginStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Upload `outgoing` from the next `apply_incoming` call which hasn't
+    /// already been given a response.
+    pub fn respond_with(&self, outgoing: Vec<Payload>) {
+        self.script.borrow_mut().push_back(Ok(outgoing));
+    }
+
+    /// Fail the next `apply_incoming` call which hasn't already been given a
+    /// response, without applying anything.
+    pub fn fail_with(&self, error: impl Into<anyhow::Error>) {
+        self.script.borrow_mut().push_back(Err(error.into()));
+    }
+
+    /// The records applied so far, by guid. Incoming tombstones remove them.
+    pub fn records(&self) -> HashMap<Guid, Payload> {
+        self.records.borrow().clone()
+    }
+
+    /// The timestamp passed to the last `sync_finished`, if any, since the
+    /// last reset.
+    pub fn last_sync(&self) -> Option<ServerTimestamp> {
+        *self.last_sync.borrow()
+    }
+
+    /// The calls made so far, oldest first.
+    pub fn calls(&self) -> Vec<FakeCall> {
+        self.calls.borrow().clone()
+    }
+
+    /// Like `calls`, but forgets them.
+    pub fn take_calls(&self) -> Vec<FakeCall> {
+        std::mem::take(&mut *self.calls.borrow_mut())
+    }
+
+    fn record_call(&self, call: FakeCall) {
+        self.calls.borrow_mut().push(call);
+    }
+}
+
+impl SyncEngine for FakeLoginStore {
+    fn collection_name(&self) -> std::borrow::Cow<'static, str> {
+        COLLECTION_NAME.into()
+    }
+
+    fn apply_incoming(
+        &self,
+        inbound: Vec<IncomingChangeset>,
+        _telem: &mut telemetry::Engine,
+    ) -> anyhow::Result<OutgoingChangeset> {
+        self.record_call(FakeCall::ApplyIncoming(
+            inbound
+                .iter()
+                .map(|changeset| {
+                    changeset
+                        .changes
+                        .iter()
+                        .map(|(payload, _)| payload.id.clone())
+                        .collect()
+                })
+                .collect(),
+        ));
+        let outgoing = self
+            .script
+            .borrow_mut()
+            .pop_front()
+            .unwrap_or_else(|| Ok(vec![]))?;
+        let timestamp = inbound
+            .last()
+            .map_or_else(ServerTimestamp::default, |changeset| changeset.timestamp);
+        let mut records = self.records.borrow_mut();
+        for (payload, _) in inbound.into_iter().flat_map(|changeset| changeset.changes) {
+            if payload.is_tombstone() {
+                records.remove(&payload.id);
+            } else {
+                records.insert(payload.id.clone(), payload);
+            }
+        }
+        let mut changeset = OutgoingChangeset::new(COLLECTION_NAME, timestamp);
+        changeset.changes = outgoing;
+        Ok(changeset)
+    }
+
+    fn sync_finished(
+        &self,
+        new_timestamp: ServerTimestamp,
+        records_synced: Vec<Guid>,
+    ) -> anyhow::Result<()> {
+        self.record_call(FakeCall::SyncFinished {
+            new_timestamp,
+            records_synced,
+        });
+        *self.last_sync.borrow_mut() = Some(new_timestamp);
+        Ok(())
+    }
+
+    fn get_collection_requests(
+        &self,
+        server_timestamp: ServerTimestamp,
+    ) -> anyhow::Result<Vec<CollectionRequest>> {
+        self.record_call(FakeCall::GetCollectionRequests(server_timestamp));
+        // As the real store does.
+        let since = self.last_sync().unwrap_or_default();
+        Ok(if since == server_timestamp {
+            vec![]
+        } else {
+            vec![CollectionRequest::new(COLLECTION_NAME)
+                .full()
+                .newer_than(since)]
+        })
+    }
+
+    fn get_sync_assoc(&self) -> anyhow::Result<EngineSyncAssociation> {
+        self.record_call(FakeCall::GetSyncAssoc);
+        Ok(self.assoc.borrow().clone())
+    }
+
+    fn reset(&self, assoc: &EngineSyncAssociation) -> anyhow::Result<()> {
+        self.record_call(FakeCall::Reset(assoc.clone()));
+        *self.assoc.borrow_mut() = assoc.clone();
+        *self.last_sync.borrow_mut() = None;
+        Ok(())
+    }
+
+    fn wipe(&self) -> anyhow::Result<()> {
+        self.record_call(FakeCall::Wipe);
+        self.records.borrow_mut().clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::login::SyncLoginData;
+
+    #[test]
+    fn test_fixtures_are_valid() {
+        let fixtures = vec![
+            LoginFixture::builder().build(),
+            LoginFixture::builder()
+                .http_realm("Example")
+                .username("")
+                .build(),
+            LoginFixture::builder()
+                .hostname("https://accounts.example.com")
+                .form_submit_url("https://accounts.example.com/login")
+                .username_field("user")
+                .password_field("pass")
+                .time_created(1000)
+                .times_used(3)
+                .build(),
+            LoginFixture::numbered(2).build(),
+        ];
+        for login in fixtures {
+            login.check_valid().unwrap();
+            // And they survive the trip to the server and back.
+            let payload = Payload::from_record(login.clone()).unwrap();
+            let data = SyncLoginData::from_payload(payload, ServerTimestamp(1000)).unwrap();
+            assert_eq!(data.inbound.0.as_ref(), Some(&login));
+        }
+        // Numbered logins are all different records.
+        let first = LoginFixture::numbered(1).build();
+        let second = LoginFixture::numbered(2).build();
+        assert_ne!(first.hostname, second.hostname);
+        assert_ne!(first.username, second.username);
+        // Each gets its own guid.
+        assert_ne!(
+            LoginFixture::builder().build().guid,
+            LoginFixture::builder().build().guid
+        );
+        // Unless it's told otherwise, including to be invalid.
+        let invalid = LoginFixture::builder()
+            .guid("dummy_000001")
+            .with(|l| l.hostname.clear())
+            .build();
+        assert_eq!(invalid.guid, "dummy_000001");
+        assert!(invalid.check_valid().is_err());
+    }
+
+    #[test]
+    fn test_incoming_changeset() {
+        let login = LoginFixture::builder().build();
+        let changeset = incoming_changeset(
+            vec![
+                Payload::from_record(login.clone()).unwrap(),
+                Payload::new_tombstone("dummy_000002"),
+            ],
+            ServerTimestamp(2000),
+        );
+        assert_eq!(changeset.collection, "passwords");
+        assert_eq!(changeset.timestamp, ServerTimestamp(2000));
+        assert_eq!(changeset.changes.len(), 2);
+        assert_eq!(changeset.changes[0].0.id, login.guid);
+        assert_eq!(changeset.changes[1].1, ServerTimestamp(2000));
+    }
+
+    #[test]
+    fn test_fake_store() {
+        let store = FakeLoginStore::new();
+        let mut telem = telemetry::Engine::new("passwords");
+        let kept = LoginFixture::builder().guid("dummy_000001").payload();
+        let deleted = LoginFixture::builder().guid("dummy_000002").payload();
+        let local = LoginFixture::builder().guid("dummy_000003").payload();
+
+        assert_eq!(
+            store
+                .get_collection_requests(ServerTimestamp(1000))
+                .unwrap()
+                .len(),
+            1
+        );
+        store.respond_with(vec![local.clone()]);
+        let outgoing = store
+            .apply_incoming(
+                vec![incoming_changeset(
+                    vec![kept.clone(), deleted],
+                    ServerTimestamp(1000),
+                )],
+                &mut telem,
+            )
+            .unwrap();
+        assert_eq!(outgoing.changes, vec![local]);
+        assert_eq!(outgoing.timestamp, ServerTimestamp(1000));
+        store
+            .sync_finished(ServerTimestamp(1000), vec!["dummy_000003".into()])
+            .unwrap();
+        assert_eq!(store.last_sync(), Some(ServerTimestamp(1000)));
+        // Nothing's changed on the server since.
+        assert!(store
+            .get_collection_requests(ServerTimestamp(1000))
+            .unwrap()
+            .is_empty());
+
+        store.fail_with(anyhow::anyhow!("it broke"));
+        let err = store
+            .apply_incoming(
+                vec![incoming_changeset(
+                    vec![Payload::new_tombstone("dummy_000002")],
+                    ServerTimestamp(2000),
+                )],
+                &mut telem,
+            )
+            .unwrap_err();
+        assert_eq!(err.to_string(), "it broke");
+        // Failed calls don't apply anything, and the script's used up.
+        assert_eq!(store.records().len(), 2);
+        let outgoing = store
+            .apply_incoming(
+                vec![incoming_changeset(
+                    vec![Payload::new_tombstone("dummy_000002")],
+                    ServerTimestamp(2000),
+                )],
+                &mut telem,
+            )
+            .unwrap();
+        assert!(outgoing.changes.is_empty());
+        let records = store.records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[&Guid::from("dummy_000001")], kept);
+
+        assert_eq!(
+            store.take_calls(),
+            vec![
+                FakeCall::GetCollectionRequests(ServerTimestamp(1000)),
+                FakeCall::ApplyIncoming(vec![vec!["dummy_000001".into(), "dummy_000002".into()]]),
+                FakeCall::SyncFinished {
+                    new_timestamp: ServerTimestamp(1000),
+                    records_synced: vec!["dummy_000003".into()],
+                },
+                FakeCall::GetCollectionRequests(ServerTimestamp(1000)),
+                FakeCall::ApplyIncoming(vec![vec!["dummy_000002".into()]]),
+                FakeCall::ApplyIncoming(vec![vec!["dummy_000002".into()]]),
+            ]
+        );
+
+        store.reset(&EngineSyncAssociation::Disconnected).unwrap();
+        store.wipe().unwrap();
+        assert_eq!(store.last_sync(), None);
+        assert!(store.records().is_empty());
+        assert_eq!(
+            store.calls(),
+            vec![
+                FakeCall::Reset(EngineSyncAssociation::Disconnected),
+                FakeCall::Wipe
+            ]
+        );
+    }
+}
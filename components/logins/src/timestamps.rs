@@ -0,0 +1,200 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Repairing the garbage timestamps records sometimes arrive with, from the
+//! FFI, imports and other clients, before they're stored. We've seen:
+//!
+//! - Negative values, such as Kotlin's defaults. These become 0, which `add`
+//!   then replaces with the current time, as it does for a missing value.
+//! - Seconds or microseconds since the epoch, rather than milliseconds.
+//!   Values before 1990 which would be after it in milliseconds are taken
+//!   to be seconds, and values after the year 3000 are taken to be
+//!   microseconds (or nanoseconds), and converted.
+//! - Values in the future, which would sort the record above everything else
+//!   for good. Anything more than `FUTURE_SLACK_MS` ahead of us becomes the
+//!   current time. For incoming records, that's the server's time, so that
+//!   applying the same record again gives the same result.
+//!
+//! A millisecond timestamp between 1990 and now is never changed. Neither
+//! are small values which can't be in any unit we know of; those are
+//! usually from tests, or from records created before timestamps were
+//! recorded.
+
+use crate::login::Login;
+
+/// 1990-01-01, in milliseconds. Nothing we store is older than this.
+const MIN_PLAUSIBLE_MS: i64 = 631_152_000_000;
+
+/// 3000-01-01, in milliseconds.
+const MAX_PLAUSIBLE_MS: i64 = 32_503_680_000_000;
+
+/// How far ahead of our clock a timestamp may be, to allow for other
+/// devices' clocks being a little fast.
+pub(crate) const FUTURE_SLACK_MS: i64 = 60 * 60 * 1000;
+
+/// Repair `login`'s timestamps, as described above, given that it's now
+/// `now_ms`. Returns how many of them were changed.
+pub(crate) fn sanitize(login: &mut Login, now_ms: i64) -> usize {
+    sanitize_with(login, now_ms, now_ms)
+}
+
+/// Like `sanitize`, but timestamps in the future become `replacement_ms`.
+pub(crate) fn sanitize_with(login: &mut Login, now_ms: i64, replacement_ms: i64) -> usize {
+    let guid = login.guid.clone();
+    let mut changed = 0;
+    for (name, value) in [
+        ("timeCreated", &mut login.time_created),
+        ("timePasswordChanged", &mut login.time_password_changed),
+        ("timeLastUsed", &mut login.time_last_used),
+    ]
+    .iter_mut()
+    {
+        let sanitized = sanitize_timestamp(**value, now_ms, replacement_ms);
+        if sanitized != **value {
+            log::warn!(
+                "Replacing {} {} of {} with {}",
+                name,
+                value,
+                guid,
+                sanitized
+            );
+            **value = sanitized;
+            changed += 1;
+        }
+    }
+    changed
+}
+
+/// Whether `sanitize` would change any of `login`'s timestamps.
+pub(crate) fn need_sanitizing(login: &Login, now_ms: i64) -> bool {
+    [
+        login.time_created,
+        login.time_password_changed,
+        login.time_last_used,
+    ]
+    .iter()
+    .any(|&value| sanitize_timestamp(value, now_ms, now_ms) != value)
+}
+
+fn sanitize_timestamp(value: i64, now_ms: i64, replacement_ms: i64) -> i64 {
+    if value <= 0 {
+        return 0;
+    }
+    let mut value = value;
+    if (MIN_PLAUSIBLE_MS / 1000..MIN_PLAUSIBLE_MS).contains(&value) {
+        // Seconds.
+        value *= 1000;
+    }
+    while value > MAX_PLAUSIBLE_MS {
+        // Microseconds, or even nanoseconds.
+        value /= 1000;
+    }
+    if value > now_ms.saturating_add(FUTURE_SLACK_MS) {
+        value = replacement_ms;
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::LoginFixture;
+
+    // 2021-01-01T00:00:00Z.
+    const NOW_MS: i64 = 1_609_459_200_000;
+
+    fn login(time_created: i64, time_password_changed: i64, time_last_used: i64) -> Login {
+        LoginFixture::builder()
+            .guid("abcdefghijkl")
+            .http_realm("https://www.example.com")
+            .username("user")
+            .time_created(time_created)
+            .time_password_changed(time_password_changed)
+            .time_last_used(time_last_used)
+            .build()
+    }
+
+    fn times(login: &Login) -> [i64; 3] {
+        [
+            login.time_created,
+            login.time_password_changed,
+            login.time_last_used,
+        ]
+    }
+
+    #[test]
+    fn test_plausible_values_unchanged() {
+        for &value in &[
+            MIN_PLAUSIBLE_MS,
+            MIN_PLAUSIBLE_MS + 1,
+            1_234_567_890_123,
+            NOW_MS - 1,
+            NOW_MS,
+            NOW_MS + FUTURE_SLACK_MS,
+        ] {
+            let mut l = login(value, value, value);
+            assert!(!need_sanitizing(&l, NOW_MS), "{}", value);
+            assert_eq!(sanitize(&mut l, NOW_MS), 0);
+            assert_eq!(times(&l), [value; 3]);
+        }
+        // And neither are values which can't be in any unit we know of.
+        for &value in &[1, 1000, MIN_PLAUSIBLE_MS / 1000 - 1] {
+            let mut l = login(value, value, value);
+            assert_eq!(sanitize(&mut l, NOW_MS), 0);
+            assert_eq!(times(&l), [value; 3]);
+        }
+    }
+
+    #[test]
+    fn test_negative_and_zero() {
+        let mut l = login(-1, i64::min_value(), 0);
+        assert!(need_sanitizing(&l, NOW_MS));
+        assert_eq!(sanitize(&mut l, NOW_MS), 2);
+        assert_eq!(times(&l), [0, 0, 0]);
+    }
+
+    #[test]
+    fn test_seconds() {
+        let mut l = login(NOW_MS / 1000, 1_500_000_000, NOW_MS);
+        assert_eq!(sanitize(&mut l, NOW_MS), 2);
+        assert_eq!(times(&l), [NOW_MS, 1_500_000_000_000, NOW_MS]);
+    }
+
+    #[test]
+    fn test_microseconds() {
+        let mut l = login(NOW_MS * 1000, 1_500_000_000_000_000, NOW_MS * 1_000_000);
+        assert_eq!(sanitize(&mut l, NOW_MS), 3);
+        assert_eq!(times(&l), [NOW_MS, 1_500_000_000_000, NOW_MS]);
+    }
+
+    #[test]
+    fn test_future() {
+        let year_2500 = 16_725_225_600_000;
+        let mut l = login(
+            NOW_MS + FUTURE_SLACK_MS + 1,
+            year_2500,
+            // Microseconds, but still in the future once converted.
+            (NOW_MS + 2 * FUTURE_SLACK_MS) * 1000,
+        );
+        assert_eq!(sanitize(&mut l, NOW_MS), 3);
+        assert_eq!(times(&l), [NOW_MS; 3]);
+        // Nanoseconds become the year 2262, which is still too far ahead.
+        let mut l = login(NOW_MS, NOW_MS, i64::max_value());
+        assert_eq!(sanitize(&mut l, NOW_MS), 1);
+        assert_eq!(times(&l), [NOW_MS; 3]);
+    }
+
+    #[test]
+    fn test_future_replacement() {
+        let server_now = NOW_MS - 5000;
+        let mut l = login(NOW_MS * 1000, NOW_MS + 2 * FUTURE_SLACK_MS, NOW_MS);
+        assert_eq!(sanitize_with(&mut l, NOW_MS, server_now), 2);
+        // Only values in the future are replaced with it.
+        assert_eq!(times(&l), [NOW_MS, server_now, NOW_MS]);
+        // And it's the same the second time round.
+        let mut again = login(NOW_MS * 1000, NOW_MS + 2 * FUTURE_SLACK_MS, NOW_MS);
+        sanitize_with(&mut again, NOW_MS + 1000, server_now);
+        assert_eq!(times(&again), times(&l));
+    }
+}
@@ -0,0 +1,158 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Local deletions which are too old to be worth uploading.
+//!
+//! A local tombstone is removed once it's been uploaded, so the ones which
+//! pile up are those without a mirror row: deletions of records the server
+//! never had, and of records whose mirror rows were dropped by a `reset`,
+//! say after a node reassignment or the server being wiped. For long-lived
+//! profiles, uploading all of those makes the first sync after a reset very
+//! slow, for deletions the server has long since stopped caring about.
+//!
+//! So tombstones without a mirror row, for records deleted longer ago than
+//! the retention window (see `set_tombstone_retention`), are "aged out":
+//! they aren't uploaded, and are removed by `run_maintenance`, or by the
+//! first sync after a reset, which counts them in its telemetry. Newer
+//! tombstones are uploaded as before.
+
+use crate::db::LoginDb;
+use crate::error::*;
+use crate::util;
+use rusqlite::named_params;
+use std::time::{Duration, SystemTime};
+
+/// How long local deletions are kept for uploading by default.
+pub const DEFAULT_TOMBSTONE_RETENTION: Duration = Duration::from_secs(180 * 24 * 60 * 60);
+
+// The `loginsL` rows which are aged out, for a `:cutoff` in milliseconds.
+// Tombstones without a `local_modified` are never aged out.
+pub(crate) const AGED_OUT_TOMBSTONES_SQL: &str = "
+    is_deleted = 1
+    AND local_modified < :cutoff
+    AND guid NOT IN (SELECT guid FROM loginsM)";
+
+impl LoginDb {
+    /// Set how long local deletions of records without a mirror row are
+    /// kept for uploading, `DEFAULT_TOMBSTONE_RETENTION` by default.
+    pub fn set_tombstone_retention(&self, retention: Duration) {
+        self.tombstone_retention.set(retention);
+    }
+
+    // The `:cutoff` for `AGED_OUT_TOMBSTONES_SQL`, as of `now`.
+    pub(crate) fn tombstone_cutoff(&self, now: SystemTime) -> i64 {
+        util::system_time_ms_i64(now) - self.tombstone_retention.get().as_millis() as i64
+    }
+
+    /// Remove the tombstones which are aged out as of `now`, returning how
+    /// many there were.
+    pub(crate) fn prune_aged_out_tombstones(&self, now: SystemTime) -> Result<usize> {
+        let pruned = self.execute_named_cached(
+            &self.sql(&format!(
+                "DELETE FROM loginsL WHERE {}",
+                AGED_OUT_TOMBSTONES_SQL
+            )),
+            named_params! { ":cutoff": self.tombstone_cutoff(now) },
+        )?;
+        if pruned > 0 {
+            log::info!("Pruned {} aged-out tombstones", pruned);
+        }
+        Ok(pruned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{sync_db, LoginFixture};
+    use sql_support::ConnExt;
+    use sync15::{CollSyncIds, EngineSyncAssociation, OutgoingChangeset, ServerTimestamp};
+    use sync_guid::Guid;
+
+    fn outgoing_tombstones(outgoing: &OutgoingChangeset) -> Vec<Guid> {
+        let mut guids = outgoing
+            .changes
+            .iter()
+            .filter(|p| p.deleted)
+            .map(|p| p.id.clone())
+            .collect::<Vec<_>>();
+        guids.sort();
+        guids
+    }
+
+    fn local_tombstones(db: &LoginDb) -> i64 {
+        db.query_one::<i64>("SELECT COUNT(*) FROM loginsL WHERE is_deleted = 1")
+            .unwrap()
+    }
+
+    // Pretend `guid` was deleted `age` ago.
+    fn backdate(db: &LoginDb, guid: &str, age: Duration) {
+        let deleted_at = util::system_time_ms_i64(SystemTime::now() - age);
+        db.execute_named(
+            "UPDATE loginsL SET local_modified = :deleted_at WHERE guid = :guid",
+            named_params! { ":deleted_at": deleted_at, ":guid": guid },
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_reset_after_long_time() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let logins = (0..3)
+            .map(|n| db.add(LoginFixture::numbered(n).build()).unwrap())
+            .collect::<Vec<_>>();
+        sync_db(&db, vec![], ServerTimestamp(1000));
+
+        // Two deletions which never made it to the server, one of them long
+        // enough ago to have aged out, before the server's wiped.
+        db.delete(&logins[0].guid).unwrap();
+        db.delete(&logins[1].guid).unwrap();
+        backdate(&db, &logins[0].guid, DEFAULT_TOMBSTONE_RETENTION * 2);
+        db.reset(&EngineSyncAssociation::Connected(CollSyncIds {
+            global: Guid::random(),
+            coll: Guid::random(),
+        }))
+        .unwrap();
+
+        let (outgoing, telem) = sync_db(&db, vec![], ServerTimestamp(2000));
+        assert_eq!(outgoing_tombstones(&outgoing), vec![logins[1].guid.clone()]);
+        // The record we still have goes up too.
+        assert_eq!(outgoing.changes.len(), 2);
+        assert!(format!("{:?}", telem).contains("agedOutTombstones"));
+        assert_eq!(local_tombstones(&db), 0);
+    }
+
+    #[test]
+    fn test_aged_out_tombstones_not_uploaded() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let synced = db.add(LoginFixture::numbered(0).build()).unwrap();
+        sync_db(&db, vec![], ServerTimestamp(1000));
+        let old = db.add(LoginFixture::numbered(1).build()).unwrap();
+        let recent = db.add(LoginFixture::numbered(2).build()).unwrap();
+        for guid in &[&synced.guid, &old.guid, &recent.guid] {
+            db.delete(guid).unwrap();
+        }
+        backdate(&db, &synced.guid, DEFAULT_TOMBSTONE_RETENTION * 2);
+        backdate(&db, &old.guid, DEFAULT_TOMBSTONE_RETENTION * 2);
+        backdate(&db, &recent.guid, Duration::from_secs(60 * 60));
+
+        // The server still has the synced record, so its deletion goes up
+        // however old it is.
+        let scope = db.begin_interrupt_scope();
+        let outgoing = db.fetch_outgoing(ServerTimestamp(0), &scope).unwrap();
+        let mut expected = vec![synced.guid.clone(), recent.guid.clone()];
+        expected.sort();
+        assert_eq!(outgoing_tombstones(&outgoing), expected);
+
+        db.set_tombstone_retention(Duration::from_secs(60));
+        let outgoing = db.fetch_outgoing(ServerTimestamp(0), &scope).unwrap();
+        assert_eq!(outgoing_tombstones(&outgoing), vec![synced.guid.clone()]);
+        assert_eq!(local_tombstones(&db), 3);
+
+        db.run_maintenance().unwrap();
+        assert_eq!(local_tombstones(&db), 1);
+        let outgoing = db.fetch_outgoing(ServerTimestamp(0), &scope).unwrap();
+        assert_eq!(outgoing_tombstones(&outgoing), vec![synced.guid.clone()]);
+    }
+}
@@ -0,0 +1,152 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Fields of incoming records which this version doesn't know about.
+//!
+//! Newer clients may add fields to login records. We can't do anything with
+//! them, but we mustn't drop them either, or every record we upload would
+//! lose them for every other client too. So whatever an incoming record has
+//! beyond the fields of a [`Login`] is kept, as a JSON object, in
+//! `loginsM.unknown_fields`, and added back to the records we upload.
+
+use crate::login::Login;
+use lazy_static::lazy_static;
+use serde_json::{Map, Value as JsonValue};
+use std::collections::HashSet;
+use sync15::Payload;
+
+/// The most we keep for a record, in bytes of JSON. Anything bigger is
+/// dropped, since it would be stored and uploaded again with every change.
+pub(crate) const MAX_UNKNOWN_FIELDS_SIZE: usize = 16 * 1024;
+
+// Added to incoming payloads by sync15, rather than part of the record.
+const AUTO_FIELDS: &[&str] = &["sortindex", "ttl"];
+
+lazy_static! {
+    // Every field a `Login` has, taken from how one serializes so that this
+    // can't fall behind.
+    static ref KNOWN_FIELDS: HashSet<String> = {
+        let login = Login {
+            form_submit_url: Some(String::new()),
+            http_realm: Some(String::new()),
+            ..Login::default()
+        };
+        match serde_json::to_value(login) {
+            Ok(JsonValue::Object(fields)) => fields.into_iter().map(|(name, _)| name).collect(),
+            _ => unreachable!("Logins serialize as objects"),
+        }
+    };
+}
+
+/// The fields of an incoming `payload` which aren't part of a `Login`, as a
+/// JSON object. None if there aren't any, or if there's too much to keep.
+pub(crate) fn extract(payload: &Payload) -> Option<String> {
+    let unknown: Map<String, JsonValue> = payload
+        .data
+        .iter()
+        .filter(|(name, _)| {
+            !KNOWN_FIELDS.contains(name.as_str()) && !AUTO_FIELDS.contains(&name.as_str())
+        })
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+    if unknown.is_empty() {
+        return None;
+    }
+    let json = JsonValue::Object(unknown).to_string();
+    if json.len() > MAX_UNKNOWN_FIELDS_SIZE {
+        log::warn!(
+            "Dropping {} bytes of unknown fields from incoming record {}",
+            json.len(),
+            payload.id
+        );
+        return None;
+    }
+    Some(json)
+}
+
+/// Adds the fields kept by `extract` back to an outgoing `payload`. Fields
+/// the payload already has are left alone.
+pub(crate) fn reattach(mut payload: Payload, unknown_fields: Option<&str>) -> Payload {
+    let unknown = match unknown_fields.map(serde_json::from_str::<Map<String, JsonValue>>) {
+        None => return payload,
+        Some(Ok(unknown)) => unknown,
+        Some(Err(e)) => {
+            log::warn!(
+                "Ignoring unreadable unknown fields of {}: {}",
+                payload.id,
+                e
+            );
+            return payload;
+        }
+    };
+    for (name, value) in unknown {
+        payload.data.entry(name).or_insert(value);
+    }
+    payload
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn payload(value: JsonValue) -> Payload {
+        Payload::from_json(value).unwrap()
+    }
+
+    #[test]
+    fn test_extract() {
+        let known = json!({
+            "id": "dummy_000001",
+            "hostname": "https://www.example.com",
+            "formSubmitURL": "https://www.example.com",
+            "httpRealm": null,
+            "username": "user",
+            "password": "pass",
+            "usernameField": "",
+            "passwordField": "",
+            "timeCreated": 1000,
+            "timePasswordChanged": 1000,
+            "timeLastUsed": 1000,
+            "timesUsed": 1,
+            "sortindex": 1,
+            "ttl": 100,
+        });
+        assert_eq!(extract(&payload(known.clone())), None);
+
+        let mut with_unknown = known;
+        with_unknown["newField"] = json!({ "nested": [1, 2, 3] });
+        with_unknown["otherField"] = json!("value");
+        let unknown: JsonValue =
+            serde_json::from_str(&extract(&payload(with_unknown)).unwrap()).unwrap();
+        assert_eq!(
+            unknown,
+            json!({ "newField": { "nested": [1, 2, 3] }, "otherField": "value" })
+        );
+    }
+
+    #[test]
+    fn test_extract_too_big() {
+        let big = "x".repeat(MAX_UNKNOWN_FIELDS_SIZE);
+        let p = payload(json!({ "id": "dummy_000001", "password": "pass", "big": big }));
+        assert_eq!(extract(&p), None);
+
+        let fits = "x".repeat(MAX_UNKNOWN_FIELDS_SIZE - r#"{"big":""}"#.len());
+        let p = payload(json!({ "id": "dummy_000001", "password": "pass", "big": fits }));
+        assert!(extract(&p).is_some());
+    }
+
+    #[test]
+    fn test_reattach() {
+        let p = payload(json!({ "id": "dummy_000001", "password": "new" }));
+        let p = reattach(p, Some(r#"{"password":"old","newField":[1]}"#));
+        assert_eq!(p.data["password"], "new");
+        assert_eq!(p.data["newField"], json!([1]));
+
+        let p = reattach(p, Some("not json"));
+        assert_eq!(p.data.len(), 2);
+        let p = reattach(p, None);
+        assert_eq!(p.data.len(), 2);
+    }
+}
@@ -0,0 +1,413 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Changing some of a record's fields, without reading and writing back the
+//! whole thing.
+//!
+//! `update` replaces every field, so a caller changing one has to fetch the
+//! record first, and anything that changed in between is lost. With
+//! `update_fields`, the caller says which fields to change, and the rest are
+//! left as they are in the database. The changes are merged with the record
+//! and validated inside the transaction which writes them, and only the
+//! columns which actually change are written. Unlike `update`, this doesn't
+//! count as using the record.
+
+use crate::db::LoginDb;
+use crate::encryption;
+use crate::error::*;
+use crate::login::{change_flags, Login, SyncStatus};
+use crate::util;
+use rusqlite::ToSql;
+use serde_derive::*;
+use std::time::SystemTime;
+
+/// The fields `LoginDb::update_fields` should change. Fields which are
+/// `None` (or missing, in JSON) are left as they are. `form_submit_url` and
+/// `http_realm` can be cleared with `Some(None)` (or `null`).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LoginChanges {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+
+    #[serde(rename = "formSubmitURL")]
+    #[serde(default, deserialize_with = "deserialize_present")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub form_submit_url: Option<Option<String>>,
+
+    #[serde(default, deserialize_with = "deserialize_present")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_realm: Option<Option<String>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username_field: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_field: Option<String>,
+}
+
+// Deserializes a field which is present, even if it's `null`, as `Some`.
+// Missing fields are `None`, through `#[serde(default)]`.
+fn deserialize_present<'de, D, T>(
+    deserializer: D,
+) -> std::result::Result<Option<Option<T>>, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+    T: serde::de::Deserialize<'de>,
+{
+    use serde::de::Deserialize;
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+impl LoginChanges {
+    fn apply_to(self, login: &mut Login) {
+        fn set<T>(field: &mut T, value: Option<T>) {
+            if let Some(value) = value {
+                *field = value;
+            }
+        }
+        set(&mut login.hostname, self.hostname);
+        set(&mut login.form_submit_url, self.form_submit_url);
+        set(&mut login.http_realm, self.http_realm);
+        set(&mut login.username, self.username);
+        set(&mut login.password, self.password);
+        set(&mut login.username_field, self.username_field);
+        set(&mut login.password_field, self.password_field);
+    }
+}
+
+// The columns whose values differ between `before` and `after`.
+fn changed_columns(before: &Login, after: &Login) -> Vec<&'static str> {
+    let mut columns = vec![];
+    let mut check = |changed, column| {
+        if changed {
+            columns.push(column);
+        }
+    };
+    check(before.hostname != after.hostname, "hostname");
+    check(
+        before.form_submit_url != after.form_submit_url,
+        "formSubmitURL",
+    );
+    check(before.http_realm != after.http_realm, "httpRealm");
+    check(before.username != after.username, "username");
+    check(before.password != after.password, "password");
+    check(
+        before.username_field != after.username_field,
+        "usernameField",
+    );
+    check(
+        before.password_field != after.password_field,
+        "passwordField",
+    );
+    columns
+}
+
+impl LoginDb {
+    /// Change the fields of the record with `guid` which are set in
+    /// `changes`, and return the record as it is afterwards. Fails with
+    /// `NoSuchRecord` or `RecordDeleted` like `update`, and with
+    /// `InvalidLogin` if the changed record isn't valid, in which case
+    /// nothing is changed. See the `update_fields` module.
+    pub fn update_fields(&self, guid: &str, changes: LoginChanges) -> Result<Login> {
+        let mut op = self.begin_op("update_fields");
+        self.check_quota()?;
+        let tx = self.unchecked_transaction()?;
+        // Note: This fails with NoSuchRecord if the record doesn't exist, and
+        // RecordDeleted if it's been deleted.
+        self.ensure_local_overlay_exists(guid)?;
+        self.mark_mirror_overridden(guid)?;
+        let before = self
+            .get_by_id(guid)?
+            .ok_or_else(|| ErrorKind::NoSuchRecord(guid.to_owned()))?;
+
+        let mut merged = before.clone();
+        let form_submit_url_given = changes.form_submit_url.is_some();
+        changes.apply_to(&mut merged);
+        let mut after = self.fixup_and_check_for_dupes(merged)?;
+        // Like `update`, keep a scheme-relative formSubmitURL as it is,
+        // unless the caller changed it.
+        if !form_submit_url_given
+            && before.has_scheme_relative_form_submit_url()
+            && after.form_submit_url.is_some()
+        {
+            after.form_submit_url = before.form_submit_url.clone();
+        }
+
+        let columns = changed_columns(&before, &after);
+        if columns.is_empty() {
+            // Dropping the transaction undoes making the local overlay.
+            return Ok(before);
+        }
+
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        let password_changed = columns.contains(&"password");
+        let mut sql = String::from("UPDATE loginsL SET local_modified = :now_millis");
+        if password_changed {
+            sql.push_str(", timePasswordChanged = :now_millis");
+        }
+        for column in &columns {
+            sql.push_str(&format!(", {column} = :{column}", column = column));
+        }
+        sql.push_str(&format!(
+            ",
+             -- leave New records as they are, otherwise update them to `changed`
+             sync_status = max(sync_status, {changed}),
+             change_flags = change_flags | {fields}
+             WHERE guid = :guid",
+            changed = SyncStatus::Changed as u8,
+            fields = change_flags::FIELDS,
+        ));
+
+        let password = encryption::encrypt_password(self.encdec(), &after.password);
+        let names = columns
+            .iter()
+            .map(|column| format!(":{}", column))
+            .collect::<Vec<_>>();
+        let mut params: Vec<(&str, &dyn ToSql)> =
+            vec![(":now_millis", &now_ms), (":guid", &after.guid)];
+        for (column, name) in columns.iter().zip(&names) {
+            let value: &dyn ToSql = match *column {
+                "hostname" => &after.hostname,
+                "formSubmitURL" => &after.form_submit_url,
+                "httpRealm" => &after.http_realm,
+                "username" => &after.username,
+                "password" => &password,
+                "usernameField" => &after.username_field,
+                _ => &after.password_field,
+            };
+            params.push((name, value));
+        }
+        self.db.execute_named(&self.sql(&sql), &params)?;
+        self.note_changed(&[guid])?;
+        let updated = self
+            .get_by_id(guid)?
+            .ok_or_else(|| ErrorKind::NoSuchRecord(guid.to_owned()))?;
+        tx.commit()?;
+        op.set_rows(1);
+        Ok(updated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{sync_db, LoginFixture};
+    use sync15::ServerTimestamp;
+
+    fn login(guid: &str, hostname: &str) -> Login {
+        LoginFixture::builder()
+            .guid(guid)
+            .hostname(hostname)
+            .form_submit_url(hostname)
+            .username("user")
+            .username_field("user_input")
+            .password_field("pass_input")
+            .build()
+    }
+
+    fn outgoing(db: &LoginDb) -> Vec<Login> {
+        let scope = db.begin_interrupt_scope();
+        db.fetch_outgoing(ServerTimestamp(1000), &scope)
+            .unwrap()
+            .changes
+            .into_iter()
+            .map(|payload| payload.into_record().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_password_only() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let added = db
+            .add(login("dummy_000001", "https://www.example.com"))
+            .unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(10));
+
+        let updated = db
+            .update_fields(
+                "dummy_000001",
+                LoginChanges {
+                    password: Some("hunter2".into()),
+                    ..LoginChanges::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(updated.password, "hunter2");
+        assert!(updated.time_password_changed > added.time_password_changed);
+        // It isn't a use of the record.
+        assert_eq!(updated.times_used, added.times_used);
+        assert_eq!(updated.time_last_used, added.time_last_used);
+        assert_eq!(
+            Login {
+                password: added.password.clone(),
+                time_password_changed: added.time_password_changed,
+                ..updated.clone()
+            },
+            added
+        );
+        assert_eq!(db.get_by_id("dummy_000001").unwrap().unwrap(), updated);
+    }
+
+    #[test]
+    fn test_metadata_only() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        db.add(login("dummy_000001", "https://www.example.com"))
+            .unwrap();
+        sync_db(&db, vec![], ServerTimestamp(1000));
+        assert!(outgoing(&db).is_empty());
+        let before = db.get_by_id("dummy_000001").unwrap().unwrap();
+
+        let updated = db
+            .update_fields(
+                "dummy_000001",
+                LoginChanges {
+                    username_field: Some("email".into()),
+                    ..LoginChanges::default()
+                },
+            )
+            .unwrap();
+        assert_eq!(updated.username_field, "email");
+        assert_eq!(updated.time_password_changed, before.time_password_changed);
+        assert_eq!(updated.password, before.password);
+
+        let outgoing = outgoing(&db);
+        assert_eq!(outgoing.len(), 1);
+        let uploaded = &outgoing[0];
+        uploaded.check_valid().unwrap();
+        assert_eq!(uploaded.username_field, "email");
+        assert_eq!(uploaded.password, "password");
+        assert_eq!(uploaded.time_password_changed, before.time_password_changed);
+    }
+
+    #[test]
+    fn test_no_changes() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        db.add(login("dummy_000001", "https://www.example.com"))
+            .unwrap();
+        sync_db(&db, vec![], ServerTimestamp(1000));
+        let before = db.get_by_id("dummy_000001").unwrap().unwrap();
+        let counter = db.get_change_counter().unwrap();
+
+        // Setting fields to what they already are doesn't change anything.
+        let changes = LoginChanges {
+            username: Some("user".into()),
+            password: Some("password".into()),
+            ..LoginChanges::default()
+        };
+        assert_eq!(db.update_fields("dummy_000001", changes).unwrap(), before);
+        assert_eq!(db.get_change_counter().unwrap(), counter);
+        assert!(outgoing(&db).is_empty());
+    }
+
+    #[test]
+    fn test_invalid_merged_record() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        db.add(login("dummy_000001", "https://www.example.com"))
+            .unwrap();
+        db.add(login("dummy_000002", "https://www.example.org"))
+            .unwrap();
+        sync_db(&db, vec![], ServerTimestamp(1000));
+        let counter = db.get_change_counter().unwrap();
+
+        // Valid on its own, but it would duplicate the other record.
+        let err = db
+            .update_fields(
+                "dummy_000002",
+                LoginChanges {
+                    hostname: Some("https://www.example.com".into()),
+                    form_submit_url: Some(Some("https://www.example.com".into())),
+                    ..LoginChanges::default()
+                },
+            )
+            .unwrap_err();
+        assert!(
+            matches!(
+                err.kind(),
+                ErrorKind::InvalidLogin(InvalidLogin::DuplicateLogin)
+            ),
+            "{:?}",
+            err
+        );
+        let err = db
+            .update_fields(
+                "dummy_000002",
+                LoginChanges {
+                    password: Some("".into()),
+                    ..LoginChanges::default()
+                },
+            )
+            .unwrap_err();
+        assert!(
+            matches!(
+                err.kind(),
+                ErrorKind::InvalidLogin(InvalidLogin::EmptyPassword)
+            ),
+            "{:?}",
+            err
+        );
+
+        // Nothing was changed, including the local overlay being made.
+        assert_eq!(
+            db.get_by_id("dummy_000002").unwrap().unwrap().hostname,
+            "https://www.example.org"
+        );
+        assert_eq!(db.get_change_counter().unwrap(), counter);
+        assert!(outgoing(&db).is_empty());
+        assert!(!db.needs_consistency_repair().unwrap());
+
+        assert!(matches!(
+            db.update_fields("dummy_000003", LoginChanges::default())
+                .unwrap_err()
+                .kind(),
+            ErrorKind::NoSuchRecord(_)
+        ));
+        db.delete("dummy_000001").unwrap();
+        assert!(matches!(
+            db.update_fields("dummy_000001", LoginChanges::default())
+                .unwrap_err()
+                .kind(),
+            ErrorKind::RecordDeleted(_)
+        ));
+    }
+
+    #[test]
+    fn test_json() {
+        let changes: LoginChanges =
+            serde_json::from_str(r#"{"httpRealm": "realm", "formSubmitURL": null}"#).unwrap();
+        assert_eq!(
+            changes,
+            LoginChanges {
+                http_realm: Some(Some("realm".into())),
+                form_submit_url: Some(None),
+                ..LoginChanges::default()
+            }
+        );
+        assert_eq!(
+            serde_json::from_str::<LoginChanges>("{}").unwrap(),
+            LoginChanges::default()
+        );
+        assert_eq!(
+            serde_json::to_string(&changes).unwrap(),
+            r#"{"formSubmitURL":null,"httpRealm":"realm"}"#
+        );
+
+        // Switching a form login to an HTTP auth one.
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        db.add(login("dummy_000001", "https://www.example.com"))
+            .unwrap();
+        let updated = db.update_fields("dummy_000001", changes).unwrap();
+        assert_eq!(updated.http_realm.as_deref(), Some("realm"));
+        assert_eq!(updated.form_submit_url, None);
+        // Which can't have these.
+        assert_eq!(updated.username_field, "");
+        assert_eq!(updated.password_field, "");
+    }
+}
@@ -2,27 +2,131 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use crate::annotations;
+use crate::encryption::EncryptorDecryptor;
 use crate::error::*;
-use crate::login::{LocalLogin, Login, MirrorLogin, SyncStatus};
+use crate::login::{change_flags, LocalLogin, Login, MirrorLogin, SyncStatus};
+use crate::recent_deletions;
+use crate::schema::{self, LoginParams, TableNames, Write};
+use crate::timestamps;
 use crate::util;
+use lazy_static::lazy_static;
 use rusqlite::{named_params, Connection};
 use sql_support::SqlInterruptScope;
 use std::time::SystemTime;
 use sync15::ServerTimestamp;
 use sync_guid::Guid;
 
+/// What to do when an incoming tombstone meets a local record which still
+/// has changes to upload.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TombstonePolicy {
+    /// Keep the local record, and upload it again, if it was changed after
+    /// the record was deleted, or was never uploaded at all. This is what
+    /// Desktop does.
+    Resurrect,
+    /// Always delete the local record.
+    Delete,
+}
+
+impl Default for TombstonePolicy {
+    fn default() -> Self {
+        TombstonePolicy::Resurrect
+    }
+}
+
+impl TombstonePolicy {
+    /// Whether to keep `local` when the server tells us the record was
+    /// deleted at `deleted_at`.
+    pub(crate) fn keeps_local(
+        self,
+        local: Option<&LocalLogin>,
+        deleted_at: ServerTimestamp,
+    ) -> bool {
+        match (self, local) {
+            (TombstonePolicy::Resurrect, Some(local)) if !local.is_deleted => {
+                match local.sync_status {
+                    SyncStatus::Synced => false,
+                    // It's not the record which was deleted, even if it
+                    // shares its guid.
+                    SyncStatus::New => true,
+                    SyncStatus::Changed => {
+                        util::system_time_ms_i64(local.local_modified) > deleted_at.as_millis()
+                    }
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
 #[derive(Default, Debug, Clone)]
 pub(crate) struct UpdatePlan {
     pub delete_mirror: Vec<Guid>,
     pub delete_local: Vec<Guid>,
+    // The records deleted by incoming tombstones, which are also in
+    // `delete_mirror` and `delete_local`.
+    pub remote_deletions: Vec<Guid>,
     pub local_updates: Vec<MirrorLogin>,
     // the bool is the `is_overridden` flag, the i64 is ServerTimestamp in millis
     pub mirror_inserts: Vec<(Login, i64, bool)>,
     pub mirror_updates: Vec<(Login, i64)>,
+    // The unknown fields to store with each mirror record inserted or
+    // updated above.
+    pub mirror_unknown_fields: Vec<(Guid, Option<String>)>,
+    // How many records had to have their httpRealm and formSubmitURL
+    // repaired, reported in the sync ping.
+    pub realm_repairs: usize,
+    // How many fields both sides of a three-way merge changed differently,
+    // also reported in the sync ping.
+    pub merge_conflicts: usize,
+    // How many timestamps of incoming records had to be repaired, also
+    // reported in the sync ping.
+    pub timestamp_repairs: usize,
+}
+
+// Make sure exactly one of `http_realm` and `form_submit_url` is set, taking
+// both from the first valid login in `sources` if not. Returns whether a
+// repair was needed.
+fn repair_target(login: &mut Login, sources: &[&Login]) -> bool {
+    if login.has_valid_target() {
+        return false;
+    }
+    log::warn!("Repairing httpRealm and formSubmitURL of {}", login.guid);
+    if let Some(source) = sources.iter().find(|source| source.has_valid_target()) {
+        login.http_realm = source.http_realm.clone();
+        login.form_submit_url = source.form_submit_url.clone();
+    } else if login.form_submit_url.is_some() {
+        // This is how `fixup` resolves it, too.
+        login.http_realm = None;
+    } else {
+        // An empty formSubmitURL matches any form.
+        login.form_submit_url = Some(String::new());
+    }
+    true
 }
 
 impl UpdatePlan {
-    pub fn plan_two_way_merge(&mut self, local: &Login, upstream: (Login, ServerTimestamp)) {
+    fn repair_target(&mut self, login: &mut Login, sources: &[&Login]) {
+        if repair_target(login, sources) {
+            self.realm_repairs += 1;
+        }
+    }
+
+    // Repair `upstream`'s timestamps before it's written anywhere. See the
+    // `timestamps` module.
+    pub fn sanitize_timestamps(
+        &mut self,
+        upstream: &mut Login,
+        now_ms: i64,
+        server_now: ServerTimestamp,
+    ) {
+        self.timestamp_repairs +=
+            timestamps::sanitize_with(upstream, now_ms, server_now.as_millis());
+    }
+
+    pub fn plan_two_way_merge(&mut self, local: &Login, mut upstream: (Login, ServerTimestamp)) {
+        self.repair_target(&mut upstream.0, &[]);
         let is_override = local.time_password_changed > upstream.0.time_password_changed;
         self.mirror_inserts
             .push((upstream.0, upstream.1.as_millis() as i64, is_override));
@@ -35,7 +139,7 @@ impl UpdatePlan {
         &mut self,
         local: LocalLogin,
         shared: MirrorLogin,
-        upstream: Login,
+        mut upstream: Login,
         upstream_time: ServerTimestamp,
         server_now: ServerTimestamp,
     ) {
@@ -44,217 +148,258 @@ impl UpdatePlan {
             .unwrap_or_default();
         let remote_age = server_now.duration_since(upstream_time).unwrap_or_default();
 
-        let local_delta = local.login.delta(&shared.login);
+        let mut local_delta = local.login.delta(&shared.login);
+        if local.change_flags & change_flags::FIELDS == 0 {
+            // The record was only used here, so anything else which looks
+            // different (say, from an earlier merge) isn't a local change,
+            // and mustn't override a remote one.
+            local_delta = local_delta.usage_only();
+        }
         let upstream_delta = upstream.delta(&shared.login);
 
-        let merged_delta = local_delta.merge(upstream_delta, remote_age < local_age);
+        let remote_is_newer = remote_age < local_age;
+        let (merged_delta, conflicts) = local_delta.merge(upstream_delta, remote_is_newer);
+        self.merge_conflicts += conflicts;
 
-        // Update mirror to upstream
-        self.mirror_updates
-            .push((upstream, upstream_time.as_millis() as i64));
+        self.repair_target(&mut upstream, &[]);
         let mut new = shared;
-
         new.login.apply_delta(merged_delta);
         new.server_modified = upstream_time;
+        // The merge itself resolves the two together, but either side could
+        // have been invalid to start with.
+        let sources = if remote_is_newer {
+            [&upstream, &local.login]
+        } else {
+            [&local.login, &upstream]
+        };
+        self.repair_target(&mut new.login, &sources);
+
+        // Update mirror to upstream
+        self.mirror_updates
+            .push((upstream, upstream_time.as_millis() as i64));
         self.local_updates.push(new);
     }
 
     pub fn plan_delete(&mut self, id: Guid) {
         self.delete_local.push(id.clone());
+        self.delete_mirror.push(id.clone());
+        self.remote_deletions.push(id);
+    }
+
+    // The server deleted the record, but we're keeping our local copy, which
+    // will be uploaded again since it isn't synced. Only the mirror (which
+    // no longer reflects the server) goes.
+    pub fn plan_resurrection(&mut self, id: Guid) {
         self.delete_mirror.push(id);
     }
 
-    pub fn plan_mirror_update(&mut self, login: Login, time: ServerTimestamp) {
+    pub fn plan_mirror_update(&mut self, mut login: Login, time: ServerTimestamp) {
+        self.repair_target(&mut login, &[]);
         self.mirror_updates.push((login, time.as_millis() as i64));
     }
 
-    pub fn plan_mirror_insert(&mut self, login: Login, time: ServerTimestamp, is_override: bool) {
+    pub fn plan_mirror_insert(
+        &mut self,
+        mut login: Login,
+        time: ServerTimestamp,
+        is_override: bool,
+    ) {
+        self.repair_target(&mut login, &[]);
         self.mirror_inserts
             .push((login, time.as_millis() as i64, is_override));
     }
 
-    fn perform_deletes(&self, conn: &Connection, scope: &SqlInterruptScope) -> Result<()> {
+    // The local record is exactly what the server has, so it's synced.
+    pub fn plan_move_to_mirror(&mut self, upstream: Login, time: ServerTimestamp) {
+        self.delete_local.push(upstream.guid.clone());
+        self.plan_mirror_insert(upstream, time, false);
+    }
+
+    // The mirror always ends up with the incoming record, which is newer than
+    // whatever it had, and so with its unknown fields too. We can't merge
+    // them, since we don't know what they mean.
+    pub fn plan_unknown_fields(&mut self, id: Guid, unknown_fields: Option<String>) {
+        self.mirror_unknown_fields.push((id, unknown_fields));
+    }
+
+    fn perform_deletes(
+        &self,
+        conn: &Connection,
+        tables: &TableNames,
+        scope: &SqlInterruptScope,
+    ) -> Result<()> {
+        recent_deletions::record_remote_deletions(conn, tables, &self.remote_deletions)?;
+        scope.err_if_interrupted()?;
         sql_support::each_chunk(&self.delete_local, |chunk, _| -> Result<()> {
             conn.execute(
-                &format!(
+                &tables.resolve(&format!(
                     "DELETE FROM loginsL WHERE guid IN ({vars})",
                     vars = sql_support::repeat_sql_vars(chunk.len())
-                ),
+                )),
                 chunk,
             )?;
             scope.err_if_interrupted()?;
             Ok(())
         })?;
 
-        sql_support::each_chunk(&self.delete_mirror, |chunk, _| {
+        sql_support::each_chunk(&self.delete_mirror, |chunk, _| -> Result<()> {
             conn.execute(
-                &format!(
+                &tables.resolve(&format!(
                     "DELETE FROM loginsM WHERE guid IN ({vars})",
                     vars = sql_support::repeat_sql_vars(chunk.len())
-                ),
+                )),
                 chunk,
             )?;
             Ok(())
-        })
+        })?;
+        scope.err_if_interrupted()?;
+
+        // Annotations are local-only, so nothing else will clean up after
+        // records we've just deleted.
+        annotations::delete_orphaned_annotations(conn, tables)
     }
 
     // These aren't batched but probably should be.
-    fn perform_mirror_updates(&self, conn: &Connection, scope: &SqlInterruptScope) -> Result<()> {
-        let sql = "
-            UPDATE loginsM
-            SET server_modified = :server_modified,
-                httpRealm       = :http_realm,
-                formSubmitURL   = :form_submit_url,
-                usernameField   = :username_field,
-                passwordField   = :password_field,
-                password        = :password,
-                hostname        = :hostname,
-                username        = :username,
-                -- Avoid zeroes if the remote has been overwritten by an older client.
-                timesUsed           = coalesce(nullif(:times_used,            0), timesUsed),
-                timeLastUsed        = coalesce(nullif(:time_last_used,        0), timeLastUsed),
-                timePasswordChanged = coalesce(nullif(:time_password_changed, 0), timePasswordChanged),
-                timeCreated         = coalesce(nullif(:time_created,          0), timeCreated)
-            WHERE guid = :guid
-        ";
-        let mut stmt = conn.prepare_cached(sql)?;
+    fn perform_mirror_updates(
+        &self,
+        conn: &Connection,
+        tables: &TableNames,
+        encdec: &dyn EncryptorDecryptor,
+        scope: &SqlInterruptScope,
+    ) -> Result<()> {
+        let mut stmt = conn.prepare_cached(&tables.resolve(&MIRROR_UPDATE_SQL))?;
         for (login, timestamp) in &self.mirror_updates {
             log::trace!("Updating mirror {:?}", login.guid_str());
-            stmt.execute_named(named_params! {
-                ":server_modified": *timestamp,
-                ":http_realm": login.http_realm,
-                ":form_submit_url": login.form_submit_url,
-                ":username_field": login.username_field,
-                ":password_field": login.password_field,
-                ":password": login.password,
-                ":hostname": login.hostname,
-                ":username": login.username,
-                ":times_used": login.times_used,
-                ":time_last_used": login.time_last_used,
-                ":time_password_changed": login.time_password_changed,
-                ":time_created": login.time_created,
-                ":guid": login.guid_str(),
-            })?;
+            stmt.execute_named(&LoginParams::new(login, encdec).bind(
+                Write::MirrorUpdate,
+                named_params! { ":server_modified": *timestamp },
+            ))?;
             scope.err_if_interrupted()?;
         }
         Ok(())
     }
 
-    fn perform_mirror_inserts(&self, conn: &Connection, scope: &SqlInterruptScope) -> Result<()> {
-        let sql = "
-            INSERT OR IGNORE INTO loginsM (
-                is_overridden,
-                server_modified,
-
-                httpRealm,
-                formSubmitURL,
-                usernameField,
-                passwordField,
-                password,
-                hostname,
-                username,
-
-                timesUsed,
-                timeLastUsed,
-                timePasswordChanged,
-                timeCreated,
-
-                guid
-            ) VALUES (
-                :is_overridden,
-                :server_modified,
-
-                :http_realm,
-                :form_submit_url,
-                :username_field,
-                :password_field,
-                :password,
-                :hostname,
-                :username,
-
-                :times_used,
-                :time_last_used,
-                :time_password_changed,
-                :time_created,
-
-                :guid
-            )";
-        let mut stmt = conn.prepare_cached(&sql)?;
-
+    fn perform_mirror_inserts(
+        &self,
+        conn: &Connection,
+        tables: &TableNames,
+        encdec: &dyn EncryptorDecryptor,
+        scope: &SqlInterruptScope,
+    ) -> Result<()> {
+        let mut stmt = conn.prepare_cached(&tables.resolve(&MIRROR_INSERT_SQL))?;
         for (login, timestamp, is_overridden) in &self.mirror_inserts {
             log::trace!("Inserting mirror {:?}", login.guid_str());
+            stmt.execute_named(&LoginParams::new(login, encdec).bind(
+                Write::Insert,
+                named_params! {
+                    ":is_overridden": *is_overridden,
+                    ":server_modified": *timestamp,
+                },
+            ))?;
+            scope.err_if_interrupted()?;
+        }
+        Ok(())
+    }
+
+    fn perform_unknown_fields(
+        &self,
+        conn: &Connection,
+        tables: &TableNames,
+        scope: &SqlInterruptScope,
+    ) -> Result<()> {
+        let mut stmt = conn
+            .prepare_cached(&tables.resolve(
+                "UPDATE loginsM SET unknown_fields = :unknown_fields WHERE guid = :guid",
+            ))?;
+        for (guid, unknown_fields) in &self.mirror_unknown_fields {
             stmt.execute_named(named_params! {
-                ":is_overridden": *is_overridden,
-                ":server_modified": *timestamp,
-                ":http_realm": login.http_realm,
-                ":form_submit_url": login.form_submit_url,
-                ":username_field": login.username_field,
-                ":password_field": login.password_field,
-                ":password": login.password,
-                ":hostname": login.hostname,
-                ":username": login.username,
-                ":times_used": login.times_used,
-                ":time_last_used": login.time_last_used,
-                ":time_password_changed": login.time_password_changed,
-                ":time_created": login.time_created,
-                ":guid": login.guid_str(),
+                ":guid": guid,
+                ":unknown_fields": unknown_fields,
             })?;
             scope.err_if_interrupted()?;
         }
         Ok(())
     }
 
-    fn perform_local_updates(&self, conn: &Connection, scope: &SqlInterruptScope) -> Result<()> {
-        let sql = format!(
-            "UPDATE loginsL
-             SET local_modified      = :local_modified,
-                 httpRealm           = :http_realm,
-                 formSubmitURL       = :form_submit_url,
-                 usernameField       = :username_field,
-                 passwordField       = :password_field,
-                 timeLastUsed        = :time_last_used,
-                 timePasswordChanged = :time_password_changed,
-                 timesUsed           = :times_used,
-                 password            = :password,
-                 hostname            = :hostname,
-                 username            = :username,
-                 sync_status         = {changed}
-             WHERE guid = :guid",
-            changed = SyncStatus::Changed as u8
-        );
-        let mut stmt = conn.prepare_cached(&sql)?;
+    fn perform_local_updates(
+        &self,
+        conn: &Connection,
+        tables: &TableNames,
+        encdec: &dyn EncryptorDecryptor,
+        scope: &SqlInterruptScope,
+    ) -> Result<()> {
+        let mut stmt = conn.prepare_cached(&tables.resolve(&LOCAL_UPDATE_SQL))?;
         // XXX OutgoingChangeset should no longer have timestamp.
         let local_ms: i64 = util::system_time_ms_i64(SystemTime::now());
         for l in &self.local_updates {
             log::trace!("Updating local {:?}", l.guid_str());
-            stmt.execute_named(named_params! {
-                ":local_modified": local_ms,
-                ":http_realm": l.login.http_realm,
-                ":form_submit_url": l.login.form_submit_url,
-                ":username_field": l.login.username_field,
-                ":password_field": l.login.password_field,
-                ":password": l.login.password,
-                ":hostname": l.login.hostname,
-                ":username": l.login.username,
-                ":time_last_used": l.login.time_last_used,
-                ":time_password_changed": l.login.time_password_changed,
-                ":times_used": l.login.times_used,
-                ":guid": l.guid_str(),
-            })?;
+            stmt.execute_named(&LoginParams::new(&l.login, encdec).bind(
+                Write::LocalUpdate,
+                named_params! { ":local_modified": local_ms },
+            ))?;
             scope.err_if_interrupted()?;
         }
         Ok(())
     }
 
-    pub fn execute(&self, conn: &Connection, scope: &SqlInterruptScope) -> Result<()> {
+    /// The guids of every record this plan will touch.
+    pub fn changed_guids(&self) -> Vec<&str> {
+        let mut guids = Vec::new();
+        guids.extend(self.delete_local.iter().map(Guid::as_str));
+        guids.extend(self.delete_mirror.iter().map(Guid::as_str));
+        guids.extend(self.local_updates.iter().map(MirrorLogin::guid_str));
+        guids.extend(self.mirror_inserts.iter().map(|(l, _, _)| l.guid_str()));
+        guids.extend(self.mirror_updates.iter().map(|(l, _)| l.guid_str()));
+        guids.sort_unstable();
+        guids.dedup();
+        guids
+    }
+
+    pub fn execute(
+        &self,
+        conn: &Connection,
+        tables: &TableNames,
+        encdec: &dyn EncryptorDecryptor,
+        scope: &SqlInterruptScope,
+    ) -> Result<()> {
         log::debug!("UpdatePlan: deleting records...");
-        self.perform_deletes(conn, scope)?;
+        self.perform_deletes(conn, tables, scope)?;
         log::debug!("UpdatePlan: Updating existing mirror records...");
-        self.perform_mirror_updates(conn, scope)?;
+        self.perform_mirror_updates(conn, tables, encdec, scope)?;
         log::debug!("UpdatePlan: Inserting new mirror records...");
-        self.perform_mirror_inserts(conn, scope)?;
+        self.perform_mirror_inserts(conn, tables, encdec, scope)?;
+        log::debug!("UpdatePlan: Storing unknown fields of mirror records...");
+        self.perform_unknown_fields(conn, tables, scope)?;
         log::debug!("UpdatePlan: Updating reconciled local records...");
-        self.perform_local_updates(conn, scope)?;
+        self.perform_local_updates(conn, tables, encdec, scope)?;
         Ok(())
     }
 }
+
+lazy_static! {
+    static ref MIRROR_UPDATE_SQL: String = format!(
+        "UPDATE loginsM
+         SET server_modified = :server_modified,
+             {assignments}
+         WHERE guid = :guid",
+        assignments = schema::update_assignments(Write::MirrorUpdate),
+    );
+    static ref MIRROR_INSERT_SQL: String = format!(
+        "INSERT OR IGNORE INTO loginsM (
+            {common_cols}, is_overridden, server_modified
+         ) VALUES (
+            {common_params}, :is_overridden, :server_modified
+         )",
+        common_cols = schema::COMMON_COLS,
+        common_params = schema::COMMON_PARAMS,
+    );
+    static ref LOCAL_UPDATE_SQL: String = format!(
+        "UPDATE loginsL
+         SET local_modified = :local_modified,
+             {assignments},
+             sync_status = {changed}
+         WHERE guid = :guid",
+        assignments = schema::update_assignments(Write::LocalUpdate),
+        changed = SyncStatus::Changed as u8,
+    );
+}
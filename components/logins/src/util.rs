@@ -7,8 +7,14 @@ use rusqlite::Row;
 use std::time;
 use url::Url;
 
+/// The host and port of `url_str`, which may be scheme-relative, like the
+/// legacy `formSubmitURL`s described in `login.rs`.
 pub fn url_host_port(url_str: &str) -> Option<String> {
-    let url = Url::parse(url_str).ok()?;
+    let url = if url_str.starts_with("//") {
+        Url::parse(&format!("https:{}", url_str)).ok()?
+    } else {
+        Url::parse(url_str).ok()?
+    };
     let host = url.host_str()?;
     Some(if let Some(p) = url.port() {
         format!("{}:{}", host, p)
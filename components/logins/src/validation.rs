@@ -0,0 +1,201 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Validating a login without a database, so that frontends can check a
+//! form as the user fills it in (and, say, disable the Save button), rather
+//! than finding out when `add` or `update` fails.
+//!
+//! This uses the same rules as `add` and `update`, via
+//! `Login::maybe_fixup_all`, so a login which passes can only fail those for
+//! being a duplicate.
+
+use crate::error::*;
+use crate::login::Login;
+use serde_derive::*;
+use serde_json::Value;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "result")]
+pub enum ValidationResult {
+    /// The login can be saved. If saving it would change it (for example,
+    /// to normalize the origin), `fixed_up` is what would be saved.
+    Ok { fixed_up: Option<Login> },
+    /// The login can't be saved, for all of these reasons.
+    Err { reasons: Vec<InvalidLogin> },
+}
+
+/// Validate a login given as JSON, in the same format as `Login`'s JSON.
+/// Unlike when deserializing a `Login`, the `id`, `hostname` and `password`
+/// may be left out (as they often are, part way through filling in a form),
+/// and are treated as empty. Fails only if `record_json` isn't a JSON object
+/// with fields of the right types.
+pub fn validate(record_json: &str) -> Result<ValidationResult> {
+    let mut record: serde_json::Map<String, Value> = serde_json::from_str(record_json)?;
+    for field in &["id", "hostname", "password"] {
+        record
+            .entry(*field)
+            .or_insert_with(|| Value::String(String::new()));
+    }
+    let login: Login = serde_json::from_value(Value::Object(record))?;
+    Ok(match login.maybe_fixup_all() {
+        Ok(fixed_up) => ValidationResult::Ok { fixed_up },
+        Err(reasons) => ValidationResult::Err { reasons },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LoginDb;
+    use serde_json::json;
+
+    fn reasons(record: Value) -> Vec<InvalidLogin> {
+        match validate(&record.to_string()).unwrap() {
+            ValidationResult::Err { reasons } => reasons,
+            ValidationResult::Ok { .. } => panic!("{} should be invalid", record),
+        }
+    }
+
+    fn illegal(field_info: &str) -> InvalidLogin {
+        InvalidLogin::IllegalFieldValue {
+            field_info: field_info.into(),
+        }
+    }
+
+    #[test]
+    fn test_valid() {
+        let result = validate(
+            &json!({
+                "hostname": "https://www.example.com",
+                "formSubmitURL": "https://www.example.com",
+                "password": "password",
+            })
+            .to_string(),
+        )
+        .unwrap();
+        assert_eq!(result, ValidationResult::Ok { fixed_up: None });
+
+        let result = validate(
+            &json!({
+                "hostname": "https://www.example.com/path?query",
+                "httpRealm": "realm",
+                "formSubmitURL": "https://www.example.com",
+                "password": "password",
+            })
+            .to_string(),
+        )
+        .unwrap();
+        match result {
+            ValidationResult::Ok {
+                fixed_up: Some(login),
+            } => {
+                assert_eq!(login.hostname, "https://www.example.com");
+                assert_eq!(login.http_realm, None);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_broken_records() {
+        let cases = vec![
+            (
+                json!({}),
+                vec![
+                    InvalidLogin::EmptyOrigin,
+                    InvalidLogin::EmptyPassword,
+                    InvalidLogin::NoTarget,
+                ],
+            ),
+            (
+                json!({"hostname": "https://www.example.com", "httpRealm": "realm"}),
+                vec![InvalidLogin::EmptyPassword],
+            ),
+            (
+                json!({
+                    "hostname": "not a url",
+                    "formSubmitURL": "also\nnot a url",
+                    "password": "password",
+                }),
+                vec![
+                    illegal("`formSubmitUrl` contains newline"),
+                    illegal("Origin is Malformed"),
+                    illegal("Origin is Malformed"),
+                ],
+            ),
+            (
+                json!({
+                    "hostname": "https://www.example.com",
+                    "httpRealm": "realm\0",
+                    "usernameField": ".",
+                    "password": "pass\0word",
+                }),
+                vec![
+                    illegal("`httpRealm` contains Nul"),
+                    illegal("`password` contains Nul"),
+                    illegal("`usernameField` is a period"),
+                ],
+            ),
+            (
+                json!({
+                    "hostname": "https://www.example.com\r",
+                    "formSubmitURL": "",
+                    "usernameField": "user\nname",
+                }),
+                vec![
+                    InvalidLogin::EmptyPassword,
+                    illegal("`hostname` contains newline"),
+                    illegal("`usernameField` contains newline"),
+                ],
+            ),
+        ];
+        for (record, expected) in cases {
+            assert_eq!(reasons(record.clone()), expected, "{}", record);
+        }
+    }
+
+    #[test]
+    fn test_bad_json() {
+        assert!(validate("not json").is_err());
+        assert!(validate("[]").is_err());
+        assert!(validate(r#"{"password": 1}"#).is_err());
+    }
+
+    #[test]
+    fn test_serialized() {
+        let result =
+            validate(r#"{"hostname": "https://www.example.com", "httpRealm": "."}"#).unwrap();
+        assert_eq!(
+            serde_json::to_value(&result).unwrap(),
+            json!({"result": "Err", "reasons": [{"reason": "EmptyPassword"}]})
+        );
+        let result = validate(r#"{"hostname": "x", "httpRealm": "r", "password": "p"}"#).unwrap();
+        assert_eq!(
+            serde_json::to_value(&result).unwrap(),
+            json!({
+                "result": "Err",
+                "reasons": [{"reason": "IllegalFieldValue", "field_info": "Origin is Malformed"}],
+            })
+        );
+    }
+
+    #[test]
+    fn test_matches_add() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let records = vec![
+            json!({"id": "", "hostname": "https://www.example.com", "formSubmitURL": "", "password": "p"}),
+            json!({"id": "", "hostname": "https://www.example.org/x", "httpRealm": "r", "password": "p"}),
+            json!({"id": "", "hostname": "https://www.example.net", "password": "p"}),
+            json!({"id": "", "hostname": "https://www.example.net", "httpRealm": "r", "password": ""}),
+        ];
+        for record in records {
+            let valid = match validate(&record.to_string()).unwrap() {
+                ValidationResult::Ok { .. } => true,
+                ValidationResult::Err { .. } => false,
+            };
+            let login: Login = serde_json::from_value(record.clone()).unwrap();
+            assert_eq!(db.add(login).is_ok(), valid, "{}", record);
+        }
+    }
+}
@@ -52,6 +52,17 @@ pub enum BroadcastValue {
     Nested(HashMap<String, BroadcastValue>),
 }
 
+/// Map an error status or bad body from the server to our error: client
+/// errors are our fault, anything else is the server's.
+fn response_error(context: &str, e: viaduct::Error) -> error::Error {
+    match e {
+        viaduct::Error::HttpStatus { code, .. } if status_codes::is_client_error_code(code) => {
+            CommunicationError(format!("{}: {}", context, e)).into()
+        }
+        _ => CommunicationServerError(format!("{}: {}", context, e)).into(),
+    }
+}
+
 /// A new communication link to the Autopush server
 pub trait Connection {
     // get the connection UAID
@@ -205,28 +216,13 @@ impl Connection for ConnectHttp {
                 );
             }
         };
-        if requested.is_server_error() {
-            return Err(CommunicationServerError("General Server error".to_string()).into());
-        }
-        if requested.is_client_error() {
-            if requested.status == status_codes::CONFLICT {
-                return Err(AlreadyRegisteredError.into());
-            }
-            return Err(CommunicationError(format!(
-                "Unhandled client error {} : {:?}",
-                requested.status,
-                String::from_utf8_lossy(&requested.body)
-            ))
-            .into());
+        if requested.status == status_codes::CONFLICT {
+            return Err(AlreadyRegisteredError.into());
         }
-        let response: Value = match requested.json() {
-            Ok(v) => v,
-            Err(e) => {
-                return Err(
-                    CommunicationServerError(format!("Could not parse response: {:?}", e)).into(),
-                );
-            }
-        };
+        let response: Value = requested
+            .check_success()
+            .and_then(|r| r.parse_json())
+            .map_err(|e| response_error("Could not register", e))?;
 
         if self.uaid.is_none() {
             self.uaid = response["uaid"].as_str().map(ToString::to_string);
@@ -357,22 +353,10 @@ impl Connection for ConnectHttp {
                 .into());
             }
         };
-        if request.is_server_error() {
-            return Err(CommunicationServerError("Server error".to_string()).into());
-        }
-        if request.is_client_error() {
-            return Err(CommunicationError(format!("Unhandled client error {:?}", request)).into());
-        }
-        let payload: Payload = match request.json() {
-            Ok(p) => p,
-            Err(e) => {
-                return Err(CommunicationServerError(format!(
-                    "Could not fetch channel_list: Bad Response {:?}",
-                    e
-                ))
-                .into());
-            }
-        };
+        let payload: Payload = request
+            .check_success()
+            .and_then(|r| r.parse_json())
+            .map_err(|e| response_error("Could not fetch channel_list", e))?;
         if payload.uaid != self.uaid.clone().unwrap() {
             return Err(
                 CommunicationServerError("Invalid Response from server".to_string()).into(),
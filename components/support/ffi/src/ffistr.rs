@@ -17,6 +17,59 @@
 use std::ffi::CStr;
 use std::marker::PhantomData;
 use std::os::raw::c_char;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The default limit on the length (in bytes) of strings we'll accept from the
+/// other side of the FFI. See [`set_max_ffi_str_len`].
+pub const MAX_FFI_STR_LEN: usize = 8 * 1024 * 1024;
+
+static MAX_LEN: AtomicUsize = AtomicUsize::new(MAX_FFI_STR_LEN);
+
+/// Change the limit on the length (in bytes) of strings accepted by [`FfiStr`]
+/// and [`FfiStrWithLen`], which defaults to [`MAX_FFI_STR_LEN`].
+///
+/// Longer strings are treated as invalid: an error is logged, and the
+/// accessors behave as they do for invalid UTF-8. This mostly exists so that
+/// a pointer to something which isn't a nul-terminated string results in an
+/// error, rather than in us reading (and copying) arbitrary amounts of memory.
+pub fn set_max_ffi_str_len(len: usize) {
+    MAX_LEN.store(len, Ordering::Relaxed);
+}
+
+#[inline]
+fn max_ffi_str_len() -> usize {
+    MAX_LEN.load(Ordering::Relaxed)
+}
+
+// Find the length of a nul-terminated string, giving up once we've looked at
+// more than `max` bytes.
+//
+// Safety: `ptr` must be non-null, and valid up to its nul terminator, or
+// `max` bytes, whichever comes first.
+unsafe fn bounded_strlen(ptr: *const c_char, max: usize) -> Option<usize> {
+    let mut len = 0;
+    while *ptr.add(len) != 0 {
+        if len == max {
+            log::error!(
+                "String passed to rust is longer than {} bytes, or isn't nul-terminated",
+                max
+            );
+            return None;
+        }
+        len += 1;
+    }
+    Some(len)
+}
+
+fn bytes_to_opt_str(bytes: Option<&[u8]>) -> Option<&str> {
+    match std::str::from_utf8(bytes?) {
+        Ok(s) => Some(s),
+        Err(e) => {
+            log::error!("Invalid UTF-8 was passed to rust! {:?}", e);
+            None
+        }
+    }
+}
 
 /// `FfiStr<'a>` is a safe (`#[repr(transparent)]`) wrapper around a
 /// nul-terminated `*const c_char` (e.g. a C string). Conceptually, it is
@@ -94,6 +147,42 @@ impl<'a> FfiStr<'a> {
         }
     }
 
+    /// Construct an [`FfiStrWithLen`] from a raw pointer and the length of the
+    /// string in bytes, not including any nul terminator.
+    ///
+    /// This is for use when the caller already knows the length of the
+    /// string (as is often the case on the other side of the FFI), and avoids
+    /// scanning for the nul terminator, which needn't be present at all. It
+    /// returns a separate type because `FfiStr` must remain pointer-sized for
+    /// use in the signatures of extern "C" functions.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must either be null, or valid for reads of `len` bytes for the
+    /// lifetime `'a`.
+    #[inline]
+    pub unsafe fn from_raw_parts(ptr: *const c_char, len: usize) -> FfiStrWithLen<'a> {
+        FfiStrWithLen {
+            ptr,
+            len,
+            _boo: PhantomData,
+        }
+    }
+
+    fn as_opt_bytes(&self) -> Option<&'a [u8]> {
+        self.as_opt_bytes_with_max(max_ffi_str_len())
+    }
+
+    fn as_opt_bytes_with_max(&self, max: usize) -> Option<&'a [u8]> {
+        if self.cstr.is_null() {
+            return None;
+        }
+        unsafe {
+            let len = bounded_strlen(self.cstr, max)?;
+            Some(std::slice::from_raw_parts(self.cstr as *const u8, len))
+        }
+    }
+
     /// Get an `&str` out of the `FfiStr`. This will panic in any case that
     /// [`FfiStr::as_opt_str`] would return `None` (e.g. null pointer or invalid
     /// UTF-8).
@@ -109,30 +198,20 @@ impl<'a> FfiStr<'a> {
 
     /// Get an `Option<&str>` out of the `FfiStr`. If this stores a null
     /// pointer, then None will be returned. If a string containing invalid
-    /// UTF-8 was passed, then an error will be logged and `None` will be
-    /// returned.
+    /// UTF-8, or longer than the limit set by [`set_max_ffi_str_len`], was
+    /// passed, then an error will be logged and `None` will be returned.
     ///
     /// If the string is a required argument, use [`FfiStr::as_str`], or
     /// [`FfiStr::into_string`] instead. If `Option<String>` is desired, use
     /// [`FfiStr::into_opt_string`] (which will handle invalid UTF-8 by
     /// replacing with the replacement character).
     pub fn as_opt_str(&self) -> Option<&'a str> {
-        if self.cstr.is_null() {
-            return None;
-        }
-        unsafe {
-            match std::ffi::CStr::from_ptr(self.cstr).to_str() {
-                Ok(s) => Some(s),
-                Err(e) => {
-                    log::error!("Invalid UTF-8 was passed to rust! {:?}", e);
-                    None
-                }
-            }
-        }
+        bytes_to_opt_str(self.as_opt_bytes())
     }
 
     /// Get an `Option<String>` out of the `FfiStr`. Returns `None` if this
-    /// `FfiStr` holds a null pointer. Note that unlike [`FfiStr::as_opt_str`],
+    /// `FfiStr` holds a null pointer, or a string longer than the limit set by
+    /// [`set_max_ffi_str_len`]. Note that unlike [`FfiStr::as_opt_str`],
     /// invalid UTF-8 is replaced with the replacement character instead of
     /// causing us to return None.
     ///
@@ -142,11 +221,8 @@ impl<'a> FfiStr<'a> {
     /// (however, note the differences in how invalid UTF-8 is handled, should
     /// this be relevant to your use).
     pub fn into_opt_string(self) -> Option<String> {
-        if !self.cstr.is_null() {
-            unsafe { Some(CStr::from_ptr(self.cstr).to_string_lossy().to_string()) }
-        } else {
-            None
-        }
+        self.as_opt_bytes()
+            .map(|bytes| String::from_utf8_lossy(bytes).into_owned())
     }
 
     /// Get a `String` out of a `FfiStr`. This function is essential a
@@ -177,76 +253,263 @@ impl<'a> std::fmt::Debug for FfiStr<'a> {
     }
 }
 
-// Conversions...
+/// `FfiStrWithLen<'a>` is a string passed over the FFI as a pointer and a
+/// length in bytes, constructed with [`FfiStr::from_raw_parts`].
+///
+/// It has the same accessors, conversions and comparisons as [`FfiStr`], and
+/// behaves identically for the same string, except that it never looks past
+/// the end of the string for a nul terminator. A string containing a nul byte
+/// is treated as invalid (as it would be silently truncated if it were passed
+/// as an `FfiStr`), as is one longer than the limit set by
+/// [`set_max_ffi_str_len`].
+pub struct FfiStrWithLen<'a> {
+    ptr: *const c_char,
+    len: usize,
+    _boo: PhantomData<&'a ()>,
+}
 
-impl<'a> From<FfiStr<'a>> for String {
-    #[inline]
-    fn from(f: FfiStr<'a>) -> Self {
-        f.into_string()
+impl<'a> FfiStrWithLen<'a> {
+    fn as_opt_bytes(&self) -> Option<&'a [u8]> {
+        if self.ptr.is_null() {
+            return None;
+        }
+        let max = max_ffi_str_len();
+        if self.len > max {
+            log::error!(
+                "String passed to rust is {} bytes long, more than the limit of {}",
+                self.len,
+                max
+            );
+            return None;
+        }
+        let bytes = unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) };
+        if bytes.contains(&0) {
+            log::error!("String passed to rust contains a nul byte");
+            return None;
+        }
+        Some(bytes)
     }
-}
 
-impl<'a> From<FfiStr<'a>> for Option<String> {
+    /// As [`FfiStr::as_str`].
     #[inline]
-    fn from(f: FfiStr<'a>) -> Self {
-        f.into_opt_string()
+    pub fn as_str(&self) -> &'a str {
+        self.as_opt_str()
+            .expect("Unexpected null string pointer passed to rust")
     }
-}
 
-impl<'a> From<FfiStr<'a>> for Option<&'a str> {
+    /// As [`FfiStr::as_opt_str`]. A string containing a nul byte is treated
+    /// as invalid.
+    pub fn as_opt_str(&self) -> Option<&'a str> {
+        bytes_to_opt_str(self.as_opt_bytes())
+    }
+
+    /// As [`FfiStr::into_opt_string`]. A string containing a nul byte is
+    /// treated as invalid, and `None` is returned.
+    pub fn into_opt_string(self) -> Option<String> {
+        self.as_opt_bytes()
+            .map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    /// As [`FfiStr::into_string`].
     #[inline]
-    fn from(f: FfiStr<'a>) -> Self {
-        f.as_opt_str()
+    pub fn into_string(self) -> String {
+        self.into_opt_string()
+            .expect("Unexpected null string pointer passed to rust")
     }
 }
 
-impl<'a> From<FfiStr<'a>> for &'a str {
-    #[inline]
-    fn from(f: FfiStr<'a>) -> Self {
-        f.as_str()
+impl<'a> std::fmt::Debug for FfiStrWithLen<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(s) = self.as_opt_str() {
+            write!(f, "FfiStrWithLen({:?})", s)
+        } else {
+            write!(f, "FfiStrWithLen(null)")
+        }
     }
 }
 
-// TODO: `AsRef<str>`?
+// Both string types have the same conversions and comparisons, which are
+// implemented in terms of their (identically behaving) accessors.
+macro_rules! impl_ffi_str_traits {
+    ($ty:ident) => {
+        // Conversions...
+
+        impl<'a> From<$ty<'a>> for String {
+            #[inline]
+            fn from(f: $ty<'a>) -> Self {
+                f.into_string()
+            }
+        }
+
+        impl<'a> From<$ty<'a>> for Option<String> {
+            #[inline]
+            fn from(f: $ty<'a>) -> Self {
+                f.into_opt_string()
+            }
+        }
+
+        impl<'a> From<$ty<'a>> for Option<&'a str> {
+            #[inline]
+            fn from(f: $ty<'a>) -> Self {
+                f.as_opt_str()
+            }
+        }
+
+        impl<'a> From<$ty<'a>> for &'a str {
+            #[inline]
+            fn from(f: $ty<'a>) -> Self {
+                f.as_str()
+            }
+        }
+
+        // TODO: `AsRef<str>`?
+
+        // Comparisons...
+
+        // Compare with eachother
+        impl<'a> PartialEq for $ty<'a> {
+            #[inline]
+            fn eq(&self, other: &$ty<'a>) -> bool {
+                self.as_opt_str() == other.as_opt_str()
+            }
+        }
+
+        // Compare with str
+        impl<'a> PartialEq<str> for $ty<'a> {
+            #[inline]
+            fn eq(&self, other: &str) -> bool {
+                self.as_opt_str() == Some(other)
+            }
+        }
 
-// Comparisons...
+        // Compare with &str
+        impl<'a, 'b> PartialEq<&'b str> for $ty<'a> {
+            #[inline]
+            fn eq(&self, other: &&'b str) -> bool {
+                self.as_opt_str() == Some(*other)
+            }
+        }
 
-// Compare FfiStr with eachother
-impl<'a> PartialEq for FfiStr<'a> {
+        // rhs/lhs swap version of above
+        impl<'a> PartialEq<$ty<'a>> for str {
+            #[inline]
+            fn eq(&self, other: &$ty<'a>) -> bool {
+                Some(self) == other.as_opt_str()
+            }
+        }
+
+        // rhs/lhs swap...
+        impl<'a, 'b> PartialEq<$ty<'a>> for &'b str {
+            #[inline]
+            fn eq(&self, other: &$ty<'a>) -> bool {
+                Some(*self) == other.as_opt_str()
+            }
+        }
+    };
+}
+
+impl_ffi_str_traits!(FfiStr);
+impl_ffi_str_traits!(FfiStrWithLen);
+
+// Compare the two string types with eachother
+impl<'a, 'b> PartialEq<FfiStrWithLen<'b>> for FfiStr<'a> {
     #[inline]
-    fn eq(&self, other: &FfiStr<'a>) -> bool {
+    fn eq(&self, other: &FfiStrWithLen<'b>) -> bool {
         self.as_opt_str() == other.as_opt_str()
     }
 }
 
-// Compare FfiStr with str
-impl<'a> PartialEq<str> for FfiStr<'a> {
+impl<'a, 'b> PartialEq<FfiStr<'b>> for FfiStrWithLen<'a> {
     #[inline]
-    fn eq(&self, other: &str) -> bool {
-        self.as_opt_str() == Some(other)
+    fn eq(&self, other: &FfiStr<'b>) -> bool {
+        self.as_opt_str() == other.as_opt_str()
     }
 }
 
-// Compare FfiStr with &str
-impl<'a, 'b> PartialEq<&'b str> for FfiStr<'a> {
-    #[inline]
-    fn eq(&self, other: &&'b str) -> bool {
-        self.as_opt_str() == Some(*other)
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ffi::CString;
+
+    #[test]
+    fn test_from_raw_parts() {
+        let s = "hello, world";
+        let with_len = unsafe { FfiStr::from_raw_parts(s.as_ptr() as *const c_char, s.len()) };
+        assert_eq!(with_len.as_str(), s);
+        assert_eq!(with_len.into_string(), s);
+
+        // Only the first `len` bytes are looked at.
+        let with_len = unsafe { FfiStr::from_raw_parts(s.as_ptr() as *const c_char, 5) };
+        assert_eq!(with_len.as_opt_str(), Some("hello"));
+
+        let null = unsafe { FfiStr::from_raw_parts(std::ptr::null(), 5) };
+        assert_eq!(null.as_opt_str(), None);
+        assert_eq!(null.into_opt_string(), None);
+
+        // A nul byte inside the string would have truncated it as an `FfiStr`.
+        let s = b"hello\0world";
+        let with_len = unsafe { FfiStr::from_raw_parts(s.as_ptr() as *const c_char, s.len()) };
+        assert_eq!(with_len.as_opt_str(), None);
+        assert_eq!(with_len.into_opt_string(), None);
+
+        let s = b"\xff\xfe";
+        let with_len = unsafe { FfiStr::from_raw_parts(s.as_ptr() as *const c_char, s.len()) };
+        assert_eq!(with_len.as_opt_str(), None);
+        assert_eq!(with_len.into_opt_string().unwrap(), "\u{FFFD}\u{FFFD}");
     }
-}
 
-// rhs/lhs swap version of above
-impl<'a> PartialEq<FfiStr<'a>> for str {
-    #[inline]
-    fn eq(&self, other: &FfiStr<'a>) -> bool {
-        Some(self) == other.as_opt_str()
+    #[test]
+    fn test_length_guard() {
+        // A length which would have us read far beyond the end of the string
+        // is rejected without looking at the string at all.
+        let s = "hello";
+        let huge =
+            unsafe { FfiStr::from_raw_parts(s.as_ptr() as *const c_char, MAX_FFI_STR_LEN + 1) };
+        assert_eq!(huge.as_opt_str(), None);
+        let huge = unsafe { FfiStr::from_raw_parts(s.as_ptr() as *const c_char, usize::MAX) };
+        assert_eq!(huge.into_opt_string(), None);
+
+        // Likewise for the nul-terminated path, with a smaller limit.
+        let cstring = CString::new("hello").unwrap();
+        let ffi_str = FfiStr::from_cstr(&cstring);
+        assert_eq!(ffi_str.as_opt_bytes_with_max(4), None);
+        assert_eq!(ffi_str.as_opt_bytes_with_max(5), Some(&b"hello"[..]));
+        assert_eq!(ffi_str.as_opt_bytes(), Some(&b"hello"[..]));
     }
-}
 
-// rhs/lhs swap...
-impl<'a, 'b> PartialEq<FfiStr<'a>> for &'b str {
-    #[inline]
-    fn eq(&self, other: &FfiStr<'a>) -> bool {
-        Some(*self) == other.as_opt_str()
+    #[test]
+    fn test_parity() {
+        let cases: &[Option<&str>] = &[None, Some(""), Some("hello"), Some("h\u{e9}llo \u{1F600}")];
+        for case in cases {
+            let cstring = case.map(|s| CString::new(s).unwrap());
+            let ffi_str = match &cstring {
+                Some(c) => FfiStr::from_cstr(c),
+                None => unsafe { FfiStr::from_raw(std::ptr::null()) },
+            };
+            let with_len = unsafe {
+                match case {
+                    Some(s) => FfiStr::from_raw_parts(s.as_ptr() as *const c_char, s.len()),
+                    None => FfiStr::from_raw_parts(std::ptr::null(), 0),
+                }
+            };
+
+            assert_eq!(ffi_str, with_len);
+            assert_eq!(with_len, ffi_str);
+            assert_eq!(ffi_str.as_opt_str(), with_len.as_opt_str());
+            assert_eq!(
+                format!("{:?}", ffi_str),
+                format!("{:?}", with_len).replacen("FfiStrWithLen", "FfiStr", 1)
+            );
+            if let Some(s) = case {
+                assert!(ffi_str == *s && with_len == *s);
+                assert!(*s == ffi_str && *s == with_len);
+                assert_eq!(<&str>::from(with_len), <&str>::from(ffi_str));
+            } else {
+                assert_eq!(
+                    Option::<String>::from(ffi_str),
+                    Option::<String>::from(with_len)
+                );
+            }
+        }
     }
 }
@@ -103,7 +103,8 @@
 //!    do as their name suggest.
 //!
 //! Additionally, c strings that are passed in as arguments may be represented using [`FfiStr`],
-//! which contains several helpful inherent methods for extracting their data.
+//! which contains several helpful inherent methods for extracting their data. Binary data (such
+//! as an encoded protobuf) may be passed in without copying using [`BorrowedBuffer`].
 //!
 
 use std::{panic, thread};
@@ -117,7 +118,7 @@ mod macros;
 mod string;
 
 pub use crate::error::*;
-pub use crate::ffistr::FfiStr;
+pub use crate::ffistr::{set_max_ffi_str_len, FfiStr, FfiStrWithLen, MAX_FFI_STR_LEN};
 pub use crate::into_ffi::*;
 pub use crate::macros::*;
 pub use crate::string::*;
@@ -447,11 +448,28 @@ impl ByteBuffer {
         ByteBuffer::from_vec(buf)
     }
 
+    /// The canonical empty `ByteBuffer`, with a length of zero and a null data
+    /// pointer. This is the same as `ByteBuffer::default()`, and owns no
+    /// memory, so destroying it is optional.
+    #[inline]
+    pub const fn empty() -> Self {
+        Self {
+            len: 0_i64,
+            data: std::ptr::null_mut(),
+        }
+    }
+
     /// Creates a `ByteBuffer` instance from a `Vec` instance.
     ///
     /// The contents of the vector will not be dropped. Instead, `destroy` must
     /// be called later to reclaim this memory or it will be leaked.
     ///
+    /// The vector's allocation is reused without copying only if its capacity
+    /// is exactly its length. Otherwise, it's first shrunk to fit, which may
+    /// reallocate and copy the data. To avoid this for large buffers, build
+    /// the vector with `Vec::with_capacity` of the exact final size (as
+    /// [`implement_into_ffi_by_protobuf!`] does with `encoded_len`).
+    ///
     /// ## Caveats
     ///
     /// This will panic if the buffer length (`usize`) cannot fit into a `i64`.
@@ -572,9 +590,100 @@ impl ByteBuffer {
 impl Default for ByteBuffer {
     #[inline]
     fn default() -> Self {
+        Self::empty()
+    }
+}
+
+/// BorrowedBuffer is a read-only view of an array of bytes owned by the other
+/// side of the FFI, used to pass binary data (typically an encoded protobuf)
+/// *into* Rust without copying it or transferring ownership. It's to
+/// [`ByteBuffer`] what [`FfiStr`] is to a Rust-allocated string.
+///
+/// ## Ownership
+///
+/// - The memory belongs to the caller, who must keep it alive and unmodified
+///   until the function it was passed to returns, and who is responsible for
+///   freeing it afterwards (using whatever allocator it came from).
+/// - Rust never frees or writes through a `BorrowedBuffer`. In particular, it
+///   must *not* be passed to a [`ByteBuffer`] destructor.
+/// - A `BorrowedBuffer` (and anything borrowed from it) must not outlive the
+///   call it was passed to, so functions accepting one should not specify the
+///   lifetime as `'static`, for the same reasons described on [`FfiStr`].
+///   Anything which needs to outlive the call must be copied or decoded into
+///   owned data first.
+///
+/// ## Layout/fields
+///
+/// This has the same layout as [`ByteBuffer`], and if it were a C struct, it
+/// would look like
+///
+/// ```c,no_run
+/// struct BorrowedBuffer {
+///     // Note: This should never be negative.
+///     int64_t len;
+///     // Note: nullable, in which case `len` is ignored.
+///     const uint8_t *data;
+/// };
+/// ```
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct BorrowedBuffer<'a> {
+    len: i64,
+    data: *const u8,
+    _boo: std::marker::PhantomData<&'a [u8]>,
+}
+
+impl<'a> BorrowedBuffer<'a> {
+    /// Borrow a `&[u8]` as a `BorrowedBuffer`. This is provided for
+    /// completeness (and for tests), as a safe way of producing one in Rust.
+    ///
+    /// ## Caveats
+    ///
+    /// This will panic if the slice length (`usize`) cannot fit into a `i64`.
+    #[inline]
+    pub fn from_slice(bytes: &'a [u8]) -> Self {
+        use std::convert::TryFrom;
         Self {
-            len: 0_i64,
-            data: std::ptr::null_mut(),
+            len: i64::try_from(bytes.len()).expect("buffer length cannot fit into a i64."),
+            data: bytes.as_ptr(),
+            _boo: std::marker::PhantomData,
+        }
+    }
+
+    /// Construct a `BorrowedBuffer` from a raw pointer and length.
+    ///
+    /// This should not be needed most of the time, and users should instead
+    /// accept `BorrowedBuffer` in function parameter lists.
+    ///
+    /// # Safety
+    ///
+    /// `data` must either be null, or valid for reads of `len` bytes for the
+    /// lifetime `'a`.
+    #[inline]
+    pub unsafe fn from_raw_parts(data: *const u8, len: i64) -> Self {
+        Self {
+            len,
+            data,
+            _boo: std::marker::PhantomData,
+        }
+    }
+
+    /// View the data as a `&[u8]`. A null data pointer is treated as empty.
+    ///
+    /// ## Caveats
+    ///
+    /// This will panic if the length is negative.
+    #[inline]
+    pub fn as_slice(&self) -> &'a [u8] {
+        use std::convert::TryInto;
+        if self.data.is_null() {
+            &[]
+        } else {
+            let len = self
+                .len
+                .try_into()
+                .expect("BorrowedBuffer length negative or overflowed");
+            unsafe { std::slice::from_raw_parts(self.data, len) }
         }
     }
 }
@@ -582,6 +691,51 @@ impl Default for ByteBuffer {
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::convert::TryFrom;
+
+    // Counts the allocations made on the current thread, while enabled.
+    struct CountingAllocator;
+
+    thread_local! {
+        static COUNT_ALLOCATIONS: Cell<bool> = Cell::new(false);
+        static ALLOCATIONS: Cell<usize> = Cell::new(0);
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            if COUNT_ALLOCATIONS.with(Cell::get) {
+                ALLOCATIONS.with(|count| count.set(count.get() + 1));
+            }
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    fn count_allocations<T>(f: impl FnOnce() -> T) -> (T, usize) {
+        ALLOCATIONS.with(|count| count.set(0));
+        COUNT_ALLOCATIONS.with(|enabled| enabled.set(true));
+        let result = f();
+        COUNT_ALLOCATIONS.with(|enabled| enabled.set(false));
+        (result, ALLOCATIONS.with(Cell::get))
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct TestMessage {
+        #[prost(string, tag = "1")]
+        name: String,
+        #[prost(bytes, tag = "2")]
+        payload: Vec<u8>,
+    }
+
+    implement_into_ffi_by_protobuf_borrowed!(TestMessage);
     #[test]
     fn test_bb_access() {
         let mut bb = ByteBuffer::from(vec![1u8, 2, 3]);
@@ -619,5 +773,44 @@ mod test {
         assert_eq!(bb.as_slice(), &[]);
         assert!(!bb.data.is_null());
         bb.destroy();
+
+        let bb = ByteBuffer::empty();
+        assert_eq!(bb.as_slice(), &[]);
+        assert!(bb.data.is_null());
+        bb.destroy();
+    }
+
+    #[test]
+    fn test_borrowed_buffer() {
+        let bytes = vec![1u8, 2, 3];
+        let borrowed = BorrowedBuffer::from_slice(&bytes);
+        assert_eq!(borrowed.as_slice(), &[1u8, 2, 3]);
+        assert_eq!(borrowed.as_slice().as_ptr(), bytes.as_ptr());
+
+        let borrowed = unsafe { BorrowedBuffer::from_raw_parts(std::ptr::null(), 10) };
+        assert_eq!(borrowed.as_slice(), &[]);
+    }
+
+    #[test]
+    fn test_protobuf_round_trip() {
+        let message = TestMessage {
+            name: String::new(),
+            payload: (0..4 * 1024 * 1024).map(|i| i as u8).collect(),
+        };
+        let outbound = message.clone();
+        let (bb, allocations) = count_allocations(|| outbound.into_ffi_value());
+        assert_eq!(allocations, 1);
+
+        // Pretend the other side of the FFI passed the buffer back to us.
+        let borrowed = BorrowedBuffer::from_slice(bb.as_slice());
+        let (decoded, allocations) = count_allocations(|| TestMessage::try_from(borrowed).unwrap());
+        assert_eq!(decoded, message);
+        // Only the payload itself is allocated.
+        assert_eq!(allocations, 1);
+        bb.destroy();
+
+        let empty = TestMessage::try_from(BorrowedBuffer::from_slice(&[])).unwrap();
+        assert_eq!(empty, TestMessage::default());
+        assert!(TestMessage::try_from(BorrowedBuffer::from_slice(&[0xff, 0xff])).is_err());
     }
 }
@@ -127,6 +127,49 @@ macro_rules! implement_into_ffi_by_protobuf {
     )*}
 }
 
+/// Implements [`IntoFfi`] for the provided types by converting to a [`ByteBuffer`], exactly as
+/// [`implement_into_ffi_by_protobuf!`] does, and additionally implements
+/// `TryFrom<BorrowedBuffer<'_>>` for them, so that FFI functions may accept them as
+/// [`BorrowedBuffer`] parameters.
+///
+/// Decoding reads directly from the memory owned by the caller, without copying it into a Rust
+/// allocation first; only the decoded message's own fields are allocated. The error type is
+/// `prost::DecodeError`.
+///
+/// ```rust,ignore
+/// #[no_mangle]
+/// pub extern "C" fn mylib_do_something(
+///     buf: BorrowedBuffer<'_>,
+///     error: &mut ExternError,
+/// ) -> ByteBuffer {
+///     call_with_result(error, || -> Result<MyResponse, MyError> {
+///         let request = MyRequest::try_from(buf)?;
+///         // ...
+///     })
+/// }
+/// ```
+///
+/// Note: for this to works, the crate it's called in must depend on `prost`.
+///
+/// Note: Each type passed in must implement or derive `prost::Message` and `Default`.
+#[macro_export]
+macro_rules! implement_into_ffi_by_protobuf_borrowed {
+    ($($FFIType:ty),* $(,)*) => {$(
+        $crate::implement_into_ffi_by_protobuf!($FFIType);
+
+        impl<'a> std::convert::TryFrom<$crate::BorrowedBuffer<'a>> for $FFIType
+        where
+            $FFIType: prost::Message + Default,
+        {
+            type Error = prost::DecodeError;
+            #[inline]
+            fn try_from(buf: $crate::BorrowedBuffer<'a>) -> Result<Self, Self::Error> {
+                <$FFIType as prost::Message>::decode(buf.as_slice())
+            }
+        }
+    )*}
+}
+
 /// Implement IntoFfi for a type by converting through another type.
 ///
 /// The argument `$MidTy` argument must implement `From<$SrcTy>` and
@@ -53,11 +53,11 @@ impl ServerTimestamp {
     /// negative timespans in rust).
     #[inline]
     pub fn duration_since(self, other: ServerTimestamp) -> Option<Duration> {
-        let delta = self.0 - other.0;
-        if delta < 0 {
-            None
-        } else {
-            Some(Duration::from_millis(delta as u64))
+        // Timestamps come from the server, so don't trust them not to
+        // overflow.
+        match self.0.checked_sub(other.0) {
+            Some(delta) if delta >= 0 => Some(Duration::from_millis(delta as u64)),
+            _ => None,
         }
     }
 
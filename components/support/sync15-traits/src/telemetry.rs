@@ -293,6 +293,11 @@ pub struct EngineIncoming {
 
     #[serde(skip_serializing_if = "crate::skip_if_default")]
     reconciled: u32,
+
+    /// Incoming records which were dropped because a newer record with the
+    /// same id was in the same batch.
+    #[serde(skip_serializing_if = "crate::skip_if_default")]
+    deduplicated: u32,
 }
 
 impl EngineIncoming {
@@ -305,7 +310,13 @@ impl EngineIncoming {
     // A helper used via skip_serializing_if
     fn is_empty(inc: &Option<Self>) -> bool {
         match inc {
-            Some(a) => a.applied == 0 && a.failed == 0 && a.new_failed == 0 && a.reconciled == 0,
+            Some(a) => {
+                a.applied == 0
+                    && a.failed == 0
+                    && a.new_failed == 0
+                    && a.reconciled == 0
+                    && a.deduplicated == 0
+            }
             None => true,
         }
     }
@@ -334,6 +345,12 @@ impl EngineIncoming {
         self.reconciled += n;
     }
 
+    /// Increment the value of `deduplicated` by `n`.
+    #[inline]
+    pub fn deduplicated(&mut self, n: u32) {
+        self.deduplicated += n;
+    }
+
     /// Get the value of `applied`. Mostly useful for testing.
     #[inline]
     pub fn get_applied(&self) -> u32 {
@@ -357,6 +374,12 @@ impl EngineIncoming {
     pub fn get_reconciled(&self) -> u32 {
         self.reconciled
     }
+
+    /// Get the value of `deduplicated`. Mostly useful for testing.
+    #[inline]
+    pub fn get_deduplicated(&self) -> u32 {
+        self.deduplicated
+    }
 }
 
 /// Outgoing record for an engine's sync
@@ -426,6 +449,12 @@ impl Engine {
         self.incoming = Some(inc);
     }
 
+    /// Get the incoming record counts, if they've been set. Mostly useful
+    /// for testing.
+    pub fn get_incoming(&self) -> Option<&EngineIncoming> {
+        self.incoming.as_ref()
+    }
+
     pub fn outgoing(&mut self, out: EngineOutgoing) {
         self.outgoing.push(out);
     }
@@ -519,6 +548,16 @@ mod engine_tests {
             &e,
             serde_json::json!({"name": "TestEngine", "when": 0.0, "incoming": {"applied": 1, "failed": 2}}),
         );
+
+        let mut i = EngineIncoming::new();
+        i.deduplicated(1);
+        let mut e = Engine::new("TestEngine");
+        e.incoming(i);
+        e.finished();
+        assert_json(
+            &e,
+            serde_json::json!({"name": "TestEngine", "when": 0.0, "incoming": {"deduplicated": 1}}),
+        );
     }
 
     #[test]
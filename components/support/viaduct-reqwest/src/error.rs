@@ -0,0 +1,184 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Turning the errors reqwest gives us into the same `viaduct::Error`
+//! variants the FFI backend reports, so that callers can tell a missing
+//! network from a server that's down, or from a certificate problem.
+//!
+//! reqwest only tells us whether it was a timeout or a failure to connect,
+//! so we look through the error's sources for the `io::Error`, TLS error,
+//! or DNS error underneath.
+
+use std::error::Error as StdError;
+use std::io;
+
+/// The `viaduct::Error` for `error`, which happened requesting something
+/// from `host`.
+pub(crate) fn classify(error: reqwest::Error, host: String) -> viaduct::Error {
+    if crate::tls::is_pin_violation(&error) {
+        return viaduct::Error::PinViolation { host };
+    }
+    let message = error.to_string();
+    if error.is_timeout() {
+        return viaduct::Error::Timeout(message);
+    }
+    let mut source: Option<&(dyn StdError + 'static)> = Some(&error);
+    while let Some(e) = source {
+        if let Some(kind) = classify_one(e) {
+            return match kind {
+                Kind::Offline => viaduct::Error::Offline(message),
+                Kind::Dns => viaduct::Error::DnsError(message),
+                Kind::ConnectionRefused => viaduct::Error::ConnectionRefused(message),
+                Kind::Timeout => viaduct::Error::Timeout(message),
+                Kind::Tls(message) => viaduct::Error::TlsError { message },
+            };
+        }
+        source = e.source();
+    }
+    viaduct::Error::NetworkError(message)
+}
+
+#[derive(Debug, PartialEq)]
+enum Kind {
+    Offline,
+    Dns,
+    ConnectionRefused,
+    Timeout,
+    // The message from the TLS library, which says what was wrong with the
+    // certificate (and the error from reqwest doesn't).
+    Tls(String),
+}
+
+fn classify_one(error: &(dyn StdError + 'static)) -> Option<Kind> {
+    if let Some(e) = error.downcast_ref::<io::Error>() {
+        // TLS errors come wrapped in an `io::Error`, whose `source` is the
+        // TLS error's source rather than the TLS error itself.
+        if let Some(kind) = e.get_ref().and_then(|inner| classify_tls(inner)) {
+            return Some(kind);
+        }
+        return classify_io(e);
+    }
+    if let Some(kind) = classify_tls(error) {
+        return Some(kind);
+    }
+    // hyper's resolver reports failed lookups as an `io::Error` of kind
+    // `Other`, so the only way to spot them is the "dns error" hyper puts
+    // in front of it.
+    if error.to_string().starts_with("dns error") {
+        return Some(Kind::Dns);
+    }
+    None
+}
+
+fn classify_tls(error: &(dyn StdError + 'static)) -> Option<Kind> {
+    if let Some(e) = error.downcast_ref::<rustls::TLSError>() {
+        return Some(Kind::Tls(e.to_string()));
+    }
+    if let Some(e) = error.downcast_ref::<native_tls::Error>() {
+        return Some(Kind::Tls(e.to_string()));
+    }
+    None
+}
+
+fn classify_io(error: &io::Error) -> Option<Kind> {
+    match error.kind() {
+        io::ErrorKind::ConnectionRefused => Some(Kind::ConnectionRefused),
+        io::ErrorKind::TimedOut => Some(Kind::Timeout),
+        _ => match error.raw_os_error() {
+            Some(code) if UNREACHABLE.contains(&code) => Some(Kind::Offline),
+            _ => None,
+        },
+    }
+}
+
+// `ENETUNREACH` and `EHOSTUNREACH`, which don't have an `io::ErrorKind` of
+// their own.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const UNREACHABLE: &[i32] = &[101, 113];
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+const UNREACHABLE: &[i32] = &[51, 65];
+#[cfg(windows)]
+const UNREACHABLE: &[i32] = &[10051, 10065];
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    windows
+)))]
+const UNREACHABLE: &[i32] = &[];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::send_with;
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    fn get(url: &str) -> Result<viaduct::Response, viaduct::Error> {
+        let client = reqwest::blocking::ClientBuilder::new()
+            .connect_timeout(Duration::from_secs(2))
+            .build()
+            .unwrap();
+        let url = url::Url::parse(url).unwrap();
+        send_with(
+            &client.into(),
+            viaduct::Request::new(viaduct::Method::Get, url),
+            false,
+        )
+    }
+
+    #[test]
+    fn test_classify_io() {
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        assert_eq!(classify_one(&refused), Some(Kind::ConnectionRefused));
+        let timed_out = io::Error::from(io::ErrorKind::TimedOut);
+        assert_eq!(classify_one(&timed_out), Some(Kind::Timeout));
+        let unreachable = io::Error::from_raw_os_error(UNREACHABLE[0]);
+        assert_eq!(classify_one(&unreachable), Some(Kind::Offline));
+        let other = io::Error::new(io::ErrorKind::Other, "something else");
+        assert_eq!(classify_one(&other), None);
+
+        let tls = io::Error::new(
+            io::ErrorKind::InvalidData,
+            rustls::TLSError::General("bad certificate".into()),
+        );
+        assert!(matches!(classify_one(&tls), Some(Kind::Tls(m)) if m.contains("bad certificate")));
+    }
+
+    #[test]
+    fn test_connection_refused() {
+        // Nothing's listening once the listener's dropped.
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        match get(&format!("http://127.0.0.1:{}/", port)) {
+            Err(viaduct::Error::ConnectionRefused(_)) => {}
+            other => panic!("Expected connection refused, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "integration_test")]
+    #[test]
+    fn test_dns_error() {
+        // `.invalid` names never resolve (RFC 2606).
+        match get("https://nothing-here.invalid/") {
+            Err(viaduct::Error::DnsError(_)) => {}
+            other => panic!("Expected a DNS error, got {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "integration_test")]
+    #[test]
+    fn test_non_routable() {
+        // Depending on the machine's routes, this either waits for the
+        // connect timeout, or fails straight away because there's no route.
+        match get("http://10.255.255.1/") {
+            Err(viaduct::Error::Timeout(_)) | Err(viaduct::Error::Offline(_)) => {}
+            other => panic!("Expected a timeout, got {:?}", other),
+        }
+    }
+}
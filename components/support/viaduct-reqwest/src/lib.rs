@@ -2,37 +2,117 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use std::{io::Read, sync::Once};
+use std::io::Read;
+use std::sync::{Arc, RwLock};
 use viaduct::{settings::GLOBAL_SETTINGS, Backend};
 
 // Note: we don't `use` things from reqwest or the viaduct crate because
 // it would be rather confusing given that we have the same name for
 // most things as them.
 
+mod error;
+mod tls;
+
 lazy_static::lazy_static! {
-    static ref CLIENT: reqwest::blocking::Client = {
-        let mut builder = reqwest::blocking::ClientBuilder::new()
-            .timeout(GLOBAL_SETTINGS.read_timeout)
-            .connect_timeout(GLOBAL_SETTINGS.connect_timeout)
-            .redirect(
-                if GLOBAL_SETTINGS.follow_redirects {
-                    reqwest::redirect::Policy::default()
-                } else {
-                    reqwest::redirect::Policy::none()
-                }
-            );
-            if cfg!(target_os = "ios") {
-                // The FxA servers rely on the UA agent to filter
-                // some push messages directed to iOS devices.
-                // This is obviously a terrible hack and we should
-                // probably do https://github.com/mozilla/application-services/issues/1326
-                // instead, but this will unblock us for now.
-                builder = builder.user_agent("Firefox-iOS-FxA/24");
-            }
-            // Note: no cookie or cache support.
-            builder.build()
-            .expect("Failed to initialize global reqwest::Client")
+    // Built for the first request, and dropped by `tear_down`, which closes
+    // their pooled connections. The request after that builds new ones.
+    static ref CLIENTS: RwLock<Option<Arc<Clients>>> = RwLock::new(None);
+}
+
+// The clients are reference-counted, so requests in flight keep theirs
+// alive through a `tear_down`.
+fn clients() -> Arc<Clients> {
+    if let Some(clients) = &*CLIENTS.read().unwrap() {
+        return clients.clone();
+    }
+    CLIENTS
+        .write()
+        .unwrap()
+        .get_or_insert_with(|| Arc::new(build_clients(viaduct::tls_config())))
+        .clone()
+}
+
+/// The clients we send requests with. Hosts which the `TlsConfig` pins, or
+/// skips verification for, need rustls (see the `tls` module), which
+/// verifies them against the Mozilla roots from webpki-roots rather than the
+/// platform's. Every other host gets native-tls and the platform's roots, as
+/// if there were no `TlsConfig`, apart from also trusting its extra roots.
+struct Clients {
+    native: reqwest::blocking::Client,
+    rustls: Option<reqwest::blocking::Client>,
+    tls_config: viaduct::TlsConfig,
+}
+
+impl Clients {
+    fn for_url(&self, url: &reqwest::Url) -> &reqwest::blocking::Client {
+        let host = url.host_str().unwrap_or_default();
+        match &self.rustls {
+            Some(rustls) if self.tls_config.has_host_specific_config(host) => rustls,
+            _ => &self.native,
+        }
+    }
+}
+
+// For tests which need a client set up differently.
+#[cfg(test)]
+impl From<reqwest::blocking::Client> for Clients {
+    fn from(native: reqwest::blocking::Client) -> Self {
+        Clients {
+            native,
+            rustls: None,
+            tls_config: viaduct::TlsConfig::default(),
+        }
+    }
+}
+
+fn build_clients(tls_config: &viaduct::TlsConfig) -> Clients {
+    let mut native = client_builder();
+    for der in &tls_config.extra_root_certs {
+        match reqwest::Certificate::from_der(der) {
+            Ok(cert) => native = native.add_root_certificate(cert),
+            Err(e) => log::error!("Ignoring invalid extra root certificate: {:?}", e),
+        }
+    }
+    let needs_rustls = !tls_config.pinned_spki_hashes.is_empty()
+        || !tls_config.danger_accept_invalid_for_hosts.is_empty();
+    let rustls = if needs_rustls {
+        Some(build_client(
+            client_builder().use_preconfigured_tls(tls::rustls_config(tls_config)),
+        ))
+    } else {
+        None
     };
+    Clients {
+        native: build_client(native),
+        rustls,
+        tls_config: tls_config.clone(),
+    }
+}
+
+fn client_builder() -> reqwest::blocking::ClientBuilder {
+    let mut builder = reqwest::blocking::ClientBuilder::new()
+        .timeout(GLOBAL_SETTINGS.read_timeout)
+        .connect_timeout(GLOBAL_SETTINGS.connect_timeout)
+        // We follow redirects ourselves in `send_with`, so that we can
+        // report them. The client is shared between requests, so a redirect
+        // policy can't tell which request it's being asked about.
+        .redirect(reqwest::redirect::Policy::none());
+    if cfg!(target_os = "ios") {
+        // The FxA servers rely on the UA agent to filter
+        // some push messages directed to iOS devices.
+        // This is obviously a terrible hack and we should
+        // probably do https://github.com/mozilla/application-services/issues/1326
+        // instead, but this will unblock us for now.
+        builder = builder.user_agent("Firefox-iOS-FxA/24");
+    }
+    builder
+}
+
+fn build_client(builder: reqwest::blocking::ClientBuilder) -> reqwest::blocking::Client {
+    // Note: no cookie or cache support.
+    builder
+        .build()
+        .expect("Failed to initialize global reqwest::Client")
 }
 
 #[allow(clippy::unnecessary_wraps)] // not worth the time to untangle
@@ -46,6 +126,7 @@ fn into_reqwest(request: viaduct::Request) -> Result<reqwest::blocking::Request,
         viaduct::Method::Connect => reqwest::Method::CONNECT,
         viaduct::Method::Options => reqwest::Method::OPTIONS,
         viaduct::Method::Trace => reqwest::Method::TRACE,
+        viaduct::Method::Patch => reqwest::Method::PATCH,
     };
     let mut result = reqwest::blocking::Request::new(method, request.url);
     for h in request.headers {
@@ -54,7 +135,7 @@ fn into_reqwest(request: viaduct::Request) -> Result<reqwest::blocking::Request,
         let value = HeaderValue::from_str(&h.value()).unwrap();
         result
             .headers_mut()
-            .insert(HeaderName::from_bytes(h.name().as_bytes()).unwrap(), value);
+            .append(HeaderName::from_bytes(h.name().as_bytes()).unwrap(), value);
     }
     *result.body_mut() = request.body.map(reqwest::blocking::Body::from);
     Ok(result)
@@ -63,50 +144,226 @@ fn into_reqwest(request: viaduct::Request) -> Result<reqwest::blocking::Request,
 pub struct ReqwestBackend;
 impl Backend for ReqwestBackend {
     fn send(&self, request: viaduct::Request) -> Result<viaduct::Response, viaduct::Error> {
-        viaduct::note_backend("reqwest (untrusted)");
-        let request_method = request.method;
-        let req = into_reqwest(request)?;
-        let mut resp = CLIENT
-            .execute(req)
-            .map_err(|e| viaduct::Error::NetworkError(e.to_string()))?;
-        let status = resp.status().as_u16();
-        let url = resp.url().clone();
-        let mut body = Vec::with_capacity(resp.content_length().unwrap_or_default() as usize);
-        resp.read_to_end(&mut body).map_err(|e| {
+        viaduct::note_backend(self.name());
+        let follow_redirects = request.should_follow_redirects();
+        send_with(&clients(), request, follow_redirects)
+    }
+
+    fn name(&self) -> &'static str {
+        "reqwest (untrusted)"
+    }
+
+    fn supports_tls_config(&self) -> bool {
+        true
+    }
+
+    fn tear_down(&self) {
+        *CLIENTS.write().unwrap() = None;
+    }
+}
+
+fn send_with(
+    clients: &Clients,
+    request: viaduct::Request,
+    follow_redirects: bool,
+) -> Result<viaduct::Response, viaduct::Error> {
+    let request_method = request.method;
+    let request_id = request.id();
+    let url = request.url.clone();
+    let upload_progress = request.upload_progress.clone();
+    let download_progress = request.download_progress.clone();
+    let size_limit = request.response_size_limit();
+    let mut req = into_reqwest(request)?;
+    let mut redirects = Vec::new();
+    let mut resp = loop {
+        // `execute` consumes the request, but we need it again if we're
+        // redirected. Our bodies are always buffered, so this always works.
+        let next = if follow_redirects {
+            req.try_clone()
+        } else {
+            None
+        };
+        let resp = execute(clients, with_upload_progress(req, upload_progress.as_ref()))?;
+        let location = match redirect_location(&resp) {
+            Some(location) if follow_redirects => location,
+            _ => break resp,
+        };
+        if redirects.len() == viaduct::MAX_REDIRECTS {
+            return Err(viaduct::Error::NetworkError(format!(
+                "Too many redirects requesting {}",
+                url
+            )));
+        }
+        req = redirected_request(
+            next.expect("buffered bodies are cloneable"),
+            &resp,
+            &location,
+        );
+        redirects.push(viaduct::RedirectHop {
+            status: resp.status().as_u16(),
+            location,
+        });
+    };
+    let status = resp.status().as_u16();
+    let final_url = resp.url().clone();
+    // The Content-Length of a HEAD response is the length the body would
+    // have had, but there isn't one.
+    let content_length = if request_method == viaduct::Method::Head {
+        Some(0)
+    } else {
+        resp.content_length()
+    };
+    let too_large = viaduct::Error::ResponseTooLarge {
+        limit: size_limit,
+        content_length,
+    };
+    if content_length.map_or(false, |len| len > size_limit) {
+        return Err(too_large);
+    }
+    let mut body = Vec::with_capacity(content_length.unwrap_or_default() as usize);
+    if request_method != viaduct::Method::Head {
+        // The Content-Length might be missing (or wrong), so read at most one
+        // byte past the limit, which is enough to tell it was passed.
+        let max_read = size_limit.saturating_add(1);
+        let read = match download_progress {
+            Some(hook) => viaduct::ProgressReader::new(&mut resp, hook, content_length)
+                .take(max_read)
+                .read_to_end(&mut body),
+            None => (&mut resp).take(max_read).read_to_end(&mut body),
+        };
+        read.map_err(|e| {
             log::error!("Failed to get body from response: {:?}", e);
             viaduct::Error::NetworkError(e.to_string())
         })?;
-        let mut headers = viaduct::Headers::with_capacity(resp.headers().len());
-        for (k, v) in resp.headers() {
-            let val = String::from_utf8_lossy(v.as_bytes()).to_string();
-            let hname = match viaduct::HeaderName::new(k.as_str().to_owned()) {
-                Ok(name) => name,
-                Err(e) => {
-                    // Ignore headers with invalid names, since nobody can look for them anyway.
-                    log::warn!("Server sent back invalid header name: '{}'", e);
-                    continue;
-                }
-            };
-            // Not using Header::new since the error it returns is for request headers.
-            headers.insert_header(viaduct::Header::new_unchecked(hname, val));
+        if body.len() as u64 > size_limit {
+            return Err(too_large);
         }
-        Ok(viaduct::Response {
-            request_method,
-            url,
-            status,
-            headers,
-            body,
-        })
     }
+    let mut headers = viaduct::Headers::with_capacity(resp.headers().len());
+    for (k, v) in resp.headers() {
+        let val = String::from_utf8_lossy(v.as_bytes()).to_string();
+        let hname = match viaduct::HeaderName::new(k.as_str().to_owned()) {
+            Ok(name) => name,
+            Err(e) => {
+                // Ignore headers with invalid names, since nobody can look for them anyway.
+                log::warn!("Server sent back invalid header name: '{}'", e);
+                continue;
+            }
+        };
+        // Not using Header::new since the error it returns is for request headers.
+        headers.append_header(viaduct::Header::new_unchecked(hname, val));
+    }
+    Ok(viaduct::Response {
+        request_method,
+        request_id,
+        url: final_url.clone(),
+        final_url,
+        redirects,
+        status,
+        headers,
+        body,
+        from_cache: false,
+    })
 }
 
-static INIT_REQWEST_BACKEND: Once = Once::new();
+/// If there's an upload progress hook, replace the body of `req` with one
+/// which reports to it as it's read.
+fn with_upload_progress(
+    mut req: reqwest::blocking::Request,
+    hook: Option<&viaduct::ProgressHook>,
+) -> reqwest::blocking::Request {
+    let hook = match hook {
+        Some(hook) => hook,
+        None => return req,
+    };
+    let bytes = match req.body().and_then(|body| body.as_bytes()) {
+        Some(bytes) => bytes.to_vec(),
+        None => return req,
+    };
+    let len = bytes.len() as u64;
+    let reader = viaduct::ProgressReader::new(std::io::Cursor::new(bytes), hook.clone(), Some(len));
+    *req.body_mut() = Some(reqwest::blocking::Body::sized(reader, len));
+    req
+}
 
+fn execute(
+    clients: &Clients,
+    req: reqwest::blocking::Request,
+) -> Result<reqwest::blocking::Response, viaduct::Error> {
+    let host = req.url().host_str().unwrap_or_default().to_owned();
+    // Redirects can take us to a different host, so this is chosen for each
+    // request we send.
+    let client = clients.for_url(req.url());
+    client.execute(req).map_err(|e| error::classify(e, host))
+}
+
+/// Where `resp` redirects us to, if it's a redirect we follow. These are the
+/// same statuses reqwest's own redirect policy follows.
+fn redirect_location(resp: &reqwest::blocking::Response) -> Option<reqwest::Url> {
+    use reqwest::StatusCode;
+    match resp.status() {
+        StatusCode::MOVED_PERMANENTLY
+        | StatusCode::FOUND
+        | StatusCode::SEE_OTHER
+        | StatusCode::TEMPORARY_REDIRECT
+        | StatusCode::PERMANENT_REDIRECT => {}
+        _ => return None,
+    }
+    let location = resp
+        .headers()
+        .get(reqwest::header::LOCATION)?
+        .to_str()
+        .ok()?;
+    resp.url().join(location).ok()
+}
+
+/// Build the request to send to `location` after `prev` was redirected by
+/// `resp`, the way reqwest would: 301, 302 and 303 become a `GET` without a
+/// body, and credentials aren't sent to a different origin.
+fn redirected_request(
+    mut prev: reqwest::blocking::Request,
+    resp: &reqwest::blocking::Response,
+    location: &reqwest::Url,
+) -> reqwest::blocking::Request {
+    use reqwest::{header, Method, StatusCode};
+    if matches!(
+        resp.status(),
+        StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND | StatusCode::SEE_OTHER
+    ) {
+        if *prev.method() != Method::HEAD {
+            *prev.method_mut() = Method::GET;
+        }
+        *prev.body_mut() = None;
+        for name in &[
+            header::CONTENT_TYPE,
+            header::CONTENT_LENGTH,
+            header::TRANSFER_ENCODING,
+        ] {
+            prev.headers_mut().remove(name);
+        }
+    }
+    if prev.url().origin() != location.origin() {
+        for name in &[
+            header::AUTHORIZATION,
+            header::COOKIE,
+            header::PROXY_AUTHORIZATION,
+            header::WWW_AUTHENTICATE,
+        ] {
+            prev.headers_mut().remove(name);
+        }
+    }
+    *prev.url_mut() = location.clone();
+    prev
+}
+
+/// Send all requests through reqwest. This is
+/// `viaduct::init(BackendChoice::Reqwest(..))`, which can safely be called
+/// more than once, but panics if a different backend was already chosen.
+#[track_caller]
 pub fn use_reqwest_backend() {
-    INIT_REQWEST_BACKEND.call_once(|| {
-        viaduct::set_backend(Box::leak(Box::new(ReqwestBackend)))
-            .expect("Backend already set (FFI)");
-    })
+    if let Err(e) = viaduct::init(viaduct::BackendChoice::Reqwest(&ReqwestBackend)) {
+        panic!("Can't use the reqwest backend: {}", e);
+    }
 }
 
 #[no_mangle]
@@ -123,3 +380,308 @@ pub extern "C" fn viaduct_detect_reqwest_backend() {
         println!("Nothing to see here (reqwest backend available).");
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+
+    const BIG_BODY_LEN: usize = 3 * 1024 * 1024;
+
+    // Start a plain HTTP server, which redirects `/redirect` to `/target`
+    // with a 302, responds to `/big` with `BIG_BODY_LEN` bytes, to
+    // `/unsized` with the same but no Content-Length, to `/lying` with "ok"
+    // and a Content-Length of `BIG_BODY_LEN`, to `/echo...` (or any URL, if
+    // it's used as a proxy) with the request target as it was sent, to PATCH
+    // requests with their body, to HEAD requests with a Content-Length of
+    // `BIG_BODY_LEN` (and no body), and to
+    // everything else with "ok" once it's read the request body. Returns the
+    // base URL.
+    fn start_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut request = vec![];
+                let mut buf = [0u8; 64 * 1024];
+                let header_end = loop {
+                    if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break end + 4;
+                    }
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break request.len(),
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                };
+                let headers = String::from_utf8_lossy(&request[..header_end]).to_lowercase();
+                let content_length = headers
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length:"))
+                    .map_or(0, |len| len.trim().parse().unwrap());
+                while request.len() - header_end < content_length {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let response = if request.starts_with(b"GET /redirect ") {
+                    b"HTTP/1.1 302 Found\r\nLocation: /target\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec()
+                } else if request.starts_with(b"GET /big ") {
+                    let mut response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        BIG_BODY_LEN
+                    )
+                    .into_bytes();
+                    response.resize(response.len() + BIG_BODY_LEN, b'x');
+                    response
+                } else if request.starts_with(b"GET /unsized ") {
+                    // Without a Content-Length, the body ends when the
+                    // connection is closed.
+                    let mut response = b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n".to_vec();
+                    response.resize(response.len() + BIG_BODY_LEN, b'x');
+                    response
+                } else if request.starts_with(b"GET /lying ") {
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\nok",
+                        BIG_BODY_LEN
+                    )
+                    .into_bytes()
+                } else if request.starts_with(b"HEAD ") {
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nX-Test: head\r\nConnection: close\r\n\r\n",
+                        BIG_BODY_LEN
+                    )
+                    .into_bytes()
+                } else if request.starts_with(b"PATCH ") {
+                    let body = &request[header_end..];
+                    let mut response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    )
+                    .into_bytes();
+                    response.extend_from_slice(body);
+                    response
+                } else if request.starts_with(b"GET /echo") || request.starts_with(b"GET http://") {
+                    let target = request.split(|&b| b == b' ').nth(1).unwrap();
+                    let mut response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        target.len()
+                    )
+                    .into_bytes();
+                    response.extend_from_slice(target);
+                    response
+                } else {
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok".to_vec()
+                };
+                let _ = stream.write_all(&response);
+            }
+        });
+        format!("http://127.0.0.1:{}", port)
+    }
+
+    type Calls = std::sync::Arc<std::sync::Mutex<Vec<(u64, Option<u64>)>>>;
+
+    fn recorder() -> (Calls, impl Fn(u64, Option<u64>) + Send + Sync + 'static) {
+        let calls = Calls::default();
+        let recorded = calls.clone();
+        (calls, move |done, total| {
+            recorded.lock().unwrap().push((done, total));
+        })
+    }
+
+    // Progress should only go up, and end at the total.
+    fn assert_progress(calls: &Calls, total: u64) {
+        let calls = calls.lock().unwrap();
+        assert!(calls.len() > 1, "Expected several reports, got {:?}", calls);
+        assert!(calls.iter().all(|(_, t)| *t == Some(total)));
+        assert!(calls.windows(2).all(|w| w[0].0 < w[1].0));
+        assert_eq!(calls.last().unwrap().0, total);
+    }
+
+    fn get(url: &str, follow_redirects: bool) -> viaduct::Response {
+        let clients = build_clients(&viaduct::TlsConfig::default());
+        let request = viaduct::Request::get(reqwest::Url::parse(url).unwrap());
+        send_with(&clients, request, follow_redirects).unwrap()
+    }
+
+    #[test]
+    fn test_follow_redirect() {
+        let base = start_server();
+        let response = get(&format!("{}/redirect", base), true);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"ok");
+        let target = reqwest::Url::parse(&format!("{}/target", base)).unwrap();
+        assert_eq!(response.final_url, target);
+        assert_eq!(
+            response.redirects,
+            vec![viaduct::RedirectHop {
+                status: 302,
+                location: target,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_upload_progress() {
+        let base = start_server();
+        let clients = build_clients(&viaduct::TlsConfig::default());
+        let (calls, callback) = recorder();
+        let body = vec![b'x'; 4 * 1024 * 1024];
+        let request =
+            viaduct::Request::post(reqwest::Url::parse(&format!("{}/sink", base)).unwrap())
+                .body(body.clone())
+                .on_upload_progress(callback);
+        let response = send_with(&clients, request, true).unwrap();
+        assert_eq!(response.body, b"ok");
+        assert_progress(&calls, body.len() as u64);
+    }
+
+    #[test]
+    fn test_download_progress() {
+        let base = start_server();
+        let clients = build_clients(&viaduct::TlsConfig::default());
+        let (calls, callback) = recorder();
+        let request = viaduct::Request::get(reqwest::Url::parse(&format!("{}/big", base)).unwrap())
+            .on_download_progress(callback);
+        let response = send_with(&clients, request, true).unwrap();
+        assert_eq!(response.body.len(), BIG_BODY_LEN);
+        assert_progress(&calls, BIG_BODY_LEN as u64);
+    }
+
+    fn get_with_limit(url: &str, limit: u64) -> Result<viaduct::Response, viaduct::Error> {
+        let clients = build_clients(&viaduct::TlsConfig::default());
+        let request =
+            viaduct::Request::get(reqwest::Url::parse(url).unwrap()).max_response_size(limit);
+        send_with(&clients, request, true)
+    }
+
+    #[test]
+    fn test_response_too_large() {
+        let base = start_server();
+        let limit = BIG_BODY_LEN as u64 - 1;
+        // Known to be too big before reading anything.
+        let err = get_with_limit(&format!("{}/big", base), limit).unwrap_err();
+        assert!(matches!(
+            err,
+            viaduct::Error::ResponseTooLarge { limit: l, content_length: Some(len) }
+                if l == limit && len == BIG_BODY_LEN as u64
+        ));
+        // Only too big once we've read it.
+        let err = get_with_limit(&format!("{}/unsized", base), limit).unwrap_err();
+        assert!(matches!(
+            err,
+            viaduct::Error::ResponseTooLarge { limit: l, content_length: None } if l == limit
+        ));
+        // We believe a Content-Length which is too big, even if the body
+        // isn't.
+        let err = get_with_limit(&format!("{}/lying", base), limit).unwrap_err();
+        assert!(matches!(err, viaduct::Error::ResponseTooLarge { .. }));
+
+        // Raising the limit lets them through.
+        let limit = BIG_BODY_LEN as u64;
+        let response = get_with_limit(&format!("{}/big", base), limit).unwrap();
+        assert_eq!(response.body.len(), BIG_BODY_LEN);
+        let response = get_with_limit(&format!("{}/unsized", base), limit).unwrap();
+        assert_eq!(response.body.len(), BIG_BODY_LEN);
+    }
+
+    #[test]
+    fn test_url_sent_as_normalized() {
+        let base = start_server();
+        // The same URLs as viaduct's `test_normalize_url`, as far as they can
+        // be sent to a local server...
+        let cases = [
+            ("/echo/a b?q=a b", "/echo/a%20b?q=a%20b"),
+            ("/echo/%7Euser?x=%2F%20", "/echo/%7Euser?x=%2F%20"),
+            ("/echo?q", "/echo?q"),
+        ];
+        for (path, expected) in &cases {
+            let response = get(&format!("{}{}", base, path), false);
+            assert_eq!(response.body, expected.as_bytes(), "requesting {}", path);
+        }
+
+        // ...and the rest through it as a proxy, which is sent the whole URL,
+        // host and all, without having to resolve or connect to the host.
+        let clients = Clients::from(
+            reqwest::blocking::ClientBuilder::new()
+                .proxy(reqwest::Proxy::http(&base).unwrap())
+                .build()
+                .unwrap(),
+        );
+        let cases = [
+            ("http://[::1]:8080/path", "http://[::1]:8080/path"),
+            ("http://[0:0:0:0:0:0:0:1]:80/", "http://[::1]/"),
+            ("http://WWW.Example.COM:80", "http://www.example.com/"),
+            ("http://localhost:4242?q", "http://localhost:4242/?q"),
+            ("http://bücher.example/", "http://xn--bcher-kva.example/"),
+            (
+                "http://www.example.com/a b?q=a b",
+                "http://www.example.com/a%20b?q=a%20b",
+            ),
+            (
+                "http://www.example.com/%7Euser?x=%2F%20",
+                "http://www.example.com/%7Euser?x=%2F%20",
+            ),
+        ];
+        for (url, expected) in &cases {
+            let request = viaduct::Request::get(reqwest::Url::parse(url).unwrap());
+            let response = send_with(&clients, request, false).unwrap();
+            assert_eq!(response.body, expected.as_bytes(), "requesting {}", url);
+        }
+    }
+
+    #[test]
+    fn test_patch() {
+        let base = start_server();
+        let clients = build_clients(&viaduct::TlsConfig::default());
+        let url = reqwest::Url::parse(&format!("{}/thing", base)).unwrap();
+        let request = viaduct::Request::new(viaduct::Method::Patch, url).body("new data");
+        let response = send_with(&clients, request, true).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"new data");
+    }
+
+    #[test]
+    fn test_head() {
+        let base = start_server();
+        let clients = build_clients(&viaduct::TlsConfig::default());
+        let url = reqwest::Url::parse(&format!("{}/big", base)).unwrap();
+        // The Content-Length is more than the limit, but there's no body.
+        let request = viaduct::Request::new(viaduct::Method::Head, url).max_response_size(1024);
+        let response = send_with(&clients, request, true).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.headers.get("x-test"), Some("head"));
+        assert!(response.body.is_empty());
+    }
+
+    #[test]
+    fn test_tear_down() {
+        let base = start_server();
+        let send = || {
+            let url = reqwest::Url::parse(&format!("{}/thing", base)).unwrap();
+            ReqwestBackend.send(viaduct::Request::get(url)).unwrap()
+        };
+        assert_eq!(send().body, b"ok");
+        assert!(CLIENTS.read().unwrap().is_some());
+        ReqwestBackend.tear_down();
+        assert!(CLIENTS.read().unwrap().is_none());
+        // The next request builds new clients.
+        assert_eq!(send().body, b"ok");
+        assert!(CLIENTS.read().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_dont_follow_redirect() {
+        let base = start_server();
+        let url = format!("{}/redirect", base);
+        let response = get(&url, false);
+        assert_eq!(response.status, 302);
+        assert_eq!(
+            response.headers.get(viaduct::header_names::LOCATION),
+            Some("/target")
+        );
+        assert_eq!(response.final_url.as_str(), url);
+        assert!(response.redirects.is_empty());
+    }
+}
@@ -0,0 +1,475 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Support for `viaduct::TlsConfig`. native-tls has no way to hook into
+//! certificate verification, so for the hosts which have pins, or which we
+//! skip verification for, we use rustls instead, with a verifier which
+//! checks pins (and skips verification for the hosts we've been told to) on
+//! top of the usual webpki checks. Other hosts stick with native-tls; see
+//! `Clients` in the crate root.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// Included in the error returned by the verifier, so we can pick pin
+// violations out of the error reqwest hands back to us.
+const PIN_VIOLATION: &str = "viaduct: certificate pin violation";
+
+pub(crate) fn rustls_config(tls_config: &viaduct::TlsConfig) -> rustls::ClientConfig {
+    let mut config = rustls::ClientConfig::new();
+    config
+        .root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    for der in &tls_config.extra_root_certs {
+        if let Err(e) = config.root_store.add(&rustls::Certificate(der.clone())) {
+            log::error!("Ignoring invalid extra root certificate: {:?}", e);
+        }
+    }
+    config
+        .dangerous()
+        .set_certificate_verifier(Arc::new(Verifier {
+            inner: rustls::WebPKIVerifier::new(),
+            pins: tls_config.pinned_spki_hashes.clone(),
+            accept_invalid: tls_config.danger_accept_invalid_for_hosts.clone(),
+        }));
+    config
+}
+
+/// Whether `error`, or anything in its chain of sources, was caused by a pin
+/// violation.
+pub(crate) fn is_pin_violation(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(error);
+    while let Some(e) = source {
+        if e.to_string().contains(PIN_VIOLATION) {
+            return true;
+        }
+        source = e.source();
+    }
+    false
+}
+
+struct Verifier {
+    inner: rustls::WebPKIVerifier,
+    pins: HashMap<String, Vec<[u8; 32]>>,
+    accept_invalid: Vec<String>,
+}
+
+impl rustls::ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        roots: &rustls::RootCertStore,
+        presented_certs: &[rustls::Certificate],
+        dns_name: webpki::DNSNameRef<'_>,
+        ocsp_response: &[u8],
+    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+        let host: &str = dns_name.into();
+        let verified = if self.accept_invalid.iter().any(|h| h == host) {
+            log::warn!("Skipping certificate verification for {}", host);
+            rustls::ServerCertVerified::assertion()
+        } else {
+            self.inner
+                .verify_server_cert(roots, presented_certs, dns_name, ocsp_response)
+                .map_err(|e| with_validity(e, presented_certs))?
+        };
+        if let Some(pins) = self.pins.get(host) {
+            // Only the server's own certificate counts. Anything can be
+            // tacked on to the end of the chain, whether it verifies or not.
+            let matched = presented_certs
+                .first()
+                .and_then(|cert| spki(&cert.0))
+                .map_or(false, |spki| {
+                    let hash = ring::digest::digest(&ring::digest::SHA256, spki);
+                    pins.iter().any(|pin| &pin[..] == hash.as_ref())
+                });
+            if !matched {
+                log::error!("{} presented a certificate which isn't pinned", host);
+                return Err(rustls::TLSError::General(PIN_VIOLATION.into()));
+            }
+        }
+        Ok(verified)
+    }
+}
+
+// A wrong clock on the device is the usual reason for a certificate to be
+// expired or not valid yet, so say when it's valid, to make that easier to
+// spot.
+fn with_validity(
+    error: rustls::TLSError,
+    presented_certs: &[rustls::Certificate],
+) -> rustls::TLSError {
+    match error {
+        rustls::TLSError::WebPKIError(e @ webpki::Error::CertNotValidYet)
+        | rustls::TLSError::WebPKIError(e @ webpki::Error::CertExpired) => {
+            match presented_certs.first().and_then(|cert| validity(&cert.0)) {
+                Some((not_before, not_after)) => rustls::TLSError::General(format!(
+                    "invalid certificate: {:?} (valid from {} to {})",
+                    e, not_before, not_after
+                )),
+                None => rustls::TLSError::WebPKIError(e),
+            }
+        }
+        _ => error,
+    }
+}
+
+// Split the DER element at the start of `input` into its tag, its contents,
+// the whole element, and whatever follows it.
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first & 0x80 == 0 {
+        (first as usize, rest)
+    } else {
+        let len_bytes = (first & 0x7f) as usize;
+        if len_bytes == 0 || len_bytes > 4 || rest.len() < len_bytes {
+            return None;
+        }
+        let len = rest[..len_bytes]
+            .iter()
+            .fold(0usize, |len, byte| (len << 8) | *byte as usize);
+        (len, &rest[len_bytes..])
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let header_len = input.len() - rest.len();
+    Some((tag, &rest[..len], &input[..header_len + len], &rest[len..]))
+}
+
+// Find the DER-encoded SubjectPublicKeyInfo in a certificate.
+fn spki(cert_der: &[u8]) -> Option<&[u8]> {
+    const SEQUENCE: u8 = 0x30;
+    const EXPLICIT_VERSION: u8 = 0xa0;
+    let (_, cert, _, _) = der_element(cert_der)?;
+    let (_, mut tbs, _, _) = der_element(cert)?;
+    let (tag, _, _, rest) = der_element(tbs)?;
+    if tag == EXPLICIT_VERSION {
+        tbs = rest;
+    }
+    // Skip the serial number, signature algorithm, issuer, validity and
+    // subject.
+    for _ in 0..5 {
+        tbs = der_element(tbs)?.3;
+    }
+    match der_element(tbs)? {
+        (SEQUENCE, _, spki, _) => Some(spki),
+        _ => None,
+    }
+}
+
+// Find the notBefore and notAfter times in a certificate, formatted like
+// "2020-01-31 12:00:00 UTC".
+fn validity(cert_der: &[u8]) -> Option<(String, String)> {
+    const EXPLICIT_VERSION: u8 = 0xa0;
+    let (_, cert, _, _) = der_element(cert_der)?;
+    let (_, mut tbs, _, _) = der_element(cert)?;
+    let (tag, _, _, rest) = der_element(tbs)?;
+    if tag == EXPLICIT_VERSION {
+        tbs = rest;
+    }
+    // Skip the serial number, signature algorithm and issuer.
+    for _ in 0..3 {
+        tbs = der_element(tbs)?.3;
+    }
+    let (_, validity, _, _) = der_element(tbs)?;
+    let (not_before_tag, not_before, _, rest) = der_element(validity)?;
+    let (not_after_tag, not_after, _, _) = der_element(rest)?;
+    Some((
+        format_time(not_before_tag, not_before)?,
+        format_time(not_after_tag, not_after)?,
+    ))
+}
+
+fn format_time(tag: u8, contents: &[u8]) -> Option<String> {
+    const UTC_TIME: u8 = 0x17;
+    const GENERALIZED_TIME: u8 = 0x18;
+    let time = std::str::from_utf8(contents).ok()?;
+    if !time.ends_with('Z') || !time[..time.len() - 1].bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    // UTCTime has a two digit year, meaning 1950 to 2049 (RFC 5280).
+    let (year, rest) = match (tag, time.len()) {
+        (UTC_TIME, 13) => {
+            let year: u32 = time[..2].parse().ok()?;
+            (
+                if year < 50 { 2000 + year } else { 1900 + year },
+                &time[2..],
+            )
+        }
+        (GENERALIZED_TIME, 15) => (time[..4].parse().ok()?, &time[4..]),
+        _ => return None,
+    };
+    Some(format!(
+        "{}-{}-{} {}:{}:{} UTC",
+        year,
+        &rest[..2],
+        &rest[2..4],
+        &rest[4..6],
+        &rest[6..8],
+        &rest[8..10]
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{build_clients, send_with};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    struct TestServer {
+        port: u16,
+        cert_der: Vec<u8>,
+    }
+
+    fn spki_hash(cert_der: &[u8]) -> [u8; 32] {
+        let hash = ring::digest::digest(&ring::digest::SHA256, spki(cert_der).unwrap());
+        let mut result = [0u8; 32];
+        result.copy_from_slice(hash.as_ref());
+        result
+    }
+
+    impl TestServer {
+        // Start a server with a self-signed certificate for `localhost`,
+        // which responds to every request with "ok".
+        fn start() -> Self {
+            Self::start_with(rcgen::CertificateParams::new(vec!["localhost".to_string()]))
+        }
+
+        // Like `start`, but with a certificate made from `params`.
+        fn start_with(params: rcgen::CertificateParams) -> Self {
+            Self::start_with_chain(params, vec![])
+        }
+
+        // Like `start_with`, but presenting `extra_certs` after the server's
+        // own certificate.
+        fn start_with_chain(params: rcgen::CertificateParams, extra_certs: Vec<Vec<u8>>) -> Self {
+            let cert = rcgen::Certificate::from_params(params).unwrap();
+            let cert_der = cert.serialize_der().unwrap();
+            let chain = std::iter::once(cert_der.clone())
+                .chain(extra_certs)
+                .map(rustls::Certificate)
+                .collect();
+            let mut config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+            config
+                .set_single_cert(chain, rustls::PrivateKey(cert.serialize_private_key_der()))
+                .unwrap();
+            let config = Arc::new(config);
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port();
+            std::thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let session = rustls::ServerSession::new(&config);
+                    let mut tls = rustls::StreamOwned::new(session, stream);
+                    // Clients which reject our certificate will hang up
+                    // during the handshake, which we just ignore.
+                    let mut request = vec![];
+                    let mut buf = [0u8; 1024];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        match tls.read(&mut buf) {
+                            Ok(0) | Err(_) => break,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let _ = tls.write_all(
+                        b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok",
+                    );
+                    tls.sess.send_close_notify();
+                    let _ = tls.flush();
+                }
+            });
+            TestServer { port, cert_der }
+        }
+
+        fn get(&self, config: &viaduct::TlsConfig) -> Result<viaduct::Response, viaduct::Error> {
+            let url = url::Url::parse(&format!("https://localhost:{}/", self.port)).unwrap();
+            send_with(
+                &build_clients(config),
+                viaduct::Request::new(viaduct::Method::Get, url),
+                true,
+            )
+        }
+
+        fn spki_hash(&self) -> [u8; 32] {
+            spki_hash(&self.cert_der)
+        }
+    }
+
+    #[test]
+    fn test_extra_roots() {
+        let server = TestServer::start();
+        match server.get(&viaduct::TlsConfig::default()) {
+            Err(viaduct::Error::TlsError { .. }) => {}
+            other => panic!("Expected a TLS error, got {:?}", other),
+        }
+
+        let config = viaduct::TlsConfig {
+            extra_root_certs: vec![server.cert_der.clone()],
+            ..viaduct::TlsConfig::default()
+        };
+        let response = server.get(&config).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"ok");
+    }
+
+    #[test]
+    fn test_pins() {
+        let server = TestServer::start();
+        let mut config = viaduct::TlsConfig {
+            extra_root_certs: vec![server.cert_der.clone()],
+            ..viaduct::TlsConfig::default()
+        };
+        config
+            .pinned_spki_hashes
+            .insert("localhost".into(), vec![[0u8; 32]]);
+        match server.get(&config) {
+            Err(viaduct::Error::PinViolation { host }) => assert_eq!(host, "localhost"),
+            other => panic!("Expected a pin violation, got {:?}", other),
+        }
+
+        config
+            .pinned_spki_hashes
+            .insert("localhost".into(), vec![[0u8; 32], server.spki_hash()]);
+        assert_eq!(server.get(&config).unwrap().status, 200);
+
+        // Pins are checked even when verification is skipped.
+        let mut config = viaduct::TlsConfig {
+            danger_accept_invalid_for_hosts: vec!["localhost".into()],
+            ..viaduct::TlsConfig::default()
+        };
+        config
+            .pinned_spki_hashes
+            .insert("localhost".into(), vec![[0u8; 32]]);
+        match server.get(&config) {
+            Err(viaduct::Error::PinViolation { .. }) => {}
+            other => panic!("Expected a pin violation, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_pins_only_match_the_leaf() {
+        // A pinned certificate tacked on after one which isn't pinned.
+        let pinned = rcgen::generate_simple_self_signed(vec!["pinned.example.com".into()])
+            .unwrap()
+            .serialize_der()
+            .unwrap();
+        let server = TestServer::start_with_chain(
+            rcgen::CertificateParams::new(vec!["localhost".to_string()]),
+            vec![pinned.clone()],
+        );
+        let mut config = viaduct::TlsConfig {
+            extra_root_certs: vec![server.cert_der.clone()],
+            ..viaduct::TlsConfig::default()
+        };
+        config
+            .pinned_spki_hashes
+            .insert("localhost".into(), vec![spki_hash(&pinned)]);
+        match server.get(&config) {
+            Err(viaduct::Error::PinViolation { host }) => assert_eq!(host, "localhost"),
+            other => panic!("Expected a pin violation, got {:?}", other),
+        }
+
+        config
+            .pinned_spki_hashes
+            .insert("localhost".into(), vec![server.spki_hash()]);
+        assert_eq!(server.get(&config).unwrap().status, 200);
+    }
+
+    #[test]
+    fn test_unconfigured_hosts_use_native_tls() {
+        let mut config = viaduct::TlsConfig::default();
+        config
+            .pinned_spki_hashes
+            .insert("pinned.example.com".into(), vec![[0u8; 32]]);
+        config
+            .danger_accept_invalid_for_hosts
+            .push("localhost".into());
+        let clients = build_clients(&config);
+        let client_for = |url: &str| clients.for_url(&url::Url::parse(url).unwrap());
+        let rustls = clients.rustls.as_ref().unwrap();
+        assert!(std::ptr::eq(
+            client_for("https://pinned.example.com/"),
+            rustls
+        ));
+        assert!(std::ptr::eq(client_for("https://localhost:4242/"), rustls));
+        assert!(std::ptr::eq(
+            client_for("https://www.example.com/"),
+            &clients.native
+        ));
+
+        // Without pins or skipped verification, there's no need for rustls.
+        let config = viaduct::TlsConfig {
+            extra_root_certs: vec![TestServer::start().cert_der],
+            ..viaduct::TlsConfig::default()
+        };
+        assert!(build_clients(&config).rustls.is_none());
+
+        // So a host which isn't configured is verified like it would be
+        // without a config: the same self-signed certificate is rejected.
+        let server = TestServer::start();
+        let mut config = viaduct::TlsConfig::default();
+        config
+            .pinned_spki_hashes
+            .insert("pinned.example.com".into(), vec![server.spki_hash()]);
+        match server.get(&config) {
+            Err(viaduct::Error::TlsError { .. }) => {}
+            other => panic!("Expected a TLS error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_danger_accept_invalid() {
+        let server = TestServer::start();
+        let mut config = viaduct::TlsConfig {
+            danger_accept_invalid_for_hosts: vec!["localhost".into()],
+            ..viaduct::TlsConfig::default()
+        };
+        assert_eq!(server.get(&config).unwrap().status, 200);
+
+        // Other hosts are still verified.
+        config.danger_accept_invalid_for_hosts = vec!["example.com".into()];
+        match server.get(&config) {
+            Err(viaduct::Error::TlsError { .. }) => {}
+            other => panic!("Expected a TLS error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_not_yet_valid() {
+        let mut params = rcgen::CertificateParams::new(vec!["localhost".to_string()]);
+        params.not_before = rcgen::date_time_ymd(2099, 1, 1);
+        params.not_after = rcgen::date_time_ymd(2100, 1, 1);
+        let server = TestServer::start_with(params);
+        let config = viaduct::TlsConfig {
+            extra_root_certs: vec![server.cert_der.clone()],
+            ..viaduct::TlsConfig::default()
+        };
+        match server.get(&config) {
+            Err(viaduct::Error::TlsError { message }) => assert!(
+                message.contains("valid from 2099-01-01 00:00:00 UTC to 2100-01-01 00:00:00 UTC"),
+                "{}",
+                message
+            ),
+            other => panic!("Expected a TLS error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(
+            format_time(0x17, b"200131120000Z").unwrap(),
+            "2020-01-31 12:00:00 UTC"
+        );
+        assert_eq!(
+            format_time(0x17, b"991231235959Z").unwrap(),
+            "1999-12-31 23:59:59 UTC"
+        );
+        assert_eq!(
+            format_time(0x18, b"20990101000000Z").unwrap(),
+            "2099-01-01 00:00:00 UTC"
+        );
+        assert_eq!(format_time(0x17, b"20990101000000Z"), None);
+        assert_eq!(format_time(0x18, b"2099010100000+"), None);
+    }
+}
@@ -115,7 +115,11 @@ impl TokenFetcher for TokenServerFetcher {
             log::trace!("  Response body {}", resp.text());
             // XXX - shouldn't we "chain" these errors - ie, a BackoffError could
             // have a TokenserverHttpError as its cause?
-            if let Some(res) = resp.headers.get_as::<f64, _>(header_names::RETRY_AFTER) {
+            if let Some(res) = resp
+                .headers
+                .get_as::<f64, _>(header_names::RETRY_AFTER)
+                .transpose()
+            {
                 let ms = res
                     .ok()
                     .map_or(RETRY_AFTER_DEFAULT_MS, |f| (f * 1000f64) as u64);
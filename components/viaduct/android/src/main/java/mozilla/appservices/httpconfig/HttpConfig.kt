@@ -28,6 +28,9 @@ object RustHttpConfig {
     // bad things will happen if it does!
     @Volatile
     private var imp: CallbackImpl? = null
+    // Whether `imp` is registered with Rust; a shutdown unregisters it.
+    @Volatile
+    private var registered = false
 
     /**
      * Set the HTTP client to be used by all Rust code.
@@ -39,16 +42,68 @@ object RustHttpConfig {
             client = c
             if (imp == null) {
                 imp = CallbackImpl()
+            }
+            if (!registered) {
                 LibViaduct.INSTANCE.viaduct_initialize(imp!!)
+                registered = true
             }
         }
     }
 
+    /**
+     * Tell Rust code whether the device is connected. While it's
+     * [NetworkStatus.OFFLINE], requests fail immediately instead of
+     * waiting to time out. This may be called from any thread.
+     */
+    fun setNetworkStatus(status: NetworkStatus) {
+        LibViaduct.INSTANCE.viaduct_set_network_status(status.value)
+    }
+
+    /**
+     * Set the User-Agent sent with requests made by Rust code, unless they
+     * set their own. This must be called before the first request is made,
+     * and only once; returns false if it's too late, or if [userAgent]
+     * isn't a valid header value.
+     */
+    fun setUserAgent(userAgent: String): Boolean {
+        return LibViaduct.INSTANCE.viaduct_set_user_agent(userAgent) == 1.toByte()
+    }
+
+    /**
+     * Limit how many requests made by Rust code are handed to the [Client]
+     * at once. Once [maxConcurrent] are in flight, up to [maxQueued] more
+     * wait their turn on the threads that made them, and any beyond that
+     * fail straight away. Passing 0 for [maxConcurrent] lifts the limit.
+     * This may be called at any time, from any thread.
+     */
+    fun setCallbackLimits(maxConcurrent: Int, maxQueued: Int) {
+        LibViaduct.INSTANCE.viaduct_set_callback_limits(maxConcurrent, maxQueued)
+    }
+
+    /**
+     * Shut down the Rust networking code, before the library is unloaded.
+     * New requests fail straight away, and the ones in flight are drained
+     * or aborted, according to [policy], waiting up to [timeoutMs] for
+     * them. Returns false if some were still in flight when it gave up.
+     * Call [setClient] again to make requests afterwards.
+     */
+    @Synchronized
+    fun shutdown(policy: ShutdownPolicy, timeoutMs: Int): Boolean {
+        // Not under `lock`, since the requests in flight hold it to fetch.
+        val done = LibViaduct.INSTANCE.viaduct_shutdown(policy.value, timeoutMs) == 1.toByte()
+        lock.write {
+            registered = false
+        }
+        return done
+    }
+
     internal fun convertRequest(request: MsgTypes.Request): Request {
         val headers = MutableHeaders()
         for (h in request.headersMap) {
             headers.append(h.key, h.value)
         }
+        // Rust has already normalized the URL (see `urlNormalized`), and it
+        // may have been signed as it is, so pass it on unchanged.
         return Request(
                 url = request.url,
                 method = convertMethod(request.method),
@@ -81,6 +136,8 @@ object RustHttpConfig {
                     val resp = client!!.value.fetch(convertRequest(request))
                     val rb = MsgTypes.Response.newBuilder()
                             .setUrl(resp.url)
+                            // concept-fetch reports the URL after redirects.
+                            .setFinalUrl(resp.url)
                             .setStatus(resp.status)
                             .setBody(resp.body.useStream {
                                 ByteString.readFrom(it)
@@ -91,7 +148,9 @@ object RustHttpConfig {
                     }
                     rb
                 } catch (e: Throwable) {
-                    MsgTypes.Response.newBuilder().setExceptionMessage("fetch error: ${e.message ?: e.javaClass.canonicalName}")
+                    MsgTypes.Response.newBuilder()
+                            .setExceptionMessage("fetch error: ${e.message ?: e.javaClass.canonicalName}")
+                            .setExceptionType(exceptionType(e))
                 }
                 val built = rb.build()
                 val needed = built.serializedSize
@@ -115,6 +174,28 @@ object RustHttpConfig {
     }
 }
 
+/**
+ * The device's connectivity, for [RustHttpConfig.setNetworkStatus]. The
+ * values must match `viaduct::NetworkStatus`.
+ */
+enum class NetworkStatus(internal val value: Byte) {
+    ONLINE(0),
+    OFFLINE(1),
+    /** Connected, but using data may cost the user money. */
+    METERED(2),
+}
+
+/**
+ * What [RustHttpConfig.shutdown] does with the requests in flight. The
+ * values must match `viaduct_shutdown`.
+ */
+enum class ShutdownPolicy(internal val value: Byte) {
+    /** Wait for them to finish. */
+    DRAIN(0),
+    /** Fail them with a cancellation error. */
+    ABORT(1),
+}
+
 internal fun convertMethod(m: MsgTypes.Request.Method): Request.Method {
     return when (m) {
         MsgTypes.Request.Method.GET -> Request.Method.GET
@@ -125,6 +206,22 @@ internal fun convertMethod(m: MsgTypes.Request.Method): Request.Method {
         MsgTypes.Request.Method.PUT -> Request.Method.PUT
         MsgTypes.Request.Method.TRACE -> Request.Method.TRACE
         MsgTypes.Request.Method.CONNECT -> Request.Method.CONNECT
+        // concept-fetch can't send these, so they fail like any other
+        // fetch error.
+        MsgTypes.Request.Method.PATCH -> throw UnsupportedOperationException("PATCH isn't supported")
+    }
+}
+
+internal fun exceptionType(e: Throwable): MsgTypes.Response.ExceptionType {
+    return when (e) {
+        is java.net.UnknownHostException -> MsgTypes.Response.ExceptionType.DNS
+        is javax.net.ssl.SSLException -> MsgTypes.Response.ExceptionType.TLS
+        is java.net.SocketTimeoutException -> MsgTypes.Response.ExceptionType.TIMEOUT
+        is java.net.NoRouteToHostException -> MsgTypes.Response.ExceptionType.NETWORK_UNREACHABLE
+        is java.net.ConnectException -> MsgTypes.Response.ExceptionType.CONNECTION_REFUSED
+        is java.io.InterruptedIOException, is InterruptedException ->
+            MsgTypes.Response.ExceptionType.CANCELLED
+        else -> MsgTypes.Response.ExceptionType.OTHER
     }
 }
 
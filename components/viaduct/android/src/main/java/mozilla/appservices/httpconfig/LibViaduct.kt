@@ -26,6 +26,14 @@ internal interface LibViaduct : Library {
     fun viaduct_alloc_bytebuffer(sz: Int): RustBuffer.ByValue
     // Returns 0 to indicate redundant init.
     fun viaduct_initialize(cb: RawFetchCallback): Byte
+    // Returns 0 if the status isn't one Rust knows about.
+    fun viaduct_set_network_status(status: Byte): Byte
+    // Returns 0 if it's too late to set it, or the value is invalid.
+    fun viaduct_set_user_agent(userAgent: String): Byte
+    // A maxConcurrent of 0 lifts the limit.
+    fun viaduct_set_callback_limits(maxConcurrent: Int, maxQueued: Int)
+
+    fun viaduct_shutdown(policy: Byte, timeoutMs: Int): Byte
 
     fun viaduct_log_error(s: String)
 }
@@ -0,0 +1,407 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Limits on how many requests the FFI backend hands to the embedding's
+//! fetch callback at once.
+//!
+//! The callback runs on whichever thread sent the request, so without a
+//! limit, every component which makes requests at the same time ties up the
+//! embedding's HTTP stack (and its threads) at once. With
+//! [`set_callback_limits`], requests beyond `max_concurrent` wait their turn,
+//! first come first served, and once `max_queued` are waiting, further
+//! requests fail straight away with [`Error::TooManyRequests`].
+//!
+//! The lock is only held to take a turn and to give it back, never while the
+//! callback runs, since the callback may well call back into Rust.
+//!
+//! Aborting a [`shutdown`](crate::shutdown) fails the requests which are
+//! waiting with [`Error::Cancelled`].
+
+use crate::clock::{self, Clock};
+use crate::Error;
+use once_cell::sync::Lazy;
+use serde_derive::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+static LIMITER: Lazy<CallbackLimiter> = Lazy::new(CallbackLimiter::default);
+
+/// See [`set_callback_limits`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct CallbackLimits {
+    /// The most requests the callback may be running at once. Zero is
+    /// treated as one.
+    pub max_concurrent: usize,
+    /// The most requests which may wait for a turn. Zero means requests
+    /// fail rather than wait.
+    pub max_queued: usize,
+}
+
+/// How requests have been waiting for the fetch callback, as reported by
+/// [`backend_info`](crate::backend_info).
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct CallbackQueueInfo {
+    /// The limits set with [`set_callback_limits`], if any.
+    pub limits: Option<CallbackLimits>,
+    /// How many requests the callback is running now.
+    pub in_flight: usize,
+    /// How many requests are waiting for a turn now.
+    pub queued: usize,
+    /// The most requests which have been waiting at once.
+    pub max_queued_seen: usize,
+    /// How many requests have had to wait for a turn.
+    pub num_queued: u64,
+    /// How many requests failed because too many were already waiting.
+    pub num_rejected: u64,
+    /// How long requests have spent waiting, in total and at most.
+    pub total_queue_wait_ms: u64,
+    pub longest_queue_wait_ms: u64,
+}
+
+/// Limit the requests the FFI backend's fetch callback runs at once, or
+/// lift the limit with `None`. This can be called at any time, and applies
+/// to requests which haven't had their turn yet.
+pub fn set_callback_limits(limits: Option<CallbackLimits>) {
+    LIMITER.set_limits(limits)
+}
+
+pub(super) fn run<T>(f: impl FnOnce() -> T) -> Result<T, Error> {
+    LIMITER.run(f)
+}
+
+pub(super) fn queue_info() -> CallbackQueueInfo {
+    LIMITER.info()
+}
+
+pub(super) fn cancel_waiting() {
+    LIMITER.cancel_waiting()
+}
+
+#[derive(Default)]
+struct CallbackLimiter {
+    state: Mutex<State>,
+    // Notified whenever a turn might have come up.
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    limits: Option<CallbackLimits>,
+    in_flight: usize,
+    // The tickets of the requests waiting for a turn, in the order they
+    // arrived.
+    waiting: VecDeque<u64>,
+    next_ticket: u64,
+    // Bumped by `cancel_waiting`, so waiters can tell they were cancelled.
+    cancellations: u64,
+    stats: CallbackQueueInfo,
+}
+
+impl State {
+    fn has_room(&self) -> bool {
+        match self.limits {
+            Some(limits) => self.in_flight < limits.max_concurrent.max(1),
+            None => true,
+        }
+    }
+}
+
+impl CallbackLimiter {
+    fn set_limits(&self, limits: Option<CallbackLimits>) {
+        self.state.lock().unwrap().limits = limits;
+        // Waiters might have more room now.
+        self.changed.notify_all();
+    }
+
+    // Fail every request which is waiting for a turn now.
+    fn cancel_waiting(&self) {
+        self.state.lock().unwrap().cancellations += 1;
+        self.changed.notify_all();
+    }
+
+    fn info(&self) -> CallbackQueueInfo {
+        let state = self.state.lock().unwrap();
+        CallbackQueueInfo {
+            limits: state.limits,
+            in_flight: state.in_flight,
+            queued: state.waiting.len(),
+            ..state.stats.clone()
+        }
+    }
+
+    fn run<T>(&self, f: impl FnOnce() -> T) -> Result<T, Error> {
+        let _turn = self.acquire_turn()?;
+        Ok(f())
+    }
+
+    fn acquire_turn(&self) -> Result<Turn<'_>, Error> {
+        let mut state = self.state.lock().unwrap();
+        let limits = match state.limits {
+            Some(limits) => limits,
+            None => {
+                state.in_flight += 1;
+                return Ok(Turn { limiter: self });
+            }
+        };
+        // Only skip the queue if nobody's already in it.
+        if state.waiting.is_empty() && state.has_room() {
+            state.in_flight += 1;
+            return Ok(Turn { limiter: self });
+        }
+        if state.waiting.len() >= limits.max_queued {
+            state.stats.num_rejected += 1;
+            return Err(Error::TooManyRequests {
+                max_concurrent: limits.max_concurrent,
+                max_queued: limits.max_queued,
+            });
+        }
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.waiting.push_back(ticket);
+        state.stats.num_queued += 1;
+        state.stats.max_queued_seen = state.stats.max_queued_seen.max(state.waiting.len());
+
+        let cancellations = state.cancellations;
+        let start = clock::current().now();
+        loop {
+            if state.cancellations != cancellations {
+                state.waiting.retain(|&waiting| waiting != ticket);
+                // We might have been holding up the next in line.
+                self.changed.notify_all();
+                return Err(Error::Cancelled);
+            }
+            if state.waiting.front() == Some(&ticket) && state.has_room() {
+                break;
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+        state.waiting.pop_front();
+        state.in_flight += 1;
+        let waited = duration_ms(clock::current().now() - start);
+        log::debug!("Waited {}ms for a turn with the fetch callback", waited);
+        state.stats.total_queue_wait_ms += waited;
+        state.stats.longest_queue_wait_ms = state.stats.longest_queue_wait_ms.max(waited);
+        // The next in line might be able to go too, if the limits were
+        // raised while we waited.
+        self.changed.notify_all();
+        Ok(Turn { limiter: self })
+    }
+}
+
+fn duration_ms(duration: Duration) -> u64 {
+    duration.as_millis() as u64
+}
+
+// A request the callback is running, counted against the limit until it's
+// dropped.
+struct Turn<'a> {
+    limiter: &'a CallbackLimiter,
+}
+
+impl<'a> Drop for Turn<'a> {
+    fn drop(&mut self) {
+        self.limiter.state.lock().unwrap().in_flight -= 1;
+        self.limiter.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{mpsc, Arc};
+    use std::thread::JoinHandle;
+    use std::time::Instant;
+
+    fn limiter(max_concurrent: usize, max_queued: usize) -> Arc<CallbackLimiter> {
+        let limiter = Arc::new(CallbackLimiter::default());
+        limiter.set_limits(Some(CallbackLimits {
+            max_concurrent,
+            max_queued,
+        }));
+        limiter
+    }
+
+    // Waits until `limiter` has `in_flight` requests running and `queued`
+    // waiting, failing the test if that takes more than a few seconds.
+    fn wait_for(limiter: &CallbackLimiter, in_flight: usize, queued: usize) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let info = limiter.info();
+            if info.in_flight == in_flight && info.queued == queued {
+                return;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "Expected {} in flight and {} queued, got {:?}",
+                in_flight,
+                queued,
+                info
+            );
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    // Stands in for a slow fetch callback: sends `n` on `started`, then
+    // doesn't return until it's told to.
+    fn scripted_send(
+        limiter: &Arc<CallbackLimiter>,
+        n: usize,
+        started: &mpsc::Sender<usize>,
+    ) -> (JoinHandle<Result<usize, Error>>, mpsc::Sender<()>) {
+        let (finish, finished) = mpsc::channel::<()>();
+        let limiter = limiter.clone();
+        let started = started.clone();
+        let thread = std::thread::spawn(move || {
+            limiter.run(|| {
+                started.send(n).unwrap();
+                finished.recv().unwrap();
+                n
+            })
+        });
+        (thread, finish)
+    }
+
+    #[test]
+    fn test_concurrency_limit() {
+        let limiter = limiter(2, 10);
+        let (started, starts) = mpsc::channel();
+        let (threads, finishes): (Vec<_>, Vec<_>) =
+            (0..8).map(|n| scripted_send(&limiter, n, &started)).unzip();
+        // Two get a turn straight away, and the rest wait for one.
+        wait_for(&limiter, 2, 6);
+        let mut running = vec![starts.recv().unwrap(), starts.recv().unwrap()];
+        assert!(starts.try_recv().is_err());
+
+        // Each time one finishes, the next in line takes its place.
+        for queued in (0..6).rev() {
+            let n = running.remove(0);
+            finishes[n].send(()).unwrap();
+            running.push(starts.recv().unwrap());
+            let info = limiter.info();
+            assert_eq!(info.in_flight, 2);
+            assert_eq!(info.queued, queued);
+        }
+        for n in running {
+            finishes[n].send(()).unwrap();
+        }
+        for (n, thread) in threads.into_iter().enumerate() {
+            assert_eq!(thread.join().unwrap().unwrap(), n);
+        }
+
+        let info = limiter.info();
+        assert_eq!(info.in_flight, 0);
+        assert_eq!(info.queued, 0);
+        assert_eq!(info.num_queued, 6);
+        assert_eq!(info.max_queued_seen, 6);
+        assert_eq!(info.num_rejected, 0);
+    }
+
+    #[test]
+    fn test_fifo_and_overflow() {
+        let limiter = limiter(1, 3);
+        let (started, starts) = mpsc::channel();
+
+        let (first, finish_first) = scripted_send(&limiter, 0, &started);
+        assert_eq!(starts.recv().unwrap(), 0);
+        // Queue the rest one at a time, so we know the order they arrived in.
+        let mut queued = vec![];
+        for n in 1..=3 {
+            queued.push(scripted_send(&limiter, n, &started));
+            wait_for(&limiter, 1, n);
+        }
+
+        // The queue's full, so this fails without waiting, or running.
+        let err = limiter.run(|| unreachable!()).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::TooManyRequests {
+                max_concurrent: 1,
+                max_queued: 3,
+            }
+        ));
+
+        // Each request starts when the one before it finishes, in the order
+        // they were queued.
+        finish_first.send(()).unwrap();
+        assert_eq!(first.join().unwrap().unwrap(), 0);
+        for (n, (thread, finish)) in queued.into_iter().enumerate() {
+            assert_eq!(starts.recv().unwrap(), n + 1);
+            finish.send(()).unwrap();
+            assert_eq!(thread.join().unwrap().unwrap(), n + 1);
+        }
+
+        let info = limiter.info();
+        assert_eq!(info.in_flight, 0);
+        assert_eq!(info.queued, 0);
+        assert_eq!(info.max_queued_seen, 3);
+        assert_eq!(info.num_queued, 3);
+        assert_eq!(info.num_rejected, 1);
+    }
+
+    #[test]
+    fn test_no_queue() {
+        let limiter = limiter(1, 0);
+        let (started, starts) = mpsc::channel();
+        let (first, finish_first) = scripted_send(&limiter, 0, &started);
+        starts.recv().unwrap();
+        assert!(matches!(
+            limiter.run(|| ()),
+            Err(Error::TooManyRequests { .. })
+        ));
+        finish_first.send(()).unwrap();
+        first.join().unwrap().unwrap();
+        // Now there's room again.
+        assert_eq!(limiter.run(|| 1).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_cancel_waiting() {
+        let limiter = limiter(1, 5);
+        let (started, starts) = mpsc::channel();
+        let (first, finish_first) = scripted_send(&limiter, 0, &started);
+        starts.recv().unwrap();
+        let waiting = (1..=2)
+            .map(|n| {
+                let sent = scripted_send(&limiter, n, &started);
+                wait_for(&limiter, 1, n);
+                sent
+            })
+            .collect::<Vec<_>>();
+
+        // The waiting requests fail without running, but the one which is
+        // already running carries on.
+        limiter.cancel_waiting();
+        for (thread, _finish) in waiting {
+            assert!(matches!(thread.join().unwrap(), Err(Error::Cancelled)));
+        }
+        assert_eq!(limiter.info().queued, 0);
+        finish_first.send(()).unwrap();
+        assert_eq!(first.join().unwrap().unwrap(), 0);
+        assert!(starts.try_recv().is_err());
+
+        // Later requests aren't affected.
+        assert_eq!(limiter.run(|| 1).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_raising_limits_releases_waiters() {
+        let limiter = limiter(1, 5);
+        let (started, starts) = mpsc::channel();
+        let (first, finish_first) = scripted_send(&limiter, 0, &started);
+        starts.recv().unwrap();
+        let (second, finish_second) = scripted_send(&limiter, 1, &started);
+        wait_for(&limiter, 1, 1);
+
+        // Lifting the limit lets the waiting request go straight away, while
+        // the first is still running.
+        limiter.set_limits(None);
+        assert_eq!(starts.recv().unwrap(), 1);
+        finish_second.send(()).unwrap();
+        assert_eq!(second.join().unwrap().unwrap(), 1);
+        finish_first.send(()).unwrap();
+        assert_eq!(first.join().unwrap().unwrap(), 0);
+        assert_eq!(limiter.info().limits, None);
+    }
+}
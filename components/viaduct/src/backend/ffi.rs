@@ -5,11 +5,11 @@
 use crate::{backend::Backend, settings::GLOBAL_SETTINGS};
 use crate::{msg_types, Error};
 use ffi_support::{ByteBuffer, FfiStr};
-
-ffi_support::implement_into_ffi_by_protobuf!(msg_types::Request);
+use std::os::raw::c_char;
 
 impl From<crate::Request> for msg_types::Request {
     fn from(request: crate::Request) -> Self {
+        let follow_redirects = request.should_follow_redirects();
         msg_types::Request {
             url: request.url.into_string(),
             body: request.body,
@@ -17,7 +17,7 @@ impl From<crate::Request> for msg_types::Request {
             // it certainly makes it convenient for us...
             method: request.method as i32,
             headers: request.headers.into(),
-            follow_redirects: GLOBAL_SETTINGS.follow_redirects,
+            follow_redirects,
             use_caches: GLOBAL_SETTINGS.use_caches,
             connect_timeout_secs: GLOBAL_SETTINGS
                 .connect_timeout
@@ -25,6 +25,8 @@ impl From<crate::Request> for msg_types::Request {
             read_timeout_secs: GLOBAL_SETTINGS
                 .read_timeout
                 .map_or(0, |d| d.as_secs() as i32),
+            // `backend::send` normalized it already.
+            url_normalized: Some(true),
         }
     }
 }
@@ -37,72 +39,164 @@ macro_rules! backend_error {
     }};
 }
 
+/// The error for a response with an `exception_message`. Backends which
+/// don't send an `exception_type` (or send one we don't know about) get the
+/// catch-all `NetworkError`, as before there were types.
+fn exception_error(exception_type: Option<i32>, message: String) -> Error {
+    use msg_types::response::ExceptionType;
+    match exception_type.and_then(ExceptionType::from_i32) {
+        Some(ExceptionType::NetworkUnreachable) => Error::Offline(message),
+        Some(ExceptionType::Dns) => Error::DnsError(message),
+        Some(ExceptionType::Tls) => Error::TlsError { message },
+        Some(ExceptionType::Timeout) => Error::Timeout(message),
+        Some(ExceptionType::Cancelled) => Error::Cancelled,
+        Some(ExceptionType::ConnectionRefused) => Error::ConnectionRefused(message),
+        Some(ExceptionType::Other) | None => {
+            Error::NetworkError(format!("Java error: {:?}", message))
+        }
+    }
+}
+
 pub struct FfiBackend;
 impl Backend for FfiBackend {
     fn send(&self, request: crate::Request) -> Result<crate::Response, Error> {
-        use ffi_support::IntoFfi;
-        use prost::Message;
-        super::note_backend("FFI (trusted)");
-
-        let method = request.method;
+        super::note_backend(self.name());
         let fetch = callback_holder::get_callback().ok_or(Error::BackendNotInitialized)?;
-        let proto_req: msg_types::Request = request.into();
-        let buf = proto_req.into_ffi_value();
-        let response = unsafe { fetch(buf) };
-        // This way we'll Drop it if we panic, unlike if we just got a slice into
-        // it. Besides, we already own it.
-        let response_bytes = response.destroy_into_vec();
-
-        let response: msg_types::Response = match Message::decode(response_bytes.as_slice()) {
-            Ok(v) => v,
-            Err(e) => {
-                panic!(
-                    "Failed to parse protobuf returned from fetch callback! {}",
-                    e
-                );
-            }
-        };
+        super::callback_limits::run(|| send_via(fetch, request))?
+    }
 
-        if let Some(exn) = response.exception_message {
-            return Err(Error::NetworkError(format!("Java error: {:?}", exn)));
-        }
-        let status = response
-            .status
-            .ok_or_else(|| backend_error!("Missing HTTP status"))?;
+    fn name(&self) -> &'static str {
+        "FFI (trusted)"
+    }
+}
 
-        if status < 0 || status > i32::from(u16::max_value()) {
-            return Err(backend_error!("Illegal HTTP status: {}", status));
-        }
+fn send_via(fetch: FetchCallback, request: crate::Request) -> Result<crate::Response, Error> {
+    use prost::Message;
 
-        let mut headers = crate::Headers::with_capacity(response.headers.len());
-        for (name, val) in response.headers {
-            let hname = match crate::HeaderName::new(name) {
-                Ok(name) => name,
-                Err(e) => {
-                    // Ignore headers with invalid names, since nobody can look for them anyway.
-                    log::warn!("Server sent back invalid header name: '{}'", e);
-                    continue;
-                }
-            };
-            // Not using Header::new since the error it returns is for request headers.
-            headers.insert_header(crate::Header::new_unchecked(hname, val));
+    let method = request.method;
+    let sensitive = request.sensitive;
+    let request_id = request.id();
+    // The embedding's fetch callback doesn't tell us how it's getting on,
+    // so the best we can do is report the start and the end.
+    let upload_len = request.body.as_ref().map_or(0, |body| body.len() as u64);
+    let upload_progress = request.upload_progress.clone();
+    let download_progress = request.download_progress.clone();
+    let size_limit = request.response_size_limit();
+    if let Some(hook) = &upload_progress {
+        hook.report(0, Some(upload_len));
+    }
+    let mut proto_req: msg_types::Request = request.into();
+    // Exactly the right size, so that encoding doesn't leave copies behind
+    // in reallocated memory.
+    let mut request_bytes = Vec::with_capacity(proto_req.encoded_len());
+    proto_req
+        .encode(&mut request_bytes)
+        .map_err(|e| backend_error!("Failed to encode request: {}", e))?;
+    if sensitive {
+        if let Some(body) = &mut proto_req.body {
+            crate::sensitive::zeroize(body);
         }
+    }
+    drop(proto_req);
+    // The embedding frees (and zeroes) this with `viaduct_destroy_bytebuffer`.
+    let response = unsafe { fetch(ByteBuffer::from_vec(request_bytes)) };
+    // This way we'll Drop it if we panic, unlike if we just got a slice into
+    // it. Besides, we already own it.
+    let mut response_bytes = response.destroy_into_vec();
 
-        let url = url::Url::parse(
-            &response
-                .url
-                .ok_or_else(|| backend_error!("Response has no URL"))?,
+    // A garbled response is a bug in the embedding's backend, but that's
+    // no reason to take the whole process down with it.
+    let decoded = Message::decode(response_bytes.as_slice());
+    if sensitive {
+        crate::sensitive::zeroize(&mut response_bytes);
+    }
+    let response: msg_types::Response = decoded.map_err(|e| {
+        backend_error!(
+            "Failed to parse protobuf returned from fetch callback: {}",
+            e
         )
-        .map_err(|e| backend_error!("Response has illegal URL: {}", e))?;
-
-        Ok(crate::Response {
-            url,
-            request_method: method,
-            body: response.body.unwrap_or_default(),
-            status: status as u16,
-            headers,
-        })
+    })?;
+
+    if let Some(exn) = response.exception_message {
+        return Err(exception_error(response.exception_type, exn));
+    }
+    let status = response
+        .status
+        .ok_or_else(|| backend_error!("Missing HTTP status"))?;
+
+    if status < 0 || status > i32::from(u16::max_value()) {
+        return Err(backend_error!("Illegal HTTP status: {}", status));
+    }
+
+    let mut headers = crate::Headers::with_capacity(response.headers.len());
+    for (name, val) in response.headers {
+        let hname = match crate::HeaderName::new(name) {
+            Ok(name) => name,
+            Err(e) => {
+                // Ignore headers with invalid names, since nobody can look for them anyway.
+                log::warn!("Server sent back invalid header name: '{}'", e);
+                continue;
+            }
+        };
+        // Not using Header::new since the error it returns is for request headers.
+        headers.insert_header(crate::Header::new_unchecked(hname, val));
+    }
+
+    let url = url::Url::parse(
+        &response
+            .url
+            .ok_or_else(|| backend_error!("Response has no URL"))?,
+    )
+    .map_err(|e| backend_error!("Response has illegal URL: {}", e))?;
+    let final_url = match response.final_url {
+        Some(final_url) => url::Url::parse(&final_url)
+            .map_err(|e| backend_error!("Response has illegal final URL: {}", e))?,
+        None => url.clone(),
+    };
+
+    if let Some(hook) = &upload_progress {
+        hook.report(upload_len, Some(upload_len));
+    }
+    // HEAD responses have no body, even if they have a `Content-Length`.
+    let body = if method == crate::Method::Head {
+        Vec::new()
+    } else {
+        response.body.unwrap_or_default()
+    };
+    // By now the embedding has buffered the whole thing, but at least it
+    // goes no further.
+    if body.len() as u64 > size_limit {
+        return Err(Error::ResponseTooLarge {
+            limit: size_limit,
+            content_length: headers.try_get(crate::header_names::CONTENT_LENGTH),
+        });
+    }
+    if let Some(hook) = &download_progress {
+        hook.report(body.len() as u64, Some(body.len() as u64));
     }
+
+    Ok(crate::Response {
+        url,
+        final_url,
+        // The platform's HTTP stack doesn't tell us about these.
+        redirects: vec![],
+        request_method: method,
+        body,
+        status: status as u16,
+        headers,
+        from_cache: false,
+        request_id,
+    })
+}
+
+pub(super) fn callback_initialized() -> bool {
+    callback_holder::get_callback().is_some()
+}
+
+// Forget the fetch callback, so that `viaduct_initialize` can register one
+// again after a shutdown.
+pub(super) fn clear_callback() {
+    callback_holder::clear_callback()
 }
 
 /// Type of the callback we need callers on the other side of the FFI to
@@ -124,7 +218,7 @@ mod callback_holder {
     use super::FetchCallback;
     use std::sync::atomic::{AtomicUsize, Ordering};
 
-    /// Note: We only assign to this once.
+    /// Note: We only assign to this once, unless `clear_callback` is called.
     static CALLBACK_PTR: AtomicUsize = AtomicUsize::new(0);
 
     // Overly-paranoid sanity checking to ensure that these types are
@@ -166,6 +260,10 @@ mod callback_holder {
             }
         }
     }
+
+    pub(super) fn clear_callback() {
+        CALLBACK_PTR.store(0, Ordering::SeqCst);
+    }
 }
 
 /// Return a ByteBuffer of the requested size. This is used to store the
@@ -188,9 +286,403 @@ pub extern "C" fn viaduct_log_error(s: FfiStr<'_>) {
     error.consume_and_log_if_error();
 }
 
+/// Registers the embedding application's fetch callback, and chooses the
+/// FFI backend, as with `init(BackendChoice::FfiCallback)`. Returns false if
+/// the callback was already registered, or if a different backend had already
+/// been chosen.
 #[no_mangle]
 pub extern "C" fn viaduct_initialize(callback: FetchCallback) -> u8 {
-    ffi_support::abort_on_panic::call_with_output(|| callback_holder::set_callback(callback))
+    ffi_support::abort_on_panic::call_with_output(|| {
+        if !callback_holder::set_callback(callback) {
+            return false;
+        }
+        match crate::init(crate::BackendChoice::FfiCallback) {
+            Ok(()) => true,
+            Err(e) => {
+                log::error!("Registered the fetch callback, but can't use it: {}", e);
+                false
+            }
+        }
+    })
+}
+
+/// Sets the network status, as with `viaduct::set_network_status`, from its
+/// value as a `NetworkStatus` (0 for online, 1 for offline, 2 for metered).
+/// Returns false, without effect, for any other value.
+#[no_mangle]
+pub extern "C" fn viaduct_set_network_status(status: u8) -> u8 {
+    ffi_support::abort_on_panic::call_with_output(|| match crate::NetworkStatus::from_u8(status) {
+        Some(status) => {
+            crate::set_network_status(status);
+            true
+        }
+        None => {
+            log::error!("Unknown network status {}", status);
+            false
+        }
+    })
+}
+
+/// Sets the User-Agent sent with every request, as with
+/// `viaduct::set_default_headers`. Returns false, without effect, if the
+/// default headers were already set or a request was already made, or if
+/// `user_agent` isn't a valid header value.
+#[no_mangle]
+pub extern "C" fn viaduct_set_user_agent(user_agent: FfiStr<'_>) -> u8 {
+    ffi_support::abort_on_panic::call_with_output(|| {
+        let defaults = crate::DefaultHeaders::with_user_agent(user_agent.as_str());
+        match crate::set_default_headers(defaults) {
+            Ok(()) => true,
+            Err(e) => {
+                log::error!("Can't set the user agent: {}", e);
+                false
+            }
+        }
+    })
 }
 
-ffi_support::define_bytebuffer_destructor!(viaduct_destroy_bytebuffer);
+/// Limits the requests the fetch callback runs at once, as with
+/// `viaduct::set_callback_limits`. Once `max_concurrent` requests are in
+/// flight, up to `max_queued` more wait their turn, and any beyond that fail.
+/// A `max_concurrent` of zero (or less) lifts the limit.
+#[no_mangle]
+pub extern "C" fn viaduct_set_callback_limits(max_concurrent: i32, max_queued: i32) {
+    ffi_support::abort_on_panic::call_with_output(|| {
+        let limits = if max_concurrent > 0 {
+            Some(crate::CallbackLimits {
+                max_concurrent: max_concurrent as usize,
+                max_queued: max_queued.max(0) as usize,
+            })
+        } else {
+            None
+        };
+        crate::set_callback_limits(limits)
+    })
+}
+
+fn diagnostics_error(e: impl std::fmt::Display) -> ffi_support::ExternError {
+    ffi_support::ExternError::new_error(ffi_support::ErrorCode::new(1), e.to_string())
+}
+
+/// Shuts viaduct down, as with `viaduct::shutdown`, before the library is
+/// unloaded. `policy` is 0 to drain in-flight requests, or 1 to abort them,
+/// and `timeout_ms` is how long to wait for them. Returns true if none were
+/// still in flight when it returned, and false, without effect, for an
+/// unknown policy.
+#[no_mangle]
+pub extern "C" fn viaduct_shutdown(policy: u8, timeout_ms: u32) -> u8 {
+    ffi_support::abort_on_panic::call_with_output(|| {
+        let policy = match policy {
+            0 => crate::ShutdownPolicy::Drain,
+            1 => crate::ShutdownPolicy::Abort,
+            _ => {
+                log::error!("Unknown shutdown policy {}", policy);
+                return false;
+            }
+        };
+        let report = crate::shutdown(
+            policy,
+            std::time::Duration::from_millis(u64::from(timeout_ms)),
+        );
+        report.abandoned == 0
+    })
+}
+
+/// Returns `viaduct::backend_info()` as a JSON string, which must be freed
+/// with `viaduct_destroy_string`.
+#[no_mangle]
+pub extern "C" fn viaduct_backend_info(error: &mut ffi_support::ExternError) -> *mut c_char {
+    ffi_support::call_with_result(error, || {
+        serde_json::to_string(&super::backend_info()).map_err(diagnostics_error)
+    })
+}
+
+/// Runs `viaduct::probe()` against `url`, and returns the result as a JSON
+/// string, which must be freed with `viaduct_destroy_string`. On failure,
+/// `error` is set with code 1 and a description of the failure.
+#[no_mangle]
+pub extern "C" fn viaduct_probe(
+    url: FfiStr<'_>,
+    error: &mut ffi_support::ExternError,
+) -> *mut c_char {
+    ffi_support::call_with_result(error, || {
+        let url = url::Url::parse(url.as_str()).map_err(diagnostics_error)?;
+        let result = crate::probe(url).map_err(diagnostics_error)?;
+        serde_json::to_string(&result).map_err(diagnostics_error)
+    })
+}
+
+/// Starts keeping the last `max_entries` requests, for `viaduct_export_har`,
+/// as with `viaduct::debug::enable_capture`. Zero (or less) stops, and
+/// forgets the requests kept so far.
+#[no_mangle]
+pub extern "C" fn viaduct_enable_capture(max_entries: i32) {
+    ffi_support::abort_on_panic::call_with_output(|| {
+        crate::debug::enable_capture(max_entries.max(0) as usize)
+    })
+}
+
+/// Returns `viaduct::debug::export_har()`, a HAR document of the requests
+/// kept since `viaduct_enable_capture`, which must be freed with
+/// `viaduct_destroy_string`.
+#[no_mangle]
+pub extern "C" fn viaduct_export_har(error: &mut ffi_support::ExternError) -> *mut c_char {
+    ffi_support::call_with_output(error, crate::debug::export_har)
+}
+
+/// Frees a buffer passed to the fetch callback. It's zeroed first, since it
+/// may hold a sensitive request (see `Request::sensitive`).
+#[no_mangle]
+pub extern "C" fn viaduct_destroy_bytebuffer(buffer: ByteBuffer) {
+    ffi_support::abort_on_panic::call_with_output(|| {
+        crate::sensitive::zeroize(&mut buffer.destroy_into_vec())
+    })
+}
+
+ffi_support::define_string_destructor!(viaduct_destroy_string);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use msg_types::response::ExceptionType;
+    use std::cell::RefCell;
+
+    thread_local! {
+        static STUB_RESPONSE: RefCell<Option<msg_types::Response>> = RefCell::new(None);
+    }
+
+    // Stands in for the embedding's fetch callback, returning whatever
+    // `STUB_RESPONSE` was set to.
+    unsafe extern "C" fn stub_fetch(request: ByteBuffer) -> ByteBuffer {
+        use prost::Message;
+        // As the embedding does.
+        viaduct_destroy_bytebuffer(request);
+        let response = STUB_RESPONSE.with(|r| r.borrow_mut().take()).unwrap();
+        let mut bytes = vec![];
+        response.encode(&mut bytes).unwrap();
+        ByteBuffer::from_vec(bytes)
+    }
+
+    fn send_stub(response: msg_types::Response) -> Result<crate::Response, Error> {
+        STUB_RESPONSE.with(|r| *r.borrow_mut() = Some(response));
+        let url = url::Url::parse("https://www.example.com").unwrap();
+        send_via(stub_fetch, crate::Request::get(url))
+    }
+
+    fn failure(exception_type: Option<i32>) -> msg_types::Response {
+        msg_types::Response {
+            exception_message: Some("it broke".into()),
+            exception_type,
+            ..msg_types::Response::default()
+        }
+    }
+
+    #[test]
+    fn test_exception_types() {
+        let err = send_stub(failure(Some(ExceptionType::NetworkUnreachable as i32))).unwrap_err();
+        assert!(matches!(err, Error::Offline(m) if m == "it broke"));
+        let err = send_stub(failure(Some(ExceptionType::Dns as i32))).unwrap_err();
+        assert!(matches!(err, Error::DnsError(m) if m == "it broke"));
+        let err = send_stub(failure(Some(ExceptionType::Tls as i32))).unwrap_err();
+        assert!(matches!(err, Error::TlsError { message } if message == "it broke"));
+        let err = send_stub(failure(Some(ExceptionType::Timeout as i32))).unwrap_err();
+        assert!(matches!(err, Error::Timeout(m) if m == "it broke"));
+        let err = send_stub(failure(Some(ExceptionType::Cancelled as i32))).unwrap_err();
+        assert!(matches!(err, Error::Cancelled));
+        let err = send_stub(failure(Some(ExceptionType::ConnectionRefused as i32))).unwrap_err();
+        assert!(matches!(err, Error::ConnectionRefused(m) if m == "it broke"));
+        let err = send_stub(failure(Some(ExceptionType::Other as i32))).unwrap_err();
+        assert!(matches!(err, Error::NetworkError(m) if m.contains("it broke")));
+    }
+
+    #[test]
+    fn test_legacy_exception() {
+        // Older embeddings only send the message.
+        let err = send_stub(failure(None)).unwrap_err();
+        assert!(matches!(err, Error::NetworkError(m) if m == "Java error: \"it broke\""));
+        // And newer ones might send types we don't know about yet.
+        let err = send_stub(failure(Some(100))).unwrap_err();
+        assert!(matches!(err, Error::NetworkError(m) if m.contains("it broke")));
+    }
+
+    #[test]
+    fn test_set_network_status() {
+        use crate::NetworkStatus;
+        let _lock = crate::testing::lock();
+        assert_eq!(viaduct_set_network_status(1), 1);
+        assert_eq!(crate::network_status(), NetworkStatus::Offline);
+        let info = serde_json::to_value(&crate::backend_info()).unwrap();
+        assert_eq!(info["network_status"], "Offline");
+        assert_eq!(viaduct_set_network_status(2), 1);
+        assert_eq!(crate::network_status(), NetworkStatus::Metered);
+        // Unknown values are ignored.
+        assert_eq!(viaduct_set_network_status(3), 0);
+        assert_eq!(crate::network_status(), NetworkStatus::Metered);
+        assert_eq!(viaduct_set_network_status(0), 1);
+        assert_eq!(crate::network_status(), NetworkStatus::Online);
+    }
+
+    #[test]
+    fn test_set_user_agent() {
+        use std::ffi::CString;
+        let set = |ua: &str| {
+            let ua = CString::new(ua).unwrap();
+            viaduct_set_user_agent(FfiStr::from_cstr(&ua))
+        };
+        // Invalid values don't lock anything in.
+        assert_eq!(set("bad\u{7f}agent"), 0);
+        // Other tests may already have sent a request, and then it's too late.
+        if set("app/1.0") == 1 {
+            let info = serde_json::to_value(&crate::backend_info()).unwrap();
+            assert_eq!(
+                info["default_headers"],
+                serde_json::json!([["user-agent", "app/1.0"]])
+            );
+        }
+        assert_eq!(set("app/2.0"), 0);
+    }
+
+    #[test]
+    fn test_success() {
+        let response = send_stub(msg_types::Response {
+            url: Some("https://www.example.com/".into()),
+            status: Some(200),
+            body: Some(b"hello".to_vec()),
+            ..msg_types::Response::default()
+        })
+        .unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"hello");
+        assert_eq!(response.final_url, response.url);
+    }
+
+    #[test]
+    fn test_initialize_after_shutdown() {
+        let _lock = crate::testing::lock();
+        // Start from scratch, whatever earlier tests chose.
+        assert_eq!(viaduct_shutdown(0, 0), 1);
+        for &policy in &[0, 1] {
+            assert_eq!(viaduct_initialize(stub_fetch), 1);
+            assert_eq!(viaduct_initialize(stub_fetch), 0);
+            let info = crate::backend_info();
+            assert_eq!(info.name, "FFI (trusted)");
+            assert!(info.callback_initialized);
+            // A shutdown forgets the callback, so it can be registered again.
+            assert_eq!(viaduct_shutdown(policy, 0), 1);
+            assert!(!crate::backend_info().callback_initialized);
+        }
+    }
+
+    #[test]
+    fn test_sensitive_buffers_zeroed() {
+        use crate::sensitive::testing::{assert_all_zero, take_zeroized};
+        let stub = || msg_types::Response {
+            url: Some("https://www.example.com/".into()),
+            status: Some(200),
+            body: Some(b"{\"keys\": \"secret\"}".to_vec()),
+            ..msg_types::Response::default()
+        };
+        let url = url::Url::parse("https://www.example.com").unwrap();
+
+        take_zeroized();
+        STUB_RESPONSE.with(|r| *r.borrow_mut() = Some(stub()));
+        let request = crate::Request::post(url.clone())
+            .body("hunter2")
+            .sensitive(true);
+        let response = send_via(stub_fetch, request).unwrap();
+        // The body's still the caller's to look after.
+        assert_eq!(response.text(), "{\"keys\": \"secret\"}");
+        // Our copy of the body, the buffer the callback freed, and the
+        // response it returned.
+        let zeroized = take_zeroized();
+        assert_eq!(zeroized.len(), 3);
+        assert_all_zero(&zeroized);
+
+        // `viaduct_destroy_bytebuffer` can't tell which buffers are sensitive,
+        // so it still zeroes the request, but that's all.
+        STUB_RESPONSE.with(|r| *r.borrow_mut() = Some(stub()));
+        send_via(stub_fetch, crate::Request::post(url).body("hunter2")).unwrap();
+        assert_eq!(take_zeroized().len(), 1);
+    }
+
+    #[test]
+    fn test_methods() {
+        use crate::Method;
+        let methods = [
+            Method::Get,
+            Method::Head,
+            Method::Post,
+            Method::Put,
+            Method::Delete,
+            Method::Connect,
+            Method::Options,
+            Method::Trace,
+            Method::Patch,
+        ];
+        for &method in &methods {
+            let url = url::Url::parse("https://www.example.com").unwrap();
+            let proto: msg_types::Request = crate::Request::new(method, url).into();
+            let proto_method = msg_types::request::Method::from_i32(proto.method).unwrap();
+            assert_eq!(
+                format!("{:?}", proto_method).to_uppercase(),
+                method.as_str()
+            );
+        }
+
+        let url = url::Url::parse("https://www.example.com").unwrap();
+        let proto: msg_types::Request = crate::Request::new(Method::Patch, url).body("data").into();
+        assert_eq!(proto.method, msg_types::request::Method::Patch as i32);
+        assert_eq!(proto.body.as_deref(), Some(&b"data"[..]));
+    }
+
+    #[test]
+    fn test_head_has_no_body() {
+        let mut headers = std::collections::HashMap::new();
+        headers.insert("content-length".to_owned(), "4096".to_owned());
+        STUB_RESPONSE.with(|r| {
+            *r.borrow_mut() = Some(msg_types::Response {
+                url: Some("https://www.example.com/".into()),
+                status: Some(200),
+                body: Some(vec![b'x'; 4096]),
+                headers,
+                ..msg_types::Response::default()
+            })
+        });
+        let url = url::Url::parse("https://www.example.com").unwrap();
+        let request = crate::Request::new(crate::Method::Head, url).max_response_size(1024);
+        let response = send_via(stub_fetch, request).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.headers.get(crate::header_names::CONTENT_LENGTH),
+            Some("4096")
+        );
+        assert!(response.body.is_empty());
+    }
+
+    #[test]
+    fn test_response_too_large() {
+        let huge = || msg_types::Response {
+            url: Some("https://www.example.com/".into()),
+            status: Some(200),
+            body: Some(vec![b'x'; 1024 + 1]),
+            ..msg_types::Response::default()
+        };
+        let url = url::Url::parse("https://www.example.com").unwrap();
+
+        STUB_RESPONSE.with(|r| *r.borrow_mut() = Some(huge()));
+        let request = crate::Request::get(url.clone()).max_response_size(1024);
+        let err = send_via(stub_fetch, request).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::ResponseTooLarge {
+                limit: 1024,
+                content_length: None,
+            }
+        ));
+
+        STUB_RESPONSE.with(|r| *r.borrow_mut() = Some(huge()));
+        let request = crate::Request::get(url).max_response_size(2048);
+        let response = send_via(stub_fetch, request).unwrap();
+        assert_eq!(response.body.len(), 1024 + 1);
+    }
+}
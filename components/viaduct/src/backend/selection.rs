@@ -0,0 +1,372 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Choosing the backend. The embedding application should call
+//! [`init`](crate::init) once, early on, to say which backend to use. If it
+//! doesn't, the first request picks one as if it had called
+//! `init(BackendChoice::Auto)`.
+//!
+//! Before this existed, whichever of `set_backend`, `viaduct_initialize` and
+//! the first request came first won, so a component which sent a request
+//! before the application had set things up could leave it with the wrong
+//! backend for good. Now an `Auto` choice isn't locked in until there's a
+//! backend for it to resolve to, and asking for a different backend than the
+//! one already chosen is an error, which says who chose it.
+
+use super::{ffi::FfiBackend, stub::StubBackend, Backend};
+use crate::Error;
+use serde_derive::Serialize;
+use std::sync::{Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Which backend to send requests through. See [`init`](crate::init).
+#[derive(Clone, Copy)]
+pub enum BackendChoice {
+    /// The reqwest backend, which lives in the `viaduct-reqwest` crate. Call
+    /// `viaduct_reqwest::use_reqwest_backend()` rather than using this
+    /// directly.
+    Reqwest(&'static dyn Backend),
+    /// The fetch callback registered by the embedding application with
+    /// `viaduct_initialize`, which must already have been called.
+    FfiCallback,
+    /// A backend which fails every request, unless a response was stubbed
+    /// for it, for tests and tools which shouldn't touch the network. See
+    /// the [`stub`](crate::stub) module.
+    Stub,
+    /// The fetch callback, once it's registered. Until then, requests fail
+    /// with `Error::BackendNotInitialized`, and a later `init` may still
+    /// choose something else. An `Auto` choice is also compatible with
+    /// whatever was chosen before it, so components which don't care can
+    /// always use it.
+    Auto,
+    /// Any other backend, as set with [`set_backend`](crate::set_backend).
+    Custom(&'static dyn Backend),
+}
+
+impl BackendChoice {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BackendChoice::Reqwest(_) => "reqwest",
+            BackendChoice::FfiCallback => "ffi-callback",
+            BackendChoice::Stub => "stub",
+            BackendChoice::Auto => "auto",
+            BackendChoice::Custom(_) => "custom",
+        }
+    }
+
+    fn is_same_as(&self, other: &BackendChoice) -> bool {
+        match (self, other) {
+            (BackendChoice::Reqwest(_), BackendChoice::Reqwest(_))
+            | (BackendChoice::FfiCallback, BackendChoice::FfiCallback)
+            | (BackendChoice::Stub, BackendChoice::Stub)
+            | (BackendChoice::Auto, BackendChoice::Auto) => true,
+            (BackendChoice::Custom(a), BackendChoice::Custom(b)) => {
+                // Compare the data pointers, since vtable pointers for the
+                // same type aren't guaranteed to be equal.
+                *a as *const dyn Backend as *const u8 == *b as *const dyn Backend as *const u8
+            }
+            _ => false,
+        }
+    }
+
+    // The choice this resolves to now, if any.
+    fn resolve(self, callback_initialized: bool) -> Option<(BackendChoice, &'static dyn Backend)> {
+        Some(match self {
+            BackendChoice::Reqwest(backend) | BackendChoice::Custom(backend) => (self, backend),
+            BackendChoice::FfiCallback => (self, &FfiBackend),
+            BackendChoice::Stub => (self, &StubBackend),
+            BackendChoice::Auto if callback_initialized => {
+                (BackendChoice::FfiCallback, &FfiBackend)
+            }
+            BackendChoice::Auto => return None,
+        })
+    }
+}
+
+impl std::fmt::Debug for BackendChoice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// How the backend was chosen, as reported by
+/// [`backend_selection`](crate::backend_selection).
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BackendSelection {
+    /// The choice passed to `init`, as in `BackendChoice::name`.
+    pub choice: &'static str,
+    /// The choice `Auto` resolved to, if it has.
+    pub resolved: Option<&'static str>,
+    /// The name of the backend requests are sent through, or `None` if an
+    /// `Auto` choice is still waiting for the fetch callback.
+    pub backend: Option<&'static str>,
+    /// When the choice was made, in milliseconds since the epoch.
+    pub chosen_at: u64,
+    /// Where `init` (or one of the functions which call it) was called
+    /// from, as `file:line:column`.
+    pub caller: String,
+}
+
+struct Selection {
+    choice: BackendChoice,
+    resolved: Option<BackendChoice>,
+    chosen_at: SystemTime,
+    caller: String,
+}
+
+pub(super) struct Selector {
+    selection: Mutex<Option<Selection>>,
+    // Set once, while `selection` is locked, when the choice is resolved. This
+    // is all `get_backend` needs to look at after that. Only `reset` clears
+    // it.
+    backend: RwLock<Option<&'static dyn Backend>>,
+    callback_initialized: fn() -> bool,
+}
+
+impl Selector {
+    pub(super) fn new(callback_initialized: fn() -> bool) -> Self {
+        Self {
+            selection: Mutex::new(None),
+            backend: RwLock::new(None),
+            callback_initialized,
+        }
+    }
+
+    /// Forget the backend, and how it was chosen, so that it can be chosen
+    /// again. See `crate::shutdown`.
+    pub(super) fn reset(&self) {
+        let mut selection = self.selection.lock().unwrap_or_else(|e| e.into_inner());
+        *self.backend.write().unwrap_or_else(|e| e.into_inner()) = None;
+        *selection = None;
+    }
+
+    pub(super) fn init(&self, choice: BackendChoice, caller: String) -> Result<(), Error> {
+        let callback_initialized = (self.callback_initialized)();
+        if let BackendChoice::FfiCallback = choice {
+            if !callback_initialized {
+                return Err(Error::BackendNotInitialized);
+            }
+        }
+        let mut selection = self.selection.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(existing) = selection.as_ref() {
+            // Only `Auto` choices can be unresolved, and until they're
+            // resolved it's not too late to be more specific.
+            let compatible = existing.resolved.is_none()
+                || choice.is_same_as(&BackendChoice::Auto)
+                || existing.choice.is_same_as(&choice)
+                || existing.resolved.map_or(false, |r| r.is_same_as(&choice));
+            if !compatible {
+                log::error!(
+                    "Asked for the {} backend at {}, but {} was already chosen at {}",
+                    choice.name(),
+                    caller,
+                    existing.choice.name(),
+                    existing.caller
+                );
+                return Err(Error::BackendMismatch {
+                    chosen: existing.choice.name(),
+                    requested: choice.name(),
+                    chosen_by: existing.caller.clone(),
+                });
+            }
+            if existing.resolved.is_some() || choice.is_same_as(&BackendChoice::Auto) {
+                return Ok(());
+            }
+        }
+        log::info!("Choosing the {} backend, at {}", choice.name(), caller);
+        let resolved = self.resolve(choice, callback_initialized);
+        *selection = Some(Selection {
+            choice,
+            resolved,
+            chosen_at: SystemTime::now(),
+            caller,
+        });
+        Ok(())
+    }
+
+    // Sets `backend` if `choice` can be resolved, returning what it resolved
+    // to. Must be called with `selection` locked.
+    fn resolve(&self, choice: BackendChoice, callback_initialized: bool) -> Option<BackendChoice> {
+        let (resolved, backend) = choice.resolve(callback_initialized)?;
+        let mut resolved_backend = self.backend.write().unwrap_or_else(|e| e.into_inner());
+        if resolved_backend.is_some() {
+            // We never resolve twice, so this is a bug.
+            log::error!("Bug: resolved the backend more than once");
+        }
+        *resolved_backend = Some(backend);
+        Some(resolved)
+    }
+
+    pub(super) fn get_backend_if_resolved(&self) -> Option<&'static dyn Backend> {
+        *self.backend.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(super) fn get_backend(&self) -> Result<&'static dyn Backend, Error> {
+        if let Some(backend) = self.get_backend_if_resolved() {
+            return Ok(backend);
+        }
+        let mut selection = self.selection.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(backend) = self.get_backend_if_resolved() {
+            return Ok(backend);
+        }
+        let selection = selection.get_or_insert_with(|| Selection {
+            choice: BackendChoice::Auto,
+            resolved: None,
+            chosen_at: SystemTime::now(),
+            caller: "(implicitly, by the first request)".into(),
+        });
+        selection.resolved = self.resolve(selection.choice, (self.callback_initialized)());
+        self.get_backend_if_resolved()
+            .ok_or(Error::BackendNotInitialized)
+    }
+
+    pub(super) fn selection(&self) -> Option<BackendSelection> {
+        let selection = self.selection.lock().unwrap_or_else(|e| e.into_inner());
+        selection.as_ref().map(|s| BackendSelection {
+            choice: s.choice.name(),
+            resolved: s.resolved.map(|r| r.name()),
+            backend: self.get_backend_if_resolved().map(|b| b.name()),
+            chosen_at: s
+                .chosen_at
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as u64),
+            caller: s.caller.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestBackend;
+    use once_cell::sync::Lazy;
+
+    fn backend() -> &'static TestBackend {
+        static BACKEND: Lazy<TestBackend> = Lazy::new(TestBackend::default);
+        &BACKEND
+    }
+
+    fn with_callback() -> bool {
+        true
+    }
+
+    fn without_callback() -> bool {
+        false
+    }
+
+    #[test]
+    fn test_mismatched_init() {
+        let selector = Selector::new(with_callback);
+        selector
+            .init(BackendChoice::Stub, "first.rs:1:1".into())
+            .unwrap();
+        // Asking again for the same thing, or for `Auto`, is fine.
+        selector
+            .init(BackendChoice::Stub, "second.rs:1:1".into())
+            .unwrap();
+        selector
+            .init(BackendChoice::Auto, "second.rs:2:1".into())
+            .unwrap();
+        match selector.init(BackendChoice::FfiCallback, "third.rs:1:1".into()) {
+            Err(Error::BackendMismatch {
+                chosen,
+                requested,
+                chosen_by,
+            }) => {
+                assert_eq!(chosen, "stub");
+                assert_eq!(requested, "ffi-callback");
+                assert_eq!(chosen_by, "first.rs:1:1");
+            }
+            other => panic!("Expected BackendMismatch, got {:?}", other),
+        }
+        assert!(selector
+            .init(BackendChoice::Custom(backend()), "third.rs:2:1".into())
+            .is_err());
+
+        assert_eq!(selector.get_backend().unwrap().name(), "stub");
+        let selection = selector.selection().unwrap();
+        assert_eq!(selection.choice, "stub");
+        assert_eq!(selection.resolved, Some("stub"));
+        assert_eq!(selection.backend, Some("stub"));
+        assert_eq!(selection.caller, "first.rs:1:1");
+        assert!(selection.chosen_at > 0);
+    }
+
+    #[test]
+    fn test_ffi_callback_requires_callback() {
+        let selector = Selector::new(without_callback);
+        assert!(matches!(
+            selector.init(BackendChoice::FfiCallback, "here".into()),
+            Err(Error::BackendNotInitialized)
+        ));
+        assert_eq!(selector.selection(), None);
+    }
+
+    #[test]
+    fn test_auto_with_callback() {
+        let selector = Selector::new(with_callback);
+        selector.init(BackendChoice::Auto, "here".into()).unwrap();
+        assert_eq!(selector.get_backend().unwrap().name(), "FFI (trusted)");
+        let selection = selector.selection().unwrap();
+        assert_eq!(selection.choice, "auto");
+        assert_eq!(selection.resolved, Some("ffi-callback"));
+        // Which is the same as asking for the callback, but not for reqwest.
+        selector
+            .init(BackendChoice::FfiCallback, "there".into())
+            .unwrap();
+        assert!(selector
+            .init(BackendChoice::Reqwest(backend()), "there".into())
+            .is_err());
+    }
+
+    #[test]
+    fn test_auto_without_callback() {
+        let selector = Selector::new(without_callback);
+        selector.init(BackendChoice::Auto, "here".into()).unwrap();
+        assert!(matches!(
+            selector.get_backend(),
+            Err(Error::BackendNotInitialized)
+        ));
+        let selection = selector.selection().unwrap();
+        assert_eq!(selection.resolved, None);
+        assert_eq!(selection.backend, None);
+
+        // It's not too late to choose something specific.
+        selector
+            .init(BackendChoice::Reqwest(backend()), "there".into())
+            .unwrap();
+        assert_eq!(selector.get_backend().unwrap().name(), "test");
+        assert_eq!(selector.selection().unwrap().caller, "there");
+    }
+
+    #[test]
+    fn test_implicit_auto() {
+        let selector = Selector::new(without_callback);
+        assert!(selector.get_backend().is_err());
+        let selection = selector.selection().unwrap();
+        assert_eq!(selection.choice, "auto");
+        assert_eq!(selection.caller, "(implicitly, by the first request)");
+
+        selector.init(BackendChoice::Stub, "here".into()).unwrap();
+        assert_eq!(selector.get_backend().unwrap().name(), "stub");
+    }
+
+    #[test]
+    fn test_reset() {
+        let selector = Selector::new(with_callback);
+        selector
+            .init(BackendChoice::Stub, "first.rs:1:1".into())
+            .unwrap();
+        assert_eq!(selector.get_backend().unwrap().name(), "stub");
+        selector.reset();
+        assert!(selector.selection().is_none());
+        assert!(selector.get_backend_if_resolved().is_none());
+        // Now a different backend can be chosen.
+        selector
+            .init(BackendChoice::Custom(backend()), "second.rs:1:1".into())
+            .unwrap();
+        assert_eq!(selector.get_backend().unwrap().name(), "test");
+        assert_eq!(selector.selection().unwrap().caller, "second.rs:1:1");
+    }
+}
@@ -0,0 +1,338 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The backend chosen with `BackendChoice::Stub`, for tests and tools which
+//! shouldn't touch the network.
+//!
+//! Tests can install canned responses for a host with [`stub_host`], or for
+//! every URL starting with a prefix with [`stub_prefix`]. Other requests
+//! fail with `Error::BackendError`, unless [`set_strict_mode`] is on, in
+//! which case they panic, naming the request and what was stubbed, so a
+//! test which forgot a stub fails where it sent the request. At the end of
+//! a test, [`require_all_stubs_used`] panics if any stub was never asked
+//! for, which usually means the test isn't doing what it thinks it is.
+//!
+//! The stubs are shared by the whole process, so tests which install them
+//! shouldn't run at the same time, and should call [`reset`] when they're
+//! done. [`shutdown`](crate::shutdown) resets them too.
+
+use crate::{backend::Backend, Error, Headers, Request, Response};
+use once_cell::sync::Lazy;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
+
+static REGISTRY: Lazy<RwLock<Registry>> = Lazy::new(RwLock::default);
+
+/// A canned response, returned by the stub backend.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StubResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl StubResponse {
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: vec![],
+            body: vec![],
+        }
+    }
+
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+}
+
+/// Respond to every request to `host` with `response`. If several stubs
+/// match a request, the one installed last wins. Installing one for a host
+/// which already has one replaces it.
+pub fn stub_host(host: &str, response: StubResponse) {
+    REGISTRY
+        .write()
+        .unwrap()
+        .install(Matcher::Host(host.to_owned()), response)
+}
+
+/// Respond to every request for a URL starting with `prefix` with
+/// `response`. The URL is compared after it's normalized, so the prefix
+/// should be too (with a lowercase host, and a `/` after it). See
+/// `stub_host` for how stubs are chosen.
+pub fn stub_prefix(prefix: &str, response: StubResponse) {
+    REGISTRY
+        .write()
+        .unwrap()
+        .install(Matcher::Prefix(prefix.to_owned()), response)
+}
+
+/// Panic on requests which no stub matches, rather than failing them. This
+/// stays on until it's turned off, or until `reset`.
+pub fn set_strict_mode(strict: bool) {
+    REGISTRY.write().unwrap().strict = strict;
+}
+
+/// Panic if any stub hasn't matched a request since it was installed,
+/// listing the ones which haven't.
+pub fn require_all_stubs_used() {
+    let unused = REGISTRY
+        .read()
+        .unwrap()
+        .stubs
+        .iter()
+        .filter(|stub| stub.used.load(Ordering::SeqCst) == 0)
+        .map(|stub| stub.matcher.to_string())
+        .collect::<Vec<_>>();
+    if !unused.is_empty() {
+        panic!("Stubs which were never used: {}", unused.join(", "));
+    }
+}
+
+/// Remove every stub, and turn strict mode off.
+pub fn reset() {
+    *REGISTRY.write().unwrap() = Registry::default();
+}
+
+#[derive(Default)]
+struct Registry {
+    // In the order they were installed.
+    stubs: Vec<Stub>,
+    strict: bool,
+}
+
+impl Registry {
+    fn install(&mut self, matcher: Matcher, response: StubResponse) {
+        self.stubs.retain(|stub| stub.matcher != matcher);
+        self.stubs.push(Stub {
+            matcher,
+            response,
+            used: AtomicUsize::new(0),
+        });
+    }
+
+    fn find(&self, request: &Request) -> Option<&Stub> {
+        self.stubs
+            .iter()
+            .rev()
+            .find(|stub| stub.matcher.matches(request))
+    }
+
+    fn describe(&self) -> String {
+        if self.stubs.is_empty() {
+            return "none".into();
+        }
+        self.stubs
+            .iter()
+            .map(|stub| stub.matcher.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+struct Stub {
+    matcher: Matcher,
+    response: StubResponse,
+    // Counted with the registry only locked for reading, so that requests
+    // don't hold each other up.
+    used: AtomicUsize,
+}
+
+#[derive(PartialEq)]
+enum Matcher {
+    Host(String),
+    Prefix(String),
+}
+
+impl Matcher {
+    fn matches(&self, request: &Request) -> bool {
+        match self {
+            Matcher::Host(host) => request.url.host_str() == Some(host.as_str()),
+            Matcher::Prefix(prefix) => request.url.as_str().starts_with(prefix.as_str()),
+        }
+    }
+}
+
+impl fmt::Display for Matcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Matcher::Host(host) => write!(f, "host {}", host),
+            Matcher::Prefix(prefix) => write!(f, "prefix {}", prefix),
+        }
+    }
+}
+
+/// Every request gets the response stubbed for it, or fails, with
+/// `Error::Offline` if the network status is `NetworkStatus::Offline`, like
+/// any other backend, so tests can simulate being offline with
+/// `set_network_status`. See the module docs.
+pub struct StubBackend;
+impl Backend for StubBackend {
+    fn send(&self, request: crate::Request) -> Result<crate::Response, Error> {
+        super::note_backend(self.name());
+        let registry = REGISTRY.read().unwrap();
+        let response = registry.find(&request).map(|stub| {
+            stub.used.fetch_add(1, Ordering::SeqCst);
+            stub.response.clone()
+        });
+        if response.is_none() && registry.strict {
+            let stubbed = registry.describe();
+            // Don't panic with the lock held.
+            drop(registry);
+            panic!(
+                "No stub for {} {} (stubbed: {})",
+                request.method.as_str(),
+                request.url,
+                stubbed
+            );
+        }
+        drop(registry);
+        match response {
+            Some(response) => respond(request, response),
+            None => Err(Error::BackendError(format!(
+                "The stub backend has no response for this request ({} {})",
+                request.method.as_str(),
+                request.url
+            ))),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "stub"
+    }
+}
+
+pub(crate) fn respond(request: Request, stubbed: StubResponse) -> Result<Response, Error> {
+    let mut headers = Headers::new();
+    for (name, value) in stubbed.headers {
+        headers.insert(name, value)?;
+    }
+    Ok(Response {
+        request_method: request.method,
+        request_id: request.id(),
+        url: request.url.clone(),
+        final_url: request.url,
+        redirects: vec![],
+        status: stubbed.status,
+        headers,
+        body: stubbed.body,
+        from_cache: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use url::Url;
+
+    fn get(url: &str) -> Request {
+        Request::get(Url::parse(url).unwrap())
+    }
+
+    // The message `f` panicked with.
+    fn panic_message(f: impl FnOnce()) -> String {
+        let payload = catch_unwind(AssertUnwindSafe(f)).unwrap_err();
+        match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => payload.downcast_ref::<&str>().unwrap().to_string(),
+        }
+    }
+
+    #[test]
+    fn test_stubs() {
+        let _lock = crate::testing::lock();
+        reset();
+        stub_host(
+            "sync.example.com",
+            StubResponse::new(200)
+                .header("Content-Type", "application/json")
+                .body("{}"),
+        );
+        stub_prefix(
+            "https://sync.example.com/1.5/",
+            StubResponse::new(404).body("not here"),
+        );
+
+        let response = StubBackend
+            .send(get("https://sync.example.com/info"))
+            .unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.headers.get("content-type"),
+            Some("application/json")
+        );
+        assert_eq!(response.body, b"{}");
+        // The prefix was installed last, so it wins where both match.
+        let response = StubBackend
+            .send(get("https://sync.example.com/1.5/storage"))
+            .unwrap();
+        assert_eq!(response.status, 404);
+        assert_eq!(response.body, b"not here");
+
+        // Without strict mode, anything else fails as usual.
+        let err = StubBackend
+            .send(get("https://other.example.com/"))
+            .unwrap_err();
+        assert!(matches!(err, Error::BackendError(_)), "{:?}", err);
+
+        // Replacing a stub starts its count again.
+        stub_host("sync.example.com", StubResponse::new(503));
+        let message = panic_message(require_all_stubs_used);
+        assert_eq!(
+            message,
+            "Stubs which were never used: host sync.example.com"
+        );
+        reset();
+    }
+
+    #[test]
+    fn test_strict_mode() {
+        let _lock = crate::testing::lock();
+        reset();
+        stub_host("sync.example.com", StubResponse::new(200));
+        stub_prefix("https://accounts.example.com/v1/", StubResponse::new(200));
+        set_strict_mode(true);
+
+        StubBackend
+            .send(get("https://sync.example.com/info"))
+            .unwrap();
+        let message = panic_message(|| {
+            let _ = StubBackend.send(get("https://accounts.example.com/v2/account?q=1"));
+        });
+        assert!(
+            message.contains("GET https://accounts.example.com/v2/account?q=1"),
+            "{}",
+            message
+        );
+        assert!(message.contains("host sync.example.com"), "{}", message);
+        assert!(
+            message.contains("prefix https://accounts.example.com/v1/"),
+            "{}",
+            message
+        );
+        // The registry isn't poisoned by the panic.
+        let message = panic_message(require_all_stubs_used);
+        assert!(
+            message.contains("prefix https://accounts.example.com/v1/"),
+            "{}",
+            message
+        );
+        assert!(!message.contains("host sync.example.com"), "{}", message);
+
+        // Resetting forgets the stubs, and turns strict mode off.
+        reset();
+        require_all_stubs_used();
+        let err = StubBackend
+            .send(get("https://sync.example.com/info"))
+            .unwrap_err();
+        assert!(matches!(err, Error::BackendError(_)), "{:?}", err);
+    }
+}
@@ -0,0 +1,455 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Backoff and concurrency limits, shared between every consumer of viaduct.
+//!
+//! When a response carries a `Retry-After`, `X-Backoff` or `X-Weave-Backoff`
+//! header, we remember not to contact that host again until the time it asks
+//! for. Until then, requests to the host fail immediately with
+//! [`Error::BackoffError`], unless they were made with
+//! `Request::ignore_backoff()`.
+//!
+//! If `GLOBAL_SETTINGS.max_concurrent_requests_per_host` is set, requests to
+//! a host beyond that limit block until an earlier one completes, or until a
+//! [`shutdown`](crate::shutdown) is aborted, which fails them with
+//! [`Error::Cancelled`].
+
+use crate::clock::{self, Clock};
+use crate::settings::GLOBAL_SETTINGS;
+use crate::{header_names, Error, Request, Response, RetryAfter};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+static LIMITER: Lazy<RateLimiter> =
+    Lazy::new(|| RateLimiter::new(GLOBAL_SETTINGS.max_concurrent_requests_per_host));
+
+/// The hosts we're currently backing off from, and how much longer we'll do so
+/// for.
+pub fn current_backoffs() -> HashMap<String, Duration> {
+    LIMITER.current_backoffs()
+}
+
+/// Forget all backoff requests, allowing requests to every host again.
+pub fn clear_backoffs() {
+    LIMITER.clear_backoffs()
+}
+
+// Fail every request which is waiting for a slot now.
+pub(crate) fn cancel_waiting() {
+    LIMITER.cancel_waiting()
+}
+
+pub(crate) fn send(
+    request: Request,
+    send: impl FnOnce(Request) -> Result<Response, Error>,
+) -> Result<Response, Error> {
+    LIMITER.send(request, send)
+}
+
+// Parse the backoff headers in a response, returning the longest duration
+// asked for, if any.
+pub(crate) fn backoff_duration(response: &Response) -> Option<Duration> {
+    let seconds = |name| {
+        response
+            .headers
+            .try_get::<u64, _>(name)
+            .map(Duration::from_secs)
+    };
+    let retry_after = response
+        .headers
+        .try_get::<RetryAfter, _>(header_names::RETRY_AFTER)
+        .map(|retry_after| retry_after.delay_from(clock::current().system_now()));
+    vec![
+        retry_after,
+        seconds(header_names::X_BACKOFF),
+        seconds(header_names::X_WEAVE_BACKOFF),
+    ]
+    .into_iter()
+    .flatten()
+    .max()
+    .filter(|duration| *duration > Duration::default())
+}
+
+#[derive(Default)]
+struct RateLimiter {
+    // The time before which we shouldn't contact each host.
+    backoffs: Mutex<HashMap<String, Instant>>,
+    slots: Mutex<Slots>,
+    // Notified whenever a slot might have come free.
+    finished: Condvar,
+    // Bumped by `cancel_waiting`, so waiters can tell they were cancelled.
+    cancellations: AtomicU64,
+}
+
+#[derive(Default)]
+struct Slots {
+    max_concurrent: Option<usize>,
+    // The number of requests in flight to each host.
+    in_flight: HashMap<String, usize>,
+}
+
+impl RateLimiter {
+    fn new(max_concurrent: Option<usize>) -> Self {
+        let limiter = Self::default();
+        limiter.slots.lock().unwrap().max_concurrent = max_concurrent;
+        limiter
+    }
+
+    fn current_backoffs(&self) -> HashMap<String, Duration> {
+        let now = clock::current().now();
+        let mut backoffs = self.backoffs.lock().unwrap();
+        backoffs.retain(|_, until| *until > now);
+        backoffs
+            .iter()
+            .map(|(host, until)| (host.clone(), *until - now))
+            .collect()
+    }
+
+    fn clear_backoffs(&self) {
+        self.backoffs.lock().unwrap().clear();
+    }
+
+    fn cancel_waiting(&self) {
+        self.cancellations.fetch_add(1, Ordering::SeqCst);
+        // Waiters check `cancellations` with the lock held, so once we have
+        // it, they've either seen the new value or are waiting to be woken.
+        drop(self.slots.lock().unwrap());
+        self.finished.notify_all();
+    }
+
+    fn send(
+        &self,
+        request: Request,
+        send: impl FnOnce(Request) -> Result<Response, Error>,
+    ) -> Result<Response, Error> {
+        let host = request.url.host_str().unwrap_or_default().to_owned();
+        if !request.ignore_backoff {
+            let backoffs = self.backoffs.lock().unwrap();
+            if let Some(until) = backoffs.get(&host) {
+                let now = clock::current().now();
+                if *until > now {
+                    return Err(Error::BackoffError {
+                        remaining: *until - now,
+                    });
+                }
+            }
+        }
+        let response = {
+            let _slot = self.acquire_slot(&host)?;
+            send(request)?
+        };
+        if let Some(duration) = backoff_duration(&response) {
+            log::warn!("Server at {} asked us to back off for {:?}", host, duration);
+            let until = clock::current().now() + duration;
+            let mut backoffs = self.backoffs.lock().unwrap();
+            let entry = backoffs.entry(host).or_insert(until);
+            *entry = (*entry).max(until);
+        }
+        Ok(response)
+    }
+
+    fn acquire_slot<'a>(&'a self, host: &'a str) -> Result<Slot<'a>, Error> {
+        let mut slots = self.slots.lock().unwrap();
+        let cancellations = self.cancellations.load(Ordering::SeqCst);
+        while let Some(max) = slots.max_concurrent {
+            if slots.in_flight.get(host).copied().unwrap_or_default() < max.max(1) {
+                break;
+            }
+            slots = self.finished.wait(slots).unwrap();
+            if self.cancellations.load(Ordering::SeqCst) != cancellations {
+                return Err(Error::Cancelled);
+            }
+        }
+        *slots.in_flight.entry(host.to_owned()).or_default() += 1;
+        Ok(Slot {
+            limiter: self,
+            host,
+        })
+    }
+}
+
+// A request in flight to `host`, counted against the concurrency limit until
+// it's dropped.
+struct Slot<'a> {
+    limiter: &'a RateLimiter,
+    host: &'a str,
+}
+
+impl<'a> Drop for Slot<'a> {
+    fn drop(&mut self) {
+        let mut slots = self.limiter.slots.lock().unwrap();
+        if let Some(count) = slots.in_flight.get_mut(self.host) {
+            *count -= 1;
+            if *count == 0 {
+                slots.in_flight.remove(self.host);
+            }
+        }
+        self.limiter.finished.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::testing::TestBackend;
+    use crate::{Backend, Headers, Method};
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+    use url::Url;
+
+    fn request(url: &str) -> Request {
+        Request::new(Method::Get, Url::parse(url).unwrap())
+    }
+
+    // A stand-in for the backend, responding with the given headers.
+    fn respond(
+        headers: &'static [(&'static str, &'static str)],
+    ) -> impl FnOnce(Request) -> Result<Response, Error> {
+        move |request| {
+            let mut response_headers = Headers::new();
+            for (name, value) in headers {
+                response_headers.insert(*name, *value)?;
+            }
+            Ok(Response {
+                request_method: request.method,
+                request_id: request.id(),
+                url: request.url.clone(),
+                final_url: request.url,
+                redirects: vec![],
+                status: 503,
+                headers: response_headers,
+                body: vec![],
+                from_cache: false,
+            })
+        }
+    }
+
+    fn response_with(headers: Headers) -> Response {
+        Response {
+            request_method: Method::Get,
+            request_id: crate::RequestId::new(),
+            url: Url::parse("https://www.example.com").unwrap(),
+            final_url: Url::parse("https://www.example.com").unwrap(),
+            redirects: vec![],
+            status: 503,
+            headers,
+            body: vec![],
+            from_cache: false,
+        }
+    }
+
+    #[test]
+    fn test_backoff_headers() {
+        let _clock = ManualClock::install();
+        let limiter = RateLimiter::new(None);
+        limiter
+            .send(
+                request("https://sync.example.com/info/collections"),
+                respond(&[("X-Weave-Backoff", "60"), ("Retry-After", "30")]),
+            )
+            .unwrap();
+        limiter
+            .send(
+                request("https://accounts.example.com/v1/account"),
+                respond(&[("X-Backoff", "10")]),
+            )
+            .unwrap();
+        limiter
+            .send(
+                request("https://other.example.com/"),
+                respond(&[("Retry-After", "soon")]),
+            )
+            .unwrap();
+
+        let backoffs = limiter.current_backoffs();
+        assert_eq!(backoffs.len(), 2);
+        // The longest of the requested durations wins.
+        assert_eq!(backoffs["sync.example.com"], Duration::from_secs(60));
+        assert_eq!(backoffs["accounts.example.com"], Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_retry_after_date() {
+        let clock = ManualClock::install();
+        let date = httpdate::fmt_http_date(clock.system_now() + Duration::from_secs(120));
+        let mut headers = Headers::new();
+        headers.insert(header_names::RETRY_AFTER, date).unwrap();
+        let response = response_with(headers);
+        assert_eq!(backoff_duration(&response), Some(Duration::from_secs(120)));
+
+        // The date is fixed, so the longer we take to get to it, the less
+        // time there is left.
+        clock.advance(Duration::from_secs(100));
+        assert_eq!(backoff_duration(&response), Some(Duration::from_secs(20)));
+        // And once it's passed, there's nothing to wait for.
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(backoff_duration(&response), None);
+    }
+
+    #[test]
+    fn test_fails_fast_during_backoff() {
+        let clock = ManualClock::install();
+        let limiter = RateLimiter::new(None);
+        limiter
+            .send(
+                request("https://sync.example.com/info/collections"),
+                respond(&[("X-Weave-Backoff", "60")]),
+            )
+            .unwrap();
+
+        let sent = AtomicUsize::new(0);
+        let send_tabs = || {
+            limiter.send(request("https://sync.example.com/storage/tabs"), |r| {
+                sent.fetch_add(1, Ordering::SeqCst);
+                respond(&[])(r)
+            })
+        };
+        clock.advance(Duration::from_secs(15));
+        match send_tabs() {
+            Err(Error::BackoffError { remaining }) => {
+                assert_eq!(remaining, Duration::from_secs(45))
+            }
+            other => panic!("Expected a backoff error, got {:?}", other),
+        }
+        assert_eq!(sent.load(Ordering::SeqCst), 0);
+
+        // Other hosts are unaffected.
+        limiter
+            .send(request("https://accounts.example.com/"), respond(&[]))
+            .unwrap();
+
+        // Requests can opt out.
+        limiter
+            .send(
+                request("https://sync.example.com/storage/tabs").ignore_backoff(),
+                respond(&[]),
+            )
+            .unwrap();
+
+        // Right up until the deadline, requests still fail...
+        clock.advance(Duration::from_secs(44));
+        assert_eq!(
+            limiter.current_backoffs()["sync.example.com"],
+            Duration::from_secs(1)
+        );
+        assert!(matches!(send_tabs(), Err(Error::BackoffError { .. })));
+        // ...and once it passes, they're allowed again.
+        clock.advance(Duration::from_secs(1));
+        assert!(limiter.current_backoffs().is_empty());
+        send_tabs().unwrap();
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+
+        limiter
+            .send(
+                request("https://sync.example.com/info/collections"),
+                respond(&[("X-Weave-Backoff", "60")]),
+            )
+            .unwrap();
+        limiter.clear_backoffs();
+        assert!(limiter.current_backoffs().is_empty());
+    }
+
+    #[test]
+    fn test_longer_backoff_wins() {
+        let clock = ManualClock::install();
+        let limiter = RateLimiter::new(None);
+        let backoff = |headers: &'static [(&'static str, &'static str)]| {
+            limiter
+                .send(
+                    request("https://sync.example.com/").ignore_backoff(),
+                    respond(headers),
+                )
+                .unwrap();
+        };
+        backoff(&[("X-Backoff", "60")]);
+        clock.advance(Duration::from_secs(10));
+        // A shorter backoff than what's left doesn't cut it short...
+        backoff(&[("X-Backoff", "5")]);
+        assert_eq!(
+            limiter.current_backoffs()["sync.example.com"],
+            Duration::from_secs(50)
+        );
+        // ...but a longer one extends it.
+        backoff(&[("Retry-After", "120")]);
+        assert_eq!(
+            limiter.current_backoffs()["sync.example.com"],
+            Duration::from_secs(120)
+        );
+    }
+
+    #[test]
+    fn test_concurrency_limit() {
+        let _clock = ManualClock::install();
+        let limiter = Arc::new(RateLimiter::new(Some(2)));
+        let backend = Arc::new(TestBackend::default());
+        backend.hold();
+        let threads = (0..6)
+            .map(|_| {
+                let limiter = limiter.clone();
+                let backend = backend.clone();
+                std::thread::spawn(move || {
+                    limiter
+                        .send(request("https://limited.example.com/"), |r| backend.send(r))
+                        .unwrap();
+                })
+            })
+            .collect::<Vec<_>>();
+        // Two get as far as the backend, and the rest wait for them to
+        // finish before taking their places.
+        backend.wait_for_in_flight(2);
+        backend.release();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(backend.max_in_flight(), 2);
+        assert!(limiter.slots.lock().unwrap().in_flight.is_empty());
+    }
+
+    #[test]
+    fn test_cancel_waiting() {
+        use std::sync::mpsc;
+        let limiter = Arc::new(RateLimiter::new(Some(1)));
+        let (started, starts) = mpsc::channel();
+        let (finish, finished) = mpsc::channel::<()>();
+        let first = {
+            let limiter = limiter.clone();
+            std::thread::spawn(move || {
+                limiter.send(request("https://sync.example.com/"), |r| {
+                    started.send(()).unwrap();
+                    finished.recv().unwrap();
+                    respond(&[])(r)
+                })
+            })
+        };
+        starts.recv().unwrap();
+        let (done, second_done) = mpsc::channel();
+        {
+            let limiter = limiter.clone();
+            std::thread::spawn(move || {
+                let result = limiter.send(request("https://sync.example.com/"), |_| {
+                    panic!("Should have been cancelled")
+                });
+                done.send(result).unwrap();
+            });
+        }
+        // The second request can only finish by being cancelled, but it
+        // might not be waiting yet.
+        let result = loop {
+            limiter.cancel_waiting();
+            if let Ok(result) = second_done.recv_timeout(Duration::from_millis(10)) {
+                break result;
+            }
+        };
+        assert!(matches!(result, Err(Error::Cancelled)));
+
+        // The request which was already sent isn't affected.
+        finish.send(()).unwrap();
+        assert_eq!(first.join().unwrap().unwrap().status, 503);
+        assert!(limiter.slots.lock().unwrap().in_flight.is_empty());
+    }
+}
@@ -0,0 +1,342 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! An opt-in, in-memory cache for conditional requests.
+//!
+//! Requests made with `Request::use_etag_cache(true)` remember the `ETag` and
+//! `Last-Modified` validators (and body) of the last successful response for
+//! their method and URL. Later requests for the same resource send these back
+//! as `If-None-Match`/`If-Modified-Since`, and if the server answers with a
+//! `304 Not Modified`, the caller gets the cached body in a `200` response
+//! with `Response::from_cache` set.
+//!
+//! Any request using a method other than `GET` or `HEAD` invalidates the
+//! entries for its URL, whether or not it opted in to the cache.
+
+use crate::{header_names, status_codes, Backend, Error, Method, Request, Response};
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+static CACHE: Lazy<Mutex<ResponseCache>> = Lazy::new(|| Mutex::new(ResponseCache::default()));
+
+/// Remove everything from the conditional request cache.
+pub fn clear_cache() {
+    CACHE.lock().unwrap().clear();
+}
+
+/// Bound the total size of the response bodies held by the conditional
+/// request cache, evicting the least recently used entries when it is
+/// exceeded. `None` (the default) means the cache is unbounded.
+pub fn set_cache_size_limit(limit: Option<usize>) {
+    CACHE.lock().unwrap().set_limit(limit);
+}
+
+pub(crate) fn send(request: Request, backend: &dyn Backend) -> Result<Response, Error> {
+    send_with_cache(&CACHE, request, backend)
+}
+
+fn is_cacheable_method(method: Method) -> bool {
+    matches!(method, Method::Get | Method::Head)
+}
+
+// Note that we don't hold the lock while the request is in flight.
+fn send_with_cache(
+    cache: &Mutex<ResponseCache>,
+    mut request: Request,
+    backend: &dyn Backend,
+) -> Result<Response, Error> {
+    if !is_cacheable_method(request.method) {
+        cache.lock().unwrap().invalidate_url(request.url.as_str());
+        return backend.send(request);
+    }
+    // Sensitive responses are never kept around.
+    if !request.use_etag_cache || request.sensitive {
+        return backend.send(request);
+    }
+    let key = (request.method, request.url.to_string());
+    // If the caller supplied their own validators, a 304 refers to whatever
+    // they have cached, not us, so we leave it alone.
+    let mut sent_validators = false;
+    let validators = cache.lock().unwrap().validators(&key);
+    if let Some((etag, last_modified)) = validators {
+        if request.headers.get(header_names::IF_NONE_MATCH).is_none()
+            && request
+                .headers
+                .get(header_names::IF_MODIFIED_SINCE)
+                .is_none()
+        {
+            if let Some(etag) = etag {
+                request.headers.insert(header_names::IF_NONE_MATCH, etag)?;
+            }
+            if let Some(last_modified) = last_modified {
+                request
+                    .headers
+                    .insert(header_names::IF_MODIFIED_SINCE, last_modified)?;
+            }
+            sent_validators = true;
+        }
+    }
+
+    let response = backend.send(request)?;
+
+    let mut cache = cache.lock().unwrap();
+    if response.status == status_codes::NOT_MODIFIED {
+        if sent_validators {
+            if let Some(mut cached) = cache.revalidate(&key, response.headers.clone()) {
+                cached.request_id = response.request_id;
+                return Ok(cached);
+            }
+            // The entry was cleared or evicted while the request was in
+            // flight. Nothing better to do than hand back the 304.
+            log::warn!("Cache entry vanished before its 304 response arrived");
+        }
+        return Ok(response);
+    }
+    if response.status == status_codes::OK {
+        cache.store(key, &response);
+    }
+    Ok(response)
+}
+
+type CacheKey = (Method, String);
+
+struct CacheEntry {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    response: Response,
+}
+
+#[derive(Default)]
+struct ResponseCache {
+    entries: HashMap<CacheKey, CacheEntry>,
+    // Least recently used first.
+    lru: VecDeque<CacheKey>,
+    // Total size of the bodies in `entries`.
+    size: usize,
+    limit: Option<usize>,
+}
+
+impl ResponseCache {
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.lru.clear();
+        self.size = 0;
+    }
+
+    fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+        self.evict();
+    }
+
+    fn validators(&self, key: &CacheKey) -> Option<(Option<String>, Option<String>)> {
+        let entry = self.entries.get(key)?;
+        Some((entry.etag.clone(), entry.last_modified.clone()))
+    }
+
+    fn touch(&mut self, key: &CacheKey) {
+        if let Some(pos) = self.lru.iter().position(|k| k == key) {
+            let key = self.lru.remove(pos).unwrap();
+            self.lru.push_back(key);
+        }
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.size -= entry.response.body.len();
+            self.lru.retain(|k| k != key);
+        }
+    }
+
+    fn invalidate_url(&mut self, url: &str) {
+        let stale = self
+            .entries
+            .keys()
+            .filter(|(_, u)| u == url)
+            .cloned()
+            .collect::<Vec<_>>();
+        for key in stale {
+            self.remove(&key);
+        }
+    }
+
+    fn revalidate(
+        &mut self,
+        key: &CacheKey,
+        not_modified_headers: crate::Headers,
+    ) -> Option<Response> {
+        let entry = self.entries.get(key)?;
+        let mut response = entry.response.clone();
+        // A 304 may carry updated metadata for the cached response.
+        response.headers.extend(not_modified_headers);
+        response.from_cache = true;
+        self.touch(key);
+        Some(response)
+    }
+
+    fn store(&mut self, key: CacheKey, response: &Response) {
+        self.remove(&key);
+        let etag = response.headers.get(header_names::ETAG).map(String::from);
+        let last_modified = response
+            .headers
+            .get(header_names::LAST_MODIFIED)
+            .map(String::from);
+        if etag.is_none() && last_modified.is_none() {
+            // Nothing to revalidate with.
+            return;
+        }
+        if matches!(self.limit, Some(limit) if response.body.len() > limit) {
+            return;
+        }
+        self.size += response.body.len();
+        self.lru.push_back(key.clone());
+        self.entries.insert(
+            key,
+            CacheEntry {
+                etag,
+                last_modified,
+                response: response.clone(),
+            },
+        );
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return,
+        };
+        while self.size > limit {
+            match self.lru.front().cloned() {
+                Some(oldest) => self.remove(&oldest),
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::stub::StubResponse;
+    use crate::testing::TestBackend;
+    use url::Url;
+
+    // Respond to the next request with `body`, and `etag` if there is one.
+    fn script(backend: &TestBackend, status: u16, etag: Option<&str>, body: &str) {
+        let mut response = StubResponse::new(status).body(body);
+        if let Some(etag) = etag {
+            response = response.header(header_names::ETAG, etag);
+        }
+        backend.respond(response);
+    }
+
+    fn last_if_none_match(backend: &TestBackend) -> Option<String> {
+        backend
+            .requests()
+            .last()
+            .unwrap()
+            .headers
+            .get(header_names::IF_NONE_MATCH)
+            .map(String::from)
+    }
+
+    fn get(url: &str) -> Request {
+        Request::get(Url::parse(url).unwrap()).use_etag_cache(true)
+    }
+
+    #[test]
+    fn test_revalidation_sequence() {
+        let cache = Mutex::new(ResponseCache::default());
+        let backend = TestBackend::default();
+        let url = "https://www.example.com/devices";
+
+        script(&backend, 200, Some("\"a\""), "one");
+        let resp = send_with_cache(&cache, get(url), &backend).unwrap();
+        assert_eq!(last_if_none_match(&backend), None);
+        assert_eq!(resp.text(), "one");
+        assert!(!resp.from_cache);
+
+        script(&backend, 304, Some("\"a\""), "");
+        let resp = send_with_cache(&cache, get(url), &backend).unwrap();
+        assert_eq!(last_if_none_match(&backend).as_deref(), Some("\"a\""));
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.text(), "one");
+        assert!(resp.from_cache);
+
+        script(&backend, 200, Some("\"b\""), "two");
+        let resp = send_with_cache(&cache, get(url), &backend).unwrap();
+        assert_eq!(last_if_none_match(&backend).as_deref(), Some("\"a\""));
+        assert_eq!(resp.text(), "two");
+        assert!(!resp.from_cache);
+
+        script(&backend, 200, Some("\"c\""), "three");
+        let resp = send_with_cache(&cache, get(url), &backend).unwrap();
+        assert_eq!(last_if_none_match(&backend).as_deref(), Some("\"b\""));
+        assert_eq!(resp.text(), "three");
+    }
+
+    #[test]
+    fn test_opt_in_only() {
+        let cache = Mutex::new(ResponseCache::default());
+        let backend = TestBackend::default();
+        let url = Url::parse("https://www.example.com/config").unwrap();
+
+        script(&backend, 200, Some("\"a\""), "one");
+        send_with_cache(&cache, Request::get(url.clone()), &backend).unwrap();
+        script(&backend, 200, Some("\"a\""), "one");
+        send_with_cache(&cache, Request::get(url), &backend).unwrap();
+        assert_eq!(last_if_none_match(&backend), None);
+    }
+
+    #[test]
+    fn test_invalidated_by_write() {
+        let cache = Mutex::new(ResponseCache::default());
+        let backend = TestBackend::default();
+        let url = "https://www.example.com/devices";
+
+        script(&backend, 200, Some("\"a\""), "one");
+        send_with_cache(&cache, get(url), &backend).unwrap();
+
+        script(&backend, 200, None, "");
+        let post = Request::post(Url::parse(url).unwrap());
+        send_with_cache(&cache, post, &backend).unwrap();
+
+        script(&backend, 200, Some("\"b\""), "two");
+        send_with_cache(&cache, get(url), &backend).unwrap();
+        assert_eq!(last_if_none_match(&backend), None);
+    }
+
+    #[test]
+    fn test_size_bound_eviction() {
+        let cache = Mutex::new(ResponseCache::default());
+        cache.lock().unwrap().set_limit(Some(10));
+        let backend = TestBackend::default();
+        let url_a = "https://www.example.com/a";
+        let url_b = "https://www.example.com/b";
+
+        script(&backend, 200, Some("\"a\""), "aaaaaa");
+        send_with_cache(&cache, get(url_a), &backend).unwrap();
+        script(&backend, 200, Some("\"b\""), "bbbbbb");
+        send_with_cache(&cache, get(url_b), &backend).unwrap();
+        assert_eq!(cache.lock().unwrap().size, 6);
+
+        // `a` was evicted to make room for `b`...
+        script(&backend, 200, Some("\"a\""), "aaaaaa");
+        send_with_cache(&cache, get(url_a), &backend).unwrap();
+        assert_eq!(last_if_none_match(&backend), None);
+
+        // ... and `b` to make room for `a` again.
+        script(&backend, 200, Some("\"b\""), "bbbbbb");
+        send_with_cache(&cache, get(url_b), &backend).unwrap();
+        assert_eq!(last_if_none_match(&backend), None);
+
+        // Bodies larger than the whole cache are never stored.
+        script(&backend, 200, Some("\"c\""), "ccccccccccccccc");
+        send_with_cache(&cache, get(url_a), &backend).unwrap();
+        let cache = cache.lock().unwrap();
+        assert_eq!(cache.entries.len(), 1);
+        assert_eq!(cache.size, 6);
+    }
+}
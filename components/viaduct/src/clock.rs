@@ -0,0 +1,256 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The time, for the features which depend on it: backoff (see the `backoff`
+//! module) and long polling.
+//!
+//! Outside of tests, [`current`] is the system clock, which costs nothing over
+//! calling `Instant::now()` and friends directly. Tests can install a
+//! [`ManualClock`] instead, which only moves when it's told to, or when
+//! something waits on it, so they don't have to sleep.
+
+use std::sync::{Condvar, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+
+pub(crate) trait Clock {
+    fn now(&self) -> Instant;
+
+    /// The wall-clock time, for `Retry-After` dates.
+    fn system_now(&self) -> SystemTime;
+
+    /// Wait on `condvar` for up to `timeout`, like `Condvar::wait_timeout`.
+    fn wait_timeout<'a, T>(
+        &self,
+        condvar: &Condvar,
+        guard: MutexGuard<'a, T>,
+        timeout: Duration,
+    ) -> MutexGuard<'a, T>;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    #[inline]
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    #[inline]
+    fn system_now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn wait_timeout<'a, T>(
+        &self,
+        condvar: &Condvar,
+        guard: MutexGuard<'a, T>,
+        timeout: Duration,
+    ) -> MutexGuard<'a, T> {
+        condvar.wait_timeout(guard, timeout).unwrap().0
+    }
+}
+
+/// The clock to use.
+#[cfg(not(test))]
+#[inline]
+pub(crate) fn current() -> SystemClock {
+    SystemClock
+}
+
+#[cfg(test)]
+pub(crate) use testing::{current, CurrentClock, ManualClock};
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+    use once_cell::sync::Lazy;
+    use std::ops::Deref;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    static INSTALLED: Lazy<Mutex<Option<Arc<ManualClock>>>> = Lazy::new(|| Mutex::new(None));
+
+    /// The clock to use: the manual clock if one's installed, or the system
+    /// clock otherwise.
+    pub(crate) fn current() -> CurrentClock {
+        match &*INSTALLED.lock().unwrap() {
+            Some(clock) => CurrentClock::Manual(clock.clone()),
+            None => CurrentClock::System(SystemClock),
+        }
+    }
+
+    pub(crate) enum CurrentClock {
+        System(SystemClock),
+        Manual(Arc<ManualClock>),
+    }
+
+    impl Clock for CurrentClock {
+        fn now(&self) -> Instant {
+            match self {
+                CurrentClock::System(clock) => clock.now(),
+                CurrentClock::Manual(clock) => clock.now(),
+            }
+        }
+
+        fn system_now(&self) -> SystemTime {
+            match self {
+                CurrentClock::System(clock) => clock.system_now(),
+                CurrentClock::Manual(clock) => clock.system_now(),
+            }
+        }
+
+        fn wait_timeout<'a, T>(
+            &self,
+            condvar: &Condvar,
+            guard: MutexGuard<'a, T>,
+            timeout: Duration,
+        ) -> MutexGuard<'a, T> {
+            match self {
+                CurrentClock::System(clock) => clock.wait_timeout(condvar, guard, timeout),
+                CurrentClock::Manual(clock) => clock.wait_timeout(condvar, guard, timeout),
+            }
+        }
+    }
+
+    /// A clock which starts at an arbitrary time, and only moves when it's
+    /// advanced. Waiting on it advances it by the whole timeout straight
+    /// away, as if nothing else happened in the meantime, unless that's
+    /// turned off with [`ManualClock::set_advance_on_wait`].
+    pub(crate) struct ManualClock {
+        start: Instant,
+        elapsed: Mutex<Duration>,
+        advance_on_wait: AtomicBool,
+    }
+
+    impl ManualClock {
+        // A whole number of seconds, so that HTTP dates (which don't have
+        // fractions of a second) are exact.
+        const SYSTEM_START: Duration = Duration::from_secs(1_600_000_000);
+
+        /// Use a new manual clock everywhere, until the returned guard is
+        /// dropped. This holds `testing::GLOBAL_LOCK` in the meantime.
+        pub(crate) fn install() -> ManualClockGuard {
+            let lock = crate::testing::lock();
+            let clock = Arc::new(ManualClock {
+                start: Instant::now(),
+                elapsed: Mutex::new(Duration::default()),
+                advance_on_wait: AtomicBool::new(true),
+            });
+            *INSTALLED.lock().unwrap() = Some(clock.clone());
+            ManualClockGuard { clock, _lock: lock }
+        }
+
+        pub(crate) fn advance(&self, by: Duration) {
+            *self.elapsed.lock().unwrap() += by;
+        }
+
+        /// If `false`, waiting doesn't move the clock, but blocks until the
+        /// condvar is notified, so a test can have something wait for as
+        /// long as the other threads take.
+        pub(crate) fn set_advance_on_wait(&self, advance: bool) {
+            self.advance_on_wait.store(advance, Ordering::SeqCst);
+        }
+
+        fn elapsed(&self) -> Duration {
+            *self.elapsed.lock().unwrap()
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            self.start + self.elapsed()
+        }
+
+        fn system_now(&self) -> SystemTime {
+            SystemTime::UNIX_EPOCH + Self::SYSTEM_START + self.elapsed()
+        }
+
+        fn wait_timeout<'a, T>(
+            &self,
+            condvar: &Condvar,
+            guard: MutexGuard<'a, T>,
+            timeout: Duration,
+        ) -> MutexGuard<'a, T> {
+            if !self.advance_on_wait.load(Ordering::SeqCst) {
+                return condvar.wait(guard).unwrap();
+            }
+            self.advance(timeout);
+            guard
+        }
+    }
+
+    /// Uninstalls the [`ManualClock`] it derefs to when dropped.
+    pub(crate) struct ManualClockGuard {
+        clock: Arc<ManualClock>,
+        _lock: MutexGuard<'static, ()>,
+    }
+
+    impl Deref for ManualClockGuard {
+        type Target = ManualClock;
+
+        fn deref(&self) -> &ManualClock {
+            &self.clock
+        }
+    }
+
+    impl Drop for ManualClockGuard {
+        fn drop(&mut self) {
+            *INSTALLED.lock().unwrap() = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_manual_clock() {
+        let clock = ManualClock::install();
+        let start = current().now();
+        let system_start = current().system_now();
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(current().now() - start, Duration::from_secs(30));
+        assert_eq!(
+            current().system_now().duration_since(system_start).unwrap(),
+            Duration::from_secs(30)
+        );
+
+        // Waiting moves the clock on, without any real waiting.
+        let mutex = Mutex::new(());
+        let real_start = Instant::now();
+        drop(current().wait_timeout(
+            &Condvar::new(),
+            mutex.lock().unwrap(),
+            Duration::from_secs(3600),
+        ));
+        assert_eq!(current().now() - start, Duration::from_secs(3630));
+        assert!(real_start.elapsed() < Duration::from_secs(1));
+
+        // Unless that's turned off, when it waits to be notified instead.
+        clock.set_advance_on_wait(false);
+        let notified = Arc::new((Mutex::new(false), Condvar::new()));
+        let notifier = {
+            let notified = notified.clone();
+            std::thread::spawn(move || {
+                *notified.0.lock().unwrap() = true;
+                notified.1.notify_all();
+            })
+        };
+        let mut done = notified.0.lock().unwrap();
+        while !*done {
+            done = current().wait_timeout(&notified.1, done, Duration::from_secs(60));
+        }
+        drop(done);
+        notifier.join().unwrap();
+        assert_eq!(current().now() - start, Duration::from_secs(3630));
+
+        // And once it's uninstalled, we're back to the real time.
+        drop(clock);
+        let _lock = crate::testing::lock();
+        assert!(current().now() < start + Duration::from_secs(60));
+    }
+}
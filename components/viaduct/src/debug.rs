@@ -0,0 +1,715 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Capturing recent requests in memory, to export as a HAR file for bug
+//! reports.
+//!
+//! Capture is off by default. [`enable_capture`] keeps the last few requests
+//! (including ones which failed before reaching the backend), and
+//! [`export_har`] writes them out as a [HAR 1.2] document, which devtools
+//! can load. Each entry has the backend which sent it, how long it waited to
+//! be sent and for the response, and anything notable which happened on the
+//! way, like a redirect or a backoff, in the non-standard `_events` field.
+//!
+//! Header values listed in [`REDACTED_HEADERS`] are never captured. Request
+//! bodies aren't either, and response bodies only are for unsuccessful
+//! responses, cut down to [`MAX_BODY_SAMPLE`] bytes, as for
+//! `Error::HttpStatus`. For [sensitive] requests, they're `[sensitive]`
+//! instead.
+//!
+//! [HAR 1.2]: http://www.softwareishard.com/blog/har-12-spec/
+//! [`REDACTED_HEADERS`]: crate::REDACTED_HEADERS
+//! [`MAX_BODY_SAMPLE`]: crate::MAX_BODY_SAMPLE
+//! [sensitive]: crate::Request::sensitive
+
+use crate::{clock, clock::Clock, header_names, Backend, Error, Request, Response};
+use once_cell::sync::Lazy;
+use serde_derive::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+static CAPTURED: Lazy<Mutex<Option<Ring>>> = Lazy::new(|| Mutex::new(None));
+
+/// Start keeping the last `max_entries` requests, or change how many are
+/// kept if capture is already on. Zero is the same as [`disable_capture`].
+pub fn enable_capture(max_entries: usize) {
+    let mut captured = CAPTURED.lock().unwrap();
+    if max_entries == 0 {
+        *captured = None;
+        return;
+    }
+    captured
+        .get_or_insert_with(|| Ring::new(max_entries))
+        .set_max_entries(max_entries);
+}
+
+/// Stop capturing requests, and forget the ones already captured.
+pub fn disable_capture() {
+    *CAPTURED.lock().unwrap() = None;
+}
+
+/// The captured requests, oldest first, as a HAR document. This has no
+/// entries if capture is off.
+pub fn export_har() -> String {
+    let captured = CAPTURED.lock().unwrap();
+    let entries = captured
+        .as_ref()
+        .map(|ring| ring.entries.iter().collect())
+        .unwrap_or_default();
+    let har = Har {
+        log: Log {
+            version: "1.2",
+            creator: Creator {
+                name: "viaduct",
+                version: env!("CARGO_PKG_VERSION"),
+            },
+            entries,
+        },
+    };
+    // These are all strings and numbers, so this can't fail.
+    serde_json::to_string(&har).expect("Failed to serialize HAR")
+}
+
+struct Ring {
+    max_entries: usize,
+    entries: VecDeque<Entry>,
+}
+
+impl Ring {
+    fn new(max_entries: usize) -> Self {
+        Self {
+            max_entries,
+            entries: VecDeque::with_capacity(max_entries),
+        }
+    }
+
+    fn set_max_entries(&mut self, max_entries: usize) {
+        self.max_entries = max_entries;
+        self.evict();
+    }
+
+    fn push(&mut self, entry: Entry) {
+        self.entries.push_back(entry);
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.entries.len() > self.max_entries {
+            self.entries.pop_front();
+        }
+    }
+}
+
+/// A request on its way through `backend::send`, which is added to the
+/// captured requests when it's finished. Does nothing if capture was off
+/// when the request was sent.
+pub(crate) struct Capture(Option<InFlight>);
+
+struct InFlight {
+    entry: Entry,
+    started: Instant,
+    dispatched: Option<Instant>,
+}
+
+impl Capture {
+    pub(crate) fn start(request: &Request) -> Self {
+        if CAPTURED.lock().unwrap().is_none() {
+            return Capture(None);
+        }
+        let clock = clock::current();
+        let entry = Entry::new(request, clock.system_now());
+        Capture(Some(InFlight {
+            entry,
+            started: clock.now(),
+            dispatched: None,
+        }))
+    }
+
+    /// Note the request as it's about to go to `backend`, with its URL
+    /// normalized and the default headers added.
+    pub(crate) fn sending(&mut self, request: &Request, backend: &dyn Backend) {
+        if let Some(in_flight) = &mut self.0 {
+            let har_request = &mut in_flight.entry.request;
+            har_request.set_url(&request.url);
+            har_request.headers = name_values(request.headers.redacted());
+            in_flight.entry.backend = Some(backend.name());
+        }
+    }
+
+    /// Note that the request has got past any backoff and concurrency limit,
+    /// and is being handed to the backend.
+    pub(crate) fn dispatched(&mut self) {
+        if let Some(in_flight) = &mut self.0 {
+            in_flight.dispatched = Some(clock::current().now());
+        }
+    }
+
+    pub(crate) fn finish(self, result: &Result<Response, Error>) {
+        let in_flight = match self.0 {
+            Some(in_flight) => in_flight,
+            None => return,
+        };
+        let now = clock::current().now();
+        let mut entry = in_flight.entry;
+        // Everything before the request reaches the backend counts as
+        // blocked, and everything after as waiting for the response, since
+        // backends don't tell us any more than that.
+        let (blocked, wait) = match in_flight.dispatched {
+            Some(dispatched) => (dispatched - in_flight.started, Some(now - dispatched)),
+            None => (now - in_flight.started, None),
+        };
+        entry.timings.blocked = millis(blocked);
+        entry.timings.wait = wait.map_or(0.0, millis);
+        entry.time = entry.timings.blocked + entry.timings.wait;
+        match result {
+            Ok(response) => entry.set_response(response),
+            Err(e) => {
+                if let Error::BackoffError { remaining } = e {
+                    entry
+                        .events
+                        .push(format!("Refused while backing off, for {:?}", remaining));
+                }
+                entry.error = Some(e.to_string());
+            }
+        }
+        if let Some(ring) = &mut *CAPTURED.lock().unwrap() {
+            ring.push(entry);
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Har<'a> {
+    log: Log<'a>,
+}
+
+#[derive(Serialize)]
+struct Log<'a> {
+    version: &'static str,
+    creator: Creator,
+    entries: Vec<&'a Entry>,
+}
+
+#[derive(Serialize)]
+struct Creator {
+    name: &'static str,
+    version: &'static str,
+}
+
+#[derive(Serialize)]
+struct Empty {}
+
+#[derive(Serialize)]
+struct NameValue {
+    name: String,
+    value: String,
+}
+
+fn name_values(pairs: impl IntoIterator<Item = (String, String)>) -> Vec<NameValue> {
+    pairs
+        .into_iter()
+        .map(|(name, value)| NameValue { name, value })
+        .collect()
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Entry {
+    started_date_time: String,
+    /// The total of the timings, in milliseconds.
+    time: f64,
+    request: HarRequest,
+    response: HarResponse,
+    cache: Empty,
+    timings: Timings,
+    #[serde(rename = "_backend")]
+    backend: Option<&'static str>,
+    #[serde(rename = "_requestId")]
+    request_id: String,
+    #[serde(rename = "_error", skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(rename = "_events")]
+    events: Vec<String>,
+    #[serde(skip)]
+    sensitive: bool,
+}
+
+impl Entry {
+    fn new(request: &Request, started: SystemTime) -> Self {
+        let mut entry = Entry {
+            started_date_time: iso_8601(started),
+            time: 0.0,
+            request: HarRequest {
+                method: request.method.as_str(),
+                url: String::new(),
+                http_version: "HTTP/1.1",
+                cookies: vec![],
+                headers: name_values(request.headers.redacted()),
+                query_string: vec![],
+                headers_size: -1,
+                body_size: request.body.as_ref().map_or(0, |body| body.len() as i64),
+            },
+            response: HarResponse::none(),
+            cache: Empty {},
+            timings: Timings::default(),
+            backend: None,
+            request_id: request.id().to_string(),
+            error: None,
+            events: vec![],
+            sensitive: request.sensitive,
+        };
+        entry.request.set_url(&request.url);
+        entry
+    }
+
+    fn set_response(&mut self, response: &Response) {
+        for hop in &response.redirects {
+            self.events
+                .push(format!("Redirected ({}) to {}", hop.status, hop.location));
+        }
+        if response.from_cache {
+            self.events
+                .push("Not modified, served from the cache".to_owned());
+        }
+        if let Some(duration) = crate::backoff::backoff_duration(response) {
+            self.events
+                .push(format!("Server asked us to back off for {:?}", duration));
+        }
+        let mime_type = response
+            .headers
+            .get(header_names::CONTENT_TYPE)
+            .unwrap_or_default();
+        self.response = HarResponse {
+            status: response.status,
+            status_text: "",
+            http_version: "HTTP/1.1",
+            cookies: vec![],
+            headers: name_values(response.headers.redacted()),
+            content: Content {
+                size: response.body.len() as i64,
+                mime_type: mime_type.to_owned(),
+                text: if response.is_success() {
+                    None
+                } else if self.sensitive {
+                    Some(crate::sensitive::PLACEHOLDER.to_owned())
+                } else {
+                    Some(crate::json::body_sample(&response.body))
+                },
+            },
+            redirect_url: response
+                .headers
+                .get(header_names::LOCATION)
+                .unwrap_or_default()
+                .to_owned(),
+            headers_size: -1,
+            body_size: response.body.len() as i64,
+        };
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HarRequest {
+    method: &'static str,
+    url: String,
+    http_version: &'static str,
+    cookies: Vec<NameValue>,
+    headers: Vec<NameValue>,
+    query_string: Vec<NameValue>,
+    headers_size: i64,
+    body_size: i64,
+}
+
+impl HarRequest {
+    fn set_url(&mut self, url: &url::Url) {
+        self.url = url.to_string();
+        self.query_string = name_values(url.query_pairs().into_owned());
+    }
+}
+
+/// A response, or, for requests which failed, a placeholder with status 0,
+/// as browsers export them.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HarResponse {
+    status: u16,
+    status_text: &'static str,
+    http_version: &'static str,
+    cookies: Vec<NameValue>,
+    headers: Vec<NameValue>,
+    content: Content,
+    #[serde(rename = "redirectURL")]
+    redirect_url: String,
+    headers_size: i64,
+    body_size: i64,
+}
+
+impl HarResponse {
+    fn none() -> Self {
+        Self {
+            status: 0,
+            status_text: "",
+            http_version: "",
+            cookies: vec![],
+            headers: vec![],
+            content: Content {
+                size: 0,
+                mime_type: String::new(),
+                text: None,
+            },
+            redirect_url: String::new(),
+            headers_size: -1,
+            body_size: -1,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Content {
+    size: i64,
+    mime_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+}
+
+/// In milliseconds. -1 means the phase doesn't apply, or we don't know.
+#[derive(Serialize)]
+struct Timings {
+    blocked: f64,
+    dns: f64,
+    connect: f64,
+    send: f64,
+    wait: f64,
+    receive: f64,
+    ssl: f64,
+}
+
+impl Default for Timings {
+    fn default() -> Self {
+        Self {
+            blocked: 0.0,
+            dns: -1.0,
+            connect: -1.0,
+            send: 0.0,
+            wait: 0.0,
+            receive: 0.0,
+            ssl: -1.0,
+        }
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+// `time` in UTC, like `2020-09-13T12:26:40.000Z`, which is the format HAR
+// wants. The date is worked out with Howard Hinnant's `civil_from_days`.
+fn iso_8601(time: SystemTime) -> String {
+    let since_epoch = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (days, second_of_day) = (seconds / 86_400, seconds % 86_400);
+    // Days since 0000-03-01, so that leap days come at the end of the year.
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        second_of_day / 3600,
+        second_of_day % 3600 / 60,
+        second_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{send_with, stub::StubResponse};
+    use crate::clock::ManualClock;
+    use crate::testing::TestBackend;
+    use crate::RedirectHop;
+    use serde_json::Value;
+    use url::Url;
+
+    const HOST: &str = "har.example.com";
+
+    // Takes a while to respond to each request, by the manual clock.
+    fn slow_backend() -> TestBackend {
+        TestBackend::with_delay(Duration::from_millis(250))
+    }
+
+    // Respond to the next request with `status`, and a cookie which needs
+    // redacting. A `503` asks us to back off, after a redirect.
+    fn script(backend: &TestBackend, status: u16) {
+        let response = StubResponse::new(status)
+            .header(header_names::CONTENT_TYPE, "text/plain")
+            .header("Set-Cookie", "session=secret")
+            .body(format!("status {}", status));
+        if status == 503 {
+            backend.respond_redirected(
+                response.header(header_names::RETRY_AFTER, "30"),
+                vec![RedirectHop {
+                    status: 302,
+                    location: Url::parse(&format!("https://{}/503", HOST)).unwrap(),
+                }],
+            );
+        } else {
+            backend.respond(response);
+        }
+    }
+
+    fn entry(n: usize) -> Entry {
+        let request = Request::get(Url::parse(&format!("https://{}/{}", HOST, n)).unwrap());
+        Entry::new(&request, SystemTime::now())
+    }
+
+    fn captured_entries() -> Vec<Value> {
+        let har: Value = serde_json::from_str(&export_har()).unwrap();
+        // Other tests may send requests while capture's on.
+        har["log"]["entries"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|entry| entry["request"]["url"].as_str().unwrap().contains(HOST))
+            .cloned()
+            .collect()
+    }
+
+    fn assert_is_name_values(value: &Value) {
+        for pair in value.as_array().unwrap() {
+            assert!(pair["name"].is_string(), "{}", pair);
+            assert!(pair["value"].is_string(), "{}", pair);
+        }
+    }
+
+    // Checks for the fields HAR 1.2 requires, with the right types.
+    fn assert_is_har_entry(entry: &Value) {
+        assert!(entry["startedDateTime"].is_string());
+        assert!(entry["time"].is_number());
+        assert!(entry["cache"].is_object());
+        let request = &entry["request"];
+        for field in &["method", "url", "httpVersion"] {
+            assert!(request[field].is_string(), "{}: {}", field, request);
+        }
+        for field in &["cookies", "headers", "queryString"] {
+            assert_is_name_values(&request[field]);
+        }
+        assert!(request["headersSize"].is_i64());
+        assert!(request["bodySize"].is_i64());
+        let response = &entry["response"];
+        assert!(response["status"].is_u64());
+        for field in &["statusText", "httpVersion", "redirectURL"] {
+            assert!(response[field].is_string(), "{}: {}", field, response);
+        }
+        for field in &["cookies", "headers"] {
+            assert_is_name_values(&response[field]);
+        }
+        assert!(response["content"]["size"].is_i64());
+        assert!(response["content"]["mimeType"].is_string());
+        assert!(response["headersSize"].is_i64());
+        assert!(response["bodySize"].is_i64());
+        let timings = &entry["timings"];
+        for field in &["send", "wait", "receive"] {
+            assert!(timings[field].as_f64().unwrap() >= 0.0, "{}", timings);
+        }
+        for field in &["blocked", "dns", "connect", "ssl"] {
+            assert!(timings[field].as_f64().unwrap() >= -1.0, "{}", timings);
+        }
+    }
+
+    #[test]
+    fn test_export_har() {
+        let clock = ManualClock::install();
+        enable_capture(10);
+        let request = |status: u16| {
+            Request::get(Url::parse(&format!("https://{}/{}?q=1", HOST, status)).unwrap())
+                .header(header_names::AUTHORIZATION, "Bearer secret-token")
+                .unwrap()
+                .ignore_backoff()
+        };
+        let backend = slow_backend();
+        script(&backend, 200);
+        script(&backend, 503);
+        send_with(request(200), || Ok(&backend)).unwrap();
+        send_with(request(503), || Ok(&backend)).unwrap();
+        send_with(
+            Request::get(Url::parse(&format!("http://{}/200", HOST)).unwrap()),
+            || Ok(&backend),
+        )
+        .unwrap_err();
+        let har_text = export_har();
+        disable_capture();
+        crate::clear_backoffs();
+
+        let har: Value = serde_json::from_str(&har_text).unwrap();
+        assert_eq!(har["log"]["version"], "1.2");
+        assert_eq!(har["log"]["creator"]["name"], "viaduct");
+        assert!(har["log"]["creator"]["version"].is_string());
+        let entries = har["log"]["entries"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|entry| entry["request"]["url"].as_str().unwrap().contains(HOST))
+            .collect::<Vec<_>>();
+        assert_eq!(entries.len(), 3);
+        for entry in &entries {
+            assert_is_har_entry(entry);
+        }
+
+        let ok = entries[0];
+        assert_eq!(ok["startedDateTime"], "2020-09-13T12:26:40.000Z");
+        assert_eq!(ok["time"], 250.0);
+        assert_eq!(ok["timings"]["wait"], 250.0);
+        assert_eq!(ok["_backend"], "test");
+        assert_eq!(ok["request"]["queryString"][0]["name"], "q");
+        assert_eq!(ok["response"]["status"], 200);
+        assert_eq!(ok["response"]["content"]["mimeType"], "text/plain");
+        // Successful responses' bodies are left out.
+        assert_eq!(ok["response"]["content"]["size"], 10);
+        assert!(ok["response"]["content"].get("text").is_none());
+
+        let unavailable = entries[1];
+        assert_eq!(unavailable["startedDateTime"], "2020-09-13T12:26:40.250Z");
+        assert_eq!(unavailable["response"]["content"]["text"], "status 503");
+        let events = unavailable["_events"].as_array().unwrap();
+        assert_eq!(events.len(), 2);
+        assert!(events[0].as_str().unwrap().starts_with("Redirected (302)"));
+        assert!(events[1].as_str().unwrap().contains("back off for 30s"));
+
+        // Requests which fail before they're sent are there too.
+        let failed = entries[2];
+        assert_eq!(failed["response"]["status"], 0);
+        assert_eq!(failed["_backend"], Value::Null);
+        assert!(failed["_error"].is_string());
+        assert_eq!(failed["time"], 0.0);
+
+        // The secrets are nowhere.
+        assert!(!har_text.contains("secret"), "{}", har_text);
+        assert!(!har_text.to_lowercase().contains("bearer"), "{}", har_text);
+        let headers = &ok["request"]["headers"];
+        assert!(headers
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|h| h["name"] == "authorization" && h["value"] == "[redacted]"));
+
+        // Turning capture off forgets everything.
+        assert!(captured_entries().is_empty());
+        drop(clock);
+    }
+
+    #[test]
+    fn test_backoff_events() {
+        let clock = ManualClock::install();
+        enable_capture(10);
+        let backend = slow_backend();
+        script(&backend, 503);
+        let url = Url::parse(&format!("https://{}/503", HOST)).unwrap();
+        send_with(Request::get(url.clone()), || Ok(&backend)).unwrap();
+        assert!(matches!(
+            send_with(Request::get(url), || Ok(&backend)).map_err(|e| e.into_inner()),
+            Err(Error::BackoffError { .. })
+        ));
+        let entries = captured_entries();
+        disable_capture();
+        crate::clear_backoffs();
+
+        assert_eq!(entries.len(), 2);
+        let refused = &entries[1];
+        assert_eq!(refused["_backend"], "test");
+        assert_eq!(refused["response"]["status"], 0);
+        assert!(refused["_events"][0]
+            .as_str()
+            .unwrap()
+            .starts_with("Refused while backing off"));
+        drop(clock);
+    }
+
+    #[test]
+    fn test_sensitive_body_sample() {
+        let clock = ManualClock::install();
+        enable_capture(10);
+        let backend = slow_backend();
+        script(&backend, 404);
+        script(&backend, 404);
+        let url = Url::parse(&format!("https://{}/404", HOST)).unwrap();
+        send_with(
+            Request::post(url.clone()).body("hunter2").sensitive(true),
+            || Ok(&backend),
+        )
+        .unwrap();
+        send_with(Request::post(url).body("hunter2"), || Ok(&backend)).unwrap();
+        let entries = captured_entries();
+        disable_capture();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["response"]["content"]["text"], "[sensitive]");
+        assert_eq!(entries[1]["response"]["content"]["text"], "status 404");
+        for entry in &entries {
+            assert!(!entry.to_string().contains("hunter2"), "{}", entry);
+        }
+        drop(clock);
+    }
+
+    #[test]
+    fn test_ring_eviction() {
+        let mut ring = Ring::new(3);
+        for n in 0..5 {
+            ring.push(entry(n));
+        }
+        let urls = |ring: &Ring| {
+            ring.entries
+                .iter()
+                .map(|entry| entry.request.url.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            urls(&ring),
+            vec![
+                format!("https://{}/2", HOST),
+                format!("https://{}/3", HOST),
+                format!("https://{}/4", HOST),
+            ]
+        );
+        // Shrinking drops the oldest.
+        ring.set_max_entries(1);
+        assert_eq!(urls(&ring), vec![format!("https://{}/4", HOST)]);
+        ring.set_max_entries(2);
+        ring.push(entry(5));
+        assert_eq!(ring.entries.len(), 2);
+    }
+
+    #[test]
+    fn test_iso_8601() {
+        let at = |seconds, millis| {
+            iso_8601(
+                SystemTime::UNIX_EPOCH
+                    + Duration::from_secs(seconds)
+                    + Duration::from_millis(millis),
+            )
+        };
+        assert_eq!(at(0, 0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(at(951_782_400, 5), "2000-02-29T00:00:00.005Z");
+        assert_eq!(at(1_600_000_000, 250), "2020-09-13T12:26:40.250Z");
+        assert_eq!(at(4_107_542_399, 999), "2100-02-28T23:59:59.999Z");
+    }
+}
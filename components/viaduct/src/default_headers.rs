@@ -0,0 +1,175 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Headers added to every request.
+//!
+//! The embedding application knows what to send as the User-Agent and
+//! Accept-Language, but the components making requests don't, so rather
+//! than have each of them ask for it, the application sets them once with
+//! [`set_default_headers`]. Requests which set one of these headers
+//! themselves keep their own value.
+
+use crate::{header_names, Error, Headers, Request};
+use once_cell::sync::OnceCell;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DefaultHeaders {
+    /// Sent as the User-Agent, unless it's empty.
+    pub user_agent: String,
+    /// Sent as the Accept-Language, if set.
+    pub accept_language: Option<String>,
+    /// Any other headers to send. These can't replace the User-Agent or
+    /// Accept-Language given above.
+    pub extra: Headers,
+}
+
+impl DefaultHeaders {
+    pub fn with_user_agent(user_agent: impl Into<String>) -> Self {
+        DefaultHeaders {
+            user_agent: user_agent.into(),
+            ..DefaultHeaders::default()
+        }
+    }
+
+    fn to_headers(&self) -> Result<Headers, Error> {
+        let mut headers = Headers::new();
+        if !self.user_agent.is_empty() {
+            headers.set(header_names::USER_AGENT, self.user_agent.as_str())?;
+        }
+        if let Some(accept_language) = &self.accept_language {
+            headers.set(header_names::ACCEPT_LANGUAGE, accept_language.as_str())?;
+        }
+        headers.merge(&self.extra);
+        Ok(headers)
+    }
+}
+
+static DEFAULT_HEADERS: OnceCell<Headers> = OnceCell::new();
+
+/// Set the headers to add to every request. Like the TLS config, this may
+/// only be set once, and must be set before the first request is made.
+/// Fails with `Error::RequestHeaderError` if a value isn't a valid header
+/// value.
+pub fn set_default_headers(defaults: DefaultHeaders) -> Result<(), Error> {
+    set_in(&DEFAULT_HEADERS, &defaults)
+}
+
+/// Get the headers added to every request, locking in none at all if
+/// `set_default_headers` hasn't been called yet.
+pub fn default_headers() -> &'static Headers {
+    DEFAULT_HEADERS.get_or_init(Headers::new)
+}
+
+/// The headers set with `set_default_headers`, without locking them in.
+pub(crate) fn default_headers_if_set() -> Option<&'static Headers> {
+    DEFAULT_HEADERS.get()
+}
+
+/// Add the default headers which `request` doesn't already have.
+pub(crate) fn apply(request: &mut Request) {
+    apply_from(&DEFAULT_HEADERS, request)
+}
+
+fn set_in(cell: &OnceCell<Headers>, defaults: &DefaultHeaders) -> Result<(), Error> {
+    let headers = defaults.to_headers()?;
+    cell.set(headers).map_err(|_| Error::SetDefaultHeadersError)
+}
+
+fn apply_from(cell: &OnceCell<Headers>, request: &mut Request) {
+    request.headers.merge(cell.get_or_init(Headers::new));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Method;
+
+    fn request() -> Request {
+        Request::new(Method::Get, "https://www.example.com".parse().unwrap())
+    }
+
+    fn defaults() -> DefaultHeaders {
+        let mut extra = Headers::new();
+        extra
+            .set("X-App-Version", "1.0")
+            .unwrap()
+            .set(header_names::USER_AGENT, "ignored")
+            .unwrap();
+        DefaultHeaders {
+            user_agent: "app/1.0".into(),
+            accept_language: Some("en-CA, fr;q=0.5".into()),
+            extra,
+        }
+    }
+
+    #[test]
+    fn test_defaults_added() {
+        let cell = OnceCell::new();
+        set_in(&cell, &defaults()).unwrap();
+        let mut r = request();
+        apply_from(&cell, &mut r);
+        assert_eq!(r.headers.get(header_names::USER_AGENT), Some("app/1.0"));
+        assert_eq!(
+            r.headers.get(header_names::ACCEPT_LANGUAGE),
+            Some("en-CA, fr;q=0.5")
+        );
+        assert_eq!(r.headers.get("x-app-version"), Some("1.0"));
+        assert_eq!(r.headers.len(), 3);
+
+        // An empty user agent isn't sent.
+        let cell = OnceCell::new();
+        set_in(&cell, &DefaultHeaders::default()).unwrap();
+        let mut r = request();
+        apply_from(&cell, &mut r);
+        assert!(r.headers.is_empty());
+    }
+
+    #[test]
+    fn test_explicit_headers_win() {
+        let cell = OnceCell::new();
+        set_in(&cell, &defaults()).unwrap();
+        let mut r = request()
+            .header(header_names::USER_AGENT, "custom/2.0")
+            .unwrap()
+            .header(header_names::ACCEPT_LANGUAGE, "de")
+            .unwrap();
+        apply_from(&cell, &mut r);
+        assert_eq!(r.headers.get(header_names::USER_AGENT), Some("custom/2.0"));
+        assert_eq!(r.headers.get(header_names::ACCEPT_LANGUAGE), Some("de"));
+        assert_eq!(r.headers.get("x-app-version"), Some("1.0"));
+        assert_eq!(r.headers.len(), 3);
+    }
+
+    #[test]
+    fn test_set_after_first_request() {
+        let cell = OnceCell::new();
+        apply_from(&cell, &mut request());
+        match set_in(&cell, &defaults()) {
+            Err(Error::SetDefaultHeadersError) => {}
+            other => panic!("Expected SetDefaultHeadersError, got {:?}", other),
+        }
+        let mut r = request();
+        apply_from(&cell, &mut r);
+        assert!(r.headers.is_empty());
+
+        // Setting them twice fails too, and keeps the first ones.
+        let cell = OnceCell::new();
+        set_in(&cell, &DefaultHeaders::with_user_agent("first/1.0")).unwrap();
+        assert!(set_in(&cell, &DefaultHeaders::with_user_agent("second/1.0")).is_err());
+        let mut r = request();
+        apply_from(&cell, &mut r);
+        assert_eq!(r.headers.get(header_names::USER_AGENT), Some("first/1.0"));
+    }
+
+    #[test]
+    fn test_invalid_value() {
+        let cell = OnceCell::new();
+        match set_in(&cell, &DefaultHeaders::with_user_agent("bad\nagent")) {
+            Err(Error::RequestHeaderError(_)) => {}
+            other => panic!("Expected RequestHeaderError, got {:?}", other),
+        }
+        // Nothing was locked in.
+        assert!(cell.get().is_none());
+    }
+}
@@ -13,12 +13,55 @@ pub enum Error {
     #[error("[no-sentry] Network error: {0}")]
     NetworkError(String),
 
+    /// The device isn't connected to a network, or there's no route to the
+    /// server.
+    #[error("[no-sentry] Network unreachable: {0}")]
+    Offline(String),
+
+    /// The server's hostname couldn't be resolved.
+    #[error("[no-sentry] DNS error: {0}")]
+    DnsError(String),
+
+    /// The TLS handshake with the server failed, say because its certificate
+    /// isn't trusted.
+    #[error("[no-sentry] TLS error: {message}")]
+    TlsError { message: String },
+
+    /// Connecting to the server, or reading its response, took too long.
+    #[error("[no-sentry] Request timed out: {0}")]
+    Timeout(String),
+
+    /// The server's host was reachable, but refused the connection, which
+    /// usually means the server is down.
+    #[error("[no-sentry] Connection refused: {0}")]
+    ConnectionRefused(String),
+
+    /// The request was cancelled before it finished.
+    #[error("[no-sentry] Request cancelled")]
+    Cancelled,
+
+    /// The request was made while viaduct was shutting down. See
+    /// `viaduct::shutdown`.
+    #[error("[no-sentry] Viaduct is shutting down")]
+    ShuttingDown,
+
     #[error("The rust-components network backend must be initialized before use!")]
     BackendNotInitialized,
 
     #[error("Backend already initialized.")]
     SetBackendError,
 
+    /// `init` asked for a different backend than the one chosen before,
+    /// by `chosen_by` (a source location).
+    #[error(
+        "Asked for the '{requested}' backend, but '{chosen}' was already chosen by {chosen_by}"
+    )]
+    BackendMismatch {
+        chosen: &'static str,
+        requested: &'static str,
+        chosen_by: String,
+    },
+
     /// Note: we return this if the server returns a bad URL with
     /// its response. This *probably* should never happen, but who knows.
     #[error("[no-sentry] URL Parse Error: {0}")]
@@ -26,6 +69,137 @@ pub enum Error {
 
     #[error("[no-sentry] Validation error: URL does not use TLS protocol.")]
     NonTlsUrl,
+
+    /// Credentials belong in headers, where they can be redacted from logs,
+    /// and not in the URL.
+    #[error("[no-sentry] Validation error: URL contains a username or password.")]
+    UrlHasCredentials,
+
+    /// Backends disagree on what to do with these, so they're refused
+    /// rather than dropped or sent.
+    #[error("[no-sentry] Validation error: {0} requests can't have a body.")]
+    BodyNotAllowed(crate::Method),
+
+    /// The server asked us not to contact it for a while, and this request
+    /// was made before that time was up. See `Request::ignore_backoff`.
+    #[error("[no-sentry] Server requested backoff, {remaining:?} remaining")]
+    BackoffError { remaining: std::time::Duration },
+
+    /// The FFI backend's fetch callback was already running `max_concurrent`
+    /// requests, and `max_queued` more were waiting for it. See
+    /// `set_callback_limits`.
+    #[error(
+        "[no-sentry] Too many requests for the fetch callback ({max_concurrent} in flight, {max_queued} waiting)"
+    )]
+    TooManyRequests {
+        max_concurrent: usize,
+        max_queued: usize,
+    },
+
+    #[error("TLS config already set.")]
+    SetTlsConfigError,
+
+    /// `set_default_headers` was called more than once, or after the first
+    /// request was made.
+    #[error("Default headers already set.")]
+    SetDefaultHeadersError,
+
+    /// None of the certificates presented by the server matched the pins
+    /// configured for it with `set_tls_config`.
+    #[error("[no-sentry] Certificate pin violation for host '{host}'")]
+    PinViolation { host: String },
+
+    /// The TLS config has settings for the request's host, but the backend
+    /// can't apply them.
+    #[error("The '{0}' backend doesn't support custom TLS configuration")]
+    TlsConfigNotSupported(&'static str),
+
+    /// Recording to or replaying from a cassette failed, or there was no
+    /// recorded response for a request. See the `replay` module.
+    #[error("Replay error: {0}")]
+    ReplayError(String),
+
+    /// The server returned a non-2xx status. Returned by
+    /// [`Response::check_success`](crate::Response::check_success); `body_sample`
+    /// is the start of the body, for debugging.
+    #[error("[no-sentry] HTTP status {code} from {url}")]
+    HttpStatus {
+        code: u16,
+        url: url::Url,
+        body_sample: String,
+    },
+
+    /// A body couldn't be serialized to, or parsed as, JSON. `path` is where
+    /// in the document it went wrong, such as `records[2].id`.
+    #[error("[no-sentry] JSON error at '{path}': {source}")]
+    JsonError {
+        path: String,
+        #[source]
+        source: serde_json::Error,
+    },
+
+    /// The response body was bigger than the request's limit. See
+    /// `Request::max_response_size`. `content_length` is what the server
+    /// said the length would be, if it did.
+    #[error(
+        "[no-sentry] Response body larger than {limit} bytes (Content-Length: {content_length:?})"
+    )]
+    ResponseTooLarge {
+        limit: u64,
+        content_length: Option<u64>,
+    },
+
+    /// `Response::parse_json` was called on a response with a non-JSON
+    /// Content-Type.
+    #[error("[no-sentry] Expected a JSON response, got Content-Type '{0}'")]
+    NotJson(String),
+
+    /// Sending the request with id `request_id` failed with `error`. Every
+    /// error from `Request::send` is wrapped in this, so that it can be
+    /// matched up with the server's logs. Match on `Error::inner` to find out
+    /// what went wrong.
+    #[error("{error} (request {request_id})")]
+    RequestFailed {
+        request_id: crate::RequestId,
+        error: Box<Error>,
+    },
+}
+
+impl Error {
+    /// The id of the request this error came from, if it came from sending
+    /// one.
+    pub fn request_id(&self) -> Option<crate::RequestId> {
+        match self {
+            Error::RequestFailed { request_id, .. } => Some(*request_id),
+            _ => None,
+        }
+    }
+
+    /// The error itself, without the request id.
+    pub fn inner(&self) -> &Error {
+        match self {
+            Error::RequestFailed { error, .. } => error,
+            _ => self,
+        }
+    }
+
+    /// Like `inner`, but takes ownership.
+    pub fn into_inner(self) -> Error {
+        match self {
+            Error::RequestFailed { error, .. } => *error,
+            _ => self,
+        }
+    }
+
+    pub(crate) fn with_request_id(self, request_id: crate::RequestId) -> Error {
+        match self {
+            Error::RequestFailed { .. } => self,
+            _ => Error::RequestFailed {
+                request_id,
+                error: Box::new(self),
+            },
+        }
+    }
 }
 
 impl From<url::ParseError> for Error {
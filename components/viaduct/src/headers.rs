@@ -5,6 +5,7 @@ pub use name::{HeaderName, InvalidHeaderName};
 use std::collections::HashMap;
 use std::iter::FromIterator;
 use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 mod name;
 
 /// A single header. Headers have a name (case insensitive) and a value. The
@@ -83,12 +84,12 @@ impl Header {
 
     #[inline]
     fn set_value<V: AsRef<str>>(&mut self, s: V) -> Result<(), crate::Error> {
-        let value = s.as_ref();
-        if !is_valid_header_value(&value) {
+        let value = s.as_ref().trim();
+        if !is_valid_header_value(value) {
             Err(crate::Error::RequestHeaderError(self.name.clone()))
         } else {
             self.value.clear();
-            self.value.push_str(s.as_ref().trim());
+            self.value.push_str(value);
             Ok(())
         }
     }
@@ -96,11 +97,27 @@ impl Header {
 
 impl std::fmt::Display for Header {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}: {}", self.name, self.value)
+        write!(f, "{}: {}", self.name.to_canonical_case(), self.value)
+    }
+}
+
+/// Returned by [`Headers::get_as`] when a header's value couldn't be parsed.
+/// The value itself is left out, since it may be sensitive.
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+#[error("Invalid value for header '{0}'")]
+pub struct HeaderParseError(HeaderName);
+
+impl HeaderParseError {
+    pub fn name(&self) -> &HeaderName {
+        &self.0
     }
 }
 
 /// A list of headers.
+///
+/// Most headers appear at most once, and for those, `set` (or `insert`)
+/// replaces any existing value. Headers which may be repeated, like
+/// `Set-Cookie`, can be added with `append`, and read with `get_all`.
 #[derive(Clone, Debug, PartialEq, Default)]
 pub struct Headers {
     headers: Vec<Header>,
@@ -144,43 +161,89 @@ impl Headers {
         self.headers.clear();
     }
 
-    /// Insert or update a new header.
+    /// Set a header, replacing any existing headers with the same name.
     ///
     /// This returns an error if you attempt to specify a header with an
     /// invalid value (values must be printable ASCII and may not contain
-    /// \r or \n)
+    /// \r or \n), rather than letting the value smuggle in another header.
     ///
     /// ## Example
     /// ```
     /// # use viaduct::Headers;
     /// # fn main() -> Result<(), viaduct::Error> {
     /// let mut h = Headers::new();
-    /// h.insert("My-Cool-Header", "example")?;
+    /// h.set("My-Cool-Header", "example")?;
     /// assert_eq!(h.get("My-Cool-Header"), Some("example"));
     ///
-    /// // Note: names are sensitive
+    /// // Note: names are case insensitive
     /// assert_eq!(h.get("my-cool-header"), Some("example"));
     ///
     /// // Also note, constants for headers are in `viaduct::header_names`, and
     /// // you can chain the result of this function.
-    /// h.insert(viaduct::header_names::CONTENT_TYPE, "something...")?
-    ///  .insert("Something-Else", "etc")?;
+    /// h.set(viaduct::header_names::CONTENT_TYPE, "something...")?
+    ///  .set("Something-Else", "etc")?;
+    ///
+    /// assert!(h.set("Evil", "a\r\nSet-Cookie: b").is_err());
     /// # Ok(())
     /// # }
     /// ```
-    pub fn insert<N, V>(&mut self, name: N, value: V) -> Result<&mut Self, crate::Error>
+    pub fn set<N, V>(&mut self, name: N, value: V) -> Result<&mut Self, crate::Error>
     where
         N: Into<HeaderName> + PartialEq<HeaderName>,
         V: Into<String> + AsRef<str>,
     {
-        if let Some(entry) = self.headers.iter_mut().find(|h| name == h.name) {
-            entry.set_value(value)?;
-        } else {
-            self.headers.push(Header::new(name, value)?);
+        match self.headers.iter().position(|h| name == h.name) {
+            Some(pos) => {
+                self.headers[pos].set_value(value)?;
+                let name = self.headers[pos].name.clone();
+                self.remove_after(pos, &name);
+            }
+            None => self.headers.push(Header::new(name, value)?),
         }
         Ok(self)
     }
 
+    /// The same as `set`.
+    pub fn insert<N, V>(&mut self, name: N, value: V) -> Result<&mut Self, crate::Error>
+    where
+        N: Into<HeaderName> + PartialEq<HeaderName>,
+        V: Into<String> + AsRef<str>,
+    {
+        self.set(name, value)
+    }
+
+    /// Add a header, keeping any existing headers with the same name. Use
+    /// this for headers which may appear more than once, like `Set-Cookie`.
+    /// Values are validated as for `set`.
+    ///
+    /// ```
+    /// # use viaduct::Headers;
+    /// # fn main() -> Result<(), viaduct::Error> {
+    /// let mut h = Headers::new();
+    /// h.append("Accept", "text/html")?.append("Accept", "application/json")?;
+    /// let accept: Vec<_> = h.get_all("accept").collect();
+    /// assert_eq!(accept, vec!["text/html", "application/json"]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn append<N, V>(&mut self, name: N, value: V) -> Result<&mut Self, crate::Error>
+    where
+        N: Into<HeaderName>,
+        V: Into<String> + AsRef<str>,
+    {
+        self.headers.push(Header::new(name, value)?);
+        Ok(self)
+    }
+
+    // Remove the headers named `name` after the one at `pos`.
+    fn remove_after(&mut self, pos: usize, name: &HeaderName) {
+        let mut i = 0;
+        self.headers.retain(|h| {
+            i += 1;
+            i <= pos + 1 || h.name != *name
+        });
+    }
+
     /// Insert the provided header unless a header is already specified.
     /// Mostly used internally, e.g. to set "Content-Type: application/json"
     /// in `Request::json()` unless it has been set specifically.
@@ -195,18 +258,55 @@ impl Headers {
         Ok(self)
     }
 
-    /// Insert or update a header directly. Typically you will want to use
-    /// `insert` over this, as it performs less work if the header needs
-    /// updating instead of insertion.
+    /// Set a header directly, replacing any existing headers with the same
+    /// name. Typically you will want to use `set` over this, as it performs
+    /// less work if the header needs updating instead of insertion.
     pub fn insert_header(&mut self, new: Header) -> &mut Self {
-        if let Some(entry) = self.headers.iter_mut().find(|h| h.name == new.name) {
-            entry.value = new.value;
-        } else {
-            self.headers.push(new);
+        match self.headers.iter().position(|h| h.name == new.name) {
+            Some(pos) => {
+                self.remove_after(pos, &new.name);
+                self.headers[pos].value = new.value;
+            }
+            None => self.headers.push(new),
         }
         self
     }
 
+    /// Add a header directly, keeping any existing headers with the same name.
+    /// Backends use this for response headers, so that repeated ones (like
+    /// `Set-Cookie`) aren't lost.
+    pub fn append_header(&mut self, new: Header) -> &mut Self {
+        self.headers.push(new);
+        self
+    }
+
+    /// Add the headers in `defaults` whose names aren't already set here, for
+    /// layering a request's own headers over ones sent with every request.
+    /// Repeated headers in `defaults` are all added.
+    ///
+    /// ```
+    /// # use viaduct::Headers;
+    /// # fn main() -> Result<(), viaduct::Error> {
+    /// let mut defaults = Headers::new();
+    /// defaults.set("User-Agent", "app/1.0")?.set("Accept", "*/*")?;
+    /// let mut h = Headers::new();
+    /// h.set("Accept", "application/json")?;
+    /// h.merge(&defaults);
+    /// assert_eq!(h.get("user-agent"), Some("app/1.0"));
+    /// assert_eq!(h.get("accept"), Some("application/json"));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn merge(&mut self, defaults: &Headers) -> &mut Self {
+        let missing = defaults
+            .iter()
+            .filter(|d| !self.headers.iter().any(|h| h.name == d.name))
+            .cloned()
+            .collect::<Vec<_>>();
+        self.headers.extend(missing);
+        self
+    }
+
     /// Add all the headers in the provided iterator to this list of headers.
     pub fn extend<I>(&mut self, iter: I) -> &mut Self
     where
@@ -262,46 +362,65 @@ impl Headers {
         self.get_header(name).map(|h| h.value.as_str())
     }
 
+    /// Get all the values of the headers with the provided name, in the order
+    /// they were added (or received).
+    ///
+    /// Where a header may be repeated, this is usually what you want rather
+    /// than `get`, which only returns the first. Note that for most headers,
+    /// repeats are equivalent to a single header with the values joined by
+    /// commas, but `Set-Cookie` is an exception (cookies can contain commas),
+    /// so don't join its values.
+    pub fn get_all<'a, S>(&'a self, name: S) -> impl Iterator<Item = &'a str>
+    where
+        S: PartialEq<HeaderName> + 'a,
+    {
+        self.headers
+            .iter()
+            .filter(move |h| name == h.name)
+            .map(|h| h.value.as_str())
+    }
+
     /// Get the value of the header with the provided name, and
     /// attempt to parse it using [`std::str::FromStr`].
     ///
-    /// - If the header is missing, it returns None.
+    /// - If the header is missing, it returns `Ok(None)`.
     /// - If the header is present but parsing failed, returns
-    ///   `Some(Err(<error returned by parsing>))`.
-    /// - Otherwise, returns `Some(Ok(result))`.
-    ///
-    /// Note that if `Option<Result<T, E>>` is inconvenient for you,
-    /// and you wish this returned `Result<Option<T>, E>`, you may use
-    /// the built-in `transpose()` method to convert between them.
+    ///   `Err(HeaderParseError)`.
+    /// - Otherwise, returns `Ok(Some(result))`.
     ///
     /// ```
     /// # use viaduct::Headers;
     /// # fn main() -> Result<(), viaduct::Error> {
     /// let mut h = Headers::new();
     /// h.insert("Example", "1234")?.insert("Illegal", "abcd")?;
-    /// let v: Option<Result<i64, _>> = h.get_as("Example");
-    /// assert_eq!(v, Some(Ok(1234)));
-    /// assert_eq!(h.get_as::<i64, _>("Example"), Some(Ok(1234)));
-    /// assert_eq!(h.get_as::<i64, _>("Illegal"), Some("abcd".parse::<i64>()));
-    /// assert_eq!(h.get_as::<i64, _>("Something-Else"), None);
+    /// assert_eq!(h.get_as::<i64, _>("Example"), Ok(Some(1234)));
+    /// assert!(h.get_as::<i64, _>("Illegal").is_err());
+    /// assert_eq!(h.get_as::<i64, _>("Something-Else"), Ok(None));
     /// # Ok(())
     /// # }
     /// ```
-    pub fn get_as<T, S>(&self, name: S) -> Option<Result<T, <T as FromStr>::Err>>
+    pub fn get_as<T, S>(&self, name: S) -> Result<Option<T>, HeaderParseError>
     where
         T: FromStr,
         S: PartialEq<HeaderName>,
     {
-        self.get(name).map(str::parse)
+        match self.get_header(name) {
+            Some(h) => match h.value.parse() {
+                Ok(v) => Ok(Some(v)),
+                Err(_) => Err(HeaderParseError(h.name.clone())),
+            },
+            None => Ok(None),
+        }
     }
+
     /// Get the value of the header with the provided name, and
     /// attempt to parse it using [`std::str::FromStr`].
     ///
     /// This is a variant of `get_as` that returns None on error,
     /// intended to be used for cases where missing and invalid
     /// headers should be treated the same. (With `get_as` this
-    /// requires `h.get_as(...).and_then(|r| r.ok())`, which is
-    /// somewhat opaque.
+    /// requires `h.get_as(...).ok().flatten()`, which is
+    /// somewhat opaque.)
     pub fn try_get<T, S>(&self, name: S) -> Option<T>
     where
         T: FromStr,
@@ -318,6 +437,36 @@ impl Headers {
     }
 }
 
+/// Headers whose values are replaced with `[redacted]` wherever requests and
+/// responses are written down, like replay cassettes and HAR exports.
+pub const REDACTED_HEADERS: &[&str] = &[
+    "authorization",
+    "cookie",
+    "proxy-authorization",
+    "set-cookie",
+    "x-keyid",
+];
+
+pub(crate) const REDACTED: &str = "[redacted]";
+
+impl Headers {
+    /// The names and values of these headers, with the values of
+    /// [`REDACTED_HEADERS`] replaced.
+    pub(crate) fn redacted(&self) -> Vec<(String, String)> {
+        self.iter()
+            .map(|header| {
+                let name = header.name().as_str();
+                let value = if REDACTED_HEADERS.contains(&name) {
+                    REDACTED
+                } else {
+                    header.value()
+                };
+                (name.to_owned(), value.to_owned())
+            })
+            .collect()
+    }
+}
+
 impl std::iter::IntoIterator for Headers {
     type IntoIter = <Vec<Header> as IntoIterator>::IntoIter;
     type Item = Header;
@@ -347,13 +496,179 @@ impl FromIterator<Header> for Headers {
     }
 }
 
+// Repeated headers are joined with commas, which is equivalent for everything
+// but `Set-Cookie`, and that's only ever a response header.
 #[allow(clippy::implicit_hasher)] // https://github.com/rust-lang/rust-clippy/issues/3899
 impl From<Headers> for HashMap<String, String> {
     fn from(headers: Headers) -> HashMap<String, String> {
-        headers
-            .into_iter()
-            .map(|h| (String::from(h.name), h.value))
-            .collect()
+        let mut map: HashMap<String, String> = HashMap::with_capacity(headers.len());
+        for h in headers {
+            let value = h.value;
+            map.entry(String::from(h.name))
+                .and_modify(|v| {
+                    v.push_str(", ");
+                    v.push_str(&value);
+                })
+                .or_insert(value);
+        }
+        map
+    }
+}
+
+/// The value of a `Retry-After` header, which may be either a number of
+/// seconds or an HTTP date. Parse it with `Headers::get_as`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryAfter {
+    Delay(Duration),
+    Date(SystemTime),
+}
+
+impl RetryAfter {
+    /// How long after `now` to wait. Dates in the past mean no wait.
+    pub fn delay_from(&self, now: SystemTime) -> Duration {
+        match self {
+            RetryAfter::Delay(delay) => *delay,
+            RetryAfter::Date(date) => date.duration_since(now).unwrap_or_default(),
+        }
+    }
+}
+
+impl FromStr for RetryAfter {
+    type Err = httpdate::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().parse::<u64>() {
+            Ok(seconds) => Ok(RetryAfter::Delay(Duration::from_secs(seconds))),
+            Err(_) => httpdate::parse_http_date(s).map(RetryAfter::Date),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::consts::*;
+    use super::*;
+
+    #[test]
+    fn test_set_cookie_duplicates() {
+        let mut h = Headers::new();
+        h.append("Set-Cookie", "a=1; Expires=Wed, 21 Oct 2015 07:28:00 GMT")
+            .unwrap()
+            .append("set-cookie", "b=2")
+            .unwrap()
+            .set(CONTENT_TYPE, "text/plain")
+            .unwrap();
+        assert_eq!(
+            h.get_all("SET-COOKIE").collect::<Vec<_>>(),
+            vec!["a=1; Expires=Wed, 21 Oct 2015 07:28:00 GMT", "b=2"]
+        );
+        assert_eq!(
+            h.get("set-cookie"),
+            Some("a=1; Expires=Wed, 21 Oct 2015 07:28:00 GMT")
+        );
+        assert_eq!(h.len(), 3);
+
+        // Backends keep repeated response headers too.
+        let mut response = Headers::new();
+        for header in &h {
+            response.append_header(header.clone());
+        }
+        assert_eq!(response, h);
+
+        // Setting replaces all of them.
+        h.set("Set-Cookie", "c=3").unwrap();
+        assert_eq!(h.get_all("set-cookie").collect::<Vec<_>>(), vec!["c=3"]);
+        h.insert_header(Header::new("set-cookie", "d=4").unwrap());
+        assert_eq!(h.get_all("set-cookie").collect::<Vec<_>>(), vec!["d=4"]);
+        assert_eq!(h.len(), 2);
+    }
+
+    #[test]
+    fn test_injection_rejected() {
+        let mut h = Headers::new();
+        h.set("X-Example", "ok").unwrap();
+        for bad in &["a\r\nSet-Cookie: b", "a\nb", "a\rb", "caf\u{e9}", "a\0b"] {
+            assert!(matches!(
+                h.set("X-Example", *bad),
+                Err(crate::Error::RequestHeaderError(_))
+            ));
+            assert!(h.append("X-Other", *bad).is_err());
+            assert!(h.insert_if_missing("X-Other", *bad).is_err());
+        }
+        // Nothing was changed.
+        assert_eq!(h.get("x-example"), Some("ok"));
+        assert_eq!(h.len(), 1);
+        // Surrounding whitespace, including a trailing newline, is trimmed.
+        h.set("X-Example", " fine\r\n").unwrap();
+        assert_eq!(h.get("x-example"), Some("fine"));
+        assert!(HeaderName::new("X-Example\r\nSet-Cookie").is_err());
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let mut h = Headers::new();
+        assert_eq!(h.get_as::<RetryAfter, _>(RETRY_AFTER), Ok(None));
+
+        h.set(RETRY_AFTER, "120").unwrap();
+        let retry_after = h.get_as::<RetryAfter, _>(RETRY_AFTER).unwrap().unwrap();
+        assert_eq!(retry_after, RetryAfter::Delay(Duration::from_secs(120)));
+        assert_eq!(
+            retry_after.delay_from(SystemTime::now()),
+            Duration::from_secs(120)
+        );
+
+        h.set(RETRY_AFTER, "Wed, 21 Oct 2015 07:28:00 GMT").unwrap();
+        let date = SystemTime::UNIX_EPOCH + Duration::from_secs(1_445_412_480);
+        assert_eq!(
+            h.get_as::<RetryAfter, _>(RETRY_AFTER),
+            Ok(Some(RetryAfter::Date(date)))
+        );
+        let retry_after = RetryAfter::Date(date);
+        assert_eq!(
+            retry_after.delay_from(date - Duration::from_secs(30)),
+            Duration::from_secs(30)
+        );
+        assert_eq!(
+            retry_after.delay_from(SystemTime::now()),
+            Duration::default()
+        );
+
+        h.set(RETRY_AFTER, "soon").unwrap();
+        let err = h.get_as::<RetryAfter, _>(RETRY_AFTER).unwrap_err();
+        assert_eq!(err.name(), &RETRY_AFTER);
+        assert_eq!(h.try_get::<RetryAfter, _>(RETRY_AFTER), None);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut defaults = Headers::new();
+        defaults
+            .set(USER_AGENT, "app/1.0")
+            .unwrap()
+            .set(ACCEPT, "*/*")
+            .unwrap()
+            .append("X-Tag", "a")
+            .unwrap()
+            .append("X-Tag", "b")
+            .unwrap();
+        let mut h = Headers::new();
+        h.set("accept", "application/json").unwrap();
+        h.merge(&defaults);
+        assert_eq!(h.get(USER_AGENT), Some("app/1.0"));
+        assert_eq!(
+            h.get_all(ACCEPT).collect::<Vec<_>>(),
+            vec!["application/json"]
+        );
+        assert_eq!(h.get_all("x-tag").collect::<Vec<_>>(), vec!["a", "b"]);
+
+        let map = HashMap::from(h);
+        assert_eq!(map["x-tag"], "a, b");
+        assert_eq!(map["accept"], "application/json");
+    }
+
+    #[test]
+    fn test_display() {
+        let header = Header::new(CONTENT_TYPE, "text/plain").unwrap();
+        assert_eq!(header.to_string(), "Content-Type: text/plain");
     }
 }
 
@@ -377,17 +692,26 @@ pub mod consts {
     // Feel free to add to these.
     headers!(
         (ACCEPT_ENCODING, "accept-encoding"),
+        (ACCEPT_LANGUAGE, "accept-language"),
         (ACCEPT, "accept"),
         (AUTHORIZATION, "authorization"),
+        (CONTENT_LENGTH, "content-length"),
         (CONTENT_TYPE, "content-type"),
         (ETAG, "etag"),
+        (IF_MODIFIED_SINCE, "if-modified-since"),
         (IF_NONE_MATCH, "if-none-match"),
+        (LAST_EVENT_ID, "last-event-id"),
+        (LAST_MODIFIED, "last-modified"),
+        (LOCATION, "location"),
+        (RANGE, "range"),
         (USER_AGENT, "user-agent"),
         // non-standard, but it's convenient to have these.
         (RETRY_AFTER, "retry-after"),
+        (X_BACKOFF, "x-backoff"),
         (X_IF_UNMODIFIED_SINCE, "x-if-unmodified-since"),
         (X_KEYID, "x-keyid"),
         (X_LAST_MODIFIED, "x-last-modified"),
+        (X_REQUEST_ID, "x-request-id"),
         (X_TIMESTAMP, "x-timestamp"),
         (X_WEAVE_NEXT_OFFSET, "x-weave-next-offset"),
         (X_WEAVE_RECORDS, "x-weave-records"),
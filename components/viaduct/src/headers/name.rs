@@ -95,6 +95,24 @@ impl HeaderName {
     pub fn as_str(&self) -> &str {
         &self.0[..]
     }
+
+    /// The name with the first letter of each word capitalized, like
+    /// `Content-Type`, for display. Names are always stored in lowercase.
+    pub fn to_canonical_case(&self) -> String {
+        let mut at_word_start = true;
+        self.0
+            .chars()
+            .map(|c| {
+                let c = if at_word_start {
+                    c.to_ascii_uppercase()
+                } else {
+                    c
+                };
+                at_word_start = c == '-';
+                c
+            })
+            .collect()
+    }
 }
 
 impl std::fmt::Display for HeaderName {
@@ -226,4 +244,19 @@ mod test {
             Ok(HeaderName("content-type".into()))
         );
     }
+
+    #[test]
+    fn test_canonical_case() {
+        assert_eq!(
+            HeaderName::from("CONTENT-type").to_canonical_case(),
+            "Content-Type"
+        );
+        assert_eq!(
+            HeaderName::from("x-weave-timestamp").to_canonical_case(),
+            "X-Weave-Timestamp"
+        );
+        assert_eq!(HeaderName::from("etag").to_canonical_case(), "Etag");
+        // Requests smuggling a header in through the name don't get this far.
+        assert!(HeaderName::new("x-foo\r\nset-cookie").is_err());
+    }
 }
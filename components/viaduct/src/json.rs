@@ -0,0 +1,203 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Helpers behind `Request::try_json`, `Response::parse_json` and
+//! `Response::check_success`.
+
+use crate::Error;
+
+/// The most of a response body kept in [`Error::HttpStatus`].
+pub const MAX_BODY_SAMPLE: usize = 500;
+
+/// The start of `body` as text, for error messages. Invalid UTF-8 is
+/// replaced, and the result is never more than `MAX_BODY_SAMPLE` bytes.
+pub(crate) fn body_sample(body: &[u8]) -> String {
+    let start = &body[..body.len().min(MAX_BODY_SAMPLE)];
+    let mut sample = String::from_utf8_lossy(start).into_owned();
+    // Replacement characters are longer than the bytes they replace.
+    if sample.len() > MAX_BODY_SAMPLE {
+        let mut end = MAX_BODY_SAMPLE;
+        while !sample.is_char_boundary(end) {
+            end -= 1;
+        }
+        sample.truncate(end);
+    }
+    sample
+}
+
+/// Is `content_type` JSON? Accepts parameters like `charset=utf-8`, and
+/// structured types like `application/problem+json`.
+pub(crate) fn is_json_content_type(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    essence == "application/json"
+        || (essence.starts_with("application/") && essence.ends_with("+json"))
+}
+
+pub(crate) fn to_vec<T: ?Sized + serde::Serialize>(val: &T) -> Result<Vec<u8>, Error> {
+    serde_json::to_vec(val).map_err(|source| Error::JsonError {
+        path: ".".into(),
+        source,
+    })
+}
+
+pub(crate) fn from_slice<T: serde::de::DeserializeOwned>(body: &[u8]) -> Result<T, Error> {
+    let mut de = serde_json::Deserializer::from_slice(body);
+    let val = serde_path_to_error::deserialize(&mut de).map_err(|e| Error::JsonError {
+        path: e.path().to_string(),
+        source: e.into_inner(),
+    })?;
+    // Trailing garbage isn't caught by `deserialize`.
+    de.end().map_err(|source| Error::JsonError {
+        path: ".".into(),
+        source,
+    })?;
+    Ok(val)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::stub::StubResponse;
+    use crate::testing::TestBackend;
+    use crate::{header_names, Backend, Request, Response};
+    use serde_derive::{Deserialize, Serialize};
+    use url::Url;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Record {
+        id: String,
+        tags: Vec<u32>,
+    }
+
+    fn get() -> Request {
+        Request::get(Url::parse("https://www.example.com/record").unwrap())
+    }
+
+    // The response to `get()`, with `status`, `content_type` and `body`.
+    fn respond(status: u16, content_type: Option<&str>, body: &str) -> Response {
+        let mut response = StubResponse::new(status).body(body);
+        if let Some(content_type) = content_type {
+            response = response.header(header_names::CONTENT_TYPE, content_type);
+        }
+        let backend = TestBackend::default();
+        backend.respond(response);
+        backend.send(get()).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let record = Record {
+            id: "abc".into(),
+            tags: vec![1, 2],
+        };
+        let request = Request::post(Url::parse("https://www.example.com/").unwrap())
+            .try_json(&record)
+            .unwrap();
+        assert_eq!(
+            request.headers.get(header_names::CONTENT_TYPE),
+            Some("application/json")
+        );
+        // Send the request's body straight back.
+        let backend = TestBackend::default();
+        backend.respond(
+            StubResponse::new(200)
+                .header(header_names::CONTENT_TYPE, "application/json")
+                .body(request.body.clone().unwrap()),
+        );
+        let response = backend.send(request).unwrap();
+        let parsed: Record = response.check_success().unwrap().parse_json().unwrap();
+        assert_eq!(parsed, record);
+    }
+
+    #[test]
+    fn test_serialize_error() {
+        let mut map = std::collections::HashMap::new();
+        map.insert(vec![1u8], 1);
+        let request = Request::post(Url::parse("https://www.example.com/").unwrap());
+        assert!(matches!(
+            request.try_json(&map),
+            Err(Error::JsonError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_content_types() {
+        for content_type in &[
+            "application/json",
+            "application/json; charset=utf-8",
+            "Application/JSON;charset=UTF-8",
+            "application/problem+json",
+        ] {
+            let response = respond(200, Some(content_type), r#"{"id":"a","tags":[]}"#);
+            assert!(response.parse_json::<Record>().is_ok(), "{}", content_type);
+        }
+        // Lots of servers don't bother saying, so no header is fine too.
+        let response = respond(200, None, r#"{"id":"a","tags":[]}"#);
+        assert!(response.parse_json::<Record>().is_ok());
+
+        let response = respond(200, Some("text/html"), "<html></html>");
+        match response.parse_json::<Record>() {
+            Err(Error::NotJson(content_type)) => assert_eq!(content_type, "text/html"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_malformed_json() {
+        let response = respond(
+            200,
+            Some("application/json"),
+            r#"{"id":"a","tags":[1,"two"]}"#,
+        );
+        match response.parse_json::<Record>() {
+            Err(Error::JsonError { path, .. }) => assert_eq!(path, "tags[1]"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let response = respond(200, Some("application/json"), r#"{"id":"a","tags":[]} x"#);
+        assert!(matches!(
+            response.parse_json::<Record>(),
+            Err(Error::JsonError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_http_status() {
+        let body = format!("{{\"error\":\"{}\"}}", "é".repeat(1000));
+        let response = respond(503, Some("application/json"), &body);
+        match response.check_success() {
+            Err(Error::HttpStatus {
+                code,
+                url,
+                body_sample,
+            }) => {
+                assert_eq!(code, 503);
+                assert_eq!(url.as_str(), "https://www.example.com/record");
+                assert!(body_sample.len() <= MAX_BODY_SAMPLE);
+                assert!(body_sample.starts_with("{\"error\":\"éé"));
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let response = respond(404, None, "");
+        assert!(matches!(
+            response.check_success(),
+            Err(Error::HttpStatus { code: 404, .. })
+        ));
+    }
+
+    #[test]
+    fn test_body_sample() {
+        assert_eq!(body_sample(b"short"), "short");
+        assert_eq!(body_sample(&[b'a'; 1000]).len(), MAX_BODY_SAMPLE);
+        let invalid = body_sample(&[0xff; 1000]);
+        assert!(invalid.len() <= MAX_BODY_SAMPLE);
+        assert!(invalid.chars().all(|c| c == '\u{fffd}'));
+    }
+}
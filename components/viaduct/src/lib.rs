@@ -10,13 +10,50 @@ use url::Url;
 mod headers;
 
 mod backend;
+mod backoff;
+mod cache;
+mod clock;
+pub mod debug;
+mod default_headers;
 pub mod error;
+mod json;
+pub mod longpoll;
+mod network_status;
+mod probe;
+mod progress;
+#[cfg(feature = "replay")]
+pub mod replay;
+mod request_id;
+mod sensitive;
 pub mod settings;
+mod shutdown;
+#[cfg(test)]
+mod testing;
+mod tls;
 pub use error::*;
 
-pub use backend::{note_backend, set_backend, Backend};
-pub use headers::{consts as header_names, Header, HeaderName, Headers, InvalidHeaderName};
+pub use backend::stub;
+pub use backend::{
+    backend_info, backend_selection, ensure_initialized, init, note_backend, set_backend,
+    set_callback_limits, Backend, BackendChoice, BackendInfo, BackendSelection, CallbackLimits,
+    CallbackQueueInfo,
+};
+pub use backoff::{clear_backoffs, current_backoffs};
+pub use cache::{clear_cache, set_cache_size_limit};
+pub use default_headers::{default_headers, set_default_headers, DefaultHeaders};
+pub use headers::{
+    consts as header_names, Header, HeaderName, HeaderParseError, Headers, InvalidHeaderName,
+    RetryAfter, REDACTED_HEADERS,
+};
+pub use json::MAX_BODY_SAMPLE;
+pub use network_status::{network_status, set_network_status, NetworkStatus};
+pub use probe::{probe, ProbeResult, MAX_PROBE_REDIRECTS};
+pub use progress::{ProgressHook, ProgressReader};
+pub use request_id::{send_request_id_header, set_send_request_id_header, RequestId};
+pub use sensitive::ZeroizingResponse;
 pub use settings::GLOBAL_SETTINGS;
+pub use shutdown::{shutdown, ShutdownPolicy, ShutdownReport};
+pub use tls::{set_tls_config, tls_config, TlsConfig};
 
 pub(crate) mod msg_types {
     include!("mozilla.appservices.httpconfig.protobuf.rs");
@@ -24,7 +61,10 @@ pub(crate) mod msg_types {
 
 /// HTTP Methods.
 ///
-/// The supported methods are the limited to what's supported by android-components.
+/// The supported methods are the limited to what's supported by android-components,
+/// plus `Patch`. The FFI backend on Android fails `Patch` requests, since
+/// android-components can't send them. The discriminants must match the
+/// `Method` enum in `fetch_msg_types.proto`.
 #[derive(Clone, Debug, Copy, PartialEq, PartialOrd, Eq, Ord, Hash)]
 #[repr(u8)]
 pub enum Method {
@@ -36,6 +76,7 @@ pub enum Method {
     Connect,
     Options,
     Trace,
+    Patch,
 }
 
 impl Method {
@@ -49,6 +90,7 @@ impl Method {
             Method::Connect => "CONNECT",
             Method::Options => "OPTIONS",
             Method::Trace => "TRACE",
+            Method::Patch => "PATCH",
         }
     }
 }
@@ -66,6 +108,31 @@ pub struct Request {
     pub url: Url,
     pub headers: Headers,
     pub body: Option<Vec<u8>>,
+    /// Whether this request should use the conditional request cache. See
+    /// `Request::use_etag_cache`.
+    pub use_etag_cache: bool,
+    /// Whether this request should be sent even if the server asked us to
+    /// back off. See `Request::ignore_backoff`.
+    pub ignore_backoff: bool,
+    /// Whether this request should be sent even if the device is offline.
+    /// See `Request::allow_while_offline`.
+    pub allow_while_offline: bool,
+    /// See `Request::on_upload_progress`.
+    pub upload_progress: Option<ProgressHook>,
+    /// See `Request::on_download_progress`.
+    pub download_progress: Option<ProgressHook>,
+    /// The largest response body to accept, in bytes, or None for
+    /// `GLOBAL_SETTINGS.max_response_size`. See `Request::max_response_size`.
+    pub max_response_size: Option<u64>,
+    /// Whether the body holds secrets. See `Request::sensitive`.
+    pub sensitive: bool,
+    /// Whether the backend should follow redirects, or None for
+    /// `GLOBAL_SETTINGS.follow_redirects`. See `Request::follow_redirects`.
+    pub follow_redirects: Option<bool>,
+    // See `Request::id`. Private, so that every request gets a fresh id
+    // unless `with_id` says otherwise, which means `Request` can't be built
+    // with a struct literal outside this crate.
+    id: RequestId,
 }
 
 impl Request {
@@ -77,9 +144,40 @@ impl Request {
             url,
             headers: Headers::new(),
             body: None,
+            use_etag_cache: false,
+            ignore_backoff: false,
+            allow_while_offline: false,
+            upload_progress: None,
+            download_progress: None,
+            max_response_size: None,
+            sensitive: false,
+            follow_redirects: None,
+            id: RequestId::new(),
         }
     }
 
+    /// This request's id, which its response, any error from sending it,
+    /// and clones of it share. See the `request_id` module.
+    pub fn id(&self) -> RequestId {
+        self.id
+    }
+
+    /// Give this request a new id, for sending a copy of it as a separate
+    /// request rather than another attempt at the same one.
+    pub fn with_new_id(mut self) -> Self {
+        self.id = RequestId::new();
+        self
+    }
+
+    /// Give this request the id `id`, for sending it as another attempt at
+    /// a request which was built separately, such as one saved from before
+    /// a restart. `Request` can't be built with a struct literal, since the
+    /// id is private, so this is the way to choose it.
+    pub fn with_id(mut self, id: RequestId) -> Self {
+        self.id = id;
+        self
+    }
+
     pub fn send(self) -> Result<Response, Error> {
         crate::backend::send(self)
     }
@@ -188,7 +286,122 @@ impl Request {
         Ok(self)
     }
 
-    /// Set this request's body.
+    /// Opt in to the in-memory conditional request cache.
+    ///
+    /// When enabled, the validators (`ETag` and `Last-Modified`) and body of
+    /// the last successful response for this method and URL are remembered,
+    /// and sent back with later requests. If the server responds with a
+    /// `304 Not Modified`, it's transparently replaced with a `200` response
+    /// containing the cached body, which has `Response::from_cache` set.
+    ///
+    /// Intended for consumers polling endpoints which rarely change. See
+    /// also [`clear_cache`] and [`set_cache_size_limit`].
+    pub fn use_etag_cache(mut self, use_cache: bool) -> Self {
+        self.use_etag_cache = use_cache;
+        self
+    }
+
+    /// Send this request even if the server asked us to back off.
+    ///
+    /// By default, after a response with a `Retry-After`, `X-Backoff` or
+    /// `X-Weave-Backoff` header, requests to the same host fail with
+    /// `Error::BackoffError` until the requested time has passed. See also
+    /// [`current_backoffs`] and [`clear_backoffs`].
+    pub fn ignore_backoff(mut self) -> Self {
+        self.ignore_backoff = true;
+        self
+    }
+
+    /// Send this request even if the embedding application has said the
+    /// device is offline.
+    ///
+    /// By default, after [`set_network_status`] is called with
+    /// `NetworkStatus::Offline`, requests fail immediately with
+    /// `Error::Offline`. This is for requests which never leave the device,
+    /// like ones to a server on `localhost`.
+    pub fn allow_while_offline(mut self, allow: bool) -> Self {
+        self.allow_while_offline = allow;
+        self
+    }
+
+    /// Call `callback` with the number of bytes of the body sent so far,
+    /// and the total, as the body is uploaded. It's never called after
+    /// `send` returns, and a panic in it is logged rather than failing the
+    /// request.
+    ///
+    /// How often it's called depends on the backend. The reqwest backend
+    /// reports each chunk as it's sent (starting again from 0 if a redirect
+    /// means the body is sent again). The FFI backend can't see the upload,
+    /// so it only reports 0 before sending and the total once the response
+    /// arrives.
+    pub fn on_upload_progress(
+        mut self,
+        callback: impl Fn(u64, Option<u64>) + Send + Sync + 'static,
+    ) -> Self {
+        self.upload_progress = Some(ProgressHook::new(callback));
+        self
+    }
+
+    /// Like `on_upload_progress`, but for the response body. The total is
+    /// the response's Content-Length, if it has one. The FFI backend only
+    /// reports the whole body once it's arrived.
+    pub fn on_download_progress(
+        mut self,
+        callback: impl Fn(u64, Option<u64>) + Send + Sync + 'static,
+    ) -> Self {
+        self.download_progress = Some(ProgressHook::new(callback));
+        self
+    }
+
+    /// Fail with `Error::ResponseTooLarge` if the response body is bigger
+    /// than `bytes`, rather than the default of
+    /// `GLOBAL_SETTINGS.max_response_size`.
+    ///
+    /// The reqwest backend stops reading as soon as the limit is passed, or
+    /// before reading at all if the Content-Length is already too big. The
+    /// FFI backend only gets the body once the embedding has buffered all of
+    /// it, so there the limit only keeps it from going any further.
+    pub fn max_response_size(mut self, bytes: u64) -> Self {
+        self.max_response_size = Some(bytes);
+        self
+    }
+
+    /// Mark this request's body as holding secrets, like passwords, keys or
+    /// OAuth tokens, so that they don't turn up in memory dumps or captures.
+    ///
+    /// The FFI backend zeroes its copies of the body and the response once
+    /// it's done with them, rather than leaving them in freed memory. (The
+    /// reqwest backend can't, since reqwest doesn't.) The HAR capture and
+    /// cassettes record `[sensitive]` instead of the bodies, and the response
+    /// isn't cached. See also `Response::zeroize_on_drop`.
+    pub fn sensitive(mut self, sensitive: bool) -> Self {
+        self.sensitive = sensitive;
+        self
+    }
+
+    /// The limit `max_response_size` set, or the default.
+    pub fn response_size_limit(&self) -> u64 {
+        self.max_response_size
+            .unwrap_or(settings::GLOBAL_SETTINGS.max_response_size)
+    }
+
+    /// Have the backend follow redirects (up to `MAX_REDIRECTS` of them), or
+    /// return the redirect response as it is, rather than doing whatever
+    /// `GLOBAL_SETTINGS.follow_redirects` says.
+    pub fn follow_redirects(mut self, follow: bool) -> Self {
+        self.follow_redirects = Some(follow);
+        self
+    }
+
+    /// Whether the backend should follow redirects for this request, as set
+    /// by `follow_redirects`, or by default.
+    pub fn should_follow_redirects(&self) -> bool {
+        self.follow_redirects
+            .unwrap_or(settings::GLOBAL_SETTINGS.follow_redirects)
+    }
+
+    /// Set this request's body. `Get` and `Head` requests can't have one,
+    /// and sending them fails with `Error::BodyNotAllowed` if they do.
     pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
         self.body = Some(body.into());
         self
@@ -207,23 +420,48 @@ impl Request {
     /// this to fail hard with an easy to track down panic, than for e.g. `sync`
     /// to fail with a JSON parse error (which we'd probably attribute to
     /// corrupt data on the server, or something).
-    pub fn json<T: ?Sized + serde::Serialize>(mut self, val: &T) -> Self {
-        self.body =
-            Some(serde_json::to_vec(val).expect("Rust component bug: serde_json::to_vec failure"));
+    pub fn json<T: ?Sized + serde::Serialize>(self, val: &T) -> Self {
+        self.try_json(val)
+            .expect("Rust component bug: serde_json::to_vec failure")
+    }
+
+    /// Like [`Request::json`], but returns an [`Error::JsonError`] rather than
+    /// panicking if `val` can't be serialized.
+    pub fn try_json<T: ?Sized + serde::Serialize>(mut self, val: &T) -> Result<Self, Error> {
+        self.body = Some(json::to_vec(val)?);
         self.headers
             .insert_if_missing(header_names::CONTENT_TYPE, "application/json")
             .unwrap(); // We know this has to be valid.
-        self
+        Ok(self)
     }
 }
 
+/// The most redirects a backend follows for one request, when
+/// `Request::should_follow_redirects` says it should.
+pub const MAX_REDIRECTS: usize = 10;
+
+/// A redirect followed on the way to a [`Response`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct RedirectHop {
+    /// The status of the redirect response, such as `302`.
+    pub status: u16,
+    /// Where it sent us, resolved against the URL it came from.
+    pub location: Url,
+}
+
 /// A response from the server.
 #[derive(Clone, Debug, PartialEq)]
 pub struct Response {
     /// The method used to request this response.
     pub request_method: Method,
-    /// The URL of this response.
+    /// The URL of this response. Some backends report the URL which was
+    /// requested here, even after following redirects; see `final_url`.
     pub url: Url,
+    /// The URL this response actually came from, after any redirects.
+    pub final_url: Url,
+    /// The redirects followed to get here, in order, if the backend reports
+    /// them. Holds at most [`MAX_REDIRECTS`] hops.
+    pub redirects: Vec<RedirectHop>,
     /// The HTTP Status code of this response.
     pub status: u16,
     /// The headers returned with this response.
@@ -231,9 +469,20 @@ pub struct Response {
     /// The body of the response. Note that responses with binary bodies are
     /// currently unsupported.
     pub body: Vec<u8>,
+    /// True if the server responded with `304 Not Modified`, and this
+    /// response was served from the conditional request cache.
+    pub from_cache: bool,
+    /// The id of the request this responds to. See `Request::id`.
+    pub request_id: RequestId,
 }
 
 impl Response {
+    /// Zero the body when the response is dropped, for responses which
+    /// hold secrets.
+    pub fn zeroize_on_drop(self) -> ZeroizingResponse {
+        ZeroizingResponse::new(self)
+    }
+
     /// Parse the body as JSON.
     pub fn json<'a, T>(&'a self) -> Result<T, serde_json::Error>
     where
@@ -242,6 +491,20 @@ impl Response {
         serde_json::from_slice(&self.body)
     }
 
+    /// Parse the body as JSON, like [`Response::json`], but returning a
+    /// viaduct [`Error`]. Fails with [`Error::NotJson`] if the response has a
+    /// Content-Type which isn't JSON (a missing one is fine), and with
+    /// [`Error::JsonError`], which says where parsing failed, if the body is
+    /// malformed.
+    pub fn parse_json<T: serde::de::DeserializeOwned>(&self) -> Result<T, Error> {
+        if let Some(content_type) = self.headers.get(header_names::CONTENT_TYPE) {
+            if !json::is_json_content_type(content_type) {
+                return Err(Error::NotJson(content_type.to_owned()));
+            }
+        }
+        json::from_slice(&self.body)
+    }
+
     /// Get the body as a string. Assumes UTF-8 encoding. Any non-utf8 bytes
     /// are replaced with the replacement character.
     pub fn text(&self) -> std::borrow::Cow<'_, str> {
@@ -282,6 +545,21 @@ impl Response {
             })
         }
     }
+
+    /// Like [`Response::require_success`], but borrows the response, and
+    /// returns an [`Error::HttpStatus`] with the first [`MAX_BODY_SAMPLE`]
+    /// bytes of the body, which usually says what went wrong.
+    pub fn check_success(&self) -> Result<&Self, Error> {
+        if self.is_success() {
+            Ok(self)
+        } else {
+            Err(Error::HttpStatus {
+                code: self.status,
+                url: self.url.clone(),
+                body_sample: json::body_sample(&self.body),
+            })
+        }
+    }
 }
 
 /// A module containing constants for all HTTP status codes.
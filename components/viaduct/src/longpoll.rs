@@ -0,0 +1,456 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A helper for long-polling a server, for consumers which can't use push.
+//!
+//! A [`LongPoller`] repeatedly sends the same request, handing each
+//! successful response to a callback. It runs on whichever thread calls
+//! [`LongPoller::run`] (there's no async runtime involved), until its
+//! [`StopToken`] is stopped. Requests go through the usual backoff handling
+//! (see [`crate::current_backoffs`]), and failures are retried with an
+//! exponentially increasing delay.
+
+use crate::clock::{self, Clock};
+use crate::{header_names, Error, Request, Response};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// The delay before retrying after the first failure in a row, by default.
+pub const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// The longest delay between retries, by default.
+pub const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// Used to ask a [`LongPoller`] (or anything else waiting on it) to stop.
+/// Clones share the same state.
+///
+/// Stopping doesn't interrupt a request which is already in flight, but the
+/// poller won't wait or send another request afterwards.
+#[derive(Clone, Default)]
+pub struct StopToken {
+    inner: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl StopToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn stop(&self) {
+        let (stopped, condvar) = &*self.inner;
+        *stopped.lock().unwrap() = true;
+        condvar.notify_all();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        *self.inner.0.lock().unwrap()
+    }
+
+    /// Wait for `timeout`, returning early if the token is stopped. Returns
+    /// whether it was stopped.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let (stopped, condvar) = &*self.inner;
+        let clock = clock::current();
+        let deadline = clock.now() + timeout;
+        let mut stopped = stopped.lock().unwrap();
+        while !*stopped {
+            let now = clock.now();
+            if now >= deadline {
+                break;
+            }
+            stopped = clock.wait_timeout(condvar, stopped, deadline - now);
+        }
+        *stopped
+    }
+}
+
+/// Exponentially increasing delays between retries.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Option<Duration>,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            current: None,
+        }
+    }
+
+    /// The delay before retrying after another failure in a row.
+    pub fn next_delay(&mut self) -> Duration {
+        let next = match self.current {
+            Some(current) => (current * 2).min(self.max),
+            None => self.initial,
+        };
+        self.current = Some(next);
+        next
+    }
+
+    /// Start again from the initial delay, after a success.
+    pub fn reset(&mut self) {
+        self.current = None;
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(DEFAULT_INITIAL_BACKOFF, DEFAULT_MAX_BACKOFF)
+    }
+}
+
+/// Tells the server where we got up to, so that it only responds with what's
+/// new.
+pub trait Cursor: Send {
+    /// Add the cursor to the next request.
+    fn apply(&self, request: Request) -> Result<Request, Error>;
+
+    /// Move the cursor forward after a successful response.
+    fn update(&mut self, response: &Response);
+}
+
+/// A [`Cursor`] which echoes the `Last-Event-Id` header of the latest
+/// response which had one back to the server, in the style of server-sent
+/// events.
+#[derive(Debug, Clone, Default)]
+pub struct LastEventId {
+    id: Option<String>,
+}
+
+impl LastEventId {
+    /// Start from `id`, if we've polled before.
+    pub fn new(id: Option<String>) -> Self {
+        Self { id }
+    }
+
+    pub fn get(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+}
+
+impl Cursor for LastEventId {
+    fn apply(&self, request: Request) -> Result<Request, Error> {
+        match &self.id {
+            Some(id) => request.header(header_names::LAST_EVENT_ID, id.clone()),
+            None => Ok(request),
+        }
+    }
+
+    fn update(&mut self, response: &Response) {
+        if let Some(id) = response.headers.get(header_names::LAST_EVENT_ID) {
+            self.id = Some(id.to_owned());
+        }
+    }
+}
+
+pub struct LongPoller {
+    template: Request,
+    min_interval: Duration,
+    stop: StopToken,
+    cursor: Box<dyn Cursor>,
+    backoff: Backoff,
+}
+
+impl LongPoller {
+    /// Poll with copies of `template`. Successive requests start at least
+    /// `min_interval` apart, so a server which responds immediately doesn't
+    /// have us spinning.
+    pub fn new(template: Request, min_interval: Duration, stop: StopToken) -> Self {
+        Self {
+            template,
+            min_interval,
+            stop,
+            cursor: Box::new(LastEventId::default()),
+            backoff: Backoff::default(),
+        }
+    }
+
+    /// Use `cursor` instead of the default [`LastEventId`].
+    pub fn cursor(mut self, cursor: impl Cursor + 'static) -> Self {
+        self.cursor = Box::new(cursor);
+        self
+    }
+
+    /// Use `backoff` for the delays between retries after failures.
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Poll until the stop token is stopped, calling `on_response` with each
+    /// successful response, in order. Failed requests (including those with
+    /// unsuccessful statuses) are retried.
+    ///
+    /// This blocks the calling thread, and only fails if the cursor can't be
+    /// applied to the request, since retrying wouldn't help.
+    pub fn run(self, on_response: impl FnMut(Response)) -> Result<(), Error> {
+        let stop = self.stop.clone();
+        self.run_with(Request::send, |delay| stop.wait_timeout(delay), on_response)
+    }
+
+    // `run`, with the sending and waiting (which returns whether we were
+    // stopped) swapped out for tests.
+    fn run_with(
+        mut self,
+        mut send: impl FnMut(Request) -> Result<Response, Error>,
+        mut wait: impl FnMut(Duration) -> bool,
+        mut on_response: impl FnMut(Response),
+    ) -> Result<(), Error> {
+        let clock = clock::current();
+        while !self.stop.is_stopped() {
+            let started = clock.now();
+            // Each poll is a request of its own, with its own id.
+            let request = self.cursor.apply(self.template.clone().with_new_id())?;
+            let delay = match send(request) {
+                Ok(response) if response.is_success() => {
+                    self.backoff.reset();
+                    self.cursor.update(&response);
+                    on_response(response);
+                    let elapsed = clock.now().saturating_duration_since(started);
+                    self.min_interval.checked_sub(elapsed).unwrap_or_default()
+                }
+                Ok(response) => {
+                    log::warn!("Long poll failed with status {}", response.status);
+                    self.backoff.next_delay()
+                }
+                Err(e) => match e.inner() {
+                    Error::BackoffError { remaining } => {
+                        log::info!("Long poll backing off for {:?}", remaining);
+                        (*remaining).max(self.min_interval)
+                    }
+                    _ => {
+                        log::warn!("Long poll failed: {}", e);
+                        self.backoff.next_delay()
+                    }
+                },
+            };
+            if wait(delay) {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for LongPoller {
+    fn drop(&mut self) {
+        // We've kept the template for the whole time we were polling.
+        if self.template.sensitive {
+            if let Some(body) = &mut self.template.body {
+                crate::sensitive::zeroize(body);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::{Headers, Method};
+    use std::time::Instant;
+    use url::Url;
+
+    enum Scripted {
+        Events(&'static str),
+        Status(u16),
+        NetworkError,
+        ServerBackoff(Duration),
+    }
+
+    fn respond(request: &Request, status: u16, event_id: Option<&str>) -> Response {
+        let mut headers = Headers::new();
+        if let Some(id) = event_id {
+            headers
+                .insert(header_names::LAST_EVENT_ID, id.to_owned())
+                .unwrap();
+        }
+        Response {
+            request_method: request.method,
+            request_id: request.id(),
+            url: request.url.clone(),
+            final_url: request.url.clone(),
+            redirects: vec![],
+            status,
+            headers,
+            body: event_id.unwrap_or_default().as_bytes().to_vec(),
+            from_cache: false,
+        }
+    }
+
+    #[test]
+    fn test_long_poll() {
+        use Scripted::*;
+        let clock = ManualClock::install();
+        let ms = Duration::from_millis;
+        let mut script = vec![
+            Events("1"),
+            Status(500),
+            Status(503),
+            NetworkError,
+            Status(500),
+            Events("2"),
+            ServerBackoff(ms(2000)),
+            Events("3"),
+        ];
+        script.reverse();
+        let script = Mutex::new(script);
+
+        let mut sent = vec![];
+        let mut delays = vec![];
+        let mut received = vec![];
+        let poller = LongPoller::new(
+            Request::new(Method::Get, Url::parse("https://example.com/poll").unwrap()),
+            ms(50),
+            StopToken::new(),
+        )
+        .backoff(Backoff::new(ms(100), ms(400)));
+        poller
+            .run_with(
+                |request| {
+                    // Each request takes a little while.
+                    clock.advance(ms(10));
+                    sent.push(
+                        request
+                            .headers
+                            .get(header_names::LAST_EVENT_ID)
+                            .map(str::to_owned),
+                    );
+                    match script.lock().unwrap().pop().unwrap() {
+                        Events(id) => Ok(respond(&request, 200, Some(id))),
+                        Status(status) => Ok(respond(&request, status, None)),
+                        NetworkError => Err(Error::NetworkError("oops".into())),
+                        // As `Request::send` returns it.
+                        ServerBackoff(remaining) => {
+                            Err(Error::BackoffError { remaining }.with_request_id(request.id()))
+                        }
+                    }
+                },
+                |delay| {
+                    delays.push(delay);
+                    script.lock().unwrap().is_empty()
+                },
+                |response| received.push(String::from_utf8(response.body).unwrap()),
+            )
+            .unwrap();
+
+        assert_eq!(received, vec!["1", "2", "3"]);
+        let some = |id: &str| Some(id.to_owned());
+        assert_eq!(
+            sent,
+            vec![
+                None,
+                some("1"),
+                some("1"),
+                some("1"),
+                some("1"),
+                some("1"),
+                some("2"),
+                some("2")
+            ]
+        );
+        assert_eq!(delays.len(), 8);
+        // After a success, we wait out the rest of the interval.
+        for i in &[0, 5, 7] {
+            assert_eq!(delays[*i], ms(40), "{:?}", delays);
+        }
+        // Failures back off exponentially, up to the maximum, and the backoff
+        // resets after a success.
+        assert_eq!(delays[1..5], [ms(100), ms(200), ms(400), ms(400)]);
+        // Backoff requested by the server is honored.
+        assert_eq!(delays[6], ms(2000));
+    }
+
+    #[test]
+    fn test_stop() {
+        // This one needs real time to pass while the poller's waiting.
+        let _lock = crate::testing::lock();
+        let stop = StopToken::new();
+        let poller = LongPoller::new(
+            Request::new(Method::Get, Url::parse("https://example.com/poll").unwrap()),
+            Duration::from_secs(60),
+            stop.clone(),
+        );
+        let waiter = stop.clone();
+        let (waiting, is_waiting) = std::sync::mpsc::channel();
+        let thread = std::thread::spawn(move || {
+            let mut polls = 0;
+            poller
+                .run_with(
+                    |request| {
+                        polls += 1;
+                        Ok(respond(&request, 200, Some("1")))
+                    },
+                    |delay| {
+                        waiting.send(()).unwrap();
+                        waiter.wait_timeout(delay)
+                    },
+                    |_| {},
+                )
+                .unwrap();
+            polls
+        });
+        // Stop it once it's waiting out the interval after the first poll.
+        is_waiting.recv().unwrap();
+        let started = Instant::now();
+        stop.stop();
+        assert_eq!(thread.join().unwrap(), 1);
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // Stopped pollers don't send anything.
+        assert!(stop.wait_timeout(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_sensitive_template_zeroed() {
+        use crate::sensitive::testing::{assert_all_zero, take_zeroized};
+        let _clock = ManualClock::install();
+        let url = Url::parse("https://example.com/poll").unwrap();
+        take_zeroized();
+        let stop = StopToken::new();
+        let poller = LongPoller::new(
+            Request::post(url.clone()).body("hunter2").sensitive(true),
+            Duration::from_secs(1),
+            stop.clone(),
+        );
+        poller
+            .run_with(
+                |request| {
+                    stop.stop();
+                    Ok(respond(&request, 200, None))
+                },
+                |_| false,
+                |_| {},
+            )
+            .unwrap();
+        let zeroized = take_zeroized();
+        assert_eq!(zeroized.len(), 1);
+        assert_all_zero(&zeroized);
+
+        // Other templates are left alone.
+        drop(LongPoller::new(
+            Request::post(url).body("hello"),
+            Duration::from_secs(1),
+            StopToken::new(),
+        ));
+        assert!(take_zeroized().is_empty());
+    }
+
+    #[test]
+    fn test_wait_timeout() {
+        let clock = ManualClock::install();
+        let start = clock.now();
+        let stop = StopToken::new();
+        assert!(!stop.wait_timeout(Duration::from_secs(60)));
+        assert_eq!(clock.now() - start, Duration::from_secs(60));
+
+        // Once it's stopped, waiting returns straight away.
+        stop.stop();
+        assert!(stop.wait_timeout(Duration::from_secs(60)));
+        assert_eq!(clock.now() - start, Duration::from_secs(60));
+    }
+}
@@ -16,6 +16,8 @@ pub struct Request {
     pub connect_timeout_secs: i32,
     #[prost(int32, required, tag="8")]
     pub read_timeout_secs: i32,
+    #[prost(bool, optional, tag="9")]
+    pub url_normalized: ::std::option::Option<bool>,
 }
 pub mod request {
     #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
@@ -29,11 +31,12 @@ pub mod request {
         Connect = 5,
         Options = 6,
         Trace = 7,
+        Patch = 8,
     }
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Response {
-    /// If this is present, nothing else is.
+    /// If this is present, nothing else is, except possibly `exception_type`.
     #[prost(string, optional, tag="1")]
     pub exception_message: ::std::option::Option<std::string::String>,
     #[prost(string, optional, tag="2")]
@@ -44,4 +47,24 @@ pub struct Response {
     pub body: ::std::option::Option<std::vec::Vec<u8>>,
     #[prost(map="string, string", tag="5")]
     pub headers: ::std::collections::HashMap<std::string::String, std::string::String>,
+    /// The URL after any redirects, if it differs from `url`.
+    #[prost(string, optional, tag="6")]
+    pub final_url: ::std::option::Option<std::string::String>,
+    #[prost(enumeration="response::ExceptionType", optional, tag="7")]
+    pub exception_type: ::std::option::Option<i32>,
+}
+pub mod response {
+    /// What kind of failure `exception_message` describes. Backends which
+    /// can't tell leave this out, and it's treated as OTHER.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+    #[repr(i32)]
+    pub enum ExceptionType {
+        Other = 0,
+        NetworkUnreachable = 1,
+        Dns = 2,
+        Tls = 3,
+        Timeout = 4,
+        Cancelled = 5,
+        ConnectionRefused = 6,
+    }
 }
@@ -0,0 +1,129 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The device's connectivity, as reported by the embedding application.
+//!
+//! Mobile platforms know the device has gone offline long before a request
+//! would time out, so the application can tell us with
+//! [`set_network_status`], and requests then fail immediately with
+//! [`Error::Offline`] rather than each waiting for its timeout.
+
+use crate::{Error, Request};
+use serde_derive::Serialize;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[repr(u8)]
+pub enum NetworkStatus {
+    /// The default, until the application says otherwise.
+    Online = 0,
+    /// Requests fail with `Error::Offline` without being sent, unless they
+    /// were made with `Request::allow_while_offline`.
+    Offline = 1,
+    /// Connected, but using data may cost the user money. Requests are sent
+    /// as usual; consumers can check [`network_status`] and put off large
+    /// transfers.
+    Metered = 2,
+}
+
+impl NetworkStatus {
+    /// The status with this value, as passed over the FFI.
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(NetworkStatus::Online),
+            1 => Some(NetworkStatus::Offline),
+            2 => Some(NetworkStatus::Metered),
+            _ => None,
+        }
+    }
+}
+
+static NETWORK_STATUS: AtomicU8 = AtomicU8::new(NetworkStatus::Online as u8);
+
+/// Record the device's connectivity. This can be called from any thread,
+/// at any time; requests already in flight aren't affected.
+pub fn set_network_status(status: NetworkStatus) {
+    let old = NETWORK_STATUS.swap(status as u8, Ordering::SeqCst);
+    if old != status as u8 {
+        log::info!("Network status is now {:?}", status);
+    }
+}
+
+/// The status last set with [`set_network_status`].
+pub fn network_status() -> NetworkStatus {
+    // We only ever store valid values.
+    NetworkStatus::from_u8(NETWORK_STATUS.load(Ordering::SeqCst)).unwrap_or(NetworkStatus::Online)
+}
+
+/// Fails with `Error::Offline` if `request` shouldn't be sent, because the
+/// device is offline.
+pub(crate) fn check(request: &Request) -> Result<(), Error> {
+    if network_status() == NetworkStatus::Offline && !request.allow_while_offline {
+        return Err(Error::Offline(format!(
+            "The device is offline ({} {})",
+            request.method, request.url
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::GLOBAL_SETTINGS;
+    use std::time::Instant;
+
+    fn request() -> Request {
+        Request::get(url::Url::parse("https://www.example.com/").unwrap())
+    }
+
+    #[test]
+    fn test_offline() {
+        let _lock = crate::testing::lock();
+        set_network_status(NetworkStatus::Offline);
+        assert_eq!(network_status(), NetworkStatus::Offline);
+        assert_eq!(crate::backend_info().network_status, NetworkStatus::Offline);
+
+        let start = Instant::now();
+        let err = request().send().unwrap_err();
+        assert!(matches!(err.inner(), Error::Offline(_)), "{:?}", err);
+        // Without waiting for anything like a timeout.
+        let timeout = GLOBAL_SETTINGS.connect_timeout.unwrap();
+        assert!(start.elapsed() < timeout / 10);
+
+        // Requests which opt out get as far as the backend, whatever that
+        // does with them.
+        let result = request()
+            .allow_while_offline(true)
+            .send()
+            .map_err(Error::into_inner);
+        assert!(!matches!(result, Err(Error::Offline(_))), "{:?}", result);
+
+        set_network_status(NetworkStatus::Online);
+        let result = request().send().map_err(Error::into_inner);
+        assert!(!matches!(result, Err(Error::Offline(_))), "{:?}", result);
+    }
+
+    #[test]
+    fn test_metered() {
+        let _lock = crate::testing::lock();
+        set_network_status(NetworkStatus::Metered);
+        assert_eq!(crate::backend_info().network_status, NetworkStatus::Metered);
+        let result = request().send().map_err(Error::into_inner);
+        assert!(!matches!(result, Err(Error::Offline(_))), "{:?}", result);
+        set_network_status(NetworkStatus::Online);
+    }
+
+    #[test]
+    fn test_from_u8() {
+        for status in &[
+            NetworkStatus::Online,
+            NetworkStatus::Offline,
+            NetworkStatus::Metered,
+        ] {
+            assert_eq!(NetworkStatus::from_u8(*status as u8), Some(*status));
+        }
+        assert_eq!(NetworkStatus::from_u8(3), None);
+    }
+}
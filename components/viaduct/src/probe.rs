@@ -0,0 +1,227 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A connectivity probe, intended to be triggered from an app's debug menu
+//! when diagnosing networking problems.
+
+use crate::backend::{get_backend, send_with};
+use crate::{header_names, status_codes, Backend, Error, Method, Request, Response};
+use serde_derive::Serialize;
+use std::time::Instant;
+use url::Url;
+
+/// The most redirects `probe` will follow. Probes ask the backend not to
+/// follow redirects itself, so that each hop is sent (and checked) like the
+/// first.
+pub const MAX_PROBE_REDIRECTS: usize = 2;
+
+/// The outcome of a successful [`probe`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ProbeResult {
+    /// The URL we ended up at, after any redirects.
+    pub url: String,
+    /// The method of the final request, `HEAD` unless the server refused it.
+    pub method: &'static str,
+    pub status: u16,
+    /// The number of redirects followed.
+    pub redirects: usize,
+    /// The name of the backend used, as in [`crate::BackendInfo`].
+    pub backend: &'static str,
+    /// Total time taken, including any redirects, in milliseconds.
+    pub total_ms: u64,
+}
+
+/// Check whether `url` is reachable, by sending it a `HEAD` request (or a
+/// one-byte ranged `GET`, for servers which don't allow `HEAD`).
+///
+/// Probe requests never carry credentials and never use the conditional
+/// request cache, but otherwise go through the same checks as any other
+/// request, so they're refused during a [`shutdown`](crate::shutdown), or
+/// while the server's asked us to back off. They're sent even if
+/// [`set_network_status`](crate::set_network_status) says the device is
+/// offline, so they can check whether it really is.
+pub fn probe(url: Url) -> Result<ProbeResult, Error> {
+    probe_with_backend(url, get_backend()?)
+}
+
+fn probe_with_backend(mut url: Url, backend: &dyn Backend) -> Result<ProbeResult, Error> {
+    let start = Instant::now();
+    let mut redirects = 0;
+    loop {
+        let mut method = Method::Head;
+        let mut response = send_probe(Request::new(Method::Head, url.clone()), backend)?;
+        if response.status == status_codes::METHOD_NOT_ALLOWED
+            || response.status == status_codes::NOT_IMPLEMENTED
+        {
+            method = Method::Get;
+            let request = Request::get(url.clone()).header(header_names::RANGE, "bytes=0-0")?;
+            response = send_probe(request, backend)?;
+        }
+        match redirect_target(&response)? {
+            Some(target) if redirects < MAX_PROBE_REDIRECTS => {
+                redirects += 1;
+                url = target;
+            }
+            _ => {
+                return Ok(ProbeResult {
+                    url: response.url.to_string(),
+                    method: method.as_str(),
+                    status: response.status,
+                    redirects,
+                    backend: backend.name(),
+                    total_ms: start.elapsed().as_millis() as u64,
+                })
+            }
+        }
+    }
+}
+
+fn send_probe(request: Request, backend: &dyn Backend) -> Result<Response, Error> {
+    let request = request.allow_while_offline(true).follow_redirects(false);
+    send_with(request, || Ok(backend))
+}
+
+fn redirect_target(response: &Response) -> Result<Option<Url>, Error> {
+    if !(300..400).contains(&response.status) || response.status == status_codes::NOT_MODIFIED {
+        return Ok(None);
+    }
+    Ok(match response.headers.get(header_names::LOCATION) {
+        Some(location) => Some(response.url.join(location)?),
+        None => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::stub::StubResponse;
+    use crate::testing::TestBackend;
+    use crate::NetworkStatus;
+
+    // Respond to the next request with `status`, redirecting to `location`
+    // if there is one.
+    fn script(backend: &TestBackend, status: u16, location: Option<&str>) {
+        let mut response = StubResponse::new(status);
+        if let Some(location) = location {
+            response = response.header(header_names::LOCATION, location);
+        }
+        backend.respond(response);
+    }
+
+    fn url(s: &str) -> Url {
+        Url::parse(s).unwrap()
+    }
+
+    #[test]
+    fn test_probe_head() {
+        let _lock = crate::testing::lock();
+        let backend = TestBackend::default();
+        script(&backend, 200, None);
+        let result = probe_with_backend(url("https://www.example.com/"), &backend).unwrap();
+        assert_eq!(result.url, "https://www.example.com/");
+        assert_eq!(result.method, "HEAD");
+        assert_eq!(result.status, 200);
+        assert_eq!(result.redirects, 0);
+        assert_eq!(result.backend, "test");
+
+        let json = serde_json::to_value(&result).unwrap();
+        for key in &[
+            "url",
+            "method",
+            "status",
+            "redirects",
+            "backend",
+            "total_ms",
+        ] {
+            assert!(json.get(key).is_some(), "missing {}", key);
+        }
+
+        let requests = backend.requests();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0]
+            .headers
+            .get(header_names::AUTHORIZATION)
+            .is_none());
+    }
+
+    #[test]
+    fn test_probe_get_fallback() {
+        let _lock = crate::testing::lock();
+        let backend = TestBackend::default();
+        script(&backend, 405, None);
+        script(&backend, 206, None);
+        let result = probe_with_backend(url("https://www.example.com/"), &backend).unwrap();
+        assert_eq!(result.method, "GET");
+        assert_eq!(result.status, 206);
+        let requests = backend.requests();
+        assert_eq!(
+            requests[1].headers.get(header_names::RANGE),
+            Some("bytes=0-0")
+        );
+        for request in requests.iter() {
+            assert!(request.headers.get(header_names::AUTHORIZATION).is_none());
+        }
+    }
+
+    #[test]
+    fn test_probe_redirect_limit() {
+        let _lock = crate::testing::lock();
+        let backend = TestBackend::default();
+        script(&backend, 302, Some("/one"));
+        script(&backend, 302, Some("https://www.example.org/two"));
+        script(&backend, 302, Some("/three"));
+        let result = probe_with_backend(url("https://www.example.com/"), &backend).unwrap();
+        assert_eq!(result.redirects, MAX_PROBE_REDIRECTS);
+        assert_eq!(result.status, 302);
+        assert_eq!(result.url, "https://www.example.org/two");
+        // The backend's asked to leave the redirects to us, so that the
+        // limit applies.
+        let requests = backend.requests();
+        assert_eq!(requests.len(), 3);
+        for request in requests.iter() {
+            assert!(!request.should_follow_redirects());
+        }
+    }
+
+    #[test]
+    fn test_probe_url_policy() {
+        let _lock = crate::testing::lock();
+        let backend = TestBackend::default();
+        script(&backend, 301, Some("http://www.example.com/"));
+        assert!(matches!(
+            probe_with_backend(url("http://www.example.com/"), &backend).map_err(Error::into_inner),
+            Err(Error::NonTlsUrl)
+        ));
+        // Redirects are held to the same policy.
+        assert!(matches!(
+            probe_with_backend(url("https://www.example.com/"), &backend)
+                .map_err(Error::into_inner),
+            Err(Error::NonTlsUrl)
+        ));
+    }
+
+    #[test]
+    fn test_probe_checks() {
+        let _lock = crate::testing::lock();
+        let backend = TestBackend::default();
+
+        // Probes are sent while we think we're offline...
+        crate::set_network_status(NetworkStatus::Offline);
+        let result = probe_with_backend(url("https://probe.example.com/"), &backend);
+        crate::set_network_status(NetworkStatus::Online);
+        assert_eq!(result.unwrap().status, 200);
+
+        // ...but not while the server's asked us to back off.
+        backend.respond(StubResponse::new(503).header(header_names::RETRY_AFTER, "30"));
+        let result = probe_with_backend(url("https://probe.example.com/"), &backend).unwrap();
+        assert_eq!(result.status, 503);
+        let result = probe_with_backend(url("https://probe.example.com/"), &backend);
+        crate::clear_backoffs();
+        assert!(matches!(
+            result.map_err(Error::into_inner),
+            Err(Error::BackoffError { .. })
+        ));
+        assert_eq!(backend.requests().len(), 2);
+    }
+}
@@ -0,0 +1,169 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Progress callbacks for request and response bodies. See
+//! `Request::on_upload_progress` and `Request::on_download_progress`.
+//!
+//! Backends report progress through the hooks on the request they're sending.
+//! `send` stops the hooks for that request when it returns, so the callbacks
+//! are never called afterwards, even if a backend is still reading the body
+//! on another thread.
+
+use std::io::Read;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+
+type Callback = dyn Fn(u64, Option<u64>) + Send + Sync;
+
+/// A progress callback attached to a request.
+#[derive(Clone)]
+pub struct ProgressHook {
+    callback: Arc<Callback>,
+    // False once the send this hook belongs to has returned. It's locked
+    // while the callback runs, so that `finish` waits for a call in progress.
+    // Each hook has its own, so a slow callback only holds up its request.
+    active: Arc<Mutex<bool>>,
+}
+
+impl ProgressHook {
+    pub(crate) fn new(callback: impl Fn(u64, Option<u64>) + Send + Sync + 'static) -> Self {
+        Self {
+            callback: Arc::new(callback),
+            active: Arc::new(Mutex::new(true)),
+        }
+    }
+
+    /// A copy of this hook for one `send`, which can be stopped without
+    /// stopping it for other copies of the request.
+    pub(crate) fn for_send(&self) -> Self {
+        Self {
+            callback: self.callback.clone(),
+            active: Arc::new(Mutex::new(true)),
+        }
+    }
+
+    /// Report that `done` bytes, out of `total` if it's known, have been sent
+    /// or received. Does nothing once the request's `send` has returned. If
+    /// the callback panics, the panic is logged and otherwise ignored.
+    pub fn report(&self, done: u64, total: Option<u64>) {
+        let active = self.active.lock().unwrap_or_else(|e| e.into_inner());
+        if !*active {
+            return;
+        }
+        if catch_unwind(AssertUnwindSafe(|| (self.callback)(done, total))).is_err() {
+            log::warn!("Progress callback panicked, ignoring it");
+        }
+    }
+
+    /// Stop calling the callback, waiting for a call in progress to finish.
+    pub(crate) fn finish(&self) {
+        *self.active.lock().unwrap_or_else(|e| e.into_inner()) = false;
+    }
+}
+
+impl std::fmt::Debug for ProgressHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ProgressHook")
+    }
+}
+
+impl PartialEq for ProgressHook {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.callback, &other.callback)
+    }
+}
+
+/// Wraps a reader, reporting how much has been read from it to a hook. For
+/// backends which read or write bodies as a stream.
+pub struct ProgressReader<R> {
+    inner: R,
+    hook: ProgressHook,
+    done: u64,
+    total: Option<u64>,
+}
+
+impl<R: Read> ProgressReader<R> {
+    pub fn new(inner: R, hook: ProgressHook, total: Option<u64>) -> Self {
+        Self {
+            inner,
+            hook,
+            done: 0,
+            total,
+        }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n > 0 {
+            self.done += n as u64;
+            self.hook.report(self.done, self.total);
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The `(done, total)` pairs the hook was called with.
+    type Calls = Arc<Mutex<Vec<(u64, Option<u64>)>>>;
+
+    fn recording_hook() -> (ProgressHook, Calls) {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let recorded = calls.clone();
+        let hook = ProgressHook::new(move |done, total| {
+            recorded.lock().unwrap().push((done, total));
+        });
+        (hook, calls)
+    }
+
+    #[test]
+    fn test_reader() {
+        let (hook, calls) = recording_hook();
+        let data = vec![7u8; 10_000];
+        let mut reader = ProgressReader::new(&data[..], hook, Some(10_000));
+        let mut buf = [0u8; 4096];
+        let mut read = vec![];
+        loop {
+            match reader.read(&mut buf).unwrap() {
+                0 => break,
+                n => read.extend_from_slice(&buf[..n]),
+            }
+        }
+        assert_eq!(read, data);
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![
+                (4096, Some(10_000)),
+                (8192, Some(10_000)),
+                (10_000, Some(10_000))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_finish() {
+        let (hook, calls) = recording_hook();
+        let sending = hook.for_send();
+        sending.report(1, None);
+        sending.finish();
+        sending.report(2, None);
+        // Other copies of the request aren't affected.
+        hook.report(3, None);
+        assert_eq!(*calls.lock().unwrap(), vec![(1, None), (3, None)]);
+        assert_eq!(hook, sending);
+    }
+
+    #[test]
+    fn test_panicking_callback() {
+        let hook = ProgressHook::new(|_, _| panic!("oops"));
+        hook.report(1, Some(2));
+        // Still usable afterwards.
+        hook.report(2, Some(2));
+        hook.finish();
+    }
+}
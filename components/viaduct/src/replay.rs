@@ -0,0 +1,590 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Recording HTTP traffic to a "cassette" file, and replaying it later.
+//!
+//! This is intended for tests and for debugging protocol issues offline, and
+//! is only built with the `replay` feature. [`record_to`] wraps a real
+//! backend, appending every request and its response to the cassette as a
+//! line of JSON, and [`load_cassette`] installs a backend which answers
+//! requests from a cassette instead of the network.
+//!
+//! Credentials in headers are redacted (see [`REDACTED_HEADERS`]), but
+//! bodies are recorded as they are, and may well contain secrets (token
+//! server responses include keys, for example). Treat cassettes as
+//! sensitive. The exception is [sensitive] requests, whose bodies (and
+//! their responses' bodies) are recorded as `[sensitive]`. When replayed,
+//! these match any body, and respond with `[sensitive]`.
+//!
+//! [sensitive]: crate::Request::sensitive
+
+use crate::{Backend, Error, Header, HeaderName, Headers, RedirectHop, Request, Response};
+use serde_derive::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use url::Url;
+
+pub use crate::REDACTED_HEADERS;
+
+/// A body in a cassette, stored as a string if it's valid UTF-8 so that
+/// cassettes stay readable.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Body {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl Body {
+    fn new(bytes: Vec<u8>) -> Self {
+        match String::from_utf8(bytes) {
+            Ok(text) => Body::Text(text),
+            Err(e) => Body::Binary(e.into_bytes()),
+        }
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Body::Text(text) => text.into_bytes(),
+            Body::Binary(bytes) => bytes,
+        }
+    }
+}
+
+/// A redirect followed on the way to a recorded response.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedRedirect {
+    pub status: u16,
+    pub location: String,
+}
+
+/// One request and the response to it, as stored in a cassette.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Interaction {
+    pub method: String,
+    pub url: String,
+    pub request_headers: Vec<(String, String)>,
+    /// A hash of the request body, if there was one. This is only used to
+    /// tell requests apart, and isn't cryptographically strong, so it's
+    /// `[sensitive]` for sensitive requests.
+    pub request_body_hash: Option<String>,
+    /// The URL of the response, which differs from `url` after redirects.
+    pub response_url: String,
+    /// The redirects followed to get to the response. Cassettes recorded
+    /// before these were tracked don't have any.
+    #[serde(default)]
+    pub redirects: Vec<RecordedRedirect>,
+    pub status: u16,
+    pub response_headers: Vec<(String, String)>,
+    pub response_body: Body,
+}
+
+// Cassettes can be written by hand, so recorded URLs are normalized the same
+// way as requests before they're compared.
+fn same_url(recorded: &str, url: &Url) -> bool {
+    match Url::parse(recorded) {
+        Ok(recorded) => crate::backend::normalize_url(&recorded).ok().as_ref() == Some(url),
+        Err(_) => false,
+    }
+}
+
+impl Interaction {
+    fn matches(&self, request: &Request, match_body: bool) -> bool {
+        self.method == request.method.as_str()
+            && same_url(&self.url, &request.url)
+            && (!match_body
+                || self.request_body_hash.as_deref() == Some(crate::sensitive::PLACEHOLDER)
+                || self.request_body_hash == body_hash(request))
+    }
+
+    fn into_response(self, request: &Request) -> Result<Response, Error> {
+        let mut headers = Headers::with_capacity(self.response_headers.len());
+        for (name, value) in self.response_headers {
+            let name = HeaderName::new(name)
+                .map_err(|e| Error::ReplayError(format!("Invalid recorded header: {}", e)))?;
+            headers.append_header(Header::new_unchecked(name, value));
+        }
+        let redirects = self
+            .redirects
+            .into_iter()
+            .map(|hop| {
+                Ok(RedirectHop {
+                    status: hop.status,
+                    location: Url::parse(&hop.location)?,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+        let url = Url::parse(&self.response_url)?;
+        Ok(Response {
+            request_method: request.method,
+            request_id: request.id(),
+            url: url.clone(),
+            final_url: url,
+            redirects,
+            status: self.status,
+            headers,
+            body: self.response_body.into_bytes(),
+            from_cache: false,
+        })
+    }
+}
+
+// 64-bit FNV-1a, which is simple and stable across Rust versions (unlike
+// `DefaultHasher`), so cassettes can be replayed by later builds.
+fn body_hash(request: &Request) -> Option<String> {
+    let body = request.body.as_ref()?;
+    let hash = body.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    Some(format!("{:016x}", hash))
+}
+
+/// Sends requests through another backend, appending each request and its
+/// response to a cassette. Requests which fail (rather than getting an
+/// unsuccessful response) aren't recorded.
+pub struct RecordingBackend {
+    inner: &'static dyn Backend,
+    cassette: Mutex<File>,
+}
+
+impl RecordingBackend {
+    /// Record to the cassette at `path`, appending if it already exists.
+    pub fn new(inner: &'static dyn Backend, path: impl AsRef<Path>) -> Result<Self, Error> {
+        let cassette = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| Error::ReplayError(format!("Failed to open cassette: {}", e)))?;
+        Ok(Self {
+            inner,
+            cassette: Mutex::new(cassette),
+        })
+    }
+}
+
+impl Backend for RecordingBackend {
+    fn send(&self, request: Request) -> Result<Response, Error> {
+        let method = request.method.as_str().to_owned();
+        let url = request.url.to_string();
+        let request_headers = request.headers.redacted();
+        let sensitive = request.sensitive;
+        let request_body_hash = if sensitive && request.body.is_some() {
+            Some(crate::sensitive::PLACEHOLDER.to_owned())
+        } else {
+            body_hash(&request)
+        };
+        let response = self.inner.send(request)?;
+        let interaction = Interaction {
+            method,
+            url,
+            request_headers,
+            request_body_hash,
+            response_url: response.final_url.to_string(),
+            redirects: response
+                .redirects
+                .iter()
+                .map(|hop| RecordedRedirect {
+                    status: hop.status,
+                    location: hop.location.to_string(),
+                })
+                .collect(),
+            status: response.status,
+            response_headers: response.headers.redacted(),
+            response_body: if sensitive {
+                Body::Text(crate::sensitive::PLACEHOLDER.to_owned())
+            } else {
+                Body::new(response.body.clone())
+            },
+        };
+        let mut line = serde_json::to_string(&interaction)
+            .map_err(|e| Error::ReplayError(format!("Failed to serialize request: {}", e)))?;
+        line.push('\n');
+        self.cassette
+            .lock()
+            .unwrap()
+            .write_all(line.as_bytes())
+            .map_err(|e| Error::ReplayError(format!("Failed to write cassette: {}", e)))?;
+        Ok(response)
+    }
+
+    fn name(&self) -> &'static str {
+        "recording"
+    }
+
+    fn supports_tls_config(&self) -> bool {
+        self.inner.supports_tls_config()
+    }
+}
+
+/// How a [`ReplayBackend`] finds the recording for a request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strictness {
+    /// Requests must be made in the order they were recorded.
+    InOrder,
+    /// Each request gets the first unused recording of the same request.
+    AnyOrder,
+}
+
+/// Answers requests from a cassette. Each recording is only used once.
+///
+/// Requests are matched by method and URL, and optionally by body (see
+/// [`ReplayBackend::match_body`]). Requests without a matching recording
+/// fail with [`Error::ReplayError`].
+pub struct ReplayBackend {
+    // Recordings are taken out as they're used.
+    interactions: Mutex<Vec<Option<Interaction>>>,
+    strictness: Strictness,
+    match_body: bool,
+}
+
+impl ReplayBackend {
+    pub fn new(interactions: Vec<Interaction>, strictness: Strictness) -> Self {
+        Self {
+            interactions: Mutex::new(interactions.into_iter().map(Some).collect()),
+            strictness,
+            match_body: false,
+        }
+    }
+
+    /// Load the cassette at `path`.
+    pub fn from_file(path: impl AsRef<Path>, strictness: Strictness) -> Result<Self, Error> {
+        let file = File::open(path)
+            .map_err(|e| Error::ReplayError(format!("Failed to open cassette: {}", e)))?;
+        let mut interactions = vec![];
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line =
+                line.map_err(|e| Error::ReplayError(format!("Failed to read cassette: {}", e)))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            interactions.push(serde_json::from_str(&line).map_err(|e| {
+                Error::ReplayError(format!("Invalid cassette entry on line {}: {}", i + 1, e))
+            })?);
+        }
+        Ok(Self::new(interactions, strictness))
+    }
+
+    /// Whether requests must also have the same body as the recording. Off
+    /// by default, since bodies often include timestamps or random IVs.
+    pub fn match_body(mut self, match_body: bool) -> Self {
+        self.match_body = match_body;
+        self
+    }
+
+    /// The number of recordings which haven't been used yet.
+    pub fn remaining(&self) -> usize {
+        self.interactions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|i| i.is_some())
+            .count()
+    }
+}
+
+impl Backend for ReplayBackend {
+    fn send(&self, request: Request) -> Result<Response, Error> {
+        let mut interactions = self.interactions.lock().unwrap();
+        let found = match self.strictness {
+            Strictness::InOrder => match interactions.iter().position(Option::is_some) {
+                Some(next) => {
+                    let expected = interactions[next].as_ref().unwrap();
+                    if expected.matches(&request, self.match_body) {
+                        Ok(next)
+                    } else {
+                        Err(format!(
+                            "; expected {} {} next",
+                            expected.method, expected.url
+                        ))
+                    }
+                }
+                None => Err("; every recording has been used".to_owned()),
+            },
+            Strictness::AnyOrder => interactions
+                .iter()
+                .position(|i| matches!(i, Some(i) if i.matches(&request, self.match_body)))
+                .ok_or_else(String::new),
+        };
+        match found {
+            Ok(index) => interactions[index].take().unwrap().into_response(&request),
+            Err(context) => Err(Error::ReplayError(format!(
+                "No recorded response for {} {}{}",
+                request.method.as_str(),
+                request.url,
+                context
+            ))),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "replay"
+    }
+}
+
+/// Record all requests to the cassette at `path`, sending them through
+/// `inner`. Like [`set_backend`](crate::set_backend), this fails if a
+/// backend has already been set.
+#[track_caller]
+pub fn record_to(path: impl AsRef<Path>, inner: &'static dyn Backend) -> Result<(), Error> {
+    crate::set_backend(Box::leak(Box::new(RecordingBackend::new(inner, path)?)))
+}
+
+/// Answer all requests from the cassette at `path`, instead of the network.
+/// Like [`set_backend`](crate::set_backend), this fails if a backend has
+/// already been set.
+#[track_caller]
+pub fn load_cassette(path: impl AsRef<Path>, strictness: Strictness) -> Result<(), Error> {
+    crate::set_backend(Box::leak(Box::new(ReplayBackend::from_file(
+        path, strictness,
+    )?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::stub::StubResponse;
+    use crate::headers::REDACTED;
+    use crate::testing::TestBackend;
+    use crate::{header_names, Method};
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // A response with a body describing `request`, and some headers which
+    // need redacting.
+    fn describe(request: &Request) -> StubResponse {
+        let mut body = format!("{} {}", request.method.as_str(), request.url).into_bytes();
+        // Not valid UTF-8.
+        body.extend_from_slice(&[0, 0xff, 0xfe]);
+        let status = if request.method == Method::Post {
+            201
+        } else {
+            200
+        };
+        StubResponse::new(status)
+            .header(header_names::CONTENT_TYPE, "application/octet-stream")
+            .header("set-cookie", "session=secret")
+            .body(body)
+    }
+
+    struct TempCassette(PathBuf);
+
+    impl TempCassette {
+        fn new() -> Self {
+            static COUNTER: AtomicUsize = AtomicUsize::new(0);
+            TempCassette(std::env::temp_dir().join(format!(
+                "viaduct-cassette-{}-{}.jsonl",
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::SeqCst)
+            )))
+        }
+    }
+
+    impl Drop for TempCassette {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn get(url: &str) -> Request {
+        Request::get(Url::parse(url).unwrap())
+            .header(header_names::AUTHORIZATION, "Bearer secret")
+            .unwrap()
+    }
+
+    fn post(url: &str, body: &str) -> Request {
+        Request::post(Url::parse(url).unwrap()).body(body)
+    }
+
+    fn requests() -> Vec<Request> {
+        vec![
+            get("https://example.com/one"),
+            post("https://example.com/two", "some data"),
+            get("https://example.com/three"),
+        ]
+    }
+
+    // Sends `requests` through a recording backend, each getting the
+    // response `describe` gives it.
+    fn record_requests(cassette: &TempCassette, requests: Vec<Request>) -> Vec<Response> {
+        let inner: &'static TestBackend = Box::leak(Box::default());
+        let backend = RecordingBackend::new(inner, &cassette.0).unwrap();
+        requests
+            .into_iter()
+            .map(|request| {
+                inner.respond(describe(&request));
+                backend.send(request).unwrap()
+            })
+            .collect()
+    }
+
+    fn record(cassette: &TempCassette) -> Vec<Response> {
+        record_requests(cassette, requests())
+    }
+
+    #[test]
+    fn test_record_redacts() {
+        let cassette = TempCassette::new();
+        record(&cassette);
+        let contents = std::fs::read_to_string(&cassette.0).unwrap();
+        assert_eq!(contents.lines().count(), 3);
+        assert!(!contents.contains("secret"));
+        assert!(contents.contains(REDACTED));
+    }
+
+    #[test]
+    fn test_replay_in_order() {
+        let cassette = TempCassette::new();
+        let recorded = record(&cassette);
+        let replay = ReplayBackend::from_file(&cassette.0, Strictness::InOrder).unwrap();
+        for (request, recorded) in requests().into_iter().zip(recorded) {
+            let replayed = replay.send(request).unwrap();
+            assert_eq!(replayed.status, recorded.status);
+            assert_eq!(replayed.url, recorded.url);
+            assert_eq!(replayed.body, recorded.body);
+            assert_eq!(
+                replayed.headers.get(header_names::CONTENT_TYPE),
+                Some("application/octet-stream")
+            );
+            assert_eq!(replayed.headers.get("set-cookie"), Some(REDACTED));
+        }
+        assert_eq!(replay.remaining(), 0);
+
+        // Each recording is only used once.
+        match replay.send(get("https://example.com/one")) {
+            Err(Error::ReplayError(msg)) => {
+                assert!(msg.contains("GET https://example.com/one"), "{}", msg);
+                assert!(msg.contains("every recording has been used"), "{}", msg);
+            }
+            other => panic!("Expected a replay error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_replay_out_of_order() {
+        let cassette = TempCassette::new();
+        record(&cassette);
+
+        let replay = ReplayBackend::from_file(&cassette.0, Strictness::InOrder).unwrap();
+        match replay.send(get("https://example.com/three")) {
+            Err(Error::ReplayError(msg)) => assert_eq!(
+                msg,
+                "No recorded response for GET https://example.com/three; \
+                 expected GET https://example.com/one next"
+            ),
+            other => panic!("Expected a replay error, got {:?}", other),
+        }
+
+        let replay = ReplayBackend::from_file(&cassette.0, Strictness::AnyOrder).unwrap();
+        let response = replay.send(get("https://example.com/three")).unwrap();
+        assert!(response.text().starts_with("GET https://example.com/three"));
+        assert_eq!(replay.remaining(), 2);
+        match replay.send(get("https://example.com/unrecorded")) {
+            Err(Error::ReplayError(msg)) => assert_eq!(
+                msg,
+                "No recorded response for GET https://example.com/unrecorded"
+            ),
+            other => panic!("Expected a replay error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_replay_match_body() {
+        let cassette = TempCassette::new();
+        record(&cassette);
+
+        let replay = ReplayBackend::from_file(&cassette.0, Strictness::AnyOrder).unwrap();
+        let response = replay
+            .send(post("https://example.com/two", "other data"))
+            .unwrap();
+        assert_eq!(response.status, 201);
+
+        let replay = ReplayBackend::from_file(&cassette.0, Strictness::AnyOrder)
+            .unwrap()
+            .match_body(true);
+        assert!(replay
+            .send(post("https://example.com/two", "other data"))
+            .is_err());
+        assert_eq!(
+            replay
+                .send(post("https://example.com/two", "some data"))
+                .unwrap()
+                .status,
+            201
+        );
+    }
+
+    #[test]
+    fn test_record_sensitive() {
+        let cassette = TempCassette::new();
+        let response = record_requests(
+            &cassette,
+            vec![post("https://example.com/token", "hunter2").sensitive(true)],
+        )
+        .remove(0);
+        // The caller still gets the real response.
+        assert!(response
+            .text()
+            .starts_with("POST https://example.com/token"));
+        let contents = std::fs::read_to_string(&cassette.0).unwrap();
+        assert!(!contents.contains("hunter2"), "{}", contents);
+        assert!(!contents.contains("POST https"), "{}", contents);
+
+        let replay = ReplayBackend::from_file(&cassette.0, Strictness::InOrder)
+            .unwrap()
+            .match_body(true);
+        let replayed = replay
+            .send(post("https://example.com/token", "hunter3").sensitive(true))
+            .unwrap();
+        assert_eq!(replayed.status, 201);
+        assert_eq!(replayed.text(), "[sensitive]");
+    }
+
+    #[test]
+    fn test_replay_redirects() {
+        // Cassettes can be written by hand to script a redirect chain.
+        let line = r#"{"method":"GET","url":"https://example.com/old","request_headers":[],
+            "request_body_hash":null,"response_url":"https://www.example.com/new",
+            "redirects":[{"status":301,"location":"https://www.example.com/moved"},
+                         {"status":302,"location":"https://www.example.com/new"}],
+            "status":200,"response_headers":[],"response_body":"ok"}"#;
+        let interaction: Interaction = serde_json::from_str(line).unwrap();
+        let replay = ReplayBackend::new(vec![interaction], Strictness::InOrder);
+        let response = replay.send(get("https://example.com/old")).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.final_url.as_str(), "https://www.example.com/new");
+        assert_eq!(
+            response.redirects,
+            vec![
+                RedirectHop {
+                    status: 301,
+                    location: Url::parse("https://www.example.com/moved").unwrap(),
+                },
+                RedirectHop {
+                    status: 302,
+                    location: Url::parse("https://www.example.com/new").unwrap(),
+                },
+            ]
+        );
+
+        // Cassettes recorded before redirects were tracked still load.
+        let line = r#"{"method":"GET","url":"https://example.com/old","request_headers":[],
+            "request_body_hash":null,"response_url":"https://example.com/old",
+            "status":200,"response_headers":[],"response_body":"ok"}"#;
+        let interaction: Interaction = serde_json::from_str(line).unwrap();
+        assert!(interaction.redirects.is_empty());
+    }
+
+    #[test]
+    fn test_replay_normalizes_recorded_urls() {
+        let line = r#"{"method":"GET","url":"HTTPS://Example.COM:443/a b","request_headers":[],
+            "request_body_hash":null,"response_url":"https://example.com/a%20b",
+            "status":200,"response_headers":[],"response_body":"ok"}"#;
+        let interaction: Interaction = serde_json::from_str(line).unwrap();
+        let replay = ReplayBackend::new(vec![interaction], Strictness::InOrder);
+        let response = replay.send(get("https://example.com/a%20b")).unwrap();
+        assert_eq!(response.status, 200);
+    }
+}
@@ -0,0 +1,136 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Ids for matching up a request in our logs with the server's.
+//!
+//! Every [`Request`] gets a random id when it's constructed, which clones of
+//! it share. The [`Response`](crate::Response) to it carries the same id, and
+//! errors from sending it are wrapped in [`Error::RequestFailed`] with the
+//! id. After [`set_send_request_id_header`]`(true)`, the id is also sent to
+//! the server as an `X-Request-Id` header. That's off by default, since some
+//! servers reject headers they don't know.
+
+use crate::{header_names, Request};
+use once_cell::sync::Lazy;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// A request's id. It's formatted like a version 4 UUID, but only needs to
+/// be unique enough to find the request in the server's logs, so it's made
+/// by hashing a counter with a per-process random key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RequestId(u128);
+
+static KEY: Lazy<RandomState> = Lazy::new(RandomState::new);
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+impl RequestId {
+    pub(crate) fn new() -> Self {
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let half = |which: u8| {
+            let mut hasher = KEY.build_hasher();
+            hasher.write_u64(n);
+            hasher.write_u8(which);
+            u128::from(hasher.finish())
+        };
+        let bits = half(0) << 64 | half(1);
+        // Mark it as a random (version 4, variant 1) UUID.
+        let bits = bits & !(0xf << 76) | (0x4 << 76);
+        let bits = bits & !(0x3 << 62) | (0x2 << 62);
+        RequestId(bits)
+    }
+}
+
+impl std::fmt::Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let hex = format!("{:032x}", self.0);
+        write!(
+            f,
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        )
+    }
+}
+
+static SEND_HEADER: AtomicBool = AtomicBool::new(false);
+
+/// Send each request's id as an `X-Request-Id` header, or stop doing so.
+/// This can be called at any time, and applies to requests which haven't
+/// been sent yet.
+pub fn set_send_request_id_header(send: bool) {
+    SEND_HEADER.store(send, Ordering::SeqCst);
+}
+
+/// Whether requests are sent with an `X-Request-Id` header. See
+/// [`set_send_request_id_header`].
+pub fn send_request_id_header() -> bool {
+    SEND_HEADER.load(Ordering::SeqCst)
+}
+
+/// Add the `X-Request-Id` header to `request`, if we've been asked to and
+/// the request doesn't have one already.
+pub(crate) fn apply_header(request: &mut Request) {
+    apply_header_with(send_request_id_header(), request)
+}
+
+fn apply_header_with(send: bool, request: &mut Request) {
+    if send {
+        let id = request.id().to_string();
+        request
+            .headers
+            .insert_if_missing(header_names::X_REQUEST_ID, id)
+            .unwrap(); // It's all hex digits and dashes.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_request_id() {
+        let ids = (0..1000).map(|_| RequestId::new()).collect::<HashSet<_>>();
+        assert_eq!(ids.len(), 1000);
+        for id in ids {
+            let s = id.to_string();
+            assert_eq!(s.len(), 36);
+            let groups = s.split('-').map(str::len).collect::<Vec<_>>();
+            assert_eq!(groups, vec![8, 4, 4, 4, 12]);
+            assert_eq!(&s[14..15], "4");
+            assert!("89ab".contains(&s[19..20]), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_apply_header() {
+        let url = url::Url::parse("https://www.example.com").unwrap();
+        let mut request = Request::get(url.clone());
+        apply_header_with(false, &mut request);
+        assert_eq!(request.headers.get(header_names::X_REQUEST_ID), None);
+
+        let mut request = Request::get(url.clone());
+        apply_header_with(true, &mut request);
+        let id = request.id().to_string();
+        assert_eq!(
+            request.headers.get(header_names::X_REQUEST_ID),
+            Some(id.as_str())
+        );
+
+        // A request which sets its own keeps it.
+        let mut request = Request::get(url)
+            .header(header_names::X_REQUEST_ID, "mine")
+            .unwrap();
+        apply_header_with(true, &mut request);
+        assert_eq!(
+            request.headers.get(header_names::X_REQUEST_ID),
+            Some("mine")
+        );
+    }
+}
@@ -0,0 +1,152 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Keeping secrets in bodies out of memory dumps and captures.
+//!
+//! Requests made with [`Request::sensitive`](crate::Request::sensitive)
+//! carry passwords, keys or tokens in their bodies. Freed memory isn't
+//! cleared, so without care, copies of them show up in the memory dumps
+//! taken for crash reports. For these requests:
+//!
+//! - The FFI backend zeroes its copies of the body, and of the response,
+//!   once it's done with them. It also zeroes every buffer the embedding
+//!   frees with `viaduct_destroy_bytebuffer`, since it can't tell which
+//!   ones held sensitive requests.
+//! - A [`LongPoller`](crate::longpoll::LongPoller) zeroes its template's
+//!   body when it's dropped, since it keeps it for every attempt.
+//! - The HAR capture (see the `debug` module) and cassettes (see the
+//!   `replay` module) record `[sensitive]` instead of the bodies.
+//! - The conditional request cache doesn't keep the responses.
+//!
+//! The response body is the caller's to look after, but
+//! [`Response::zeroize_on_drop`](crate::Response::zeroize_on_drop) will zero
+//! it when it's dropped. Other backends, including the reqwest backend, hand
+//! the body to HTTP stacks which don't zero their copies.
+
+use crate::Response;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{compiler_fence, Ordering};
+
+/// What the capture layers record instead of sensitive bodies.
+pub(crate) const PLACEHOLDER: &str = "[sensitive]";
+
+/// Overwrite `bytes` with zeros, including any spare capacity (which may
+/// hold what was there before it was truncated), and then empty it. The
+/// writes are volatile, so they can't be optimized away even though nothing
+/// reads them.
+pub(crate) fn zeroize(bytes: &mut Vec<u8>) {
+    let ptr = bytes.as_mut_ptr();
+    for i in 0..bytes.capacity() {
+        // Safety: `i` is within the allocation, and writing a `u8` doesn't
+        // need the memory to have been initialized.
+        unsafe { std::ptr::write_volatile(ptr.add(i), 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+    #[cfg(test)]
+    testing::note_zeroized(unsafe { std::slice::from_raw_parts(ptr, bytes.capacity()) });
+    bytes.clear();
+}
+
+/// A [`Response`] whose body is zeroed when it's dropped. See
+/// [`Response::zeroize_on_drop`].
+#[derive(Debug)]
+pub struct ZeroizingResponse(Response);
+
+impl ZeroizingResponse {
+    pub(crate) fn new(response: Response) -> Self {
+        ZeroizingResponse(response)
+    }
+}
+
+impl Deref for ZeroizingResponse {
+    type Target = Response;
+
+    fn deref(&self) -> &Response {
+        &self.0
+    }
+}
+
+impl DerefMut for ZeroizingResponse {
+    fn deref_mut(&mut self) -> &mut Response {
+        &mut self.0
+    }
+}
+
+impl Drop for ZeroizingResponse {
+    fn drop(&mut self) {
+        zeroize(&mut self.0.body);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use std::cell::RefCell;
+
+    thread_local! {
+        static ZEROIZED: RefCell<Vec<Vec<u8>>> = RefCell::new(vec![]);
+    }
+
+    // Keeps a copy of a whole allocation, just after it's been zeroed.
+    pub(super) fn note_zeroized(allocation: &[u8]) {
+        ZEROIZED.with(|z| z.borrow_mut().push(allocation.to_vec()));
+    }
+
+    /// The buffers zeroed on this thread since the last call, as they were
+    /// just before they were freed.
+    pub(crate) fn take_zeroized() -> Vec<Vec<u8>> {
+        ZEROIZED.with(|z| std::mem::take(&mut *z.borrow_mut()))
+    }
+
+    pub(crate) fn assert_all_zero(buffers: &[Vec<u8>]) {
+        for buffer in buffers {
+            assert!(buffer.iter().all(|&b| b == 0), "{:?}", buffer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::*;
+    use super::*;
+    use crate::{Headers, Method, RequestId};
+    use url::Url;
+
+    #[test]
+    fn test_zeroize_spare_capacity() {
+        take_zeroized();
+        let mut bytes = b"hunter2 and then some".to_vec();
+        bytes.truncate(7);
+        zeroize(&mut bytes);
+        assert!(bytes.is_empty());
+        let zeroized = take_zeroized();
+        assert_eq!(zeroized.len(), 1);
+        assert!(zeroized[0].len() >= 21);
+        assert_all_zero(&zeroized);
+    }
+
+    #[test]
+    fn test_zeroize_on_drop() {
+        take_zeroized();
+        let url = Url::parse("https://www.example.com").unwrap();
+        let response = Response {
+            request_method: Method::Post,
+            request_id: RequestId::new(),
+            url: url.clone(),
+            final_url: url,
+            redirects: vec![],
+            status: 200,
+            headers: Headers::new(),
+            body: b"{\"keys\": \"secret\"}".to_vec(),
+            from_cache: false,
+        }
+        .zeroize_on_drop();
+        assert_eq!(response.text(), "{\"keys\": \"secret\"}");
+        assert!(take_zeroized().is_empty());
+        drop(response);
+        let zeroized = take_zeroized();
+        assert_eq!(zeroized.len(), 1);
+        assert!(zeroized[0].len() >= 18);
+        assert_all_zero(&zeroized);
+    }
+}
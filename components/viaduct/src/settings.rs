@@ -20,6 +20,13 @@ pub struct Settings {
     pub connect_timeout: Option<Duration>,
     pub follow_redirects: bool,
     pub use_caches: bool,
+    /// The largest response body, in bytes, a request accepts unless it
+    /// sets its own limit with `Request::max_response_size`.
+    pub max_response_size: u64,
+    /// The most requests which may be in flight to a single host at once.
+    /// Further requests wait for an earlier one to complete. Zero is treated
+    /// as one, and `None` means there's no limit.
+    pub max_concurrent_requests_per_host: Option<usize>,
 }
 
 #[cfg(target_os = "ios")]
@@ -34,4 +41,6 @@ pub static GLOBAL_SETTINGS: &Settings = &Settings {
     connect_timeout: Some(TIMEOUT_DURATION),
     follow_redirects: true,
     use_caches: false,
+    max_response_size: 64 * 1024 * 1024,
+    max_concurrent_requests_per_host: None,
 };
@@ -0,0 +1,387 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Shutting viaduct down before the library is unloaded.
+//!
+//! If the library goes away while a request is still running, the thread
+//! sending it returns into unmapped code, or frees memory with an allocator
+//! which no longer exists. [`shutdown`] stops new requests, failing them with
+//! [`Error::ShuttingDown`], and waits for the ones in flight:
+//!
+//! - [`ShutdownPolicy::Drain`] lets them finish as usual.
+//! - [`ShutdownPolicy::Abort`] fails the requests which are still waiting for
+//!   their turn, for the fetch callback or for a host, with
+//!   [`Error::Cancelled`]. Requests which a backend is already sending can't
+//!   be interrupted, but they fail with `Error::Cancelled` when it returns,
+//!   so their callers stop straight away, without handling the response.
+//!
+//! Either way, once they're done, or the timeout is up, viaduct tears down
+//! the backend (see `Backend::tear_down`; the reqwest backend drops its
+//! client, closing its pooled connections), and forgets it, the FFI fetch
+//! callback, the cached responses, the hosts it was backing off from, and
+//! any stubbed responses. Requests are allowed again, and the embedding
+//! application can choose a backend with `init`, or register a fetch
+//! callback, as if viaduct had just been loaded.
+
+use crate::clock::{self, Clock};
+use crate::Error;
+use once_cell::sync::Lazy;
+use serde_derive::Serialize;
+use std::sync::{Condvar, Mutex, PoisonError};
+use std::time::Duration;
+
+static TRACKER: Lazy<Tracker> = Lazy::new(Tracker::default);
+
+// Only one shutdown at a time.
+static SHUTDOWN_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// What [`shutdown`] does with the requests in flight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ShutdownPolicy {
+    /// Wait for them to finish.
+    Drain,
+    /// Cancel them. See the module docs.
+    Abort,
+}
+
+/// How a [`shutdown`] went.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ShutdownReport {
+    pub policy: ShutdownPolicy,
+    /// How many requests were in flight when the shutdown started.
+    pub in_flight: usize,
+    /// How many of those were still in flight when the timeout was up. They
+    /// finish against the freshly reset viaduct, and might not be safe to
+    /// unload the library under.
+    pub abandoned: usize,
+    /// How many requests failed with `Error::ShuttingDown` in the meantime.
+    pub rejected: u64,
+    pub elapsed_ms: u64,
+}
+
+/// Stop sending requests, deal with the ones in flight according to
+/// `policy`, waiting up to `timeout` for them, and reset viaduct. See the
+/// module docs.
+pub fn shutdown(policy: ShutdownPolicy, timeout: Duration) -> ShutdownReport {
+    let _one_at_a_time = SHUTDOWN_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+    log::info!("Shutting down ({:?}), waiting up to {:?}", policy, timeout);
+    let report = TRACKER.shutdown(policy, timeout, cancel_waiting, tear_down);
+    if report.abandoned > 0 {
+        log::warn!("Gave up waiting for {} requests", report.abandoned);
+    }
+    log::info!("Shut down: {:?}", report);
+    report
+}
+
+fn cancel_waiting() {
+    crate::backend::cancel_waiting();
+    crate::backoff::cancel_waiting();
+}
+
+fn tear_down() {
+    crate::backend::reset();
+    crate::cache::clear_cache();
+    crate::backoff::clear_backoffs();
+    crate::backend::stub::reset();
+}
+
+/// Count a request as in flight until the returned guard is dropped, or
+/// fail with `Error::ShuttingDown`.
+pub(crate) fn start_send() -> Result<InFlight<'static>, Error> {
+    TRACKER.start()
+}
+
+#[derive(Default)]
+struct Tracker {
+    state: Mutex<State>,
+    // Notified whenever a request finishes.
+    finished: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    // Set while shutting down.
+    shutting_down: Option<ShutdownPolicy>,
+    in_flight: usize,
+    // Bumped by each aborted shutdown, so requests can tell whether one
+    // happened while they were in flight.
+    aborts: u64,
+    rejected: u64,
+}
+
+impl Tracker {
+    fn start(&self) -> Result<InFlight<'_>, Error> {
+        let mut state = self.state.lock().unwrap();
+        if state.shutting_down.is_some() {
+            state.rejected += 1;
+            return Err(Error::ShuttingDown);
+        }
+        state.in_flight += 1;
+        Ok(InFlight {
+            tracker: self,
+            aborts: state.aborts,
+        })
+    }
+
+    fn shutdown(
+        &self,
+        policy: ShutdownPolicy,
+        timeout: Duration,
+        cancel_waiting: impl FnOnce(),
+        tear_down: impl FnOnce(),
+    ) -> ShutdownReport {
+        let clock = clock::current();
+        let start = clock.now();
+        let in_flight = {
+            let mut state = self.state.lock().unwrap();
+            state.shutting_down = Some(policy);
+            state.rejected = 0;
+            if policy == ShutdownPolicy::Abort {
+                state.aborts += 1;
+            }
+            state.in_flight
+        };
+        if policy == ShutdownPolicy::Abort {
+            cancel_waiting();
+        }
+        let deadline = start + timeout;
+        let abandoned = {
+            let mut state = self.state.lock().unwrap();
+            while state.in_flight > 0 {
+                let now = clock.now();
+                if now >= deadline {
+                    break;
+                }
+                state = clock.wait_timeout(&self.finished, state, deadline - now);
+            }
+            state.in_flight
+        };
+        // New requests are still refused while we tear down.
+        tear_down();
+        let mut state = self.state.lock().unwrap();
+        state.shutting_down = None;
+        ShutdownReport {
+            policy,
+            in_flight,
+            abandoned,
+            rejected: state.rejected,
+            elapsed_ms: (clock.now() - start).as_millis() as u64,
+        }
+    }
+}
+
+// A request in flight, counted until it's dropped.
+pub(crate) struct InFlight<'a> {
+    tracker: &'a Tracker,
+    aborts: u64,
+}
+
+impl<'a> InFlight<'a> {
+    /// `result`, unless a shutdown was aborted while the request was in
+    /// flight, in which case it's cancelled.
+    pub(crate) fn finish<T>(self, result: Result<T, Error>) -> Result<T, Error> {
+        if self.tracker.state.lock().unwrap().aborts != self.aborts {
+            return Err(Error::Cancelled);
+        }
+        result
+    }
+}
+
+impl<'a> Drop for InFlight<'a> {
+    fn drop(&mut self) {
+        self.tracker.state.lock().unwrap().in_flight -= 1;
+        self.tracker.finished.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::testing::TestBackend;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc};
+    use std::thread::JoinHandle;
+
+    // A request in flight on its own thread, and the sender which tells it
+    // to finish.
+    type SlowSend = (JoinHandle<Result<usize, Error>>, mpsc::Sender<()>);
+
+    // Stands in for a slow request: sends on `started` once it's in flight,
+    // then doesn't finish until it's told to.
+    fn slow_send(tracker: &Arc<Tracker>, n: usize, started: &mpsc::Sender<()>) -> SlowSend {
+        let (finish, finished) = mpsc::channel::<()>();
+        let tracker = tracker.clone();
+        let started = started.clone();
+        let thread = std::thread::spawn(move || {
+            let in_flight = tracker.start()?;
+            started.send(()).unwrap();
+            finished.recv().unwrap();
+            in_flight.finish(Ok(n))
+        });
+        (thread, finish)
+    }
+
+    fn slow_sends(tracker: &Arc<Tracker>, count: usize) -> Vec<SlowSend> {
+        let (started, starts) = mpsc::channel();
+        let sends = (0..count)
+            .map(|n| slow_send(tracker, n, &started))
+            .collect::<Vec<_>>();
+        for _ in 0..count {
+            starts.recv().unwrap();
+        }
+        sends
+    }
+
+    fn is_shutting_down(tracker: &Tracker) -> bool {
+        tracker.state.lock().unwrap().shutting_down.is_some()
+    }
+
+    #[test]
+    fn test_drain() {
+        // The shutdown waits until the requests are done, however long
+        // that takes, without the clock moving.
+        let clock = ManualClock::install();
+        clock.set_advance_on_wait(false);
+        let tracker = Arc::new(Tracker::default());
+        let sends = slow_sends(&tracker, 3);
+
+        let torn_down = Arc::new(AtomicUsize::new(0));
+        let shutdown = {
+            let tracker = tracker.clone();
+            let torn_down = torn_down.clone();
+            std::thread::spawn(move || {
+                tracker.shutdown(
+                    ShutdownPolicy::Drain,
+                    Duration::from_secs(60),
+                    || panic!("Draining shouldn't cancel anything"),
+                    || {
+                        torn_down.fetch_add(1, Ordering::SeqCst);
+                    },
+                )
+            })
+        };
+        while !is_shutting_down(&tracker) {
+            std::thread::yield_now();
+        }
+        // New requests fail straight away...
+        assert!(matches!(tracker.start(), Err(Error::ShuttingDown)));
+        assert_eq!(torn_down.load(Ordering::SeqCst), 0);
+        // ...while the ones in flight finish as usual.
+        for (n, (thread, finish)) in sends.into_iter().enumerate() {
+            finish.send(()).unwrap();
+            assert_eq!(thread.join().unwrap().unwrap(), n);
+        }
+
+        let report = shutdown.join().unwrap();
+        assert_eq!(report.policy, ShutdownPolicy::Drain);
+        assert_eq!(report.in_flight, 3);
+        assert_eq!(report.abandoned, 0);
+        assert_eq!(report.rejected, 1);
+        assert_eq!(report.elapsed_ms, 0);
+        assert_eq!(torn_down.load(Ordering::SeqCst), 1);
+
+        // Once it's done, requests can be made again.
+        let in_flight = tracker.start().unwrap();
+        assert_eq!(in_flight.finish(Ok(1)).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_abort() {
+        // Waiting for the requests times out straight away.
+        let _clock = ManualClock::install();
+        let tracker = Arc::new(Tracker::default());
+        let sends = slow_sends(&tracker, 2);
+
+        let cancelled = AtomicUsize::new(0);
+        let report = tracker.shutdown(
+            ShutdownPolicy::Abort,
+            Duration::from_secs(1),
+            || {
+                cancelled.fetch_add(1, Ordering::SeqCst);
+            },
+            || {},
+        );
+        assert_eq!(cancelled.load(Ordering::SeqCst), 1);
+        assert_eq!(report.in_flight, 2);
+        assert_eq!(report.abandoned, 2);
+        assert_eq!(report.elapsed_ms, 1000);
+        assert!(!is_shutting_down(&tracker));
+
+        // A request made since isn't affected...
+        let later = tracker.start().unwrap();
+        // ...but the ones in flight are cancelled when they return.
+        for (thread, finish) in sends {
+            finish.send(()).unwrap();
+            assert!(matches!(thread.join().unwrap(), Err(Error::Cancelled)));
+        }
+        assert_eq!(later.finish(Ok(1)).unwrap(), 1);
+        assert_eq!(tracker.state.lock().unwrap().in_flight, 0);
+
+        // And draining the next time round doesn't cancel anything.
+        let sends = slow_sends(&tracker, 1);
+        let report = tracker.shutdown(ShutdownPolicy::Drain, Duration::default(), || {}, || {});
+        assert_eq!(report.abandoned, 1);
+        for (thread, finish) in sends {
+            finish.send(()).unwrap();
+            assert_eq!(thread.join().unwrap().unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn test_shutdown_end_to_end() {
+        static BACKEND: Lazy<TestBackend> = Lazy::new(TestBackend::default);
+        let send = || {
+            let url = url::Url::parse("https://shutdown.example.com/").unwrap();
+            crate::backend::send_with(crate::Request::get(url), crate::backend::get_backend)
+        };
+
+        // The shutdown waits for the requests, however long they take.
+        let clock = ManualClock::install();
+        clock.set_advance_on_wait(false);
+        // Start from scratch, whatever earlier tests chose.
+        crate::shutdown(ShutdownPolicy::Drain, Duration::default());
+        for (n, &policy) in [ShutdownPolicy::Drain, ShutdownPolicy::Abort]
+            .iter()
+            .enumerate()
+        {
+            crate::init(crate::BackendChoice::Custom(&*BACKEND)).unwrap();
+            BACKEND.hold();
+            let requests = (0..2).map(|_| std::thread::spawn(send)).collect::<Vec<_>>();
+            BACKEND.wait_for_in_flight(2);
+
+            let shutdown =
+                std::thread::spawn(move || crate::shutdown(policy, Duration::from_secs(60)));
+            while !is_shutting_down(&TRACKER) {
+                std::thread::yield_now();
+            }
+            let err = send().unwrap_err();
+            assert!(matches!(err.inner(), Error::ShuttingDown), "{:?}", err);
+            assert_eq!(BACKEND.torn_down(), n);
+
+            BACKEND.release();
+            for request in requests {
+                let result = request.join().unwrap().map_err(Error::into_inner);
+                match policy {
+                    ShutdownPolicy::Drain => assert_eq!(result.unwrap().status, 200),
+                    ShutdownPolicy::Abort => {
+                        assert!(matches!(result, Err(Error::Cancelled)), "{:?}", result)
+                    }
+                }
+            }
+            let report = shutdown.join().unwrap();
+            assert_eq!(report.in_flight, 2);
+            assert_eq!(report.abandoned, 0);
+            assert_eq!(report.rejected, 1);
+            assert_eq!(BACKEND.torn_down(), n + 1);
+
+            // The backend's forgotten, so another can be chosen.
+            assert!(crate::backend_selection().is_none());
+            crate::init(crate::BackendChoice::Stub).unwrap();
+            let err = send().unwrap_err();
+            assert!(matches!(err.inner(), Error::BackendError(_)), "{:?}", err);
+            crate::shutdown(ShutdownPolicy::Drain, Duration::default());
+        }
+    }
+}
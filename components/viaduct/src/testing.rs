@@ -0,0 +1,157 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Helpers shared between the tests of several modules.
+
+use crate::backend::stub::{self, StubResponse};
+use crate::backend::Backend;
+use crate::clock::{self, CurrentClock};
+use crate::{Error, RedirectHop, Request, Response};
+use once_cell::sync::Lazy;
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Held by tests which touch viaduct's global state: the clock, the network
+/// status, the backend, the stubs, the backoff and concurrency limits, or a
+/// shutdown. There's one lock for all of it, since most tests which send a
+/// request depend on several of them.
+pub(crate) static GLOBAL_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// Take `GLOBAL_LOCK`, even if a test panicked while holding it.
+pub(crate) fn lock() -> MutexGuard<'static, ()> {
+    GLOBAL_LOCK.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The backend tests send requests through. It responds to each request
+/// with the next response scripted with [`TestBackend::respond`] (or
+/// [`TestBackend::fail`]), or with an empty `200` once the script runs out,
+/// and remembers the requests it was sent.
+///
+/// While it's [held](TestBackend::hold), requests wait in `send` until it's
+/// released, so that tests can have several in flight at once without any
+/// real waiting.
+#[derive(Default)]
+pub(crate) struct TestBackend {
+    state: Mutex<TestBackendState>,
+    // Notified whenever a request arrives, or the backend's released.
+    changed: Condvar,
+    delay: Duration,
+}
+
+#[derive(Default)]
+struct TestBackendState {
+    script: VecDeque<Result<(StubResponse, Vec<RedirectHop>), Error>>,
+    requests: Vec<Request>,
+    held: bool,
+    in_flight: usize,
+    max_in_flight: usize,
+    torn_down: usize,
+}
+
+impl TestBackend {
+    /// A backend which takes `delay` to respond to each request, by the
+    /// manual clock, which must be installed.
+    pub(crate) fn with_delay(delay: Duration) -> Self {
+        Self {
+            delay,
+            ..Self::default()
+        }
+    }
+
+    /// Respond to the next request with `response`.
+    pub(crate) fn respond(&self, response: StubResponse) {
+        self.respond_redirected(response, vec![]);
+    }
+
+    /// Respond to the next request with `response`, as if the request was
+    /// redirected through `redirects` to get it.
+    pub(crate) fn respond_redirected(&self, response: StubResponse, redirects: Vec<RedirectHop>) {
+        let mut state = self.state.lock().unwrap();
+        state.script.push_back(Ok((response, redirects)));
+    }
+
+    /// Fail the next request with `error`.
+    pub(crate) fn fail(&self, error: Error) {
+        self.state.lock().unwrap().script.push_back(Err(error));
+    }
+
+    /// Hold requests until `release` is called.
+    pub(crate) fn hold(&self) {
+        self.state.lock().unwrap().held = true;
+    }
+
+    /// Let the requests which are being held, and any after them, go.
+    pub(crate) fn release(&self) {
+        self.state.lock().unwrap().held = false;
+        self.changed.notify_all();
+    }
+
+    /// Wait until `n` requests are being held, failing the test if that
+    /// takes more than a few seconds.
+    pub(crate) fn wait_for_in_flight(&self, n: usize) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut state = self.state.lock().unwrap();
+        while state.in_flight < n {
+            let now = Instant::now();
+            assert!(
+                now < deadline,
+                "Expected {} requests in flight, got {}",
+                n,
+                state.in_flight
+            );
+            state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    /// The requests this backend has been sent, in the order they arrived.
+    pub(crate) fn requests(&self) -> Vec<Request> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// The most requests this backend has had in flight at once.
+    pub(crate) fn max_in_flight(&self) -> usize {
+        self.state.lock().unwrap().max_in_flight
+    }
+
+    /// How many times this backend has been torn down.
+    pub(crate) fn torn_down(&self) -> usize {
+        self.state.lock().unwrap().torn_down
+    }
+}
+
+impl Backend for TestBackend {
+    fn send(&self, request: Request) -> Result<Response, Error> {
+        if self.delay > Duration::default() {
+            match clock::current() {
+                CurrentClock::Manual(clock) => clock.advance(self.delay),
+                CurrentClock::System(_) => panic!("Expected a manual clock"),
+            }
+        }
+        let mut state = self.state.lock().unwrap();
+        state.requests.push(request.clone());
+        state.in_flight += 1;
+        state.max_in_flight = state.max_in_flight.max(state.in_flight);
+        self.changed.notify_all();
+        while state.held {
+            state = self.changed.wait(state).unwrap();
+        }
+        state.in_flight -= 1;
+        let scripted = state.script.pop_front();
+        drop(state);
+        let (response, redirects) =
+            scripted.unwrap_or_else(|| Ok((StubResponse::new(200), vec![])))?;
+        let mut response = stub::respond(request, response)?;
+        response.redirects = redirects;
+        Ok(response)
+    }
+
+    fn name(&self) -> &'static str {
+        "test"
+    }
+
+    fn tear_down(&self) {
+        self.state.lock().unwrap().torn_down += 1;
+    }
+}
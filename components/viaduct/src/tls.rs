@@ -0,0 +1,62 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! TLS configuration for backends which manage their own connections.
+//!
+//! This is mostly for the benefit of CLI tools and tests which talk to local
+//! servers with self-signed certificates, and for pinning the certificates
+//! of specific servers. Only the reqwest backend supports it; the FFI
+//! backend leaves TLS to the embedding application, so requests which would
+//! need host-specific handling fail with [`Error::TlsConfigNotSupported`]
+//! there instead of silently ignoring it.
+
+use crate::Error;
+use once_cell::sync::OnceCell;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TlsConfig {
+    /// Additional DER-encoded root certificates to trust, on top of the
+    /// default ones.
+    pub extra_root_certs: Vec<Vec<u8>>,
+    /// For each host listed here, the server's own (leaf) certificate must
+    /// have a SubjectPublicKeyInfo with one of these SHA-256 hashes, or the
+    /// request fails with [`Error::PinViolation`].
+    pub pinned_spki_hashes: HashMap<String, Vec<[u8; 32]>>,
+    /// Hosts for which certificate verification is skipped entirely. Never
+    /// use this outside of tests.
+    pub danger_accept_invalid_for_hosts: Vec<String>,
+}
+
+impl TlsConfig {
+    /// Whether this config asks for anything other than the default
+    /// verification.
+    pub fn is_default(&self) -> bool {
+        *self == TlsConfig::default()
+    }
+
+    /// Whether this config changes how connections to `host` are verified,
+    /// beyond trusting additional roots.
+    pub fn has_host_specific_config(&self, host: &str) -> bool {
+        self.pinned_spki_hashes.contains_key(host)
+            || self
+                .danger_accept_invalid_for_hosts
+                .iter()
+                .any(|h| h == host)
+    }
+}
+
+static TLS_CONFIG: OnceCell<TlsConfig> = OnceCell::new();
+
+/// Set the TLS configuration. Like the backend, this may only be set once,
+/// and must be set before the first request is made.
+pub fn set_tls_config(config: TlsConfig) -> Result<(), Error> {
+    TLS_CONFIG.set(config).map_err(|_| Error::SetTlsConfigError)
+}
+
+/// Get the TLS configuration, locking in the default one if `set_tls_config`
+/// hasn't been called yet.
+pub fn tls_config() -> &'static TlsConfig {
+    TLS_CONFIG.get_or_init(TlsConfig::default)
+}
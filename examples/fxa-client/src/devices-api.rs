@@ -13,6 +13,7 @@ use std::{
     thread, time,
 };
 use url::Url;
+use viaduct::longpoll::{Backoff, StopToken};
 
 static CREDENTIALS_PATH: &str = "credentials.json";
 static CONTENT_SERVER: &str = "https://accounts.firefox.com";
@@ -85,34 +86,48 @@ fn main() -> Result<()> {
     persist_fxa_state(&acct);
 
     let acct: Arc<Mutex<FirefoxAccount>> = Arc::new(Mutex::new(acct));
-    {
+    let stop = StopToken::new();
+    let poller = {
         let acct = acct.clone();
+        let stop = stop.clone();
         thread::spawn(move || {
+            let mut backoff =
+                Backoff::new(time::Duration::from_secs(1), time::Duration::from_secs(60));
             loop {
-                let evts = acct
+                let polled = acct
                     .lock()
                     .unwrap()
-                    .poll_device_commands(device::CommandFetchReason::Poll)
-                    .unwrap_or_else(|_| vec![]); // Ignore 404 errors for now.
-                persist_fxa_state(&acct.lock().unwrap());
-                for e in evts {
-                    match e {
-                        IncomingDeviceCommand::TabReceived { sender, payload } => {
-                            let tab = &payload.entries[0];
-                            match sender {
-                                Some(ref d) => {
-                                    println!("Tab received from {}: {}", d.display_name, tab.url)
+                    .poll_device_commands(device::CommandFetchReason::Poll);
+                let delay = match polled {
+                    Ok(evts) => {
+                        backoff.reset();
+                        persist_fxa_state(&acct.lock().unwrap());
+                        for e in evts {
+                            match e {
+                                IncomingDeviceCommand::TabReceived { sender, payload } => {
+                                    let tab = &payload.entries[0];
+                                    match sender {
+                                        Some(ref d) => println!(
+                                            "Tab received from {}: {}",
+                                            d.display_name, tab.url
+                                        ),
+                                        None => println!("Tab received: {}", tab.url),
+                                    };
+                                    webbrowser::open(&tab.url).unwrap();
                                 }
-                                None => println!("Tab received: {}", tab.url),
-                            };
-                            webbrowser::open(&tab.url).unwrap();
+                            }
                         }
+                        time::Duration::from_secs(1)
                     }
+                    // Ignore errors (like 404s) for now, but don't hammer the server.
+                    Err(_) => backoff.next_delay(),
+                };
+                if stop.wait_timeout(delay) {
+                    break;
                 }
-                thread::sleep(time::Duration::from_secs(1));
             }
-        });
-    }
+        })
+    };
 
     // Menu:
     loop {
@@ -151,7 +166,11 @@ fn main() -> Result<()> {
                     .unwrap();
                 println!("Tab sent!");
             }
-            2 => ::std::process::exit(0),
+            2 => {
+                stop.stop();
+                poller.join().unwrap();
+                return Ok(());
+            }
             _ => panic!("Invalid choice!"),
         }
     }
@@ -11,11 +11,15 @@ use cli_support::prompt::{prompt_char, prompt_string, prompt_usize};
 use logins::{Login, PasswordStore};
 use prettytable::{cell, row, Cell, Row, Table};
 use rusqlite::NO_PARAMS;
+use std::io::Write;
 use sync15::EngineSyncAssociation;
 use sync_guid::Guid;
 
 // I'm completely punting on good error handling here.
-use anyhow::Result;
+use anyhow::{bail, Result};
+
+// Shown instead of passwords, unless `--show-passwords` is passed.
+const MASKED_PASSWORD: &str = "********";
 
 fn read_login() -> Login {
     let username = prompt_string("username").unwrap_or_default();
@@ -225,6 +229,102 @@ fn prompt_record_id(s: &PasswordStore, action: &str) -> Result<Option<String>> {
     Ok(Some(index_to_id[input].as_str().into()))
 }
 
+fn login_to_json(login: &Login, show_passwords: bool) -> Result<serde_json::Value> {
+    let mut json = serde_json::to_value(login)?;
+    if !show_passwords {
+        json["password"] = MASKED_PASSWORD.into();
+    }
+    Ok(json)
+}
+
+fn run_list(store: &PasswordStore, out: &mut dyn Write, show_passwords: bool) -> Result<()> {
+    let json = store
+        .list()?
+        .iter()
+        .map(|login| login_to_json(login, show_passwords))
+        .collect::<Result<Vec<_>>>()?;
+    writeln!(out, "{}", serde_json::to_string_pretty(&json)?)?;
+    Ok(())
+}
+
+fn run_get(
+    store: &PasswordStore,
+    out: &mut dyn Write,
+    guid: &str,
+    show_passwords: bool,
+) -> Result<()> {
+    let login = match store.get(guid)? {
+        Some(login) => login,
+        None => bail!("No login with guid `{}`", guid),
+    };
+    let json = login_to_json(&login, show_passwords)?;
+    writeln!(out, "{}", serde_json::to_string_pretty(&json)?)?;
+    Ok(())
+}
+
+fn run_import(store: &PasswordStore, out: &mut dyn Write, file: &str) -> Result<()> {
+    let logins: Vec<Login> = serde_json::from_reader(std::fs::File::open(file)?)?;
+    let metrics = store.import_multiple(&logins)?;
+    writeln!(out, "{}", serde_json::to_string_pretty(&metrics)?)?;
+    Ok(())
+}
+
+fn run_doctor(store: &PasswordStore, out: &mut dyn Write) -> Result<()> {
+    let conn = store.conn();
+    let integrity: String =
+        conn.query_row("PRAGMA integrity_check", NO_PARAMS, |row| row.get(0))?;
+    writeln!(out, "Integrity check: {}", integrity)?;
+
+    let mirror: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM loginsM WHERE is_overridden = 0",
+        NO_PARAMS,
+        |row| row.get(0),
+    )?;
+    writeln!(out, "Synced, unchanged: {}", mirror)?;
+    let mut stmt = conn.prepare(
+        "SELECT sync_status, is_deleted, COUNT(*) FROM loginsL
+         GROUP BY sync_status, is_deleted
+         ORDER BY sync_status, is_deleted",
+    )?;
+    let mut rows = stmt.query(NO_PARAMS)?;
+    while let Some(row) = rows.next()? {
+        let status = match row.get::<_, i64>(0)? {
+            0 => "Synced",
+            1 => "Changed",
+            2 => "New",
+            _ => "Unknown",
+        };
+        let deleted = if row.get::<_, bool>(1)? {
+            " (deleted)"
+        } else {
+            ""
+        };
+        writeln!(out, "{}{}: {}", status, deleted, row.get::<_, i64>(2)?)?;
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT key, value FROM loginsSyncMeta
+         WHERE key IN ('last_sync_time', 'sync_in_progress')",
+    )?;
+    let mut rows = stmt.query(NO_PARAMS)?;
+    let mut last_sync = None;
+    let mut interrupted = false;
+    while let Some(row) = rows.next()? {
+        match row.get::<_, String>(0)?.as_str() {
+            "last_sync_time" => last_sync = Some(row.get::<_, i64>(1)?),
+            _ => interrupted = true,
+        }
+    }
+    match last_sync {
+        Some(ts) => writeln!(out, "Last sync: {}", ts)?,
+        None => writeln!(out, "Last sync: never")?,
+    }
+    if interrupted {
+        writeln!(out, "The last sync was interrupted")?;
+    }
+    Ok(())
+}
+
 #[allow(clippy::cognitive_complexity)] // FIXME
 fn main() -> Result<()> {
     cli_support::init_trace_logging();
@@ -259,6 +359,37 @@ fn main() -> Result<()> {
                     "Path to store our cached fxa credentials (defaults to \"./credentials.json\"",
                 ),
         )
+        .arg(
+            clap::Arg::with_name("show_passwords")
+                .long("show-passwords")
+                .help("Print passwords from the list and get commands, instead of masking them"),
+        )
+        // Without a subcommand, we start an interactive session.
+        .subcommand(
+            clap::SubCommand::with_name("list").about("Prints all logins as JSON, and exits"),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("get")
+                .about("Prints a login as JSON, and exits")
+                .arg(
+                    clap::Arg::with_name("guid")
+                        .required(true)
+                        .help("The guid of the login to print"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("import")
+                .about("Imports logins from a file containing a JSON array of logins, and exits")
+                .arg(
+                    clap::Arg::with_name("file")
+                        .required(true)
+                        .help("The file to import"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("doctor")
+                .about("Checks the database, prints a summary of its state, and exits"),
+        )
         .get_matches();
 
     let cred_file = matches
@@ -277,11 +408,23 @@ fn main() -> Result<()> {
         db_path
     );
 
+    let store = PasswordStore::new(db_path, Some(encryption_key))?;
+
+    let out = &mut std::io::stdout();
+    let show_passwords = matches.is_present("show_passwords");
+    match matches.subcommand() {
+        ("list", _) => return run_list(&store, out, show_passwords),
+        ("get", Some(m)) => {
+            return run_get(&store, out, m.value_of("guid").unwrap(), show_passwords)
+        }
+        ("import", Some(m)) => return run_import(&store, out, m.value_of("file").unwrap()),
+        ("doctor", _) => return run_doctor(&store, out),
+        _ => {}
+    }
+
     // TODO: allow users to use stage/etc.
     let cli_fxa = get_cli_fxa(get_default_fxa_config(), cred_file)?;
 
-    let store = PasswordStore::new(db_path, Some(encryption_key))?;
-
     log::info!("Store has {} passwords", store.list()?.len());
 
     if let Err(e) = show_all(&store) {
@@ -419,3 +562,64 @@ fn main() -> Result<()> {
     println!("Exiting (bye!)");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_store(dir: &tempdir::TempDir) -> PasswordStore {
+        PasswordStore::new(dir.path().join("logins.db"), Some("testing")).unwrap()
+    }
+
+    fn run(f: impl FnOnce(&mut dyn Write) -> Result<()>) -> String {
+        let mut out = Vec::new();
+        f(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn login(hostname: &str) -> Login {
+        Login {
+            hostname: hostname.into(),
+            form_submit_url: Some(hostname.into()),
+            username: "user".into(),
+            password: "hunter2".into(),
+            ..Login::default()
+        }
+    }
+
+    #[test]
+    fn test_list_get() {
+        let dir = tempdir::TempDir::new("sync_pass").unwrap();
+        let store = open_store(&dir);
+        assert_eq!(run(|out| run_list(&store, out, false)).trim(), "[]");
+
+        let guid = store.add(login("https://www.example.com")).unwrap();
+
+        // Reopen the database, to check it was really written.
+        drop(store);
+        let store = open_store(&dir);
+        let got = run(|out| run_get(&store, out, &guid, true));
+        let got: serde_json::Value = serde_json::from_str(&got).unwrap();
+        assert_eq!(got["hostname"], "https://www.example.com");
+        assert_eq!(got["password"], "hunter2");
+
+        let listed = run(|out| run_list(&store, out, false));
+        let listed: Vec<serde_json::Value> = serde_json::from_str(&listed).unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0]["id"], guid.as_str());
+        assert_eq!(listed[0]["password"], MASKED_PASSWORD);
+
+        assert!(run_get(&store, &mut Vec::new(), "dummy_000001", false).is_err());
+    }
+
+    #[test]
+    fn test_doctor() {
+        let dir = tempdir::TempDir::new("sync_pass").unwrap();
+        let store = open_store(&dir);
+        store.add(login("https://www.example.com")).unwrap();
+        let report = run(|out| run_doctor(&store, out));
+        assert!(report.contains("Integrity check: ok"));
+        assert!(report.contains("New: 1"));
+        assert!(report.contains("Last sync: never"));
+    }
+}
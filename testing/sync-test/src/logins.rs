@@ -80,6 +80,19 @@ pub fn sync_logins(client: &mut TestClient) -> Result<()> {
     Ok(())
 }
 
+// Sync, returning the telemetry for the logins engine, if it synced.
+pub fn sync_logins_with_telemetry(client: &mut TestClient) -> Result<Option<serde_json::Value>> {
+    let (init, key, _device_id) = client.data_for_sync()?;
+    let ping = serde_json::to_value(client.logins_store.sync(&init, &key)?)?;
+    let engines = ping["syncs"][0]["engines"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+    Ok(engines
+        .into_iter()
+        .find(|engine| engine["name"] == "passwords"))
+}
+
 // Actual tests.
 
 fn test_login_general(c0: &mut TestClient, c1: &mut TestClient) {
@@ -268,13 +281,13 @@ fn test_login_deletes(c0: &mut TestClient, c1: &mut TestClient) {
     verify_login(&c1.logins_store, &login2);
     verify_login(&c1.logins_store, &login3);
 
-    // The 4 logins are for the for possible scenarios. All of them should result in the record
-    // being deleted.
+    // The 4 logins are for the for possible scenarios.
 
     // 1. Client A deletes record, client B has no changes (should delete).
     // 2. Client A deletes record, client B has also deleted record (should delete).
-    // 3. Client A deletes record, client B has modified record locally (should delete).
-    // 4. Same as #3 but in reverse order.
+    // 3. Client A deletes record after syncing client B's change (should delete).
+    // 4. Client A deletes record, then client B modifies it locally before
+    //    syncing (should keep B's change, which is newer than the deletion).
 
     // case 1. (c1 deletes record, c0 should have deleted on the other side)
     log::info!("Deleting {} from c1", l0id);
@@ -308,7 +321,7 @@ fn test_login_deletes(c0: &mut TestClient, c1: &mut TestClient) {
 
     log::info!("Update {} on c0", l3id);
     // 4b
-    update_login(&c0.logins_store, l3id, |l| {
+    let login3_new = update_login(&c0.logins_store, l3id, |l| {
         l.password = "quux".into();
     })
     .unwrap();
@@ -322,7 +335,8 @@ fn test_login_deletes(c0: &mut TestClient, c1: &mut TestClient) {
     verify_missing_login(&c0.logins_store, l0id);
     verify_missing_login(&c0.logins_store, l1id);
     verify_login(&c0.logins_store, &login2_new);
-    verify_missing_login(&c0.logins_store, l3id);
+    // The local change is newer than the deletion, so it's resurrected.
+    verify_login(&c0.logins_store, &login3_new);
 
     log::info!("Delete {} on c1", l2id);
     // 3b
@@ -334,6 +348,8 @@ fn test_login_deletes(c0: &mut TestClient, c1: &mut TestClient) {
     log::info!("{} should stay dead", l2id);
     // Ensure we didn't revive it.
     verify_missing_login(&c1.logins_store, l2id);
+    log::info!("{} should have been resurrected", l3id);
+    verify_login(&c1.logins_store, &login3_new);
 
     log::info!("Syncing c0");
     sync_logins(c0).expect("c0 sync to work");
@@ -341,12 +357,51 @@ fn test_login_deletes(c0: &mut TestClient, c1: &mut TestClient) {
     verify_missing_login(&c0.logins_store, l2id);
 }
 
+fn test_login_collection(c0: &mut TestClient, c1: &mut TestClient) {
+    log::info!("Add a login to client0, and sync it");
+    let l0id = "cccccccccccc";
+    add_login(
+        &c0.logins_store,
+        Login {
+            guid: l0id.into(),
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("https://login.example.com".into()),
+            username: "cool_username".into(),
+            password: "hunter2".into(),
+            username_field: "uname".into(),
+            password_field: "pword".into(),
+            ..Login::default()
+        },
+    )
+    .expect("add l0");
+
+    let telemetry = sync_logins_with_telemetry(c0)
+        .expect("c0 sync to work")
+        .expect("c0 to sync the passwords engine");
+    assert_eq!(telemetry["outgoing"][0]["sent"], 1);
+
+    log::info!("Syncing c0 again shouldn't upload anything");
+    if let Some(telemetry) = sync_logins_with_telemetry(c0).expect("c0 sync to work") {
+        assert!(telemetry.get("outgoing").is_none());
+    }
+
+    log::info!("Syncing c1 should download the login");
+    let telemetry = sync_logins_with_telemetry(c1)
+        .expect("c1 sync to work")
+        .expect("c1 to sync the passwords engine");
+    assert_eq!(telemetry["incoming"]["applied"], 1);
+    let login = c0.logins_store.get(l0id).unwrap().unwrap();
+    verify_login(&c1.logins_store, &login);
+}
+
 pub fn get_test_group() -> TestGroup {
     TestGroup::new(
         "logins",
         vec![
             ("test_login_general", test_login_general),
             ("test_login_deletes", test_login_deletes),
+            ("test_login_collection", test_login_collection),
         ],
     )
+    .parallel_safe()
 }
@@ -4,17 +4,23 @@ http://creativecommons.org/publicdomain/zero/1.0/ */
 #![allow(unknown_lints)]
 #![warn(rust_2018_idioms)]
 
-use std::{collections::HashSet, process};
+use std::collections::{HashSet, VecDeque};
+use std::io::Write;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use structopt::StructOpt;
 
 mod auth;
 mod logins;
+mod report;
 mod sync15;
 mod tabs;
 mod testing;
 
 use crate::auth::{FxaConfigUrl, TestUser};
-use crate::testing::TestGroup;
+use crate::testing::{GroupResult, TestGroup, TestOutcome, TestResult};
 
 macro_rules! cleanup_clients {
     ($($client:expr),+) => {
@@ -23,21 +29,46 @@ macro_rules! cleanup_clients {
     };
 }
 
-pub fn init_testing() {
-    viaduct_reqwest::use_reqwest_backend();
+pub fn init_testing(opts: &Opts) {
+    match &opts.cassette {
+        Some(path) => viaduct::replay::record_to(path, &viaduct_reqwest::ReqwestBackend)
+            .expect("Failed to start recording"),
+        None => viaduct_reqwest::use_reqwest_backend(),
+    }
     // Enable backtraces.
     std::env::set_var("RUST_BACKTRACE", "1");
+    testing::install_panic_hook();
     // Turn on trace logging for everything except for a few crates (mostly from
     // our network stack) that happen to be particularly noisy (even on `info`
     // level), which get turned on at the warn level. This can still be
     // overridden with RUST_LOG, however.
     let log_filter = "trace,tokio_threadpool=warn,tokio_reactor=warn,tokio_core=warn,tokio=warn,\
          hyper=warn,want=warn,mio=warn,reqwest=warn,trust_dns_proto=warn,trust_dns_resolver=warn";
-    env_logger::init_from_env(env_logger::Env::default().filter_or("RUST_LOG", log_filter));
+    // When running groups in parallel, their output is interleaved, so we
+    // prefix each line with the group it came from. Each group's recent
+    // lines are also kept, to report with a failure.
+    env_logger::Builder::from_env(env_logger::Env::default().filter_or("RUST_LOG", log_filter))
+        .format(|buf, record| {
+            let group = testing::current_group();
+            let line = format!(
+                "[{} {:<5} {}] {}{}",
+                buf.timestamp(),
+                record.level(),
+                record.target(),
+                group.map(|name| format!("[{}] ", name)).unwrap_or_default(),
+                record.args()
+            );
+            if let Some(group) = group {
+                testing::record_log_line(group, &line);
+            }
+            writeln!(buf, "{}", line)
+        })
+        .init();
 }
 
-// Runs each test group with a fresh Firefox account.
-pub fn run_test_groups(opts: &Opts, groups: Vec<TestGroup>) {
+// Runs each test group with a fresh Firefox account, returning their results
+// in the order the groups were given.
+pub fn run_test_groups(opts: &Opts, groups: Vec<TestGroup>) -> Vec<GroupResult> {
     let all_names = groups
         .iter()
         .map(|group| group.name)
@@ -59,28 +90,193 @@ pub fn run_test_groups(opts: &Opts, groups: Vec<TestGroup>) {
         .into_iter()
         .filter(|group| requested_names.contains(&group.name))
         .collect::<Vec<_>>();
-    log::info!("+ Testing {} groups", groups.len());
-    for group in groups {
-        run_test_group(opts, group);
+    let order = groups.iter().map(|group| group.name).collect::<Vec<_>>();
+    let jobs = if opts.force_username.is_some() && opts.jobs > 1 {
+        log::warn!("+ Groups share an account with `--force-username`, running them serially");
+        1
+    } else {
+        opts.jobs.max(1)
+    };
+    log::info!("+ Testing {} groups, {} at a time", groups.len(), jobs);
+    let (parallel, serial): (Vec<_>, Vec<_>) = groups
+        .into_iter()
+        .partition(|group| jobs > 1 && group.parallel_safe);
+    let mut results = run_test_groups_in_parallel(opts, parallel, jobs);
+    for group in serial {
+        results.push(run_test_group(opts, group));
     }
     log::info!("+ Test groups finished");
+    results.sort_by_key(|result| order.iter().position(|name| *name == result.name));
+    results
+}
+
+fn run_test_groups_in_parallel(
+    opts: &Opts,
+    groups: Vec<TestGroup>,
+    jobs: usize,
+) -> Vec<GroupResult> {
+    let queue = Arc::new(Mutex::new(groups.into_iter().collect::<VecDeque<_>>()));
+    let results = Arc::new(Mutex::new(Vec::new()));
+    let workers = (0..jobs)
+        .map(|_| {
+            let opts = opts.clone();
+            let queue = queue.clone();
+            let results = results.clone();
+            std::thread::spawn(move || loop {
+                let group = match queue.lock().unwrap().pop_front() {
+                    Some(group) => group,
+                    None => break,
+                };
+                let result = run_test_group(&opts, group);
+                results.lock().unwrap().push(result);
+            })
+        })
+        .collect::<Vec<_>>();
+    for worker in workers {
+        worker.join().expect("Test runner thread panicked");
+    }
+    let mut results = results.lock().unwrap();
+    std::mem::take(&mut *results)
 }
 
-pub fn run_test_group(opts: &Opts, group: TestGroup) {
-    let mut user = TestUser::new(opts, 2).expect("Failed to get test user.");
+pub fn run_test_group(opts: &Opts, group: TestGroup) -> GroupResult {
+    testing::set_current_group(Some(group.name));
+    log::info!("++ TestGroup begin {}", group.name);
+    let mut result = GroupResult {
+        name: group.name,
+        tests: Vec::with_capacity(group.tests.len()),
+    };
+    let mut user = match TestUser::new(opts, 2) {
+        Ok(user) => user,
+        Err(e) => {
+            log::error!("++ Failed to get test user: {}", e);
+            for (name, _) in group.tests {
+                let outcome = TestOutcome::Failed(format!("Failed to get test user: {}", e));
+                result.tests.push(TestResult::not_run(name, outcome));
+            }
+            testing::set_current_group(None);
+            return result;
+        }
+    };
     let (c0, c1) = {
         let (c0s, c1s) = user.clients.split_at_mut(1);
         (&mut c0s[0], &mut c1s[0])
     };
-    log::info!("++ TestGroup begin {}", group.name);
-    for (name, test) in group.tests {
-        log::info!("+++ Test begin {}::{}", group.name, name);
-        test(c0, c1);
-        log::info!("+++ Test cleanup {}::{}", group.name, name);
+    result.tests = run_tests(group.name, group.tests, c0, c1, |c0, c1| {
         cleanup_clients!(c0, c1);
-        log::info!("+++ Test finish {}::{}", group.name, name);
-    }
+    });
     log::info!("++ TestGroup end {}", group.name);
+    // Delete the account while we can still tell whose log lines are whose.
+    drop(user);
+    testing::set_current_group(None);
+    result
+}
+
+// Runs the tests in a group one after another, since they may share state,
+// cleaning up the clients after each one. If a test fails we still try to
+// clean up after it, but if that fails too, the rest of the group can't be
+// trusted.
+fn run_tests<C>(
+    group_name: &'static str,
+    tests: Vec<(&'static str, fn(&mut C, &mut C))>,
+    c0: &mut C,
+    c1: &mut C,
+    mut cleanup: impl FnMut(&mut C, &mut C),
+) -> Vec<TestResult> {
+    let mut results = Vec::with_capacity(tests.len());
+    let mut clean = true;
+    for (name, test) in tests {
+        if !clean {
+            results.push(TestResult::not_run(name, TestOutcome::Skipped));
+            continue;
+        }
+        log::info!("+++ Test begin {}::{}", group_name, name);
+        // Only keep what this test logs, and the panics on its helper
+        // threads.
+        testing::take_log_tail(group_name);
+        testing::take_stray_panics();
+        let start = Instant::now();
+        let mut outcome = match panic::catch_unwind(AssertUnwindSafe(|| test(c0, c1))) {
+            Ok(()) => TestOutcome::Passed,
+            Err(payload) => TestOutcome::Panicked(testing::panic_message(&*payload)),
+        };
+        let duration = start.elapsed();
+        let stray_panics = testing::take_stray_panics();
+        if outcome == TestOutcome::Passed && !stray_panics.is_empty() {
+            outcome = TestOutcome::Failed(stray_panics.join("; "));
+        }
+        if let TestOutcome::Panicked(message) | TestOutcome::Failed(message) = &outcome {
+            log::error!("+++ Test failed {}::{}: {}", group_name, name, message);
+        }
+        log::info!("+++ Test cleanup {}::{}", group_name, name);
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| cleanup(c0, c1))) {
+            let message = testing::panic_message(&*payload);
+            log::error!(
+                "+++ Test cleanup failed {}::{}: {}",
+                group_name,
+                name,
+                message
+            );
+            clean = false;
+        }
+        log::info!("+++ Test finish {}::{}", group_name, name);
+        let log_tail = testing::take_log_tail(group_name);
+        results.push(TestResult {
+            name,
+            log_tail: if outcome == TestOutcome::Passed {
+                Vec::new()
+            } else {
+                log_tail
+            },
+            outcome,
+            duration,
+        });
+    }
+    results
+}
+
+// Prints the results of every test.
+fn print_summary(results: &[GroupResult]) {
+    println!("\n### Results");
+    for group in results {
+        for test in &group.tests {
+            let (status, message) = match &test.outcome {
+                TestOutcome::Passed => ("PASS", None),
+                TestOutcome::Failed(message) => ("FAIL", Some(message)),
+                TestOutcome::Panicked(message) => ("PANIC", Some(message)),
+                TestOutcome::Skipped => ("SKIP", None),
+            };
+            match message {
+                Some(message) => println!(
+                    "{} {}::{} ({:.1?}): {}",
+                    status, group.name, test.name, test.duration, message
+                ),
+                None => println!(
+                    "{} {}::{} ({:.1?})",
+                    status, group.name, test.name, test.duration
+                ),
+            }
+        }
+    }
+}
+
+// Writes the reports asked for with `--report-json` and `--report-junit`,
+// returning whether that worked.
+fn write_reports(opts: &Opts, results: &[GroupResult]) -> bool {
+    let mut ok = true;
+    if let Some(path) = &opts.report_json {
+        if let Err(e) = report::write_json(path, results) {
+            eprintln!("Failed to write JSON report to {:?}: {}", path, e);
+            ok = false;
+        }
+    }
+    if let Some(path) = &opts.report_junit {
+        if let Err(e) = report::write_junit(path, results) {
+            eprintln!("Failed to write JUnit report to {:?}: {}", path, e);
+            ok = false;
+        }
+    }
+    ok
 }
 
 // Note: this uses doc comments to generate the help text.
@@ -116,6 +312,26 @@ pub struct Opts {
     /// Disable deleting the fx account after use. Incompatible with oauth-retries.
     pub no_delete_account: bool,
 
+    #[structopt(name = "jobs", long, short = "j", default_value = "1")]
+    /// Number of test groups to run at once, each with its own account.
+    /// Tests within a group always run one at a time.
+    pub jobs: usize,
+
+    #[structopt(name = "cassette", long, parse(from_os_str))]
+    /// Record every request and response to this file, as newline-delimited
+    /// JSON, for replaying with `viaduct::replay::load_cassette`. Credentials
+    /// in headers are redacted, but bodies (which include keys) aren't.
+    pub cassette: Option<std::path::PathBuf>,
+
+    #[structopt(name = "report-json", long, parse(from_os_str))]
+    /// Write every test's outcome, duration, and (if it failed) the end of
+    /// its log to this file as JSON, once all the groups have run.
+    pub report_json: Option<std::path::PathBuf>,
+
+    #[structopt(name = "report-junit", long, parse(from_os_str))]
+    /// Like `--report-json`, but in JUnit's XML format.
+    pub report_junit: Option<std::path::PathBuf>,
+
     #[structopt(name = "helper-debug", long)]
     /// Run the helper browser as non-headless, and enable extra logging
     pub helper_debug: bool,
@@ -126,8 +342,8 @@ pub struct Opts {
 pub fn main() {
     let opts = Opts::from_args();
     println!("### Running sync integration tests ###");
-    init_testing();
-    run_test_groups(
+    init_testing(&opts);
+    let results = run_test_groups(
         &opts,
         vec![
             crate::logins::get_test_group(),
@@ -135,6 +351,105 @@ pub fn main() {
             crate::sync15::get_test_group(),
         ],
     );
-
+    print_summary(&results);
+    let reported = write_reports(&opts, &results);
+    let code = report::exit_code(&results);
+    if code != 0 {
+        println!("\n### Sync integration tests failed!");
+        process::exit(code);
+    }
+    if !reported {
+        process::exit(1);
+    }
     println!("\n### Sync integration tests passed!");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct DummyClient {
+        cleanups: usize,
+    }
+
+    type DummyTest = fn(&mut DummyClient, &mut DummyClient);
+
+    fn test_passes(_: &mut DummyClient, _: &mut DummyClient) {}
+
+    fn test_fails(_: &mut DummyClient, _: &mut DummyClient) {
+        log::info!("About to fail");
+        assert_eq!(1 + 1, 3, "deliberately failing");
+    }
+
+    fn test_helper_thread_panics(_: &mut DummyClient, _: &mut DummyClient) {
+        let helper = std::thread::spawn(|| panic!("deliberate helper panic"));
+        assert!(helper.join().is_err());
+    }
+
+    fn run_dummy_group(
+        tests: Vec<(&'static str, DummyTest)>,
+        cleanup: impl FnMut(&mut DummyClient, &mut DummyClient),
+    ) -> (GroupResult, DummyClient) {
+        testing::install_panic_hook();
+        testing::set_current_group(Some("dummy"));
+        let (mut c0, mut c1) = (DummyClient::default(), DummyClient::default());
+        let tests = run_tests("dummy", tests, &mut c0, &mut c1, cleanup);
+        testing::set_current_group(None);
+        (
+            GroupResult {
+                name: "dummy",
+                tests,
+            },
+            c0,
+        )
+    }
+
+    #[test]
+    fn test_failing_group() {
+        let (result, c0) = run_dummy_group(
+            vec![
+                ("test_passes", test_passes),
+                ("test_fails", test_fails),
+                ("test_helper_thread_panics", test_helper_thread_panics),
+            ],
+            |c0, _| c0.cleanups += 1,
+        );
+        // A failure doesn't stop the rest of the group, or its cleanup.
+        assert_eq!(c0.cleanups, 3);
+        assert_eq!(result.tests[0].outcome, TestOutcome::Passed);
+        assert!(result.tests[0].log_tail.is_empty());
+        assert!(matches!(
+            &result.tests[1].outcome,
+            TestOutcome::Panicked(message) if message.contains("deliberately failing")
+        ));
+        assert!(matches!(
+            &result.tests[2].outcome,
+            TestOutcome::Failed(message) if message.contains("deliberate helper panic")
+        ));
+
+        let results = vec![result];
+        assert_ne!(report::exit_code(&results), 0);
+        let path = std::env::temp_dir().join(format!("sync-test-junit-{}.xml", process::id()));
+        let opts = Opts::from_iter(&["sync-test", "--report-junit", path.to_str().unwrap()]);
+        assert!(write_reports(&opts, &results));
+        let xml = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        report::check_well_formed(&xml).unwrap();
+        assert!(xml.contains("tests=\"3\" failures=\"1\" errors=\"1\" skipped=\"0\""));
+    }
+
+    #[test]
+    fn test_failed_cleanup_skips_rest_of_group() {
+        let (result, _) = run_dummy_group(
+            vec![
+                ("test_passes", test_passes),
+                ("test_also_passes", test_passes),
+            ],
+            |_, _| panic!("cleanup failed"),
+        );
+        assert_eq!(result.tests[0].outcome, TestOutcome::Passed);
+        assert_eq!(result.tests[1].outcome, TestOutcome::Skipped);
+        assert!(!result.passed());
+    }
+}
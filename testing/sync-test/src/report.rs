@@ -0,0 +1,367 @@
+/* Any copyright is dedicated to the Public Domain.
+http://creativecommons.org/publicdomain/zero/1.0/ */
+
+//! Machine-readable reports of a run, for CI. See `--report-json` and
+//! `--report-junit`.
+
+use crate::testing::{GroupResult, TestOutcome, TestResult};
+use serde_derive::Serialize;
+use std::fmt::Write;
+use std::path::Path;
+use std::time::Duration;
+
+#[derive(Debug, Serialize)]
+struct JsonReport<'a> {
+    passed: bool,
+    groups: Vec<JsonGroup<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct JsonGroup<'a> {
+    name: &'a str,
+    tests: Vec<JsonTest<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct JsonTest<'a> {
+    name: &'a str,
+    /// One of "passed", "failed", "panicked" or "skipped".
+    outcome: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<&'a str>,
+    duration_ms: u64,
+    log_tail: &'a [String],
+}
+
+fn outcome_name(outcome: &TestOutcome) -> &'static str {
+    match outcome {
+        TestOutcome::Passed => "passed",
+        TestOutcome::Failed(_) => "failed",
+        TestOutcome::Panicked(_) => "panicked",
+        TestOutcome::Skipped => "skipped",
+    }
+}
+
+fn outcome_message(outcome: &TestOutcome) -> Option<&str> {
+    match outcome {
+        TestOutcome::Failed(message) | TestOutcome::Panicked(message) => Some(message),
+        TestOutcome::Passed | TestOutcome::Skipped => None,
+    }
+}
+
+/// Whether every test in every group passed.
+pub fn all_passed(results: &[GroupResult]) -> bool {
+    results.iter().all(GroupResult::passed)
+}
+
+/// The process exit code for a run with these results.
+pub fn exit_code(results: &[GroupResult]) -> i32 {
+    if all_passed(results) {
+        0
+    } else {
+        1
+    }
+}
+
+pub fn to_json(results: &[GroupResult]) -> String {
+    let report = JsonReport {
+        passed: all_passed(results),
+        groups: results
+            .iter()
+            .map(|group| JsonGroup {
+                name: group.name,
+                tests: group
+                    .tests
+                    .iter()
+                    .map(|test| JsonTest {
+                        name: test.name,
+                        outcome: outcome_name(&test.outcome),
+                        message: outcome_message(&test.outcome),
+                        duration_ms: test.duration.as_millis() as u64,
+                        log_tail: &test.log_tail,
+                    })
+                    .collect(),
+            })
+            .collect(),
+    };
+    serde_json::to_string_pretty(&report).expect("Serializing the report can't fail")
+}
+
+#[derive(Default)]
+struct Counts {
+    tests: usize,
+    failures: usize,
+    errors: usize,
+    skipped: usize,
+    time: Duration,
+}
+
+impl Counts {
+    fn of<'a>(tests: impl Iterator<Item = &'a TestResult>) -> Self {
+        let mut counts = Self::default();
+        for test in tests {
+            counts.tests += 1;
+            match test.outcome {
+                TestOutcome::Panicked(_) => counts.failures += 1,
+                TestOutcome::Failed(_) => counts.errors += 1,
+                TestOutcome::Skipped => counts.skipped += 1,
+                TestOutcome::Passed => {}
+            }
+            counts.time += test.duration;
+        }
+        counts
+    }
+
+    fn attributes(&self) -> String {
+        format!(
+            "tests=\"{}\" failures=\"{}\" errors=\"{}\" skipped=\"{}\" time=\"{:.3}\"",
+            self.tests,
+            self.failures,
+            self.errors,
+            self.skipped,
+            self.time.as_secs_f64()
+        )
+    }
+}
+
+/// The results in JUnit's XML format. A test which panicked is a
+/// `<failure>`, and one which couldn't run properly is an `<error>`.
+pub fn to_junit(results: &[GroupResult]) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let all = Counts::of(results.iter().flat_map(|group| group.tests.iter()));
+    writeln!(xml, "<testsuites name=\"sync-test\" {}>", all.attributes()).unwrap();
+    for group in results {
+        writeln!(
+            xml,
+            "  <testsuite name=\"{}\" {}>",
+            xml_escape(group.name),
+            Counts::of(group.tests.iter()).attributes()
+        )
+        .unwrap();
+        for test in &group.tests {
+            write!(
+                xml,
+                "    <testcase classname=\"{}\" name=\"{}\" time=\"{:.3}\"",
+                xml_escape(group.name),
+                xml_escape(test.name),
+                test.duration.as_secs_f64()
+            )
+            .unwrap();
+            let (element, kind) = match test.outcome {
+                TestOutcome::Passed => {
+                    xml.push_str("/>\n");
+                    continue;
+                }
+                TestOutcome::Skipped => {
+                    xml.push_str(">\n      <skipped/>\n    </testcase>\n");
+                    continue;
+                }
+                TestOutcome::Panicked(_) => ("failure", "panic"),
+                TestOutcome::Failed(_) => ("error", "error"),
+            };
+            writeln!(
+                xml,
+                ">\n      <{} type=\"{}\" message=\"{}\">{}</{}>\n    </testcase>",
+                element,
+                kind,
+                xml_escape(outcome_message(&test.outcome).unwrap_or_default()),
+                xml_escape(&test.log_tail.join("\n")),
+                element
+            )
+            .unwrap();
+        }
+        xml.push_str("  </testsuite>\n");
+    }
+    xml.push_str("</testsuites>\n");
+    xml
+}
+
+// Escapes text for an XML attribute or element. Characters XML doesn't
+// allow at all, like the escape codes for colored log output, are replaced.
+fn xml_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c < ' ' || c == '\u{FFFE}' || c == '\u{FFFF}' => {
+                escaped.push(std::char::REPLACEMENT_CHARACTER)
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn write_json(path: &Path, results: &[GroupResult]) -> std::io::Result<()> {
+    std::fs::write(path, to_json(results))
+}
+
+pub fn write_junit(path: &Path, results: &[GroupResult]) -> std::io::Result<()> {
+    std::fs::write(path, to_junit(results))
+}
+
+/// Checks that `xml` is well-formed, as far as our reports go: a prolog,
+/// then one root element, with balanced tags and only known entities.
+#[cfg(test)]
+pub fn check_well_formed(xml: &str) -> Result<(), String> {
+    fn check_text(text: &str) -> Result<(), String> {
+        for (i, _) in text.match_indices('&') {
+            let entity = &text[i..];
+            if !["&amp;", "&lt;", "&gt;", "&quot;", "&apos;"]
+                .iter()
+                .any(|known| entity.starts_with(known))
+            {
+                return Err(format!("Bad entity in {:?}", text));
+            }
+        }
+        if text.contains('>') {
+            return Err(format!("Unescaped '>' in {:?}", text));
+        }
+        Ok(())
+    }
+    let prolog = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>";
+    let mut rest = xml
+        .strip_prefix(prolog)
+        .ok_or_else(|| "Missing prolog".to_owned())?;
+    let mut open: Vec<&str> = Vec::new();
+    let mut roots = 0;
+    while let Some(start) = rest.find('<') {
+        let text = &rest[..start];
+        if open.is_empty() && !text.trim().is_empty() {
+            return Err(format!("Text outside the root: {:?}", text));
+        }
+        check_text(text)?;
+        let end = rest[start..]
+            .find('>')
+            .ok_or_else(|| "Unterminated tag".to_owned())?
+            + start;
+        let tag = &rest[start + 1..end];
+        if tag.contains('<') {
+            return Err(format!("Unescaped '<' in tag {:?}", tag));
+        }
+        if tag.matches('"').count() % 2 != 0 {
+            return Err(format!("Unbalanced quotes in tag {:?}", tag));
+        }
+        if let Some(name) = tag.strip_prefix('/') {
+            match open.pop() {
+                Some(opened) if opened == name => {}
+                opened => return Err(format!("</{}> closes {:?}", name, opened)),
+            }
+        } else {
+            if open.is_empty() {
+                roots += 1;
+            }
+            check_text(tag)?;
+            let name = tag.split_whitespace().next().unwrap_or_default();
+            if !tag.ends_with('/') {
+                open.push(name.trim_end_matches('/'));
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    if !open.is_empty() {
+        return Err(format!("Unclosed elements: {:?}", open));
+    }
+    if roots != 1 || !rest.trim().is_empty() {
+        return Err("Expected exactly one root element".to_owned());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn results() -> Vec<GroupResult> {
+        vec![
+            GroupResult {
+                name: "logins",
+                tests: vec![
+                    TestResult {
+                        name: "test_ok",
+                        outcome: TestOutcome::Passed,
+                        duration: Duration::from_millis(1500),
+                        log_tail: vec![],
+                    },
+                    TestResult {
+                        name: "test_assert",
+                        outcome: TestOutcome::Panicked("assertion failed: a < b && \"c\"".into()),
+                        duration: Duration::from_millis(20),
+                        log_tail: vec!["\u{1b}[31mERROR\u{1b}[0m <oops> & 'more'".into()],
+                    },
+                    TestResult::not_run("test_skipped", TestOutcome::Skipped),
+                ],
+            },
+            GroupResult {
+                name: "tabs",
+                tests: vec![TestResult::not_run(
+                    "test_tabs",
+                    TestOutcome::Failed("Failed to get test user".into()),
+                )],
+            },
+        ]
+    }
+
+    #[test]
+    fn test_json() {
+        let report: serde_json::Value = serde_json::from_str(&to_json(&results())).unwrap();
+        assert_eq!(report["passed"], false);
+        assert_eq!(
+            report["groups"][0]["tests"][0],
+            json!({
+                "name": "test_ok",
+                "outcome": "passed",
+                "duration_ms": 1500,
+                "log_tail": [],
+            })
+        );
+        assert_eq!(report["groups"][0]["tests"][1]["outcome"], "panicked");
+        assert_eq!(
+            report["groups"][0]["tests"][1]["message"],
+            "assertion failed: a < b && \"c\""
+        );
+        assert_eq!(report["groups"][0]["tests"][2]["outcome"], "skipped");
+        assert_eq!(report["groups"][1]["tests"][0]["outcome"], "failed");
+    }
+
+    #[test]
+    fn test_junit() {
+        let xml = to_junit(&results());
+        check_well_formed(&xml).unwrap();
+        assert!(xml.contains(
+            "<testsuites name=\"sync-test\" tests=\"4\" failures=\"1\" errors=\"1\" skipped=\"1\" time=\"1.520\">"
+        ));
+        assert!(xml.contains("<testcase classname=\"logins\" name=\"test_ok\" time=\"1.500\"/>"));
+        assert!(xml.contains(
+            "<failure type=\"panic\" message=\"assertion failed: a &lt; b &amp;&amp; &quot;c&quot;\">\
+             \u{FFFD}[31mERROR\u{FFFD}[0m &lt;oops&gt; &amp; &apos;more&apos;</failure>"
+        ));
+        assert!(xml.contains("<skipped/>"));
+        assert!(xml.contains("<error type=\"error\" message=\"Failed to get test user\"></error>"));
+    }
+
+    #[test]
+    fn test_check_well_formed() {
+        assert!(check_well_formed("<?xml version=\"1.0\" encoding=\"UTF-8\"?><a><b/></a>").is_ok());
+        for bad in &[
+            "<a></a>",
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><a><b></a>",
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><a>x & y</a>",
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><a/><b/>",
+        ] {
+            assert!(check_well_formed(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(exit_code(&results()), 1);
+        assert_eq!(exit_code(&results()[..0]), 0);
+    }
+}
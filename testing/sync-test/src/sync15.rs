@@ -246,5 +246,5 @@ fn test_sync_multiple(c0: &mut TestClient, c1: &mut TestClient) {
 
 // Boilerplate...
 pub fn get_test_group() -> TestGroup {
-    TestGroup::new("sync15", vec![("test_sync_multiple", test_sync_multiple)])
+    TestGroup::new("sync15", vec![("test_sync_multiple", test_sync_multiple)]).parallel_safe()
 }
@@ -96,5 +96,5 @@ fn test_tabs(c0: &mut TestClient, c1: &mut TestClient) {
 }
 
 pub fn get_test_group() -> TestGroup {
-    TestGroup::new("tabs", vec![("test_tabs", test_tabs)])
+    TestGroup::new("tabs", vec![("test_tabs", test_tabs)]).parallel_safe()
 }
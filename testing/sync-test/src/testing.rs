@@ -2,6 +2,12 @@
 http://creativecommons.org/publicdomain/zero/1.0/ */
 
 use crate::auth::TestClient;
+use lazy_static::lazy_static;
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::panic;
+use std::sync::{Mutex, Once};
+use std::time::Duration;
 
 // A (name, test_func) tuple. Eventually we should allow for more/less
 // than 2 clients, and maybe this should be a trait or something.
@@ -10,10 +16,160 @@ type Test = (&'static str, fn(&mut TestClient, &mut TestClient));
 pub struct TestGroup {
     pub name: &'static str,
     pub tests: Vec<Test>,
+    /// Whether this group may run at the same time as other groups (with
+    /// `--jobs`). Groups which touch process-wide state, such as viaduct's
+    /// settings, must not be.
+    pub parallel_safe: bool,
 }
 
 impl TestGroup {
     pub fn new(name: &'static str, tests: Vec<Test>) -> Self {
-        Self { name, tests }
+        Self {
+            name,
+            tests,
+            parallel_safe: false,
+        }
+    }
+
+    /// Allow this group to run alongside others.
+    pub fn parallel_safe(mut self) -> Self {
+        self.parallel_safe = true;
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TestOutcome {
+    Passed,
+    /// The test couldn't be run, or something it started (such as a helper
+    /// thread) panicked.
+    Failed(String),
+    /// The test function itself panicked, usually because an assertion
+    /// failed.
+    Panicked(String),
+    /// Not run, because an earlier failure left the group's clients in an
+    /// unknown state.
+    Skipped,
+}
+
+#[derive(Debug, Clone)]
+pub struct TestResult {
+    pub name: &'static str,
+    pub outcome: TestOutcome,
+    pub duration: Duration,
+    /// The group's last few log lines, if the test didn't pass.
+    pub log_tail: Vec<String>,
+}
+
+impl TestResult {
+    /// The result of a test which was never started.
+    pub fn not_run(name: &'static str, outcome: TestOutcome) -> Self {
+        Self {
+            name,
+            outcome,
+            duration: Duration::default(),
+            log_tail: Vec::new(),
+        }
+    }
+}
+
+pub struct GroupResult {
+    pub name: &'static str,
+    pub tests: Vec<TestResult>,
+}
+
+impl GroupResult {
+    pub fn passed(&self) -> bool {
+        self.tests
+            .iter()
+            .all(|test| test.outcome == TestOutcome::Passed)
+    }
+}
+
+thread_local! {
+    static CURRENT_GROUP: Cell<Option<&'static str>> = Cell::new(None);
+}
+
+/// Set the name of the group being run on this thread, which prefixes its
+/// log output.
+pub fn set_current_group(name: Option<&'static str>) {
+    CURRENT_GROUP.with(|group| group.set(name));
+}
+
+pub fn current_group() -> Option<&'static str> {
+    CURRENT_GROUP.with(Cell::get)
+}
+
+/// Turn the payload of a caught panic into a message for the summary.
+pub fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        (*message).to_owned()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "(unknown panic)".to_owned()
+    }
+}
+
+/// How many log lines are kept for each group, to report with a failure.
+const LOG_TAIL_LINES: usize = 50;
+
+lazy_static! {
+    static ref LOG_TAILS: Mutex<HashMap<&'static str, VecDeque<String>>> = Mutex::default();
+    static ref STRAY_PANICS: Mutex<Vec<String>> = Mutex::default();
+}
+
+/// Remember a line logged by `group`, for `take_log_tail`.
+pub fn record_log_line(group: &'static str, line: &str) {
+    // Don't make a logging call panic because another thread panicked.
+    if let Ok(mut tails) = LOG_TAILS.lock() {
+        let tail = tails.entry(group).or_default();
+        if tail.len() == LOG_TAIL_LINES {
+            tail.pop_front();
+        }
+        tail.push_back(line.to_owned());
+    }
+}
+
+/// The lines `group` has logged since the last call, up to
+/// `LOG_TAIL_LINES` of them.
+pub fn take_log_tail(group: &'static str) -> Vec<String> {
+    match LOG_TAILS.lock() {
+        Ok(mut tails) => tails.remove(group).map(Vec::from).unwrap_or_default(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Record panics on threads which aren't running a group, such as helper
+/// threads started by a test, which `catch_unwind` around the test can't
+/// see. The default hook still prints them.
+pub fn install_panic_hook() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if current_group().is_none() {
+                let thread = std::thread::current();
+                let message = format!(
+                    "Thread '{}' panicked: {}",
+                    thread.name().unwrap_or("<unnamed>"),
+                    panic_message(info.payload())
+                );
+                if let Ok(mut panics) = STRAY_PANICS.lock() {
+                    panics.push(message);
+                }
+            }
+            default_hook(info);
+        }));
+    });
+}
+
+/// The panics recorded by `install_panic_hook` since the last call. When
+/// groups run in parallel, these can't be told apart, so they're blamed on
+/// whichever test notices them first.
+pub fn take_stray_panics() -> Vec<String> {
+    match STRAY_PANICS.lock() {
+        Ok(mut panics) => std::mem::take(&mut *panics),
+        Err(_) => Vec::new(),
     }
 }